@@ -0,0 +1,54 @@
+/* C api of the brainfuck interpreter, built using:
+ *
+ *     cargo rustc --lib --release --features capi --crate-type cdylib
+ */
+#ifndef BRAINFUCK_H
+#define BRAINFUCK_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* status codes returned by `bf_run` and `bf_set_io_callbacks` */
+#define BF_FINISHED 0
+#define BF_STEP_LIMIT 1
+#define BF_TAPE_LIMIT 2
+#define BF_INVALID_ARGUMENT -1
+
+/* a compiled program, and the callbacks used when running it */
+typedef struct bf_program bf_program;
+
+/* returns the next input byte, or a negative value at the end of the input */
+typedef int (*bf_read_fn)(void *user_data);
+/* writes an output byte */
+typedef void (*bf_write_fn)(void *user_data, uint8_t byte);
+
+/* parse and optimize the brainfuck source code of `len` bytes at `src` using the optimization
+ * `opt_level` [0..=3], returns null if the code is invalid or optimizing it panicked, see
+ * `bf_last_error` */
+bf_program *bf_compile(const uint8_t *src, size_t len, uint8_t opt_level);
+
+/* use the callbacks instead of the standard streams when running the `program`, a null callback
+ * restores the standard stream, the `user_data` is passed to the callbacks */
+int bf_set_io_callbacks(bf_program *program, bf_read_fn read, bf_write_fn write, void *user_data);
+
+/* run the `program` with a fresh tape, executing at most `max_steps` instructions unless it is
+ * zero, returns one of the `BF_*` status codes, `BF_INVALID_ARGUMENT` also if running the program
+ * panicked, see `bf_last_error` */
+int bf_run(bf_program *program, uint64_t max_steps);
+
+/* free a `program` returned by `bf_compile`, null is ignored */
+void bf_free(bf_program *program);
+
+/* the message of the last error on the current thread, or null, the string is valid until the
+ * next call on the same thread */
+const char *bf_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
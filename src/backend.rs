@@ -0,0 +1,302 @@
+//! The interface of the code generators, so new targets only have to implement [`Backend`] and
+//! are selected using `--emit` and `--target`.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::cli::Config;
+use crate::interp::Eof;
+use crate::{Instruction, NUM_REGISTERS};
+
+/// The runtime behavior of compiled programs, which all backends implement the same way.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuntimeOptions {
+    /// The value read at the end of the input.
+    pub eof: Eof,
+    /// The number of registers of the tape.
+    pub tape_len: usize,
+    /// Whether the output may be buffered until the program reads input or exits, otherwise each
+    /// byte is written immediately.
+    pub buffered_output: bool,
+    /// Periodically save the state of the program, see [`Checkpoint`].
+    pub checkpoint: Option<Checkpoint>,
+    /// The file the machine code of the program is cached in, so only the parts that changed
+    /// since the last compilation are generated again, see [`crate::cache::CodeCache`]. Only used
+    /// by the x86-64 `elf` backend.
+    pub code_cache: Option<PathBuf>,
+    /// Put the terminal connected to stdin into non-canonical mode while the program runs, so
+    /// each key is read as soon as it's pressed. Only used by the x86-64 `elf` backend.
+    pub interactive: bool,
+}
+
+/// Save the tape and the location inside the program to a file every `interval` seconds, so a
+/// long running program can be resumed from it after being killed. The program resumes from the
+/// file on startup if it exists, and removes it once it finishes. The input and output aren't
+/// part of the state, output written after the last checkpoint is written again when resuming.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub interval: u32,
+    /// The path of the file, relative to the working directory of the program.
+    pub path: String,
+}
+
+impl RuntimeOptions {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            eof: config.eof,
+            tape_len: NUM_REGISTERS,
+            buffered_output: !config.interactive,
+            checkpoint: None,
+            code_cache: None,
+            interactive: config.interactive,
+        }
+    }
+}
+
+/// The output of a [`Backend`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Artifact {
+    /// A binary that can be executed directly.
+    Executable(Vec<u8>),
+    /// Source code, which has to be compiled by another compiler.
+    Source(String),
+    /// A static library, and the C header declaring its functions.
+    Library { archive: Vec<u8>, header: String },
+    /// A raw memory image loaded by a boot loader, which can't be executed directly.
+    Image(Vec<u8>),
+}
+
+impl Artifact {
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Artifact::Executable(bytes) => bytes,
+            Artifact::Source(code) => code.as_bytes(),
+            Artifact::Library { archive, .. } => archive,
+            Artifact::Image(bytes) => bytes,
+        }
+    }
+
+    pub fn is_executable(&self) -> bool {
+        matches!(self, Artifact::Executable(_))
+    }
+}
+
+/// A code generator for a target.
+pub trait Backend {
+    /// The name of the target, which is passed to `--emit`.
+    fn name(&self) -> &'static str;
+
+    /// The extension of the files the artifacts are written to.
+    fn extension(&self) -> &'static str;
+
+    /// Generate the program implementing the optimized `ir`, which has resolved jump locations.
+    fn compile(&self, config: &Config, ir: &[Instruction], options: &RuntimeOptions) -> Artifact;
+}
+
+/// The processor architecture of a [`Target`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    I686,
+    Aarch64,
+    Riscv64,
+}
+
+/// The operating system of a [`Target`], `None` for programs running on bare metal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Os {
+    Linux,
+    None,
+    Windows,
+    MacOs,
+}
+
+/// The file format of the executables of a [`Target`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Elf,
+    /// A flat binary without any headers.
+    Bin,
+    Coff,
+    MachO,
+}
+
+/// The platform compiled executables run on, parsed from target triples like
+/// `x86_64-unknown-linux-gnu`, or the shorter `<arch>-<os>-<format>` form like `x86_64-linux-elf`
+/// used when printing it. The vendor and environment components are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub arch: Arch,
+    pub os: Os,
+    pub format: Format,
+}
+
+/// The targets executables or static libraries can be generated for.
+pub const TARGETS: &[Target] = &[
+    Target {
+        arch: Arch::X86_64,
+        os: Os::Linux,
+        format: Format::Elf,
+    },
+    Target {
+        arch: Arch::X86_64,
+        os: Os::None,
+        format: Format::Elf,
+    },
+    Target {
+        arch: Arch::I686,
+        os: Os::Linux,
+        format: Format::Elf,
+    },
+    Target {
+        arch: Arch::I686,
+        os: Os::None,
+        format: Format::Bin,
+    },
+];
+
+impl Target {
+    /// The target of the machine running the compiler.
+    pub fn host() -> Self {
+        let arch = match () {
+            _ if cfg!(target_arch = "x86") => Arch::I686,
+            _ if cfg!(target_arch = "aarch64") => Arch::Aarch64,
+            _ if cfg!(target_arch = "riscv64") => Arch::Riscv64,
+            _ => Arch::X86_64,
+        };
+        let os = match () {
+            _ if cfg!(target_os = "windows") => Os::Windows,
+            _ if cfg!(target_os = "macos") => Os::MacOs,
+            _ => Os::Linux,
+        };
+        Self {
+            arch,
+            os,
+            format: os.default_format(),
+        }
+    }
+
+    /// The backend generating executables for the target, or `None` if it isn't supported.
+    pub fn backend(self) -> Option<&'static dyn Backend> {
+        match self {
+            Target {
+                arch: Arch::X86_64,
+                os: Os::Linux,
+                format: Format::Elf,
+            } => Some(&crate::x86::Elf),
+            Target {
+                arch: Arch::I686,
+                os: Os::Linux,
+                format: Format::Elf,
+            } => Some(&crate::i686::Elf),
+            _ => None,
+        }
+    }
+
+    /// The backend generating static libraries for the target, or `None` if it isn't supported.
+    /// The code of static libraries performs I/O using callbacks instead of system calls, so it
+    /// also runs on bare metal.
+    pub fn library_backend(self) -> Option<&'static dyn Backend> {
+        match self {
+            Target {
+                arch: Arch::X86_64,
+                os: Os::Linux | Os::None,
+                format: Format::Elf,
+            } => Some(&crate::staticlib::StaticLib),
+            _ => None,
+        }
+    }
+
+    /// The backend generating flat binary images for the target, or `None` if it isn't
+    /// supported. Images run on bare metal in 32-bit protected mode, which x86-64 processors
+    /// start in as well, so the operating system and the format of the target are ignored.
+    pub fn image_backend(self) -> Option<&'static dyn Backend> {
+        match self.arch {
+            Arch::X86_64 | Arch::I686 => Some(&crate::i686::FlatBin),
+            _ => None,
+        }
+    }
+}
+
+impl Os {
+    fn default_format(self) -> Format {
+        match self {
+            Os::Linux | Os::None => Format::Elf,
+            Os::Windows => Format::Coff,
+            Os::MacOs => Format::MachO,
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(triple: &str) -> Result<Self, Self::Err> {
+        let mut components = triple.split('-');
+        let arch = match components.next().unwrap_or_default() {
+            "x86_64" | "amd64" => Arch::X86_64,
+            "i386" | "i486" | "i586" | "i686" | "x86" => Arch::I686,
+            "aarch64" | "arm64" => Arch::Aarch64,
+            "riscv64" | "riscv64gc" => Arch::Riscv64,
+            a => {
+                return Err(format!(
+                    "unknown architecture `{a}` of the target `{triple}`"
+                ))
+            }
+        };
+        let mut os = None;
+        let mut format = None;
+        for c in components {
+            match c {
+                "linux" => os = Some(Os::Linux),
+                "none" => os = Some(Os::None),
+                "windows" => os = Some(Os::Windows),
+                "darwin" | "macos" => os = Some(Os::MacOs),
+                "elf" => format = Some(Format::Elf),
+                "bin" => format = Some(Format::Bin),
+                "coff" | "pe" => format = Some(Format::Coff),
+                "macho" => format = Some(Format::MachO),
+                // vendors and environments
+                "unknown" | "pc" | "apple" | "gnu" | "musl" | "msvc" => (),
+                _ => return Err(format!("unknown component `{c}` of the target `{triple}`")),
+            }
+        }
+        // a format without an operating system, like `x86_64-elf`, is a bare metal target
+        let os = match (os, format) {
+            (Some(os), _) => os,
+            (None, Some(_)) => Os::None,
+            (None, None) => {
+                return Err(format!("missing operating system of the target `{triple}`"));
+            }
+        };
+        Ok(Self {
+            arch,
+            os,
+            format: format.unwrap_or(os.default_format()),
+        })
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arch = match self.arch {
+            Arch::X86_64 => "x86_64",
+            Arch::I686 => "i686",
+            Arch::Aarch64 => "aarch64",
+            Arch::Riscv64 => "riscv64",
+        };
+        let os = match self.os {
+            Os::Linux => "linux",
+            Os::None => "none",
+            Os::Windows => "windows",
+            Os::MacOs => "macos",
+        };
+        let format = match self.format {
+            Format::Elf => "elf",
+            Format::Bin => "bin",
+            Format::Coff => "coff",
+            Format::MachO => "macho",
+        };
+        write!(f, "{arch}-{os}-{format}")
+    }
+}
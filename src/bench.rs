@@ -0,0 +1,178 @@
+//! Compare the optimization passes by running a program under increasingly optimized
+//! configurations, to quantify the value of each pass on real workloads.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use crate::cli::{self, Config, Pass, Passes};
+use crate::interp::{Dispatch, Interpreter, Limit, Program, Status};
+use crate::{error, optim, Instruction};
+
+/// The configurations the program is run with, each one enables more passes than the previous. The
+/// last one compares threaded dispatch with the central match of the `-O3` run.
+fn configurations() -> [(&'static str, Passes, Dispatch); 7] {
+    let with = |passes: &[Pass]| {
+        let mut set = Passes::NONE;
+        for &p in passes {
+            set.insert(p);
+        }
+        set
+    };
+    let switch = Dispatch::Switch;
+    [
+        ("-O0", Passes::NONE, switch),
+        ("zeros", with(&[Pass::Zeros]), switch),
+        (
+            "+arithmetic",
+            with(&[Pass::Zeros, Pass::Arithmetic]),
+            switch,
+        ),
+        (
+            "+jumps",
+            with(&[Pass::Zeros, Pass::Arithmetic, Pass::Jumps]),
+            switch,
+        ),
+        (
+            "+dead-code",
+            with(&[Pass::Zeros, Pass::Arithmetic, Pass::Jumps, Pass::DeadCode]),
+            switch,
+        ),
+        ("-O3", Passes::level(3), switch),
+        ("-O3 threaded", Passes::level(3), Dispatch::Threaded),
+    ]
+}
+
+/// The result of running the program with one configuration.
+struct Outcome {
+    num_instructions: usize,
+    steps: u64,
+    time: Duration,
+    output: Vec<u8>,
+    limit: Option<Limit>,
+}
+
+/// Run the program at `path` with each configuration, and print the number of instructions, the
+/// number of executed instructions and the wall time of each run. The input is read from stdin
+/// once and passed to every run. Fails if the output of a configuration differs from the
+/// unoptimized one.
+pub fn bench_opts(config: &Config, path: &Path) -> ExitCode {
+    let instructions = match File::open(path).and_then(|f| crate::parse(config, f)) {
+        Ok(Ok(i)) => i,
+        Ok(Err(errors)) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut input = Vec::new();
+    if let Err(e) = std::io::stdin().read_to_end(&mut input) {
+        error!("failed to read the input: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    if !config.json {
+        println!(
+            "{:<16}{:>14}{:>18}{:>14}{:>10}",
+            "configuration", "instructions", "executed", "time", "speedup"
+        );
+    }
+    let mut json = String::from("{\"path\":");
+    cli::write_json_string(&mut json, &path.display().to_string());
+    json.push_str(",\"configurations\":[");
+    let mut baseline: Option<Outcome> = None;
+    let mut different = false;
+    for (i, (name, passes, dispatch)) in configurations().into_iter().enumerate() {
+        let config = config.clone().with_passes(passes);
+        let outcome = run(&config, instructions.clone(), &input, dispatch);
+
+        let base = baseline.as_ref().unwrap_or(&outcome);
+        let speedup = base.time.as_secs_f64() / outcome.time.as_secs_f64().max(f64::MIN_POSITIVE);
+        let time = format!("{:.3}s", outcome.time.as_secs_f64());
+        let (num, steps) = (outcome.num_instructions, outcome.steps);
+        if config.json {
+            if i > 0 {
+                json.push(',');
+            }
+            let time = outcome.time.as_secs_f64();
+            _ = write!(
+                json,
+                "{{\"name\":\"{name}\",\"instructions\":{num},\"executed\":{steps},\"time\":{time:.6},\"limit\":"
+            );
+            match outcome.limit {
+                Some(limit) => cli::write_json_string(&mut json, &limit.to_string()),
+                None => json.push_str("null"),
+            }
+            json.push('}');
+        } else {
+            match outcome.limit {
+                Some(limit) => println!("{name:<16}{num:>14}{steps:>18}{time:>14}    ({limit})"),
+                None => println!("{name:<16}{num:>14}{steps:>18}{time:>14}{speedup:>9.2}x"),
+            }
+        }
+
+        // the output of a run stopped by a limit is only compared up to where it stopped
+        let truncated = |o: &Outcome| o.limit.is_some();
+        let len = match truncated(base) || truncated(&outcome) {
+            true => base.output.len().min(outcome.output.len()),
+            false => base.output.len().max(outcome.output.len()),
+        };
+        if base.output.get(..len) != outcome.output.get(..len) {
+            error!("the output of `{name}` differs from the unoptimized program");
+            different = true;
+        }
+        baseline.get_or_insert(outcome);
+    }
+    if config.json {
+        json.push_str("]}");
+        println!("{json}");
+    }
+
+    match different {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+fn run(
+    config: &Config,
+    mut instructions: Vec<Instruction>,
+    input: &[u8],
+    dispatch: Dispatch,
+) -> Outcome {
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    let program = Program::new(config, instructions);
+
+    let mut output = Vec::new();
+    let mut interpreter =
+        Interpreter::from_config(config, &program, input, &mut output).with_dispatch(dispatch);
+    let start = Instant::now();
+    let status = interpreter.run();
+    let time = start.elapsed();
+    let steps = interpreter.steps();
+    let limit = match status {
+        Status::Paused => unreachable!(),
+        Status::Finished => None,
+        Status::Exceeded(limit) => Some(limit),
+    };
+    Outcome {
+        num_instructions: program.instructions.len(),
+        steps,
+        time,
+        output,
+        limit,
+    }
+}
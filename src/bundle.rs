@@ -0,0 +1,165 @@
+//! Executables embedding the optimized ir of a program and the interpreter, for platforms the
+//! native backends don't support. A bundle is a copy of the current executable with the program
+//! appended, which is detected and run on startup instead of parsing the arguments.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::cli::Config;
+use crate::interp::{Eof, Interpreter, Program, Status};
+use crate::{cache, error, optim};
+
+const MAGIC: &[u8; 8] = b"bfbundle";
+/// The length of the trailer following the program, the length of the program and the magic bytes.
+const TRAILER_LEN: usize = 8 + MAGIC.len();
+
+/// The program of a bundle, and the options it's run with.
+pub struct Bundle {
+    pub eof: Eof,
+    pub program: Program,
+}
+
+impl Bundle {
+    /// Serialize the bundle as the eof mode, the bounds flag, and the serialized ir.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = vec![self.eof as u8, self.program.in_bounds as u8];
+        bytes.extend_from_slice(&cache::serialize(&self.program.instructions));
+        bytes
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        let ([eof, in_bounds], bytes) = bytes.split_first_chunk()?;
+        let eof = match eof {
+            0 => Eof::Unchanged,
+            1 => Eof::Zero,
+            2 => Eof::Max,
+            _ => return None,
+        };
+        Some(Self {
+            eof,
+            program: Program {
+                instructions: cache::deserialize_compacted(bytes)?,
+                in_bounds: *in_bounds != 0,
+            },
+        })
+    }
+}
+
+/// Write a copy of the current executable with the optimized program at `path` appended.
+pub fn bundle(config: &Config, path: &Path) -> ExitCode {
+    let mut instructions = match File::open(path).and_then(|f| crate::parse(config, f)) {
+        Ok(Ok(i)) => i,
+        Ok(Err(errors)) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    let bundle = Bundle {
+        eof: config.eof,
+        program: Program::new(config, instructions),
+    };
+
+    let bin_path = match &config.output {
+        Some(path) => path.clone(),
+        None => path.with_extension(""),
+    };
+    if bin_path == path {
+        let path = path.display();
+        error!("`{path}` has no extension, pass the output path using `--output`");
+        return ExitCode::FAILURE;
+    }
+    let exe = match std::env::current_exe().and_then(std::fs::read) {
+        Ok(exe) => exe,
+        Err(e) => {
+            error!("failed to read the current executable: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let payload = bundle.serialize();
+
+    let file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .mode(0o755)
+        .open(&bin_path);
+    let mut file = match file {
+        Ok(f) => f,
+        Err(e) => {
+            let bin_path = bin_path.display();
+            error!("failed to create `{bin_path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = (file.write_all(&exe))
+        .and_then(|_| file.write_all(&payload))
+        .and_then(|_| file.write_all(&(payload.len() as u64).to_le_bytes()))
+        .and_then(|_| file.write_all(MAGIC));
+    if let Err(e) = result {
+        let bin_path = bin_path.display();
+        error!("failed to write `{bin_path}`: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// The program appended to the current executable, if it's a bundle.
+pub fn embedded() -> Option<Vec<u8>> {
+    let mut file = File::open(std::env::current_exe().ok()?).ok()?;
+    let len = file.seek(SeekFrom::End(0)).ok()?;
+    if len < TRAILER_LEN as u64 {
+        return None;
+    }
+    let mut trailer = [0; TRAILER_LEN];
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64))).ok()?;
+    file.read_exact(&mut trailer).ok()?;
+    let (payload_len, magic) = trailer.split_first_chunk::<8>()?;
+    if magic != MAGIC {
+        return None;
+    }
+    let payload_len = u64::from_le_bytes(*payload_len);
+    let start = (len - TRAILER_LEN as u64).checked_sub(payload_len)?;
+
+    let mut payload = vec![0; payload_len as usize];
+    file.seek(SeekFrom::Start(start)).ok()?;
+    file.read_exact(&mut payload).ok()?;
+    Some(payload)
+}
+
+/// Interpret the program embedded into a bundle using the standard streams.
+pub fn run(payload: &[u8]) -> ExitCode {
+    let Some(bundle) = Bundle::deserialize(payload) else {
+        error!("the embedded program is invalid");
+        return ExitCode::FAILURE;
+    };
+    let config = Config {
+        eof: bundle.eof,
+        ..Config::default()
+    };
+    let mut interpreter = Interpreter::from_config(
+        &config,
+        &bundle.program,
+        std::io::stdin(),
+        std::io::stdout(),
+    );
+    if let Status::Exceeded(limit) = interpreter.run() {
+        error!("{limit}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
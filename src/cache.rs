@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{Config, Pass};
+use crate::{warn, Instruction, Jump};
+
+const MAGIC: &[u8; 4] = b"bfir";
+const CODE_MAGIC: &[u8; 6] = b"bfcode";
+/// Incremented whenever the format of the code cache or the generated code changes.
+const CODE_FORMAT_VERSION: u8 = 1;
+/// Incremented whenever the serialization format or the optimizer output changes.
+const FORMAT_VERSION: u8 = 7;
+
+/// The cache entry of the optimized ir of a program, identified by a hash of the source code and
+/// all options affecting the generated ir.
+pub struct Key {
+    hash: u64,
+}
+
+impl Key {
+    /// Hash the source code read from `src` in chunks.
+    pub fn new(config: &Config, mut src: impl Read) -> std::io::Result<Self> {
+        // the schedule and bounds passes only affect how the ir is executed
+        let mut passes = config.passes;
+        passes.remove(Pass::Schedule);
+        passes.remove(Pass::Bounds);
+
+        let mut hash = Fnv1a::new();
+        hash.write(env!("CARGO_PKG_VERSION").as_bytes());
+        hash.write(&[FORMAT_VERSION]);
+        hash.write(&passes.bits().to_le_bytes());
+        hash.write(&config.static_fuel.to_le_bytes());
+        hash.write(&[
+            config.syntax as u8,
+            config.dialect as u8,
+            config.recover as u8,
+            config.superopt as u8,
+            config.strict_syntax as u8,
+        ]);
+        if config.strict_syntax {
+            hash.write(config.comment_marker.as_bytes());
+        }
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            match src.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => hash.write(&buf[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Self {
+            hash: hash.finish(),
+        })
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        Some(cache_dir()?.join(format!("{:016x}.ir", self.hash)))
+    }
+}
+
+/// `$XDG_CACHE_HOME/bf` or `$HOME/.cache/bf`.
+fn cache_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("bf"))
+}
+
+/// Load the cached ir, a missing or corrupt entry is treated as a cache miss.
+pub fn load(key: &Key) -> Option<Vec<Instruction>> {
+    let bytes = std::fs::read(key.path()?).ok()?;
+    deserialize(&bytes)
+}
+
+pub fn store(key: &Key, instructions: &[Instruction]) {
+    let Some(path) = key.path() else {
+        warn!("failed to determine cache directory");
+        return;
+    };
+    write_entry(&path, &serialize(instructions));
+}
+
+/// Write a cache entry to a temporary file first, so concurrent runs never observe partial entries.
+fn write_entry(path: &Path, bytes: &[u8]) {
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    let result = (|| {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::File::create(&tmp_path)?.write_all(bytes)?;
+        std::fs::rename(&tmp_path, path)
+    })();
+    if let Err(e) = result {
+        _ = std::fs::remove_file(&tmp_path);
+        let path = path.display();
+        warn!("failed to write cache entry `{path}`: {e}");
+    }
+}
+
+/// The machine code of a top-level segment of a program, a run of instructions ending after a
+/// top-level loop, which doesn't depend on the code around it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Segment {
+    pub code: Vec<u8>,
+    /// The start of the code of each instruction, relative to the start of the segment.
+    pub locations: Vec<u32>,
+    /// The positions after the displacements of calls of runtime routines, relative to the start
+    /// of the segment, and the routines, which are patched once the code is stitched together.
+    pub calls: Vec<(u32, u8)>,
+}
+
+/// The machine code of the segments of the last compilation of a program, so only segments whose
+/// ir changed have to be generated again. The segments are identified by a hash of their ir and
+/// all options affecting their code.
+pub struct CodeCache {
+    path: PathBuf,
+    cached: HashMap<u64, Segment>,
+    /// The segments of the current compilation, which replace the cached ones when stored.
+    current: HashMap<u64, Segment>,
+    /// The number of segments reused from the cache.
+    pub reused: usize,
+}
+
+impl CodeCache {
+    /// The file the code of the program compiled to `out_path` is cached in.
+    pub fn path(out_path: &Path) -> Option<PathBuf> {
+        let out_path = std::path::absolute(out_path).ok()?;
+        let mut hash = Fnv1a::new();
+        hash.write(out_path.as_os_str().as_encoded_bytes());
+        Some(cache_dir()?.join(format!("{:016x}.code", hash.finish())))
+    }
+
+    /// Load the cached segments, a missing or corrupt file is treated as an empty cache.
+    pub fn load(path: &Path) -> Self {
+        let cached = std::fs::read(path)
+            .ok()
+            .and_then(|bytes| decode_segments(&bytes))
+            .unwrap_or_default();
+        Self {
+            path: path.to_path_buf(),
+            cached,
+            current: HashMap::new(),
+            reused: 0,
+        }
+    }
+
+    pub fn get(&mut self, hash: u64) -> Option<&Segment> {
+        if !self.current.contains_key(&hash) {
+            let segment = self.cached.remove(&hash)?;
+            self.current.insert(hash, segment);
+        }
+        self.reused += 1;
+        self.current.get(&hash)
+    }
+
+    pub fn insert(&mut self, hash: u64, segment: Segment) {
+        self.current.insert(hash, segment);
+    }
+
+    /// Replace the cached segments with the ones of the current compilation.
+    pub fn store(&self) {
+        let mut segments = self.current.iter().collect::<Vec<_>>();
+        segments.sort_unstable_by_key(|(hash, _)| **hash);
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(CODE_MAGIC);
+        bytes.push(CODE_FORMAT_VERSION);
+        for (hash, segment) in segments {
+            bytes.extend_from_slice(&hash.to_le_bytes());
+            bytes.extend_from_slice(&(segment.code.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&segment.code);
+            bytes.extend_from_slice(&(segment.locations.len() as u32).to_le_bytes());
+            for location in segment.locations.iter() {
+                bytes.extend_from_slice(&location.to_le_bytes());
+            }
+            bytes.extend_from_slice(&(segment.calls.len() as u32).to_le_bytes());
+            for &(pos, routine) in segment.calls.iter() {
+                bytes.extend_from_slice(&pos.to_le_bytes());
+                bytes.push(routine);
+            }
+        }
+        write_entry(&self.path, &bytes);
+    }
+}
+
+fn decode_segments(bytes: &[u8]) -> Option<HashMap<u64, Segment>> {
+    let bytes = bytes.strip_prefix(CODE_MAGIC)?;
+    let ([version], mut bytes) = bytes.split_first_chunk()?;
+    if *version != CODE_FORMAT_VERSION {
+        return None;
+    }
+
+    let mut segments = HashMap::new();
+    while !bytes.is_empty() {
+        let hash = u64::from_le_bytes(take(&mut bytes)?);
+        let len = u32::from_le_bytes(take(&mut bytes)?) as usize;
+        let code = bytes.get(..len)?.to_vec();
+        bytes = &bytes[len..];
+        let num_locations = u32::from_le_bytes(take(&mut bytes)?);
+        let locations = (0..num_locations)
+            .map(|_| Some(u32::from_le_bytes(take(&mut bytes)?)))
+            .collect::<Option<Vec<_>>>()?;
+        let num_calls = u32::from_le_bytes(take(&mut bytes)?);
+        let calls = (0..num_calls)
+            .map(|_| {
+                let pos = u32::from_le_bytes(take(&mut bytes)?);
+                let [routine] = take(&mut bytes)?;
+                Some((pos, routine))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let segment = Segment {
+            code,
+            locations,
+            calls,
+        };
+        segments.insert(hash, segment);
+    }
+    Some(segments)
+}
+
+/// Serialize instructions as the magic bytes and format version, followed by one opcode byte per
+/// instruction and its little endian operands.
+pub fn serialize(instructions: &[Instruction]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + 4 * instructions.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(FORMAT_VERSION);
+    for inst in instructions {
+        match *inst {
+            Instruction::Shl(n) => write_op(&mut bytes, 0, &n.to_le_bytes()),
+            Instruction::Shr(n) => write_op(&mut bytes, 1, &n.to_le_bytes()),
+            Instruction::Inc(o, n) => write_op(&mut bytes, 2, &offset_and_value(o, n)),
+            Instruction::Dec(o, n) => write_op(&mut bytes, 3, &offset_and_value(o, n)),
+            Instruction::Output(o) => write_op(&mut bytes, 4, &o.to_le_bytes()),
+            Instruction::Input(o) => write_op(&mut bytes, 5, &o.to_le_bytes()),
+            Instruction::JumpZ(jump) => write_op(&mut bytes, 6, &jump_location(jump)),
+            Instruction::JumpNz(jump) => write_op(&mut bytes, 7, &jump_location(jump)),
+            Instruction::Zero(o) => write_op(&mut bytes, 8, &o.to_le_bytes()),
+            Instruction::Set(o, n) => write_op(&mut bytes, 9, &offset_and_value(o, n)),
+            Instruction::Add(o) => write_op(&mut bytes, 10, &o.to_le_bytes()),
+            Instruction::Sub(o) => write_op(&mut bytes, 11, &o.to_le_bytes()),
+            Instruction::AddMul(o, n) => write_op(&mut bytes, 12, &offset_and_value(o, n)),
+            Instruction::SubMul(o, n) => write_op(&mut bytes, 13, &offset_and_value(o, n)),
+            Instruction::CopyRange(s, d, n) => {
+                let ([s0, s1], [d0, d1], [n0, n1]) =
+                    (s.to_le_bytes(), d.to_le_bytes(), n.to_le_bytes());
+                write_op(&mut bytes, 14, &[s0, s1, d0, d1, n0, n1]);
+            }
+            Instruction::DivMod => write_op(&mut bytes, 15, &[]),
+            Instruction::PrintDecimal(o) => write_op(&mut bytes, 16, &o.to_le_bytes()),
+            Instruction::OutputConst(n) => write_op(&mut bytes, 17, &[n]),
+            Instruction::Pipe => write_op(&mut bytes, 18, &[]),
+        }
+    }
+    bytes
+}
+
+fn write_op(bytes: &mut Vec<u8>, opcode: u8, operands: &[u8]) {
+    bytes.push(opcode);
+    bytes.extend_from_slice(operands);
+}
+
+fn offset_and_value(o: i16, n: u8) -> [u8; 3] {
+    let [o0, o1] = o.to_le_bytes();
+    [o0, o1, n]
+}
+
+/// Redundant jumps are stored with location `0`.
+fn jump_location(jump: Jump) -> [u8; 4] {
+    match jump {
+        Jump::Location(loc) => loc.get().to_le_bytes(),
+        Jump::Redundant => [0; 4],
+        Jump::Unresolved => unreachable!("jumps are resolved before being cached"),
+    }
+}
+
+/// Deserialize instructions written by [`serialize`], returns `None` if the data is invalid or
+/// was written using a different format version. Jump locations are read as is, and have to be
+/// updated using [`crate::update_jump_indices`] before the instructions can be trusted.
+pub fn deserialize(bytes: &[u8]) -> Option<Vec<Instruction>> {
+    let instructions = decode(bytes)?;
+    let mut depth = 0_usize;
+    for inst in instructions.iter() {
+        match inst {
+            Instruction::JumpZ(_) => depth += 1,
+            Instruction::JumpNz(_) => depth = depth.checked_sub(1)?,
+            _ => (),
+        }
+    }
+    if depth != 0 {
+        return None;
+    }
+    Some(instructions)
+}
+
+/// Deserialize instructions written by [`serialize`] after their jumps were compacted using
+/// [`optim::compact_jumps`], so the brackets aren't balanced. The jump locations are kept, and
+/// only checked to be inside the program.
+///
+/// [`optim::compact_jumps`]: crate::optim::compact_jumps
+pub fn deserialize_compacted(bytes: &[u8]) -> Option<Vec<Instruction>> {
+    let instructions = decode(bytes)?;
+    let in_range = |inst: &Instruction| match inst {
+        Instruction::JumpZ(Jump::Location(loc)) | Instruction::JumpNz(Jump::Location(loc)) => {
+            loc.get() as usize <= instructions.len()
+        }
+        _ => true,
+    };
+    instructions.iter().all(in_range).then_some(instructions)
+}
+
+fn decode(bytes: &[u8]) -> Option<Vec<Instruction>> {
+    let bytes = bytes.strip_prefix(MAGIC)?;
+    let ([version], mut bytes) = bytes.split_first_chunk()?;
+    if *version != FORMAT_VERSION {
+        return None;
+    }
+
+    let mut instructions = Vec::new();
+    while let Some((&opcode, rest)) = bytes.split_first() {
+        bytes = rest;
+        let inst = match opcode {
+            0 => Instruction::Shl(u16::from_le_bytes(take(&mut bytes)?)),
+            1 => Instruction::Shr(u16::from_le_bytes(take(&mut bytes)?)),
+            2 => take_offset_and_value(&mut bytes).map(|(o, n)| Instruction::Inc(o, n))?,
+            3 => take_offset_and_value(&mut bytes).map(|(o, n)| Instruction::Dec(o, n))?,
+            4 => Instruction::Output(i16::from_le_bytes(take(&mut bytes)?)),
+            5 => Instruction::Input(i16::from_le_bytes(take(&mut bytes)?)),
+            6 => Instruction::JumpZ(take_jump(&mut bytes)?),
+            7 => Instruction::JumpNz(take_jump(&mut bytes)?),
+            8 => Instruction::Zero(i16::from_le_bytes(take(&mut bytes)?)),
+            9 => take_offset_and_value(&mut bytes).map(|(o, n)| Instruction::Set(o, n))?,
+            10 => Instruction::Add(i16::from_le_bytes(take(&mut bytes)?)),
+            11 => Instruction::Sub(i16::from_le_bytes(take(&mut bytes)?)),
+            12 => take_offset_and_value(&mut bytes).map(|(o, n)| Instruction::AddMul(o, n))?,
+            13 => take_offset_and_value(&mut bytes).map(|(o, n)| Instruction::SubMul(o, n))?,
+            14 => {
+                let [s0, s1, d0, d1, n0, n1] = take(&mut bytes)?;
+                let (s, d) = (i16::from_le_bytes([s0, s1]), i16::from_le_bytes([d0, d1]));
+                Instruction::CopyRange(s, d, u16::from_le_bytes([n0, n1]))
+            }
+            15 => Instruction::DivMod,
+            16 => Instruction::PrintDecimal(i16::from_le_bytes(take(&mut bytes)?)),
+            17 => Instruction::OutputConst(u8::from_le_bytes(take(&mut bytes)?)),
+            18 => Instruction::Pipe,
+            _ => return None,
+        };
+        instructions.push(inst);
+    }
+    Some(instructions)
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+    let (chunk, rest) = bytes.split_first_chunk()?;
+    *bytes = rest;
+    Some(*chunk)
+}
+
+fn take_offset_and_value(bytes: &mut &[u8]) -> Option<(i16, u8)> {
+    let [o0, o1, n] = take(bytes)?;
+    Some((i16::from_le_bytes([o0, o1]), n))
+}
+
+fn take_jump(bytes: &mut &[u8]) -> Option<Jump> {
+    let loc = u32::from_le_bytes(take(bytes)?);
+    Some(NonZeroU32::new(loc).map_or(Jump::Redundant, Jump::Location))
+}
+
+/// The 64-bit FNV-1a hash, which is stable across compiler versions unlike the std hashers.
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
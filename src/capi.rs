@@ -0,0 +1,221 @@
+//! A C api for embedding the interpreter, declared in `include/brainfuck.h`. Programs are compiled
+//! to opaque handles, which are run using the standard streams or I/O callbacks.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::io::{Read, Write};
+use std::panic::AssertUnwindSafe;
+
+use crate::cli::Config;
+use crate::interp::{self, Interpreter, Limit, Status};
+use crate::optim;
+
+pub const BF_FINISHED: c_int = 0;
+pub const BF_STEP_LIMIT: c_int = 1;
+pub const BF_TAPE_LIMIT: c_int = 2;
+pub const BF_INVALID_ARGUMENT: c_int = -1;
+
+/// Returns the next input byte, or a negative value at the end of the input.
+pub type ReadFn = unsafe extern "C" fn(user_data: *mut c_void) -> c_int;
+/// Writes an output byte.
+pub type WriteFn = unsafe extern "C" fn(user_data: *mut c_void, byte: u8);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A compiled program, and the callbacks used when running it.
+pub struct Program {
+    program: interp::Program,
+    read: Option<ReadFn>,
+    write: Option<WriteFn>,
+    user_data: *mut c_void,
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', "\\0")).expect("nul bytes are escaped");
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Record the message of a caught panic as the last error, panics must not unwind into C.
+fn set_panic_error(payload: Box<dyn Any + Send>) {
+    let msg = (payload.downcast_ref::<&str>().copied())
+        .or(payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    set_last_error(format!("internal error: {msg}"));
+}
+
+/// Parse and optimize the brainfuck source code of `len` bytes at `src` using the optimization
+/// `opt_level` [0..=3]. Returns null if the code is invalid or optimizing it panicked, see
+/// [`bf_last_error`].
+///
+/// # Safety
+/// `src` has to point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bf_compile(src: *const u8, len: usize, opt_level: u8) -> *mut Program {
+    if (src.is_null() && len > 0) || opt_level > 3 {
+        set_last_error("invalid argument".into());
+        return std::ptr::null_mut();
+    }
+    let src = match len {
+        0 => &[],
+        _ => unsafe { std::slice::from_raw_parts(src, len) },
+    };
+    let result = std::panic::catch_unwind(|| {
+        let config = Config::default().with_opt_level(opt_level);
+        let mut instructions = match crate::parse(&config, src) {
+            Ok(Ok(i)) => i,
+            Ok(Err(errors)) => {
+                set_last_error(errors.join("\n"));
+                return std::ptr::null_mut();
+            }
+            Err(e) => {
+                set_last_error(e.to_string());
+                return std::ptr::null_mut();
+            }
+        };
+        if config.optimize() {
+            optim::optimize(&config, &mut instructions);
+        }
+        crate::update_jump_indices(&mut instructions);
+        optim::thread_jumps(&config, &mut instructions);
+
+        let program = Program {
+            program: interp::Program::new(&config, instructions),
+            read: None,
+            write: None,
+            user_data: std::ptr::null_mut(),
+        };
+        Box::into_raw(Box::new(program))
+    });
+    result.unwrap_or_else(|payload| {
+        set_panic_error(payload);
+        std::ptr::null_mut()
+    })
+}
+
+/// Use the callbacks instead of the standard streams when running the `program`, a null callback
+/// restores the standard stream. The `user_data` is passed to the callbacks.
+///
+/// # Safety
+/// `program` has to be returned by [`bf_compile`], and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn bf_set_io_callbacks(
+    program: *mut Program,
+    read: Option<ReadFn>,
+    write: Option<WriteFn>,
+    user_data: *mut c_void,
+) -> c_int {
+    let Some(program) = (unsafe { program.as_mut() }) else {
+        return BF_INVALID_ARGUMENT;
+    };
+    program.read = read;
+    program.write = write;
+    program.user_data = user_data;
+    BF_FINISHED
+}
+
+/// Run the `program` with a fresh tape, executing at most `max_steps` instructions unless it is
+/// zero. Returns one of the `BF_*` status codes, [`BF_INVALID_ARGUMENT`] also if running the
+/// program panicked, see [`bf_last_error`].
+///
+/// # Safety
+/// `program` has to be returned by [`bf_compile`], and not freed yet. The callbacks have to be
+/// safe to call with the `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_run(program: *mut Program, max_steps: u64) -> c_int {
+    let Some(program) = (unsafe { program.as_ref() }) else {
+        return BF_INVALID_ARGUMENT;
+    };
+    let input: Box<dyn Read> = match program.read {
+        Some(read) => Box::new(CallbackReader {
+            read,
+            user_data: program.user_data,
+        }),
+        None => Box::new(std::io::stdin()),
+    };
+    let output: Box<dyn Write> = match program.write {
+        Some(write) => Box::new(CallbackWriter {
+            write,
+            user_data: program.user_data,
+        }),
+        None => Box::new(std::io::stdout()),
+    };
+    let config = Config {
+        max_steps: (max_steps > 0).then_some(max_steps),
+        ..Config::default()
+    };
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        Interpreter::from_config(&config, &program.program, input, output).run()
+    }));
+    match result {
+        Ok(Status::Paused | Status::Finished) => BF_FINISHED,
+        Ok(Status::Exceeded(Limit::Steps)) => BF_STEP_LIMIT,
+        Ok(Status::Exceeded(Limit::Tape)) => BF_TAPE_LIMIT,
+        Ok(Status::Exceeded(Limit::Output | Limit::Time | Limit::Eof)) => {
+            unreachable!("no such limits are set")
+        }
+        Err(payload) => {
+            set_panic_error(payload);
+            BF_INVALID_ARGUMENT
+        }
+    }
+}
+
+/// Free a `program` returned by [`bf_compile`], null is ignored.
+///
+/// # Safety
+/// `program` has to be returned by [`bf_compile`], and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn bf_free(program: *mut Program) {
+    if !program.is_null() {
+        drop(unsafe { Box::from_raw(program) });
+    }
+}
+
+/// The message of the last error on the current thread, or null. The string is valid until the
+/// next call on the same thread.
+#[no_mangle]
+pub extern "C" fn bf_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(std::ptr::null(), |e| e.as_ptr()))
+}
+
+struct CallbackReader {
+    read: ReadFn,
+    user_data: *mut c_void,
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // one byte at a time, so interactive hosts are asked for input only when it's needed
+        match unsafe { (self.read)(self.user_data) } {
+            b @ 0..=255 => {
+                buf[0] = b as u8;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+struct CallbackWriter {
+    write: WriteFn,
+    user_data: *mut c_void,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &b in buf {
+            unsafe { (self.write)(self.user_data, b) };
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
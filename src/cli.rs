@@ -1,24 +1,590 @@
+use std::fmt::Write as _;
 use std::ops::ControlFlow;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::Duration;
 
-use crate::Instruction;
+use crate::backend::{Arch, Backend, Target, TARGETS};
+use crate::encoding::InputEncoding;
+use crate::interp::{Eof, Limits};
+use crate::lint::{self, Level, Lint};
+use crate::optim::{self, PointerRange};
+use crate::project::{Manifest, DEV_PROFILE, MANIFEST, RELEASE_PROFILE};
+use crate::{error, rust, Instruction, Jump, Token};
 
 pub const ANSII_CLEAR: &str = "\x1b[0m";
 pub const ANSII_UNDERLINED: &str = "\x1b[4m";
 pub const ANSII_COLOR_RED: &str = "\x1b[91m";
 pub const ANSII_COLOR_YELLOW: &str = "\x1b[93m";
+pub const ANSII_CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
 
+const SANDBOX_MAX_STEPS: u64 = 10_000_000_000;
+const SANDBOX_MAX_OUTPUT: usize = 1 << 24;
+const SANDBOX_MAX_TIME: Duration = Duration::from_secs(10);
+/// Number of loops listed by [`print_cycle_counts_json`].
+const NUM_HOT_LOOPS: usize = 10;
+/// The start of the comments allowed by `--strict-syntax`, if `--comment-marker` isn't passed.
+pub const DEFAULT_COMMENT_MARKER: &str = "#";
+
+/// The options of all modes. Parsed from the command line arguments by [`parse_args`], or built
+/// using the presets and `with_*` methods when using the library.
+#[derive(Clone, Debug)]
 pub struct Config {
     pub verbose: u8,
     pub print_unoptimized_ir: bool,
-    pub optimize: bool,
-    pub o_zeros: bool,
-    pub o_arithmetic: bool,
-    pub o_jumps: bool,
-    pub o_dead_code: bool,
-    pub o_init: bool,
-    pub o_simplify: bool,
+    pub print_asm: bool,
+    pub passes: Passes,
+    pub superopt: bool,
+    /// The maximum number of instructions executed at compile time by the `init`, `dead-code`
+    /// and `unroll` passes, see [`optim::optimize_static_code`].
+    pub static_fuel: u64,
+    /// Check every local rewrite of the optimization passes by running the original and the
+    /// rewritten instructions on random tapes, see [`crate::peephole::check_rewrite`].
+    pub verify_passes: bool,
+    /// The levels of the lints, see [`crate::lint`].
+    pub lints: lint::Levels,
+    pub port: u16,
+    pub max_steps: Option<u64>,
+    pub max_output: Option<usize>,
+    pub max_time: Option<Duration>,
+    /// The number of symbolic input bytes of the `symex` mode.
+    pub max_input: Option<usize>,
+    /// The number of times each path of the `symex` mode forks.
+    pub max_depth: Option<u32>,
+    pub sandbox: bool,
+    pub count_cycles: bool,
+    pub cache: bool,
+    pub watch: bool,
+    pub eof: Eof,
+    /// The eof value was passed on the command line or in the manifest, so it isn't overridden
+    /// by the pragmas of the programs, see [`crate::pragma`].
+    pub explicit_eof: bool,
+    pub syntax: Syntax,
+    pub dialect: Dialect,
+    /// How the input of programs run by the `run` mode is decoded, see [`crate::encoding`].
+    pub input_encoding: InputEncoding,
+    pub recover: bool,
+    pub keep_comments: bool,
+    /// The expected output, a file or the output itself, see [`crate::expect::load_output`].
+    pub expect_output: Option<PathBuf>,
+    pub expect_exit: Option<u8>,
+    pub expect_crash: bool,
+    /// The lint the program has to be reported by in the `reduce` mode.
+    pub expect_warning: Option<Lint>,
+    pub emit: Emit,
+    /// The platform executables are generated for.
+    pub target: Target,
+    /// The interval in seconds of the checkpoints of compiled programs.
+    pub checkpoint: Option<u32>,
+    /// The address `flat-bin` images are loaded at.
+    pub org: Option<u32>,
+    /// Start `flat-bin` images with a multiboot header.
+    pub multiboot: bool,
+    pub no_ir: bool,
+    pub output: Option<PathBuf>,
+    /// The directory the `install` mode places executables in, `~/.local/bin` by default.
+    pub install_dir: Option<PathBuf>,
+    pub pipe: bool,
+    /// Also compile the tests of the `test` mode to executables and run them.
+    pub compiled: bool,
+    /// The number of threads processing multiple programs, see [`crate::parallel`].
+    pub jobs: Option<usize>,
+    /// The time spent running inputs in the `fuzz` mode.
+    pub fuzz_duration: Option<Duration>,
+    /// The seed of the random inputs of the `fuzz` mode.
+    pub seed: Option<u64>,
+    /// The assertions of the `fuzz` mode, see [`crate::fuzz`].
+    pub asserts: Vec<String>,
+    pub trace_hash: bool,
+    pub mem_stats: bool,
+    pub dump_core: bool,
+    /// The file snapshots of the tape are written to while running the program, see
+    /// [`crate::viz`].
+    pub tape_log: Option<PathBuf>,
+    /// The number of executed instructions between the snapshots of `--tape-log`.
+    pub tape_interval: Option<u64>,
+    pub core: Option<PathBuf>,
+    /// The line and column of the loop in the `explain` mode.
+    pub at: Option<(usize, usize)>,
+    /// Print the results of the `check` and `bench-opts` modes and of `--count-cycles` as json.
+    pub json: bool,
+    /// Report whether each loop terminates in the `check` mode, see [`crate::termination`].
+    pub termination: bool,
+    /// Reject programs relying on behavior that differs between implementations.
+    pub strict: bool,
+    /// Reject programs containing other bytes than commands, whitespace and comments, see
+    /// [`crate::check_strict_syntax`].
+    pub strict_syntax: bool,
+    /// The start of the comments allowed by `--strict-syntax`.
+    pub comment_marker: String,
+    /// Write the output immediately and read single keys, see [`crate::tty`].
+    pub interactive: bool,
+    /// Escape control characters in the output of programs run by the `run` mode if it goes to a
+    /// terminal, see [`crate::tty::Sanitized`].
+    pub sanitize_output: bool,
+    /// Write the output immediately without changing the terminal, set by `meta run`.
+    pub unbuffered: bool,
+    /// Stop when reading past the end of the input, set by `--strict` unless the eof value was
+    /// chosen explicitly.
+    pub strict_eof: bool,
+}
+
+impl Default for Config {
+    /// The options of the library, which disable all optimization passes. The command line uses
+    /// the optimization level of the mode instead, see [`Command::default_opt_level`].
+    fn default() -> Self {
+        Self {
+            verbose: 0,
+            print_unoptimized_ir: false,
+            print_asm: false,
+            passes: Passes::NONE,
+            superopt: false,
+            static_fuel: optim::DEFAULT_STATIC_FUEL,
+            verify_passes: false,
+            lints: lint::Levels::default(),
+            port: 8080,
+            max_steps: None,
+            max_output: None,
+            max_time: None,
+            max_input: None,
+            max_depth: None,
+            sandbox: false,
+            count_cycles: false,
+            cache: false,
+            watch: false,
+            eof: Eof::default(),
+            explicit_eof: false,
+            input_encoding: InputEncoding::default(),
+            syntax: Syntax::default(),
+            dialect: Dialect::default(),
+            recover: false,
+            keep_comments: false,
+            expect_output: None,
+            expect_exit: None,
+            expect_crash: false,
+            expect_warning: None,
+            emit: Emit::default(),
+            target: Target::host(),
+            checkpoint: None,
+            org: None,
+            multiboot: false,
+            no_ir: false,
+            output: None,
+            install_dir: None,
+            pipe: false,
+            compiled: false,
+            jobs: None,
+            fuzz_duration: None,
+            seed: None,
+            asserts: Vec::new(),
+            trace_hash: false,
+            mem_stats: false,
+            dump_core: false,
+            tape_log: None,
+            tape_interval: None,
+            core: None,
+            at: None,
+            json: false,
+            termination: false,
+            strict: false,
+            strict_syntax: false,
+            comment_marker: DEFAULT_COMMENT_MARKER.to_owned(),
+            strict_eof: false,
+            interactive: false,
+            sanitize_output: false,
+            unbuffered: false,
+        }
+    }
+}
+
+impl Config {
+    /// The options used by `-O2`, all optimization passes except `unroll`.
+    pub fn o2() -> Self {
+        Self::default().with_opt_level(2)
+    }
+
+    /// The options used by `-O2 --sandbox`, which turns programs that run away into errors, by
+    /// enforcing limits on steps, output, wall time and the tape.
+    pub fn sandboxed() -> Self {
+        Self::o2().with_sandbox()
+    }
+
+    /// Enable the passes of the optimization `level` [0..=3], see [`Passes::level`].
+    pub fn with_opt_level(mut self, level: u8) -> Self {
+        self.passes = Passes::level(level);
+        self
+    }
+
+    /// Enable exactly the optimization `passes`.
+    pub fn with_passes(mut self, passes: Passes) -> Self {
+        self.passes = passes;
+        self
+    }
+
+    /// Enable or disable a single optimization `pass`.
+    pub fn with_pass(mut self, pass: Pass, enabled: bool) -> Self {
+        match enabled {
+            true => self.passes.insert(pass),
+            false => self.passes.remove(pass),
+        }
+        self
+    }
+
+    /// Stop executing the start of programs at compile time after `fuel` instructions.
+    pub fn with_static_fuel(mut self, fuel: u64) -> Self {
+        self.static_fuel = fuel;
+        self
+    }
+
+    /// The verbosity level [0..=3] of the diagnostics printed by the optimizer.
+    pub fn with_verbose(mut self, verbose: u8) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    pub fn with_syntax(mut self, syntax: Syntax) -> Self {
+        self.syntax = syntax;
+        self
+    }
+
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Whether the source code is plain brainfuck, whose commands are located where they are in
+    /// the source code, since it isn't translated.
+    pub fn is_brainfuck(&self) -> bool {
+        self.syntax == Syntax::Brainfuck && self.dialect == Dialect::Brainfuck
+    }
+
+    /// Recover from unmatched brackets by inserting the missing ones.
+    pub fn with_recover(mut self) -> Self {
+        self.recover = true;
+        self
+    }
+
+    /// Enforce the `limits` when running programs, in sandbox mode the unspecified ones default
+    /// to the sandbox limits.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.max_steps = limits.steps;
+        self.max_output = limits.output;
+        self.max_time = limits.time;
+        self
+    }
+
+    /// Enforce limits on steps, output, wall time and the tape, see [`Config::limits`].
+    pub fn with_sandbox(mut self) -> Self {
+        self.sandbox = true;
+        self
+    }
+
+    /// Whether the optimization `pass` is enabled.
+    pub fn pass(&self, pass: Pass) -> bool {
+        self.passes.contains(pass)
+    }
+
+    /// Whether any optimization pass is enabled.
+    pub fn optimize(&self) -> bool {
+        self.passes != Passes::NONE
+    }
+
+    /// The limits enforced when running programs, with defaults for unspecified limits in sandbox
+    /// mode.
+    pub fn limits(&self) -> Limits {
+        if self.sandbox {
+            Limits {
+                steps: Some(self.max_steps.unwrap_or(SANDBOX_MAX_STEPS)),
+                output: Some(self.max_output.unwrap_or(SANDBOX_MAX_OUTPUT)),
+                time: Some(self.max_time.unwrap_or(SANDBOX_MAX_TIME)),
+            }
+        } else {
+            Limits {
+                steps: self.max_steps,
+                output: self.max_output,
+                time: self.max_time,
+            }
+        }
+    }
+}
+
+/// The language of the source code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Syntax {
+    #[default]
+    Brainfuck,
+    /// Each brainfuck command is written as a pair of `Ook.`, `Ook?` and `Ook!` words, see
+    /// [`crate::translate_ook`].
+    Ook,
+    /// A tiny imperative language compiled to brainfuck, requires the `lang` feature.
+    Lang,
+}
+
+impl FromStr for Syntax {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "brainfuck" => Ok(Syntax::Brainfuck),
+            "ook" => Ok(Syntax::Ook),
+            "lang" => Ok(Syntax::Lang),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The commands of the brainfuck dialect, which extend the brainfuck commands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Brainfuck,
+    /// Brainfuck extended with `}` and `{` switching between multiple tapes, see
+    /// [`crate::translate_multi_tape`].
+    MultiTape,
+}
+
+impl FromStr for Dialect {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "brainfuck" => Ok(Dialect::Brainfuck),
+            "multi-tape" => Ok(Dialect::MultiTape),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The output of the `compile` mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Emit {
+    /// An executable for the target, see [`Target::backend`].
+    #[default]
+    Elf,
+    /// A standalone rust program, see [`crate::rust::generate`].
+    Rust,
+    /// The SHA-256 digest of the executable, which is printed instead of being written.
+    Hash,
+    /// A static library for C programs, see [`crate::staticlib`].
+    StaticLib,
+    /// A flat binary image running on bare metal, see [`crate::i686::compile_flat`].
+    FlatBin,
+}
+
+impl Emit {
+    /// The backend generating the output for the `target`, or `None` if the target isn't
+    /// supported.
+    pub fn backend(self, target: Target) -> Option<&'static dyn Backend> {
+        match self {
+            Emit::Elf | Emit::Hash => target.backend(),
+            Emit::Rust => Some(&rust::Rust),
+            Emit::StaticLib => target.library_backend(),
+            Emit::FlatBin => target.image_backend(),
+        }
+    }
+}
+
+impl FromStr for Emit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "elf" => Ok(Emit::Elf),
+            "rust" => Ok(Emit::Rust),
+            "hash" => Ok(Emit::Hash),
+            "staticlib" => Ok(Emit::StaticLib),
+            "flat-bin" => Ok(Emit::FlatBin),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The semantics of well-known implementations, which differ in the eof value and the syntax. The
+/// presets don't change the tape, all of them run with 8-bit wrapping registers and the tape of
+/// [`crate::NUM_REGISTERS`] registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compat {
+    /// The original implementation by Urban Müller, its tape of 30000 registers fits into the tape
+    /// of 32768 registers.
+    UrbanMueller,
+    /// Oleg Mazonka's bff, its tape grows on demand, so programs using more than 32768 registers
+    /// leave the tape.
+    Bff,
+    /// The beef interpreter, which leaves the register unchanged at eof like the original. Its
+    /// tape grows on demand, so programs using more than 32768 registers leave the tape.
+    Beef,
+    /// The Ook! language by David Morgan-Mar, run with the tape of 32768 registers.
+    Ook,
+}
+
+impl Compat {
+    const ALL: [Compat; 4] = [Compat::UrbanMueller, Compat::Bff, Compat::Beef, Compat::Ook];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compat::UrbanMueller => "urban-mueller",
+            Compat::Bff => "bff",
+            Compat::Beef => "beef",
+            Compat::Ook => "ook",
+        }
+    }
+
+    pub fn eof(self) -> Eof {
+        match self {
+            Compat::UrbanMueller => Eof::Unchanged,
+            Compat::Bff => Eof::Zero,
+            Compat::Beef => Eof::Unchanged,
+            Compat::Ook => Eof::Unchanged,
+        }
+    }
+
+    pub fn syntax(self) -> Syntax {
+        match self {
+            Compat::UrbanMueller | Compat::Bff | Compat::Beef => Syntax::Brainfuck,
+            Compat::Ook => Syntax::Ook,
+        }
+    }
+}
+
+impl FromStr for Compat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Compat::ALL.into_iter().find(|c| c.name() == s).ok_or(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pass {
+    Zeros,
+    Arithmetic,
+    Jumps,
+    DeadCode,
+    Init,
+    Simplify,
+    Unroll,
+    Schedule,
+    Threading,
+    Compact,
+    Copy,
+    DivMod,
+    Decimal,
+    Bounds,
+    Pipe,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 15] = [
+        Pass::Zeros,
+        Pass::Arithmetic,
+        Pass::Jumps,
+        Pass::DeadCode,
+        Pass::Init,
+        Pass::Simplify,
+        Pass::Unroll,
+        Pass::Schedule,
+        Pass::Threading,
+        Pass::Compact,
+        Pass::Copy,
+        Pass::DivMod,
+        Pass::Decimal,
+        Pass::Bounds,
+        Pass::Pipe,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Zeros => "zeros",
+            Pass::Arithmetic => "arithmetic",
+            Pass::Jumps => "jumps",
+            Pass::DeadCode => "dead-code",
+            Pass::Init => "init",
+            Pass::Simplify => "simplify",
+            Pass::Unroll => "unroll",
+            Pass::Schedule => "schedule",
+            Pass::Threading => "threading",
+            Pass::Compact => "compact",
+            Pass::Copy => "copy",
+            Pass::DivMod => "divmod",
+            Pass::Decimal => "decimal",
+            Pass::Bounds => "bounds",
+            Pass::Pipe => "pipe",
+        }
+    }
+}
+
+impl FromStr for Pass {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pass::ALL.into_iter().find(|p| p.name() == s).ok_or(())
+    }
+}
+
+/// A set of optimization passes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Passes(u16);
+
+impl Passes {
+    pub const NONE: Passes = Passes(0);
+
+    /// The passes enabled at optimization level `-O<level>`:
+    /// - `0`: none
+    /// - `1`: `zeros`, `arithmetic`, `jumps` and `simplify`
+    /// - `2`: additionally `dead-code`, `init`, `schedule`, `threading`, `compact`, `copy`,
+    ///   `divmod`, `decimal`, `bounds` and `pipe`
+    /// - `3`: additionally `unroll`
+    pub fn level(level: u8) -> Passes {
+        let passes: &[Pass] = match level {
+            0 => &[],
+            1 => &[Pass::Zeros, Pass::Arithmetic, Pass::Jumps, Pass::Simplify],
+            2 => &[
+                Pass::Zeros,
+                Pass::Arithmetic,
+                Pass::Jumps,
+                Pass::Simplify,
+                Pass::DeadCode,
+                Pass::Init,
+                Pass::Schedule,
+                Pass::Threading,
+                Pass::Compact,
+                Pass::Copy,
+                Pass::DivMod,
+                Pass::Decimal,
+                Pass::Bounds,
+                Pass::Pipe,
+            ],
+            _ => &Pass::ALL,
+        };
+        let mut set = Passes::NONE;
+        for &p in passes {
+            set.insert(p);
+        }
+        set
+    }
+
+    pub fn contains(self, pass: Pass) -> bool {
+        self.0 & (1 << pass as u16) != 0
+    }
+
+    pub fn insert(&mut self, pass: Pass) {
+        self.0 |= 1 << pass as u16;
+    }
+
+    pub fn remove(&mut self, pass: Pass) {
+        self.0 &= !(1 << pass as u16);
+    }
+
+    /// A bit mask of the contained passes.
+    pub fn bits(self) -> u16 {
+        self.0
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -26,7 +592,73 @@ pub enum Command {
     Format,
     Ir,
     Run,
+    Check,
     Compile,
+    Serve,
+    Selftest,
+    Diff,
+    Reduce,
+    Link,
+    Pipe,
+    Cover,
+    Debug,
+    Explain,
+    Translate,
+    Bundle,
+    BenchOpts,
+    New,
+    Init,
+    Install,
+    Viz,
+    Symex,
+    Test,
+    Fuzz,
+}
+
+impl Command {
+    /// The optimization level used if none was specified.
+    pub fn default_opt_level(self) -> u8 {
+        match self {
+            Command::Format
+            | Command::Translate
+            | Command::Cover
+            | Command::New
+            | Command::Init
+            | Command::Viz
+            | Command::Symex => 0,
+            Command::Check => 1,
+            Command::Serve => 2,
+            Command::Ir
+            | Command::Run
+            | Command::Compile
+            | Command::Selftest
+            | Command::Diff
+            | Command::Reduce
+            | Command::Link
+            | Command::Pipe
+            | Command::Debug
+            | Command::Explain
+            | Command::Bundle
+            | Command::BenchOpts
+            | Command::Install
+            | Command::Test
+            | Command::Fuzz => 3,
+        }
+    }
+}
+
+/// The profile of the project selected by `--release` or `--profile <name>`, the last one wins.
+fn profile_arg(args: &[String]) -> Option<&str> {
+    let mut profile = None;
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "--release" => profile = Some(RELEASE_PROFILE),
+            "--profile" => profile = args.next().map(String::as_str),
+            _ => (),
+        }
+    }
+    profile
 }
 
 macro_rules! input_error {
@@ -40,14 +672,48 @@ macro_rules! input_error {
     }};
 }
 
-pub fn parse_args() -> ControlFlow<ExitCode, (Config, Command, PathBuf)> {
-    let mut args = std::env::args();
-    _ = args.next();
+pub fn parse_args() -> ControlFlow<ExitCode, (Config, Command, Vec<PathBuf>)> {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>().into_iter();
+    // `meta run` is the `run` mode with the semantics self-interpreters expect
+    let mut meta = false;
+    // `build` is the `compile` mode using the manifest of the project in the working directory
+    let mut build = false;
     let command = match args.next().as_deref() {
         Some("format") => Command::Format,
         Some("ir") => Command::Ir,
         Some("run") => Command::Run,
+        Some("check") => Command::Check,
         Some("compile") => Command::Compile,
+        Some("serve") => Command::Serve,
+        Some("selftest") => Command::Selftest,
+        Some("diff") => Command::Diff,
+        Some("reduce") => Command::Reduce,
+        Some("link") => Command::Link,
+        Some("pipe") => Command::Pipe,
+        Some("cover") => Command::Cover,
+        Some("debug") => Command::Debug,
+        Some("explain") => Command::Explain,
+        Some("translate") => Command::Translate,
+        Some("bundle") => Command::Bundle,
+        Some("bench-opts") => Command::BenchOpts,
+        Some("new") => Command::New,
+        Some("init") => Command::Init,
+        Some("install") => Command::Install,
+        Some("viz") => Command::Viz,
+        Some("symex") => Command::Symex,
+        Some("test") => Command::Test,
+        Some("fuzz") => Command::Fuzz,
+        Some("build") => {
+            build = true;
+            Command::Compile
+        }
+        Some("meta") => match args.next().as_deref() {
+            Some("run") => {
+                meta = true;
+                Command::Run
+            }
+            _ => input_error!("the `meta` mode expects the `run` subcommand"),
+        },
         Some("help") => {
             print_help();
             return ControlFlow::Break(ExitCode::SUCCESS);
@@ -60,77 +726,877 @@ pub fn parse_args() -> ControlFlow<ExitCode, (Config, Command, PathBuf)> {
         }
     };
 
-    let mut path = None;
-    let mut config = Config {
-        verbose: 0,
-        print_unoptimized_ir: false,
-        optimize: true,
-        o_zeros: true,
-        o_arithmetic: true,
-        o_jumps: true,
-        o_dead_code: true,
-        o_init: true,
-        o_simplify: true,
+    // the options of the manifest precede the ones passed on the command line, which override them
+    let manifest = match Manifest::load(Path::new(".")) {
+        Some(Ok(manifest))
+            if build || matches!(command, Command::Run | Command::Install | Command::Test) =>
+        {
+            let rest = args.collect::<Vec<_>>();
+            let default_profile = match command {
+                Command::Install => RELEASE_PROFILE,
+                _ => DEV_PROFILE,
+            };
+            let profile = profile_arg(&rest).unwrap_or(default_profile);
+            let Some(manifest_args) = manifest.args(profile) else {
+                input_error!("the profile `{profile}` isn't defined in `{MANIFEST}`");
+            };
+            args = (manifest_args.into_iter().chain(rest))
+                .collect::<Vec<_>>()
+                .into_iter();
+            Some(manifest)
+        }
+        Some(Err(errors))
+            if build || matches!(command, Command::Run | Command::Install | Command::Test) =>
+        {
+            for e in errors {
+                error!("{e}");
+            }
+            return ControlFlow::Break(ExitCode::FAILURE);
+        }
+        _ if build => {
+            input_error!("the `build` mode expects a `{MANIFEST}` in the working directory")
+        }
+        _ => None,
     };
-    for a in args {
+
+    let mut paths = Vec::new();
+    let mut profile_set = false;
+    let mut opt_level = None;
+    let mut pass_overrides = Vec::new();
+    let mut eof_set = false;
+    let mut comment_marker_set = false;
+    let mut config = Config::default();
+    while let Some(a) = args.next() {
         if let Some(n) = a.strip_prefix("--") {
             match n {
                 "verbose" => config.verbose += 1,
                 "print-unoptimized-ir" => config.print_unoptimized_ir = true,
-                "debug" => config.optimize = false,
-                "no-optimize-zeroes" => config.o_zeros = false,
-                "no-optimize-arithmetic" => config.o_arithmetic = false,
-                "no-optimize-jumps" => config.o_jumps = false,
-                "no-optimize-dead-code" => config.o_dead_code = false,
-                "no-optimize-init" => config.o_init = false,
-                "no-optimize-simplify" => config.o_simplify = false,
+                "print-asm" if cfg!(feature = "disasm") => config.print_asm = true,
+                "print-asm" => input_error!("`{a}` requires the `disasm` feature"),
+                "debug" => opt_level = Some(0),
+                "superopt" if cfg!(feature = "superopt") => config.superopt = true,
+                "superopt" => input_error!("`{a}` requires the `superopt` feature"),
+                "verify-passes" => config.verify_passes = true,
+                "warn" | "allow" => match parse_value::<Lint>(args.next()) {
+                    Some(lint) if n == "warn" => config.lints.set(lint, Level::Warn),
+                    Some(lint) => config.lints.set(lint, Level::Allow),
+                    None => input_error!("missing or unknown lint for `{a}`"),
+                },
+                "enable-pass" | "disable-pass" => match parse_value(args.next()) {
+                    Some(pass) => pass_overrides.push((n == "enable-pass", pass)),
+                    None => input_error!("missing or invalid pass name for `{a}`"),
+                },
+                "port" => match parse_value(args.next()) {
+                    Some(port) => config.port = port,
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "max-steps" => match parse_value(args.next()) {
+                    Some(n) => config.max_steps = Some(n),
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "max-output" => match parse_value(args.next()) {
+                    Some(n) => config.max_output = Some(n),
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "max-time" => match parse_value(args.next()).map(Duration::try_from_secs_f64) {
+                    Some(Ok(d)) => config.max_time = Some(d),
+                    _ => input_error!("missing or invalid value for `{a}`"),
+                },
+                "static-fuel" => match parse_value(args.next()) {
+                    Some(n) => config.static_fuel = n,
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "max-input" => match parse_value(args.next()) {
+                    Some(n) => config.max_input = Some(n),
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "max-depth" => match parse_value(args.next()) {
+                    Some(n) => config.max_depth = Some(n),
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "sandbox" => config.sandbox = true,
+                "count-cycles" => config.count_cycles = true,
+                "cache" => config.cache = true,
+                "watch" => config.watch = true,
+                "eof" => match parse_value(args.next()) {
+                    Some(eof) => {
+                        config.eof = eof;
+                        eof_set = true;
+                    }
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "syntax" => match parse_value(args.next()) {
+                    Some(syntax) => config.syntax = syntax,
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "dialect" => match parse_value(args.next()) {
+                    Some(dialect) => config.dialect = dialect,
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "recover" => config.recover = true,
+                "strict" => config.strict = true,
+                "strict-syntax" => config.strict_syntax = true,
+                "comment-marker" => match args.next() {
+                    Some(marker) => {
+                        config.comment_marker = marker;
+                        comment_marker_set = true;
+                    }
+                    None => input_error!("missing value for `{a}`"),
+                },
+                "interactive" => config.interactive = true,
+                "sanitize-output" => config.sanitize_output = true,
+                "install-dir" => match args.next() {
+                    Some(path) => config.install_dir = Some(PathBuf::from(path)),
+                    None => input_error!("missing path for `{a}`"),
+                },
+                "release" => profile_set = true,
+                "profile" => match args.next() {
+                    Some(_) => profile_set = true,
+                    None => input_error!("missing value for `{a}`"),
+                },
+                "keep-comments" => config.keep_comments = true,
+                "expect-output" => match args.next() {
+                    Some(output) => config.expect_output = Some(PathBuf::from(output)),
+                    None => input_error!("missing path or output for `{a}`"),
+                },
+                "expect-exit" => match parse_value(args.next()) {
+                    Some(code) => config.expect_exit = Some(code),
+                    None => input_error!("missing or invalid exit code for `{a}`"),
+                },
+                "expect-crash" => config.expect_crash = true,
+                "expect-warning" => match parse_value(args.next()) {
+                    Some(lint) => config.expect_warning = Some(lint),
+                    None => input_error!("missing or invalid lint for `{a}`"),
+                },
+                "no-ir" => config.no_ir = true,
+                "input-encoding" => match parse_value(args.next()) {
+                    Some(encoding) => config.input_encoding = encoding,
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "output" => match args.next() {
+                    Some(path) => config.output = Some(PathBuf::from(path)),
+                    None => input_error!("missing path for `{a}`"),
+                },
+                "pipe" => config.pipe = true,
+                "compiled" => config.compiled = true,
+                "jobs" => match parse_value(args.next()) {
+                    Some(0) | None => input_error!("missing or invalid value for `{a}`"),
+                    Some(n) => config.jobs = Some(n),
+                },
+                "seconds" => match parse_value(args.next()).map(Duration::try_from_secs_f64) {
+                    Some(Ok(d)) => config.fuzz_duration = Some(d),
+                    _ => input_error!("missing or invalid value for `{a}`"),
+                },
+                "seed" => match parse_value(args.next()) {
+                    Some(n) => config.seed = Some(n),
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "assert" => match args.next() {
+                    Some(assertion) => config.asserts.push(assertion),
+                    None => input_error!("missing assertion for `{a}`"),
+                },
+                "trace-hash" => config.trace_hash = true,
+                "mem-stats" => config.mem_stats = true,
+                "dump-core" => config.dump_core = true,
+                "tape-log" => match args.next() {
+                    Some(path) => config.tape_log = Some(PathBuf::from(path)),
+                    None => input_error!("missing path for `{a}`"),
+                },
+                "tape-interval" => match parse_value(args.next()) {
+                    Some(0) | None => input_error!("missing or invalid value for `{a}`"),
+                    Some(n) => config.tape_interval = Some(n),
+                },
+                "json" => config.json = true,
+                "termination" => config.termination = true,
+                "at" => match args.next().as_deref().and_then(|a| a.split_once(':')) {
+                    Some((line, col)) => match (line.parse(), col.parse()) {
+                        (Ok(line), Ok(col)) => config.at = Some((line, col)),
+                        _ => input_error!("invalid location for `{a}`"),
+                    },
+                    None => input_error!("missing or invalid location for `{a}`"),
+                },
+                "core" => match args.next() {
+                    Some(path) => config.core = Some(PathBuf::from(path)),
+                    None => input_error!("missing path for `{a}`"),
+                },
+                "target" => match args.next().map(|t| t.parse()) {
+                    Some(Ok(target)) => config.target = target,
+                    Some(Err(e)) => input_error!("{e}"),
+                    None => input_error!("missing value for `{a}`"),
+                },
+                "emit" => match parse_value(args.next()) {
+                    Some(emit) => config.emit = emit,
+                    None => input_error!("missing or invalid value for `{a}`"),
+                },
+                "checkpoint" => match parse_value(args.next()) {
+                    Some(0) | None => input_error!("missing or invalid value for `{a}`"),
+                    Some(secs) => config.checkpoint = Some(secs),
+                },
+                "org" => match args.next().as_deref().and_then(parse_address) {
+                    Some(org) => config.org = Some(org),
+                    None => input_error!("missing or invalid address for `{a}`"),
+                },
+                "multiboot" => config.multiboot = true,
+                "compat" => match parse_value::<Compat>(args.next()) {
+                    Some(compat) => {
+                        config.eof = compat.eof();
+                        config.syntax = compat.syntax();
+                        eof_set = true;
+                    }
+                    None => input_error!("missing or invalid preset for `{a}`"),
+                },
                 _ => input_error!("unexpected argument `{a}`"),
             }
+        } else if a == "-W" || a == "-A" {
+            match parse_value::<Lint>(args.next()) {
+                Some(lint) if a == "-W" => config.lints.set(lint, Level::Warn),
+                Some(lint) => config.lints.set(lint, Level::Allow),
+                None => input_error!("missing or unknown lint for `{a}`"),
+            }
+        } else if a == "-o" {
+            match args.next() {
+                Some(path) => config.output = Some(PathBuf::from(path)),
+                None => input_error!("missing path for `{a}`"),
+            }
+        } else if let Some(level) = a.strip_prefix("-O") {
+            match level.parse() {
+                Ok(level @ 0..=3) => opt_level = Some(level),
+                _ => input_error!("invalid optimization level `{a}`"),
+            }
         } else if let Some(n) = a.strip_prefix('-') {
             for c in n.chars() {
                 match c {
                     'v' => config.verbose += 1,
                     'u' => config.print_unoptimized_ir = true,
-                    'd' => config.optimize = false,
+                    'd' => opt_level = Some(0),
                     _ => input_error!("unexpected flag `{c}`"),
                 }
             }
         } else {
-            if path.is_some() {
+            if matches!(command, Command::Serve | Command::Selftest) {
                 input_error!("unexpected positional argument `{a}`");
             }
-            path = Some(a);
+            paths.push(PathBuf::from(a));
+        }
+    }
+    config.passes = Passes::level(opt_level.unwrap_or(command.default_opt_level()));
+    config.strict_eof = config.strict && !eof_set;
+    config.explicit_eof = eof_set;
+    if meta {
+        if !eof_set {
+            config.eof = Eof::Zero;
+        }
+        config.unbuffered = true;
+    }
+    for (enable, pass) in pass_overrides {
+        if enable {
+            config.passes.insert(pass);
+        } else {
+            config.passes.remove(pass);
         }
     }
-    let Some(path) = path else {
+
+    if profile_set && manifest.is_none() {
+        input_error!(
+            "`--release` and `--profile` can only be used with the `build`, `run` and `install` modes inside of a project"
+        );
+    }
+    // the `test` mode runs the tests of the project instead
+    let project_main = (manifest.as_ref()).filter(|_| paths.is_empty() && command != Command::Test);
+    if let Some(manifest) = project_main {
+        paths.push(manifest.main.clone());
+    }
+    if paths.is_empty()
+        && !matches!(
+            command,
+            Command::Serve | Command::Selftest | Command::Init | Command::Test
+        )
+    {
         input_error!("missing second positional argument <path>");
+    }
+    if config.watch && command == Command::Serve {
+        input_error!("`--watch` can't be used with the `serve` mode");
+    }
+    if config.watch && command == Command::Selftest {
+        input_error!("`--watch` can't be used with the `selftest` mode");
+    }
+    if command == Command::Diff && paths.len() != 2 {
+        input_error!("the `diff` mode expects exactly two paths");
+    }
+    if config.watch && command == Command::Diff {
+        input_error!("`--watch` can't be used with the `diff` mode");
+    }
+    if command == Command::Reduce && paths.len() != 1 {
+        input_error!("the `reduce` mode expects exactly one path");
+    }
+    let num_expectations = config.expect_output.is_some() as u8
+        + config.expect_crash as u8
+        + config.expect_warning.is_some() as u8;
+    if command == Command::Reduce && num_expectations != 1 {
+        input_error!(
+            "the `reduce` mode expects one of `--expect-output`, `--expect-crash` or `--expect-warning`"
+        );
+    }
+    if config.watch && command == Command::Reduce {
+        input_error!("`--watch` can't be used with the `reduce` mode");
+    }
+    if command == Command::Link && paths.len() < 2 {
+        input_error!("the `link` mode expects at least two paths");
+    }
+    if config.watch && command == Command::Link {
+        input_error!("`--watch` can't be used with the `link` mode");
+    }
+    if config.sandbox && command == Command::Link {
+        input_error!("`--sandbox` can't be used with the `link` mode");
+    }
+    if command == Command::Pipe && paths.len() < 2 {
+        input_error!("the `pipe` mode expects at least two paths");
+    }
+    if config.watch && command == Command::Pipe {
+        input_error!("`--watch` can't be used with the `pipe` mode");
+    }
+    if command == Command::Cover && paths.len() != 1 {
+        input_error!("the `cover` mode expects exactly one path");
+    }
+    if command == Command::Cover && (config.watch || config.no_ir) {
+        input_error!("`--watch` and `--no-ir` can't be used with the `cover` mode");
+    }
+    if command == Command::Cover && (!config.is_brainfuck() || config.recover) {
+        input_error!("the `cover` mode only supports plain brainfuck without `--recover`");
+    }
+    if command == Command::Debug && (paths.len() != 1 || config.core.is_none()) {
+        input_error!("the `debug` mode expects exactly one path and `--core`");
+    }
+    if command == Command::Explain && (paths.len() != 1 || config.at.is_none()) {
+        input_error!("the `explain` mode expects exactly one path and `--at`");
+    }
+    if command == Command::Explain && (!config.is_brainfuck() || config.recover) {
+        input_error!("the `explain` mode only supports plain brainfuck without `--recover`");
+    }
+    if config.watch && command == Command::Explain {
+        input_error!("`--watch` can't be used with the `explain` mode");
+    }
+    if config.watch && command == Command::Debug {
+        input_error!("`--watch` can't be used with the `debug` mode");
+    }
+    if command == Command::Bundle && paths.len() != 1 {
+        input_error!("the `bundle` mode expects exactly one path");
+    }
+    if config.watch && command == Command::Bundle {
+        input_error!("`--watch` can't be used with the `bundle` mode");
+    }
+    if config.json
+        && !matches!(command, Command::Check | Command::BenchOpts)
+        && !(command == Command::Run && config.count_cycles)
+    {
+        input_error!(
+            "`--json` can only be used with the `check` and `bench-opts` modes, or `--count-cycles`"
+        );
+    }
+    if config.termination && command != Command::Check {
+        input_error!("`--termination` can only be used with the `check` mode");
+    }
+    if config.termination && !config.is_brainfuck() {
+        input_error!("`--termination` can only be used with plain brainfuck");
+    }
+    if command == Command::BenchOpts && paths.len() != 1 {
+        input_error!("the `bench-opts` mode expects exactly one path");
+    }
+    if command == Command::BenchOpts && (config.watch || config.no_ir) {
+        input_error!("`--watch` and `--no-ir` can't be used with the `bench-opts` mode");
+    }
+    let backend = |target: Target| match command {
+        Command::Compile => Some(config.emit.backend(target)),
+        Command::Install => Some(target.backend()),
+        // linked executables are only generated by the x86-64 backend
+        Command::Link => Some(target.backend().filter(|_| target.arch == Arch::X86_64)),
+        _ => None,
     };
+    if backend(config.target).is_some_and(|b| b.is_none()) {
+        let target = config.target;
+        let supported = (TARGETS.iter())
+            .filter(|&&t| backend(t).flatten().is_some())
+            .map(|t| format!("`{t}`"))
+            .collect::<Vec<_>>();
+        let supported = supported.join(", ");
+        input_error!(
+            "the target `{target}` isn't supported, the supported targets are {supported}"
+        );
+    }
+    if command == Command::Install && paths.len() != 1 {
+        input_error!("the `install` mode expects exactly one path");
+    }
+    if command == Command::Install && config.emit != Emit::Elf {
+        input_error!("the `install` mode can only install `elf` executables");
+    }
+    if command == Command::Install && config.output.is_none() {
+        // the executable is named after the project, or the source file
+        let name = match project_main {
+            Some(manifest) => Some(manifest.name.clone().into()),
+            None => paths[0].file_stem().map(|s| s.to_owned()),
+        };
+        let Some(name) = name else {
+            let path = paths[0].display();
+            input_error!("failed to derive the name of the executable from `{path}`");
+        };
+        let dir = match (&config.install_dir, std::env::var_os("HOME")) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(home)) => Path::new(&home).join(".local").join("bin"),
+            (None, None) => input_error!("`$HOME` isn't set, pass `--install-dir`"),
+        };
+        config.output = Some(dir.join(name));
+    }
+    if command == Command::New && paths.len() != 1 {
+        input_error!("the `new` mode expects exactly one path, the directory of the project");
+    }
+    if command == Command::Init && paths.len() > 1 {
+        input_error!("the `init` mode expects at most one path, the directory of the project");
+    }
+    if meta && paths.len() != 1 {
+        input_error!("the `meta run` mode expects exactly one path, the self-interpreter");
+    }
+    if config.sandbox && command == Command::Compile && config.emit == Emit::StaticLib {
+        input_error!("`--sandbox` can't be used together with `--emit staticlib`");
+    }
+    let flat_bin = command == Command::Compile && config.emit == Emit::FlatBin;
+    if config.sandbox && flat_bin {
+        input_error!("`--sandbox` can't be used together with `--emit flat-bin`");
+    }
+    if (config.org.is_some() || config.multiboot) && !flat_bin {
+        input_error!(
+            "`--org` and `--multiboot` can only be used with the `compile` mode and `--emit flat-bin`"
+        );
+    }
+    // the stack and the tape follow the image, and all of them have to fit below 4GiB
+    if config.org.is_some_and(|org| org >= 0xC000_0000) {
+        input_error!("the address passed to `--org` has to be below 0xc0000000");
+    }
+    let executable = command == Command::Compile && matches!(config.emit, Emit::Elf | Emit::Hash);
+    if config.checkpoint.is_some() && !executable {
+        input_error!(
+            "`--checkpoint` can only be used with the `compile` mode and `--emit elf` or `hash`"
+        );
+    }
+    if config.checkpoint.is_some() && config.sandbox {
+        input_error!("`--checkpoint` can't be used together with `--sandbox`");
+    }
+    if config.interactive && command != Command::Run && !executable {
+        input_error!(
+            "`--interactive` can only be used with the `run` mode, or the `compile` mode and `--emit elf` or `hash`"
+        );
+    }
+    if executable
+        && config.target.arch == Arch::I686
+        && (config.checkpoint.is_some() || config.interactive)
+    {
+        input_error!("`--checkpoint` and `--interactive` can't be used with the `i686` target");
+    }
+    if config.sanitize_output && command != Command::Run {
+        input_error!("`--sanitize-output` can only be used with the `run` mode");
+    }
+    if config.input_encoding != InputEncoding::Bytes && command != Command::Run {
+        input_error!("`--input-encoding` can only be used with the `run` mode");
+    }
+    if config.expect_output.is_some() && !matches!(command, Command::Run | Command::Reduce) {
+        input_error!("`--expect-output` can only be used with the `run` and `reduce` modes");
+    }
+    if config.expect_exit.is_some() && command != Command::Run {
+        input_error!("`--expect-exit` can only be used with the `run` mode");
+    }
+    if (config.expect_output.is_some() || config.expect_exit.is_some()) && config.interactive {
+        input_error!(
+            "`--expect-output` and `--expect-exit` can't be used together with `--interactive`"
+        );
+    }
+    if config.interactive && config.sandbox {
+        input_error!("`--interactive` can't be used together with `--sandbox`");
+    }
+    if config.strict && config.recover {
+        input_error!("`--strict` can't be used together with `--recover`");
+    }
+    if config.strict && config.no_ir {
+        input_error!("`--strict` can't be used together with `--no-ir`");
+    }
+    if comment_marker_set && !config.strict_syntax {
+        input_error!("`--comment-marker` requires `--strict-syntax`");
+    }
+    if config.comment_marker.is_empty()
+        || (config.comment_marker.bytes())
+            .any(|b| b"<>+-.,[]".contains(&b) || b.is_ascii_whitespace())
+    {
+        input_error!("the comment marker can't be empty, or contain commands or whitespace");
+    }
+    if config.strict_syntax && !config.is_brainfuck() {
+        input_error!("`--strict-syntax` can only be used with plain brainfuck");
+    }
+    if config.strict_syntax && config.keep_comments {
+        input_error!("`--strict-syntax` can't be used together with `--keep-comments`");
+    }
+    if config.keep_comments && config.recover {
+        input_error!("`--keep-comments` can't be used together with `--recover`");
+    }
+    if config.keep_comments && !config.is_brainfuck() {
+        input_error!("`--keep-comments` can only be used with plain brainfuck");
+    }
+    if config.dialect != Dialect::Brainfuck && config.syntax != Syntax::Brainfuck {
+        input_error!("`--dialect` can only be used with the brainfuck syntax");
+    }
+    if config.syntax == Syntax::Lang && !cfg!(feature = "lang") {
+        input_error!("`--syntax lang` requires the `lang` feature");
+    }
+    if config.trace_hash && config.no_ir {
+        input_error!("`--trace-hash` can't be used together with `--no-ir`");
+    }
+    if config.mem_stats && config.no_ir {
+        input_error!("`--mem-stats` can't be used together with `--no-ir`");
+    }
+    if config.dump_core && config.no_ir {
+        input_error!("`--dump-core` can't be used together with `--no-ir`");
+    }
+    if config.tape_log.is_some() && command != Command::Run {
+        input_error!("`--tape-log` can only be used with the `run` mode");
+    }
+    if config.tape_log.is_some() && config.no_ir {
+        input_error!("`--tape-log` can't be used together with `--no-ir`");
+    }
+    if config.tape_interval.is_some() && config.tape_log.is_none() {
+        input_error!("`--tape-interval` can only be used together with `--tape-log`");
+    }
+    if command == Command::Symex && paths.len() != 1 {
+        input_error!("the `symex` mode expects exactly one path");
+    }
+    if command == Command::Symex && (!config.is_brainfuck() || config.recover) {
+        input_error!("the `symex` mode only supports plain brainfuck without `--recover`");
+    }
+    if config.watch && command == Command::Symex {
+        input_error!("`--watch` can't be used with the `symex` mode");
+    }
+    if config.max_input.is_some() && !matches!(command, Command::Symex | Command::Fuzz) {
+        input_error!("`--max-input` can only be used with the `symex` and `fuzz` modes");
+    }
+    if config.max_depth.is_some() && command != Command::Symex {
+        input_error!("`--max-depth` can only be used with the `symex` mode");
+    }
+    if command == Command::Viz && paths.len() != 1 {
+        input_error!("the `viz` mode expects exactly one path, the tape log");
+    }
+    if config.watch && command == Command::Viz {
+        input_error!("`--watch` can't be used with the `viz` mode");
+    }
+    if config.compiled && command != Command::Test {
+        input_error!("`--compiled` can only be used with the `test` mode");
+    }
+    if config.jobs.is_some() && !matches!(command, Command::Run | Command::Check | Command::Test) {
+        input_error!("`--jobs` can only be used with the `run`, `check` and `test` modes");
+    }
+    if command == Command::Fuzz && paths.len() != 1 {
+        input_error!("the `fuzz` mode expects exactly one path");
+    }
+    if config.watch && command == Command::Fuzz {
+        input_error!("`--watch` can't be used with the `fuzz` mode");
+    }
+    let fuzz_options = config.fuzz_duration.is_some() || config.seed.is_some();
+    if (fuzz_options || !config.asserts.is_empty()) && command != Command::Fuzz {
+        input_error!("`--seconds`, `--seed` and `--assert` can only be used with the `fuzz` mode");
+    }
+    if config.watch && command == Command::Test {
+        input_error!("`--watch` can't be used with the `test` mode");
+    }
+    if config.no_ir && config.count_cycles {
+        input_error!("`--no-ir` can't be used together with `--count-cycles`");
+    }
 
-    ControlFlow::Continue((config, command, path.into()))
+    ControlFlow::Continue((config, command, paths))
+}
+
+fn parse_value<T: FromStr>(value: Option<String>) -> Option<T> {
+    value?.parse().ok()
+}
+
+/// Parse a decimal address, or a hexadecimal one with a `0x` prefix.
+fn parse_address(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
 }
 
 fn print_help() {
     eprintln!(
         "\
-brainfuck <mode> [<option>] <path>
+brainfuck <mode> [<option>] [<path>...]
 
 {ANSII_UNDERLINED}modes{ANSII_CLEAR}
     format          pretty print brainfuck code
     ir              print the intermediate representation
     run             interpret the ir
-    compile         generate an ELF64 x86-64 system-v executable
+    check           check programs for errors without running them
+    compile         generate an ELF64 x86-64 or ELF32 i686 system-v executable
+    serve           serve an http api to run programs, `POST /run?input=<input>`
+                    with the program as body
+    selftest        run a built-in conformance corpus through the interpreter, the
+                    jit and the compiler
+    diff            compare the optimized ir of two programs, and their outputs for
+                    generated inputs
+    reduce          find a minimal program that still produces the expected output
+                    or still crashes, when run with an empty input
+    link            generate a single executable running the programs one after
+                    another, each with a fresh tape
+    pipe            interpret the programs as a pipeline, the output of each one is
+                    the input of the next one
+    cover           run a program and reprint its source code with the tokens that
+                    were never executed highlighted
+    debug           inspect the state stored in a core file written by `--dump-core`
+                    for post-mortem debugging
+    explain         report why the loop at `--at` was or wasn't rewritten by the loop
+                    passes
+    translate       print the brainfuck code a program of another syntax or dialect
+                    translates to
+    bundle          generate an executable embedding the optimized program and the
+                    interpreter, for platforms `compile` doesn't support
+    bench-opts      run a program with increasingly optimized configurations, and
+                    print the number of executed instructions and the time of each,
+                    the last one interprets `-O3` using threaded dispatch
+    meta run        run a brainfuck self-interpreter like `examples/dbfi.b`, with
+                    `--eof zero` and unbuffered output, its input is the interpreted
+                    program followed by `!` and the input of that program
+    new             create a project in a new directory, with a `bf.toml` manifest
+                    and a `main.bf` program
+    init            create a project in an existing directory, the working directory
+                    by default
+    build           compile the program of the project in the working directory
+    install         compile a program or the program of the project in the working
+                    directory with release settings, and place the executable in
+                    `--install-dir`, named after the program or the project
+    viz             render a tape log written by `--tape-log` as a heatmap image, with
+                    a column per register and a row per snapshot
+    symex           symbolically execute a program with symbolic input bytes, and
+                    print the inputs leading to each path, its output and whether it
+                    finished or left the tape, experimental
+    test            run the programs in `tests` with the input of the adjacent `.in`
+                    file, compare their output to the adjacent `.out` file, and print a
+                    summary, the test cases of the project are also run
+    fuzz            run a program with random inputs, and report an input that makes
+                    it leave the tape, exceed the step limit, crash the interpreter or
+                    break an assertion passed with `--assert`
     help            print this help message
 
+multiple paths can be passed, directories are searched recursively for `.b` and `.bf` files
+
+inside of a project, `build`, `run`, `install` and `test` use the options in the `[options]`
+section of its `bf.toml` and in the `[profile.<name>]` section of the profile as defaults, and
+run or install the program of the project if no path is passed, `install` uses the `release`
+profile by default, `test` also runs the test cases of the `[test.<name>]` sections, which
+set the `main` program, the `input` and `output` files, and the expected `exit` code
+
 {ANSII_UNDERLINED}options{ANSII_CLEAR}
     -v,--verbose                change verbosity level via number of occurences [0..=3]
     -u,--print-unoptimized-ir   print the ir before optimizations are applied
-    -d,--debug                  disable all optimizations
-       --no-optimize-zeros      disable zeroing optimization
-       --no-optimize-arithmetic disable arithmetic optimizations
-       --no-optimize-jumps      disable redundant jump elmination
-       --no-optimize-dead-code  disable dead code elmination
-       --no-optimize-init       disable state initialization optimization
-       --no-optimize-simplify   disable code simplification
+       --print-asm              print the disassembly of the compiled code
+                                (requires the `disasm` feature)
+    -O<level>                   optimization level [0..=3], defaults to 3 for `ir`, `run`,
+                                `compile`, `install`, `selftest`, `diff`, `reduce`, `link`,
+                                `pipe`, `debug`, `explain`, `bundle`, `test` and `fuzz`, 2 for
+                                `serve` and 1 for `check`
+    -d,--debug                  disable all optimizations, same as `-O0`
+       --enable-pass <pass>     enable an optimization pass regardless of the level
+       --disable-pass <pass>    disable an optimization pass regardless of the level
+    -W,--warn <lint>            report the lint as a warning, see below
+    -A,--allow <lint>           don't report the lint
+       --superopt               replace straight-line code with shorter equivalent code found
+                                using equality saturation, experimental
+                                (requires the `superopt` feature)
+       --verify-passes          check that every rewrite of the `arithmetic`, `simplify`,
+                                `copy`, `zeros` and `pipe` passes behaves like the original
+                                code on random tapes, rewrites that don't are reported and
+                                skipped
+       --static-fuel <n>        maximum number of instructions executed at compile time by the
+                                `init`, `dead-code` and `unroll` passes, the rest of the program
+                                is left to run time [default: 16777216]
+       --port <port>            port used by the `serve` mode [default: 8080]
+       --max-steps <n>          maximum number of executed instructions
+                                [`serve` default: 100000000, `--sandbox` default: 10000000000]
+       --max-output <n>         maximum number of output bytes
+                                [`serve` default: 65536, `--sandbox` default: 16777216]
+       --max-time <secs>        maximum wall time of the interpreter
+                                [`--sandbox` default: 10]
+       --max-input <n>          number of symbolic input bytes of the `symex` mode, longer
+                                inputs aren't explored, and maximum length of the inputs of
+                                the `fuzz` mode [`symex` default: 4, `fuzz` default: 64]
+       --max-depth <n>          number of times each path of the `symex` mode forks at loops
+                                depending on the input [default: 32]
+       --sandbox                enforce limits on steps, output, wall time and the tape when
+                                interpreting, and restrict compiled executables to the read,
+                                write and exit syscalls using seccomp
+       --count-cycles           print the number of executed instructions per opcode, and their
+                                estimated cost per class of work (dispatch, memory, i/o) and
+                                per opcode after running the program
+       --trace-hash             print a hash of the instruction pointer, the register pointer
+                                and the current register after every executed instruction
+                                after running the program
+       --mem-stats              print the range of accessed registers and the size of the tape
+                                after running the program, or the statically known range when
+                                compiling it
+       --dump-core              write the tape, the pointers and the recently executed
+                                instructions to `<path>.bfcore` when the program exceeds a
+                                limit, which slows down the interpreter
+       --tape-log <path>        write snapshots of the tape to a tape log while running the
+                                program, which the `viz` mode renders
+       --tape-interval <n>      number of executed instructions between the snapshots of
+                                `--tape-log` [default: 10000]
+       --json                   print the results of the `check` and `bench-opts` modes and of
+                                `--count-cycles` as json, one object per program
+       --termination            report whether each loop terminates in the `check` mode:
+                                `terminates`, `possibly non-terminating` or `unknown`
+       --core <path>            core file inspected by the `debug` mode
+       --at <line:col>          location of the loop in the `explain` mode, as printed in
+                                diagnostics, any command inside the loop can be used
+       --cache                  cache the optimized ir in `$XDG_CACHE_HOME/bf`, keyed by the
+                                source code and optimization options, and the machine code
+                                of compiled programs, so only the top-level loops that
+                                changed are compiled again
+       --watch                  rerun whenever one of the programs is modified
+       --eof <value>            value stored by input instructions at the end of the input:
+                                `unchanged`, `zero` or `max` [default: unchanged]
+       --input-encoding <enc>   how the input of the `run` mode is read: as `bytes`, as
+                                `utf8-codepoints` truncated to 8 bits, or as `hex` pairs
+                                [default: bytes]
+       --syntax <syntax>        language of the source code: `brainfuck`, `ook` or `lang`
+                                (requires the `lang` feature) [default: brainfuck]
+       --dialect <dialect>      commands of the brainfuck dialect: `brainfuck` or `multi-tape`,
+                                see below [default: brainfuck]
+       --compat <preset>        use the semantics of a well-known implementation, see below
+       --recover                recover from unmatched brackets by dropping stray closing ones
+                                and closing open ones at the end, and report them as warnings
+                                instead of errors
+       --strict                 reject programs relying on behavior that differs between
+                                implementations: accessing registers left of the first one or
+                                beyond the 30000th, and reading past the end of the input
+                                unless `--eof` or `--compat` is passed
+       --strict-syntax          reject programs containing other bytes than commands,
+                                whitespace and comments, which start with the comment marker
+                                and end at the end of the line, commands in comments are ignored
+       --comment-marker <text>  start of the comments allowed by `--strict-syntax`
+                                [default: #]
+       --keep-comments          keep the comments when formatting programs
+       --emit <kind>            output of the `compile` mode: an `elf` executable, a
+                                standalone `rust` program, the sha-256 `hash` of the
+                                executable, which is reproducible, a `staticlib` and a C
+                                header declaring `int bf_prog_main(bf_io *io)`, or a
+                                `flat-bin` image of 32-bit x86 code running on bare metal,
+                                see below [default: elf]
+       --target <triple>        platform of the executables generated by the `compile`,
+                                `install` and `link` modes, like `x86_64-unknown-linux-gnu`
+                                or `x86_64-linux-elf`, see below [default: the host]
+       --checkpoint <secs>      make `elf` executables save their state to `<name>.checkpoint`
+                                in the working directory every few seconds, and resume from it
+                                when started again
+       --org <address>          address `flat-bin` images are loaded at, decimal or
+                                hexadecimal with a `0x` prefix [default: 0x100000]
+       --multiboot              start `flat-bin` images with a multiboot header, so they can
+                                be booted by grub or `qemu -kernel`
+    -o,--output <path>          output path of the `link`, `bundle`, `install` and `viz`
+                                modes, `viz` writes a PPM image if it has the `ppm` extension
+                                [default: the first path with the `elf` extension, without
+                                an extension for `bundle`, or the `png` extension for `viz`]
+       --install-dir <path>     directory the `install` mode places executables in
+                                [default: ~/.local/bin]
+       --pipe                   pass the output of each program as input to the next one in
+                                the `link` mode
+       --expect-output <path>   output the program has to produce in the `run` and `reduce`
+                                modes, the contents of the file at the path if it exists,
+                                otherwise the argument itself, `run` fails with a diff if
+                                the output differs
+       --expect-exit <code>     exit code the program has to exit with in the `run` mode, 0
+                                if it finishes and 1 if it exceeds a limit [default: 0]
+       --expect-crash           the program has to crash while being optimized or interpreted
+                                in the `reduce` mode
+       --expect-warning <lint>  lint the program has to be reported by in the `reduce` mode
+       --compiled               also compile the tests of the `test` mode and run the
+                                executables, except for tests expected to exceed a limit
+       --jobs <n>               number of threads running the tests of the `test` mode, and
+                                multiple programs in the `run` and `check` modes, whose output
+                                is written in order, each program run in parallel reads all of
+                                the input [default: the number of cpus]
+       --seconds <n>            time spent running inputs in the `fuzz` mode [default: 10]
+       --seed <n>               seed of the random inputs of the `fuzz` mode
+                                [default: the current time]
+       --assert <assertion>     an assertion the input and output of a finished run has to
+                                hold in the `fuzz` mode, comparing values with `==`, `!=`,
+                                `<`, `<=`, `>` or `>=`, or testing `ascii(<value>)`, values
+                                are `in`, `out`, numbers, quoted strings, and `len`, `sorted`,
+                                `reverse` or `trim` of a value, e.g. `out == sorted(in)`, can
+                                be passed multiple times
+       --no-ir                  interpret the tokens directly in the `run` mode, without
+                                building and optimizing the ir
+       --interactive            write the output of the `run` mode or compiled executables
+                                immediately, and if stdin is a terminal, read each key when it's
+                                pressed instead of waiting for enter
+       --sanitize-output        escape control characters like escape sequences and the bell
+                                in the output of the `run` mode if it goes to a terminal, so
+                                untrusted programs can't control the terminal
+       --release                use the `release` profile of the project, see below
+       --profile <name>         use a profile defined in the `bf.toml` of the project
+                                [default: dev, release for `install`]
+
+{ANSII_UNDERLINED}passes{ANSII_CLEAR}                                                              level
+    zeros           replace loops clearing a register                   1
+    arithmetic      replace loops with arithmetic instructions          1
+    jumps           redundant jump elmination                           1
+    simplify        combine and simplify instructions                   1
+    dead-code       dead code elmination                                2
+    init            constant state and output of the program start      2
+    schedule        scheduling of pointer moves in compiled code        2
+    threading       jump directly to the destination of jump chains     2
+    compact         remove redundant jumps before interpreting          2
+    copy            replace copies of register ranges                   2
+    divmod          native division for the divmod loop idiom           2
+    decimal         replace routines printing a register as decimal     2
+    bounds          skip bounds checks and shrink the tape if provable  2
+    pipe            copy the input to the output in chunks for `[.,]`   2
+    unroll          unrolling of loops with a constant trip count       3
+
+{ANSII_UNDERLINED}lints{ANSII_CLEAR}                                                                   default
+    infinite_loop               loops that never terminate once entered warn
+    tape_underflow              moves left of the first register        warn
+    unreachable_code            loops that are never entered            warn
+    always_true_condition       entered loops that never terminate      warn
+    unbalanced_pointer_in_loop  loops moving the pointer each iteration allow
+
+lints are reported by the `check` mode
+
+{ANSII_UNDERLINED}compat presets{ANSII_CLEAR}                                                          eof
+    urban-mueller   the original implementation                         unchanged
+    bff             Oleg Mazonka's bff                                  zero
+    beef            the beef interpreter                                unchanged
+    ook             Ook! syntax                                         unchanged
+
+all presets use 8-bit wrapping registers and the tape of 32768 registers, which covers the 30000
+registers of the original implementation, programs using more registers of the growing tapes of
+bff and beef leave the tape
+
+the supported targets are `x86_64-linux-elf` and `i686-linux-elf`, whose executables don't
+support `--checkpoint`, `--interactive` and the `link` mode, static libraries support
+`x86_64-linux-elf` and `x86_64-none-elf`, and `flat-bin` images all `x86_64` and `i686` targets
+like `i686-none-bin`, `i686` executables leaving the tape are killed by `SIGSEGV` instead of
+reporting the instruction that left it
+
+the `multi-tape` dialect extends brainfuck with `}}` and `{{`, which switch to the next and the
+previous tape, all tapes share the position of the head and loops have to end on the tape they
+started on
+
+brainfuck programs can set the semantics they expect in pragmas at the top of the file, like
+`#[bf: tape=32768, cells=8, eof=zero]`, which override the defaults but not the options passed on
+the command line or in the manifest, only 8-bit cells and tapes of 32768 registers are supported
+
+compiled `elf` executables buffer their output unless `--interactive` is passed, `x86_64`
+executables write it when interrupted by `SIGINT`, and print the current instruction and the
+registers to stderr when receiving `SIGUSR1`
+
+`flat-bin` images run in 32-bit protected mode without paging, starting at their first byte or
+at the entry of their multiboot header, they read and write the serial port COM1 at 115200 baud,
+where the end of transmission byte 0x04 ends the input, keep their stack and tape in the memory
+following the image, and halt once the program finished
     "
     );
 }
@@ -151,8 +1617,10 @@ pub fn print_brainfuck_code(instructions: &[Instruction]) {
             Instruction::Inc(_, _) => unreachable!(),
             Instruction::Dec(0, n) => println!("{:-<width$}", "", width = n as usize),
             Instruction::Dec(_, _) => unreachable!(),
-            Instruction::Output => println!("."),
-            Instruction::Input => println!(","),
+            Instruction::Output(0) => println!("."),
+            Instruction::Output(_) => unreachable!(),
+            Instruction::Input(0) => println!(","),
+            Instruction::Input(_) => unreachable!(),
             Instruction::JumpZ(_) => println!("["),
             Instruction::JumpNz(_) => println!("]"),
 
@@ -162,6 +1630,11 @@ pub fn print_brainfuck_code(instructions: &[Instruction]) {
             Instruction::Sub(_) => unreachable!(),
             Instruction::AddMul(_, _) => unreachable!(),
             Instruction::SubMul(_, _) => unreachable!(),
+            Instruction::CopyRange(..) => unreachable!(),
+            Instruction::DivMod => unreachable!(),
+            Instruction::PrintDecimal(_) => unreachable!(),
+            Instruction::OutputConst(_) => unreachable!(),
+            Instruction::Pipe => unreachable!(),
         }
         if let Instruction::JumpZ(_) = inst {
             indent += 1
@@ -169,18 +1642,310 @@ pub fn print_brainfuck_code(instructions: &[Instruction]) {
     }
 }
 
+/// Format brainfuck code like [`print_brainfuck_code`], but keep the comments. A comment following
+/// a token on the same line stays at the end of that line, other comments are put on their own
+/// lines before the next token. The brackets of the source code have to be matched.
+pub fn format_brainfuck_code_with_comments(src: &[u8]) -> String {
+    use std::fmt::Write;
+
+    fn flush(output: &mut String, line: &mut String, indent: usize, comment: &str) {
+        if line.is_empty() && comment.is_empty() {
+            return;
+        }
+        for _ in 0..indent {
+            output.push_str("    ");
+        }
+        output.push_str(line);
+        if !line.is_empty() && !comment.is_empty() {
+            output.push(' ');
+        }
+        output.push_str(comment);
+        output.push('\n');
+        line.clear();
+    }
+
+    let mut output = String::new();
+    let mut indent = 0;
+    // the current line of repeated tokens, and its indentation
+    let mut line = String::new();
+    let mut line_indent = 0;
+    let mut last = None;
+    let mut comment_start = 0;
+    let mut tokens = crate::tokens(src);
+    loop {
+        let next = tokens.next();
+        let comment_end = next.map_or(src.len(), |(_, span)| span.offset);
+        let comment = String::from_utf8_lossy(&src[comment_start..comment_end]);
+        let (trailing, leading) = comment.split_once('\n').unwrap_or((&comment, ""));
+        let trailing = trailing.trim();
+        if !trailing.is_empty() || !leading.trim().is_empty() {
+            if line.is_empty() {
+                flush(&mut output, &mut line, indent, trailing);
+            } else {
+                flush(&mut output, &mut line, line_indent, trailing);
+            }
+            for l in leading.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                flush(&mut output, &mut line, indent, l);
+            }
+        }
+
+        let Some((t, span)) = next else {
+            flush(&mut output, &mut line, line_indent, "");
+            break;
+        };
+        if !(t.is_combinable() && last == Some(t) && !line.is_empty()) {
+            flush(&mut output, &mut line, line_indent, "");
+            if t == Token::RSquare {
+                indent -= 1;
+            }
+            line_indent = indent;
+            if t == Token::LSquare {
+                indent += 1;
+            }
+        }
+        _ = write!(line, "{t}");
+        last = Some(t);
+        comment_start = span.offset + 1;
+    }
+    output
+}
+
 pub fn print_instructions(instructions: &[Instruction]) {
+    print!("{}", format_instructions(instructions));
+}
+
+pub fn format_instructions(instructions: &[Instruction]) -> String {
+    use std::fmt::Write;
+
+    let mut output = String::new();
     let mut indent = 0;
     for i in instructions.iter() {
         if let Instruction::JumpNz(_) = i {
             indent -= 1
         }
         for _ in 0..indent {
-            print!("    ");
+            output.push_str("    ");
         }
-        println!("{i}");
+        _ = writeln!(output, "{i}");
         if let Instruction::JumpZ(_) = i {
             indent += 1
         }
     }
+    output
+}
+
+/// Print the range of `accessed` registers and the size of the tape, the range is unknown if it's
+/// `None`.
+pub fn print_mem_stats(accessed: Option<PointerRange>, tape_len: usize) {
+    eprintln!("============================================================");
+    match accessed {
+        Some(r) => eprintln!("accessed registers: {}..={}", r.min, r.max),
+        None => eprintln!("accessed registers: unknown"),
+    }
+    eprintln!("tape size: {tape_len}");
+    eprintln!("============================================================");
+}
+
+/// Print the total number of executed instructions, and the number per opcode, followed by the
+/// estimated cost per class of work and per opcode, see [`Cost`].
+pub fn print_cycle_counts(instructions: &[Instruction], counts: &[u64]) {
+    let opcodes = opcode_counts(instructions, counts);
+    let total: u64 = counts.iter().sum();
+    eprintln!("============================================================");
+    eprintln!("cycles: {total}");
+    for (name, count) in opcodes {
+        let percent = 100.0 * count as f64 / total.max(1) as f64;
+        eprintln!("    {name:<8} {count:>16} ({percent:.3}%)");
+    }
+
+    let (classes, opcodes) = cost_breakdown(instructions, counts);
+    let total = classes.total();
+    eprintln!("estimated cost: {total}");
+    for (class, cost) in classes.named() {
+        let percent = 100.0 * cost as f64 / total.max(1) as f64;
+        eprintln!("    {class:<8} {cost:>16} ({percent:.3}%)");
+    }
+    for (name, cost) in opcodes {
+        let percent = 100.0 * cost as f64 / total.max(1) as f64;
+        eprintln!("    {percent:>7.3}% in {name}");
+    }
+    eprintln!("============================================================");
+}
+
+/// The estimated cost of executing instructions, split into the classes of work, in units of
+/// incrementing a register. The cost model is a rough approximation of the interpreter, which
+/// shows where new combined instructions or code generation patterns would pay off most.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cost {
+    /// Decoding and branching to the instruction, and taking jumps.
+    pub dispatch: u64,
+    /// Reading and writing registers, and moving the pointer.
+    pub memory: u64,
+    /// Reading input and writing output.
+    pub io: u64,
+}
+
+impl Cost {
+    /// The estimated cost of executing the instruction once.
+    pub fn of(inst: &Instruction) -> Self {
+        use Instruction::*;
+
+        let (memory, io) = match *inst {
+            Shl(_) | Shr(_) => (1, 0),
+            Inc(..) | Dec(..) | Zero(_) | Set(..) => (1, 0),
+            Add(_) | Sub(_) => (2, 0),
+            AddMul(..) | SubMul(..) => (3, 0),
+            CopyRange(_, _, len) => (2 * len as u64, 0),
+            DivMod => (8, 0),
+            JumpZ(_) | JumpNz(_) => (1, 0),
+            Output(_) | Input(_) => (1, 4),
+            OutputConst(_) => (0, 4),
+            PrintDecimal(_) => (1, 8),
+            Pipe => (0, 16),
+        };
+        let dispatch = match inst {
+            JumpZ(_) | JumpNz(_) => 2,
+            _ => 1,
+        };
+        Self {
+            dispatch,
+            memory,
+            io,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.dispatch + self.memory + self.io
+    }
+
+    fn named(&self) -> [(&'static str, u64); 3] {
+        [
+            ("dispatch", self.dispatch),
+            ("memory", self.memory),
+            ("i/o", self.io),
+        ]
+    }
+
+    fn add_times(&mut self, other: Cost, n: u64) {
+        self.dispatch += other.dispatch * n;
+        self.memory += other.memory * n;
+        self.io += other.io * n;
+    }
+}
+
+/// The estimated cost of the executed instructions, and the cost per opcode sorted by the cost.
+/// Instructions accessing registers at an offset from the pointer are listed separately.
+fn cost_breakdown(instructions: &[Instruction], counts: &[u64]) -> (Cost, Vec<(String, u64)>) {
+    let mut classes = Cost::default();
+    let mut opcodes: Vec<(String, u64)> = Vec::new();
+    for (inst, &count) in instructions.iter().zip(counts) {
+        let cost = Cost::of(inst);
+        classes.add_times(cost, count);
+        let name = match at_offset(inst) {
+            true => format!("{} at offsets", inst.name()),
+            false => inst.name().to_owned(),
+        };
+        match opcodes.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, c)) => *c += cost.total() * count,
+            None => opcodes.push((name, cost.total() * count)),
+        }
+    }
+    opcodes.retain(|(_, c)| *c > 0);
+    opcodes.sort_by(|(_, a), (_, b)| b.cmp(a));
+    (classes, opcodes)
+}
+
+/// Whether the instruction accesses a register at an offset from the pointer.
+fn at_offset(inst: &Instruction) -> bool {
+    use Instruction::*;
+
+    match *inst {
+        Inc(o, _) | Dec(o, _) | Output(o) | Input(o) | Zero(o) | Set(o, _) => o != 0,
+        Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) | PrintDecimal(o) => o != 0,
+        CopyRange(src, dst, _) => src != 0 || dst != 0,
+        _ => false,
+    }
+}
+
+/// The number of executed instructions per opcode, sorted by the count.
+fn opcode_counts(instructions: &[Instruction], counts: &[u64]) -> Vec<(&'static str, u64)> {
+    let mut opcodes: Vec<(&str, u64)> = Vec::new();
+    for (inst, &count) in instructions.iter().zip(counts) {
+        match opcodes.iter_mut().find(|(name, _)| *name == inst.name()) {
+            Some((_, c)) => *c += count,
+            None => opcodes.push((inst.name(), count)),
+        }
+    }
+    opcodes.sort_by(|(_, a), (_, b)| b.cmp(a));
+    opcodes
+}
+
+/// Print the number of executed instructions per opcode, and the loops most of them were executed
+/// in, as a json object. The loops are identified by the instruction indices of their bodies.
+pub fn print_cycle_counts_json(instructions: &[Instruction], counts: &[u64]) {
+    let total: u64 = counts.iter().sum();
+    let mut json = format!("{{\"cycles\":{total},\"opcodes\":{{");
+    for (i, (name, count)) in opcode_counts(instructions, counts).into_iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        _ = write!(json, "\"{name}\":{count}");
+    }
+
+    let (classes, opcodes) = cost_breakdown(instructions, counts);
+    _ = write!(json, "}},\"cost\":{{\"total\":{}", classes.total());
+    for (class, cost) in classes.named() {
+        _ = write!(json, ",\"{class}\":{cost}");
+    }
+    json.push_str(",\"opcodes\":{");
+    for (i, (name, cost)) in opcodes.into_iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_json_string(&mut json, &name);
+        _ = write!(json, ":{cost}");
+    }
+    json.push('}');
+
+    // the closing jump of a loop jumps back to the start of its body
+    let mut loops = (instructions.iter().enumerate())
+        .filter_map(|(i, inst)| match inst {
+            Instruction::JumpNz(Jump::Location(start)) if (start.get() as usize) <= i => {
+                let start = start.get() as usize;
+                Some((start, i, counts[start..=i].iter().sum::<u64>()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    loops.sort_by(|(_, _, a), (_, _, b)| b.cmp(a));
+    json.push_str("},\"loops\":[");
+    for (i, (start, end, cycles)) in loops.into_iter().take(NUM_HOT_LOOPS).enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let iterations = counts[end];
+        _ = write!(
+            json,
+            "{{\"start\":{start},\"end\":{end},\"cycles\":{cycles},\"iterations\":{iterations}}}"
+        );
+    }
+    json.push_str("]}");
+    eprintln!("{json}");
+}
+
+/// Append `str` as a json string, escaping quotes, backslashes and control characters.
+pub fn write_json_string(json: &mut String, str: &str) {
+    json.push('"');
+    for c in str.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => _ = write!(json, "\\u{:04x}", c as u32),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
 }
@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::cli::Config;
+use crate::interp::{Interpreter, Limit, Program, State};
+use crate::{cache, error, optim, warn, Instruction, NUM_REGISTERS};
+
+const MAGIC: &[u8; 6] = b"bfcore";
+const FORMAT_VERSION: u8 = 1;
+/// Number of registers printed around the register pointer.
+const NUM_PRINTED_REGISTERS: usize = 16;
+
+/// The state of an interpreter that exceeded a limit, for post-mortem inspection using the
+/// `debug` mode.
+pub struct Core {
+    pub limit: Limit,
+    pub state: State,
+    pub steps: u64,
+    /// The instruction pointers of the most recently executed instructions, oldest first.
+    pub history: Vec<usize>,
+    /// The instructions that were interpreted, which the instruction pointers refer to.
+    pub instructions: Vec<Instruction>,
+}
+
+impl Core {
+    pub fn new<R: Read, W: Write>(
+        interpreter: &Interpreter<R, W>,
+        limit: Limit,
+        instructions: &[Instruction],
+    ) -> Self {
+        Self {
+            limit,
+            state: interpreter.snapshot(),
+            steps: interpreter.steps(),
+            history: interpreter
+                .history()
+                .map_or(Vec::new(), |h| h.iter().copied().collect()),
+            instructions: instructions.to_vec(),
+        }
+    }
+
+    /// Serialize the core as the magic bytes and format version, the limit, the instruction and
+    /// register pointers, the step count, the history, all registers, and the serialized ir.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + NUM_REGISTERS + 64);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(match self.limit {
+            Limit::Steps => 0,
+            Limit::Output => 1,
+            Limit::Time => 2,
+            Limit::Tape => 3,
+            Limit::Eof => 4,
+        });
+        bytes.extend_from_slice(&(self.state.ip as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.state.rp.to_le_bytes());
+        bytes.extend_from_slice(&self.steps.to_le_bytes());
+        bytes.extend_from_slice(&(self.history.len() as u32).to_le_bytes());
+        for &ip in self.history.iter() {
+            bytes.extend_from_slice(&(ip as u64).to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.state.registers[..]);
+        bytes.extend_from_slice(&cache::serialize(&self.instructions));
+        bytes
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.strip_prefix(MAGIC)?;
+        let ([version, limit], mut bytes) = bytes.split_first_chunk()?;
+        if *version != FORMAT_VERSION {
+            return None;
+        }
+        let limit = match limit {
+            0 => Limit::Steps,
+            1 => Limit::Output,
+            2 => Limit::Time,
+            3 => Limit::Tape,
+            4 => Limit::Eof,
+            _ => return None,
+        };
+        let ip = u64::from_le_bytes(take(&mut bytes)?) as usize;
+        let rp = i16::from_le_bytes(take(&mut bytes)?);
+        let steps = u64::from_le_bytes(take(&mut bytes)?);
+        let len = u32::from_le_bytes(take(&mut bytes)?);
+        let history = (0..len)
+            .map(|_| Some(u64::from_le_bytes(take(&mut bytes)?) as usize))
+            .collect::<Option<Vec<_>>>()?;
+        let registers = Box::new(take::<NUM_REGISTERS>(&mut bytes)?);
+        // the ir was compacted before being interpreted
+        let instructions = cache::deserialize_compacted(bytes)?;
+
+        let in_range = |ip: usize| ip <= instructions.len();
+        if !in_range(ip) || !history.iter().all(|&ip| in_range(ip)) {
+            return None;
+        }
+        Some(Self {
+            limit,
+            state: State { ip, rp, registers },
+            steps,
+            history,
+            instructions,
+        })
+    }
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+    let (chunk, rest) = bytes.split_first_chunk::<N>()?;
+    *bytes = rest;
+    Some(*chunk)
+}
+
+/// Print the state stored in the core written for the program at `path`: the exceeded limit, the
+/// recently executed instructions, and the registers around the register pointer.
+pub fn debug(config: &Config, core_path: &Path, path: &Path) -> ExitCode {
+    let core = match std::fs::read(core_path) {
+        Ok(bytes) => match Core::deserialize(&bytes) {
+            Some(core) => core,
+            None => {
+                let core_path = core_path.display();
+                error!("`{core_path}` isn't a valid core file");
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(e) => {
+            let core_path = core_path.display();
+            error!("failed to read `{core_path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // the instruction pointers refer to the ir, which depends on the optimization options
+    let mut instructions = match File::open(path).and_then(|f| crate::parse(config, f)) {
+        Ok(Ok(i)) => i,
+        Ok(Err(errors)) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    if Program::new(config, instructions).instructions != core.instructions {
+        let path = path.display();
+        warn!("the ir of `{path}` differs from the one stored in the core, which is shown instead");
+    }
+
+    let State { ip, rp, registers } = &core.state;
+    println!("{}", core.limit);
+    println!("steps: {}", core.steps);
+    println!("register pointer: {rp}");
+    println!("============================================================");
+    println!("recent instructions:");
+    for &i in core.history.iter() {
+        print_instruction(&core.instructions, i, "   ");
+    }
+    print_instruction(&core.instructions, *ip, "-->");
+    println!("============================================================");
+
+    let len = NUM_PRINTED_REGISTERS;
+    let start = (*rp as isize - len as isize / 2).clamp(0, (NUM_REGISTERS - len) as isize) as usize;
+    println!("registers {start}..{}:", start + len);
+    for (i, r) in registers[start..start + len].iter().enumerate() {
+        if start + i == *rp as usize {
+            print!("[{r:3}]");
+        } else {
+            print!(" {r:3} ");
+        }
+    }
+    println!();
+    let nonzero = registers.iter().filter(|&&r| r != 0).count();
+    match registers.iter().rposition(|&r| r != 0) {
+        Some(last) => println!("nonzero registers: {nonzero}, the last one is {last}"),
+        None => println!("nonzero registers: none"),
+    }
+    ExitCode::SUCCESS
+}
+
+fn print_instruction(instructions: &[Instruction], ip: usize, marker: &str) {
+    match instructions.get(ip) {
+        Some(inst) => println!("{marker} {ip:>8}  {inst}"),
+        None => println!("{marker} {ip:>8}  end of program"),
+    }
+}
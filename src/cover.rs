@@ -0,0 +1,103 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::cli::{Config, ANSII_CLEAR, ANSII_COLOR_RED};
+use crate::interp::{Interpreter, Status};
+use crate::{error, Span, Token};
+
+/// Run the unoptimized program with the standard input, and report which tokens of the source code
+/// were never executed. The report is printed to stderr, the source code is reprinted with the
+/// unexecuted tokens highlighted, followed by a list of the unexecuted regions.
+pub fn cover(config: &Config, path: &Path) -> ExitCode {
+    let src = match std::fs::read(path) {
+        Ok(src) => src,
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let checked = crate::preprocess(config, src).and_then(|src| crate::tokenize(&src).map(|_| src));
+    let src = match checked {
+        Ok(src) => src,
+        Err(errors) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+    let (tokens, spans): (Vec<Token>, Vec<Span>) = crate::tokens(&src).unzip();
+    let mut instructions = crate::combine(tokens.iter().copied());
+    crate::update_jump_indices(&mut instructions);
+
+    // tokens are combined like the `Combiner` does, so each instruction originates from a run of
+    // tokens
+    let mut origins = Vec::with_capacity(tokens.len());
+    for (i, t) in tokens.iter().enumerate() {
+        let combined = i > 0 && tokens[i - 1] == *t && t.is_combinable();
+        let prev = origins.last().copied().unwrap_or(0);
+        origins.push(if combined || i == 0 { prev } else { prev + 1 });
+    }
+    debug_assert_eq!(origins.last().map_or(0, |&o| o + 1), instructions.len());
+
+    let mut interpreter = Interpreter::new(&instructions, std::io::stdin(), std::io::stdout())
+        .with_limits(config.limits())
+        .with_eof(config.eof)
+        .with_counts();
+    let status = interpreter.run();
+    let counts = interpreter.counts().expect("counts are enabled");
+    let executed = origins.iter().map(|&o| counts[o] > 0).collect::<Vec<_>>();
+
+    print_source(&src, &spans, &executed);
+    let num_executed = executed.iter().filter(|&&e| e).count();
+    let percent = 100.0 * num_executed as f64 / tokens.len().max(1) as f64;
+    eprintln!("============================================================");
+    eprintln!(
+        "coverage: {num_executed}/{} tokens ({percent:.1}%)",
+        tokens.len()
+    );
+    let mut i = 0;
+    while i < tokens.len() {
+        if executed[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < tokens.len() && !executed[i] {
+            i += 1;
+        }
+        let (first, last) = (spans[start], spans[i - 1]);
+        let len = i - start;
+        eprintln!("never executed: {first}..{last} ({len} tokens)");
+    }
+
+    if let Status::Exceeded(limit) = status {
+        error!("{limit}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Print the source code with the tokens that weren't executed highlighted.
+fn print_source(src: &[u8], spans: &[Span], executed: &[bool]) {
+    let mut stderr = std::io::stderr().lock();
+    let mut pos = 0;
+    for (span, &executed) in spans.iter().zip(executed) {
+        if executed {
+            continue;
+        }
+        _ = stderr.write_all(&src[pos..span.offset]);
+        _ = write!(
+            stderr,
+            "{ANSII_COLOR_RED}{}{ANSII_CLEAR}",
+            src[span.offset] as char
+        );
+        pos = span.offset + 1;
+    }
+    _ = stderr.write_all(&src[pos..]);
+    if !src.ends_with(b"\n") {
+        _ = stderr.write_all(b"\n");
+    }
+}
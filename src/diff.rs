@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::cli::Config;
+use crate::interp::{Interpreter, Limit, Limits, Program, Status};
+use crate::rng::Rng;
+use crate::{error, optim, Instruction};
+
+const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+const DEFAULT_MAX_OUTPUT: usize = 1 << 16;
+/// Number of generated inputs both programs are run with.
+const NUM_INPUTS: usize = 16;
+const MAX_INPUT_LEN: usize = 64;
+/// Number of output bytes printed from the first difference on.
+const MAX_PRINTED_OUTPUT: usize = 32;
+
+/// The output of a program for one input, and the limit that stopped it, if any.
+struct Outcome {
+    output: Vec<u8>,
+    limit: Option<Limit>,
+}
+
+impl Outcome {
+    /// Whether the program was stopped before it finished, so its output might be incomplete.
+    fn truncated(&self) -> bool {
+        matches!(self.limit, Some(Limit::Steps | Limit::Output | Limit::Time))
+    }
+}
+
+/// Compare the optimized ir of two programs, and their outputs for generated inputs. Fails if a
+/// difference in behavior was found.
+pub fn diff(config: &Config, a: &Path, b: &Path) -> ExitCode {
+    let (Some(program_a), Some(program_b)) = (build(config, a), build(config, b)) else {
+        return ExitCode::FAILURE;
+    };
+    let (name_a, name_b) = (a.display(), b.display());
+
+    // the jump locations only differ if the loop bodies differ
+    let same = |a: &Instruction, b: &Instruction| match (a, b) {
+        (Instruction::JumpZ(_), Instruction::JumpZ(_)) => true,
+        (Instruction::JumpNz(_), Instruction::JumpNz(_)) => true,
+        _ => a == b,
+    };
+    let first_difference = (program_a.iter())
+        .zip(program_b.iter())
+        .position(|(a, b)| !same(a, b));
+    match first_difference {
+        None if program_a.len() == program_b.len() => println!("structure: equivalent"),
+        None => {
+            let (len_a, len_b) = (program_a.len(), program_b.len());
+            let msg = format!("`{name_a}` has {len_a} instructions and `{name_b}` {len_b}");
+            println!("structure: different, {msg}");
+        }
+        Some(i) => {
+            let (inst_a, inst_b) = (program_a[i], program_b[i]);
+            let msg = format!("first difference at instruction {i}: `{inst_a}` vs `{inst_b}`");
+            println!("structure: different, {msg}");
+        }
+    }
+
+    let limits = Limits {
+        steps: Some(config.max_steps.unwrap_or(DEFAULT_MAX_STEPS)),
+        output: Some(config.max_output.unwrap_or(DEFAULT_MAX_OUTPUT)),
+        time: config.max_time,
+    };
+    let mut inconclusive = 0;
+    for input in generate_inputs() {
+        let outcome_a = run(config, &program_a, &input, limits);
+        let outcome_b = run(config, &program_b, &input, limits);
+        let different = if outcome_a.truncated() || outcome_b.truncated() {
+            inconclusive += 1;
+            let len = outcome_a.output.len().min(outcome_b.output.len());
+            outcome_a.output[..len] != outcome_b.output[..len]
+        } else {
+            outcome_a.output != outcome_b.output || outcome_a.limit != outcome_b.limit
+        };
+
+        if different {
+            let (output_a, output_b) = (&outcome_a.output, &outcome_b.output);
+            let pos = (output_a.iter())
+                .zip(output_b.iter())
+                .position(|(a, b)| a != b)
+                .unwrap_or(output_a.len().min(output_b.len()));
+            let input = input.escape_ascii();
+            println!("behavior:  different for input `{input}`, from output byte {pos} on");
+            for (name, outcome) in [(&name_a, &outcome_a), (&name_b, &outcome_b)] {
+                let output = &outcome.output[pos..];
+                let end = if output.len() > MAX_PRINTED_OUTPUT {
+                    "..."
+                } else {
+                    ""
+                };
+                let output = output[..output.len().min(MAX_PRINTED_OUTPUT)].escape_ascii();
+                match outcome.limit {
+                    Some(limit) => println!("    {name}: `{output}`{end} ({limit})"),
+                    None => println!("    {name}: `{output}`{end}"),
+                }
+            }
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if inconclusive == 0 {
+        println!("behavior:  same for {NUM_INPUTS} generated inputs");
+    } else {
+        let msg = format!("{inconclusive} of them exceeded a limit");
+        println!("behavior:  same for {NUM_INPUTS} generated inputs, {msg}");
+    }
+    ExitCode::SUCCESS
+}
+
+/// Parse and optimize the program, the optimized ir is the canonical form that's compared.
+fn build(config: &Config, path: &Path) -> Option<Vec<Instruction>> {
+    let mut instructions = match File::open(path).and_then(|f| crate::parse(config, f)) {
+        Ok(Ok(i)) => i,
+        Ok(Err(errors)) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return None;
+        }
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return None;
+        }
+    };
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    Some(instructions)
+}
+
+fn run(config: &Config, instructions: &[Instruction], input: &[u8], limits: Limits) -> Outcome {
+    let mut instructions = instructions.to_vec();
+    optim::thread_jumps(config, &mut instructions);
+    let program = Program::new(config, instructions);
+
+    let mut output = Vec::new();
+    let mut interpreter =
+        Interpreter::from_config(config, &program, input, &mut output).with_limits(limits);
+    let limit = match interpreter.run() {
+        Status::Paused => unreachable!(),
+        Status::Finished => None,
+        Status::Exceeded(limit) => Some(limit),
+    };
+    Outcome { output, limit }
+}
+
+/// Generate the same inputs on every run: an empty input, a few lines of digits and text, and
+/// random bytes.
+fn generate_inputs() -> Vec<Vec<u8>> {
+    let mut rng = Rng::new(0);
+
+    let mut inputs = vec![
+        Vec::new(),
+        b"\n".to_vec(),
+        b"0\n".to_vec(),
+        b"42\n".to_vec(),
+    ];
+    while inputs.len() < NUM_INPUTS {
+        let len = 1 + rng.below(MAX_INPUT_LEN);
+        let input = match inputs.len() % 3 {
+            0 => (0..len).map(|_| b'0' + rng.below(10) as u8).collect(),
+            1 => (0..len).map(|_| b' ' + rng.below(95) as u8).collect(),
+            _ => (0..len).map(|_| rng.byte()).collect(),
+        };
+        inputs.push(input);
+    }
+    inputs
+}
@@ -0,0 +1,215 @@
+//! The encodings of the input of programs run by the `run` mode, see `--input-encoding`.
+
+use std::io::{ErrorKind, Read};
+
+use crate::error;
+
+/// How the input is fed to the input instructions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputEncoding {
+    /// Each byte is read as is.
+    #[default]
+    Bytes,
+    /// The input is decoded as utf-8, and each unicode scalar value is read as a single byte,
+    /// truncated to its lowest 8 bits, so latin-1 text is read unchanged. Bytes that aren't part
+    /// of valid utf-8 are read as is.
+    Utf8Codepoints,
+    /// Each pair of hex digits is read as a byte, whitespace in between is ignored.
+    Hex,
+}
+
+impl InputEncoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            InputEncoding::Bytes => "bytes",
+            InputEncoding::Utf8Codepoints => "utf8-codepoints",
+            InputEncoding::Hex => "hex",
+        }
+    }
+}
+
+impl std::str::FromStr for InputEncoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            InputEncoding::Bytes,
+            InputEncoding::Utf8Codepoints,
+            InputEncoding::Hex,
+        ]
+        .into_iter()
+        .find(|e| e.name() == s)
+        .ok_or(())
+    }
+}
+
+/// A reader decoding the input of the `inner` reader. The input is read in chunks as it becomes
+/// available, so interactive programs still see each line as soon as it's entered. Invalid input
+/// is reported once and treated as the end of the input.
+pub struct Decoder<R> {
+    inner: R,
+    encoding: InputEncoding,
+    /// The bytes of an incomplete utf-8 char, or the first digit of an incomplete hex pair.
+    pending: Vec<u8>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(encoding: InputEncoding, inner: R) -> Self {
+        Self {
+            inner,
+            encoding,
+            pending: Vec::with_capacity(4),
+            buf: vec![0; 1 << 12],
+            done: false,
+        }
+    }
+
+    /// Decode the `input` into `out`, and return the number of written bytes. `out` has room for
+    /// at least as many bytes as the input, utf-8 input that doesn't fit is kept pending.
+    fn decode(&mut self, input: &[u8], out: &mut [u8]) -> usize {
+        let mut n = 0;
+        for &b in input {
+            match self.encoding {
+                InputEncoding::Bytes => {
+                    out[n] = b;
+                    n += 1;
+                }
+                InputEncoding::Utf8Codepoints => {
+                    self.pending.push(b);
+                    n += self.decode_pending(&mut out[n..]);
+                }
+                InputEncoding::Hex => {
+                    if b.is_ascii_whitespace() {
+                        continue;
+                    }
+                    let Some(digit) = (b as char).to_digit(16) else {
+                        let c = [b].escape_ascii().to_string();
+                        error!("invalid hex digit `{c}` in the input");
+                        self.done = true;
+                        return n;
+                    };
+                    match self.pending.pop() {
+                        Some(high) => {
+                            out[n] = (high << 4) | digit as u8;
+                            n += 1;
+                        }
+                        None => self.pending.push(digit as u8),
+                    }
+                }
+            }
+        }
+        n
+    }
+
+    /// Decode as many pending utf-8 chars as fit into `out`, and return the number of written
+    /// bytes. An incomplete char at the end stays pending.
+    fn decode_pending(&mut self, out: &mut [u8]) -> usize {
+        let mut n = 0;
+        while !self.pending.is_empty() && n < out.len() {
+            let valid = match std::str::from_utf8(&self.pending) {
+                Ok(s) => s,
+                Err(e) => std::str::from_utf8(&self.pending[..e.valid_up_to()]).unwrap(),
+            };
+            if let Some(c) = valid.chars().next() {
+                out[n] = c as u32 as u8;
+                n += 1;
+                self.pending.drain(..c.len_utf8());
+                continue;
+            }
+            match std::str::from_utf8(&self.pending).unwrap_err().error_len() {
+                // the char isn't complete yet
+                None => break,
+                // the invalid bytes are read as is, the rest might start a char
+                Some(len) => {
+                    let len = len.min(out.len() - n);
+                    for b in self.pending.drain(..len) {
+                        out[n] = b;
+                        n += 1;
+                    }
+                }
+            }
+        }
+        n
+    }
+
+    /// Write as many pending bytes of an incomplete utf-8 char as fit into `out` as is.
+    fn flush_pending(&mut self, out: &mut [u8]) -> usize {
+        let n = self.pending.len().min(out.len());
+        out[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.done || out.is_empty() {
+                return Ok(0);
+            }
+            // chars completed by an earlier read, which didn't fit into its buffer
+            if self.encoding == InputEncoding::Utf8Codepoints {
+                let n = self.decode_pending(out);
+                if n > 0 {
+                    return Ok(n);
+                }
+            }
+            // leave room for the pending bytes of invalid utf-8
+            let len = out
+                .len()
+                .saturating_sub(self.pending.len())
+                .clamp(1, self.buf.len());
+            let read = match self.inner.read(&mut self.buf[..len]) {
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if read == 0 {
+                if self.encoding == InputEncoding::Hex && !self.pending.is_empty() {
+                    error!("incomplete hex pair at the end of the input");
+                    self.pending.clear();
+                }
+                let n = self.flush_pending(out);
+                self.done = self.pending.is_empty();
+                return Ok(n);
+            }
+            let input = std::mem::take(&mut self.buf);
+            let n = self.decode(&input[..read], out);
+            self.buf = input;
+            if n > 0 || self.done {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the `input` decoded using the `encoding` one byte at a time.
+    fn read_bytewise(encoding: InputEncoding, input: &[u8]) -> Vec<u8> {
+        let mut decoder = Decoder::new(encoding, input);
+        let mut output = Vec::new();
+        let mut byte = [0];
+        while decoder.read(&mut byte).unwrap() == 1 {
+            output.push(byte[0]);
+        }
+        output
+    }
+
+    #[test]
+    fn invalid_utf8_is_read_bytewise() {
+        let input = b"\xE2\x82a\xC3\xA9\xFF\xE2\x82";
+        let output = read_bytewise(InputEncoding::Utf8Codepoints, input);
+        assert_eq!(output, b"\xE2\x82a\xE9\xFF\xE2\x82");
+    }
+
+    #[test]
+    fn utf8_is_decoded_into_short_buffers() {
+        let output = read_bytewise(InputEncoding::Utf8Codepoints, "a\u{e9}\u{20ac}b".as_bytes());
+        assert_eq!(output, b"a\xE9\xACb");
+    }
+}
@@ -0,0 +1,110 @@
+//! Expectations of the `run` mode, which compares the output and the exit code of a program
+//! against `--expect-output` and `--expect-exit`, so programs can be tested by a shell one-liner.
+
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::cli::Config;
+use crate::error;
+
+/// Number of differing lines printed of each side of the diff.
+const MAX_PRINTED_LINES: usize = 16;
+
+/// Read the expected output, the contents of the file at `arg` if it exists, otherwise the
+/// argument itself.
+pub fn load_output(arg: &Path) -> std::io::Result<Vec<u8>> {
+    match arg.is_file() {
+        true => std::fs::read(arg),
+        false => Ok(arg.as_os_str().as_bytes().to_vec()),
+    }
+}
+
+/// Writes the output to the `inner` writer, and records it to be compared against the expected
+/// output once the program stopped.
+pub struct Recorder<'a, W: Write> {
+    inner: W,
+    output: &'a mut Vec<u8>,
+}
+
+impl<'a, W: Write> Recorder<'a, W> {
+    pub fn new(inner: W, output: &'a mut Vec<u8>) -> Self {
+        Self { inner, output }
+    }
+}
+
+impl<W: Write> Write for Recorder<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.output.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compare the `output` and the `exit` code of a run against the expectations of the `config`,
+/// and report the mismatches with a diff of the output. The program is expected to finish, if
+/// only the output is expected. Returns whether the expectations were met.
+pub fn check(config: &Config, expected_output: Option<&[u8]>, output: &[u8], exit: u8) -> bool {
+    let mut met = true;
+    if let Some(expected) = expected_output.filter(|&e| e != output) {
+        error!("the output doesn't match the expected output");
+        for line in diff(expected, output) {
+            crate::parallel::eprint(format_args!("{line}\n"));
+        }
+        met = false;
+    }
+    let expected = config.expect_exit.unwrap_or(0);
+    if exit != expected {
+        error!("the program exited with {exit}, but {expected} was expected");
+        met = false;
+    }
+    met
+}
+
+/// The lines that differ between the expected and the actual output, after the lines both start
+/// and end with, like a hunk of a unified diff.
+pub fn diff(expected: &[u8], actual: &[u8]) -> Vec<String> {
+    let expected = expected
+        .split_inclusive(|&b| b == b'\n')
+        .collect::<Vec<_>>();
+    let actual = actual.split_inclusive(|&b| b == b'\n').collect::<Vec<_>>();
+    let prefix = (expected.iter())
+        .zip(actual.iter())
+        .take_while(|(e, a)| e == a)
+        .count();
+    let suffix = (expected[prefix..].iter().rev())
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(e, a)| e == a)
+        .count();
+    let expected = &expected[prefix..expected.len() - suffix];
+    let actual = &actual[prefix..actual.len() - suffix];
+
+    let line = prefix + 1;
+    let mut diff = vec![format!(
+        "@@ -{line},{} +{line},{} @@",
+        expected.len(),
+        actual.len()
+    )];
+    for (sign, lines) in [('-', expected), ('+', actual)] {
+        for l in lines.iter().take(MAX_PRINTED_LINES) {
+            match l.strip_suffix(b"\n") {
+                Some(l) => diff.push(format!("{sign}{}", l.escape_ascii())),
+                None => {
+                    diff.push(format!("{sign}{}", l.escape_ascii()));
+                    diff.push("\\ no newline at the end".to_owned());
+                }
+            }
+        }
+        if lines.len() > MAX_PRINTED_LINES {
+            diff.push(format!(
+                "{sign}... {} more lines",
+                lines.len() - MAX_PRINTED_LINES
+            ));
+        }
+    }
+    diff
+}
@@ -0,0 +1,160 @@
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::cli::{Config, Pass};
+use crate::ir::{self, Node};
+use crate::optim::{self, LoopVerdict};
+use crate::{error, Instruction, Span, Token};
+
+/// Report why the loop at `line:col`, or the innermost loop containing it, was or wasn't
+/// rewritten by the loop passes. The passes are applied to the loop in isolation, so passes over
+/// the whole program, like `init`, `dead-code` and `unroll`, might still remove or unroll it.
+pub fn explain(config: &Config, path: &Path, (line, col): (usize, usize)) -> ExitCode {
+    let src = match std::fs::read(path) {
+        Ok(src) => src,
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let checked = crate::preprocess(config, src).and_then(|src| crate::tokenize(&src).map(|_| src));
+    let src = match checked {
+        Ok(src) => src,
+        Err(errors) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+    let (tokens, spans): (Vec<Token>, Vec<Span>) = crate::tokens(&src).unzip();
+
+    let Some(idx) = spans.iter().position(|s| s.line == line && s.col == col) else {
+        error!("there is no command at [{line}:{col}]");
+        return ExitCode::FAILURE;
+    };
+    // the innermost loop containing the token, a closing bracket belongs to its own loop
+    let mut stack = Vec::new();
+    for (i, t) in tokens[..idx].iter().enumerate() {
+        match t {
+            Token::LSquare => stack.push(i),
+            Token::RSquare => _ = stack.pop(),
+            _ => (),
+        }
+    }
+    let start = match tokens[idx] {
+        Token::LSquare => Some(idx),
+        _ => stack.pop(),
+    };
+    let Some(start) = start else {
+        error!("[{line}:{col}] isn't inside a loop");
+        return ExitCode::FAILURE;
+    };
+    let mut depth = 0_usize;
+    let end = (start..tokens.len())
+        .find(|&i| {
+            match tokens[i] {
+                Token::LSquare => depth += 1,
+                Token::RSquare => depth -= 1,
+                _ => (),
+            }
+            depth == 0
+        })
+        .expect("brackets are matched");
+
+    println!("loop {}..{}", spans[start], spans[end]);
+    let config = Config {
+        verbose: 0,
+        ..config.clone()
+    };
+    for line in verdicts(&config, &tokens[start..=end]) {
+        println!("- {line}");
+    }
+    ExitCode::SUCCESS
+}
+
+/// The verdicts of the loop passes in the order they are run.
+fn verdicts(config: &Config, tokens: &[Token]) -> Vec<String> {
+    use Instruction::{Dec, Input, JumpNz, JumpZ, Output};
+
+    let mut instructions = crate::combine(tokens.iter().copied());
+    let mut verdicts = Vec::new();
+    let enabled = |pass: Pass| match config.pass(pass) {
+        true => format!("the `{}` pass", pass.name()),
+        false => format!("the `{}` pass, which is disabled", pass.name()),
+    };
+
+    if optim::is_div_mod_loop(&instructions) {
+        verdicts.push(format!(
+            "divmod idiom: a native division is inserted before the loop by {}",
+            enabled(Pass::DivMod)
+        ));
+    }
+    if let [JumpZ(_), Output(0), Input(0), JumpNz(_)] = instructions[..] {
+        verdicts.push(format!(
+            "echo loop: the input is copied in chunks before the loop by {}",
+            enabled(Pass::Pipe)
+        ));
+        return verdicts;
+    }
+
+    if let [JumpZ(_), Dec(0, 1), JumpNz(_)] = instructions[..] {
+        verdicts.push(format!(
+            "clears the register: replaced with `zero` by {}",
+            enabled(Pass::Zeros)
+        ));
+        return verdicts;
+    }
+
+    // nested loops clearing a register are replaced before the loop is analyzed
+    if config.pass(Pass::Zeros) {
+        optim::replace_zeros(config, &mut instructions);
+    }
+    let Some(Node::Loop(l)) = ir::build(&instructions).into_iter().next() else {
+        unreachable!("the instructions are a single loop")
+    };
+    if !l.is_innermost() {
+        verdicts.push("contains a nested loop: only innermost loops are rewritten".into());
+        return verdicts;
+    }
+
+    let inner = (l.body.iter())
+        .map(|n| match n {
+            Node::Inst(inst) => *inst,
+            Node::Loop(_) => unreachable!("the loop is innermost"),
+        })
+        .collect::<Vec<_>>();
+    verdicts.push(match optim::arithmetic_loop_verdict(&inner) {
+        LoopVerdict::Arithmetic => format!(
+            "only arithmetic, decrements its counter by 1: replaced with multiplications by {}",
+            enabled(Pass::Arithmetic)
+        ),
+        LoopVerdict::Unsupported(inst) => match optim::split_io_arithmetic_loop(&inner) {
+            Some(_) => format!(
+                "contains `{}`: registers the I/O doesn't observe are replaced with multiplications before the loop by {}",
+                inst.name(),
+                enabled(Pass::Arithmetic)
+            ),
+            None => format!("contains `{}`: arithmetic rewrite skipped", inst.name()),
+        },
+        LoopVerdict::Unbalanced(offset) => format!(
+            "moves the register pointer by {offset:+} per iteration: arithmetic rewrite skipped"
+        ),
+        LoopVerdict::OffsetOverflow => {
+            "register offsets don't fit into 16 bits: arithmetic rewrite skipped".into()
+        }
+        LoopVerdict::RunsOnce => format!(
+            "always zeros its counter, the body runs at most once: the closing jump is removed by {}",
+            enabled(Pass::Jumps)
+        ),
+        LoopVerdict::Infinite => {
+            "doesn't change its counter, or sets it to a nonzero value: infinite loop if entered"
+                .into()
+        }
+        LoopVerdict::Step(d) => {
+            format!("iteration diff {d:+}: possible infinite loop, arithmetic rewrite skipped")
+        }
+    });
+    verdicts
+}
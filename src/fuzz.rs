@@ -0,0 +1,500 @@
+//! The `fuzz` mode, which runs a program with random inputs until the time is up, looking for
+//! inputs that crash the interpreter, move the pointer out of the tape, don't finish within the
+//! step limit, or break one of the assertions passed with `--assert`.
+//!
+//! Inputs are random bytes, printable text, numbers, or mutations of previous inputs. An input is
+//! kept for further mutation if it executes an instruction a number of times that no previous
+//! input did, bucketed by powers of two like loop iteration counts. A failing input is shrunk by
+//! removing parts of it, as long as it still fails the same way.
+//!
+//! Assertions compare values with `==`, `!=`, `<`, `<=`, `>` and `>=`, or test the predicate
+//! `ascii(<value>)`. The values are the input `in`, the output `out`, numbers, quoted strings,
+//! and the functions `len`, `sorted`, `reverse` and `trim` applied to a value, for example
+//! `out == sorted(in)` or `len(out) <= 100`. They're only checked if the program finishes.
+
+use std::collections::HashSet;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cli::Config;
+use crate::interp::{Interpreter, Limit, Status};
+use crate::rng::Rng;
+use crate::{error, test, Instruction};
+
+/// The time spent fuzzing, if `--seconds` isn't passed.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+/// The length of generated inputs, if `--max-input` isn't passed.
+pub const DEFAULT_MAX_INPUT: usize = 64;
+/// The number of steps of each run, if `--max-steps` isn't passed.
+const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+/// The number of output bytes of each run, if `--max-output` isn't passed.
+const DEFAULT_MAX_OUTPUT: usize = 1 << 20;
+/// The number of inputs kept for mutation, once it's reached new inputs replace random ones.
+const MAX_CORPUS: usize = 1024;
+/// The number of output bytes printed for a failing input.
+const MAX_PRINTED_OUTPUT: usize = 64;
+
+/// Fuzz the program, and report the first failing input. Fails if one was found.
+pub fn fuzz(config: &Config, path: &Path) -> ExitCode {
+    let mut assertions = Vec::new();
+    for src in config.asserts.iter() {
+        match Assertion::parse(src) {
+            Ok(a) => assertions.push((src.as_str(), a)),
+            Err(e) => {
+                error!("invalid assertion `{src}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let instructions = match test::build(config, path) {
+        Ok(i) => i,
+        Err(errors) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let seed = config.seed.unwrap_or_else(|| {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        now.map_or(0, |d| d.as_nanos() as u64)
+    });
+    let mut fuzzer = Fuzzer {
+        config,
+        instructions: &instructions,
+        assertions: &assertions,
+        max_input: config.max_input.unwrap_or(DEFAULT_MAX_INPUT),
+        rng: Rng::new(seed),
+        corpus: Vec::new(),
+        coverage: HashSet::new(),
+        runs: 0,
+    };
+
+    // panic messages are reported with the failing input instead
+    let panic_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let duration = config.fuzz_duration.unwrap_or(DEFAULT_DURATION);
+    let start = Instant::now();
+    let mut found = None;
+    while start.elapsed() < duration {
+        let input = fuzzer.generate();
+        let run = fuzzer.run(&input);
+        if let Some(failure) = run.failure {
+            found = Some(fuzzer.shrink(input, failure));
+            break;
+        }
+        fuzzer.update_corpus(input, &run.counts);
+    }
+    std::panic::set_hook(panic_hook);
+
+    let (runs, elapsed) = (fuzzer.runs, start.elapsed().as_secs_f64());
+    let Some((input, failure)) = found else {
+        let inputs = fuzzer.corpus.len();
+        println!("no failures in {runs} runs ({elapsed:.1}s, {inputs} inputs kept, seed {seed})");
+        return ExitCode::SUCCESS;
+    };
+    let output = fuzzer.run(&input).output;
+    let reason = match failure {
+        Failure::Panic(msg) => format!("the interpreter panicked: {msg}"),
+        Failure::Exceeded(Limit::Steps) => {
+            let steps = config.max_steps.unwrap_or(DEFAULT_MAX_STEPS);
+            format!("the program didn't finish within {steps} steps")
+        }
+        Failure::Exceeded(limit) => format!("the program stopped: {limit}"),
+        Failure::Assertion(i) => format!("the assertion `{}` doesn't hold", assertions[i].0),
+    };
+    error!("{reason}");
+    println!("found a failing input after {runs} runs ({elapsed:.1}s, seed {seed})");
+    println!("    input:  \"{}\"", input.escape_ascii());
+    print!(
+        "    output: \"{}\"",
+        output[..output.len().min(MAX_PRINTED_OUTPUT)].escape_ascii()
+    );
+    match output.len().checked_sub(MAX_PRINTED_OUTPUT) {
+        Some(n) if n > 0 => println!(" ... {n} more bytes"),
+        _ => println!(),
+    }
+    ExitCode::FAILURE
+}
+
+/// How a program failed for an input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Failure {
+    Panic(String),
+    Exceeded(Limit),
+    /// The assertion with the index doesn't hold.
+    Assertion(usize),
+}
+
+impl Failure {
+    /// Whether both failures are the same kind of failure, panics are compared by their kind
+    /// only, since messages often contain values depending on the input.
+    fn same(&self, other: &Failure) -> bool {
+        match (self, other) {
+            (Failure::Panic(_), Failure::Panic(_)) => true,
+            _ => self == other,
+        }
+    }
+}
+
+/// The result of running the program with an input.
+struct Run {
+    output: Vec<u8>,
+    /// The number of times each instruction was executed.
+    counts: Vec<u64>,
+    failure: Option<Failure>,
+}
+
+struct Fuzzer<'a> {
+    config: &'a Config,
+    instructions: &'a [Instruction],
+    assertions: &'a [(&'a str, Assertion)],
+    max_input: usize,
+    rng: Rng,
+    /// The inputs that covered something new.
+    corpus: Vec<Vec<u8>>,
+    /// The instructions, and the bucketed number of times they were executed by an input.
+    coverage: HashSet<(usize, u32)>,
+    runs: u64,
+}
+
+impl Fuzzer<'_> {
+    fn run(&mut self, input: &[u8]) -> Run {
+        self.runs += 1;
+        let mut limits = self.config.limits();
+        limits.steps = Some(limits.steps.unwrap_or(DEFAULT_MAX_STEPS));
+        limits.output = Some(limits.output.unwrap_or(DEFAULT_MAX_OUTPUT));
+        let mut output = Vec::new();
+        let mut counts = Vec::new();
+        let status = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut interpreter = Interpreter::new(self.instructions, input, &mut output)
+                .with_limits(limits)
+                .with_eof(self.config.eof)
+                .with_counts();
+            if self.config.strict_eof {
+                interpreter = interpreter.with_strict_eof();
+            }
+            let status = interpreter.run();
+            counts = interpreter.counts().unwrap_or_default().to_vec();
+            status
+        }));
+
+        let failure = match status {
+            Err(payload) => {
+                let msg = (payload.downcast_ref::<&str>().copied())
+                    .or(payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                Some(Failure::Panic(msg.to_owned()))
+            }
+            Ok(Status::Exceeded(limit)) => Some(Failure::Exceeded(limit)),
+            Ok(_) => (self.assertions.iter())
+                .position(|(_, a)| !a.holds(input, &output))
+                .map(Failure::Assertion),
+        };
+        Run {
+            output,
+            counts,
+            failure,
+        }
+    }
+
+    fn generate(&mut self) -> Vec<u8> {
+        let rng = &mut self.rng;
+        // short inputs find most bugs, and are cheap to run
+        let len = match rng.below(2) {
+            0 => rng.below(self.max_input.min(8) + 1),
+            _ => rng.below(self.max_input + 1),
+        };
+        match rng.below(5) {
+            0 => (0..len).map(|_| rng.byte()).collect(),
+            1 => (0..len).map(|_| b" !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~\n"[rng.below(96)]).collect(),
+            2 => (0..len).map(|_| b"0123456789 \n-"[rng.below(13)]).collect(),
+            _ if !self.corpus.is_empty() => {
+                let mut input = self.corpus[rng.below(self.corpus.len())].clone();
+                for _ in 0..=rng.below(4) {
+                    self.mutate(&mut input);
+                }
+                input
+            }
+            _ => vec![0; len],
+        }
+    }
+
+    fn mutate(&mut self, input: &mut Vec<u8>) {
+        let rng = &mut self.rng;
+        let i = rng.below(input.len() + 1);
+        match rng.below(5) {
+            0 if i < input.len() => input[i] ^= 1 << rng.below(8),
+            1 if i < input.len() => {
+                input[i] = input[i].wrapping_add(rng.byte() % 3).wrapping_sub(1)
+            }
+            2 if i < input.len() => _ = input.remove(i),
+            3 => {
+                let other = match self.corpus.is_empty() {
+                    true => &*input,
+                    false => &self.corpus[rng.below(self.corpus.len())],
+                };
+                let j = rng.below(other.len() + 1);
+                let chunk = other[j..(j + rng.below(8)).min(other.len())].to_vec();
+                input.splice(i..i, chunk);
+            }
+            _ => input.insert(i, rng.byte()),
+        }
+        input.truncate(self.max_input);
+    }
+
+    /// Keep the input, if it covered something no previous input did.
+    fn update_corpus(&mut self, input: Vec<u8>, counts: &[u64]) {
+        let mut new = false;
+        for (i, &count) in counts.iter().enumerate().filter(|(_, c)| **c > 0) {
+            new |= self.coverage.insert((i, u64::BITS - count.leading_zeros()));
+        }
+        if !new {
+            return;
+        }
+        if self.config.verbose >= 1 {
+            println!("new coverage: \"{}\"", input.escape_ascii());
+        }
+        match self.corpus.len() < MAX_CORPUS {
+            true => self.corpus.push(input),
+            false => {
+                let i = self.rng.below(MAX_CORPUS);
+                self.corpus[i] = input;
+            }
+        }
+    }
+
+    /// Remove parts of the input as long as it still fails the same way.
+    fn shrink(&mut self, mut input: Vec<u8>, failure: Failure) -> (Vec<u8>, Failure) {
+        let mut chunk = input.len().div_ceil(2);
+        while chunk > 0 {
+            let mut start = 0;
+            while start < input.len() {
+                let mut candidate = input.clone();
+                candidate.drain(start..(start + chunk).min(input.len()));
+                match self.run(&candidate).failure {
+                    Some(f) if f.same(&failure) => input = candidate,
+                    _ => start += chunk,
+                }
+            }
+            chunk /= 2;
+        }
+        (input, failure)
+    }
+}
+
+/// An assertion about the input and the output of a finished run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Assertion {
+    Compare(Expr, Op, Expr),
+    Ascii(Expr),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Input,
+    Output,
+    Num(u64),
+    Str(Vec<u8>),
+    Len(Box<Expr>),
+    Sorted(Box<Expr>),
+    Reverse(Box<Expr>),
+    Trim(Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Value {
+    Num(u64),
+    Bytes(Vec<u8>),
+}
+
+impl Assertion {
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut parser = Parser { src, pos: 0 };
+        let assertion = if let Some(rest) = src.trim_start().strip_prefix("ascii(") {
+            parser.pos = src.len() - rest.len();
+            let expr = parser.expr()?;
+            parser.expect(")")?;
+            Assertion::Ascii(expr)
+        } else {
+            let lhs = parser.expr()?;
+            let op = parser.op()?;
+            let rhs = parser.expr()?;
+            if lhs.is_num() != rhs.is_num() {
+                return Err("can't compare a number with bytes".to_owned());
+            }
+            Assertion::Compare(lhs, op, rhs)
+        };
+        parser.skip_whitespace();
+        if parser.pos < src.len() {
+            return Err(format!("unexpected `{}`", &src[parser.pos..]));
+        }
+        Ok(assertion)
+    }
+
+    /// Whether the assertion holds for the input and output of a finished run.
+    pub fn holds(&self, input: &[u8], output: &[u8]) -> bool {
+        match self {
+            Assertion::Compare(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.eval(input, output), rhs.eval(input, output));
+                match op {
+                    Op::Eq => lhs == rhs,
+                    Op::Ne => lhs != rhs,
+                    Op::Lt => lhs < rhs,
+                    Op::Le => lhs <= rhs,
+                    Op::Gt => lhs > rhs,
+                    Op::Ge => lhs >= rhs,
+                }
+            }
+            Assertion::Ascii(expr) => match expr.eval(input, output) {
+                Value::Num(_) => true,
+                Value::Bytes(b) => {
+                    (b.iter()).all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+                }
+            },
+        }
+    }
+}
+
+impl Expr {
+    fn is_num(&self) -> bool {
+        matches!(self, Expr::Num(_) | Expr::Len(_))
+    }
+
+    fn eval(&self, input: &[u8], output: &[u8]) -> Value {
+        let bytes = |e: &Expr| match e.eval(input, output) {
+            Value::Bytes(b) => b,
+            Value::Num(n) => n.to_string().into_bytes(),
+        };
+        match self {
+            Expr::Input => Value::Bytes(input.to_vec()),
+            Expr::Output => Value::Bytes(output.to_vec()),
+            Expr::Num(n) => Value::Num(*n),
+            Expr::Str(s) => Value::Bytes(s.clone()),
+            Expr::Len(e) => Value::Num(bytes(e).len() as u64),
+            Expr::Sorted(e) => {
+                let mut b = bytes(e);
+                b.sort_unstable();
+                Value::Bytes(b)
+            }
+            Expr::Reverse(e) => {
+                let mut b = bytes(e);
+                b.reverse();
+                Value::Bytes(b)
+            }
+            Expr::Trim(e) => Value::Bytes(bytes(e).trim_ascii().to_vec()),
+        }
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.pos = self.src.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let eaten = self.rest().starts_with(token);
+        if eaten {
+            self.pos += token.len();
+        }
+        eaten
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        match self.eat(token) {
+            true => Ok(()),
+            false => Err(format!("expected `{token}`")),
+        }
+    }
+
+    fn op(&mut self) -> Result<Op, String> {
+        let ops = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        (ops.into_iter())
+            .find(|(token, _)| self.eat(token))
+            .map(|(_, op)| op)
+            .ok_or_else(|| "expected one of `==`, `!=`, `<`, `<=`, `>` or `>=`".to_owned())
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        if self.eat("\"") {
+            return self.string();
+        }
+        let len = (self.rest().bytes())
+            .take_while(|b| b.is_ascii_alphanumeric() || *b == b'_')
+            .count();
+        let (src, start) = (self.src, self.pos);
+        self.pos += len;
+        let word = &src[start..self.pos];
+        if let Ok(n) = word.parse() {
+            return Ok(Expr::Num(n));
+        }
+        let function: fn(Box<Expr>) -> Expr = match word {
+            "in" => return Ok(Expr::Input),
+            "out" => return Ok(Expr::Output),
+            "len" => Expr::Len,
+            "sorted" => Expr::Sorted,
+            "reverse" => Expr::Reverse,
+            "trim" => Expr::Trim,
+            "" => return Err("expected a value".to_owned()),
+            _ => return Err(format!("unknown value `{word}`")),
+        };
+        self.expect("(")?;
+        let arg = self.expr()?;
+        self.expect(")")?;
+        Ok(function(Box::new(arg)))
+    }
+
+    fn string(&mut self) -> Result<Expr, String> {
+        let mut bytes = Vec::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            let escaped = match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(Expr::Str(bytes));
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some(c @ ('\\' | '"')) => c,
+                    _ => return Err("invalid escape sequence".to_owned()),
+                },
+                c => c,
+            };
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+        }
+        Err("unterminated string".to_owned())
+    }
+}
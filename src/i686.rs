@@ -0,0 +1,939 @@
+//! The backend generating 32-bit x86 code, either as linux ELF executables for systems that still
+//! run 32-bit userlands, or as flat binary images running on bare metal. The code of the
+//! instructions is the one of the 64-bit backend in [`crate::x86`], whose encoders are shared, but
+//! addresses are 32 bits, and the primitives setting up the tape, performing I/O and exiting
+//! depend on the [`Runtime`]. Checkpoints, the interactive mode and reporting tape overruns
+//! aren't supported, an access outside of the tape of an executable faults on its guard pages.
+
+use crate::backend::{Artifact, Backend, RuntimeOptions};
+use crate::cli::{self, Config, Pass};
+use crate::object::{Executable, Machine, Note, BSS_VADDR};
+use crate::optim;
+use crate::x86::*;
+use crate::Instruction;
+
+/// Number of bytes of stack space below the tape, used by the runtime routines.
+const STACK_SIZE: usize = 1 << 16;
+/// Number of bytes of inaccessible guard pages on both ends of the tape.
+const MAP_GUARD_SIZE: usize = 1 << 16;
+/// The granularity of memory protection.
+const PAGE_SIZE: usize = 1 << 12;
+/// Number of bytes of the output buffer.
+const OUTPUT_BUF_LEN: i32 = 1 << 12;
+/// The runtime state is stored in the bss segment: the number of buffered output bytes, and the
+/// output buffer.
+const BSS_OUTPUT_LEN: i32 = BSS_VADDR as i32;
+const BSS_OUTPUT_BUF: i32 = BSS_VADDR as i32 + 4;
+
+const SYSCALL_EXIT: i32 = 1;
+const SYSCALL_READ: i32 = 3;
+const SYSCALL_WRITE: i32 = 4;
+const STDOUT_FD: i32 = 1;
+
+/// The address flat binary images are loaded at if `--org` isn't passed, the conventional load
+/// address of multiboot kernels.
+const DEFAULT_ORG: u32 = 0x10_0000;
+/// The i/o port of the first serial port, COM1, and its line status register.
+const COM1: i32 = 0x3F8;
+const COM1_LINE_STATUS: i32 = COM1 + 5;
+/// The line status bits signaling a received byte, and an empty transmitter holding register.
+const LINE_DATA_READY: u8 = 0x01;
+const LINE_TRANSMIT_EMPTY: u8 = 0x20;
+/// The byte received on the serial port which signals the end of the input, end of transmission.
+const SERIAL_EOF: u8 = 0x04;
+
+const MULTIBOOT_MAGIC: u32 = 0x1BAD_B002;
+/// The flag signaling that the load addresses are stored in the header, which is required for
+/// images that aren't ELF files.
+const MULTIBOOT_AOUT_KLUDGE: u32 = 1 << 16;
+const MULTIBOOT_HEADER_LEN: usize = 32;
+
+/// The backend generating 32-bit x86 linux ELF binaries.
+pub struct Elf;
+
+impl Backend for Elf {
+    fn name(&self) -> &'static str {
+        "elf"
+    }
+
+    fn extension(&self) -> &'static str {
+        "elf"
+    }
+
+    fn compile(&self, config: &Config, ir: &[Instruction], options: &RuntimeOptions) -> Artifact {
+        Artifact::Executable(compile(config, options, ir))
+    }
+}
+
+/// The backend generating flat binary images of 32-bit x86 code, which run on bare metal and
+/// perform I/O on the serial port.
+pub struct FlatBin;
+
+impl Backend for FlatBin {
+    fn name(&self) -> &'static str {
+        "flat-bin"
+    }
+
+    fn extension(&self) -> &'static str {
+        "bin"
+    }
+
+    fn compile(&self, config: &Config, ir: &[Instruction], options: &RuntimeOptions) -> Artifact {
+        Artifact::Image(compile_flat(config, options, ir))
+    }
+}
+
+/// Generate a 32-bit x86 linux ELF binary
+pub fn compile(config: &Config, options: &RuntimeOptions, instructions: &[Instruction]) -> Vec<u8> {
+    let mut executable = Executable::new(Machine::I386);
+    executable.bss_len = (BSS_OUTPUT_BUF - BSS_OUTPUT_LEN + OUTPUT_BUF_LEN) as usize;
+    executable.notes.push(Note::version());
+    let runtime = Runtime::Linux {
+        buffered: options.buffered_output,
+    };
+    if config.pass(Pass::Schedule) {
+        let (scheduled, _) = schedule_pointer_moves(config, instructions);
+        write_program(config, options, runtime, &mut executable.text, &scheduled);
+    } else {
+        write_program(config, options, runtime, &mut executable.text, instructions);
+    }
+
+    #[cfg(feature = "disasm")]
+    if config.print_asm {
+        print_disassembly(&executable.text, executable.text_vaddr(), 32);
+        println!("============================================================");
+    }
+
+    let code = executable.write();
+    if config.verbose >= 1 {
+        println!("generated code size: {}b", code.len());
+        println!("============================================================");
+    }
+    code
+}
+
+/// Generate a flat binary image of 32-bit x86 code loaded at `--org`, optionally starting with a
+/// multiboot header.
+pub fn compile_flat(
+    config: &Config,
+    options: &RuntimeOptions,
+    instructions: &[Instruction],
+) -> Vec<u8> {
+    let org = config.org.unwrap_or(DEFAULT_ORG);
+    let header_len = match config.multiboot {
+        true => MULTIBOOT_HEADER_LEN,
+        false => 0,
+    };
+    let code_org = org + header_len as u32;
+    let runtime = Runtime::Serial { org: code_org };
+    let mut code = Vec::new();
+    let tape_len = if config.pass(Pass::Schedule) {
+        let (scheduled, _) = schedule_pointer_moves(config, instructions);
+        write_program(config, options, runtime, &mut code, &scheduled)
+    } else {
+        write_program(config, options, runtime, &mut code, instructions)
+    };
+
+    #[cfg(feature = "disasm")]
+    if config.print_asm {
+        print_disassembly(&code, code_org as u64, 32);
+        println!("============================================================");
+    }
+
+    let mut image = Vec::with_capacity(header_len + code.len());
+    if config.multiboot {
+        let load_end = code_org + code.len() as u32;
+        let bss_end = serial_tape_addr(code_org, code.len()) + tape_len as u32;
+        let fields = [
+            MULTIBOOT_MAGIC,
+            MULTIBOOT_AOUT_KLUDGE,
+            MULTIBOOT_MAGIC
+                .wrapping_add(MULTIBOOT_AOUT_KLUDGE)
+                .wrapping_neg(),
+            // header, load, load end, bss end and entry address
+            org,
+            org,
+            load_end,
+            bss_end,
+            code_org,
+        ];
+        for field in fields {
+            image.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+    image.extend_from_slice(&code);
+
+    if config.verbose >= 1 {
+        println!("generated code size: {}b", image.len());
+        println!("============================================================");
+    }
+    image
+}
+
+/// How the generated code interacts with its environment, which only affects setting up the
+/// tape, performing I/O and exiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Runtime {
+    /// A linux process performing I/O using system calls, whose output is collected in a buffer
+    /// if it's `buffered`.
+    Linux { buffered: bool },
+    /// Code running on bare metal in 32-bit protected mode, located at `org`. I/O is performed on
+    /// the serial port COM1, which is polled, and the processor is halted once the program
+    /// finishes. The stack and the tape follow the code, see [`serial_tape_addr`].
+    Serial { org: u32 },
+}
+
+/// The address of the tape of code running on bare metal, which starts [`STACK_SIZE`] bytes
+/// after the `code_len` bytes of code at `org`, leaving space for the stack.
+fn serial_tape_addr(org: u32, code_len: usize) -> u32 {
+    (org + code_len as u32).next_multiple_of(16) + STACK_SIZE as u32
+}
+
+/// The runtime routines called by the code of the instructions.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Routine {
+    /// See [`write_flush`].
+    Flush,
+    /// See [`write_append`].
+    Append,
+    /// See [`write_serial_write`].
+    SerialWrite,
+    /// See [`write_serial_read`].
+    SerialRead,
+}
+
+/// The positions after the displacements of calls of runtime routines, which are updated once
+/// the routines are written.
+#[derive(Default)]
+struct Calls(Vec<(usize, Routine)>);
+
+impl Calls {
+    fn write(&mut self, code: &mut Vec<u8>, routine: Routine) {
+        write(code, call_rel32(0));
+        self.0.push((code.len(), routine));
+    }
+}
+
+/// Write the machine code of the `instructions`, and return the length of the tape.
+///
+/// `esp` points to the start of the tape and `ecx` is the register pointer, so the current
+/// register is addressed by the same `[esp + ecx]` scaled index byte as in 64-bit mode.
+fn write_program(
+    config: &Config,
+    options: &RuntimeOptions,
+    runtime: Runtime,
+    code: &mut Vec<u8>,
+    instructions: &[Instruction],
+) -> usize {
+    // only map the registers the program can access if they are known
+    let accessed = optim::register_range(instructions)
+        .filter(|r| config.pass(Pass::Bounds) && r.is_within(options.tape_len));
+    let tape_len = match accessed {
+        Some(r) => (r.max as usize + 1).next_multiple_of(16),
+        None => options.tape_len,
+    };
+    if config.verbose >= 1 {
+        println!("register tape size: {tape_len}");
+        if let Runtime::Linux { .. } = runtime {
+            println!("mapped register tape with guard pages");
+        }
+        println!("============================================================");
+    }
+    if config.mem_stats {
+        cli::print_mem_stats(accessed, tape_len);
+    }
+
+    // the position after the address of the tape, which is known once the code is written
+    let mut tape_addr_pos = None;
+    match runtime {
+        Runtime::Linux { .. } => {
+            write_map_tape(code, tape_len);
+            if config.sandbox {
+                // `prctl(PR_SET_SECCOMP, SECCOMP_MODE_STRICT)` only allows the read, write, exit
+                // and sigreturn syscalls from here on
+                const SYSCALL_PRCTL: i32 = 172;
+                const PR_SET_SECCOMP: i32 = 22;
+                const SECCOMP_MODE_STRICT: i32 = 1;
+                write(code, mov_imm32_to_r32(Reg::Rax, SYSCALL_PRCTL));
+                write(code, mov_imm32_to_r32(Reg::Rbx, PR_SET_SECCOMP));
+                write(code, mov_imm32_to_r32(Reg::Rcx, SECCOMP_MODE_STRICT));
+                write(code, INT_0X80);
+                write_exit_on_error(code);
+            }
+
+            // the register pointer starts at the first register, which is located after the
+            // guard pages
+            write(code, mov_imm32_to_r32(Reg::Rcx, MAP_GUARD_SIZE as i32));
+        }
+        Runtime::Serial { .. } => {
+            write(code, CLI);
+            write(code, CLD);
+            write(code, mov_imm32_to_r32(Reg::Rsp, 0));
+            tape_addr_pos = Some(code.len());
+
+            // memory isn't necessarily cleared by the boot loader, `rep stosb` leaves `ecx` zero,
+            // so the register pointer starts at the first register
+            write(code, mov_r32_to_r32(Reg::Rsp, Reg::Rdi));
+            write(code, mov_imm32_to_r32(Reg::Rcx, tape_len as i32));
+            write(code, xor_r32_r32(Reg::Rax, Reg::Rax));
+            write(code, REP_STOSB);
+            write_serial_init(code);
+        }
+    }
+
+    let mut calls = Calls::default();
+    write_instructions(options, runtime, code, instructions, &mut calls);
+    write_exit(code, runtime, &mut calls);
+
+    let mut routines = Vec::new();
+    match runtime {
+        Runtime::Linux { buffered: false } => (),
+        Runtime::Linux { buffered: true } => {
+            // appending to a full buffer falls through into flushing it
+            routines.push((Routine::Append, code.len()));
+            write_append(code);
+            routines.push((Routine::Flush, code.len()));
+            write_flush(code);
+        }
+        Runtime::Serial { .. } => {
+            routines.push((Routine::SerialWrite, code.len()));
+            write_serial_write(code);
+            routines.push((Routine::SerialRead, code.len()));
+            write_serial_read(code);
+        }
+    }
+    for (pos, routine) in calls.0 {
+        let (_, target) = (routines.iter())
+            .find(|(r, _)| *r == routine)
+            .expect("the routines of the runtime are written");
+        let disp = *target as i32 - pos as i32;
+        code[pos - 4..pos].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+
+    if let (Runtime::Serial { org }, Some(pos)) = (runtime, tape_addr_pos) {
+        let addr = serial_tape_addr(org, code.len());
+        code[pos - 4..pos].copy_from_slice(&addr.to_le_bytes());
+    }
+    tape_len
+}
+
+/// Map the register tape using `mmap2`, and use it as the stack, leaving [`STACK_SIZE`]
+/// bytes below it for the stack usage of the generated code. The tape is surrounded by
+/// [`MAP_GUARD_SIZE`] bytes of inaccessible guard pages on both ends, so that accesses outside of
+/// it fault. The program exits with status 1 if the mapping fails.
+fn write_map_tape(code: &mut Vec<u8>, tape_len: usize) {
+    const SYSCALL_MMAP2: i32 = 192;
+    const PROT_READ: i32 = 0x1;
+    const PROT_WRITE: i32 = 0x2;
+    const MAP_PRIVATE: i32 = 0x02;
+    const MAP_ANONYMOUS: i32 = 0x20;
+    write(code, mov_imm32_to_r32(Reg::Rax, SYSCALL_MMAP2));
+    write(code, xor_r32_r32(Reg::Rbx, Reg::Rbx));
+    let tape_len = tape_len.next_multiple_of(PAGE_SIZE);
+    let len = STACK_SIZE + MAP_GUARD_SIZE + tape_len + MAP_GUARD_SIZE;
+    write(code, mov_imm32_to_r32(Reg::Rcx, len as i32));
+    write(code, mov_imm32_to_r32(Reg::Rdx, PROT_READ | PROT_WRITE));
+    write(
+        code,
+        mov_imm32_to_r32(Reg::Rsi, MAP_PRIVATE | MAP_ANONYMOUS),
+    );
+    write(code, mov_imm32_to_r32(Reg::Rdi, -1));
+    write(code, xor_r32_r32(Reg::Rbp, Reg::Rbp));
+    write(code, INT_0X80);
+    write_exit_on_error(code);
+
+    write(code, add_imm32_to_r32(Reg::Rax, STACK_SIZE as i32));
+    write(code, mov_r32_to_r32(Reg::Rax, Reg::Rsp));
+
+    // make the guard pages below and above the tape inaccessible
+    const SYSCALL_MPROTECT: i32 = 125;
+    const PROT_NONE: i32 = 0x0;
+    for offset in [0, MAP_GUARD_SIZE + tape_len] {
+        write(code, mov_imm32_to_r32(Reg::Rax, SYSCALL_MPROTECT));
+        write(code, mov_r32_to_r32(Reg::Rsp, Reg::Rbx));
+        if offset != 0 {
+            write(code, add_imm32_to_r32(Reg::Rbx, offset as i32));
+        }
+        write(code, mov_imm32_to_r32(Reg::Rcx, MAP_GUARD_SIZE as i32));
+        write(code, mov_imm32_to_r32(Reg::Rdx, PROT_NONE));
+        write(code, INT_0X80);
+        write_exit_on_error(code);
+    }
+}
+
+/// Exit with status 1 if the system call returned an error, which is a value in `-4095..0`.
+fn write_exit_on_error(code: &mut Vec<u8>) {
+    const EXIT_LEN: usize = 2 * mov_imm32_to_r32(Reg::Rax, 0).len() + INT_0X80.len();
+    write(code, cmp_r32_with_imm32(Reg::Rax, -4095));
+    write(code, jb_rel8(EXIT_LEN as i8));
+    write(code, mov_imm32_to_r32(Reg::Rax, SYSCALL_EXIT));
+    write(code, mov_imm32_to_r32(Reg::Rbx, 1));
+    write(code, INT_0X80);
+}
+
+/// Write the address of the brainfuck register at displacement `disp` into `dest`.
+fn write_register_address(code: &mut Vec<u8>, dest: Reg, disp: i32) {
+    write(code, mov_r32_to_r32(Reg::Rsp, dest));
+    write(code, add_r32_to_r32(Reg::Rcx, dest));
+    match disp {
+        0 => (),
+        -128..=127 => write(code, add_imm8_to_r32(dest, disp as i8)),
+        _ => write(code, add_imm32_to_r32(dest, disp)),
+    }
+}
+
+/// Write the `edx` bytes at `esi` to the output, by appending them to the output buffer, or
+/// writing them immediately if the output isn't buffered.
+fn write_output(code: &mut Vec<u8>, runtime: Runtime, calls: &mut Calls) {
+    match runtime {
+        Runtime::Linux { buffered: true } => calls.write(code, Routine::Append),
+        Runtime::Linux { buffered: false } => {
+            write(code, push_r32(Reg::Rcx));
+            write(code, mov_r32_to_r32(Reg::Rsi, Reg::Rcx));
+            write(code, mov_imm32_to_r32(Reg::Rax, SYSCALL_WRITE));
+            write(code, mov_imm32_to_r32(Reg::Rbx, STDOUT_FD));
+            write(code, INT_0X80);
+            write(code, pop_r32(Reg::Rcx));
+        }
+        Runtime::Serial { .. } => calls.write(code, Routine::SerialWrite),
+    }
+}
+
+/// Read a byte of input to `esi`, `eax` is set to the number of bytes read, which is zero at the
+/// end of the input.
+fn write_input(code: &mut Vec<u8>, runtime: Runtime, calls: &mut Calls) {
+    match runtime {
+        Runtime::Linux { buffered } => {
+            // the output is written before the program waits for input
+            if buffered {
+                calls.write(code, Routine::Flush);
+            }
+
+            write(code, push_r32(Reg::Rcx));
+            write(code, mov_imm32_to_r32(Reg::Rax, SYSCALL_READ));
+            const _STDIN_FD: i32 = 0;
+            write(code, xor_r32_r32(Reg::Rbx, Reg::Rbx));
+            write(code, mov_r32_to_r32(Reg::Rsi, Reg::Rcx));
+            write(code, mov_imm32_to_r32(Reg::Rdx, 1));
+            write(code, INT_0X80);
+            write(code, pop_r32(Reg::Rcx));
+        }
+        Runtime::Serial { .. } => calls.write(code, Routine::SerialRead),
+    }
+}
+
+/// Stop the program once it finished, by exiting the process, or halting the processor.
+fn write_exit(code: &mut Vec<u8>, runtime: Runtime, calls: &mut Calls) {
+    match runtime {
+        Runtime::Linux { buffered } => {
+            if buffered {
+                calls.write(code, Routine::Flush);
+            }
+            write(code, mov_imm32_to_r32(Reg::Rax, SYSCALL_EXIT));
+            write(code, xor_r32_r32(Reg::Rbx, Reg::Rbx));
+            write(code, INT_0X80);
+        }
+        Runtime::Serial { .. } => {
+            // non-maskable interrupts still wake up the processor
+            write(code, CLI);
+            write(code, HLT);
+            const LOOP_LEN: usize = HLT.len() + jmp_rel8(0).len();
+            write(code, jmp_rel8(-(LOOP_LEN as i8)));
+        }
+    }
+}
+
+/// Initialize the serial port COM1 to 115200 baud, 8 data bits, no parity and one stop bit,
+/// without interrupts.
+fn write_serial_init(code: &mut Vec<u8>) {
+    #[rustfmt::skip]
+    const REGISTERS: [(i32, u8); 7] = [
+        // disable interrupts
+        (1, 0x00),
+        // enable the divisor latch, and set the divisor to 1
+        (3, 0x80),
+        (0, 0x01),
+        (1, 0x00),
+        // 8 data bits, no parity, one stop bit
+        (3, 0x03),
+        // enable and clear the fifos
+        (2, 0xC7),
+        // data terminal ready and request to send
+        (4, 0x03),
+    ];
+    for (offset, value) in REGISTERS {
+        write(code, mov_imm32_to_r32(Reg::Rdx, COM1 + offset));
+        write(code, mov_imm32_to_r32(Reg::Rax, value as i32));
+        write(code, OUT_DX_AL);
+    }
+}
+
+fn write_instructions(
+    options: &RuntimeOptions,
+    runtime: Runtime,
+    code: &mut Vec<u8>,
+    instructions: &[Instruction],
+    calls: &mut Calls,
+) {
+    // scaled index byte used to index into the brainfuck register array
+    const SIB: Sib = Sib::new(Scale::B1, Reg::Rcx, Reg::Rsp);
+
+    // stores if the jump is redundant, and the location after the opening jump (`[`), the jump
+    // offset is stored inside the 4 bytes before that
+    let mut jump_stack = Vec::new();
+    // consecutive constant output, which is written at once
+    let mut output = Vec::new();
+    for inst in instructions.iter() {
+        if !matches!(inst, Instruction::OutputConst(_)) && !output.is_empty() {
+            write_output_const(code, runtime, &output, calls);
+            output.clear();
+        }
+
+        match *inst {
+            #[rustfmt::skip]
+            Instruction::Shl(n) => match n {
+                0..=127 => write(code, sub_imm8_from_r32(Reg::Rcx, n as i8)),
+                _ =>       write(code, sub_imm32_from_r32(Reg::Rcx, n as i32)),
+            },
+            #[rustfmt::skip]
+            Instruction::Shr(n) => match n {
+                0..=127 => write(code, add_imm8_to_r32(Reg::Rcx, n as i8)),
+                _ =>       write(code, add_imm32_to_r32(Reg::Rcx, n as i32)),
+            },
+            #[rustfmt::skip]
+            Instruction::Inc(disp, n) => match disp {
+                0 =>          write(code, add_imm8_to_sib8(SIB, n)),
+                -128..=127 => write(code, add_imm8_to_sib8_disp8(SIB, disp as i8, n)),
+                _ =>          write(code, add_imm8_to_sib8_disp32(SIB, disp as i32, n)),
+            },
+            #[rustfmt::skip]
+            Instruction::Dec(disp, n) => match disp {
+                0 =>          write(code, sub_imm8_from_sib8(SIB, n)),
+                -128..=127 => write(code, sub_imm8_from_sib8_disp8(SIB, disp as i8, n)),
+                _ =>          write(code, sub_imm8_from_sib8_disp32(SIB, disp as i32, n)),
+            },
+            // the echo loop following the pipe copies the input byte by byte
+            Instruction::Pipe => (),
+            Instruction::Output(disp) => {
+                write_register_address(code, Reg::Rsi, disp as i32);
+                write(code, mov_imm32_to_r32(Reg::Rdx, 1));
+                write_output(code, runtime, calls);
+            }
+            Instruction::Input(disp) => {
+                write_register_address(code, Reg::Rsi, disp as i32);
+                write_input(code, runtime, calls);
+
+                // no bytes are read at the end of the input
+                if let Some(val) = options.eof.value() {
+                    let mut set = Vec::new();
+                    #[rustfmt::skip]
+                    match disp {
+                        0 =>          write(&mut set, mov_imm8_to_sib8(SIB, val)),
+                        -128..=127 => write(&mut set, mov_imm8_to_sib8_disp8(SIB, disp as i8, val)),
+                        _ =>          write(&mut set, mov_imm8_to_sib8_disp32(SIB, disp as i32, val)),
+                    };
+                    write(code, cmp_r32_with_imm8(Reg::Rax, 0x00));
+                    write(code, jnz_rel8(set.len() as i8));
+                    code.extend_from_slice(&set);
+                }
+            }
+            Instruction::JumpZ(jump) => {
+                let redundant = jump.is_redundant();
+                if !redundant {
+                    write(code, cmp_sib8_with_imm8(SIB, 0));
+                    // actual jump offset is updated when writing the matching JumpNz (`]`) instruction
+                    write(code, jz_rel32(0));
+                }
+                jump_stack.push((redundant, code.len()));
+            }
+            Instruction::JumpNz(jump) => {
+                let Some((start_redundant, start_pos)) = jump_stack.pop() else {
+                    unreachable!()
+                };
+
+                const CMP_INST_LEN: usize = cmp_sib8_with_imm8(SIB, 0).len();
+                const REL8_INST_LEN: usize = CMP_INST_LEN + jnz_rel8(0).len();
+                const REL32_JUMP_INST_LEN: usize = jnz_rel32(0).len();
+                const REL32_INST_LEN: usize = CMP_INST_LEN + REL32_JUMP_INST_LEN;
+                let offset_without_inst = code.len() - start_pos;
+                let redundant = jump.is_redundant();
+
+                let (rel8, offset) = if redundant {
+                    (offset_without_inst < 128, offset_without_inst)
+                } else if offset_without_inst + REL8_INST_LEN < 128 {
+                    (true, offset_without_inst + REL8_INST_LEN)
+                } else {
+                    (false, offset_without_inst + REL32_INST_LEN)
+                };
+
+                if !start_redundant {
+                    if rel8 {
+                        let jump_inst = jz_rel8(offset as i8);
+                        code.splice(start_pos - REL32_JUMP_INST_LEN..start_pos, jump_inst);
+
+                        // the calls inside the loop body were moved by the shorter jump
+                        let moved = REL32_JUMP_INST_LEN - jump_inst.len();
+                        for (pos, _) in calls.0.iter_mut().rev() {
+                            if *pos < start_pos {
+                                break;
+                            }
+                            *pos -= moved;
+                        }
+                    } else {
+                        let offset =
+                            i32::try_from(offset).expect("loop bodies are smaller than 2GiB");
+                        code[start_pos - 4..start_pos].copy_from_slice(&offset.to_le_bytes());
+                    }
+                }
+
+                if !redundant {
+                    write(code, cmp_sib8_with_imm8(SIB, 0));
+                    if rel8 {
+                        write(code, jnz_rel8(-(offset as i8)));
+                    } else {
+                        write(code, jnz_rel32(-(offset as i32)));
+                    }
+                }
+            }
+            #[rustfmt::skip]
+            Instruction::Zero(disp) => match disp {
+                0 =>          write(code, mov_imm8_to_sib8(SIB, 0x00)),
+                -128..=127 => write(code, mov_imm8_to_sib8_disp8(SIB, disp as i8, 0x00)),
+                _ =>          write(code, mov_imm8_to_sib8_disp32(SIB, disp as i32, 0x00)),
+            },
+            #[rustfmt::skip]
+            Instruction::Set(disp, n) => match disp {
+                0 =>          write(code, mov_imm8_to_sib8(SIB, n)),
+                -128..=127 => write(code, mov_imm8_to_sib8_disp8(SIB, disp as i8, n)),
+                _ =>          write(code, mov_imm8_to_sib8_disp32(SIB, disp as i32, n)),
+            },
+            Instruction::Add(disp) => {
+                write(code, mov_sib8_to_r8(SIB, Reg::Rax));
+                #[rustfmt::skip]
+                match disp {
+                    0 =>          write(code, add_r8_to_sib8(Reg::Rax, SIB)),
+                    -128..=127 => write(code, add_r8_to_sib8_disp8(Reg::Rax, SIB, disp as i8)),
+                    _ =>          write(code, add_r8_to_sib8_disp32(Reg::Rax, SIB, disp as i32)),
+                };
+            }
+            Instruction::Sub(disp) => {
+                write(code, mov_sib8_to_r8(SIB, Reg::Rax));
+                #[rustfmt::skip]
+                match disp {
+                    0 =>          write(code, sub_r8_from_sib8(Reg::Rax, SIB)),
+                    -128..=127 => write(code, sub_r8_from_sib8_disp8(Reg::Rax, SIB, disp as i8)),
+                    _ =>          write(code, sub_r8_from_sib8_disp32(Reg::Rax, SIB, disp as i32)),
+                };
+            }
+            Instruction::AddMul(disp, n) => {
+                write_mul_sib8_to_al(code, SIB, n);
+                #[rustfmt::skip]
+                match disp {
+                    0 =>          write(code, add_r8_to_sib8(Reg::Rax, SIB)),
+                    -128..=127 => write(code, add_r8_to_sib8_disp8(Reg::Rax, SIB, disp as i8)),
+                    _ =>          write(code, add_r8_to_sib8_disp32(Reg::Rax, SIB, disp as i32)),
+                };
+            }
+            Instruction::SubMul(disp, n) => {
+                write_mul_sib8_to_al(code, SIB, n);
+                #[rustfmt::skip]
+                match disp {
+                    0 =>          write(code, sub_r8_from_sib8(Reg::Rax, SIB)),
+                    -128..=127 => write(code, sub_r8_from_sib8_disp8(Reg::Rax, SIB, disp as i8)),
+                    _ =>          write(code, sub_r8_from_sib8_disp32(Reg::Rax, SIB, disp as i32)),
+                };
+            }
+            Instruction::CopyRange(src, dst, len) => {
+                let (src, dst, len) = (src as i32, dst as i32, len as i32);
+                // copy backwards if the start of the destination overlaps the source
+                let backwards = src < dst && dst < src + len;
+                if backwards {
+                    write_register_address(code, Reg::Rsi, src + len - 1);
+                    write_register_address(code, Reg::Rdi, dst + len - 1);
+                    write(code, STD);
+                } else {
+                    write_register_address(code, Reg::Rsi, src);
+                    write_register_address(code, Reg::Rdi, dst);
+                }
+
+                write(code, push_r32(Reg::Rcx));
+                write(code, mov_imm32_to_r32(Reg::Rcx, len));
+                write(code, REP_MOVSB);
+                write(code, pop_r32(Reg::Rcx));
+                if backwards {
+                    write(code, CLD);
+                }
+            }
+            Instruction::DivMod => write_div_mod(code, SIB),
+            Instruction::PrintDecimal(disp) => write_print_decimal(code, runtime, disp, calls),
+            Instruction::OutputConst(n) => output.push(n),
+        }
+    }
+    if !output.is_empty() {
+        write_output_const(code, runtime, &output, calls);
+    }
+}
+
+/// Write the constant `bytes` to the output, they are embedded into the code and jumped over.
+/// Bytes that don't fit into the output buffer at once are written in chunks.
+fn write_output_const(code: &mut Vec<u8>, runtime: Runtime, bytes: &[u8], calls: &mut Calls) {
+    match bytes.len() {
+        0..=127 => write(code, jmp_rel8(bytes.len() as i8)),
+        _ => write(code, jmp_rel32(bytes.len() as i32)),
+    }
+    let start = code.len();
+    code.extend_from_slice(bytes);
+
+    // there's no instruction pointer relative addressing in 32-bit mode, the address of the code
+    // is pushed by a call of the following instruction instead
+    for (i, chunk) in bytes.chunks(OUTPUT_BUF_LEN as usize).enumerate() {
+        write(code, call_rel32(0));
+        let disp = code.len() - (start + i * OUTPUT_BUF_LEN as usize);
+        write(code, pop_r32(Reg::Rsi));
+        write(code, sub_imm32_from_r32(Reg::Rsi, disp as i32));
+        write(code, mov_imm32_to_r32(Reg::Rdx, chunk.len() as i32));
+        write_output(code, runtime, calls);
+    }
+}
+
+/// Write the value of the register at displacement `disp` to the output as decimal number, the
+/// digits are generated backwards into a buffer on the stack.
+fn write_print_decimal(code: &mut Vec<u8>, runtime: Runtime, disp: i16, calls: &mut Calls) {
+    write_register_address(code, Reg::Rsi, disp as i32);
+    write(code, xor_r32_r32(Reg::Rax, Reg::Rax));
+    write(code, mov_m8_to_r8(RmI::RegRsi, Reg::Rax));
+
+    write(code, push_r32(Reg::Rcx));
+    write(code, sub_imm8_from_r32(Reg::Rsp, 4));
+    write(code, mov_r32_to_r32(Reg::Rsp, Reg::Rsi));
+    write(code, add_imm8_to_r32(Reg::Rsi, 4));
+
+    // `esi` is left pointing to the first digit, `ecx` is used as divisor
+    write(code, mov_imm32_to_r32(Reg::Rcx, 10));
+    let loop_start = code.len();
+    write(code, add_imm8_to_r32(Reg::Rsi, -1));
+    write(code, xor_r32_r32(Reg::Rdx, Reg::Rdx));
+    write(code, div_edx_eax_by_r32(Reg::Rcx));
+    write(code, add_imm8_to_r8(Reg::Rdx, b'0'));
+    write(code, mov_r8_to_m8(Reg::Rdx, RmI::RegRsi));
+    write(code, cmp_r32_with_imm8(Reg::Rax, 0x00));
+    let loop_end = code.len() + const { jnz_rel8(0).len() };
+    write(
+        code,
+        jnz_rel8((loop_start as isize - loop_end as isize) as i8),
+    );
+
+    // the length of the digits is `esp + 4 - esi`
+    write(code, mov_r32_to_r32(Reg::Rsp, Reg::Rdx));
+    write(code, add_imm8_to_r32(Reg::Rdx, 4));
+    write(code, sub_r32_from_r32(Reg::Rsi, Reg::Rdx));
+    write_output(code, runtime, calls);
+
+    write(code, add_imm8_to_r32(Reg::Rsp, 4));
+    write(code, pop_r32(Reg::Rcx));
+}
+
+/// Write the routine appending the `edx` bytes at `esi` to the output buffer, which is flushed
+/// first if they don't fit. At most [`OUTPUT_BUF_LEN`] bytes can be appended at once. Preserves
+/// `ecx`. The flush routine has to follow it.
+fn write_append(code: &mut Vec<u8>) {
+    write(code, push_r32(Reg::Rcx));
+    write(code, mov_imm32_to_r32(Reg::Rdi, BSS_OUTPUT_LEN));
+    write(code, mov_m32_disp8_to_r32(RmID::RegRdi, 0, Reg::Rax));
+    write(code, add_r32_to_r32(Reg::Rdx, Reg::Rax));
+    write(code, cmp_r32_with_imm32(Reg::Rax, OUTPUT_BUF_LEN));
+    let mut flush_code = Vec::new();
+    write(&mut flush_code, push_r32(Reg::Rsi));
+    write(&mut flush_code, push_r32(Reg::Rdx));
+    write(&mut flush_code, call_rel32(0));
+    let call_end = flush_code.len();
+    write(&mut flush_code, pop_r32(Reg::Rdx));
+    write(&mut flush_code, pop_r32(Reg::Rsi));
+    write(code, jbe_rel8(flush_code.len() as i8));
+    let call_end = code.len() + call_end;
+    code.extend_from_slice(&flush_code);
+
+    // the length is updated before the bytes are copied after the buffered ones
+    write(code, mov_imm32_to_r32(Reg::Rdi, BSS_OUTPUT_LEN));
+    write(code, mov_m32_disp8_to_r32(RmID::RegRdi, 0, Reg::Rax));
+    write(code, mov_r32_to_r32(Reg::Rax, Reg::Rcx));
+    write(code, add_r32_to_r32(Reg::Rdx, Reg::Rcx));
+    write(code, mov_r32_to_m32_disp8(Reg::Rcx, RmID::RegRdi, 0));
+    write(code, mov_imm32_to_r32(Reg::Rdi, BSS_OUTPUT_BUF));
+    write(code, add_r32_to_r32(Reg::Rax, Reg::Rdi));
+    write(code, mov_r32_to_r32(Reg::Rdx, Reg::Rcx));
+    write(code, REP_MOVSB);
+    write(code, pop_r32(Reg::Rcx));
+    write(code, RET);
+
+    // the flush routine follows
+    let disp = (code.len() - call_end) as i32;
+    code[call_end - 4..call_end].copy_from_slice(&disp.to_le_bytes());
+}
+
+/// Write the routine writing the buffered output to stdout. Output that can't be written is
+/// dropped. Preserves `ecx`.
+fn write_flush(code: &mut Vec<u8>) {
+    write(code, push_r32(Reg::Rcx));
+    write(code, mov_imm32_to_r32(Reg::Rdi, BSS_OUTPUT_LEN));
+    write(code, mov_m32_disp8_to_r32(RmID::RegRdi, 0, Reg::Rdx));
+    write(code, mov_imm32_to_r32(Reg::Rcx, BSS_OUTPUT_BUF));
+
+    // `ecx` points to the bytes left, and `edx` counts them
+    let mut done_jumps = Vec::new();
+    let loop_start = code.len();
+    write(code, cmp_r32_with_imm8(Reg::Rdx, 0x00));
+    write(code, jz_rel8(0));
+    done_jumps.push(code.len() - 1);
+    write(code, mov_imm32_to_r32(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_imm32_to_r32(Reg::Rbx, STDOUT_FD));
+    write(code, INT_0X80);
+    write(code, cmp_r32_with_imm8(Reg::Rax, 0x00));
+    write(code, jle_rel8(0));
+    done_jumps.push(code.len() - 1);
+    write(code, add_r32_to_r32(Reg::Rax, Reg::Rcx));
+    write(code, sub_r32_from_r32(Reg::Rax, Reg::Rdx));
+    let loop_end = code.len() + const { jmp_rel8(0).len() };
+    write(
+        code,
+        jmp_rel8((loop_start as isize - loop_end as isize) as i8),
+    );
+
+    for pos in done_jumps {
+        code[pos] = (code.len() - (pos + 1)) as u8;
+    }
+    write(code, xor_r32_r32(Reg::Rax, Reg::Rax));
+    write(code, mov_r32_to_m32_disp8(Reg::Rax, RmID::RegRdi, 0));
+    write(code, pop_r32(Reg::Rcx));
+    write(code, RET);
+}
+
+/// Write the routine writing the `edx` bytes at `esi` to the serial port, waiting for the
+/// transmitter to be ready before each byte. Preserves `ecx`.
+fn write_serial_write(code: &mut Vec<u8>) {
+    write(code, push_r32(Reg::Rcx));
+    write(code, mov_r32_to_r32(Reg::Rdx, Reg::Rcx));
+    write(code, cmp_r32_with_imm8(Reg::Rcx, 0x00));
+    write(code, jz_rel8(0));
+    let done_jump = code.len() - 1;
+
+    // `esi` points to the bytes left, and `ecx` counts them
+    let loop_start = code.len();
+    write(code, mov_imm32_to_r32(Reg::Rdx, COM1_LINE_STATUS));
+    let poll_start = code.len();
+    write(code, IN_AL_DX);
+    write(code, test_r8_with_imm8(Reg::Rax, LINE_TRANSMIT_EMPTY));
+    let poll_end = code.len() + const { jz_rel8(0).len() };
+    write(
+        code,
+        jz_rel8((poll_start as isize - poll_end as isize) as i8),
+    );
+    write(code, mov_imm32_to_r32(Reg::Rdx, COM1));
+    write(code, mov_m8_to_r8(RmI::RegRsi, Reg::Rax));
+    write(code, OUT_DX_AL);
+    write(code, add_imm8_to_r32(Reg::Rsi, 1));
+    write(code, sub_imm8_from_r32(Reg::Rcx, 1));
+    let loop_end = code.len() + const { jnz_rel8(0).len() };
+    write(
+        code,
+        jnz_rel8((loop_start as isize - loop_end as isize) as i8),
+    );
+
+    code[done_jump] = (code.len() - (done_jump + 1)) as u8;
+    write(code, pop_r32(Reg::Rcx));
+    write(code, RET);
+}
+
+/// Write the routine reading a byte from the serial port to `esi`, waiting until one was
+/// received. `eax` is set to the number of bytes read, receiving [`SERIAL_EOF`] signals the end
+/// of the input. Preserves `ecx`.
+fn write_serial_read(code: &mut Vec<u8>) {
+    write(code, mov_imm32_to_r32(Reg::Rdx, COM1_LINE_STATUS));
+    let poll_start = code.len();
+    write(code, IN_AL_DX);
+    write(code, test_r8_with_imm8(Reg::Rax, LINE_DATA_READY));
+    let poll_end = code.len() + const { jz_rel8(0).len() };
+    write(
+        code,
+        jz_rel8((poll_start as isize - poll_end as isize) as i8),
+    );
+    write(code, mov_imm32_to_r32(Reg::Rdx, COM1));
+    write(code, IN_AL_DX);
+
+    let mut read = Vec::new();
+    write(&mut read, mov_r8_to_m8(Reg::Rax, RmI::RegRsi));
+    write(&mut read, mov_imm32_to_r32(Reg::Rax, 1));
+    write(&mut read, RET);
+    write(code, cmp_r8_with_imm8(Reg::Rax, SERIAL_EOF));
+    write(code, jz_rel8(read.len() as i8));
+    code.extend_from_slice(&read);
+    write(code, xor_r32_r32(Reg::Rax, Reg::Rax));
+    write(code, RET);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest;
+
+    /// The multiboot header has to be 4-byte aligned within the first bytes of the image.
+    const MULTIBOOT_SEARCH_LEN: usize = 8192;
+
+    /// Check the multiboot header of a flat binary image: its checksum, and that its addresses
+    /// describe an image loaded in place whose entry point is part of it.
+    fn check_multiboot(bytes: &[u8]) -> Result<(), String> {
+        let search_len = bytes.len().min(MULTIBOOT_SEARCH_LEN);
+        let offset = (0..search_len.saturating_sub(MULTIBOOT_HEADER_LEN - 1))
+            .step_by(4)
+            .find(|&o| bytes[o..o + 4] == MULTIBOOT_MAGIC.to_le_bytes())
+            .ok_or("no multiboot header found")?;
+        let field = |i: usize| {
+            let start = offset + 4 * i;
+            u32::from_le_bytes(bytes[start..start + 4].try_into().expect("4 bytes"))
+        };
+        let [magic, flags, checksum, header_addr, load_addr, load_end, bss_end, entry] =
+            std::array::from_fn(field);
+
+        if magic.wrapping_add(flags).wrapping_add(checksum) != 0 {
+            return Err(format!("invalid multiboot checksum {checksum:#010x}"));
+        }
+        if flags & MULTIBOOT_AOUT_KLUDGE == 0 {
+            return Err("the multiboot header doesn't contain load addresses".to_owned());
+        }
+        if header_addr.wrapping_sub(load_addr) as usize != offset {
+            return Err(format!(
+                "the multiboot header is at offset {offset}, but its address {header_addr:#x} is at offset {} of the loaded image",
+                header_addr.wrapping_sub(load_addr) as i32,
+            ));
+        }
+        let loaded_len = load_end.wrapping_sub(load_addr) as usize;
+        if load_end < load_addr || loaded_len > bytes.len() {
+            return Err(format!(
+                "the loaded range {load_addr:#x}..{load_end:#x} exceeds the {} bytes of the image",
+                bytes.len(),
+            ));
+        }
+        if bss_end != 0 && bss_end < load_end {
+            return Err(format!(
+                "the bss end {bss_end:#x} is before the load end {load_end:#x}"
+            ));
+        }
+        if !(load_addr..load_end).contains(&entry) {
+            return Err(format!(
+                "the entry point {entry:#x} is outside of the loaded range {load_addr:#x}..{load_end:#x}"
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn multiboot_header_is_valid() {
+        let config = Config {
+            multiboot: true,
+            ..Config::default()
+        };
+        let instructions = selftest::build(&config, b",[>+<-]>+.").unwrap();
+        let image = compile_flat(&config, &RuntimeOptions::new(&config), &instructions);
+        assert_eq!(check_multiboot(&image), Ok(()));
+    }
+}
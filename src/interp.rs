@@ -0,0 +1,1334 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::cache::Fnv1a;
+use crate::cli::Config;
+use crate::optim::{self, PointerRange};
+use crate::{Instruction, Jump, Token, NUM_REGISTERS};
+
+/// Number of instructions executed by [`Interpreter::run`] between checks of the time limit.
+const TIME_CHECK_INTERVAL: u64 = 1 << 20;
+/// Number of recently executed instructions remembered, see [`Interpreter::with_history`].
+pub const HISTORY_LEN: usize = 32;
+
+/// The complete execution state of an [`Interpreter`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    /// The instruction pointer.
+    pub ip: usize,
+    /// The register pointer.
+    pub rp: i16,
+    pub registers: Box<[u8; NUM_REGISTERS]>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            ip: 0,
+            rp: 0,
+            // allocated zeroed, instead of copying a zeroed array onto the heap
+            registers: vec![0; NUM_REGISTERS]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The step limit was reached before the program finished.
+    Paused,
+    /// The instruction pointer went past the last instruction.
+    Finished,
+    /// Execution was stopped, because continuing would exceed a limit.
+    Exceeded(Limit),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Steps,
+    Output,
+    Time,
+    /// The register pointer left the tape.
+    Tape,
+    /// An input instruction read past the end of the input, see
+    /// [`Interpreter::with_strict_eof`].
+    Eof,
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Steps => f.write_str("step limit exceeded"),
+            Limit::Output => f.write_str("output limit exceeded"),
+            Limit::Time => f.write_str("time limit exceeded"),
+            Limit::Tape => f.write_str("register pointer out of tape bounds"),
+            Limit::Eof => f.write_str("read past the end of the input"),
+        }
+    }
+}
+
+/// The value an input instruction stores in the register, when the end of the input is reached.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Eof {
+    #[default]
+    Unchanged,
+    Zero,
+    /// `255`, which is `-1` for implementations using signed registers.
+    Max,
+}
+
+impl Eof {
+    pub fn name(self) -> &'static str {
+        match self {
+            Eof::Unchanged => "unchanged",
+            Eof::Zero => "zero",
+            Eof::Max => "max",
+        }
+    }
+
+    /// The value stored in the register, or `None` if it's left unchanged.
+    pub fn value(self) -> Option<u8> {
+        match self {
+            Eof::Unchanged => None,
+            Eof::Zero => Some(0),
+            Eof::Max => Some(u8::MAX),
+        }
+    }
+}
+
+impl std::str::FromStr for Eof {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Eof::Unchanged, Eof::Zero, Eof::Max]
+            .into_iter()
+            .find(|e| e.name() == s)
+            .ok_or(())
+    }
+}
+
+/// How the [`Interpreter`] dispatches instructions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dispatch {
+    /// A loop with a central match over all instructions.
+    #[default]
+    Switch,
+    /// Every instruction is paired with a handler function ahead of time, which is called
+    /// directly, without matching on the instruction. The indirect calls are about twice as slow
+    /// as the match, so this is only used by the `bench-opts` mode to compare them.
+    Threaded,
+}
+
+/// Hard caps enforced by an [`Interpreter`], `None` means unlimited. The tape is always limited
+/// to [`NUM_REGISTERS`] cells.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The total number of executed instructions.
+    pub steps: Option<u64>,
+    /// The total number of written output bytes.
+    pub output: Option<usize>,
+    /// The wall time since the first instruction was executed.
+    pub time: Option<Duration>,
+}
+
+pub struct Interpreter<'a, R, W> {
+    instructions: &'a [Instruction],
+    state: State,
+    /// The total number of executed instructions.
+    steps: u64,
+    limits: Limits,
+    output_bytes: usize,
+    start: Option<Instant>,
+    /// The number of times each instruction was executed, if enabled.
+    counts: Option<Vec<u64>>,
+    /// The hash of the states after each executed instruction, if enabled.
+    trace: Option<Fnv1a>,
+    mem_stats: bool,
+    /// The registers accessed so far, if memory stats are enabled.
+    accessed: Option<PointerRange>,
+    /// The instruction pointers of the most recently executed instructions, if enabled.
+    history: Option<VecDeque<usize>>,
+    dispatch: Dispatch,
+    /// The handlers of all instructions, created before the first instruction is executed using
+    /// threaded dispatch.
+    threaded: Vec<Threaded>,
+    bounds_checks: bool,
+    eof: Eof,
+    strict_eof: bool,
+    /// Buffered, so input heavy programs aren't bound by one syscall per byte. A terminal in
+    /// canonical mode still delivers the input one line at a time.
+    input: BufReader<R>,
+    output: W,
+}
+
+/// Instructions prepared for the [`Interpreter`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    /// All register accesses are proven to stay inside the tape, so bounds checks can be skipped.
+    pub in_bounds: bool,
+}
+
+impl Program {
+    /// Check the bounds of the `instructions` and compact their jumps. The jump locations have to
+    /// be resolved and threaded, like [`crate::selftest::build`] does.
+    pub fn new(config: &Config, mut instructions: Vec<Instruction>) -> Self {
+        let in_bounds = optim::in_bounds(config, &instructions);
+        optim::compact_jumps(config, &mut instructions);
+        Self {
+            instructions,
+            in_bounds,
+        }
+    }
+}
+
+impl<'a, R: Read, W: Write> Interpreter<'a, R, W> {
+    /// An interpreter for the `program` using the limits and the eof value of the `config`. Bounds
+    /// checks are skipped if the program is proven to stay inside the tape.
+    pub fn from_config(config: &Config, program: &'a Program, input: R, output: W) -> Self {
+        let interpreter = Self::new(&program.instructions, input, output)
+            .with_limits(config.limits())
+            .with_eof(config.eof);
+        match program.in_bounds {
+            true => interpreter.without_bounds_checks(),
+            false => interpreter,
+        }
+    }
+
+    pub fn new(instructions: &'a [Instruction], input: R, output: W) -> Self {
+        Self {
+            instructions,
+            state: State::default(),
+            steps: 0,
+            limits: Limits::default(),
+            output_bytes: 0,
+            start: None,
+            counts: None,
+            trace: None,
+            mem_stats: false,
+            accessed: None,
+            history: None,
+            dispatch: Dispatch::default(),
+            threaded: Vec::new(),
+            bounds_checks: true,
+            eof: Eof::default(),
+            strict_eof: false,
+            input: BufReader::new(input),
+            output,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    /// Stop with [`Limit::Eof`] instead of storing the eof value, when an input instruction reads
+    /// past the end of the input. The value differs between implementations, so portable programs
+    /// can't rely on it.
+    pub fn with_strict_eof(mut self) -> Self {
+        self.strict_eof = true;
+        self
+    }
+
+    /// Count how many times each instruction is executed, see [`Interpreter::counts`].
+    pub fn with_counts(mut self) -> Self {
+        self.counts = Some(vec![0; self.instructions.len()]);
+        self
+    }
+
+    /// Hash the state after each executed instruction, see [`Interpreter::trace_hash`]. The
+    /// instructions are executed one at a time, which is a lot slower.
+    pub fn with_trace_hash(mut self) -> Self {
+        self.trace = Some(Fnv1a::new());
+        self
+    }
+
+    /// Track the registers accessed by the executed instructions, see [`Interpreter::accessed`].
+    /// Like tracing, the instructions are executed one at a time.
+    pub fn with_mem_stats(mut self) -> Self {
+        self.mem_stats = true;
+        self
+    }
+
+    /// Remember the last [`HISTORY_LEN`] executed instructions, see [`Interpreter::history`]. Like
+    /// tracing, the instructions are executed one at a time.
+    pub fn with_history(mut self) -> Self {
+        self.history = Some(VecDeque::with_capacity(HISTORY_LEN));
+        self
+    }
+
+    /// Counts are only supported by [`Dispatch::Switch`], which is used instead if counts are
+    /// enabled.
+    pub fn with_dispatch(mut self, dispatch: Dispatch) -> Self {
+        self.dispatch = dispatch;
+        self
+    }
+
+    /// Skip the bounds checks of register accesses, which have to be proven to stay inside the
+    /// tape, see [`crate::optim::register_range`]. An access outside the tape means the proof was
+    /// wrong, it fails a debug assertion or panics instead of stopping execution.
+    pub fn without_bounds_checks(mut self) -> Self {
+        self.bounds_checks = false;
+        self
+    }
+
+    /// The number of times each instruction was executed, indexed like the instructions.
+    pub fn counts(&self) -> Option<&[u64]> {
+        self.counts.as_deref()
+    }
+
+    /// The FNV-1a hash of the instruction pointer, the register pointer and the value of the
+    /// current register after each executed instruction. It only depends on the instructions and
+    /// the input, so it identifies the execution of programs without output.
+    pub fn trace_hash(&self) -> Option<u64> {
+        self.trace.as_ref().map(Fnv1a::finish)
+    }
+
+    /// The range of registers accessed so far, or `None` if memory stats aren't enabled or no
+    /// register was accessed.
+    pub fn accessed(&self) -> Option<PointerRange> {
+        self.accessed
+    }
+
+    /// The instruction pointers of the most recently executed instructions, oldest first.
+    pub fn history(&self) -> Option<&VecDeque<usize>> {
+        self.history.as_ref()
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The total number of executed instructions.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Take a snapshot of the current execution state, which can later be restored using
+    /// [`Interpreter::restore`].
+    pub fn snapshot(&self) -> State {
+        self.state.clone()
+    }
+
+    pub fn restore(&mut self, state: State) {
+        self.state = state;
+    }
+
+    /// Run until the program finishes or a limit is exceeded.
+    pub fn run(&mut self) -> Status {
+        let status = loop {
+            match self.run_for(TIME_CHECK_INTERVAL) {
+                Status::Paused => continue,
+                status => break status,
+            }
+        };
+        _ = self.output.flush();
+        status
+    }
+
+    /// Run like [`Interpreter::run`], and call `snapshot` with the state and the number of executed
+    /// instructions every `interval` instructions, and once more when the program stops.
+    pub fn run_with_snapshots(
+        &mut self,
+        interval: u64,
+        mut snapshot: impl FnMut(&State, u64),
+    ) -> Status {
+        let mut next = self.steps + interval;
+        let status = loop {
+            let status = self.run_for((next - self.steps).min(TIME_CHECK_INTERVAL));
+            if status != Status::Paused {
+                break status;
+            }
+            if self.steps >= next {
+                snapshot(&self.state, self.steps);
+                next = self.steps + interval;
+            }
+        };
+        snapshot(&self.state, self.steps);
+        _ = self.output.flush();
+        status
+    }
+
+    /// Execute at most `steps` instructions. Execution is paused before an input instruction if
+    /// the input returns [`ErrorKind::WouldBlock`], and resumed by the next call.
+    pub fn run_for(&mut self, steps: u64) -> Status {
+        if self.trace.is_none() && !self.mem_stats && self.history.is_none() {
+            return self.execute_for(steps);
+        }
+        for _ in 0..steps {
+            let before = self.steps;
+            let (ip, rp) = (self.state.ip, self.state.rp);
+            let inst = self.instructions.get(ip);
+            let status = self.execute_for(1);
+            if status != Status::Paused || self.steps == before {
+                return status;
+            }
+            if let Some(trace) = &mut self.trace {
+                let State { ip, rp, registers } = &self.state;
+                let register = registers.get(*rp as usize).copied().unwrap_or(0);
+                trace.write(&(*ip as u64).to_le_bytes());
+                trace.write(&rp.to_le_bytes());
+                trace.write(&[register]);
+            }
+            if let Some(range) = inst
+                .filter(|_| self.mem_stats)
+                .and_then(optim::inst_accesses)
+            {
+                let range = range.shift(rp as i32);
+                self.accessed = Some(self.accessed.map_or(range, |r| r.join(range)));
+            }
+            if let Some(history) = &mut self.history {
+                if history.len() == HISTORY_LEN {
+                    history.pop_front();
+                }
+                history.push_back(ip);
+            }
+        }
+        Status::Paused
+    }
+
+    fn execute_for(&mut self, steps: u64) -> Status {
+        let mut budget = steps;
+        if let Some(max) = self.limits.steps {
+            let left = max.saturating_sub(self.steps);
+            if left == 0 {
+                return Status::Exceeded(Limit::Steps);
+            }
+            budget = budget.min(left);
+        }
+        if let Some(max) = self.limits.time {
+            let start = *self.start.get_or_insert_with(Instant::now);
+            if start.elapsed() >= max {
+                return Status::Exceeded(Limit::Time);
+            }
+        }
+
+        let use_threaded = self.dispatch == Dispatch::Threaded && self.counts.is_none();
+        if use_threaded && self.threaded.len() != self.instructions.len() {
+            self.threaded = (self.instructions.iter())
+                .map(|&inst| match self.bounds_checks {
+                    true => Threaded::new::<true>(inst),
+                    false => Threaded::new::<false>(inst),
+                })
+                .collect();
+        }
+
+        let mut remaining = budget;
+        let status = loop {
+            let (instructions, state, remaining) =
+                (self.instructions, &mut self.state, &mut remaining);
+            let event = match (&mut self.counts, self.bounds_checks) {
+                (None, _) if use_threaded => execute_threaded(&self.threaded, state, remaining),
+                (Some(counts), true) => {
+                    execute::<true, true>(instructions, state, remaining, counts)
+                }
+                (Some(counts), false) => {
+                    execute::<true, false>(instructions, state, remaining, counts)
+                }
+                (None, true) => execute::<false, true>(instructions, state, remaining, &mut []),
+                (None, false) => execute::<false, false>(instructions, state, remaining, &mut []),
+            };
+            match event {
+                Event::Paused => break Status::Paused,
+                Event::Finished => break Status::Finished,
+                Event::OutOfBounds => break Status::Exceeded(Limit::Tape),
+                Event::Output(idx) => {
+                    if self
+                        .limits
+                        .output
+                        .is_some_and(|max| self.output_bytes >= max)
+                    {
+                        break Status::Exceeded(Limit::Output);
+                    }
+                    _ = self.output.write(&self.state.registers[idx..][..1]);
+                    self.output_bytes += 1;
+                }
+                Event::OutputConst(n) => {
+                    if (self.limits.output).is_some_and(|max| self.output_bytes >= max) {
+                        break Status::Exceeded(Limit::Output);
+                    }
+                    _ = self.output.write(&[n]);
+                    self.output_bytes += 1;
+                }
+                Event::PrintDecimal(idx) => {
+                    let mut buf = [0; 3];
+                    let digits = decimal_digits(self.state.registers[idx], &mut buf);
+                    if (self.limits.output)
+                        .is_some_and(|max| self.output_bytes + digits.len() > max)
+                    {
+                        break Status::Exceeded(Limit::Output);
+                    }
+                    _ = self.output.write_all(digits);
+                    self.output_bytes += digits.len();
+                }
+                Event::Input(idx) => {
+                    // waiting for input counts towards the time limit
+                    let start = self.start.get_or_insert_with(Instant::now);
+                    if (self.limits.time).is_some_and(|max| start.elapsed() >= max) {
+                        break Status::Exceeded(Limit::Time);
+                    }
+                    let register = &mut self.state.registers[idx];
+                    match read_input(&mut self.input, &mut self.output, register, self.eof) {
+                        InputStatus::Read => (),
+                        InputStatus::End if !self.strict_eof => (),
+                        InputStatus::End => break Status::Exceeded(Limit::Eof),
+                        InputStatus::TimedOut => break Status::Exceeded(Limit::Time),
+                        InputStatus::Blocked => {
+                            // the instruction is executed again once input is available
+                            *remaining += 1;
+                            if let Some(counts) = &mut self.counts {
+                                counts[self.state.ip] -= 1;
+                            }
+                            break Status::Paused;
+                        }
+                    }
+                }
+                Event::Pipe(idx) => {
+                    let max = (self.limits.output)
+                        .map_or(usize::MAX, |max| max.saturating_sub(self.output_bytes));
+                    let register = &mut self.state.registers[idx];
+                    self.output_bytes += pipe(&mut self.input, &mut self.output, register, max);
+                }
+            }
+            self.state.ip += 1;
+        };
+        self.steps += budget - remaining;
+        status
+    }
+}
+
+/// The result of [`read_input`].
+enum InputStatus {
+    /// A byte was read, or reading failed and the register was left unchanged.
+    Read,
+    /// The end of the input was reached, and the eof value was stored.
+    End,
+    /// A non-blocking input has no byte available yet, see [`Interpreter::run_for`].
+    Blocked,
+    /// Reading timed out, see [`crate::tty::TimedStdin`].
+    TimedOut,
+}
+
+/// Read a byte from the `input` into the `register`, or handle the end of the input. Pending
+/// `output` is flushed before the input buffer is refilled, so prompts are visible before blocking.
+fn read_input(
+    input: &mut BufReader<impl Read>,
+    output: &mut impl Write,
+    register: &mut u8,
+    eof: Eof,
+) -> InputStatus {
+    if input.buffer().is_empty() {
+        _ = output.flush();
+    }
+    match input.fill_buf() {
+        Ok(&[byte, ..]) => {
+            *register = byte;
+            input.consume(1);
+            InputStatus::Read
+        }
+        Ok([]) => {
+            if let Some(val) = eof.value() {
+                *register = val;
+            }
+            InputStatus::End
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => InputStatus::Blocked,
+        Err(e) if e.kind() == ErrorKind::TimedOut => InputStatus::TimedOut,
+        Err(_) => InputStatus::Read,
+    }
+}
+
+/// Execute iterations of the echo loop `[.,]` on the `register`, until it's zero, no more input is
+/// available, or `max` bytes were written. The input is copied in chunks, every byte up to the
+/// first zero is written. Returns the number of bytes written.
+fn pipe(
+    input: &mut BufReader<impl Read>,
+    output: &mut impl Write,
+    register: &mut u8,
+    max: usize,
+) -> usize {
+    let mut written = 0;
+    while *register != 0 && written < max {
+        if input.buffer().is_empty() {
+            _ = output.flush();
+        }
+        let buf = match input.fill_buf() {
+            Ok(buf) if !buf.is_empty() => buf,
+            _ => break,
+        };
+
+        // each iteration writes the register and then reads the next byte into it
+        let len = buf.len().min(max - written);
+        let n = buf[..len]
+            .iter()
+            .position(|&b| b == 0)
+            .map_or(len, |i| i + 1);
+        _ = output.write_all(std::slice::from_ref(register));
+        _ = output.write_all(&buf[..n - 1]);
+        *register = buf[n - 1];
+        input.consume(n);
+        written += n;
+    }
+    written
+}
+
+/// Interprets tokens directly using a jump table, without building the ir. It's a lot slower than
+/// the [`Interpreter`], but serves as a reference when testing and debugging the ir pipeline.
+/// Steps count the executed tokens.
+pub struct TokenInterpreter<'a, R, W> {
+    tokens: &'a [Token],
+    /// The index of the matching bracket of each bracket token.
+    jumps: Vec<u32>,
+    state: State,
+    steps: u64,
+    limits: Limits,
+    output_bytes: usize,
+    eof: Eof,
+    input: BufReader<R>,
+    output: W,
+}
+
+impl<'a, R: Read, W: Write> TokenInterpreter<'a, R, W> {
+    /// The brackets of the `tokens` have to be matched.
+    pub fn new(tokens: &'a [Token], input: R, output: W) -> Self {
+        let mut jumps = vec![0; tokens.len()];
+        let mut stack = Vec::new();
+        for (i, t) in tokens.iter().enumerate() {
+            match t {
+                Token::LSquare => stack.push(i),
+                Token::RSquare => {
+                    let Some(start) = stack.pop() else {
+                        unreachable!("mismatched brackets")
+                    };
+                    jumps[start] = i as u32;
+                    jumps[i] = start as u32;
+                }
+                _ => (),
+            }
+        }
+        Self {
+            tokens,
+            jumps,
+            state: State::default(),
+            steps: 0,
+            limits: Limits::default(),
+            output_bytes: 0,
+            eof: Eof::default(),
+            input: BufReader::new(input),
+            output,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The total number of executed tokens.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Run until the program finishes or a limit is exceeded.
+    pub fn run(&mut self) -> Status {
+        let start = Instant::now();
+        let status = loop {
+            let State { ip, rp, registers } = &mut self.state;
+            let Some(&token) = self.tokens.get(*ip) else {
+                break Status::Finished;
+            };
+            if self.limits.steps.is_some_and(|max| self.steps >= max) {
+                break Status::Exceeded(Limit::Steps);
+            }
+            if self.steps.is_multiple_of(TIME_CHECK_INTERVAL)
+                && self.limits.time.is_some_and(|max| start.elapsed() >= max)
+            {
+                break Status::Exceeded(Limit::Time);
+            }
+            self.steps += 1;
+
+            if let Token::Shl | Token::Shr = token {
+                *rp = if token == Token::Shl {
+                    rp.wrapping_sub(1)
+                } else {
+                    rp.wrapping_add(1)
+                };
+                *ip += 1;
+                continue;
+            }
+            let Some(register) = registers.get_mut(*rp as usize) else {
+                break Status::Exceeded(Limit::Tape);
+            };
+            match token {
+                Token::Shl | Token::Shr => unreachable!(),
+                Token::Inc => *register = register.wrapping_add(1),
+                Token::Dec => *register = register.wrapping_sub(1),
+                Token::Output => {
+                    if (self.limits.output).is_some_and(|max| self.output_bytes >= max) {
+                        break Status::Exceeded(Limit::Output);
+                    }
+                    _ = self.output.write(std::slice::from_ref(register));
+                    self.output_bytes += 1;
+                }
+                Token::Input => {
+                    // waiting for input counts towards the time limit
+                    if self.limits.time.is_some_and(|max| start.elapsed() >= max) {
+                        break Status::Exceeded(Limit::Time);
+                    }
+                    let status = read_input(&mut self.input, &mut self.output, register, self.eof);
+                    if let InputStatus::TimedOut = status {
+                        break Status::Exceeded(Limit::Time);
+                    }
+                }
+                Token::LSquare if *register == 0 => *ip = self.jumps[*ip] as usize,
+                Token::RSquare if *register != 0 => *ip = self.jumps[*ip] as usize,
+                Token::LSquare | Token::RSquare => (),
+            }
+            *ip += 1;
+        };
+        _ = self.output.flush();
+        status
+    }
+}
+
+/// An [`Interpreter`] performing I/O using non-blocking readers and writers, so programs can be
+/// served over network connections without blocking a thread per session.
+#[cfg(feature = "async")]
+pub struct AsyncInterpreter<'a, R, W> {
+    instructions: &'a [Instruction],
+    state: State,
+    /// The total number of executed instructions.
+    steps: u64,
+    limits: Limits,
+    output_bytes: usize,
+    start: Option<Instant>,
+    eof: Eof,
+    input: R,
+    output: W,
+}
+
+#[cfg(feature = "async")]
+impl<'a, R, W> AsyncInterpreter<'a, R, W>
+where
+    R: futures_io::AsyncRead + Unpin,
+    W: futures_io::AsyncWrite + Unpin,
+{
+    pub fn new(instructions: &'a [Instruction], input: R, output: W) -> Self {
+        Self {
+            instructions,
+            state: State::default(),
+            steps: 0,
+            limits: Limits::default(),
+            output_bytes: 0,
+            start: None,
+            eof: Eof::default(),
+            input,
+            output,
+        }
+    }
+
+    /// Enforce the `limits` like [`Interpreter::with_limits`]. The time limit is checked before
+    /// each input instruction, but a pending read isn't interrupted.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The total number of executed instructions.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Whether the time limit was exceeded, the time is measured from the first call.
+    fn time_exceeded(&mut self) -> bool {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.limits.time.is_some_and(|max| start.elapsed() >= max)
+    }
+
+    pub fn snapshot(&self) -> State {
+        self.state.clone()
+    }
+
+    pub fn restore(&mut self, state: State) {
+        self.state = state;
+    }
+
+    /// Run until the program finishes or a limit is exceeded.
+    pub async fn run(&mut self) -> std::io::Result<Status> {
+        let status = loop {
+            match self.run_for(TIME_CHECK_INTERVAL).await? {
+                Status::Paused => continue,
+                status => break status,
+            }
+        };
+        std::future::poll_fn(|cx| std::pin::Pin::new(&mut self.output).poll_flush(cx)).await?;
+        Ok(status)
+    }
+
+    /// Execute at most `steps` instructions.
+    pub async fn run_for(&mut self, steps: u64) -> std::io::Result<Status> {
+        use std::future::poll_fn;
+        use std::pin::Pin;
+
+        let mut remaining = steps;
+        if let Some(max) = self.limits.steps {
+            let left = max.saturating_sub(self.steps);
+            if left == 0 {
+                return Ok(Status::Exceeded(Limit::Steps));
+            }
+            remaining = remaining.min(left);
+        }
+        if self.time_exceeded() {
+            return Ok(Status::Exceeded(Limit::Time));
+        }
+
+        let status = loop {
+            let before = remaining;
+            let event =
+                execute::<false, true>(self.instructions, &mut self.state, &mut remaining, &mut []);
+            self.steps += before - remaining;
+            match event {
+                Event::Paused => break Status::Paused,
+                Event::Finished => break Status::Finished,
+                Event::OutOfBounds => break Status::Exceeded(Limit::Tape),
+                Event::Output(idx) => {
+                    if (self.limits.output).is_some_and(|max| self.output_bytes >= max) {
+                        break Status::Exceeded(Limit::Output);
+                    }
+                    let buf = &self.state.registers[idx..][..1];
+                    let n = poll_fn(|cx| Pin::new(&mut self.output).poll_write(cx, buf)).await?;
+                    if n == 0 {
+                        return Err(std::io::ErrorKind::WriteZero.into());
+                    }
+                    self.output_bytes += 1;
+                }
+                Event::OutputConst(n) => {
+                    if (self.limits.output).is_some_and(|max| self.output_bytes >= max) {
+                        break Status::Exceeded(Limit::Output);
+                    }
+                    let buf = &[n];
+                    let n = poll_fn(|cx| Pin::new(&mut self.output).poll_write(cx, buf)).await?;
+                    if n == 0 {
+                        return Err(std::io::ErrorKind::WriteZero.into());
+                    }
+                    self.output_bytes += 1;
+                }
+                Event::PrintDecimal(idx) => {
+                    let mut buf = [0; 3];
+                    let mut digits = decimal_digits(self.state.registers[idx], &mut buf);
+                    if (self.limits.output)
+                        .is_some_and(|max| self.output_bytes + digits.len() > max)
+                    {
+                        break Status::Exceeded(Limit::Output);
+                    }
+                    self.output_bytes += digits.len();
+                    while !digits.is_empty() {
+                        let n =
+                            poll_fn(|cx| Pin::new(&mut self.output).poll_write(cx, digits)).await?;
+                        if n == 0 {
+                            return Err(std::io::ErrorKind::WriteZero.into());
+                        }
+                        digits = &digits[n..];
+                    }
+                }
+                Event::Input(idx) => {
+                    if self.time_exceeded() {
+                        break Status::Exceeded(Limit::Time);
+                    }
+                    let buf = &mut self.state.registers[idx..][..1];
+                    let n = poll_fn(|cx| Pin::new(&mut self.input).poll_read(cx, buf)).await?;
+                    if let (0, Some(val)) = (n, self.eof.value()) {
+                        buf[0] = val;
+                    }
+                }
+                // the echo loop following the pipe copies the input byte by byte
+                Event::Pipe(_) => (),
+            }
+            self.state.ip += 1;
+        };
+        Ok(status)
+    }
+}
+
+/// Compute the effect of the divmod loop `[->-[>+>>]>[+[-<+>]>+>>]<<<<<]` on the six registers
+/// starting at the current one:
+/// ```txt
+/// n d 0 q 0 0  ->  0 d-n%d n%d q+n/d 0 0
+/// ```
+/// The registers are left untouched if `n` is zero, `d` is less than two, or the scratch registers
+/// aren't zero, in which case the loop has to compute the result.
+pub fn div_mod(registers: &mut [u8]) {
+    let &mut [n, d, r, q, a, b] = registers else {
+        unreachable!()
+    };
+    if n == 0 || d < 2 || r != 0 || a != 0 || b != 0 {
+        return;
+    }
+    registers[0] = 0;
+    registers[1] = d - n % d;
+    registers[2] = n % d;
+    registers[3] = q.wrapping_add(n / d);
+}
+
+/// The ascii digits of `n` without leading zeros, written into the end of `buf`.
+pub fn decimal_digits(mut n: u8, buf: &mut [u8; 3]) -> &[u8] {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + n % 10;
+        n /= 10;
+        if n == 0 {
+            break &buf[i..];
+        }
+    }
+}
+
+/// The reason [`execute`] returned.
+enum Event {
+    Paused,
+    Finished,
+    /// The instruction at the instruction pointer is an [`Instruction::Output`] of the register
+    /// at the index.
+    Output(usize),
+    /// The instruction at the instruction pointer is an [`Instruction::Input`] into the register
+    /// at the index.
+    Input(usize),
+    /// The instruction at the instruction pointer is an [`Instruction::PrintDecimal`] of the
+    /// register at the index.
+    PrintDecimal(usize),
+    /// The instruction at the instruction pointer is an [`Instruction::OutputConst`] of the byte.
+    OutputConst(u8),
+    /// The instruction at the instruction pointer is an [`Instruction::Pipe`] of the register at
+    /// the index.
+    Pipe(usize),
+    /// The instruction at the instruction pointer would access a register outside the tape.
+    OutOfBounds,
+}
+
+/// Execute at most `steps` instructions, or until an I/O instruction is reached, which has to be
+/// handled by the caller. If `COUNT` is set, the executions of each instruction are counted in
+/// `counts`. If `CHECKED` isn't set, register accesses aren't checked against the tape, so the
+/// program has to be proven to stay inside of it, an access outside of it panics.
+fn execute<const COUNT: bool, const CHECKED: bool>(
+    instructions: &[Instruction],
+    state: &mut State,
+    steps: &mut u64,
+    counts: &mut [u64],
+) -> Event {
+    let State { ip, rp, registers } = state;
+
+    // out of bounds accesses stop execution before the instruction is executed
+    macro_rules! out_of_bounds {
+        () => {{
+            *steps += 1;
+            if COUNT {
+                counts[*ip] -= 1;
+            }
+            return Event::OutOfBounds;
+        }};
+    }
+    macro_rules! register {
+        ($offset:expr) => {
+            if CHECKED {
+                match registers.get_mut(rp.wrapping_add($offset) as usize) {
+                    Some(r) => r,
+                    None => out_of_bounds!(),
+                }
+            } else {
+                let idx = rp.wrapping_add($offset) as usize;
+                debug_assert!(idx < NUM_REGISTERS, "unchecked access outside the tape");
+                &mut registers[idx]
+            }
+        };
+    }
+    macro_rules! range {
+        ($offset:expr, $len:expr) => {{
+            let start = rp.wrapping_add($offset) as usize;
+            match start.checked_add($len) {
+                Some(end) if end <= registers.len() => start..end,
+                _ => out_of_bounds!(),
+            }
+        }};
+    }
+
+    while *steps > 0 {
+        *steps -= 1;
+
+        let Some(inst) = instructions.get(*ip) else {
+            return Event::Finished;
+        };
+        if COUNT {
+            counts[*ip] += 1;
+        }
+
+        match *inst {
+            Instruction::Shl(n) => *rp = rp.wrapping_sub(n as i16),
+            Instruction::Shr(n) => *rp = rp.wrapping_add(n as i16),
+            Instruction::Inc(o, n) => {
+                let r = register!(o);
+                *r = r.wrapping_add(n);
+            }
+            Instruction::Dec(o, n) => {
+                let r = register!(o);
+                *r = r.wrapping_sub(n);
+            }
+            Instruction::Output(o) => {
+                register!(o);
+                return Event::Output(rp.wrapping_add(o) as usize);
+            }
+            Instruction::Input(o) => {
+                register!(o);
+                return Event::Input(rp.wrapping_add(o) as usize);
+            }
+            Instruction::PrintDecimal(o) => {
+                register!(o);
+                return Event::PrintDecimal(rp.wrapping_add(o) as usize);
+            }
+            Instruction::OutputConst(n) => return Event::OutputConst(n),
+            Instruction::Pipe => {
+                register!(0);
+                return Event::Pipe(*rp as usize);
+            }
+            Instruction::JumpZ(Jump::Location(idx)) => {
+                if *register!(0) == 0 {
+                    *ip = idx.get() as usize;
+                    continue;
+                }
+            }
+            Instruction::JumpZ(Jump::Redundant) => (),
+            Instruction::JumpNz(Jump::Location(idx)) => {
+                if *register!(0) > 0 {
+                    *ip = idx.get() as usize;
+                    continue;
+                }
+            }
+            Instruction::JumpNz(Jump::Redundant) => (),
+            Instruction::JumpZ(Jump::Unresolved) | Instruction::JumpNz(Jump::Unresolved) => {
+                unreachable!("unresolved jump")
+            }
+
+            Instruction::Zero(o) => *register!(o) = 0,
+            Instruction::Set(o, n) => *register!(o) = n,
+            Instruction::Add(o) => {
+                let val = *register!(0);
+                let r = register!(o);
+                *r = r.wrapping_add(val);
+            }
+            Instruction::Sub(o) => {
+                let val = *register!(0);
+                let r = register!(o);
+                *r = r.wrapping_sub(val);
+            }
+            Instruction::AddMul(o, n) => {
+                let val = *register!(0);
+                let r = register!(o);
+                *r = r.wrapping_add(n.wrapping_mul(val));
+            }
+            Instruction::SubMul(o, n) => {
+                let val = *register!(0);
+                let r = register!(o);
+                *r = r.wrapping_sub(n.wrapping_mul(val));
+            }
+            Instruction::CopyRange(s, d, n) => {
+                let src = range!(s, n as usize);
+                let dst = range!(d, n as usize);
+                registers.copy_within(src, dst.start);
+            }
+            Instruction::DivMod => {
+                // out of bounds accesses are left to the divmod loop
+                let start = *rp as usize;
+                if let Some(registers) = registers.get_mut(start..start.saturating_add(6)) {
+                    div_mod(registers);
+                }
+            }
+        }
+
+        *ip += 1;
+    }
+
+    if *ip < instructions.len() {
+        Event::Paused
+    } else {
+        Event::Finished
+    }
+}
+
+/// Executes a single instruction and advances the instruction pointer, or returns the event
+/// stopping execution, before the instruction is executed.
+type Handler = fn(&mut State, Instruction) -> Option<Event>;
+
+/// An instruction paired with the handler executing it.
+#[derive(Clone, Copy)]
+struct Threaded {
+    handler: Handler,
+    inst: Instruction,
+}
+
+impl Threaded {
+    fn new<const CHECKED: bool>(inst: Instruction) -> Self {
+        let handler: Handler = match inst {
+            Instruction::Shl(_) => handler::shl,
+            Instruction::Shr(_) => handler::shr,
+            Instruction::Inc(..) => handler::inc::<CHECKED>,
+            Instruction::Dec(..) => handler::dec::<CHECKED>,
+            Instruction::Output(_) => handler::output::<CHECKED>,
+            Instruction::Input(_) => handler::input::<CHECKED>,
+            Instruction::JumpZ(Jump::Location(_)) => handler::jump_z::<CHECKED>,
+            Instruction::JumpNz(Jump::Location(_)) => handler::jump_nz::<CHECKED>,
+            Instruction::JumpZ(Jump::Redundant) | Instruction::JumpNz(Jump::Redundant) => {
+                handler::nop
+            }
+            Instruction::JumpZ(Jump::Unresolved) | Instruction::JumpNz(Jump::Unresolved) => {
+                unreachable!("unresolved jump")
+            }
+            Instruction::Zero(_) => handler::zero::<CHECKED>,
+            Instruction::Set(..) => handler::set::<CHECKED>,
+            Instruction::Add(_) | Instruction::Sub(_) => handler::add_sub::<CHECKED>,
+            Instruction::AddMul(..) | Instruction::SubMul(..) => handler::add_sub_mul::<CHECKED>,
+            Instruction::CopyRange(..) => handler::copy_range,
+            Instruction::DivMod => handler::div_mod,
+            Instruction::PrintDecimal(_) => handler::print_decimal::<CHECKED>,
+            Instruction::OutputConst(_) => handler::output_const,
+            Instruction::Pipe => handler::pipe::<CHECKED>,
+        };
+        Self { handler, inst }
+    }
+}
+
+/// Execute at most `steps` instructions by calling their handlers, like [`execute`].
+fn execute_threaded(code: &[Threaded], state: &mut State, steps: &mut u64) -> Event {
+    while *steps > 0 {
+        *steps -= 1;
+
+        let Some(&Threaded { handler, inst }) = code.get(state.ip) else {
+            return Event::Finished;
+        };
+        if let Some(event) = handler(state, inst) {
+            if let Event::OutOfBounds = event {
+                *steps += 1;
+            }
+            return event;
+        }
+    }
+
+    if state.ip < code.len() {
+        Event::Paused
+    } else {
+        Event::Finished
+    }
+}
+
+/// The handlers of [`Dispatch::Threaded`], one per kind of instruction.
+mod handler {
+    use super::{div_mod as compute_div_mod, Event, State};
+    use crate::{Instruction, Jump, NUM_REGISTERS};
+
+    /// The register at `offset`, or return [`Event::OutOfBounds`] if it's outside the tape.
+    macro_rules! register {
+        ($state:expr, $offset:expr) => {{
+            let State { rp, registers, .. } = $state;
+            if CHECKED {
+                match registers.get_mut(rp.wrapping_add($offset) as usize) {
+                    Some(r) => r,
+                    None => return Some(Event::OutOfBounds),
+                }
+            } else {
+                let idx = rp.wrapping_add($offset) as usize;
+                debug_assert!(idx < NUM_REGISTERS, "unchecked access outside the tape");
+                &mut registers[idx]
+            }
+        }};
+    }
+
+    /// Destructure the instruction, handlers are only paired with their kind of instruction.
+    macro_rules! operands {
+        ($inst:expr, $pattern:pat) => {
+            let $pattern = $inst else { unreachable!() };
+        };
+    }
+
+    pub fn shl(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::Shl(n));
+        state.rp = state.rp.wrapping_sub(n as i16);
+        state.ip += 1;
+        None
+    }
+
+    pub fn shr(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::Shr(n));
+        state.rp = state.rp.wrapping_add(n as i16);
+        state.ip += 1;
+        None
+    }
+
+    pub fn inc<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::Inc(o, n));
+        let r = register!(state, o);
+        *r = r.wrapping_add(n);
+        state.ip += 1;
+        None
+    }
+
+    pub fn dec<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::Dec(o, n));
+        let r = register!(state, o);
+        *r = r.wrapping_sub(n);
+        state.ip += 1;
+        None
+    }
+
+    pub fn output<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::Output(o));
+        register!(state, o);
+        Some(Event::Output(state.rp.wrapping_add(o) as usize))
+    }
+
+    pub fn input<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::Input(o));
+        register!(state, o);
+        Some(Event::Input(state.rp.wrapping_add(o) as usize))
+    }
+
+    pub fn print_decimal<const CHECKED: bool>(
+        state: &mut State,
+        inst: Instruction,
+    ) -> Option<Event> {
+        operands!(inst, Instruction::PrintDecimal(o));
+        register!(state, o);
+        Some(Event::PrintDecimal(state.rp.wrapping_add(o) as usize))
+    }
+
+    pub fn output_const(_: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::OutputConst(n));
+        Some(Event::OutputConst(n))
+    }
+
+    pub fn pipe<const CHECKED: bool>(state: &mut State, _: Instruction) -> Option<Event> {
+        register!(state, 0);
+        Some(Event::Pipe(state.rp as usize))
+    }
+
+    pub fn jump_z<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::JumpZ(Jump::Location(idx)));
+        if *register!(state, 0) == 0 {
+            state.ip = idx.get() as usize;
+        } else {
+            state.ip += 1;
+        }
+        None
+    }
+
+    pub fn jump_nz<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::JumpNz(Jump::Location(idx)));
+        if *register!(state, 0) > 0 {
+            state.ip = idx.get() as usize;
+        } else {
+            state.ip += 1;
+        }
+        None
+    }
+
+    pub fn nop(state: &mut State, _: Instruction) -> Option<Event> {
+        state.ip += 1;
+        None
+    }
+
+    pub fn zero<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::Zero(o));
+        *register!(state, o) = 0;
+        state.ip += 1;
+        None
+    }
+
+    pub fn set<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::Set(o, n));
+        *register!(state, o) = n;
+        state.ip += 1;
+        None
+    }
+
+    pub fn add_sub<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, (Instruction::Add(o) | Instruction::Sub(o)));
+        let val = *register!(state, 0);
+        let r = register!(state, o);
+        *r = match inst {
+            Instruction::Add(_) => r.wrapping_add(val),
+            _ => r.wrapping_sub(val),
+        };
+        state.ip += 1;
+        None
+    }
+
+    pub fn add_sub_mul<const CHECKED: bool>(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(
+            inst,
+            (Instruction::AddMul(o, n) | Instruction::SubMul(o, n))
+        );
+        let val = n.wrapping_mul(*register!(state, 0));
+        let r = register!(state, o);
+        *r = match inst {
+            Instruction::AddMul(..) => r.wrapping_add(val),
+            _ => r.wrapping_sub(val),
+        };
+        state.ip += 1;
+        None
+    }
+
+    /// Ranges are always bounds checked.
+    pub fn copy_range(state: &mut State, inst: Instruction) -> Option<Event> {
+        operands!(inst, Instruction::CopyRange(s, d, n));
+        let State { rp, registers, .. } = state;
+        let range = |offset: i16| {
+            let start = rp.wrapping_add(offset) as usize;
+            let end = start.checked_add(n as usize)?;
+            (end <= registers.len()).then_some(start..end)
+        };
+        let (Some(src), Some(dst)) = (range(s), range(d)) else {
+            return Some(Event::OutOfBounds);
+        };
+        registers.copy_within(src, dst.start);
+        state.ip += 1;
+        None
+    }
+
+    /// Out of bounds accesses are left to the divmod loop.
+    pub fn div_mod(state: &mut State, _: Instruction) -> Option<Event> {
+        let start = state.rp as usize;
+        if let Some(registers) = state.registers.get_mut(start..start.saturating_add(6)) {
+            compute_div_mod(registers);
+        }
+        state.ip += 1;
+        None
+    }
+}
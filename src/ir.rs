@@ -0,0 +1,85 @@
+//! The structured intermediate representation, used by the passes rewriting loops: `arithmetic`,
+//! `simplify`, `jumps` and `decimal`. The passes executing the program at compile time and the
+//! peephole rules matching straight-line code work on the flat instructions, which is also the
+//! form the interpreter and the backends consume once the jump locations are resolved.
+
+use crate::{Instruction, Jump};
+
+/// A node of the structured intermediate representation, where loops contain their body instead
+/// of being delimited by jump instructions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    /// Any instruction except [`Instruction::JumpZ`] and [`Instruction::JumpNz`].
+    Inst(Instruction),
+    Loop(Loop),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Loop {
+    /// The opening jump is known to never be taken.
+    pub start_redundant: bool,
+    /// The closing jump is known to never be taken, see [`Jump::Redundant`].
+    pub end_redundant: bool,
+    pub body: Vec<Node>,
+}
+
+impl Loop {
+    /// Whether the body doesn't contain any nested loops.
+    pub fn is_innermost(&self) -> bool {
+        self.body.iter().all(|n| matches!(n, Node::Inst(_)))
+    }
+}
+
+/// Build the structured representation of instructions with matched brackets.
+pub fn build(instructions: &[Instruction]) -> Vec<Node> {
+    let mut stack = Vec::new();
+    let mut nodes = Vec::new();
+    for inst in instructions.iter() {
+        match *inst {
+            Instruction::JumpZ(jump) => {
+                stack.push((jump.is_redundant(), std::mem::take(&mut nodes)));
+            }
+            Instruction::JumpNz(jump) => {
+                let Some((start_redundant, parent)) = stack.pop() else {
+                    unreachable!("mismatched brackets")
+                };
+                let body = std::mem::replace(&mut nodes, parent);
+                nodes.push(Node::Loop(Loop {
+                    start_redundant,
+                    end_redundant: jump.is_redundant(),
+                    body,
+                }));
+            }
+            inst => nodes.push(Node::Inst(inst)),
+        }
+    }
+    if !stack.is_empty() {
+        unreachable!("mismatched brackets")
+    }
+    nodes
+}
+
+/// Lower the structured representation to flat instructions, the jump locations have to be
+/// updated using [`crate::update_jump_indices`] afterwards.
+pub fn lower(nodes: &[Node]) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(nodes.len());
+    lower_into(nodes, &mut instructions);
+    instructions
+}
+
+fn lower_into(nodes: &[Node], instructions: &mut Vec<Instruction>) {
+    let jump = |redundant| match redundant {
+        true => Jump::Redundant,
+        false => Jump::Unresolved,
+    };
+    for node in nodes.iter() {
+        match node {
+            Node::Inst(inst) => instructions.push(*inst),
+            Node::Loop(l) => {
+                instructions.push(Instruction::JumpZ(jump(l.start_redundant)));
+                lower_into(&l.body, instructions);
+                instructions.push(Instruction::JumpNz(jump(l.end_redundant)));
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::ffi::c_void;
+use std::io::{BufRead, Write};
+
+use crate::backend::RuntimeOptions;
+use crate::cli::Config;
+use crate::interp::{self, Eof, Limit, Status};
+use crate::{x86, Instruction};
+
+const PROT_READ: i32 = 0x1;
+const PROT_WRITE: i32 = 0x2;
+const PROT_EXEC: i32 = 0x4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// I/O callbacks passed to a function generated by [`x86::compile_function`]. The generated code
+/// calls the callbacks with a pointer to this struct and the address of the current register, or
+/// the address and length of the constant output.
+#[repr(C)]
+pub struct Io<'a> {
+    pub output: extern "sysv64" fn(*mut Io, *mut u8),
+    pub input: extern "sysv64" fn(*mut Io, *mut u8),
+    pub print_decimal: extern "sysv64" fn(*mut Io, *mut u8),
+    pub output_const: extern "sysv64" fn(*mut Io, *const u8, usize),
+    pub pipe: extern "sysv64" fn(*mut Io, *mut u8),
+    reader: &'a mut dyn BufRead,
+    writer: &'a mut dyn Write,
+    eof: Eof,
+    error: Option<std::io::Error>,
+}
+
+extern "sysv64" fn output(io: *mut Io, register: *mut u8) {
+    let io = unsafe { &mut *io };
+    let val = unsafe { *register };
+    if io.error.is_none() {
+        if let Err(e) = io.writer.write_all(&[val]) {
+            io.error = Some(e);
+        }
+    }
+}
+
+extern "sysv64" fn print_decimal(io: *mut Io, register: *mut u8) {
+    let io = unsafe { &mut *io };
+    let val = unsafe { *register };
+    if io.error.is_none() {
+        let mut buf = [0; 3];
+        if let Err(e) = io.writer.write_all(interp::decimal_digits(val, &mut buf)) {
+            io.error = Some(e);
+        }
+    }
+}
+
+extern "sysv64" fn output_const(io: *mut Io, bytes: *const u8, len: usize) {
+    let io = unsafe { &mut *io };
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, len) };
+    if io.error.is_none() {
+        if let Err(e) = io.writer.write_all(bytes) {
+            io.error = Some(e);
+        }
+    }
+}
+
+extern "sysv64" fn input(io: *mut Io, register: *mut u8) {
+    let io = unsafe { &mut *io };
+    let register = unsafe { &mut *register };
+    match io.reader.read(std::slice::from_mut(register)) {
+        Ok(0) => {
+            if let Some(val) = io.eof.value() {
+                *register = val;
+            }
+        }
+        Ok(_) => (),
+        Err(e) => _ = io.error.get_or_insert(e),
+    }
+}
+
+extern "sysv64" fn pipe(io: *mut Io, register: *mut u8) {
+    let io = unsafe { &mut *io };
+    let register = unsafe { &mut *register };
+    while *register != 0 && io.error.is_none() {
+        let buf = match io.reader.fill_buf() {
+            Ok([]) => break,
+            Ok(buf) => buf,
+            Err(e) => {
+                io.error = Some(e);
+                break;
+            }
+        };
+        // each iteration of the echo loop writes the register and then reads the next byte into it
+        let n = buf
+            .iter()
+            .position(|&b| b == 0)
+            .map_or(buf.len(), |i| i + 1);
+        let written = (io.writer.write_all(std::slice::from_ref(register)))
+            .and_then(|()| io.writer.write_all(&buf[..n - 1]));
+        if let Err(e) = written {
+            io.error = Some(e);
+            break;
+        }
+        *register = buf[n - 1];
+        io.reader.consume(n);
+    }
+}
+
+/// Compile the instructions to x86-64 machine code, and execute them inside the current process
+/// reading from `reader` and writing to `writer`. The program is stopped with
+/// [`Limit::Tape`] before it accesses a register outside of the tape.
+///
+/// Only used to test the code generated for static libraries, which runs the same way.
+pub(crate) fn run(
+    config: &Config,
+    instructions: &[Instruction],
+    reader: &mut dyn BufRead,
+    writer: &mut dyn Write,
+) -> std::io::Result<Status> {
+    let options = RuntimeOptions::new(config);
+    let code = x86::compile_function(config, &options, instructions);
+    let len = code.len();
+
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if ptr == MAP_FAILED {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let result = unsafe {
+        std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, len);
+        if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            let function: extern "sysv64" fn(*mut Io) -> i32 = std::mem::transmute(ptr);
+            let mut io = Io {
+                output,
+                input,
+                print_decimal,
+                output_const,
+                pipe,
+                reader,
+                writer,
+                eof: options.eof,
+                error: None,
+            };
+            let overrun = function(&mut io) != 0;
+            match io.error {
+                Some(e) => Err(e),
+                None if overrun => Ok(Status::Exceeded(Limit::Tape)),
+                None => Ok(Status::Finished),
+            }
+        }
+    };
+
+    unsafe { munmap(ptr, len) };
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::Interpreter;
+    use crate::selftest;
+
+    /// Run `src` optimized with `-O2` in-process, and return its status and output.
+    fn run_src(src: &str, mut input: &[u8]) -> (Status, Vec<u8>) {
+        let config = Config::o2();
+        let instructions = selftest::build(&config, src.as_bytes()).unwrap();
+        let mut output = Vec::new();
+        let status = run(&config, &instructions, &mut input, &mut output).unwrap();
+        (status, output)
+    }
+
+    /// Interpret `src` optimized with `-O2`, and return its status and output.
+    fn interpret_src(src: &str, input: &[u8]) -> (Status, Vec<u8>) {
+        let config = Config::o2();
+        let instructions = selftest::build(&config, src.as_bytes()).unwrap();
+        let mut output = Vec::new();
+        let status = Interpreter::new(&instructions, input, &mut output).run();
+        (status, output)
+    }
+
+    #[test]
+    fn output_matches_interpreter() {
+        let programs: [(&str, &[u8]); 7] = [
+            (include_str!("../examples/hello.bf"), b""),
+            (include_str!("../examples/numwarp.bf"), b"3.14-159\n"),
+            (include_str!("../examples/dbfi.b"), b",[.,]!echo\0"),
+            ("-.+.>++++++++[<++++++++++++++++++++++++++++++++>-]<.", b""),
+            (",[.,],.", b"ab\0cd"),
+            // print decimal and multiplication loops
+            ("++++++++[>+++++<-]>[>+>+<<-]>>[-<<+>>]<<.>.", b""),
+            // far offsets, that can't be proven to stay inside the tape
+            (
+                ",[>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>+<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<-]>.",
+                b"A",
+            ),
+        ];
+        for (src, input) in programs {
+            assert_eq!(run_src(src, input), interpret_src(src, input), "{src}");
+        }
+    }
+
+    #[test]
+    fn overrun_stops_program() {
+        let (status, output) = run_src("+[.>+]", b"");
+        assert_eq!(status, Status::Exceeded(Limit::Tape));
+        assert_eq!(output.len(), crate::NUM_REGISTERS);
+
+        let (status, output) = run_src("+.<+.", b"");
+        assert_eq!(status, Status::Exceeded(Limit::Tape));
+        assert_eq!(output, [1]);
+    }
+
+    #[test]
+    fn far_offsets_are_checked() {
+        let src = format!(",[{}+{}-]", ">".repeat(1 << 14), "<".repeat(1 << 14));
+        let (status, _) = run_src(&src, b"\x01");
+        assert_eq!(status, Status::Finished);
+        let src = format!(",[{}+{}-]", ">".repeat(1 << 15), "<".repeat(1 << 15));
+        let (status, _) = run_src(&src, b"\x01");
+        assert_eq!(status, Status::Exceeded(Limit::Tape));
+    }
+}
@@ -0,0 +1,744 @@
+use std::collections::HashMap;
+
+/// Translate source code of a tiny imperative language to brainfuck. Programs consist of
+/// statements operating on 8-bit variables, which are declared using `var`:
+///
+/// ```text
+/// // print the digits from 9 to 0
+/// var i = 10;
+/// while (i != 0) {
+///     i = i - 1;
+///     putc('0' + i);
+/// }
+/// putc('\n');
+/// ```
+///
+/// Expressions consist of decimal and char literals, variables, `getc()`, the binary operators
+/// `*`, `+`, `-`, `<`, `>`, `<=`, `>=`, `==`, `!=`, `&&` and `||` in decreasing order of precedence,
+/// and the unary operators `-` and `!`. Arithmetic wraps around, comparisons and logical operators
+/// evaluate to 0 or 1 and always evaluate both operands. `while` and `if`/`else` statements check
+/// whether their condition is nonzero, `putc(<expr>);` outputs a byte.
+pub fn translate(src: &[u8]) -> Result<Vec<u8>, Vec<String>> {
+    let tokens = lex(src).map_err(|e| vec![e])?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        vars: HashMap::new(),
+    };
+    let program = parser.parse_program().map_err(|e| vec![e])?;
+
+    let mut codegen = Codegen {
+        code: Vec::new(),
+        ptr: 0,
+        num_vars: parser.vars.len(),
+        num_temps: 0,
+    };
+    for stmt in program.iter() {
+        codegen.stmt(stmt);
+    }
+    Ok(codegen.code)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Tok {
+    Ident(String),
+    Num(u8),
+    Var,
+    While,
+    If,
+    Else,
+    Putc,
+    Getc,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Semicolon,
+    Comma,
+    Assign,
+    Star,
+    Plus,
+    Minus,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+    Not,
+    End,
+}
+
+impl std::fmt::Display for Tok {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            Tok::Ident(name) => return write!(f, "`{name}`"),
+            Tok::Num(n) => return write!(f, "`{n}`"),
+            Tok::Var => "var",
+            Tok::While => "while",
+            Tok::If => "if",
+            Tok::Else => "else",
+            Tok::Putc => "putc",
+            Tok::Getc => "getc",
+            Tok::LParen => "(",
+            Tok::RParen => ")",
+            Tok::LBrace => "{",
+            Tok::RBrace => "}",
+            Tok::Semicolon => ";",
+            Tok::Comma => ",",
+            Tok::Assign => "=",
+            Tok::Star => "*",
+            Tok::Plus => "+",
+            Tok::Minus => "-",
+            Tok::Lt => "<",
+            Tok::Gt => ">",
+            Tok::Le => "<=",
+            Tok::Ge => ">=",
+            Tok::Eq => "==",
+            Tok::Ne => "!=",
+            Tok::And => "&&",
+            Tok::Or => "||",
+            Tok::Not => "!",
+            Tok::End => return write!(f, "end of input"),
+        };
+        write!(f, "`{symbol}`")
+    }
+}
+
+/// A token and its line and column.
+struct Lexed {
+    tok: Tok,
+    line: usize,
+    col: usize,
+}
+
+fn lex(src: &[u8]) -> Result<Vec<Lexed>, String> {
+    let mut tokens = Vec::new();
+    let (mut line, mut col) = (1, 0);
+    let mut i = 0;
+    while i < src.len() {
+        let start = i;
+        let tok = match src[i..] {
+            [b'\n', ..] => {
+                i += 1;
+                line += 1;
+                col = 0;
+                continue;
+            }
+            [b' ' | b'\t' | b'\r', ..] => {
+                i += 1;
+                col += 1;
+                continue;
+            }
+            [b'/', b'/', ..] => {
+                while i < src.len() && src[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            [b'\'', b'\\', c, b'\'', ..] => {
+                i += 4;
+                match c {
+                    b'n' => Tok::Num(b'\n'),
+                    b't' => Tok::Num(b'\t'),
+                    b'0' => Tok::Num(0),
+                    b'\\' | b'\'' => Tok::Num(c),
+                    _ => return Err(format!("invalid escape sequence at [{line}:{col}]")),
+                }
+            }
+            [b'\'', c, b'\'', ..] if c.is_ascii() => {
+                i += 3;
+                Tok::Num(c)
+            }
+            [c, ..] if c.is_ascii_digit() => {
+                while i < src.len() && src[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let digits = std::str::from_utf8(&src[start..i]).expect("ascii digits");
+                match digits.parse() {
+                    Ok(n) => Tok::Num(n),
+                    Err(_) => return Err(format!("number out of range at [{line}:{col}]")),
+                }
+            }
+            [c, ..] if c.is_ascii_alphabetic() || c == b'_' => {
+                while i < src.len() && (src[i].is_ascii_alphanumeric() || src[i] == b'_') {
+                    i += 1;
+                }
+                match &src[start..i] {
+                    b"var" => Tok::Var,
+                    b"while" => Tok::While,
+                    b"if" => Tok::If,
+                    b"else" => Tok::Else,
+                    b"putc" => Tok::Putc,
+                    b"getc" => Tok::Getc,
+                    name => Tok::Ident(String::from_utf8_lossy(name).into_owned()),
+                }
+            }
+            [a @ (b'<' | b'>' | b'=' | b'!'), b'=', ..] => {
+                i += 2;
+                match a {
+                    b'<' => Tok::Le,
+                    b'>' => Tok::Ge,
+                    b'=' => Tok::Eq,
+                    _ => Tok::Ne,
+                }
+            }
+            [b'&', b'&', ..] => {
+                i += 2;
+                Tok::And
+            }
+            [b'|', b'|', ..] => {
+                i += 2;
+                Tok::Or
+            }
+            [c, ..] => {
+                i += 1;
+                match c {
+                    b'(' => Tok::LParen,
+                    b')' => Tok::RParen,
+                    b'{' => Tok::LBrace,
+                    b'}' => Tok::RBrace,
+                    b';' => Tok::Semicolon,
+                    b',' => Tok::Comma,
+                    b'=' => Tok::Assign,
+                    b'*' => Tok::Star,
+                    b'+' => Tok::Plus,
+                    b'-' => Tok::Minus,
+                    b'<' => Tok::Lt,
+                    b'>' => Tok::Gt,
+                    b'!' => Tok::Not,
+                    _ => return Err(format!("unexpected character at [{line}:{col}]")),
+                }
+            }
+            [] => unreachable!(),
+        };
+        tokens.push(Lexed { tok, line, col });
+        col += i - start;
+    }
+    tokens.push(Lexed {
+        tok: Tok::End,
+        line,
+        col,
+    });
+    Ok(tokens)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinOp {
+    Mul,
+    Add,
+    Sub,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Num(u8),
+    Var(usize),
+    Getc,
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Assign(usize, Expr),
+    Putc(Expr),
+    While(Expr, Vec<Stmt>),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+}
+
+struct Parser<'a> {
+    tokens: &'a [Lexed],
+    pos: usize,
+    /// The cell of each declared variable.
+    vars: HashMap<String, usize>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.pos].tok
+    }
+
+    fn next(&mut self) -> &Tok {
+        let tok = &self.tokens[self.pos].tok;
+        if *tok != Tok::End {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn eat(&mut self, tok: Tok) -> bool {
+        let matches = *self.peek() == tok;
+        if matches {
+            self.next();
+        }
+        matches
+    }
+
+    fn error(&self, expected: &str) -> String {
+        let Lexed { tok, line, col } = &self.tokens[self.pos];
+        format!("expected {expected} at [{line}:{col}], found {tok}")
+    }
+
+    fn expect(&mut self, tok: Tok) -> Result<(), String> {
+        if self.eat(tok.clone()) {
+            Ok(())
+        } else {
+            Err(self.error(&tok.to_string()))
+        }
+    }
+
+    fn parse_program(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut stmts = Vec::new();
+        while *self.peek() != Tok::End {
+            self.parse_stmt(&mut stmts)?;
+        }
+        Ok(stmts)
+    }
+
+    fn parse_block(&mut self) -> Result<Vec<Stmt>, String> {
+        self.expect(Tok::LBrace)?;
+        let mut stmts = Vec::new();
+        while !self.eat(Tok::RBrace) {
+            self.parse_stmt(&mut stmts)?;
+        }
+        Ok(stmts)
+    }
+
+    fn parse_stmt(&mut self, stmts: &mut Vec<Stmt>) -> Result<(), String> {
+        match self.peek().clone() {
+            Tok::Var => {
+                self.next();
+                loop {
+                    let Tok::Ident(name) = self.peek().clone() else {
+                        return Err(self.error("variable name"));
+                    };
+                    if self.vars.contains_key(&name) {
+                        let Lexed { line, col, .. } = &self.tokens[self.pos];
+                        return Err(format!(
+                            "variable `{name}` at [{line}:{col}] already declared"
+                        ));
+                    }
+                    self.next();
+                    let var = self.vars.len();
+                    self.vars.insert(name, var);
+                    if self.eat(Tok::Assign) {
+                        stmts.push(Stmt::Assign(var, self.parse_expr()?));
+                    }
+                    if !self.eat(Tok::Comma) {
+                        break;
+                    }
+                }
+                self.expect(Tok::Semicolon)?;
+            }
+            Tok::Ident(_) => {
+                let var = self.parse_var()?;
+                self.expect(Tok::Assign)?;
+                stmts.push(Stmt::Assign(var, self.parse_expr()?));
+                self.expect(Tok::Semicolon)?;
+            }
+            Tok::Putc => {
+                self.next();
+                self.expect(Tok::LParen)?;
+                stmts.push(Stmt::Putc(self.parse_expr()?));
+                self.expect(Tok::RParen)?;
+                self.expect(Tok::Semicolon)?;
+            }
+            Tok::While => {
+                self.next();
+                let cond = self.parse_cond()?;
+                let body = self.parse_block()?;
+                stmts.push(Stmt::While(cond, body));
+            }
+            Tok::If => {
+                self.next();
+                let cond = self.parse_cond()?;
+                let then = self.parse_block()?;
+                let otherwise = match self.eat(Tok::Else) {
+                    true if *self.peek() == Tok::If => {
+                        let mut stmts = Vec::new();
+                        self.parse_stmt(&mut stmts)?;
+                        stmts
+                    }
+                    true => self.parse_block()?,
+                    false => Vec::new(),
+                };
+                stmts.push(Stmt::If(cond, then, otherwise));
+            }
+            _ => return Err(self.error("statement")),
+        }
+        Ok(())
+    }
+
+    fn parse_var(&mut self) -> Result<usize, String> {
+        let Lexed { tok, line, col } = &self.tokens[self.pos];
+        let Tok::Ident(name) = tok else {
+            return Err(self.error("variable name"));
+        };
+        let Some(&var) = self.vars.get(name) else {
+            return Err(format!("undeclared variable `{name}` at [{line}:{col}]"));
+        };
+        self.next();
+        Ok(var)
+    }
+
+    fn parse_cond(&mut self) -> Result<Expr, String> {
+        self.expect(Tok::LParen)?;
+        let cond = self.parse_expr()?;
+        self.expect(Tok::RParen)?;
+        Ok(cond)
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, String> {
+        self.parse_binary(0)
+    }
+
+    /// Parse binary operators of at least the precedence `level`.
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: [&[Tok]; 6] = [
+            &[Tok::Or],
+            &[Tok::And],
+            &[Tok::Eq, Tok::Ne],
+            &[Tok::Lt, Tok::Gt, Tok::Le, Tok::Ge],
+            &[Tok::Plus, Tok::Minus],
+            &[Tok::Star],
+        ];
+        if level == LEVELS.len() {
+            return self.parse_unary();
+        }
+
+        let mut lhs = self.parse_binary(level + 1)?;
+        while LEVELS[level].contains(self.peek()) {
+            let tok = self.next().clone();
+            let rhs = self.parse_binary(level + 1)?;
+            let (op, lhs_, rhs) = match tok {
+                Tok::Or => (BinOp::Or, lhs, rhs),
+                Tok::And => (BinOp::And, lhs, rhs),
+                Tok::Eq => (BinOp::Eq, lhs, rhs),
+                Tok::Ne => (BinOp::Ne, lhs, rhs),
+                Tok::Lt => (BinOp::Lt, lhs, rhs),
+                Tok::Gt => (BinOp::Lt, rhs, lhs),
+                Tok::Le => (BinOp::Le, lhs, rhs),
+                Tok::Ge => (BinOp::Le, rhs, lhs),
+                Tok::Plus => (BinOp::Add, lhs, rhs),
+                Tok::Minus => (BinOp::Sub, lhs, rhs),
+                Tok::Star => (BinOp::Mul, lhs, rhs),
+                _ => unreachable!(),
+            };
+            lhs = Expr::Binary(op, Box::new(lhs_), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.peek().clone() {
+            Tok::Minus => {
+                self.next();
+                Ok(Expr::Neg(Box::new(self.parse_unary()?)))
+            }
+            Tok::Not => {
+                self.next();
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Tok::Num(n) => {
+                self.next();
+                Ok(Expr::Num(n))
+            }
+            Tok::Ident(_) => Ok(Expr::Var(self.parse_var()?)),
+            Tok::Getc => {
+                self.next();
+                self.expect(Tok::LParen)?;
+                self.expect(Tok::RParen)?;
+                Ok(Expr::Getc)
+            }
+            Tok::LParen => {
+                self.next();
+                let expr = self.parse_expr()?;
+                self.expect(Tok::RParen)?;
+                Ok(expr)
+            }
+            _ => Err(self.error("expression")),
+        }
+    }
+}
+
+/// Generates brainfuck code. Variables are stored in the first cells of the tape, followed by a
+/// stack of temporary cells that are zero when they are allocated and freed.
+struct Codegen {
+    code: Vec<u8>,
+    /// The statically known register pointer.
+    ptr: usize,
+    num_vars: usize,
+    num_temps: usize,
+}
+
+impl Codegen {
+    fn alloc(&mut self) -> usize {
+        self.num_temps += 1;
+        self.num_vars + self.num_temps - 1
+    }
+
+    fn free(&mut self, cell: usize) {
+        self.num_temps -= 1;
+        debug_assert_eq!(cell, self.num_vars + self.num_temps);
+    }
+
+    fn move_to(&mut self, cell: usize) {
+        if cell > self.ptr {
+            self.code.extend(std::iter::repeat_n(b'>', cell - self.ptr));
+        } else {
+            self.code.extend(std::iter::repeat_n(b'<', self.ptr - cell));
+        }
+        self.ptr = cell;
+    }
+
+    fn add(&mut self, cell: usize, n: u8) {
+        self.move_to(cell);
+        if n <= 128 {
+            self.code.extend(std::iter::repeat_n(b'+', n as usize));
+        } else {
+            self.code
+                .extend(std::iter::repeat_n(b'-', 256 - n as usize));
+        }
+    }
+
+    fn clear(&mut self, cell: usize) {
+        self.move_to(cell);
+        self.code.extend_from_slice(b"[-]");
+    }
+
+    /// Start a loop that runs while the `cell` is nonzero, the loop body has to end at the same
+    /// cell.
+    fn open(&mut self, cell: usize) {
+        self.move_to(cell);
+        self.code.push(b'[');
+    }
+
+    fn close(&mut self, cell: usize) {
+        self.move_to(cell);
+        self.code.push(b']');
+    }
+
+    /// Add the value of `src` to the `dsts`, or subtract it from them, and clear `src`.
+    fn move_add(&mut self, src: usize, dsts: &[(usize, bool)]) {
+        self.open(src);
+        self.code.push(b'-');
+        for &(dst, subtract) in dsts {
+            self.move_to(dst);
+            self.code.push(if subtract { b'-' } else { b'+' });
+        }
+        self.close(src);
+    }
+
+    /// Copy the value of `src` to a new temporary cell.
+    fn copy(&mut self, src: usize) -> usize {
+        let dst = self.alloc();
+        let tmp = self.alloc();
+        self.move_add(src, &[(dst, false), (tmp, false)]);
+        self.move_add(tmp, &[(src, false)]);
+        self.free(tmp);
+        dst
+    }
+
+    /// Replace the value of the `cell` with 1 if it's nonzero, or 0 otherwise.
+    fn bool(&mut self, cell: usize) {
+        let tmp = self.alloc();
+        self.open(cell);
+        self.clear(cell);
+        self.add(tmp, 1);
+        self.close(cell);
+        self.move_add(tmp, &[(cell, false)]);
+        self.free(tmp);
+    }
+
+    /// Replace the value of the `cell` with 1 if it's zero, or 0 otherwise.
+    fn not(&mut self, cell: usize) {
+        let tmp = self.alloc();
+        self.add(tmp, 1);
+        self.open(cell);
+        self.clear(cell);
+        self.add(tmp, 255);
+        self.close(cell);
+        self.move_add(tmp, &[(cell, false)]);
+        self.free(tmp);
+    }
+
+    /// Multiply `a` by `b`, and clear `b`.
+    fn mul(&mut self, a: usize, b: usize) {
+        let product = self.alloc();
+        let tmp = self.alloc();
+        self.open(b);
+        self.code.push(b'-');
+        self.move_add(a, &[(product, false), (tmp, false)]);
+        self.move_add(tmp, &[(a, false)]);
+        self.close(b);
+        self.clear(a);
+        self.move_add(product, &[(a, false)]);
+        self.free(tmp);
+        self.free(product);
+    }
+
+    /// Replace the value of `a` with 1 if it's less than `b`, or 0 otherwise, and clear `b`.
+    fn lt(&mut self, a: usize, b: usize) {
+        // decrement both until one of them is zero
+        let cond = self.alloc();
+        self.both_nonzero(a, b, cond);
+        self.open(cond);
+        self.clear(cond);
+        self.add(a, 255);
+        self.add(b, 255);
+        self.both_nonzero(a, b, cond);
+        self.close(cond);
+        self.free(cond);
+
+        self.clear(a);
+        self.bool(b);
+        self.move_add(b, &[(a, false)]);
+    }
+
+    /// Set the zeroed `dst` to 1 if both `a` and `b` are nonzero.
+    fn both_nonzero(&mut self, a: usize, b: usize, dst: usize) {
+        let a_copy = self.copy(a);
+        self.open(a_copy);
+        self.clear(a_copy);
+        let b_copy = self.copy(b);
+        self.open(b_copy);
+        self.clear(b_copy);
+        self.add(dst, 1);
+        self.close(b_copy);
+        self.free(b_copy);
+        self.close(a_copy);
+        self.free(a_copy);
+    }
+
+    /// Evaluate the expression into a new temporary cell.
+    fn expr(&mut self, expr: &Expr) -> usize {
+        match expr {
+            Expr::Num(n) => {
+                let cell = self.alloc();
+                self.add(cell, *n);
+                cell
+            }
+            &Expr::Var(var) => self.copy(var),
+            Expr::Getc => {
+                let cell = self.alloc();
+                self.move_to(cell);
+                self.code.push(b',');
+                cell
+            }
+            Expr::Neg(expr) => {
+                let cell = self.alloc();
+                let value = self.expr(expr);
+                self.move_add(value, &[(cell, true)]);
+                self.free(value);
+                cell
+            }
+            Expr::Not(expr) => {
+                let cell = self.expr(expr);
+                self.not(cell);
+                cell
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let a = self.expr(lhs);
+                let b = self.expr(rhs);
+                match op {
+                    BinOp::Mul => self.mul(a, b),
+                    BinOp::Add => self.move_add(b, &[(a, false)]),
+                    BinOp::Sub => self.move_add(b, &[(a, true)]),
+                    BinOp::Lt => self.lt(a, b),
+                    BinOp::Le => {
+                        // a <= b is !(b < a)
+                        let tmp = self.alloc();
+                        self.move_add(a, &[(tmp, false)]);
+                        self.move_add(b, &[(a, false)]);
+                        self.lt(a, tmp);
+                        self.not(a);
+                        self.free(tmp);
+                    }
+                    BinOp::Eq => {
+                        self.move_add(b, &[(a, true)]);
+                        self.not(a);
+                    }
+                    BinOp::Ne => {
+                        self.move_add(b, &[(a, true)]);
+                        self.bool(a);
+                    }
+                    BinOp::And => {
+                        self.bool(a);
+                        self.bool(b);
+                        self.mul(a, b);
+                    }
+                    BinOp::Or => {
+                        self.bool(a);
+                        self.bool(b);
+                        self.move_add(b, &[(a, false)]);
+                        self.bool(a);
+                    }
+                }
+                self.free(b);
+                a
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            &Stmt::Assign(var, ref expr) => {
+                let value = self.expr(expr);
+                self.clear(var);
+                self.move_add(value, &[(var, false)]);
+                self.free(value);
+            }
+            Stmt::Putc(expr) => {
+                let value = self.expr(expr);
+                self.move_to(value);
+                self.code.push(b'.');
+                self.clear(value);
+                self.free(value);
+            }
+            Stmt::While(cond, body) => {
+                let value = self.expr(cond);
+                self.open(value);
+                self.clear(value);
+                for stmt in body.iter() {
+                    self.stmt(stmt);
+                }
+                let next = self.expr(cond);
+                self.move_add(next, &[(value, false)]);
+                self.free(next);
+                self.close(value);
+                self.free(value);
+            }
+            Stmt::If(cond, then, otherwise) => {
+                let value = self.expr(cond);
+                let is_else = self.alloc();
+                self.add(is_else, 1);
+                self.open(value);
+                self.clear(value);
+                self.add(is_else, 255);
+                for stmt in then.iter() {
+                    self.stmt(stmt);
+                }
+                self.close(value);
+                self.open(is_else);
+                self.add(is_else, 255);
+                for stmt in otherwise.iter() {
+                    self.stmt(stmt);
+                }
+                self.close(is_else);
+                self.free(is_else);
+                self.free(value);
+            }
+        }
+        self.code.push(b'\n');
+    }
+}
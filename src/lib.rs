@@ -0,0 +1,952 @@
+use std::cmp::PartialOrd;
+use std::io::Read;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{Config, Dialect, Syntax};
+
+pub mod backend;
+pub mod bench;
+pub mod bundle;
+pub mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod cli;
+pub mod coredump;
+pub mod cover;
+pub mod diff;
+pub mod digest;
+pub mod encoding;
+pub mod expect;
+pub mod explain;
+pub mod fuzz;
+pub mod i686;
+pub mod interp;
+pub mod ir;
+pub mod jit;
+#[cfg(feature = "lang")]
+pub mod lang;
+pub mod link;
+pub mod lint;
+pub mod object;
+pub mod optim;
+pub mod parallel;
+pub mod peephole;
+pub mod pipeline;
+pub mod pragma;
+pub mod project;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod reduce;
+pub mod rng;
+pub mod rust;
+pub mod selftest;
+pub mod serve;
+pub mod staticlib;
+#[cfg(feature = "superopt")]
+pub mod superopt;
+pub mod symex;
+pub mod termination;
+pub mod test;
+pub mod tty;
+pub mod viz;
+pub mod x86;
+
+pub const NUM_REGISTERS: usize = 1 << 15;
+/// The maximum number of instructions of a program, jump locations are 32 bits wide.
+pub const MAX_INSTRUCTIONS: usize = u32::MAX as usize - 1;
+
+#[macro_export]
+macro_rules! warn {
+    ($pat:expr) => {{
+        use $crate::cli::{ANSII_CLEAR, ANSII_COLOR_YELLOW};
+        $crate::parallel::eprint(format_args!(
+            "{ANSII_COLOR_YELLOW}warning{ANSII_CLEAR}: {}\n",
+            format_args!($pat)
+        ));
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($pat:expr) => {{
+        use $crate::cli::{ANSII_CLEAR, ANSII_COLOR_RED};
+        $crate::parallel::eprint(format_args!(
+            "{ANSII_COLOR_RED}error{ANSII_CLEAR}: {}\n",
+            format_args!($pat)
+        ));
+    }};
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Token {
+    Shl,
+    Shr,
+    Inc,
+    Dec,
+    Output,
+    Input,
+    LSquare,
+    RSquare,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Shl => write!(f, "<"),
+            Token::Shr => write!(f, ">"),
+            Token::Inc => write!(f, "+"),
+            Token::Dec => write!(f, "-"),
+            Token::Output => write!(f, "."),
+            Token::Input => write!(f, ","),
+            Token::LSquare => write!(f, "["),
+            Token::RSquare => write!(f, "]"),
+        }
+    }
+}
+
+impl Token {
+    pub fn is_combinable(self) -> bool {
+        match self {
+            Token::Shl | Token::Shr | Token::Inc | Token::Dec => true,
+            Token::Output | Token::Input | Token::LSquare | Token::RSquare => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Instruction {
+    Shl(u16),
+    Shr(u16),
+    Inc(i16, u8),
+    Dec(i16, u8),
+    /// Write the register at offset to the output.
+    Output(i16),
+    /// Read a byte from the input into the register at offset.
+    Input(i16),
+    /// Jump to the position if the current register value is zero.
+    JumpZ(Jump),
+    /// Jump to the position if the current register value is not zero.
+    JumpNz(Jump),
+
+    /// Clear the current register:
+    /// ```bf
+    /// [
+    ///     -
+    /// ]
+    /// ```
+    Zero(i16),
+    Set(i16, u8),
+    /// Add current register value to register at offset.
+    Add(i16),
+    /// Subtract current register value from register at offset.
+    Sub(i16),
+    /// Multiply current register value and add to register at offset.
+    AddMul(i16, u8),
+    /// Multiply current register value and subtraction from register at offset.
+    SubMul(i16, u8),
+    /// Copy a range of registers `(src_offset, dst_offset, len)`, the ranges may overlap.
+    CopyRange(i16, i16, u16),
+    /// Divide the current register by the next one, if the registers are laid out as expected by
+    /// the divmod loop following this instruction, see [`interp::div_mod`].
+    DivMod,
+    /// Write the value of the register at offset to the output as decimal number.
+    PrintDecimal(i16),
+    /// Write a constant byte to the output, consecutive constant bytes are written at once by the
+    /// compiled code.
+    OutputConst(u8),
+    /// Copy the input to the output in large chunks, like the echo loop `[.,]` following this
+    /// instruction, until the current register is zero or the input is exhausted. The loop is
+    /// kept, it handles the end of the input and continues wherever the copy stopped.
+    Pipe,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Jump {
+    Location(NonZeroU32),
+    /// The location hasn't been set yet, see [`update_jump_indices`]. Newly created jumps start
+    /// out unresolved, and executing one is a bug.
+    Unresolved,
+    /// The jump is known to never be taken. A loop with a redundant closing jump is an `if`
+    /// statement, which executes its body at most once.
+    Redundant,
+}
+
+impl Jump {
+    pub fn is_redundant(&self) -> bool {
+        matches!(self, Self::Redundant)
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Shl(n) => write!(f, "< ({n})"),
+            Instruction::Shr(n) => write!(f, "> ({n})"),
+            Instruction::Inc(0, n) => write!(f, "+ ({n})"),
+            Instruction::Inc(o, n) => write!(f, "<{o}> + ({n})"),
+            Instruction::Dec(0, n) => write!(f, "- ({n})"),
+            Instruction::Dec(o, n) => write!(f, "<{o}> - ({n})"),
+            Instruction::Output(0) => write!(f, "out"),
+            Instruction::Output(o) => write!(f, "<{o}> out"),
+            Instruction::Input(0) => write!(f, "in"),
+            Instruction::Input(o) => write!(f, "<{o}> in"),
+            Instruction::JumpZ(Jump::Redundant) => write!(f, "[ !"),
+            Instruction::JumpZ(Jump::Location(_) | Jump::Unresolved) => write!(f, "["),
+            Instruction::JumpNz(Jump::Redundant) => write!(f, "] !"),
+            Instruction::JumpNz(Jump::Location(_) | Jump::Unresolved) => write!(f, "]"),
+
+            Instruction::Zero(0) => write!(f, "zero"),
+            Instruction::Zero(o) => write!(f, "<{o}> zero"),
+            Instruction::Set(0, n) => write!(f, "set {n}"),
+            Instruction::Set(o, n) => write!(f, "<{o}> set {n}"),
+            Instruction::Add(o) => write!(f, "<{o}> add"),
+            Instruction::Sub(o) => write!(f, "<{o}> sub"),
+            Instruction::AddMul(o, n) => write!(f, "<{o}> addmul({n})"),
+            Instruction::SubMul(o, n) => write!(f, "<{o}> submul({n})"),
+            Instruction::CopyRange(s, d, n) => write!(f, "<{d}> copy <{s}> ({n})"),
+            Instruction::DivMod => write!(f, "divmod"),
+            Instruction::PrintDecimal(0) => write!(f, "out decimal"),
+            Instruction::PrintDecimal(o) => write!(f, "<{o}> out decimal"),
+            Instruction::OutputConst(n) => write!(f, "out const {n}"),
+            Instruction::Pipe => write!(f, "pipe"),
+        }
+    }
+}
+
+impl Instruction {
+    /// The name of the opcode, ignoring operands.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::Shl(_) => "shl",
+            Instruction::Shr(_) => "shr",
+            Instruction::Inc(..) => "inc",
+            Instruction::Dec(..) => "dec",
+            Instruction::Output(_) => "out",
+            Instruction::Input(_) => "in",
+            Instruction::JumpZ(_) => "jumpz",
+            Instruction::JumpNz(_) => "jumpnz",
+            Instruction::Zero(_) => "zero",
+            Instruction::Set(..) => "set",
+            Instruction::Add(_) => "add",
+            Instruction::Sub(_) => "sub",
+            Instruction::AddMul(..) => "addmul",
+            Instruction::SubMul(..) => "submul",
+            Instruction::CopyRange(..) => "copy",
+            Instruction::DivMod => "divmod",
+            Instruction::PrintDecimal(_) => "outdec",
+            Instruction::OutputConst(_) => "outconst",
+            Instruction::Pipe => "pipe",
+        }
+    }
+}
+
+/// Replace directories with all brainfuck programs inside them, sorted by path.
+pub fn collect_programs(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>, (PathBuf, std::io::Error)> {
+    let mut programs = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let start = programs.len();
+            collect_dir(&path, &mut programs)?;
+            programs[start..].sort();
+        } else {
+            programs.push(path);
+        }
+    }
+    Ok(programs)
+}
+
+fn collect_dir(dir: &Path, programs: &mut Vec<PathBuf>) -> Result<(), (PathBuf, std::io::Error)> {
+    let entries = std::fs::read_dir(dir).map_err(|e| (dir.to_path_buf(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| (dir.to_path_buf(), e))?.path();
+        if path.is_dir() {
+            collect_dir(&path, programs)?;
+        } else if matches!(path.extension().and_then(|e| e.to_str()), Some("b" | "bf")) {
+            programs.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Tokenize brainfuck source code, and check that all brackets are matched.
+pub fn tokenize(input: &[u8]) -> Result<Vec<Token>, Vec<String>> {
+    let mut tokenizer = Tokenizer::new();
+    let mut tokens = Vec::new();
+    tokenizer.feed(input, |t| tokens.push(t));
+    tokenizer.finish()?;
+    Ok(tokens)
+}
+
+/// Tokenize brainfuck source code, recovering from unmatched brackets instead of failing, so
+/// editors can still work with programs that are being edited. Closing brackets without an opening
+/// one are dropped, missing closing brackets are inserted at the end of the program, and a
+/// diagnostic is recorded for each of them.
+pub fn tokenize_tolerant(src: &[u8]) -> (Vec<Token>, Vec<String>) {
+    let mut tokens = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut par_stack = Vec::new();
+    for (t, span) in self::tokens(src) {
+        match t {
+            Token::LSquare => par_stack.push(span),
+            Token::RSquare if par_stack.pop().is_none() => {
+                let msg = format!("missing opening bracket for {span}, removed the closing one");
+                diagnostics.push(msg);
+                continue;
+            }
+            _ => (),
+        }
+        tokens.push(t);
+    }
+    for span in par_stack.iter() {
+        let msg = format!("missing closing bracket for {span}, inserted one at the end");
+        diagnostics.push(msg);
+        tokens.push(Token::RSquare);
+    }
+    (tokens, diagnostics)
+}
+
+/// Combine repeated tokens into single instructions.
+pub fn combine_tokens(config: &Config, tokens: &[Token]) -> Vec<Instruction> {
+    let mut combiner = Combiner::new().with_verbose(config.verbose);
+    for &t in tokens {
+        combiner.push(t);
+    }
+    combiner.finish()
+}
+
+/// Lazily tokenize brainfuck source code, yielding each token together with its span. In contrast
+/// to [`tokenize`] brackets aren't checked, so tools like linters and highlighters can also
+/// process incomplete programs.
+pub fn tokens(src: &[u8]) -> Tokens<'_> {
+    Tokens {
+        bytes: src.iter(),
+        lexer: Lexer::new(),
+    }
+}
+
+/// Combine repeated tokens, as yielded by [`tokens`], into single instructions. The span of each
+/// instruction is the one of its first token. The jump locations aren't resolved, use
+/// [`update_jump_indices`] once the brackets are known to be matched.
+pub fn instructions(
+    tokens: impl IntoIterator<Item = (Token, Span)>,
+) -> (Vec<Instruction>, Vec<Span>) {
+    let mut combiner = Combiner::new();
+    let mut spans = Vec::new();
+    for (t, span) in tokens {
+        combiner.push(t);
+        if combiner.instructions.len() > spans.len() {
+            spans.push(span);
+        }
+    }
+    (combiner.finish(), spans)
+}
+
+/// Combine repeated tokens into single instructions, like [`instructions`] without spans.
+pub(crate) fn combine(tokens: impl IntoIterator<Item = Token>) -> Vec<Instruction> {
+    let mut combiner = Combiner::new();
+    for t in tokens {
+        combiner.push(t);
+    }
+    combiner.finish()
+}
+
+/// Translate Ook! source code to brainfuck. Each pair of `Ook.`, `Ook?` and `Ook!` words is a
+/// brainfuck command, other text is ignored. Newlines are kept, so errors found in the brainfuck
+/// code refer to the same lines.
+pub fn translate_ook(src: &[u8]) -> Result<Vec<u8>, Vec<String>> {
+    let mut code = Vec::new();
+    let mut errors = Vec::new();
+    // the punctuation and position of the first word of the current pair
+    let mut first = None;
+    let (mut line, mut col) = (1, 0);
+    let mut i = 0;
+    while i < src.len() {
+        if let [b'O', b'o', b'k', p @ (b'.' | b'?' | b'!'), ..] = src[i..] {
+            match first.take() {
+                None => first = Some((p, line, col)),
+                Some((a, l, c)) => match (a, p) {
+                    (b'.', b'?') => code.push(b'>'),
+                    (b'?', b'.') => code.push(b'<'),
+                    (b'.', b'.') => code.push(b'+'),
+                    (b'!', b'!') => code.push(b'-'),
+                    (b'!', b'.') => code.push(b'.'),
+                    (b'.', b'!') => code.push(b','),
+                    (b'!', b'?') => code.push(b'['),
+                    (b'?', b'!') => code.push(b']'),
+                    _ => {
+                        let (a, p) = (a as char, p as char);
+                        errors.push(format!("invalid Ook! command `Ook{a} Ook{p}` at [{l}:{c}]"));
+                    }
+                },
+            }
+            i += 4;
+            col += 4;
+            continue;
+        }
+
+        if src[i] == b'\n' {
+            code.push(b'\n');
+            line += 1;
+            col = 0;
+        } else {
+            col += 1;
+        }
+        i += 1;
+    }
+    if let Some((_, l, c)) = first {
+        errors.push(format!("incomplete Ook! command at [{l}:{c}]"));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(code)
+}
+
+/// Translate brainfuck source code with multiple tapes to brainfuck. `}` and `{` switch to the next
+/// and the previous tape, all tapes share the position of the head. The registers of the tapes
+/// are interleaved, so the tape a command operates on has to be known statically: loops have to
+/// end on the tape they started on. Newlines are kept, so errors found in the brainfuck code refer
+/// to the same lines.
+pub fn translate_multi_tape(src: &[u8]) -> Result<Vec<u8>, Vec<String>> {
+    let mut errors = Vec::new();
+    // the tape and position of each open loop
+    let mut loops = Vec::new();
+    let mut tape = 0_usize;
+    let mut num_tapes = 1;
+    let (mut line, mut col) = (1, 0);
+    for (offset, &c) in src.iter().enumerate() {
+        let span = Span { offset, line, col };
+        match c {
+            b'}' => {
+                tape += 1;
+                num_tapes = num_tapes.max(tape + 1);
+            }
+            b'{' if tape == 0 => errors.push(format!("switch before the first tape at {span}")),
+            b'{' => tape -= 1,
+            b'[' => loops.push((tape, span)),
+            // unmatched brackets are reported by the tokenizer
+            b']' => match loops.pop() {
+                Some((start, start_span)) if start != tape => errors.push(format!(
+                    "loop at {start_span} starts on tape {start} but ends on tape {tape} at {span}"
+                )),
+                _ => (),
+            },
+            _ => (),
+        }
+        if c == b'\n' {
+            line += 1;
+            col = 0;
+        } else if c & 0xC0 != 0x80 {
+            // utf-8 continuation bytes belong to the preceding char
+            col += 1;
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // the register `i` of tape `t` is located at `i * num_tapes + t`
+    let mut code = Vec::new();
+    for &c in src {
+        match c {
+            b'>' | b'<' => code.extend(std::iter::repeat_n(c, num_tapes)),
+            b'}' => code.push(b'>'),
+            b'{' => code.push(b'<'),
+            b'+' | b'-' | b'.' | b',' | b'[' | b']' | b'\n' => code.push(c),
+            _ => (),
+        }
+    }
+    Ok(code)
+}
+
+/// Translate source code of the `syntax` to brainfuck.
+pub fn translate(syntax: Syntax, src: Vec<u8>) -> Result<Vec<u8>, Vec<String>> {
+    match syntax {
+        Syntax::Brainfuck => Ok(src),
+        Syntax::Ook => translate_ook(&src),
+        #[cfg(feature = "lang")]
+        Syntax::Lang => lang::translate(&src),
+        #[cfg(not(feature = "lang"))]
+        Syntax::Lang => unreachable!("requires the `lang` feature"),
+    }
+}
+
+/// Check that brainfuck source code only contains commands, whitespace and comments, which start
+/// with the `marker` and end at the end of the line, see `--strict-syntax`. The comments are
+/// replaced with spaces, so the commands in them are ignored and the spans of the other commands
+/// don't change. Each run of other bytes in a line is reported with its location.
+pub fn check_strict_syntax(src: &mut [u8], marker: &[u8]) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    let (mut line, mut col) = (1, 0);
+    // the start of the current run of unexpected bytes
+    let mut unexpected: Option<(usize, Span)> = None;
+    let mut i = 0;
+    while i < src.len() {
+        let b = src[i];
+        let allowed = matches!(b, b'<' | b'>' | b'+' | b'-' | b'.' | b',' | b'[' | b']')
+            || b.is_ascii_whitespace();
+        let comment = !marker.is_empty() && src[i..].starts_with(marker);
+        if allowed || comment {
+            if let Some((start, span)) = unexpected.take() {
+                let bytes = src[start..i].escape_ascii();
+                errors.push(format!("unexpected `{bytes}` at {span}, only commands, whitespace and comments are allowed"));
+            }
+        } else if unexpected.is_none() {
+            let span = Span {
+                offset: i,
+                line,
+                col,
+            };
+            unexpected = Some((i, span));
+        }
+
+        if comment {
+            let end = (src[i..].iter().position(|&b| b == b'\n')).map_or(src.len(), |n| i + n);
+            src[i..end].fill(b' ');
+            i = end;
+            continue;
+        }
+        if b == b'\n' {
+            line += 1;
+            col = 0;
+        } else if b & 0xC0 != 0x80 {
+            // utf-8 continuation bytes belong to the preceding char
+            col += 1;
+        }
+        i += 1;
+    }
+    if let Some((start, span)) = unexpected {
+        let bytes = src[start..].escape_ascii();
+        errors.push(format!(
+            "unexpected `{bytes}` at {span}, only commands, whitespace and comments are allowed"
+        ));
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+/// Prepare source code for tokenizing, by removing the pragmas of brainfuck source code, see
+/// [`pragma`], checking it with [`check_strict_syntax`] if `--strict-syntax` is passed, and
+/// translating source code of other syntaxes and dialects to brainfuck.
+pub fn preprocess(config: &Config, mut src: Vec<u8>) -> Result<Vec<u8>, Vec<String>> {
+    if config.syntax == Syntax::Brainfuck {
+        pragma::strip(&mut src);
+    }
+    if config.strict_syntax {
+        check_strict_syntax(&mut src, config.comment_marker.as_bytes())?;
+    }
+    let src = translate(config.syntax, src)?;
+    match config.dialect {
+        Dialect::Brainfuck => Ok(src),
+        Dialect::MultiTape => translate_multi_tape(&src),
+    }
+}
+
+/// Tokenize, combine and validate brackets of source code read in chunks, without holding the
+/// complete source code or token list in memory. Source code of other syntaxes is translated at
+/// once, and when recovering from unmatched brackets or checking the strict syntax the source
+/// code is also read at once.
+pub fn parse(
+    config: &Config,
+    mut reader: impl Read,
+) -> std::io::Result<Result<Vec<Instruction>, Vec<String>>> {
+    if !config.is_brainfuck() || config.recover || config.strict_syntax {
+        let mut src = Vec::new();
+        reader.read_to_end(&mut src)?;
+        src = match preprocess(config, src) {
+            Ok(code) => code,
+            Err(errors) => return Ok(Err(errors)),
+        };
+        let tokens = if config.recover {
+            let (tokens, diagnostics) = tokenize_tolerant(&src);
+            for d in diagnostics {
+                warn!("{d}");
+            }
+            tokens
+        } else {
+            match tokenize(&src) {
+                Ok(t) => t,
+                Err(errors) => return Ok(Err(errors)),
+            }
+        };
+        return Ok(check_size(combine_tokens(config, &tokens)));
+    }
+
+    let mut tokenizer = Tokenizer::new();
+    let mut combiner = Combiner::new().with_verbose(config.verbose);
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        tokenizer.feed(&buf[..n], |t| combiner.push(t));
+    }
+    if let Err(errors) = tokenizer.finish() {
+        return Ok(Err(errors));
+    }
+    Ok(check_size(combiner.finish()))
+}
+
+/// Reject programs with more than [`MAX_INSTRUCTIONS`] instructions, jumps couldn't address all of
+/// their instructions.
+fn check_size(instructions: Vec<Instruction>) -> Result<Vec<Instruction>, Vec<String>> {
+    if instructions.len() > MAX_INSTRUCTIONS {
+        return Err(vec![format!(
+            "program too large: {} instructions after combining, at most {MAX_INSTRUCTIONS} are supported",
+            instructions.len()
+        )]);
+    }
+    Ok(instructions)
+}
+
+/// The location of a token in source code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Span {
+    /// The byte offset of the token, tokens are always a single byte long.
+    pub offset: usize,
+    /// The 1-based line number.
+    pub line: usize,
+    /// The number of chars preceding the token in its line.
+    pub col: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}:{}]", self.line, self.col)
+    }
+}
+
+/// Recognizes tokens byte by byte, and tracks their position. The source code doesn't have to be
+/// valid utf-8, columns count utf-8 encoded chars and each byte that isn't part of one as a single
+/// column.
+#[derive(Clone, Copy, Debug)]
+struct Lexer {
+    offset: usize,
+    line: usize,
+    /// The number of chars preceding the current byte in the current line.
+    col: usize,
+    /// The number of continuation bytes expected to follow, to complete the current utf-8 char.
+    continuation_bytes: u8,
+}
+
+impl Lexer {
+    const fn new() -> Self {
+        Self {
+            offset: 0,
+            line: 1,
+            col: 0,
+            continuation_bytes: 0,
+        }
+    }
+
+    #[inline(always)]
+    fn next(&mut self, b: u8) -> Option<(Token, Span)> {
+        let span = Span {
+            offset: self.offset,
+            line: self.line,
+            col: self.col,
+        };
+        self.offset += 1;
+        if self.continuation_bytes > 0 {
+            if b & 0xC0 == 0x80 {
+                self.continuation_bytes -= 1;
+                return None;
+            }
+            // incomplete utf-8 char
+            self.continuation_bytes = 0;
+        }
+
+        let t = match b {
+            b'<' => Token::Shl,
+            b'>' => Token::Shr,
+            b'+' => Token::Inc,
+            b'-' => Token::Dec,
+            b'.' => Token::Output,
+            b',' => Token::Input,
+            b'[' => Token::LSquare,
+            b']' => Token::RSquare,
+            b'\n' => {
+                self.line += 1;
+                self.col = 0;
+                return None;
+            }
+            _ => {
+                self.continuation_bytes = match b {
+                    0xC2..=0xDF => 1,
+                    0xE0..=0xEF => 2,
+                    0xF0..=0xF4 => 3,
+                    _ => 0,
+                };
+                self.col += 1;
+                return None;
+            }
+        };
+        self.col += 1;
+        Some((t, span))
+    }
+}
+
+/// An iterator over the tokens of source code and their spans, see [`tokens`].
+#[derive(Clone, Debug)]
+pub struct Tokens<'a> {
+    bytes: std::slice::Iter<'a, u8>,
+    lexer: Lexer,
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = (Token, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let lexer = &mut self.lexer;
+        self.bytes.by_ref().find_map(|&b| lexer.next(b))
+    }
+}
+
+/// Incrementally tokenizes source code fed in arbitrary chunks, and checks that all brackets are
+/// matched.
+pub struct Tokenizer {
+    lexer: Lexer,
+    par_stack: Vec<Span>,
+    errors: Vec<String>,
+}
+
+impl Tokenizer {
+    pub fn new() -> Self {
+        Self {
+            lexer: Lexer::new(),
+            par_stack: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8], mut f: impl FnMut(Token)) {
+        let mut lexer = self.lexer;
+        for &b in bytes.iter() {
+            let Some((t, span)) = lexer.next(b) else {
+                continue;
+            };
+            match t {
+                Token::LSquare => self.par_stack.push(span),
+                Token::RSquare if self.par_stack.pop().is_none() => {
+                    let error = format!("missing opening bracket for {span}");
+                    self.errors.push(error);
+                }
+                _ => (),
+            }
+            f(t);
+        }
+        self.lexer = lexer;
+    }
+
+    pub fn finish(mut self) -> Result<(), Vec<String>> {
+        for span in self.par_stack.iter() {
+            self.errors
+                .push(format!("missing closing bracket for {span}"));
+        }
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Incrementally combines repeated tokens into single instructions.
+pub struct Combiner {
+    instructions: Vec<Instruction>,
+    num_tokens: usize,
+    /// The last token and the number of times it was repeated.
+    chunk: Option<(Token, usize)>,
+    verbose: u8,
+}
+
+impl Combiner {
+    pub fn new() -> Self {
+        Self {
+            instructions: Vec::new(),
+            num_tokens: 0,
+            chunk: None,
+            verbose: 0,
+        }
+    }
+
+    /// Print statistics about the combined tokens, depending on the verbosity level.
+    pub fn with_verbose(mut self, verbose: u8) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn push(&mut self, token: Token) {
+        self.num_tokens += 1;
+        if let Some((t, len)) = &mut self.chunk {
+            if *t == token && token.is_combinable() {
+                match self.instructions.last_mut() {
+                    // a run of shifts longer than the operand starts a new instruction
+                    Some(Instruction::Shl(n) | Instruction::Shr(n)) => match n.checked_add(1) {
+                        Some(m) => *n = m,
+                        None => {
+                            self.push_instruction(token);
+                            return;
+                        }
+                    },
+                    // registers wrap around, so increments are combined modulo 256
+                    Some(Instruction::Inc(_, n) | Instruction::Dec(_, n)) => *n = n.wrapping_add(1),
+                    _ => unreachable!(),
+                }
+                *len += 1;
+                return;
+            }
+        }
+
+        self.finish_chunk();
+        self.chunk = Some((token, 1));
+        self.push_instruction(token);
+    }
+
+    /// Start a new instruction for the `token`.
+    fn push_instruction(&mut self, token: Token) {
+        let inst = match token {
+            Token::Shl => Instruction::Shl(1),
+            Token::Shr => Instruction::Shr(1),
+            Token::Inc => Instruction::Inc(0, 1),
+            Token::Dec => Instruction::Dec(0, 1),
+            Token::Output => Instruction::Output(0),
+            Token::Input => Instruction::Input(0),
+            Token::LSquare => Instruction::JumpZ(Jump::Unresolved),
+            Token::RSquare => Instruction::JumpNz(Jump::Unresolved),
+        };
+        self.instructions.push(inst);
+    }
+
+    fn finish_chunk(&mut self) {
+        if let Some((_, len)) = self.chunk {
+            if self.verbose >= 3 && len > 1 {
+                println!("combine {len}");
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<Instruction> {
+        self.finish_chunk();
+        if self.verbose >= 1 {
+            println!("============================================================");
+            println!(
+                "tokens before {} after: {} ({:.3}%)",
+                self.num_tokens,
+                self.instructions.len(),
+                100.0 * self.instructions.len() as f32 / self.num_tokens as f32,
+            );
+            println!("============================================================");
+        }
+
+        self.instructions
+    }
+}
+
+impl Default for Combiner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Update the locations of all jumps, after instructions were added or removed.
+pub fn update_jump_indices(instructions: &mut [Instruction]) {
+    let mut jump_stack = Vec::new();
+    for (i, instruction) in instructions.iter_mut().enumerate() {
+        match instruction {
+            Instruction::JumpZ(closing_idx_ref) => jump_stack.push((i, closing_idx_ref)),
+            Instruction::JumpNz(opening_idx_ref) => {
+                let Some((opening_idx, closing_idx_ref)) = jump_stack.pop() else {
+                    unreachable!("mismatched brackets")
+                };
+
+                if !opening_idx_ref.is_redundant() {
+                    *opening_idx_ref = Jump::Location(jump_location(opening_idx + 1));
+                }
+                if !closing_idx_ref.is_redundant() {
+                    *closing_idx_ref = Jump::Location(jump_location(i + 1));
+                }
+            }
+            _ => (),
+        }
+    }
+    if !jump_stack.is_empty() {
+        unreachable!("mismatched brackets")
+    }
+}
+
+/// The location of a jump to the instruction at `idx`, programs with more than
+/// [`MAX_INSTRUCTIONS`] instructions are rejected by [`parse`].
+fn jump_location(idx: usize) -> NonZeroU32 {
+    u32::try_from(idx).ok().and_then(NonZeroU32::new).expect(
+        "jump targets are never the first index and programs are at most MAX_INSTRUCTIONS long",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Passes;
+    use crate::interp::{Interpreter, Program, TokenInterpreter};
+
+    /// The length of the runs, longer than the operands of combined instructions.
+    const RUN_LEN: usize = 1 << 16;
+
+    /// Run the `src` unoptimized and at `-O3`, and compare both to interpreting its tokens.
+    fn check(src: &str) {
+        let tokens = tokenize(src.as_bytes()).unwrap();
+        let mut expected = Vec::new();
+        let expected_status = TokenInterpreter::new(&tokens, &b""[..], &mut expected).run();
+        for config in [
+            Config::default().with_passes(Passes::NONE),
+            Config::default().with_opt_level(3),
+        ] {
+            let instructions = crate::selftest::build(&config, src.as_bytes()).unwrap();
+            let program = Program::new(&config, instructions);
+            let mut output = Vec::new();
+            let mut interpreter =
+                Interpreter::from_config(&config, &program, &b""[..], &mut output);
+            let status = interpreter.run();
+            drop(interpreter);
+            assert_eq!(status, expected_status, "{:?}", config.passes);
+            assert_eq!(output, expected, "{:?}", config.passes);
+        }
+    }
+
+    #[test]
+    fn multi_tape_errors_use_zero_based_columns() {
+        let errors = translate_multi_tape("ä\n {[}]".as_bytes()).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                "switch before the first tape at [2:1]",
+                "loop at [2:2] starts on tape 0 but ends on tape 1 at [2:4]",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_increment_run() {
+        check(&format!("{}.", "+".repeat(RUN_LEN + b'!' as usize)));
+    }
+
+    #[test]
+    fn long_shift_run() {
+        check(&format!("{}+.", ">".repeat(RUN_LEN)));
+        check(&format!("+{}<+.", ">".repeat(RUN_LEN + 1)));
+        check(&format!("{}+.", "<".repeat(RUN_LEN)));
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use crate::backend::RuntimeOptions;
+use crate::cli::Config;
+use crate::{error, optim, x86, Instruction};
+
+/// Compile the programs into a single executable, which runs them one after another, each with a
+/// fresh tape. With `--pipe` the output of each program is the input of the next one.
+pub fn link(config: &Config, paths: &[PathBuf]) -> ExitCode {
+    let mut programs = Vec::with_capacity(paths.len());
+    for path in paths {
+        let Some(instructions) = build(config, path) else {
+            return ExitCode::FAILURE;
+        };
+        programs.push(instructions);
+    }
+
+    let options = RuntimeOptions::new(config);
+    let code = x86::compile_linked(config, &options, &programs, config.pipe);
+    let bin_path = match &config.output {
+        Some(path) => path.clone(),
+        None => paths[0].with_extension("elf"),
+    };
+    let file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .mode(0o755)
+        .open(&bin_path);
+    let mut file = match file {
+        Ok(f) => f,
+        Err(e) => {
+            let bin_path = bin_path.display();
+            error!("failed to create `{bin_path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = file.write_all(&code) {
+        let bin_path = bin_path.display();
+        error!("failed to write `{bin_path}`: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn build(config: &Config, path: &Path) -> Option<Vec<Instruction>> {
+    let mut instructions = match File::open(path).and_then(|f| crate::parse(config, f)) {
+        Ok(Ok(i)) => i,
+        Ok(Err(errors)) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return None;
+        }
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return None;
+        }
+    };
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    Some(instructions)
+}
@@ -0,0 +1,408 @@
+//! Lints reporting suspicious code at its location in the source code, like loops that never
+//! terminate. Each lint has a level, which is changed using `-W <lint>` and `-A <lint>`.
+
+use std::str::FromStr;
+
+use crate::cli::Config;
+use crate::optim::{self, LoopVerdict};
+use crate::{Instruction, Span, Token, NUM_REGISTERS};
+
+/// The maximum number of instructions executed when evaluating a loop at compile time.
+const EVAL_FUEL: u32 = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lint {
+    /// A loop that doesn't change its iteration register, or sets it to a non-zero value.
+    InfiniteLoop,
+    /// The pointer is moved left of the first register.
+    TapeUnderflow,
+    /// A loop that is never entered, since the current register is always zero at its start.
+    UnreachableCode,
+    /// A loop that is always entered and never terminates, since the current register is known
+    /// at its start, and never becomes zero.
+    AlwaysTrueCondition,
+    /// A loop that moves the pointer in each iteration, like the scan loop `[>]`.
+    UnbalancedPointerInLoop,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::InfiniteLoop,
+        Lint::TapeUnderflow,
+        Lint::UnreachableCode,
+        Lint::AlwaysTrueCondition,
+        Lint::UnbalancedPointerInLoop,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::InfiniteLoop => "infinite_loop",
+            Lint::TapeUnderflow => "tape_underflow",
+            Lint::UnreachableCode => "unreachable_code",
+            Lint::AlwaysTrueCondition => "always_true_condition",
+            Lint::UnbalancedPointerInLoop => "unbalanced_pointer_in_loop",
+        }
+    }
+
+    /// Brainfuck code the lint applies to, used to check the lint.
+    pub fn example(self) -> &'static str {
+        match self {
+            Lint::InfiniteLoop => ",[[-]+]",
+            Lint::TapeUnderflow => "><<",
+            Lint::UnreachableCode => "++--[>]",
+            Lint::AlwaysTrueCondition => "+++[>+<--]",
+            Lint::UnbalancedPointerInLoop => "+[>]",
+        }
+    }
+
+    /// The level used unless it's changed on the command line. Unbalanced loops are common
+    /// idioms, so they aren't reported by default.
+    pub fn default_level(self) -> Level {
+        match self {
+            Lint::UnbalancedPointerInLoop => Level::Allow,
+            _ => Level::Warn,
+        }
+    }
+}
+
+impl FromStr for Lint {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Lint::ALL.into_iter().find(|l| l.name() == s).ok_or(())
+    }
+}
+
+impl std::fmt::Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// The lint isn't reported.
+    Allow,
+    /// The lint is reported as a warning.
+    Warn,
+}
+
+/// The levels of all lints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Levels([Level; Lint::ALL.len()]);
+
+impl Default for Levels {
+    fn default() -> Self {
+        Self(Lint::ALL.map(Lint::default_level))
+    }
+}
+
+impl Levels {
+    pub fn get(&self, lint: Lint) -> Level {
+        self.0[lint as usize]
+    }
+
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.0[lint as usize] = level;
+    }
+
+    /// Whether any lint is reported.
+    pub fn any(&self) -> bool {
+        self.0.iter().any(|&l| l != Level::Allow)
+    }
+}
+
+/// A lint reported at a location in the source code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub span: Span,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} (`{}`)", self.span, self.message, self.lint)
+    }
+}
+
+/// A loop whose body is being checked.
+struct LoopState {
+    start: usize,
+    /// The position of the pointer at the start of the loop, if it's known.
+    entry_pos: Option<i64>,
+    /// The distance the pointer moved since the start of the body, unknown after an unbalanced
+    /// inner loop.
+    moved: Option<i64>,
+    /// The tape after the loop, if it was evaluated at compile time.
+    evaluated: Option<Vec<u8>>,
+}
+
+/// Check the brainfuck source code `src` using the lints that aren't allowed, and return the
+/// diagnostics in the order of the source code. Unmatched brackets are ignored.
+///
+/// The tape is known until the program reads input, or a loop that can't be evaluated at compile
+/// time is entered. After that, the position of the pointer is known until the first loop that
+/// moves it, and the current register is known to be zero after a loop. Loops containing letters
+/// are comments, and aren't reported as unreachable.
+pub fn check(levels: &Levels, src: &[u8]) -> Vec<Diagnostic> {
+    let (tokens, spans): (Vec<Token>, Vec<Span>) = crate::tokens(src).unzip();
+    let mut matching = vec![None; tokens.len()];
+    let mut stack = Vec::new();
+    for (i, t) in tokens.iter().enumerate() {
+        match t {
+            Token::LSquare => stack.push(i),
+            Token::RSquare => {
+                if let Some(start) = stack.pop() {
+                    matching[start] = Some(i);
+                    matching[i] = Some(start);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut diagnostics = Vec::new();
+    let mut report = |lint: Lint, span: Span, message: String| {
+        if levels.get(lint) != Level::Allow {
+            diagnostics.push(Diagnostic {
+                lint,
+                span,
+                message,
+            });
+        }
+    };
+    let mut pos = Some(0_i64);
+    let mut tape = Some(vec![0_u8; NUM_REGISTERS]);
+    let mut zero = true;
+    let mut loops: Vec<LoopState> = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let span = spans[i];
+        // the tape is only known while the pointer is
+        let current = match (&mut tape, pos) {
+            (Some(t), Some(p)) => t.get_mut(p as usize),
+            _ => None,
+        };
+        match tokens[i] {
+            Token::Shl | Token::Shr => {
+                let n = if tokens[i] == Token::Shl { -1 } else { 1 };
+                pos = pos.map(|p| p + n);
+                if let Some(l) = loops.last_mut() {
+                    l.moved = l.moved.map(|m| m + n);
+                }
+                if pos == Some(-1) {
+                    let msg = "moves the pointer left of the first register".to_owned();
+                    report(Lint::TapeUnderflow, span, msg);
+                    pos = None;
+                }
+                if pos.is_none_or(|p| p as usize >= NUM_REGISTERS) {
+                    tape = None;
+                }
+                zero = false;
+            }
+            Token::Inc => {
+                if let Some(r) = current {
+                    *r = r.wrapping_add(1);
+                }
+                zero = false;
+            }
+            Token::Dec => {
+                if let Some(r) = current {
+                    *r = r.wrapping_sub(1);
+                }
+                zero = false;
+            }
+            Token::Input => {
+                tape = None;
+                zero = false;
+            }
+            Token::Output => (),
+            Token::LSquare => {
+                let Some(end) = matching[i] else {
+                    i += 1;
+                    continue;
+                };
+                let value = current.map(|r| *r);
+                if zero || value == Some(0) {
+                    let text = &src[span.offset..spans[end].offset];
+                    if !text.iter().any(u8::is_ascii_alphabetic) {
+                        let msg =
+                            "the loop is never entered, the current register is always zero here";
+                        report(Lint::UnreachableCode, span, msg.to_owned());
+                    }
+                    // the register is still zero after skipping the loop
+                    i = end + 1;
+                    continue;
+                }
+                let body = &tokens[i + 1..end];
+                let mut evaluated = None;
+                if let Some(value) = value.filter(|&v| never_terminates(body, v)) {
+                    let msg = format!("the loop is always entered and never terminates, the current register is always {value} here");
+                    report(Lint::AlwaysTrueCondition, span, msg);
+                } else {
+                    if is_infinite(body) {
+                        let msg = "the loop never terminates once it's entered, it doesn't change the current register or sets it to a non-zero value";
+                        report(Lint::InfiniteLoop, span, msg.to_owned());
+                    }
+                    if let (Some(t), Some(p)) = (&tape, pos) {
+                        match evaluate(&tokens, &matching, i, t, p) {
+                            Evaluation::Finished(t) => evaluated = Some(t),
+                            Evaluation::Underflow(k) => {
+                                let msg = "moves the pointer left of the first register when the loop is executed".to_owned();
+                                report(Lint::TapeUnderflow, spans[k], msg);
+                            }
+                            Evaluation::Unknown => (),
+                        }
+                    }
+                }
+                loops.push(LoopState {
+                    start: i,
+                    entry_pos: pos,
+                    moved: Some(0),
+                    evaluated,
+                });
+                // the body is checked without knowing the tape, the loop might be executed
+                // multiple times
+                tape = None;
+                zero = false;
+            }
+            Token::RSquare => {
+                let Some(l) = loops.pop() else {
+                    i += 1;
+                    continue;
+                };
+                match l.moved {
+                    Some(0) => pos = l.entry_pos,
+                    Some(n) => {
+                        let msg = format!("the loop moves the pointer by {n} in each iteration");
+                        report(Lint::UnbalancedPointerInLoop, spans[l.start], msg);
+                        pos = None;
+                    }
+                    None => pos = None,
+                }
+                if let Some(outer) = loops.last_mut() {
+                    if l.moved != Some(0) {
+                        outer.moved = None;
+                    }
+                }
+                tape = l.evaluated;
+                zero = true;
+            }
+        }
+        i += 1;
+    }
+    // unbalanced loops are reported at their end
+    diagnostics.sort_by_key(|d| d.span);
+    // an underflow in the first iteration of an evaluated loop is found twice
+    diagnostics.dedup_by(|a, b| a.lint == b.lint && a.span == b.span);
+    diagnostics
+}
+
+/// The result of executing a loop at compile time.
+enum Evaluation {
+    /// The tape after the loop.
+    Finished(Vec<u8>),
+    /// The token moves the pointer left of the first register.
+    Underflow(usize),
+    /// The loop reads input, moves the pointer beyond the last register, or runs out of fuel.
+    Unknown,
+}
+
+/// Execute the loop starting at the token `start` on a copy of the `tape`, with the pointer at
+/// `pos`. `matching` contains the indices of the matching brackets.
+fn evaluate(
+    tokens: &[Token],
+    matching: &[Option<usize>],
+    start: usize,
+    tape: &[u8],
+    pos: i64,
+) -> Evaluation {
+    let Some(end) = matching[start] else {
+        return Evaluation::Unknown;
+    };
+    let mut tape = tape.to_vec();
+    let mut pos = pos as usize;
+    let mut ip = start;
+    for _ in 0..EVAL_FUEL {
+        if ip > end {
+            return Evaluation::Finished(tape);
+        }
+        match tokens[ip] {
+            Token::Shl => match pos.checked_sub(1) {
+                Some(p) => pos = p,
+                None => return Evaluation::Underflow(ip),
+            },
+            Token::Shr if pos + 1 < NUM_REGISTERS => pos += 1,
+            Token::Shr => return Evaluation::Unknown,
+            Token::Inc => tape[pos] = tape[pos].wrapping_add(1),
+            Token::Dec => tape[pos] = tape[pos].wrapping_sub(1),
+            Token::Output => (),
+            Token::Input => return Evaluation::Unknown,
+            // the brackets inside of the loop are matched
+            Token::LSquare if tape[pos] == 0 => {
+                ip = matching[ip].expect("the brackets are matched")
+            }
+            Token::RSquare if tape[pos] != 0 => {
+                ip = matching[ip].expect("the brackets are matched")
+            }
+            Token::LSquare | Token::RSquare => (),
+        }
+        ip += 1;
+    }
+    Evaluation::Unknown
+}
+
+/// Check that the `lint` reports its example, and that it's the only lint doing so.
+pub fn check_example(lint: Lint) -> Result<(), String> {
+    let mut levels = Levels::default();
+    for l in Lint::ALL {
+        levels.set(l, Level::Warn);
+    }
+    let lints = (check(&levels, lint.example().as_bytes()).into_iter())
+        .map(|d| d.lint)
+        .collect::<Vec<_>>();
+    match lints[..] {
+        [l] if l == lint => Ok(()),
+        _ => Err(format!("the example is reported as {lints:?}")),
+    }
+}
+
+/// Whether the loop with the `body` never terminates once it's entered, loops containing other
+/// loops than `[-]` aren't checked.
+fn is_infinite(body: &[Token]) -> bool {
+    verdict(body) == Some(LoopVerdict::Infinite)
+}
+
+/// Whether the loop with the `body` never terminates when it's entered with the current register
+/// set to `value`. A loop changing the register by `d` in each iteration only reaches zero if the
+/// value is a multiple of the greatest common divisor of `d` and 256.
+fn never_terminates(body: &[Token], value: u8) -> bool {
+    match verdict(body) {
+        Some(LoopVerdict::Infinite) => true,
+        Some(LoopVerdict::Step(d)) => {
+            let gcd = 1_u8 << (d as u8).trailing_zeros();
+            !value.is_multiple_of(gcd)
+        }
+        _ => false,
+    }
+}
+
+/// The verdict of the `arithmetic` pass on the loop with the `body`, or `None` if it contains
+/// other loops than `[-]`.
+fn verdict(body: &[Token]) -> Option<LoopVerdict> {
+    let mut instructions = crate::combine(body.iter().copied());
+    optim::replace_zeros(&Config::default(), &mut instructions);
+    let nested = (instructions.iter()).any(|i| matches!(i, Instruction::JumpZ(_)));
+    (!nested).then(|| optim::arithmetic_loop_verdict(&instructions))
+}
+
+/// Check the preprocessed source code `src` using the lints configured in the `config`. Only
+/// brainfuck source code is checked, since the locations in translated code don't correspond to
+/// the source code.
+pub fn check_source(config: &Config, src: &[u8]) -> Vec<Diagnostic> {
+    if !config.is_brainfuck() || !config.lints.any() {
+        return Vec::new();
+    }
+    check(&config.lints, src)
+}
@@ -1,411 +1,851 @@
-use std::cmp::PartialOrd;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
-use std::num::NonZeroU32;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Cursor, IsTerminal, Read, Write};
 use std::ops::ControlFlow;
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use brainfuck::backend::{Artifact, Checkpoint, RuntimeOptions};
+use brainfuck::cli::{self, Command, Config, Emit, ANSII_CLEAR_SCREEN};
+use brainfuck::coredump::Core;
+use brainfuck::encoding::{Decoder, InputEncoding};
+use brainfuck::expect::{self, Recorder};
+use brainfuck::interp::{Interpreter, Program, Status, TokenInterpreter};
+use brainfuck::{
+    bench, bundle, cache, cover, diff, digest, explain, fuzz, link, lint, optim, parallel,
+    pipeline, pragma, project, reduce, selftest, serve, symex, termination, test, tty, viz,
+};
+use brainfuck::{collect_programs, coredump, error, warn, Instruction, NUM_REGISTERS};
+use brainfuck::{parse, preprocess, tokenize, tokenize_tolerant, update_jump_indices};
 
-use crate::cli::Command;
+fn main() -> ExitCode {
+    if let Some(payload) = bundle::embedded() {
+        return bundle::run(&payload);
+    }
 
-pub mod cli;
-pub mod optim;
-pub mod x86;
+    let (config, command, paths) = match cli::parse_args() {
+        ControlFlow::Continue(c) => c,
+        ControlFlow::Break(e) => return e,
+    };
 
-const NUM_REGISTERS: usize = 1 << 15;
+    if command == Command::Serve {
+        return serve::serve(&config);
+    }
+    if command == Command::Selftest {
+        return selftest::selftest(&config);
+    }
+    if command == Command::Diff {
+        return diff::diff(&config, &paths[0], &paths[1]);
+    }
+    if command == Command::Test {
+        return test::test(&config, paths);
+    }
 
-#[macro_export]
-macro_rules! warn {
-    ($pat:expr) => {{
-        use crate::cli::{ANSII_CLEAR, ANSII_COLOR_YELLOW};
-        eprint!("{ANSII_COLOR_YELLOW}warning{ANSII_CLEAR}: ");
-        eprintln!($pat);
-    }};
-}
+    // the modes processing a single program use the semantics set by its pragmas
+    let config = match command {
+        Command::Reduce
+        | Command::Cover
+        | Command::Explain
+        | Command::Bundle
+        | Command::BenchOpts
+        | Command::Symex
+        | Command::Fuzz
+        | Command::Debug => match with_pragmas(&config, &paths[0]) {
+            Some(config) => config,
+            None => return ExitCode::FAILURE,
+        },
+        _ => config,
+    };
+    if command == Command::Reduce {
+        return reduce::reduce(&config, &paths[0]);
+    }
+    if command == Command::Link {
+        return link::link(&config, &paths);
+    }
+    if command == Command::Pipe {
+        return pipeline::pipeline(&config, &paths);
+    }
+    if command == Command::Cover {
+        return cover::cover(&config, &paths[0]);
+    }
+    if command == Command::Explain {
+        let at = config.at.expect("checked by parse_args");
+        return explain::explain(&config, &paths[0], at);
+    }
+    if command == Command::Bundle {
+        return bundle::bundle(&config, &paths[0]);
+    }
+    if command == Command::New {
+        return project::new(&paths[0]);
+    }
+    if command == Command::Init {
+        return project::init(paths.first().map_or(Path::new("."), |p| p));
+    }
+    if command == Command::BenchOpts {
+        return bench::bench_opts(&config, &paths[0]);
+    }
+    if command == Command::Symex {
+        return symex::symex(&config, &paths[0]);
+    }
+    if command == Command::Fuzz {
+        return fuzz::fuzz(&config, &paths[0]);
+    }
+    if command == Command::Viz {
+        return viz::viz(&config, &paths[0]);
+    }
+    if command == Command::Debug {
+        let core = config.core.as_deref().expect("checked by parse_args");
+        return coredump::debug(&config, core, &paths[0]);
+    }
 
-#[macro_export]
-macro_rules! error {
-    ($pat:expr) => {{
-        use crate::cli::{ANSII_CLEAR, ANSII_COLOR_RED};
-        eprint!("{ANSII_COLOR_RED}error{ANSII_CLEAR}: ");
-        eprintln!($pat);
-    }};
+    // restored when the programs finished
+    let _raw_mode = (config.interactive && command == Command::Run).then(tty::RawMode::enable);
+    if config.watch {
+        watch(&config, command, &paths);
+    }
+    process_programs(&config, command, paths)
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Token {
-    Shl,
-    Shr,
-    Inc,
-    Dec,
-    Output,
-    Input,
-    LSquare,
-    RSquare,
+/// The input of programs run by the `run` mode, decoded as configured by `--input-encoding`.
+/// Programs run in parallel each read all of the input, see [`parallel::input`].
+fn stdin(config: &Config) -> Box<dyn BufRead> {
+    let stdin: Box<dyn BufRead> = match (parallel::input(), config.limits().time) {
+        (Some(input), _) => Box::new(Cursor::new(input)),
+        (None, Some(max)) => Box::new(BufReader::new(tty::TimedStdin::new(Instant::now() + max))),
+        (None, None) => Box::new(std::io::stdin().lock()),
+    };
+    match config.input_encoding {
+        InputEncoding::Bytes => stdin,
+        encoding => Box::new(BufReader::new(Decoder::new(encoding, stdin))),
+    }
 }
 
-impl std::fmt::Display for Token {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Token::Shl => write!(f, "<"),
-            Token::Shr => write!(f, ">"),
-            Token::Inc => write!(f, "+"),
-            Token::Dec => write!(f, "-"),
-            Token::Output => write!(f, "."),
-            Token::Input => write!(f, ","),
-            Token::LSquare => write!(f, "["),
-            Token::RSquare => write!(f, "]"),
-        }
+/// The output of programs run by the `run` mode, which isn't buffered in the `--interactive` mode
+/// and by `meta run`, and is sanitized by `--sanitize-output`. It's recorded into `recorded` if
+/// `--expect-output` is passed. The output of programs run in parallel is captured.
+fn stdout<'a>(config: &Config, recorded: &'a mut Vec<u8>) -> Box<dyn Write + 'a> {
+    let stdout: Box<dyn Write> = match parallel::capturing() {
+        true => Box::new(parallel::Stdout),
+        false => Box::new(std::io::stdout().lock()),
+    };
+    let stdout: Box<dyn Write> = match config.interactive || config.unbuffered {
+        true => Box::new(tty::Unbuffered::new(stdout)),
+        false => Box::new(stdout),
+    };
+    let stdout: Box<dyn Write> = match config.sanitize_output {
+        true => Box::new(tty::Sanitized::new(stdout)),
+        false => stdout,
+    };
+    match config.expect_output.is_some() {
+        true => Box::new(Recorder::new(stdout, recorded)),
+        false => stdout,
     }
 }
 
-impl Token {
-    pub fn is_combinable(self) -> bool {
-        match self {
-            Token::Shl | Token::Shr | Token::Inc | Token::Dec => true,
-            Token::Output | Token::Input | Token::LSquare | Token::RSquare => false,
-        }
+/// The exit code of the `run` mode, the `exit` code of the program, or whether it met the
+/// expectations of `--expect-output` and `--expect-exit`.
+fn run_exit_code(
+    config: &Config,
+    expected_output: Option<&[u8]>,
+    output: &[u8],
+    exit: u8,
+) -> ExitCode {
+    if config.expect_output.is_none() && config.expect_exit.is_none() {
+        return ExitCode::from(exit);
+    }
+    match expect::check(config, expected_output, output, exit) {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Instruction {
-    Shl(u16),
-    Shr(u16),
-    Inc(i16, u8),
-    Dec(i16, u8),
-    Output,
-    Input,
-    /// Jump to the position if the current register value is zero.
-    JumpZ(Jump),
-    /// Jump to the position if the current register value is not zero.
-    JumpNz(Jump),
-
-    /// Clear the current register:
-    /// ```bf
-    /// [
-    ///     -
-    /// ]
-    /// ```
-    Zero(i16),
-    Set(i16, u8),
-    /// Add current register value to register at offset.
-    Add(i16),
-    /// Subtract current register value from register at offset.
-    Sub(i16),
-    /// Multiply current register value and add to register at offset.
-    AddMul(i16, u8),
-    /// Multiply current register value and subtraction from register at offset.
-    SubMul(i16, u8),
+/// Write the executable to `bin_path`, creating its directory if needed. The executable is written
+/// to a temporary file first and renamed, so a running executable can be replaced.
+fn install(bin_path: &Path, bin: &[u8]) -> ExitCode {
+    let dir = bin_path.parent().unwrap_or(Path::new("."));
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        let dir = dir.display();
+        error!("failed to create `{dir}`: {e}");
+        return ExitCode::FAILURE;
+    }
+    let mut tmp_name = bin_path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = bin_path.with_file_name(tmp_name);
+    let file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .mode(0o755)
+        .open(&tmp_path);
+    let res = file
+        .and_then(|mut f| f.write_all(bin))
+        .and_then(|_| std::fs::rename(&tmp_path, bin_path));
+    if let Err(e) = res {
+        _ = std::fs::remove_file(&tmp_path);
+        let bin_path = bin_path.display();
+        error!("failed to install `{bin_path}`: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    println!("installed `{}`", bin_path.display());
+    let in_path = std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|p| p == dir));
+    if !in_path {
+        let dir = dir.display();
+        warn!("`{dir}` isn't in `PATH`");
+    }
+    ExitCode::SUCCESS
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Jump {
-    Location(NonZeroU32),
-    Redundant,
+/// Re-run the command whenever one of the programs is modified, until the process is terminated.
+fn watch(config: &Config, command: Command, paths: &[PathBuf]) -> ! {
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+    let mut prev_modified = None;
+    loop {
+        let modified = match collect_programs(paths.to_vec()) {
+            Ok(programs) => programs
+                .iter()
+                .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        if prev_modified.as_ref() != Some(&modified) {
+            print!("{ANSII_CLEAR_SCREEN}");
+            _ = process_programs(config, command, paths.to_vec());
+            _ = std::io::stdout().flush();
+            prev_modified = Some(modified);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
 }
 
-impl Jump {
-    pub fn is_redundant(&self) -> bool {
-        matches!(self, Self::Redundant)
+fn process_programs(config: &Config, command: Command, paths: Vec<PathBuf>) -> ExitCode {
+    let paths = match collect_programs(paths) {
+        Ok(p) => p,
+        Err((path, e)) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let [path] = &paths[..] {
+        return process_program(config, command, path);
     }
+    let jobs = config.jobs.unwrap_or_else(parallel::default_jobs);
+    if jobs > 1 && runs_in_parallel(config, command) {
+        return process_programs_parallel(config, command, &paths, jobs);
+    }
+
+    // json is printed as one object per line, which already contains the path
+    if config.json {
+        let failed = (paths.iter())
+            .filter(|path| process_program(config, command, path) != ExitCode::SUCCESS);
+        return match failed.count() {
+            0 => ExitCode::SUCCESS,
+            _ => ExitCode::FAILURE,
+        };
+    }
+
+    let mut failed = Vec::new();
+    for path in paths.iter() {
+        println!("==> {} <==", path.display());
+        let exit_code = process_program(config, command, path);
+        _ = std::io::stdout().flush();
+        if exit_code != ExitCode::SUCCESS {
+            failed.push(path);
+        }
+        println!();
+    }
+    print_summary(&paths, &failed)
 }
 
-impl std::fmt::Display for Instruction {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Instruction::Shl(n) => write!(f, "< ({n})"),
-            Instruction::Shr(n) => write!(f, "> ({n})"),
-            Instruction::Inc(0, n) => write!(f, "+ ({n})"),
-            Instruction::Inc(o, n) => write!(f, "<{o}> + ({n})"),
-            Instruction::Dec(0, n) => write!(f, "- ({n})"),
-            Instruction::Dec(o, n) => write!(f, "<{o}> - ({n})"),
-            Instruction::Output => write!(f, "out"),
-            Instruction::Input => write!(f, "in"),
-            Instruction::JumpZ(Jump::Redundant) => write!(f, "[ !"),
-            Instruction::JumpZ(Jump::Location(_)) => write!(f, "["),
-            Instruction::JumpNz(Jump::Redundant) => write!(f, "] !"),
-            Instruction::JumpNz(Jump::Location(_)) => write!(f, "]"),
-
-            Instruction::Zero(0) => write!(f, "zero"),
-            Instruction::Zero(o) => write!(f, "<{o}> zero"),
-            Instruction::Set(0, n) => write!(f, "set {n}"),
-            Instruction::Set(o, n) => write!(f, "<{o}> set {n}"),
-            Instruction::Add(o) => write!(f, "<{o}> add"),
-            Instruction::Sub(o) => write!(f, "<{o}> sub"),
-            Instruction::AddMul(o, n) => write!(f, "<{o}> addmul({n})"),
-            Instruction::SubMul(o, n) => write!(f, "<{o}> submul({n})"),
+/// Whether the programs can be processed in parallel, which captures their output. The reports
+/// of the verbose output and of the options printing statistics aren't captured, and programs
+/// reading from a terminal have to run one after another.
+fn runs_in_parallel(config: &Config, command: Command) -> bool {
+    let reports = config.verbose > 0 || config.print_unoptimized_ir;
+    match command {
+        Command::Check => !reports,
+        Command::Run => {
+            let statistics = config.count_cycles || config.mem_stats || config.trace_hash;
+            let files = config.dump_core || config.tape_log.is_some();
+            let terminal = config.interactive || std::io::stdin().is_terminal();
+            !(reports || statistics || files || terminal)
         }
+        _ => false,
     }
 }
 
-fn main() -> ExitCode {
-    let (config, command, path) = match cli::parse_args() {
-        ControlFlow::Continue(c) => c,
-        ControlFlow::Break(e) => return e,
+/// Process the programs on `jobs` threads like [`process_programs`], and write the output of each
+/// program once it and all previous programs are done. Each program reads all of the input.
+fn process_programs_parallel(
+    config: &Config,
+    command: Command,
+    paths: &[PathBuf],
+    jobs: usize,
+) -> ExitCode {
+    let mut input = Vec::new();
+    if command == Command::Run {
+        if let Err(e) = std::io::stdin().lock().read_to_end(&mut input) {
+            error!("failed to read the input: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let input = Arc::<[u8]>::from(input);
+
+    let mut failed = Vec::new();
+    let process = |path: &PathBuf| {
+        parallel::capture(input.clone(), || process_program(config, command, path))
     };
+    parallel::map_ordered(paths, jobs, process, |path, (exit_code, output)| {
+        // json is printed as one object per line, which already contains the path
+        if !config.json {
+            println!("==> {} <==", path.display());
+        }
+        output.replay();
+        if exit_code != ExitCode::SUCCESS {
+            failed.push(path);
+        }
+        if !config.json {
+            println!();
+        }
+    });
 
-    let input = std::fs::read_to_string(&path).unwrap();
-    let bytes = input.as_bytes();
-
-    let mut line = 1;
-    let mut line_start = 0;
-    let mut par_stack = Vec::new();
-    let mut tokens = Vec::new();
-    let mut mismatched = false;
-    for (i, b) in bytes.iter().enumerate() {
-        let t = match *b {
-            b'<' => Token::Shl,
-            b'>' => Token::Shr,
-            b'+' => Token::Inc,
-            b'-' => Token::Dec,
-            b'.' => Token::Output,
-            b',' => Token::Input,
-            b'[' => {
-                let col = input[line_start..i].chars().count();
-                par_stack.push((line, col));
-                Token::LSquare
-            }
-            b']' => {
-                if par_stack.pop().is_none() {
-                    let col = input[line_start..i].chars().count();
-                    error!("missing opening bracket for [{line}:{col}]");
-                    mismatched = true;
-                }
-                Token::RSquare
-            }
-            b'\n' => {
-                line += 1;
-                line_start = i + 1;
-                continue;
-            }
-            _ => continue,
+    if config.json {
+        return match failed.is_empty() {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
         };
-        tokens.push(t);
     }
-    for &(line, col) in par_stack.iter() {
-        error!("missing closing bracket for [{line}:{col}]");
-        mismatched = true;
+    print_summary(paths, &failed)
+}
+
+/// Print the number of programs that succeeded and failed, and the failed programs.
+fn print_summary(paths: &[PathBuf], failed: &[&PathBuf]) -> ExitCode {
+    let num_succeeded = paths.len() - failed.len();
+    println!("{num_succeeded} succeeded, {} failed", failed.len());
+    for path in failed.iter() {
+        println!("    {}", path.display());
     }
-    if mismatched {
-        return ExitCode::FAILURE;
+    if failed.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
     }
+}
 
-    // combine instructions
-    let mut instructions = tokens
-        .chunk_by(|a, b| a.is_combinable() && a == b)
-        .inspect(|c| {
-            if config.verbose >= 3 && c.len() > 1 {
-                println!("combine {}", c.len());
-            }
-        })
-        .map(|chunk| match chunk[0] {
-            Token::Shl => Instruction::Shl(chunk.len() as u16),
-            Token::Shr => Instruction::Shr(chunk.len() as u16),
-            Token::Inc => Instruction::Inc(0, chunk.len() as u8),
-            Token::Dec => Instruction::Dec(0, chunk.len() as u8),
-            Token::Output => Instruction::Output,
-            Token::Input => Instruction::Input,
-            Token::LSquare => Instruction::JumpZ(Jump::Location(NonZeroU32::MAX)),
-            Token::RSquare => Instruction::JumpNz(Jump::Location(NonZeroU32::MAX)),
-        })
-        .collect::<Vec<_>>();
-    if config.verbose >= 1 {
-        println!("============================================================");
-        println!(
-            "tokens before {} after: {} ({:.3}%)",
-            tokens.len(),
-            instructions.len(),
-            100.0 * instructions.len() as f32 / tokens.len() as f32,
-        );
-        println!("============================================================");
+/// Report the register accesses of the program that aren't portable, see `--strict`.
+fn check_portable(instructions: &[Instruction]) -> bool {
+    let errors = optim::portability_errors(instructions);
+    for e in errors.iter() {
+        error!("{e}");
     }
-    if config.verbose >= 3 || command == Command::Format {
-        cli::print_brainfuck_code(&instructions);
-        if command == Command::Format {
-            return ExitCode::SUCCESS;
+    errors.is_empty()
+}
+
+/// Check the program at `path`, and print the errors or the number of instructions before and
+/// after optimizing it, and the time it took, as a json object.
+fn check_json(config: &Config, path: &Path) -> ExitCode {
+    let start = Instant::now();
+    let (parsed, source) = match Source::read(config, path) {
+        Ok(source) => (parse(config, &source.code[..]), Some(source)),
+        Err(e) => (Err(e), None),
+    };
+    let parsed = match parsed {
+        Ok(Ok(i)) => Ok(i),
+        Ok(Err(errors)) => Err(errors.iter().map(ToString::to_string).collect()),
+        Err(e) => Err(vec![format!("failed to read `{}`: {e}", path.display())]),
+    };
+    let parse_time = start.elapsed();
+
+    let mut json = String::from("{\"path\":");
+    cli::write_json_string(&mut json, &path.display().to_string());
+    let write_errors = |json: &mut String, errors: &[String]| {
+        let status = if errors.is_empty() { "ok" } else { "error" };
+        _ = write!(json, ",\"status\":\"{status}\",\"errors\":[");
+        for (i, e) in errors.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            cli::write_json_string(json, e);
         }
-        if config.verbose >= 1 {
-            println!("============================================================");
+        json.push(']');
+    };
+    let (mut instructions, source) = match (parsed, source) {
+        (Ok(instructions), Some(source)) => (instructions, source),
+        (Ok(_), None) => unreachable!("the program was parsed from its source code"),
+        (Err(errors), _) => {
+            write_errors(&mut json, &errors);
+            json.push('}');
+            parallel::print(format_args!("{json}\n"));
+            return ExitCode::FAILURE;
         }
-    }
+    };
 
-    if config.optimize {
-        if config.print_unoptimized_ir {
-            cli::print_instructions(&instructions);
-            println!("============================================================");
+    let unoptimized = instructions.len();
+    let start = Instant::now();
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    let optimize_time = start.elapsed();
+    let errors = match config.strict {
+        true => optim::portability_errors(&instructions),
+        false => Vec::new(),
+    };
+    write_errors(&mut json, &errors);
+    json.push_str(",\"warnings\":[");
+    for (i, d) in source.diagnostics(config).iter().enumerate() {
+        if i > 0 {
+            json.push(',');
         }
+        let (lint, line, col) = (d.lint, d.span.line, d.span.col);
+        _ = write!(
+            json,
+            "{{\"lint\":\"{lint}\",\"line\":{line},\"col\":{col},\"message\":"
+        );
+        cli::write_json_string(&mut json, &d.message);
+        json.push('}');
+    }
+    json.push(']');
+    if config.termination {
+        json.push_str(",\"termination\":[");
+        for (i, r) in source.termination().iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let (line, col, termination) = (r.span.line, r.span.col, r.termination);
+            _ = write!(
+                json,
+                "{{\"line\":{line},\"col\":{col},\"termination\":\"{termination}\",\"reason\":"
+            );
+            cli::write_json_string(&mut json, &r.reason);
+            json.push('}');
+        }
+        json.push(']');
+    }
+    _ = write!(
+        json,
+        ",\"instructions\":{{\"unoptimized\":{unoptimized},\"optimized\":{}}},\"time\":{{\"parse\":{:.6},\"optimize\":{:.6}}}}}",
+        instructions.len(),
+        parse_time.as_secs_f64(),
+        optimize_time.as_secs_f64(),
+    );
+    parallel::print(format_args!("{json}\n"));
+    match errors.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
 
-        let prev_len = instructions.len();
+/// The source code of a program in the `check` mode, which is read once for the parser, the lints
+/// and the termination analysis.
+struct Source {
+    code: Vec<u8>,
+    /// The preprocessed source code, errors preprocessing it are left to the parser.
+    preprocessed: Option<Vec<u8>>,
+}
 
-        // zero register
-        if config.o_zeros {
-            optim::replace_zeros(&config, &mut instructions);
-        }
+impl Source {
+    fn read(config: &Config, path: &Path) -> std::io::Result<Self> {
+        let code = std::fs::read(path)?;
+        let preprocessed = preprocess(config, code.clone()).ok();
+        Ok(Self { code, preprocessed })
+    }
 
-        if config.o_dead_code || config.o_init || config.o_jumps {
-            optim::optimize_static_code(&config, &mut instructions);
+    fn diagnostics(&self, config: &Config) -> Vec<lint::Diagnostic> {
+        match &self.preprocessed {
+            Some(src) => lint::check_source(config, src),
+            None => Vec::new(),
         }
+    }
 
-        if config.o_jumps {
-            optim::remove_redundant_jump_pairs(&config, &mut instructions);
+    fn termination(&self) -> Vec<termination::Report> {
+        match &self.preprocessed {
+            Some(src) => termination::analyze(src),
+            None => Vec::new(),
         }
+    }
+}
 
-        if config.o_arithmetic || config.o_jumps {
-            let mut i = 0;
-            while i < instructions.len() {
-                optim::arithmetic_loop_pass(&config, &mut instructions, i);
-                i += 1;
+/// Apply the pragmas of the program at `path` to the `config`, and report invalid ones.
+fn with_pragmas(config: &Config, path: &Path) -> Option<Config> {
+    match pragma::apply_file(config, path) {
+        Ok(config) => Some(config),
+        Err(errors) => {
+            for e in errors {
+                error!("{e}");
             }
+            None
         }
+    }
+}
 
-        if config.o_simplify {
-            optim::simplify_code(&config, &mut instructions);
+fn process_program(config: &Config, command: Command, path: &Path) -> ExitCode {
+    let Some(config) = &with_pragmas(config, path) else {
+        return ExitCode::FAILURE;
+    };
+    let cache_key = if config.cache && matches!(command, Command::Run | Command::Compile) {
+        match File::open(path).and_then(|f| cache::Key::new(config, f)) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                let path = path.display();
+                error!("failed to read `{path}`: {e}");
+                return ExitCode::FAILURE;
+            }
         }
+    } else {
+        None
+    };
+    if command == Command::Check && config.json {
+        return check_json(config, path);
+    }
+    let source = match command {
+        Command::Check => match Source::read(config, path) {
+            Ok(source) => Some(source),
+            Err(e) => {
+                let path = path.display();
+                error!("failed to read `{path}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => None,
+    };
+    // reported before the errors of the parser
+    if let Some(source) = &source {
+        for d in source.diagnostics(config) {
+            warn!("{d}");
+        }
+    }
+    if command == Command::Translate {
+        let src = match std::fs::read(path) {
+            Ok(src) => src,
+            Err(e) => {
+                let path = path.display();
+                error!("failed to read `{path}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        match preprocess(config, src) {
+            Ok(code) => _ = std::io::stdout().write_all(&code),
+            Err(errors) => {
+                for e in errors {
+                    error!("{e}");
+                }
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
 
-        if config.o_dead_code || config.o_init || config.o_jumps {
-            optim::optimize_static_code(&config, &mut instructions);
+    if command == Command::Format && config.keep_comments {
+        let src = match std::fs::read(path) {
+            Ok(src) => src,
+            Err(e) => {
+                let path = path.display();
+                error!("failed to read `{path}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if let Err(errors) = tokenize(&src) {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
         }
+        print!("{}", cli::format_brainfuck_code_with_comments(&src));
+        return ExitCode::SUCCESS;
+    }
 
-        if config.o_jumps {
-            optim::remove_redundant_jump_pairs(&config, &mut instructions);
+    let expected_output = match config.expect_output.as_deref() {
+        Some(arg) if command == Command::Run => match expect::load_output(arg) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                let arg = arg.display();
+                error!("failed to read `{arg}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => None,
+    };
+    let mut recorded = Vec::new();
+
+    if command == Command::Run && config.no_ir {
+        let src = match std::fs::read(path) {
+            Ok(src) => src,
+            Err(e) => {
+                let path = path.display();
+                error!("failed to read `{path}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let tokens = preprocess(config, src).and_then(|src| {
+            if config.recover {
+                let (tokens, diagnostics) = tokenize_tolerant(&src);
+                for d in diagnostics {
+                    warn!("{d}");
+                }
+                Ok(tokens)
+            } else {
+                tokenize(&src)
+            }
+        });
+        let tokens = match tokens {
+            Ok(t) => t,
+            Err(errors) => {
+                for e in errors {
+                    error!("{e}");
+                }
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut interpreter =
+            TokenInterpreter::new(&tokens, stdin(config), stdout(config, &mut recorded))
+                .with_limits(config.limits())
+                .with_eof(config.eof);
+        let status = interpreter.run();
+        drop(interpreter);
+        if let Status::Exceeded(limit) = status {
+            error!("{limit}");
         }
+        let exit = matches!(status, Status::Exceeded(_)) as u8;
+        return run_exit_code(config, expected_output.as_deref(), &recorded, exit);
+    }
 
+    let cached = cache_key.as_ref().and_then(cache::load);
+    let instructions = if let Some(mut instructions) = cached {
         if config.verbose >= 1 {
-            if config.verbose >= 2 {
+            println!("loaded ir from cache");
+        }
+        update_jump_indices(&mut instructions);
+        optim::thread_jumps(config, &mut instructions);
+        if config.strict && !check_portable(&instructions) {
+            return ExitCode::FAILURE;
+        }
+        instructions
+    } else {
+        let parsed = match &source {
+            Some(source) => parse(config, &source.code[..]),
+            None => File::open(path).and_then(|f| parse(config, f)),
+        };
+        let mut instructions = match parsed {
+            Ok(Ok(i)) => i,
+            Ok(Err(errors)) => {
+                for e in errors {
+                    error!("{e}");
+                }
+                return ExitCode::FAILURE;
+            }
+            Err(e) => {
+                let path = path.display();
+                error!("failed to read `{path}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if config.verbose >= 3 || command == Command::Format {
+            cli::print_brainfuck_code(&instructions);
+            if command == Command::Format {
+                return ExitCode::SUCCESS;
+            }
+            if config.verbose >= 1 {
                 println!("============================================================");
             }
-            println!(
-                "instructions before {} after: {} ({:.3}%)",
-                prev_len,
-                instructions.len(),
-                100.0 * instructions.len() as f32 / prev_len as f32,
-            );
-            println!("============================================================");
         }
-    }
 
-    // update jump indices
-    let mut jump_stack = Vec::new();
-    for (i, instruction) in instructions.iter_mut().enumerate() {
-        match instruction {
-            Instruction::JumpZ(closing_idx_ref) => jump_stack.push((i, closing_idx_ref)),
-            Instruction::JumpNz(opening_idx_ref) => {
-                let Some((opening_idx, closing_idx_ref)) = jump_stack.pop() else {
-                    unreachable!("mismatched brackets")
-                };
+        if config.optimize() {
+            if config.print_unoptimized_ir {
+                cli::print_instructions(&instructions);
+                println!("============================================================");
+            }
+
+            optim::optimize(config, &mut instructions);
+        }
 
-                if let Jump::Location(loc) = opening_idx_ref {
-                    *loc = unsafe { NonZeroU32::new_unchecked(opening_idx as u32 + 1) };
-                }
-                if let Jump::Location(loc) = closing_idx_ref {
-                    *loc = unsafe { NonZeroU32::new_unchecked(i as u32 + 1) };
+        update_jump_indices(&mut instructions);
+        optim::thread_jumps(config, &mut instructions);
+        debug_assert_eq!(optim::verify_jumps(&instructions), Ok(()));
+        if config.strict && !check_portable(&instructions) {
+            return ExitCode::FAILURE;
+        }
+        if let Some(source) = &source {
+            if config.termination {
+                for r in source.termination() {
+                    parallel::print(format_args!("{r}\n"));
                 }
             }
-            _ => (),
+            return ExitCode::SUCCESS;
         }
-    }
-    if !jump_stack.is_empty() {
-        unreachable!("mismatched brackets")
-    }
 
-    if config.verbose >= 3 || command == Command::Ir {
-        cli::print_instructions(&instructions);
-        if command == Command::Ir {
-            return ExitCode::SUCCESS;
-        } else {
-            println!("============================================================");
+        if config.verbose >= 3 || command == Command::Ir {
+            cli::print_instructions(&instructions);
+            if command == Command::Ir {
+                return ExitCode::SUCCESS;
+            } else {
+                println!("============================================================");
+            }
         }
-    }
+
+        if let Some(key) = &cache_key {
+            cache::store(key, &instructions);
+        }
+        instructions
+    };
 
     match command {
         Command::Format => unreachable!(),
         Command::Ir => unreachable!(),
-        Command::Run => run(&instructions),
-        Command::Compile => {
-            let code = x86::compile(&config, &instructions);
-            let path: &Path = path.as_ref();
-            let bin_path = path.with_extension("elf");
-            let mut file = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .mode(0o755)
-                .open(bin_path)
-                .unwrap();
-            file.write_all(&code).unwrap();
+        Command::Check => unreachable!(),
+        Command::Serve => unreachable!(),
+        Command::Selftest => unreachable!(),
+        Command::Diff => unreachable!(),
+        Command::Reduce => unreachable!(),
+        Command::Link => unreachable!(),
+        Command::Install => {
+            let options = RuntimeOptions::new(config);
+            let backend = config.target.backend().expect("checked by parse_args");
+            let bin = backend.compile(config, &instructions, &options);
+            let bin_path = config.output.as_deref().expect("set by parse_args");
+            return install(bin_path, bin.bytes());
         }
-    }
-
-    ExitCode::SUCCESS
-}
-
-fn run(instructions: &[Instruction]) {
-    let mut ip = 0;
-    let mut rp: i16 = 0;
-    let mut registers = [0u8; NUM_REGISTERS];
-    loop {
-        let Some(inst) = instructions.get(ip) else {
-            break;
-        };
-
-        match *inst {
-            Instruction::Shl(n) => rp -= n as i16,
-            Instruction::Shr(n) => rp += n as i16,
-            Instruction::Inc(o, n) => {
-                let r = &mut registers[(rp + o) as usize];
-                *r = r.wrapping_add(n);
+        Command::Pipe => unreachable!(),
+        Command::Cover => unreachable!(),
+        Command::Debug => unreachable!(),
+        Command::Explain => unreachable!(),
+        Command::Translate => unreachable!(),
+        Command::Bundle => unreachable!(),
+        Command::BenchOpts => unreachable!(),
+        Command::New => unreachable!(),
+        Command::Init => unreachable!(),
+        Command::Viz => unreachable!(),
+        Command::Symex => unreachable!(),
+        Command::Test => unreachable!(),
+        Command::Fuzz => unreachable!(),
+        Command::Run => {
+            let program = Program::new(config, instructions);
+            let stdout = stdout(config, &mut recorded);
+            let mut interpreter = Interpreter::from_config(config, &program, stdin(config), stdout);
+            if config.count_cycles {
+                interpreter = interpreter.with_counts();
+            }
+            if config.trace_hash {
+                interpreter = interpreter.with_trace_hash();
             }
-            Instruction::Dec(o, n) => {
-                let r = &mut registers[(rp + o) as usize];
-                *r = r.wrapping_sub(n);
+            if config.mem_stats {
+                interpreter = interpreter.with_mem_stats();
             }
-            Instruction::Output => {
-                _ = std::io::stdout().write(&registers[rp as usize..][..1]);
+            if config.dump_core {
+                interpreter = interpreter.with_history();
             }
-            Instruction::Input => {
-                _ = std::io::stdin().read(&mut registers[rp as usize..][..1]);
+            if config.strict_eof {
+                interpreter = interpreter.with_strict_eof();
             }
-            Instruction::JumpZ(Jump::Location(idx)) => {
-                if registers[rp as usize] == 0 {
-                    ip = idx.get() as usize;
-                    continue;
+            let status = match &config.tape_log {
+                Some(log_path) => {
+                    let interval = config.tape_interval.unwrap_or(viz::DEFAULT_INTERVAL);
+                    match viz::run_logged(&mut interpreter, interval, log_path) {
+                        Ok(status) => status,
+                        Err(e) => {
+                            let log_path = log_path.display();
+                            error!("failed to write `{log_path}`: {e}");
+                            return ExitCode::FAILURE;
+                        }
+                    }
+                }
+                None => interpreter.run(),
+            };
+            match interpreter.counts() {
+                Some(counts) if config.json => {
+                    cli::print_cycle_counts_json(&program.instructions, counts)
                 }
+                Some(counts) => cli::print_cycle_counts(&program.instructions, counts),
+                None => (),
             }
-            Instruction::JumpZ(Jump::Redundant) => (),
-            Instruction::JumpNz(Jump::Location(idx)) => {
-                if registers[rp as usize] > 0 {
-                    ip = idx.get() as usize;
-                    continue;
+            if let Some(hash) = interpreter.trace_hash() {
+                eprintln!("trace hash: {hash:016x}");
+            }
+            if config.mem_stats {
+                cli::print_mem_stats(interpreter.accessed(), NUM_REGISTERS);
+            }
+            if let Status::Exceeded(limit) = status {
+                error!("{limit}");
+                if config.dump_core {
+                    let core = Core::new(&interpreter, limit, &program.instructions);
+                    let core_path = path.with_extension("bfcore");
+                    match std::fs::write(&core_path, core.serialize()) {
+                        Ok(()) => eprintln!("core written to `{}`", core_path.display()),
+                        Err(e) => {
+                            let core_path = core_path.display();
+                            error!("failed to write `{core_path}`: {e}");
+                        }
+                    }
                 }
             }
-            Instruction::JumpNz(Jump::Redundant) => (),
-
-            Instruction::Zero(o) => registers[(rp + o) as usize] = 0,
-            Instruction::Set(o, n) => registers[(rp + o) as usize] = n,
-            Instruction::Add(o) => {
-                let val = registers[rp as usize];
-                let r = &mut registers[(rp + o) as usize];
-                *r = r.wrapping_add(val);
+            drop(interpreter);
+            let exit = matches!(status, Status::Exceeded(_)) as u8;
+            return run_exit_code(config, expected_output.as_deref(), &recorded, exit);
+        }
+        Command::Compile => {
+            let backend = (config.emit.backend(config.target)).expect("checked by parse_args");
+            let out_path = path.with_extension(backend.extension());
+            let mut options = RuntimeOptions::new(config);
+            if config.cache {
+                options.code_cache = cache::CodeCache::path(&out_path);
             }
-            Instruction::Sub(o) => {
-                let val = registers[rp as usize];
-                let r = &mut registers[(rp + o) as usize];
-                *r = r.wrapping_sub(val);
+            if let Some(interval) = config.checkpoint {
+                let name = out_path.with_extension("checkpoint");
+                let name = name.file_name().expect("the path of a file");
+                options.checkpoint = Some(Checkpoint {
+                    interval,
+                    path: name.to_string_lossy().into_owned(),
+                });
             }
-            Instruction::AddMul(o, n) => {
-                let val = registers[rp as usize];
-                let r = &mut registers[(rp + o) as usize];
-                *r = r.wrapping_add(n.wrapping_mul(val));
+            let artifact = backend.compile(config, &instructions, &options);
+            // printed in the format of `sha256sum`, so the executable can be verified using
+            // `sha256sum --check`
+            if config.emit == Emit::Hash {
+                let digest = digest::sha256(artifact.bytes());
+                println!("{}  {}", digest::hex(&digest), out_path.display());
+                return ExitCode::SUCCESS;
             }
-            Instruction::SubMul(o, n) => {
-                let val = registers[rp as usize];
-                let r = &mut registers[(rp + o) as usize];
-                *r = r.wrapping_sub(n.wrapping_mul(val));
+            let file = OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .create(true)
+                .mode(if artifact.is_executable() {
+                    0o755
+                } else {
+                    0o666
+                })
+                .open(&out_path);
+            let mut file = match file {
+                Ok(f) => f,
+                Err(e) => {
+                    let out_path = out_path.display();
+                    error!("failed to create `{out_path}`: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            // `write_all` fails on short writes that can't make progress
+            if let Err(e) = file.write_all(artifact.bytes()) {
+                let out_path = out_path.display();
+                error!("failed to write `{out_path}`: {e}");
+                return ExitCode::FAILURE;
+            }
+            if let Artifact::Library { header, .. } = &artifact {
+                let header_path = out_path.with_extension("h");
+                if let Err(e) = std::fs::write(&header_path, header) {
+                    let header_path = header_path.display();
+                    error!("failed to write `{header_path}`: {e}");
+                    return ExitCode::FAILURE;
+                }
             }
         }
-
-        ip += 1;
     }
+
+    ExitCode::SUCCESS
 }
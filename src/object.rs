@@ -0,0 +1,866 @@
+//! A writer for ELF64 and ELF32 executables, consisting of a text segment, a read-only data segment, a
+//! zero-initialized data segment, and note sections for metadata. The data segments are loaded at
+//! fixed addresses, so code can refer to them before the layout of the file is known, the text
+//! has to be position independent. Relocatable objects only consist of position independent text
+//! and the symbols of the functions inside of it, so they don't need any relocations.
+
+use crate::const_assert;
+
+/// The virtual address of the start of the file, the text is loaded at the same offset from it.
+pub const TEXT_BASE_VADDR: u64 = 0x40_0000;
+/// The virtual address of the read-only data.
+pub const RODATA_VADDR: u64 = 0x1000_0000;
+/// The virtual address of the zero-initialized data.
+pub const BSS_VADDR: u64 = 0x2000_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const ELF_HEADER_LEN: usize = 0x40;
+const PROGRAM_HEADER_LEN: usize = 0x38;
+const SECTION_HEADER_LEN: usize = 0x40;
+const SYMBOL_LEN: usize = 0x18;
+const ELF32_HEADER_LEN: usize = 0x34;
+const ELF32_PROGRAM_HEADER_LEN: usize = 0x20;
+const ELF32_SECTION_HEADER_LEN: usize = 0x28;
+
+const_assert!(ELF_HEADER_LEN == std::mem::size_of::<ElfFileHeader>());
+const_assert!(PROGRAM_HEADER_LEN == std::mem::size_of::<ElfProgramHeader>());
+const_assert!(SECTION_HEADER_LEN == std::mem::size_of::<ElfSectionHeader>());
+const_assert!(SYMBOL_LEN == std::mem::size_of::<ElfSymbol>());
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOTE: u32 = 7;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[repr(C)]
+pub struct ElfFileHeader {
+    pub ei_magic: [u8; 4],
+    pub ei_class: u8,
+    pub ei_data: u8,
+    pub ei_version: u8,
+    pub ei_osabi: u8,
+    pub ei_abiversion: u8,
+    pub ei_pad: [u8; 7],
+
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    /// program header offset
+    pub e_phoff: u64,
+    /// section header offset
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    /// size of this header
+    pub e_ehsize: u16,
+    /// program header entry size
+    pub e_phentsize: u16,
+    /// number of program header table entries
+    pub e_phnum: u16,
+    /// section header entry size
+    pub e_shentsize: u16,
+    /// number of section header table entries
+    pub e_shnum: u16,
+    /// index of section header table entry that contains section names
+    pub e_shstrndx: u16,
+}
+
+#[repr(C)]
+pub struct ElfProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+#[repr(C)]
+pub struct ElfSectionHeader {
+    /// offset of the name in the section name string table
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u64,
+    pub sh_addr: u64,
+    pub sh_offset: u64,
+    pub sh_size: u64,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u64,
+    pub sh_entsize: u64,
+}
+
+#[repr(C)]
+pub struct ElfSymbol {
+    /// offset of the name in the string table
+    pub st_name: u32,
+    /// binding in the high nibble, type in the low nibble
+    pub st_info: u8,
+    pub st_other: u8,
+    /// index of the section the symbol is defined in
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+/// The architecture of the code in the text segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Machine {
+    X86_64 = 0x3E,
+    I386 = 0x03,
+}
+
+impl Machine {
+    /// Whether executables for the machine use the ELF64 format, otherwise the ELF32 format.
+    pub fn is_64_bit(self) -> bool {
+        match self {
+            Machine::X86_64 => true,
+            Machine::I386 => false,
+        }
+    }
+
+    /// The length of the file header, of a program header, and of a section header.
+    fn header_lens(self) -> (usize, usize, usize) {
+        match self.is_64_bit() {
+            true => (ELF_HEADER_LEN, PROGRAM_HEADER_LEN, SECTION_HEADER_LEN),
+            false => (
+                ELF32_HEADER_LEN,
+                ELF32_PROGRAM_HEADER_LEN,
+                ELF32_SECTION_HEADER_LEN,
+            ),
+        }
+    }
+}
+
+// The headers of ELF32 files have the same fields, but addresses, offsets and sizes are only 4
+// bytes, and the flags of program headers follow the sizes.
+
+impl ElfFileHeader {
+    fn to_elf32(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ELF32_HEADER_LEN);
+        bytes.extend_from_slice(&self.ei_magic);
+        bytes.extend_from_slice(&[self.ei_class, self.ei_data, self.ei_version]);
+        bytes.extend_from_slice(&[self.ei_osabi, self.ei_abiversion]);
+        bytes.extend_from_slice(&self.ei_pad);
+        bytes.extend_from_slice(&self.e_type.to_le_bytes());
+        bytes.extend_from_slice(&self.e_machine.to_le_bytes());
+        bytes.extend_from_slice(&self.e_version.to_le_bytes());
+        for word in [self.e_entry, self.e_phoff, self.e_shoff] {
+            bytes.extend_from_slice(&(word as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.e_flags.to_le_bytes());
+        for half in [
+            self.e_ehsize,
+            self.e_phentsize,
+            self.e_phnum,
+            self.e_shentsize,
+            self.e_shnum,
+            self.e_shstrndx,
+        ] {
+            bytes.extend_from_slice(&half.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+impl ElfProgramHeader {
+    fn to_elf32(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ELF32_PROGRAM_HEADER_LEN);
+        bytes.extend_from_slice(&self.p_type.to_le_bytes());
+        for word in [
+            self.p_offset,
+            self.p_vaddr,
+            self.p_paddr,
+            self.p_filesz,
+            self.p_memsz,
+        ] {
+            bytes.extend_from_slice(&(word as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.p_flags.to_le_bytes());
+        bytes.extend_from_slice(&(self.p_align as u32).to_le_bytes());
+        bytes
+    }
+}
+
+impl ElfSectionHeader {
+    fn to_elf32(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ELF32_SECTION_HEADER_LEN);
+        bytes.extend_from_slice(&self.sh_name.to_le_bytes());
+        bytes.extend_from_slice(&self.sh_type.to_le_bytes());
+        for word in [self.sh_flags, self.sh_addr, self.sh_offset, self.sh_size] {
+            bytes.extend_from_slice(&(word as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.sh_link.to_le_bytes());
+        bytes.extend_from_slice(&self.sh_info.to_le_bytes());
+        for word in [self.sh_addralign, self.sh_entsize] {
+            bytes.extend_from_slice(&(word as u32).to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// An ELF note, the `desc` is interpreted depending on the `name` and the `kind`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Note {
+    pub name: String,
+    pub kind: u32,
+    pub desc: Vec<u8>,
+}
+
+impl Note {
+    /// The note identifying the version of this compiler.
+    pub fn version() -> Self {
+        Self {
+            name: "brainfuck".into(),
+            kind: 1,
+            desc: env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&(self.name.len() as u32 + 1).to_le_bytes());
+        bytes.extend_from_slice(&(self.desc.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.kind.to_le_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.push(0);
+        pad_to(bytes, 4);
+        bytes.extend_from_slice(&self.desc);
+        pad_to(bytes, 4);
+    }
+}
+
+/// The contents of an executable, which is laid out by [`Executable::write`].
+#[derive(Clone, Debug)]
+pub struct Executable {
+    pub machine: Machine,
+    /// Position independent code, which is executed starting at its first byte.
+    pub text: Vec<u8>,
+    /// Loaded at [`RODATA_VADDR`].
+    pub rodata: Vec<u8>,
+    /// The number of zero-initialized bytes loaded at [`BSS_VADDR`].
+    pub bss_len: usize,
+    pub notes: Vec<Note>,
+}
+
+/// A section and the segment it's loaded as, if any.
+struct Section {
+    name: &'static str,
+    sh_type: u32,
+    sh_flags: u64,
+    offset: usize,
+    vaddr: u64,
+    size: usize,
+    align: u64,
+}
+
+impl Executable {
+    pub fn new(machine: Machine) -> Self {
+        Self {
+            machine,
+            text: Vec::new(),
+            rodata: Vec::new(),
+            bss_len: 0,
+            notes: Vec::new(),
+        }
+    }
+
+    fn num_program_headers(&self) -> usize {
+        1 + !self.notes.is_empty() as usize
+            + !self.rodata.is_empty() as usize
+            + (self.bss_len > 0) as usize
+    }
+
+    fn text_offset(&self) -> usize {
+        let (header_len, program_header_len, _) = self.machine.header_lens();
+        header_len + self.num_program_headers() * program_header_len
+    }
+
+    /// The virtual address the text is loaded at, which is the entry point.
+    pub fn text_vaddr(&self) -> u64 {
+        TEXT_BASE_VADDR + self.text_offset() as u64
+    }
+
+    /// Lay out the executable: the headers, the text, the notes, the read-only data starting at a
+    /// page boundary, and finally the section names and section headers, which aren't loaded.
+    pub fn write(&self) -> Vec<u8> {
+        let is_64_bit = self.machine.is_64_bit();
+        let (header_len, program_header_len, section_header_len) = self.machine.header_lens();
+        let text_end = self.text_vaddr() + self.text.len() as u64;
+        assert!(
+            text_end <= RODATA_VADDR,
+            "the text overlaps the read-only data"
+        );
+        let mut bytes = vec![0; self.text_offset()];
+        let mut sections = Vec::new();
+
+        sections.push(Section {
+            name: ".text",
+            sh_type: SHT_PROGBITS,
+            sh_flags: SHF_ALLOC | SHF_EXECINSTR,
+            offset: bytes.len(),
+            vaddr: self.text_vaddr(),
+            size: self.text.len(),
+            align: 1,
+        });
+        bytes.extend_from_slice(&self.text);
+
+        if !self.notes.is_empty() {
+            pad_to(&mut bytes, 4);
+            let offset = bytes.len();
+            for note in self.notes.iter() {
+                note.write(&mut bytes);
+            }
+            sections.push(Section {
+                name: ".note",
+                sh_type: SHT_NOTE,
+                sh_flags: 0,
+                offset,
+                vaddr: 0,
+                size: bytes.len() - offset,
+                align: 4,
+            });
+        }
+
+        if !self.rodata.is_empty() {
+            pad_to(&mut bytes, PAGE_SIZE as usize);
+            sections.push(Section {
+                name: ".rodata",
+                sh_type: SHT_PROGBITS,
+                sh_flags: SHF_ALLOC,
+                offset: bytes.len(),
+                vaddr: RODATA_VADDR,
+                size: self.rodata.len(),
+                align: PAGE_SIZE,
+            });
+            bytes.extend_from_slice(&self.rodata);
+        }
+
+        if self.bss_len > 0 {
+            sections.push(Section {
+                name: ".bss",
+                sh_type: SHT_NOBITS,
+                sh_flags: SHF_ALLOC | SHF_WRITE,
+                offset: bytes.len(),
+                vaddr: BSS_VADDR,
+                size: self.bss_len,
+                align: PAGE_SIZE,
+            });
+        }
+
+        // program headers
+        let mut program_headers = Vec::new();
+        for s in sections.iter() {
+            let (p_type, p_flags) = match s.name {
+                ".text" => (PT_LOAD, PF_R | PF_X),
+                ".rodata" => (PT_LOAD, PF_R),
+                ".bss" => (PT_LOAD, PF_R | PF_W),
+                _ => (PT_NOTE, PF_R),
+            };
+            let loaded = p_type == PT_LOAD;
+            program_headers.push(ElfProgramHeader {
+                p_type,
+                p_flags,
+                p_offset: if s.sh_type == SHT_NOBITS {
+                    0
+                } else {
+                    s.offset as u64
+                },
+                p_vaddr: s.vaddr,
+                p_paddr: 0, // physical address is not used
+                p_filesz: if s.sh_type == SHT_NOBITS {
+                    0
+                } else {
+                    s.size as u64
+                },
+                p_memsz: s.size as u64,
+                p_align: if loaded { PAGE_SIZE } else { s.align },
+            });
+        }
+        debug_assert_eq!(program_headers.len(), self.num_program_headers());
+        for (i, header) in program_headers.into_iter().enumerate() {
+            let offset = header_len + i * program_header_len;
+            let bytes = &mut bytes[offset..offset + program_header_len];
+            if is_64_bit {
+                let header: [u8; PROGRAM_HEADER_LEN] = unsafe { std::mem::transmute(header) };
+                bytes.copy_from_slice(&header);
+            } else {
+                bytes.copy_from_slice(&header.to_elf32());
+            }
+        }
+
+        // section names, the first entry is the empty name of the null section
+        let mut names = vec![0];
+        let mut name_offsets = Vec::new();
+        for s in sections.iter() {
+            name_offsets.push(names.len() as u32);
+            names.extend_from_slice(s.name.as_bytes());
+            names.push(0);
+        }
+        let shstrtab_name = names.len() as u32;
+        names.extend_from_slice(b".shstrtab\0");
+        let shstrtab_offset = bytes.len();
+        bytes.extend_from_slice(&names);
+
+        // section headers, starting with the null section and ending with the section names
+        pad_to(&mut bytes, 8);
+        let shoff = bytes.len();
+        let null = ElfSectionHeader {
+            sh_name: 0,
+            sh_type: 0,
+            sh_flags: 0,
+            sh_addr: 0,
+            sh_offset: 0,
+            sh_size: 0,
+            sh_link: 0,
+            sh_info: 0,
+            sh_addralign: 0,
+            sh_entsize: 0,
+        };
+        let shstrtab = ElfSectionHeader {
+            sh_name: shstrtab_name,
+            sh_type: SHT_STRTAB,
+            sh_flags: 0,
+            sh_addr: 0,
+            sh_offset: shstrtab_offset as u64,
+            sh_size: names.len() as u64,
+            sh_link: 0,
+            sh_info: 0,
+            sh_addralign: 1,
+            sh_entsize: 0,
+        };
+        let section_headers = std::iter::once(null)
+            .chain(
+                sections
+                    .iter()
+                    .zip(name_offsets)
+                    .map(|(s, name)| ElfSectionHeader {
+                        sh_name: name,
+                        sh_type: s.sh_type,
+                        sh_flags: s.sh_flags,
+                        sh_addr: s.vaddr,
+                        sh_offset: s.offset as u64,
+                        sh_size: s.size as u64,
+                        sh_link: 0,
+                        sh_info: 0,
+                        sh_addralign: s.align,
+                        sh_entsize: 0,
+                    }),
+            )
+            .chain(std::iter::once(shstrtab));
+        let mut shnum = 0;
+        for header in section_headers {
+            if is_64_bit {
+                let header: [u8; SECTION_HEADER_LEN] = unsafe { std::mem::transmute(header) };
+                bytes.extend_from_slice(&header);
+            } else {
+                bytes.extend_from_slice(&header.to_elf32());
+            }
+            shnum += 1;
+        }
+
+        let header = ElfFileHeader {
+            // e_ident
+            ei_magic: *b"\x7fELF",
+            ei_class: if is_64_bit { 0x02 } else { 0x01 },
+            ei_data: 0x01, // little-endian
+            ei_version: 0x01,
+            ei_osabi: 0x00, // system-v
+            ei_abiversion: 0x00,
+            ei_pad: [0x00; 7], // reserved
+
+            e_type: 0x0002, // executable
+            e_machine: self.machine as u16,
+            e_version: 1,
+            e_entry: self.text_vaddr(),
+            e_phoff: header_len as u64, // program headers immediately follow the ELF header
+            e_shoff: shoff as u64,
+            e_flags: 0x0000_0000, // no flags
+            e_ehsize: header_len as u16,
+            e_phentsize: program_header_len as u16,
+            e_phnum: self.num_program_headers() as u16,
+            e_shentsize: section_header_len as u16,
+            e_shnum: shnum,
+            e_shstrndx: shnum - 1, // the section names are the last section
+        };
+        if is_64_bit {
+            let header: [u8; ELF_HEADER_LEN] = unsafe { std::mem::transmute(header) };
+            bytes[..ELF_HEADER_LEN].copy_from_slice(&header);
+        } else {
+            bytes[..ELF32_HEADER_LEN].copy_from_slice(&header.to_elf32());
+        }
+
+        bytes
+    }
+}
+
+/// A global function inside the text of a [`Relocatable`] object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    /// The offset of the first instruction inside the text.
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// The contents of a relocatable object, which is laid out by [`Relocatable::write`].
+#[derive(Clone, Debug)]
+pub struct Relocatable {
+    pub machine: Machine,
+    /// Position independent code, which doesn't refer to anything outside of it.
+    pub text: Vec<u8>,
+    pub functions: Vec<Function>,
+}
+
+impl Relocatable {
+    pub fn new(machine: Machine) -> Self {
+        Self {
+            machine,
+            text: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    /// Lay out the object: the header, the text, the symbols and their names, the section names,
+    /// and finally the section headers. The empty `.note.GNU-stack` section marks the stack as
+    /// non-executable.
+    pub fn write(&self) -> Vec<u8> {
+        const TEXT_INDEX: u16 = 1;
+        const STRTAB_INDEX: u32 = 4;
+
+        let mut bytes = vec![0; ELF_HEADER_LEN];
+        pad_to(&mut bytes, 16);
+        let text_offset = bytes.len();
+        bytes.extend_from_slice(&self.text);
+
+        // symbol names, the first entry is the empty name of the null symbol
+        let mut names = vec![0];
+        pad_to(&mut bytes, 8);
+        let symtab_offset = bytes.len();
+        bytes.extend_from_slice(&[0; SYMBOL_LEN]);
+        for f in self.functions.iter() {
+            let symbol = ElfSymbol {
+                st_name: names.len() as u32,
+                st_info: (STB_GLOBAL << 4) | STT_FUNC,
+                st_other: 0, // default visibility
+                st_shndx: TEXT_INDEX,
+                st_value: f.offset as u64,
+                st_size: f.len as u64,
+            };
+            names.extend_from_slice(f.name.as_bytes());
+            names.push(0);
+            let symbol: [u8; SYMBOL_LEN] = unsafe { std::mem::transmute(symbol) };
+            bytes.extend_from_slice(&symbol);
+        }
+        let symtab_len = bytes.len() - symtab_offset;
+        let strtab_offset = bytes.len();
+        bytes.extend_from_slice(&names);
+
+        let section_names = [
+            ".text",
+            ".note.GNU-stack",
+            ".symtab",
+            ".strtab",
+            ".shstrtab",
+        ];
+        let mut shstrtab = vec![0];
+        let mut name_offsets = Vec::new();
+        for name in section_names {
+            name_offsets.push(shstrtab.len() as u32);
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+        let shstrtab_offset = bytes.len();
+        bytes.extend_from_slice(&shstrtab);
+
+        let section = |sh_name, sh_type, sh_flags, offset: usize, size: usize, sh_addralign| {
+            ElfSectionHeader {
+                sh_name,
+                sh_type,
+                sh_flags,
+                sh_addr: 0,
+                sh_offset: offset as u64,
+                sh_size: size as u64,
+                sh_link: 0,
+                sh_info: 0,
+                sh_addralign,
+                sh_entsize: 0,
+            }
+        };
+        let section_headers = [
+            section(0, 0, 0, 0, 0, 0),
+            section(
+                name_offsets[0],
+                SHT_PROGBITS,
+                SHF_ALLOC | SHF_EXECINSTR,
+                text_offset,
+                self.text.len(),
+                16,
+            ),
+            section(name_offsets[1], SHT_PROGBITS, 0, strtab_offset, 0, 1),
+            ElfSectionHeader {
+                sh_link: STRTAB_INDEX,
+                // the index of the first global symbol, all symbols except the null one are global
+                sh_info: 1,
+                sh_entsize: SYMBOL_LEN as u64,
+                ..section(name_offsets[2], SHT_SYMTAB, 0, symtab_offset, symtab_len, 8)
+            },
+            section(
+                name_offsets[3],
+                SHT_STRTAB,
+                0,
+                strtab_offset,
+                names.len(),
+                1,
+            ),
+            section(
+                name_offsets[4],
+                SHT_STRTAB,
+                0,
+                shstrtab_offset,
+                shstrtab.len(),
+                1,
+            ),
+        ];
+        pad_to(&mut bytes, 8);
+        let shoff = bytes.len();
+        let shnum = section_headers.len() as u16;
+        for header in section_headers {
+            let header: [u8; SECTION_HEADER_LEN] = unsafe { std::mem::transmute(header) };
+            bytes.extend_from_slice(&header);
+        }
+
+        let header = ElfFileHeader {
+            // e_ident
+            ei_magic: *b"\x7fELF",
+            ei_class: 0x02, // 64-bit
+            ei_data: 0x01,  // little-endian
+            ei_version: 0x01,
+            ei_osabi: 0x00, // system-v
+            ei_abiversion: 0x00,
+            ei_pad: [0x00; 7], // reserved
+
+            e_type: 0x0001, // relocatable
+            e_machine: self.machine as u16,
+            e_version: 1,
+            e_entry: 0,
+            e_phoff: 0,
+            e_shoff: shoff as u64,
+            e_flags: 0x0000_0000, // no flags
+            e_ehsize: ELF_HEADER_LEN as u16,
+            e_phentsize: 0,
+            e_phnum: 0,
+            e_shentsize: SECTION_HEADER_LEN as u16,
+            e_shnum: shnum,
+            e_shstrndx: shnum - 1, // the section names are the last section
+        };
+        let header: [u8; ELF_HEADER_LEN] = unsafe { std::mem::transmute(header) };
+        bytes[..ELF_HEADER_LEN].copy_from_slice(&header);
+
+        bytes
+    }
+}
+
+fn pad_to(bytes: &mut Vec<u8>, align: usize) {
+    bytes.resize(bytes.len().next_multiple_of(align), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RuntimeOptions;
+    use crate::cli::Config;
+    use crate::{i686, selftest, x86};
+
+    /// Check the structure of an ELF64 or ELF32 executable written by [`Executable::write`]
+    /// without running it: the identification and the header fields, that loaded segments fit into
+    /// the file, are aligned and don't share pages, that the sections fit into the file and are
+    /// named, and that the entry point is inside the executable segment and the `.text` section.
+    fn check_executable(bytes: &[u8]) -> Result<(), String> {
+        if bytes.get(..4) != Some(b"\x7fELF") {
+            return Err("missing the ELF magic".to_owned());
+        }
+        let ident = (bytes[4], bytes[5], bytes[6]);
+        let machine = match ident {
+            (0x02, 0x01, 0x01) => Machine::X86_64,
+            (0x01, 0x01, 0x01) => Machine::I386,
+            _ => {
+                return Err(format!(
+                    "expected a 64-bit or 32-bit little-endian ELF file, got {ident:?}"
+                ))
+            }
+        };
+        // addresses, offsets and sizes are 8 bytes in ELF64 files and 4 bytes in ELF32 files, which
+        // moves the fields following them
+        let is_64_bit = machine.is_64_bit();
+        let at = |offset64: u64, offset32: u64| if is_64_bit { offset64 } else { offset32 };
+        let read_word = |offset: u64| match is_64_bit {
+            true => read_u64(bytes, offset),
+            false => read_u32(bytes, offset).map(u64::from),
+        };
+        let e_type = read_u16(bytes, 0x10)?;
+        let e_machine = read_u16(bytes, 0x12)?;
+        let e_version = read_u32(bytes, 0x14)?;
+        let e_entry = read_word(0x18)?;
+        let e_phoff = read_word(at(0x20, 0x1c))?;
+        let e_shoff = read_word(at(0x28, 0x20))?;
+        let sizes = (
+            read_u16(bytes, at(0x34, 0x28))?,
+            read_u16(bytes, at(0x36, 0x2a))?,
+            read_u16(bytes, at(0x3a, 0x2e))?,
+        );
+        let e_phnum = read_u16(bytes, at(0x38, 0x2c))?;
+        let e_shnum = read_u16(bytes, at(0x3c, 0x30))?;
+        let e_shstrndx = read_u16(bytes, at(0x3e, 0x32))?;
+        if (e_type, e_machine, e_version) != (0x0002, machine as u16, 1) {
+            return Err(format!(
+                "expected an {machine:?} executable of version 1, got type {e_type:#x}, machine {e_machine:#x} and version {e_version}"
+            ));
+        }
+        let (header_len, program_header_len, section_header_len) = machine.header_lens();
+        let expected = (header_len, program_header_len, section_header_len);
+        if sizes != (expected.0 as u16, expected.1 as u16, expected.2 as u16) {
+            return Err(format!("unexpected header sizes {sizes:?}"));
+        }
+
+        // program headers
+        let mut loaded = Vec::new();
+        let mut entry_segment = false;
+        for i in 0..e_phnum as u64 {
+            let header = e_phoff + i * program_header_len as u64;
+            let p_type = read_u32(bytes, header)?;
+            let p_flags = read_u32(bytes, header + at(0x04, 0x18))?;
+            let p_offset = read_word(header + at(0x08, 0x04))?;
+            let p_vaddr = read_word(header + at(0x10, 0x08))?;
+            let p_filesz = read_word(header + at(0x20, 0x10))?;
+            let p_memsz = read_word(header + at(0x28, 0x14))?;
+            let p_align = read_word(header + at(0x30, 0x1c))?;
+            if !p_align.is_power_of_two() {
+                return Err(format!("segment {i} has the alignment {p_align:#x}"));
+            }
+            if p_filesz > p_memsz || p_offset + p_filesz > bytes.len() as u64 {
+                return Err(format!(
+                    "segment {i} doesn't fit into the file or its memory"
+                ));
+            }
+            if p_type != PT_LOAD {
+                continue;
+            }
+            if p_align != PAGE_SIZE || p_offset % p_align != p_vaddr % p_align {
+                return Err(format!(
+                    "segment {i} at offset {p_offset:#x} and address {p_vaddr:#x} isn't page aligned"
+                ));
+            }
+            let in_segment = (p_vaddr..p_vaddr + p_filesz).contains(&e_entry);
+            entry_segment |= in_segment && p_flags & PF_X != 0;
+            loaded.push((i, p_vaddr, p_vaddr + p_memsz));
+        }
+        loaded.sort_by_key(|(_, start, _)| *start);
+        for pair in loaded.windows(2) {
+            let ((a, _, end), (b, start, _)) = (pair[0], pair[1]);
+            if end.next_multiple_of(PAGE_SIZE) > start - start % PAGE_SIZE {
+                return Err(format!("the loaded segments {a} and {b} share a page"));
+            }
+        }
+        if !entry_segment {
+            return Err(format!(
+                "the entry point {e_entry:#x} isn't inside an executable segment"
+            ));
+        }
+
+        // section headers
+        if e_shoff + e_shnum as u64 * section_header_len as u64 > bytes.len() as u64 {
+            return Err("the section headers don't fit into the file".to_owned());
+        }
+        if e_shstrndx >= e_shnum {
+            return Err(format!("the section names {e_shstrndx} aren't a section"));
+        }
+        let section = |i: u64| e_shoff + i * section_header_len as u64;
+        let names_offset = read_word(section(e_shstrndx as u64) + at(0x18, 0x10))?;
+        let names_size = read_word(section(e_shstrndx as u64) + at(0x20, 0x14))?;
+        let names = (bytes.get(names_offset as usize..(names_offset + names_size) as usize))
+            .ok_or("the section names don't fit into the file")?;
+        let mut text_section = false;
+        for i in 1..e_shnum as u64 {
+            let sh_name = read_u32(bytes, section(i))? as usize;
+            let sh_type = read_u32(bytes, section(i) + 0x04)?;
+            let sh_addr = read_word(section(i) + at(0x10, 0x0c))?;
+            let sh_offset = read_word(section(i) + at(0x18, 0x10))?;
+            let sh_size = read_word(section(i) + at(0x20, 0x14))?;
+            let name = (names.get(sh_name..))
+                .and_then(|n| n.split(|&b| b == 0).next().filter(|_| n.contains(&0)))
+                .ok_or_else(|| format!("section {i} has no name"))?;
+            if sh_type != SHT_NOBITS && sh_offset + sh_size > bytes.len() as u64 {
+                let name = name.escape_ascii();
+                return Err(format!("the section `{name}` doesn't fit into the file"));
+            }
+            if name == b".text" {
+                text_section = (sh_addr..sh_addr + sh_size).contains(&e_entry);
+            }
+        }
+        if !text_section {
+            return Err(format!(
+                "the entry point {e_entry:#x} isn't inside the `.text` section"
+            ));
+        }
+        Ok(())
+    }
+
+    fn read<const N: usize>(bytes: &[u8], offset: u64) -> Result<[u8; N], String> {
+        (bytes.get(offset as usize..))
+            .and_then(|b| b.first_chunk::<N>())
+            .copied()
+            .ok_or_else(|| format!("the file ends before offset {offset:#x}"))
+    }
+
+    fn read_u16(bytes: &[u8], offset: u64) -> Result<u16, String> {
+        read(bytes, offset).map(u16::from_le_bytes)
+    }
+
+    fn read_u32(bytes: &[u8], offset: u64) -> Result<u32, String> {
+        read(bytes, offset).map(u32::from_le_bytes)
+    }
+
+    fn read_u64(bytes: &[u8], offset: u64) -> Result<u64, String> {
+        read(bytes, offset).map(u64::from_le_bytes)
+    }
+
+    /// The program compiled to check the structure of the generated executables.
+    const PROGRAM: &str = ",[>+<-]>+.";
+
+    #[test]
+    fn compiled_executables_are_valid() {
+        let config = Config::default();
+        let options = RuntimeOptions::new(&config);
+        let instructions = selftest::build(&config, PROGRAM.as_bytes()).unwrap();
+        let x86_64 = x86::compile(&config, &options, &instructions);
+        assert_eq!(check_executable(&x86_64), Ok(()));
+        let i686 = i686::compile(&config, &options, &instructions);
+        assert_eq!(check_executable(&i686), Ok(()));
+    }
+
+    #[test]
+    fn executable_with_all_segments_is_valid() {
+        let mut executable = Executable::new(Machine::X86_64);
+        executable.text = vec![0xc3];
+        executable.rodata = vec![0; 3];
+        executable.bss_len = 5;
+        executable.notes.push(Note::version());
+        assert_eq!(check_executable(&executable.write()), Ok(()));
+    }
+}
@@ -1,35 +1,321 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::num::NonZeroU32;
 use std::ops::ControlFlow;
 
-use crate::cli::Config;
-use crate::{warn, Instruction, Jump, NUM_REGISTERS};
+use crate::cli::{Config, Pass};
+use crate::ir::{self, Loop, Node};
+use crate::peephole::{self, rules, Rule};
+use crate::{interp, Instruction, Jump, NUM_REGISTERS};
+
+/// Minimum number of instructions before local passes are run in parallel.
+const PARALLEL_THRESHOLD: usize = 1 << 16;
+/// Maximum number of instructions of a segment. The local passes aren't linear in the number of
+/// instructions, so large programs are split into many more segments than threads.
+const MAX_SEGMENT_LEN: usize = 1 << 16;
+/// Number of instructions simplified at once, at least half of it is available to look ahead.
+const SIMPLIFY_WINDOW: usize = 1 << 12;
+/// Maximum number of instructions executed at compile time by [`optimize_static_code`], if
+/// `--static-fuel` isn't passed.
+pub const DEFAULT_STATIC_FUEL: u64 = 1 << 24;
+/// Number of registers portable programs can rely on, the tape length of the original
+/// implementation.
+pub const PORTABLE_TAPE_LEN: usize = 30000;
 
 enum IndexInc {
     Zero = 0,
     One = 1,
 }
 
-pub fn replace_zeros(config: &Config, instructions: &mut Vec<Instruction>) {
+/// A step of [`optimize`], which runs one or several optimization passes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Pass(Pass),
+    /// The `dead-code`, `init`, `jumps` and `unroll` passes, which execute the code at the start
+    /// of the program at compile time.
+    StaticCode,
+    /// The `arithmetic`, `simplify` and `jumps` passes applied to the segments of a large program
+    /// in parallel.
+    Segments,
+    Superopt,
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::Pass(pass) => f.write_str(pass.name()),
+            Stage::StaticCode => f.write_str("static-code"),
+            Stage::Segments => f.write_str("segments"),
+            Stage::Superopt => f.write_str("superopt"),
+        }
+    }
+}
+
+/// Run all optimization passes enabled in the `config`.
+pub fn optimize(config: &Config, instructions: &mut Vec<Instruction>) {
+    run_stages(config, instructions, &mut |_, _| ());
+}
+
+/// Run all optimization passes enabled in the `config` like [`optimize`], and return the
+/// instructions after each stage that ran, so tools can show the intermediate results. The jump
+/// locations of the instructions aren't resolved.
+pub fn optimize_with_trace(
+    config: &Config,
+    instructions: &[Instruction],
+) -> Vec<(Stage, Vec<Instruction>)> {
+    let mut trace = Vec::new();
+    let mut instructions = instructions.to_vec();
+    run_stages(config, &mut instructions, &mut |stage, i| {
+        trace.push((stage, i.to_vec()))
+    });
+    trace
+}
+
+/// Run the stages of [`optimize`], and call `traced` with the instructions after each one.
+fn run_stages(
+    config: &Config,
+    instructions: &mut Vec<Instruction>,
+    traced: &mut dyn FnMut(Stage, &[Instruction]),
+) {
+    let prev_len = instructions.len();
+
+    if config.pass(Pass::DivMod) {
+        insert_div_mod(config, instructions);
+        traced(Stage::Pass(Pass::DivMod), instructions);
+    }
+
+    // zero register
+    if config.pass(Pass::Zeros) {
+        replace_zeros(config, instructions);
+        traced(Stage::Pass(Pass::Zeros), instructions);
+    }
+
+    if config.pass(Pass::DeadCode)
+        || config.pass(Pass::Init)
+        || config.pass(Pass::Jumps)
+        || config.pass(Pass::Unroll)
+    {
+        optimize_static_code(config, instructions);
+        traced(Stage::StaticCode, instructions);
+    }
+
+    if config.pass(Pass::Jumps) {
+        let mut nodes = ir::build(instructions);
+        remove_redundant_jump_pairs(config, &mut nodes);
+        *instructions = ir::lower(&nodes);
+        traced(Stage::Pass(Pass::Jumps), instructions);
+    }
+
+    if instructions.len() >= PARALLEL_THRESHOLD {
+        optimize_segments_parallel(config, instructions);
+        traced(Stage::Segments, instructions);
+    } else {
+        optimize_loops_and_simplify(config, instructions, traced);
+    }
+
+    if config.pass(Pass::DeadCode)
+        || config.pass(Pass::Init)
+        || config.pass(Pass::Jumps)
+        || config.pass(Pass::Unroll)
+    {
+        optimize_static_code(config, instructions);
+        traced(Stage::StaticCode, instructions);
+    }
+
+    if config.pass(Pass::Jumps) {
+        let mut nodes = ir::build(instructions);
+        remove_redundant_jump_pairs(config, &mut nodes);
+        *instructions = ir::lower(&nodes);
+        traced(Stage::Pass(Pass::Jumps), instructions);
+    }
+
+    if config.pass(Pass::Decimal) {
+        replace_print_decimal(config, instructions);
+        traced(Stage::Pass(Pass::Decimal), instructions);
+    }
+
+    if config.pass(Pass::Copy) {
+        replace_copies(config, instructions);
+        traced(Stage::Pass(Pass::Copy), instructions);
+    }
+
+    if config.pass(Pass::Pipe) {
+        insert_pipes(config, instructions);
+        traced(Stage::Pass(Pass::Pipe), instructions);
+    }
+
+    #[cfg(feature = "superopt")]
+    if config.superopt {
+        crate::superopt::superoptimize(config, instructions);
+        traced(Stage::Superopt, instructions);
+    }
+
+    if config.verbose >= 1 {
+        if config.verbose >= 2 {
+            println!("============================================================");
+        }
+        println!(
+            "instructions before {} after: {} ({:.3}%)",
+            prev_len,
+            instructions.len(),
+            100.0 * instructions.len() as f32 / prev_len as f32,
+        );
+        println!("============================================================");
+    }
+}
+
+/// Passes that only look at loops and runs of instructions locally, and can therefore be applied
+/// to segments of the program independently.
+fn optimize_loops_and_simplify(
+    config: &Config,
+    instructions: &mut Vec<Instruction>,
+    traced: &mut dyn FnMut(Stage, &[Instruction]),
+) {
+    let mut nodes = ir::build(instructions);
+
+    if config.pass(Pass::Arithmetic) || config.pass(Pass::Jumps) {
+        arithmetic_loop_pass(config, &mut nodes);
+        traced(Stage::Pass(Pass::Arithmetic), &ir::lower(&nodes));
+    }
+
+    if config.pass(Pass::Simplify) {
+        simplify_nodes(config, &mut nodes);
+        traced(Stage::Pass(Pass::Simplify), &ir::lower(&nodes));
+    }
+
+    if config.pass(Pass::Jumps) {
+        mark_if_loops(config, &mut nodes);
+        traced(Stage::Pass(Pass::Jumps), &ir::lower(&nodes));
+    }
+
+    *instructions = ir::lower(&nodes);
+}
+
+/// Split the program at top-level loop boundaries into segments, and run the local passes on all
+/// segments in parallel.
+fn optimize_segments_parallel(config: &Config, instructions: &mut Vec<Instruction>) {
+    let num_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let segment_len = instructions
+        .len()
+        .div_ceil(num_threads)
+        .min(MAX_SEGMENT_LEN);
+
+    let mut segments = Vec::with_capacity(num_threads);
+    let mut segment = Vec::with_capacity(segment_len);
+    let mut depth = 0_usize;
+    for inst in instructions.drain(..) {
+        match inst {
+            Instruction::JumpZ(_) => depth += 1,
+            Instruction::JumpNz(_) => depth -= 1,
+            _ => (),
+        }
+        segment.push(inst);
+        if depth == 0 && segment.len() >= segment_len && matches!(inst, Instruction::JumpNz(_)) {
+            segments.push(std::mem::replace(
+                &mut segment,
+                Vec::with_capacity(segment_len),
+            ));
+        }
+    }
+    segments.push(segment);
+
+    if config.verbose >= 1 {
+        println!("optimizing {} segments in parallel", segments.len());
+    }
+    let segments_per_thread = segments.len().div_ceil(num_threads);
+    std::thread::scope(|s| {
+        for chunk in segments.chunks_mut(segments_per_thread) {
+            s.spawn(|| {
+                for segment in chunk.iter_mut() {
+                    optimize_loops_and_simplify(config, segment, &mut |_, _| ());
+                }
+            });
+        }
+    });
+
+    for segment in segments {
+        instructions.extend(segment);
+    }
+
+    // loops replaced by arithmetic might be combinable with code at the start of the next segment
+    if config.pass(Pass::Simplify) {
+        simplify_code(config, instructions);
+    }
+}
+
+/// The divmod loop `[->-[>+>>]>[+[-<+>]>+>>]<<<<<]` as combined instructions.
+const DIV_MOD_LOOP: [Instruction; 24] = {
     use Instruction::*;
+    const J: Jump = Jump::Unresolved;
+    #[rustfmt::skip]
+    let l = [
+        JumpZ(J), Dec(0, 1), Shr(1), Dec(0, 1),
+            JumpZ(J), Shr(1), Inc(0, 1), Shr(2), JumpNz(J),
+            Shr(1),
+            JumpZ(J), Inc(0, 1),
+                JumpZ(J), Dec(0, 1), Shl(1), Inc(0, 1), Shr(1), JumpNz(J),
+                Shr(1), Inc(0, 1), Shr(2),
+            JumpNz(J),
+            Shl(5),
+        JumpNz(J),
+    ];
+    l
+};
 
+/// Insert a [`Instruction::DivMod`] before every divmod loop. The loop is kept, since the layout
+/// of the registers it requires can only be checked at runtime, if it holds the division is
+/// computed directly and the loop is skipped.
+pub fn insert_div_mod(config: &Config, instructions: &mut Vec<Instruction>) {
     let mut i = 0;
-    while i + 3 < instructions.len() {
-        let [a, b, c] = &instructions[i..i + 3] else {
-            unreachable!()
-        };
-        if let (JumpZ(_), Dec(0, 1), JumpNz(_)) = (a, b, c) {
-            let range = i..i + 3;
-            if config.verbose >= 2 {
-                println!("replaced {range:?} with zero");
-            }
-            instructions.drain(range);
-            instructions.insert(i, Zero(0));
+    while i + DIV_MOD_LOOP.len() <= instructions.len() {
+        if !is_div_mod_loop(&instructions[i..i + DIV_MOD_LOOP.len()]) {
+            i += 1;
+            continue;
         }
 
-        i += 1;
+        if config.verbose >= 2 {
+            println!("divmod loop at {i}");
+        }
+        instructions.insert(i, Instruction::DivMod);
+        i += 1 + DIV_MOD_LOOP.len();
     }
 }
 
+/// Whether the instructions are exactly the divmod loop, ignoring jump locations.
+pub fn is_div_mod_loop(instructions: &[Instruction]) -> bool {
+    let same_op = |a: &Instruction, b: &Instruction| match (a, b) {
+        (Instruction::JumpZ(_), Instruction::JumpZ(_)) => true,
+        (Instruction::JumpNz(_), Instruction::JumpNz(_)) => true,
+        _ => a == b,
+    };
+    instructions.len() == DIV_MOD_LOOP.len()
+        && instructions
+            .iter()
+            .zip(DIV_MOD_LOOP.iter())
+            .all(|(a, b)| same_op(a, b))
+}
+
+/// The rules of the `pipe` pass.
+pub const PIPE_RULES: &[Rule] = rules! {
+    // the loop is kept to handle the end of the input
+    "echo-loop", "[.,]":
+        [JumpZ(a), Output(0), Input(0), JumpNz(b)] => [Pipe, JumpZ(a), Output(0), Input(0), JumpNz(b)];
+};
+
+/// The rules of the `zeros` pass.
+pub const ZERO_RULES: &[Rule] = rules! {
+    "zero-loop", "[-]": [JumpZ(_), Dec(0, 1), JumpNz(_)] => [Zero(0)];
+};
+
+/// Insert a [`Instruction::Pipe`] before every echo loop `[.,]`.
+pub fn insert_pipes(config: &Config, instructions: &mut Vec<Instruction>) {
+    peephole::rewrite(config, PIPE_RULES, instructions);
+}
+
+/// Replace every loop `[-]` with a [`Instruction::Zero`].
+pub fn replace_zeros(config: &Config, instructions: &mut Vec<Instruction>) {
+    peephole::rewrite(config, ZERO_RULES, instructions);
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Update {
     /// A relative change to a register value.
@@ -64,114 +350,163 @@ impl Update {
     }
 }
 
-pub fn arithmetic_loop_pass(config: &Config, instructions: &mut Vec<Instruction>, i: usize) {
-    use Instruction::*;
-
-    let JumpZ(_) = instructions[i] else { return };
+/// Replace innermost loops that only perform arithmetic and decrement their iteration register by
+/// one, with multiplications.
+pub fn arithmetic_loop_pass(config: &Config, nodes: &mut Vec<Node>) {
+    // the nodes are moved into a new list, splicing the replacements in place is quadratic
+    let mut replaced = Vec::with_capacity(nodes.len());
+    for mut node in std::mem::take(nodes) {
+        let Node::Loop(l) = &mut node else {
+            replaced.push(node);
+            continue;
+        };
+        if !l.is_innermost() {
+            arithmetic_loop_pass(config, &mut l.body);
+            replaced.push(node);
+            continue;
+        }
 
-    let start = i + 1;
-    let mut end = None;
-    for (j, inst) in instructions[start..].iter().enumerate() {
-        match inst {
-            JumpZ(_) => break,
-            JumpNz(jump) => {
-                end = Some((jump, start + j));
-                break;
+        match replace_arithmetic_loop(config, l) {
+            Some(replacements)
+                if peephole::verify(
+                    config,
+                    "arithmetic",
+                    &ir::lower(std::slice::from_ref(&node)),
+                    &ir::lower(&replacements),
+                ) =>
+            {
+                replaced.extend(replacements)
             }
-            _ => (),
+            _ => replaced.push(node),
         }
     }
-    let Some((end_jump, end)) = end else { return };
-    let inner = &instructions[start..end];
-    let mut offset = 0;
-    let mut num_arith = 0;
+    *nodes = replaced;
+}
+
+/// The result of analyzing the body of an innermost loop in the `arithmetic` pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopVerdict {
+    /// The loop only performs arithmetic and decrements its iteration register by one, so it can
+    /// be replaced with multiplications.
+    Arithmetic,
+    /// The loop contains an instruction other than arithmetic and pointer moves.
+    Unsupported(Instruction),
+    /// The loop moves the register pointer by this offset in each iteration.
+    Unbalanced(i16),
+    /// An offset of the loop body doesn't fit into 16 bits.
+    OffsetOverflow,
+    /// The loop always zeros its iteration register, so the body is executed at most once.
+    RunsOnce,
+    /// The loop doesn't alter its iteration register, or sets it to a constant non zero value.
+    Infinite,
+    /// The loop changes its iteration register by this relative amount other than -1 in each
+    /// iteration, so it might never complete depending on how the register was initialized.
+    Step(i8),
+}
+
+/// Analyze the `inner` instructions of an innermost loop.
+pub fn arithmetic_loop_verdict(inner: &[Instruction]) -> LoopVerdict {
+    use Instruction::*;
+
+    let mut offset = 0_i16;
     let mut iteration_diff = Update::Relative(0);
-    for inst in inner {
+    for inst in inner.iter() {
+        let at = |o: i16| offset.checked_add(o);
         match *inst {
-            Shl(n) => offset -= n as i16,
-            Shr(n) => offset += n as i16,
-            Inc(o, n) => {
-                if offset + o == 0 {
-                    iteration_diff.inc(n);
-                } else {
-                    num_arith += 1;
-                }
-            }
-            Dec(o, n) => {
-                if offset + o == 0 {
-                    iteration_diff.dec(n);
-                } else {
-                    num_arith += 1;
-                }
-            }
-            Zero(o) => {
-                if offset + o == 0 {
-                    iteration_diff.set(0);
-                } else {
-                    num_arith += 1;
-                }
-            }
-            Set(o, n) => {
-                if offset + o == 0 {
-                    iteration_diff.set(n);
-                } else {
-                    num_arith += 1;
-                }
-            }
-            Output | Input | JumpZ(_) | JumpNz(_) | Add(_) | Sub(_) | AddMul(..) | SubMul(..) => {
-                return
+            Shl(n) => match offset.checked_sub_unsigned(n) {
+                Some(o) => offset = o,
+                None => return LoopVerdict::OffsetOverflow,
+            },
+            Shr(n) => match offset.checked_add_unsigned(n) {
+                Some(o) => offset = o,
+                None => return LoopVerdict::OffsetOverflow,
+            },
+            Inc(o, n) => match at(o) {
+                Some(0) => iteration_diff.inc(n),
+                Some(_) => (),
+                None => return LoopVerdict::OffsetOverflow,
+            },
+            Dec(o, n) => match at(o) {
+                Some(0) => iteration_diff.dec(n),
+                Some(_) => (),
+                None => return LoopVerdict::OffsetOverflow,
+            },
+            Zero(o) => match at(o) {
+                Some(0) => iteration_diff.set(0),
+                Some(_) => (),
+                None => return LoopVerdict::OffsetOverflow,
+            },
+            Set(o, n) => match at(o) {
+                Some(0) => iteration_diff.set(n),
+                Some(_) => (),
+                None => return LoopVerdict::OffsetOverflow,
+            },
+            Output(_) | PrintDecimal(_) | OutputConst(_) | Input(_) | Pipe | JumpZ(_)
+            | JumpNz(_) | Add(_) | Sub(_) | AddMul(..) | SubMul(..) | CopyRange(..) | DivMod => {
+                return LoopVerdict::Unsupported(*inst)
             }
         }
     }
 
     if offset != 0 {
-        return;
+        return LoopVerdict::Unbalanced(offset);
     }
 
     match iteration_diff {
         // The loop is decremented by 1 in each iteration
-        Update::Relative(-1) => (),
+        Update::Relative(-1) => LoopVerdict::Arithmetic,
         // The loop always zeros the iteration register
-        Update::Absolute(0) => {
-            if config.o_jumps {
-                let JumpNz(jump) = &mut instructions[end] else {
-                    unreachable!();
-                };
-                *jump = Jump::Redundant;
-                if config.verbose >= 2 {
-                    println!("redundant jump if not zero at {}", end);
-                }
-            }
-            return;
-        }
+        Update::Absolute(0) => LoopVerdict::RunsOnce,
         // The loop doesn't alter the value inside the iteration register, or sets it to a constant
         // non zero value
-        Update::Relative(0) | Update::Absolute(_) => {
-            if !end_jump.is_redundant() {
-                let range = start - 1..end + 1;
-                let l = &instructions[range.clone()];
-                warn!("infinite loop detected at {range:?}:\n{l:?}");
-            }
-            return;
-        }
+        Update::Relative(0) | Update::Absolute(_) => LoopVerdict::Infinite,
         // An relative increment other than -1, the loop might never complete depending on how the
         // iteration register was initialized
         // TODO: handle +1 case
-        Update::Relative(_) => return,
+        Update::Relative(d) => LoopVerdict::Step(d),
     }
+}
 
-    if !config.o_arithmetic {
-        return;
+fn replace_arithmetic_loop(config: &Config, l: &mut Loop) -> Option<Vec<Node>> {
+    use Instruction::*;
+
+    let inner = (l.body.iter())
+        .map(|n| match n {
+            Node::Inst(inst) => *inst,
+            Node::Loop(_) => unreachable!("only called on innermost loops"),
+        })
+        .collect::<Vec<_>>();
+    match arithmetic_loop_verdict(&inner) {
+        LoopVerdict::Arithmetic => (),
+        LoopVerdict::RunsOnce => {
+            if config.pass(Pass::Jumps) {
+                l.end_redundant = true;
+                if config.verbose >= 2 {
+                    println!("redundant jump if not zero at the end of {inner:?}");
+                }
+            }
+            return None;
+        }
+        LoopVerdict::Unsupported(_) => return split_io_loop(config, l, &inner),
+        // reported by the `infinite_loop` lint
+        LoopVerdict::Infinite
+        | LoopVerdict::Unbalanced(_)
+        | LoopVerdict::OffsetOverflow
+        | LoopVerdict::Step(_) => return None,
+    }
+
+    if !config.pass(Pass::Arithmetic) {
+        return None;
     }
 
-    let mut offset = 0;
-    let mut replacements = Vec::with_capacity(num_arith + 1);
+    let mut offset = 0_i16;
+    let mut replacements = Vec::with_capacity(inner.len() + 1);
     for inst in inner.iter() {
         match *inst {
-            Shl(n) => offset -= n as i16,
-            Shr(n) => offset += n as i16,
+            Shl(n) => offset = offset.checked_sub_unsigned(n)?,
+            Shr(n) => offset = offset.checked_add_unsigned(n)?,
             Inc(o, n) => {
-                let offset = offset + o;
+                let offset = offset.checked_add(o)?;
                 if offset != 0 {
                     if let Some(set) = find_set_instruction_at_offset(&mut replacements, offset) {
                         *set.inst = Set(offset, set.prev_val.wrapping_add(n));
@@ -185,7 +520,7 @@ pub fn arithmetic_loop_pass(config: &Config, instructions: &mut Vec<Instruction>
                 }
             }
             Dec(o, n) => {
-                let offset = offset + o;
+                let offset = offset.checked_add(o)?;
                 if offset != 0 {
                     if let Some(set) = find_set_instruction_at_offset(&mut replacements, offset) {
                         *set.inst = Set(offset, set.prev_val.wrapping_add(n));
@@ -199,13 +534,13 @@ pub fn arithmetic_loop_pass(config: &Config, instructions: &mut Vec<Instruction>
                 }
             }
             Zero(o) => {
-                let offset = offset + o;
+                let offset = offset.checked_add(o)?;
                 if offset != 0 {
                     if let Some(set) = find_set_instruction_at_offset(&mut replacements, offset) {
                         *set.inst = Zero(offset)
                     } else {
                         replacements.extend([
-                            JumpZ(Jump::Location(NonZeroU32::MAX)),
+                            JumpZ(Jump::Unresolved),
                             Zero(offset),
                             JumpNz(Jump::Redundant),
                         ]);
@@ -213,31 +548,31 @@ pub fn arithmetic_loop_pass(config: &Config, instructions: &mut Vec<Instruction>
                 }
             }
             Set(o, n) => {
-                let offset = offset + o;
+                let offset = offset.checked_add(o)?;
                 if offset != 0 {
                     if let Some(set) = find_set_instruction_at_offset(&mut replacements, offset) {
                         *set.inst = Set(offset, n);
                     } else {
                         replacements.extend([
-                            JumpZ(Jump::Location(NonZeroU32::MAX)),
+                            JumpZ(Jump::Unresolved),
                             Zero(offset),
                             JumpNz(Jump::Redundant),
                         ]);
                     }
                 }
             }
-            Output | Input | JumpZ(_) | JumpNz(_) | Add(_) | Sub(_) | AddMul(..) | SubMul(..) => {
+            Output(_) | PrintDecimal(_) | OutputConst(_) | Input(_) | Pipe | JumpZ(_)
+            | JumpNz(_) | Add(_) | Sub(_) | AddMul(..) | SubMul(..) | CopyRange(..) | DivMod => {
                 unreachable!()
             }
         }
     }
     replacements.push(Zero(0));
 
-    let range = start - 1..end + 1;
     if config.verbose >= 2 {
-        println!("replaced {range:?} with {replacements:?}");
+        println!("replaced {inner:?} with {replacements:?}");
     }
-    _ = instructions.splice(range, replacements);
+    Some(ir::build(&replacements))
 }
 
 struct SetInstruction<'a> {
@@ -264,36 +599,264 @@ fn find_set_instruction_at_offset(
     None
 }
 
+/// The registers accessed by an instruction, relative to the register pointer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Registers {
+    None,
+    One(i16),
+    Two(i16, i16),
+    /// A range of registers, or registers that aren't known statically.
+    All,
+}
+
+impl Registers {
+    fn contains(self, offset: i16) -> bool {
+        match self {
+            Registers::None => false,
+            Registers::One(a) => a == offset,
+            Registers::Two(a, b) => a == offset || b == offset,
+            Registers::All => true,
+        }
+    }
+
+    fn overlaps(self, other: Registers) -> bool {
+        match self {
+            Registers::None => false,
+            Registers::One(a) => other.contains(a),
+            Registers::Two(a, b) => other.contains(a) || other.contains(b),
+            Registers::All => other != Registers::None,
+        }
+    }
+}
+
+/// The effects of an instruction, which decide whether it may be reordered with others, see
+/// [`commutes`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Effects {
+    pub reads: Registers,
+    pub writes: Registers,
+    /// The instruction reads from the input or writes to the output.
+    pub io: bool,
+    /// The instruction moves the register pointer or jumps, so the offsets of the instructions
+    /// before and after it aren't relative to the same register.
+    pub barrier: bool,
+}
+
+impl Effects {
+    pub fn of(inst: Instruction) -> Self {
+        use Instruction::*;
+        use Registers::{All, One, Two};
+
+        let (reads, writes, io, barrier) = match inst {
+            Shl(_) | Shr(_) | JumpZ(_) | JumpNz(_) => (One(0), Registers::None, false, true),
+            Inc(o, _) | Dec(o, _) => (One(o), One(o), false, false),
+            Zero(o) | Set(o, _) => (Registers::None, One(o), false, false),
+            Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) => (Two(0, o), One(o), false, false),
+            CopyRange(..) | DivMod => (All, All, false, false),
+            Output(o) | PrintDecimal(o) => (One(o), Registers::None, true, false),
+            OutputConst(_) => (Registers::None, Registers::None, true, false),
+            Input(o) => (Registers::None, One(o), true, false),
+            Pipe => (All, All, true, true),
+        };
+        Self {
+            reads,
+            writes,
+            io,
+            barrier,
+        }
+    }
+}
+
+/// Whether swapping the adjacent instructions `a` and `b` doesn't change the behavior of the
+/// program. This is the effect-ordering model passes follow when moving instructions: I/O is
+/// never reordered with other I/O, so the output and the consumed input stay in order, nor with
+/// instructions writing the registers it reads or accessing the registers it writes, so the
+/// output observes the same register values, and the input is written before it's read.
+/// Instructions moving the pointer are never reordered, passes moving instructions past shifts,
+/// like [`combine_shifts`], adjust their offsets instead.
+pub fn commutes(a: Instruction, b: Instruction) -> bool {
+    let (a, b) = (Effects::of(a), Effects::of(b));
+    let conflict = a.barrier
+        || b.barrier
+        || (a.io && b.io)
+        || a.writes.overlaps(b.reads)
+        || a.writes.overlaps(b.writes)
+        || b.writes.overlaps(a.reads);
+    !conflict
+}
+
+/// Split an innermost loop containing I/O, which otherwise only performs arithmetic and
+/// decrements its iteration register by one, into the multiplications of the registers that
+/// nothing else in the loop accesses, followed by a loop performing the rest of the body. The
+/// shifts of the body are folded into the offsets of the remaining instructions. Returns the
+/// multiplications and the remaining body, or `None` if no register can be hoisted out.
+pub fn split_io_arithmetic_loop(
+    inner: &[Instruction],
+) -> Option<(Vec<Instruction>, Vec<Instruction>)> {
+    use Instruction::*;
+
+    let mut offset = 0_i16;
+    let mut body = Vec::with_capacity(inner.len());
+    for inst in inner.iter() {
+        let at = |o: i16| offset.checked_add(o);
+        let inst = match *inst {
+            Shl(n) => {
+                offset = offset.checked_sub_unsigned(n)?;
+                continue;
+            }
+            Shr(n) => {
+                offset = offset.checked_add_unsigned(n)?;
+                continue;
+            }
+            Inc(o, n) => Inc(at(o)?, n),
+            Dec(o, n) => Dec(at(o)?, n),
+            Zero(o) => Zero(at(o)?),
+            Set(o, n) => Set(at(o)?, n),
+            Output(o) => Output(at(o)?),
+            PrintDecimal(o) => PrintDecimal(at(o)?),
+            OutputConst(n) => OutputConst(n),
+            Input(o) => Input(at(o)?),
+            JumpZ(_) | JumpNz(_) | Add(_) | Sub(_) | AddMul(..) | SubMul(..) | CopyRange(..)
+            | DivMod | Pipe => return None,
+        };
+        body.push(inst);
+    }
+    if offset != 0 {
+        return None;
+    }
+
+    // the number of iterations is the initial value of the iteration register, as long as the
+    // I/O doesn't write to it
+    let (io, arithmetic): (Vec<_>, Vec<_>) = body.iter().partition(|i| Effects::of(**i).io);
+    if io.iter().any(|i| Effects::of(*i).writes.contains(0))
+        || arithmetic_loop_verdict(&arithmetic) != LoopVerdict::Arithmetic
+    {
+        return None;
+    }
+
+    // a register can be hoisted if it's only changed relatively, and all other instructions
+    // commute with the changes
+    let hoistable = |offset: i16| {
+        offset != 0
+            && body.iter().all(|&inst| match inst {
+                Inc(o, _) | Dec(o, _) if o == offset => true,
+                _ => commutes(Inc(offset, 1), inst),
+            })
+    };
+    let mut factors: Vec<(i16, i8)> = Vec::new();
+    let mut kept = Vec::with_capacity(body.len());
+    for &inst in body.iter() {
+        let (o, n) = match inst {
+            Inc(o, n) if hoistable(o) => (o, n as i8),
+            Dec(o, n) if hoistable(o) => (o, (n as i8).wrapping_neg()),
+            _ => {
+                kept.push(inst);
+                continue;
+            }
+        };
+        match factors.iter_mut().find(|(offset, _)| *offset == o) {
+            Some((_, factor)) => *factor = factor.wrapping_add(n),
+            None => factors.push((o, n)),
+        }
+    }
+    if factors.is_empty() {
+        return None;
+    }
+
+    let hoisted = (factors.into_iter())
+        .filter_map(|(offset, factor)| match factor {
+            ..=-2 => Some(SubMul(offset, factor.unsigned_abs())),
+            -1 => Some(Sub(offset)),
+            0 => None,
+            1 => Some(Add(offset)),
+            2.. => Some(AddMul(offset, factor as u8)),
+        })
+        .collect();
+    Some((hoisted, kept))
+}
+
+/// Replace an innermost loop containing I/O with the multiplications and the remaining loop of
+/// [`split_io_arithmetic_loop`]. The multiplications are executed before the loop, while the
+/// iteration register still holds the number of iterations.
+fn split_io_loop(config: &Config, l: &Loop, inner: &[Instruction]) -> Option<Vec<Node>> {
+    if !config.pass(Pass::Arithmetic) {
+        return None;
+    }
+    let (hoisted, kept) = split_io_arithmetic_loop(inner)?;
+    if config.verbose >= 2 {
+        println!("split {inner:?} into {hoisted:?} and the loop {kept:?}");
+    }
+    let mut replacements = hoisted.into_iter().map(Node::Inst).collect::<Vec<_>>();
+    replacements.push(Node::Loop(Loop {
+        start_redundant: l.start_redundant,
+        end_redundant: l.end_redundant,
+        body: kept.into_iter().map(Node::Inst).collect(),
+    }));
+    Some(replacements)
+}
+
+/// Execute the start of the program at compile time, until it reads input, or its control flow
+/// depends on a loop that can't be unrolled. The executed instructions are replaced with the
+/// output and the state of the tape they produce, and loops that are never entered are removed.
+/// At most `config.static_fuel` instructions are executed, so long running constant prefixes
+/// don't stall the compilation, the rest of the program is executed at run time.
 pub fn optimize_static_code(config: &Config, instructions: &mut Vec<Instruction>) {
     let mut registers = [0u8; NUM_REGISTERS];
     let mut rp: i16 = 0;
+    let mut output = Vec::new();
     let mut i = 0;
+    let mut steps = 0;
     while i < instructions.len() {
-        match static_code_execution_pass(config, instructions, i, &mut registers, &mut rp) {
+        let pass = match steps < config.static_fuel {
+            true => static_code_execution_pass(
+                config,
+                instructions,
+                i,
+                &mut registers,
+                &mut rp,
+                &mut output,
+            ),
+            false => {
+                if config.verbose >= 1 {
+                    let len = instructions.len();
+                    println!(
+                        "static evaluation ran out of fuel after {steps} steps, at instruction {i} of {len}"
+                    );
+                }
+                ControlFlow::Break(())
+            }
+        };
+        steps += 1;
+        match pass {
             ControlFlow::Continue(index_inc) => i += index_inc as usize,
             ControlFlow::Break(()) => {
-                if i > 0 && config.o_init {
-                    let all_set = instructions[0..i - 1]
-                        .iter()
-                        .all(|inst| matches!(inst, Instruction::Set(..)));
-                    let last_set_or_shr = matches!(
+                if i > 0 && config.pass(Pass::Init) {
+                    let all_const = instructions[0..i - 1].iter().all(|inst| {
+                        matches!(inst, Instruction::OutputConst(_) | Instruction::Set(..))
+                    });
+                    let last_const_or_shr = matches!(
                         instructions[i - 1],
-                        Instruction::Set(..) | Instruction::Shr(_)
+                        Instruction::OutputConst(_)
+                            | Instruction::Set(..)
+                            | Instruction::Shl(_)
+                            | Instruction::Shr(_)
                     );
-                    if all_set && last_set_or_shr {
+                    if all_const && last_const_or_shr {
                         return;
                     }
 
-                    let replacements = registers
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(i, n)| {
+                    let replacements = (output.iter().map(|&n| Instruction::OutputConst(n)))
+                        .chain(registers.iter().enumerate().filter_map(|(i, n)| {
                             if *n == 0 {
                                 return None;
                             }
                             Some(Instruction::Set(i as i16, *n))
+                        }))
+                        .chain(match rp {
+                            ..=-1 => Some(Instruction::Shl(rp.unsigned_abs())),
+                            0 => None,
+                            1.. => Some(Instruction::Shr(rp as u16)),
                         })
-                        .chain((rp != 0).then_some(Instruction::Shr(rp as u16)))
                         .collect::<Vec<_>>();
                     if config.verbose >= 2 {
                         let range = 0..i;
@@ -306,8 +869,15 @@ pub fn optimize_static_code(config: &Config, instructions: &mut Vec<Instruction>
         }
     }
 
-    // TODO: if all instructions could be executed at compile time the program doesn't produce any
-    // output and could thus be empty
+    // all instructions could be executed at compile time, so only the output remains
+    let only_output = (instructions.iter()).all(|inst| matches!(inst, Instruction::OutputConst(_)));
+    if config.pass(Pass::Init) && !only_output {
+        let replacements = output.iter().map(|&n| Instruction::OutputConst(n));
+        if config.verbose >= 2 {
+            println!("replaced the whole program with its output {output:?}");
+        }
+        instructions.splice(.., replacements);
+    }
 }
 
 fn static_code_execution_pass(
@@ -316,28 +886,62 @@ fn static_code_execution_pass(
     i: usize,
     registers: &mut [u8; NUM_REGISTERS],
     rp: &mut i16,
+    output: &mut Vec<u8>,
 ) -> ControlFlow<(), IndexInc> {
+    if let (Some(Instruction::JumpZ(_)), Some(&val)) =
+        (instructions.get(i), registers.get(*rp as usize))
+    {
+        if val != 0
+            && config.pass(Pass::Unroll)
+            && unroll_loop(config, instructions, i, registers, *rp)
+        {
+            return ControlFlow::Continue(IndexInc::Zero);
+        }
+    }
+
     let Some(inst) = instructions.get_mut(i) else {
         unreachable!()
     };
 
+    // out of bounds accesses are left to be reported at runtime, and by the `tape_underflow` lint
+    if let Some(range) = inst_accesses(inst) {
+        if !range.shift(*rp as i32).is_within(NUM_REGISTERS) {
+            if config.verbose >= 1 {
+                println!("static evaluation stopped at instruction {i}, which accesses a register off the tape");
+            }
+            return ControlFlow::Break(());
+        }
+    }
+
     match inst {
-        Instruction::Shl(n) => *rp -= *n as i16,
-        Instruction::Shr(n) => *rp += *n as i16,
-        Instruction::Inc(o, n) => {
-            let r = &mut registers[(*rp + *o) as usize];
-            *r = r.wrapping_add(*n);
-        }
-        Instruction::Dec(o, n) => {
-            let r = &mut registers[(*rp + *o) as usize];
-            *r = r.wrapping_sub(*n);
-        }
-        Instruction::Output => return ControlFlow::Break(()),
-        Instruction::Input => return ControlFlow::Break(()),
+        Instruction::Shl(n) => match rp.checked_sub_unsigned(*n) {
+            Some(p) => *rp = p,
+            None => return ControlFlow::Break(()),
+        },
+        Instruction::Shr(n) => match rp.checked_add_unsigned(*n) {
+            Some(p) => *rp = p,
+            None => return ControlFlow::Break(()),
+        },
+        // the output is only known to be constant if it's replaced together with the initialization
+        Instruction::Output(o) if config.pass(Pass::Init) => {
+            output.push(registers[(*rp + *o) as usize]);
+        }
+        Instruction::PrintDecimal(o) if config.pass(Pass::Init) => {
+            let mut buf = [0; 3];
+            output.extend_from_slice(interp::decimal_digits(
+                registers[(*rp + *o) as usize],
+                &mut buf,
+            ));
+        }
+        Instruction::OutputConst(n) if config.pass(Pass::Init) => output.push(*n),
+        Instruction::Output(_) | Instruction::PrintDecimal(_) | Instruction::OutputConst(_) => {
+            return ControlFlow::Break(())
+        }
+        Instruction::Input(_) | Instruction::Pipe => return ControlFlow::Break(()),
         Instruction::JumpZ(jump) => {
             let val = registers[*rp as usize];
             if val != 0 {
-                if config.o_jumps {
+                if config.pass(Pass::Jumps) {
                     if config.verbose >= 2 {
                         println!("redundant jump if zero at {}", i);
                     }
@@ -345,7 +949,7 @@ fn static_code_execution_pass(
                 }
                 return ControlFlow::Break(());
             }
-            if !config.o_dead_code {
+            if !config.pass(Pass::DeadCode) {
                 return ControlFlow::Break(());
             }
 
@@ -353,125 +957,410 @@ fn static_code_execution_pass(
             return ControlFlow::Continue(IndexInc::Zero);
         }
         Instruction::JumpNz(_) => return ControlFlow::Break(()),
+        Instruction::Inc(..)
+        | Instruction::Dec(..)
+        | Instruction::Zero(_)
+        | Instruction::Set(..)
+        | Instruction::Add(_)
+        | Instruction::Sub(_)
+        | Instruction::AddMul(..)
+        | Instruction::SubMul(..)
+        | Instruction::CopyRange(..)
+        | Instruction::DivMod => execute_static(*inst, registers, *rp),
+    }
 
-        Instruction::Zero(o) => registers[(*rp + *o) as usize] = 0,
-        Instruction::Set(o, n) => registers[(*rp + *o) as usize] = *n,
-        Instruction::Add(o) => {
-            let val = registers[*rp as usize];
-            let r = &mut registers[(*rp + *o) as usize];
+    ControlFlow::Continue(IndexInc::One)
+}
+
+/// Execute the instruction `inst` at compile time on the `registers`, if it only changes
+/// registers: it doesn't move the register pointer, jump or perform I/O. The registers it accesses
+/// relative to `rp` have to be on the tape, see [`inst_accesses`].
+fn execute_static(inst: Instruction, registers: &mut [u8; NUM_REGISTERS], rp: i16) {
+    use Instruction::*;
+
+    let idx = |o: i16| (rp + o) as usize;
+    match inst {
+        Inc(o, n) => {
+            let r = &mut registers[idx(o)];
+            *r = r.wrapping_add(n);
+        }
+        Dec(o, n) => {
+            let r = &mut registers[idx(o)];
+            *r = r.wrapping_sub(n);
+        }
+        Zero(o) => registers[idx(o)] = 0,
+        Set(o, n) => registers[idx(o)] = n,
+        Add(o) => {
+            let val = registers[idx(0)];
+            let r = &mut registers[idx(o)];
             *r = r.wrapping_add(val);
         }
-        Instruction::Sub(o) => {
-            let val = registers[*rp as usize];
-            let r = &mut registers[(*rp + *o) as usize];
+        Sub(o) => {
+            let val = registers[idx(0)];
+            let r = &mut registers[idx(o)];
             *r = r.wrapping_sub(val);
         }
-        Instruction::AddMul(o, n) => {
-            let val = registers[*rp as usize];
-            let r = &mut registers[(*rp + *o) as usize];
+        AddMul(o, n) => {
+            let val = registers[idx(0)];
+            let r = &mut registers[idx(o)];
             *r = r.wrapping_add(n.wrapping_mul(val));
         }
-        Instruction::SubMul(o, n) => {
-            let val = registers[*rp as usize];
-            let r = &mut registers[(*rp + *o) as usize];
+        SubMul(o, n) => {
+            let val = registers[idx(0)];
+            let r = &mut registers[idx(o)];
             *r = r.wrapping_sub(n.wrapping_mul(val));
         }
+        CopyRange(s, d, n) => {
+            let src = idx(s);
+            registers.copy_within(src..src + n as usize, idx(d));
+        }
+        DivMod => interp::div_mod(&mut registers[idx(0)..idx(0) + 6]),
+        Shl(_) | Shr(_) | Output(_) | PrintDecimal(_) | OutputConst(_) | Input(_) | Pipe
+        | JumpZ(_) | JumpNz(_) => unreachable!("{inst:?} doesn't only change registers"),
     }
+}
 
-    ControlFlow::Continue(IndexInc::One)
+/// Maximum number of instructions a loop may expand to when it is unrolled.
+const MAX_UNROLL_LEN: usize = 256;
+/// Maximum number of instructions executed while computing the trip count of a loop.
+const MAX_UNROLL_STEPS: usize = 1 << 16;
+
+/// Replace the loop starting at `start` with `k` copies of its body, if the statically known
+/// register state proves that it runs exactly `k` times.
+fn unroll_loop(
+    config: &Config,
+    instructions: &mut Vec<Instruction>,
+    start: usize,
+    registers: &mut [u8; NUM_REGISTERS],
+    rp: i16,
+) -> bool {
+    let end = find_matching_jump(instructions, start);
+    let body = &instructions[start + 1..end];
+    let Some(iterations) = count_loop_iterations(body, registers, rp) else {
+        return false;
+    };
+    if iterations * body.len() > MAX_UNROLL_LEN {
+        return false;
+    }
+
+    let replacements = body.repeat(iterations);
+    let range = start..end + 1;
+    if config.verbose >= 2 {
+        println!("unrolled loop {range:?} {iterations} times");
+    }
+    instructions.splice(range, replacements);
+
+    true
 }
 
-fn remove_dead_code(config: &Config, instructions: &mut Vec<Instruction>, start: usize) {
+fn find_matching_jump(instructions: &[Instruction], start: usize) -> usize {
     let mut jump_stack = 0;
-
     for (i, inst) in instructions[start..].iter().enumerate() {
         match inst {
             Instruction::JumpZ(_) => jump_stack += 1,
             Instruction::JumpNz(_) => {
                 jump_stack -= 1;
                 if jump_stack == 0 {
-                    let range = start..start + i + 1;
-                    if config.verbose >= 2 {
-                        println!("removed dead code at {range:?}");
-                    }
-                    instructions.drain(range);
-                    return;
+                    return start + i;
                 }
             }
             _ => (),
         }
     }
 
-    unreachable!()
+    unreachable!("mismatched brackets")
+}
+
+/// Execute the loop `body` on the `registers`, and count how often it is executed. Returns
+/// [`None`] if the loop performs input, leaves the tape or doesn't complete within
+/// [`MAX_UNROLL_STEPS`]. The registers are restored afterwards, only the ones the loop writes are
+/// saved, instead of copying the whole tape.
+fn count_loop_iterations(
+    body: &[Instruction],
+    registers: &mut [u8; NUM_REGISTERS],
+    rp: i16,
+) -> Option<usize> {
+    let mut saved = Vec::new();
+    let iterations = execute_loop(body, registers, rp, &mut saved);
+    for (idx, val) in saved.into_iter().rev() {
+        registers[idx] = val;
+    }
+    iterations
 }
 
-pub fn remove_redundant_jump_pairs(config: &Config, instructions: &mut Vec<Instruction>) {
+/// Execute the loop `body` like [`count_loop_iterations`], and append the index and the previous
+/// value of the registers it writes to `saved`.
+fn execute_loop(
+    body: &[Instruction],
+    registers: &mut [u8; NUM_REGISTERS],
+    mut rp: i16,
+    saved: &mut Vec<(usize, u8)>,
+) -> Option<usize> {
+    use Instruction::*;
+
+    let mut pairs = vec![0; body.len()];
     let mut jump_stack = Vec::new();
-    let mut i = 0;
-    while i < instructions.len() {
-        let inst = instructions[i];
+    for (i, inst) in body.iter().enumerate() {
         match inst {
-            Instruction::JumpZ(jump) => jump_stack.push((i, jump.is_redundant())),
-            Instruction::JumpNz(end_jump) => {
-                let Some((start_idx, start_redundant)) = jump_stack.pop() else {
+            JumpZ(_) => jump_stack.push(i),
+            JumpNz(_) => {
+                let Some(start) = jump_stack.pop() else {
                     unreachable!("mismatched brackets")
                 };
+                pairs[start] = i;
+                pairs[i] = start;
+            }
+            _ => (),
+        }
+    }
+
+    let current = |registers: &[u8; NUM_REGISTERS], rp: i16| {
+        usize::try_from(rp)
+            .ok()
+            .and_then(|i| registers.get(i).copied())
+    };
+    let mut iterations = 0;
+    let mut steps = 0;
+    loop {
+        iterations += 1;
+        if iterations > MAX_UNROLL_STEPS {
+            return None;
+        }
 
-                if start_redundant && end_jump.is_redundant() {
-                    if config.verbose >= 2 {
-                        println!("remove redundant jump pair at {start_idx} and {i}");
-                    }
-                    instructions.remove(i);
-                    instructions.remove(start_idx);
-
-                    i -= 2;
+        let mut ip = 0;
+        while let Some(&inst) = body.get(ip) {
+            steps += 1;
+            if steps > MAX_UNROLL_STEPS {
+                return None;
+            }
+
+            match inst {
+                Shl(n) => rp = rp.checked_sub_unsigned(n)?,
+                Shr(n) => rp = rp.checked_add_unsigned(n)?,
+                Output(_) | PrintDecimal(_) | OutputConst(_) => (),
+                Input(_) | Pipe => return None,
+                JumpZ(_) => {
+                    if current(registers, rp)? == 0 {
+                        ip = pairs[ip];
+                    }
+                }
+                JumpNz(_) => {
+                    if current(registers, rp)? != 0 {
+                        ip = pairs[ip];
+                    }
+                }
+                _ => {
+                    let range = inst_accesses(&inst).map(|r| r.shift(rp as i32));
+                    if let Some(range) = range {
+                        if !range.is_within(NUM_REGISTERS) {
+                            return None;
+                        }
+                        let (min, max) = (range.min as usize, range.max as usize);
+                        saved.extend((min..=max).map(|i| (i, registers[i])));
+                    }
+                    execute_static(inst, registers, rp);
+                }
+            }
+
+            ip += 1;
+        }
+
+        if current(registers, rp)? == 0 {
+            return Some(iterations);
+        }
+    }
+}
+
+fn remove_dead_code(config: &Config, instructions: &mut Vec<Instruction>, start: usize) {
+    let mut jump_stack = 0;
+
+    for (i, inst) in instructions[start..].iter().enumerate() {
+        match inst {
+            Instruction::JumpZ(_) => jump_stack += 1,
+            Instruction::JumpNz(_) => {
+                jump_stack -= 1;
+                if jump_stack == 0 {
+                    let range = start..start + i + 1;
+                    if config.verbose >= 2 {
+                        println!("removed dead code at {range:?}");
+                    }
+                    instructions.drain(range);
+                    return;
                 }
             }
             _ => (),
         }
-        i += 1;
     }
-    if !jump_stack.is_empty() {
-        unreachable!("mismatched brackets")
+
+    unreachable!()
+}
+
+/// Mark the closing jumps of loops that always zero the current register at the end of their body
+/// as redundant. These loops execute their body at most once, like an `if` statement, so no back
+/// edge has to be generated, and the closing check can be skipped.
+pub fn mark_if_loops(config: &Config, nodes: &mut [Node]) {
+    mark_if_loops_at(config, nodes, &mut 0);
+}
+
+/// Mark the loops of the `nodes`, whose first instruction is at index `pos` of the lowered
+/// program, `pos` is advanced past them.
+fn mark_if_loops_at(config: &Config, nodes: &mut [Node], pos: &mut usize) {
+    for node in nodes.iter_mut() {
+        *pos += 1;
+        let Node::Loop(l) = node else {
+            continue;
+        };
+        mark_if_loops_at(config, &mut l.body, pos);
+        if !l.end_redundant && is_zeroed_at_end(&l.body) {
+            l.end_redundant = true;
+            if config.verbose >= 2 {
+                println!("redundant jump if not zero at {pos}, the loop is an if statement");
+            }
+        }
+        *pos += 1;
     }
 }
 
-pub fn simplify_code(config: &Config, instructions: &mut Vec<Instruction>) {
+/// Whether the current register is always zero at the end of the loop `body`.
+fn is_zeroed_at_end(body: &[Node]) -> bool {
     use Instruction::*;
 
-    let mut i = 0;
-    while i < instructions.len() {
-        let inst = instructions[i];
-        let index_inc = match inst {
-            Shl(n) => combine_shifts(config, instructions, i, -(n as i16)),
-            Shr(n) => combine_shifts(config, instructions, i, n as i16),
+    for node in body.iter().rev() {
+        let Node::Inst(inst) = node else {
+            return false;
+        };
+        match *inst {
+            Zero(0) | Set(0, 0) => return true,
+            // instructions that don't write to the current register
+            Inc(o, _) | Dec(o, _) | Zero(o) | Set(o, _) if o != 0 => (),
+            Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) | Input(o) if o != 0 => (),
+            Output(_) | PrintDecimal(_) | OutputConst(_) => (),
+            _ => return false,
+        }
+    }
+    false
+}
 
-            Inc(o, n) => combine_sets(config, instructions, i, o, Update::Relative(n as i8)),
-            Dec(o, n) => combine_sets(
-                config,
-                instructions,
-                i,
-                o,
-                Update::Relative((n as i8).wrapping_neg()),
-            ),
-            Zero(o) => combine_sets(config, instructions, i, o, Update::Absolute(0)),
-            Set(o, n) => combine_sets(config, instructions, i, o, Update::Absolute(n as i8)),
-
-            Add(o) => combine_add_sub(config, instructions, i, o, 1),
-            Sub(o) => combine_add_sub(config, instructions, i, o, -1),
-            AddMul(o, n) => combine_add_sub(config, instructions, i, o, n as i8),
-            SubMul(o, n) => combine_add_sub(config, instructions, i, o, (n as i8).wrapping_neg()),
-
-            Output => IndexInc::One,
-            Input => IndexInc::One,
-            JumpZ(_) => IndexInc::One,
-            JumpNz(_) => IndexInc::One,
+/// Replace loops whose opening and closing jumps are both redundant with their body.
+pub fn remove_redundant_jump_pairs(config: &Config, nodes: &mut Vec<Node>) {
+    remove_redundant_jump_pairs_at(config, nodes, &mut 0);
+}
+
+/// Remove the redundant jump pairs of the `nodes`, whose first instruction is at index `pos` of
+/// the lowered program, `pos` is advanced past them.
+fn remove_redundant_jump_pairs_at(config: &Config, nodes: &mut Vec<Node>, pos: &mut usize) {
+    // the nodes are moved into a new list, splicing the bodies in place is quadratic
+    let mut removed = Vec::with_capacity(nodes.len());
+    for node in std::mem::take(nodes) {
+        let Node::Loop(mut l) = node else {
+            *pos += 1;
+            removed.push(node);
+            continue;
         };
+        let start = *pos;
+        *pos += 1;
+        remove_redundant_jump_pairs_at(config, &mut l.body, pos);
+        if l.start_redundant && l.end_redundant {
+            if config.verbose >= 2 {
+                println!("remove redundant jump pair at {start} and {pos}");
+            }
+            *pos -= 1;
+            removed.extend(l.body);
+        } else {
+            *pos += 1;
+            removed.push(Node::Loop(l));
+        }
+    }
+    *nodes = removed;
+}
+
+pub fn simplify_code(config: &Config, instructions: &mut Vec<Instruction>) {
+    // a window is moved over the instructions, so removing and replacing instructions inside of it
+    // stays cheap for large programs
+    let mut input = std::mem::take(instructions).into_iter();
+    let mut window = Vec::with_capacity(SIMPLIFY_WINDOW);
+    loop {
+        window.extend(input.by_ref().take(SIMPLIFY_WINDOW - window.len()));
+        let last = input.len() == 0;
+        let mut i = 0;
+        while i < window.len() && (last || i + SIMPLIFY_WINDOW / 2 < window.len()) {
+            i += simplify_instruction(config, &mut window, i) as usize;
+        }
+        instructions.extend(window.drain(..i));
+        if last {
+            break;
+        }
+    }
+}
+
+/// Run [`simplify_code`] on the runs of instructions between the loops of the `nodes`, the
+/// instructions aren't combined across loop boundaries anyway.
+fn simplify_nodes(config: &Config, nodes: &mut Vec<Node>) {
+    let mut simplified = Vec::with_capacity(nodes.len());
+    let mut run = Vec::new();
+    let flush = |run: &mut Vec<Instruction>, simplified: &mut Vec<Node>| {
+        simplify_code(config, run);
+        simplified.extend(run.drain(..).map(Node::Inst));
+    };
+    for node in std::mem::take(nodes) {
+        match node {
+            Node::Inst(inst) => run.push(inst),
+            Node::Loop(mut l) => {
+                flush(&mut run, &mut simplified);
+                simplify_nodes(config, &mut l.body);
+                simplified.push(Node::Loop(l));
+            }
+        }
+    }
+    flush(&mut run, &mut simplified);
+    *nodes = simplified;
+}
+
+/// Combine the instruction at `i` with the following ones.
+fn simplify_instruction(
+    config: &Config,
+    instructions: &mut Vec<Instruction>,
+    i: usize,
+) -> IndexInc {
+    use Instruction::*;
+
+    match instructions[i] {
+        // shifts that don't fit into an offset are left alone
+        Shl(n) => match 0_i16.checked_sub_unsigned(n) {
+            Some(shift) => combine_shifts(config, instructions, i, shift),
+            None => IndexInc::One,
+        },
+        Shr(n) => match 0_i16.checked_add_unsigned(n) {
+            Some(shift) => combine_shifts(config, instructions, i, shift),
+            None => IndexInc::One,
+        },
 
-        i += index_inc as usize;
+        Inc(o, n) => combine_sets(config, instructions, i, o, Update::Relative(n as i8)),
+        Dec(o, n) => combine_sets(
+            config,
+            instructions,
+            i,
+            o,
+            Update::Relative((n as i8).wrapping_neg()),
+        ),
+        Zero(o) => combine_sets(config, instructions, i, o, Update::Absolute(0)),
+        Set(o, n) => combine_sets(config, instructions, i, o, Update::Absolute(n as i8)),
+
+        Add(o) => combine_add_sub(config, instructions, i, o, 1),
+        Sub(o) => combine_add_sub(config, instructions, i, o, -1),
+        AddMul(o, n) => combine_add_sub(config, instructions, i, o, n as i8),
+        SubMul(o, n) => combine_add_sub(config, instructions, i, o, (n as i8).wrapping_neg()),
+        CopyRange(..) => IndexInc::One,
+        DivMod => IndexInc::One,
+
+        Output(_) | PrintDecimal(_) | OutputConst(_) => IndexInc::One,
+        Input(_) | Pipe => IndexInc::One,
+        JumpZ(_) => IndexInc::One,
+        JumpNz(_) => IndexInc::One,
     }
 }
 
+/// Combine consecutive shifts, I/O in between is moved before the shifts by adjusting its offset.
 fn combine_shifts(
     config: &Config,
     instructions: &mut Vec<Instruction>,
@@ -480,11 +1369,31 @@ fn combine_shifts(
 ) -> IndexInc {
     use Instruction::*;
 
+    let mut io = Vec::new();
     let mut i = start + 1;
     while i < instructions.len() {
         match instructions[i] {
-            Shl(n) => shift -= n as i16,
-            Shr(n) => shift += n as i16,
+            Shl(n) => match shift.checked_sub_unsigned(n) {
+                Some(s) => shift = s,
+                None => break,
+            },
+            Shr(n) => match shift.checked_add_unsigned(n) {
+                Some(s) => shift = s,
+                None => break,
+            },
+            Output(o) => match shift.checked_add(o) {
+                Some(o) => io.push(Output(o)),
+                None => break,
+            },
+            Input(o) => match shift.checked_add(o) {
+                Some(o) => io.push(Input(o)),
+                None => break,
+            },
+            PrintDecimal(o) => match shift.checked_add(o) {
+                Some(o) => io.push(PrintDecimal(o)),
+                None => break,
+            },
+            OutputConst(n) => io.push(OutputConst(n)),
             _ => break,
         }
 
@@ -497,22 +1406,27 @@ fn combine_shifts(
 
     let range = start..i;
     let replacement = match shift {
-        ..=-1 => Shl((-shift) as u16),
-        0 => {
-            if config.verbose >= 2 {
-                let removed = &instructions[range.clone()];
-                println!("remove redundant shifts {range:?} {removed:?}");
-            }
-            instructions.drain(range);
-            return IndexInc::Zero;
-        }
-        1.. => Shr(shift as u16),
+        ..=-1 => Some(Shl(shift.unsigned_abs())),
+        0 => None,
+        1.. => Some(Shr(shift as u16)),
     };
+    io.extend(replacement);
+    if !peephole::verify(config, "simplify", &instructions[range.clone()], &io) {
+        return IndexInc::One;
+    }
+    if io.is_empty() {
+        if config.verbose >= 2 {
+            let removed = &instructions[range.clone()];
+            println!("remove redundant shifts {range:?} {removed:?}");
+        }
+        instructions.drain(range);
+        return IndexInc::Zero;
+    }
     if config.verbose >= 2 {
         let removed = &instructions[range.clone()];
-        println!("simplify shifts {range:?} {removed:?} with {replacement:?}");
+        println!("simplify shifts {range:?} {removed:?} with {io:?}");
     }
-    instructions.splice(range, Some(replacement));
+    instructions.splice(range, io);
 
     IndexInc::One
 }
@@ -548,6 +1462,9 @@ fn combine_sets(
         Update::Relative(n) => match n {
             ..=-1 => Dec(offset, n.wrapping_neg() as u8),
             0 => {
+                if !peephole::verify(config, "simplify", &instructions[range.clone()], &[]) {
+                    return IndexInc::One;
+                }
                 if config.verbose >= 2 {
                     let removed = &instructions[range.clone()];
                     println!("removed redundant {range:?} {removed:?}");
@@ -560,6 +1477,14 @@ fn combine_sets(
         Update::Absolute(0) => Zero(offset),
         Update::Absolute(n) => Set(offset, n as u8),
     };
+    if !peephole::verify(
+        config,
+        "simplify",
+        &instructions[range.clone()],
+        &[replacement],
+    ) {
+        return IndexInc::One;
+    }
     if config.verbose >= 2 {
         let removed = &instructions[range.clone()];
         println!("simplify {range:?} {removed:?} with {replacement:?}");
@@ -589,6 +1514,9 @@ fn combine_add_sub(
             // if field is zeroed all instruction combined until here are redundant
             Zero(o) | Set(o, _) if o == offset => {
                 let range = start..i + 1;
+                if !peephole::verify(config, "simplify", &instructions[range.clone()], &[]) {
+                    return IndexInc::One;
+                }
                 if config.verbose >= 2 {
                     let removed = &instructions[range.clone()];
                     println!("removed redundant {range:?} {removed:?}");
@@ -608,8 +1536,11 @@ fn combine_add_sub(
     let range = start..i;
     let replacement = match factor {
         ..=-2 => SubMul(offset, -factor as u8),
-        ..=-1 => Sub(offset),
+        -1 => Sub(offset),
         0 => {
+            if !peephole::verify(config, "simplify", &instructions[range.clone()], &[]) {
+                return IndexInc::One;
+            }
             if config.verbose >= 2 {
                 let removed = &instructions[range.clone()];
                 println!("remove redundant {range:?} {removed:?}");
@@ -620,6 +1551,14 @@ fn combine_add_sub(
         1 => Add(offset),
         2.. => AddMul(offset, factor as u8),
     };
+    if !peephole::verify(
+        config,
+        "simplify",
+        &instructions[range.clone()],
+        &[replacement],
+    ) {
+        return IndexInc::One;
+    }
     if config.verbose >= 2 {
         let removed = &instructions[range.clone()];
         println!("simplify {range:?} {removed:?} with {replacement:?}");
@@ -628,3 +1567,1093 @@ fn combine_add_sub(
 
     IndexInc::One
 }
+
+/// Maximum number of consecutive nodes checked for printing a register as decimal number.
+const MAX_PRINT_NODES: usize = 32;
+/// Maximum number of instructions executed for a single register value, while checking if nodes
+/// print a register as decimal number.
+const MAX_PRINT_STEPS: usize = 1 << 14;
+/// Number of registers on either side of the start of the simulated nodes.
+const PRINT_TAPE_RADIUS: i16 = 256;
+
+/// Replace routines printing a register as decimal number with [`Instruction::PrintDecimal`].
+/// Consecutive nodes containing output are simulated for every possible value of the register,
+/// and replaced if it's the only register read before being written, it's printed as decimal
+/// number, and the state afterwards doesn't depend on its value. Other registers may only be read
+/// if they're known to be zero before the nodes.
+pub fn replace_print_decimal(config: &Config, instructions: &mut Vec<Instruction>) {
+    let mut nodes = ir::build(instructions);
+    // the tape starts out zeroed
+    if replace_print_decimal_nodes(config, &mut nodes, Some(BTreeSet::new())) {
+        *instructions = ir::lower(&nodes);
+    }
+}
+
+fn replace_print_decimal_nodes(
+    config: &Config,
+    nodes: &mut Vec<Node>,
+    mut nonzero: NonZero,
+) -> bool {
+    let mut replaced = false;
+    let mut start = 0;
+    while start < nodes.len() {
+        if let Node::Loop(l) = &mut nodes[start] {
+            let body_nonzero = nonzero_loop_entry(&nonzero, l);
+            replaced |= replace_print_decimal_nodes(config, &mut l.body, body_nonzero);
+        }
+
+        let max_end = nodes.len().min(start + MAX_PRINT_NODES);
+        // candidates have to contain an output
+        let ends = match nodes[start..max_end].iter().any(contains_output) {
+            true => print_decimal_ends(&nodes[start..max_end], &nonzero),
+            false => Vec::new(),
+        };
+        let found = (ends.into_iter().rev())
+            .map(|len| start + len)
+            .find_map(|end| Some((end, check_print_decimal(&nodes[start..end], &nonzero)?)));
+        let Some((end, replacement)) = found else {
+            nonzero_after_node(&mut nonzero, &nodes[start]);
+            start += 1;
+            continue;
+        };
+
+        if config.verbose >= 2 {
+            let num_instructions = ir::lower(&nodes[start..end]).len();
+            println!(
+                "replaced {num_instructions} instructions printing decimal with {replacement:?}"
+            );
+        }
+        for &inst in replacement.iter() {
+            nonzero_after_inst(&mut nonzero, inst);
+        }
+        let len = replacement.len();
+        nodes.splice(start..end, replacement.into_iter().map(Node::Inst));
+        start += len;
+        replaced = true;
+    }
+    replaced
+}
+
+/// Offsets relative to the register pointer of registers that might not be zero, or `None` if
+/// nothing is known about the registers.
+type NonZero = Option<BTreeSet<i16>>;
+
+fn nonzero_after_node(nonzero: &mut NonZero, node: &Node) {
+    match node {
+        Node::Inst(inst) => nonzero_after_inst(nonzero, *inst),
+        Node::Loop(l) => {
+            // the loop is exited once the current register is zero
+            *nonzero = loop_writes(l).and_then(|writes| {
+                let mut set = nonzero.take()?;
+                set.extend(writes);
+                set.remove(&0);
+                Some(set)
+            });
+        }
+    }
+}
+
+/// The registers that might not be zero at the start of any iteration of the loop.
+fn nonzero_loop_entry(nonzero: &NonZero, l: &Loop) -> NonZero {
+    let mut set = nonzero.clone()?;
+    set.extend(loop_writes(l)?);
+    set.insert(0);
+    Some(set)
+}
+
+/// The offsets of all registers a loop might write a nonzero value to, or `None` if the loop, or
+/// any nested loop, doesn't return to the register it started at.
+fn loop_writes(l: &Loop) -> Option<BTreeSet<i16>> {
+    let mut writes = BTreeSet::new();
+    let mut rp = 0_i16;
+    for node in l.body.iter() {
+        match node {
+            Node::Inst(Instruction::Shl(n)) => rp = rp.checked_sub(i16::try_from(*n).ok()?)?,
+            Node::Inst(Instruction::Shr(n)) => rp = rp.checked_add(i16::try_from(*n).ok()?)?,
+            Node::Inst(inst) => {
+                for o in inst_writes(*inst) {
+                    writes.insert(rp.checked_add(o)?);
+                }
+            }
+            Node::Loop(inner) => {
+                for o in loop_writes(inner)? {
+                    writes.insert(rp.checked_add(o)?);
+                }
+            }
+        }
+    }
+    (rp == 0).then_some(writes)
+}
+
+/// The offsets of all registers an instruction might write a nonzero value to.
+fn inst_writes(inst: Instruction) -> std::ops::Range<i16> {
+    use Instruction::*;
+
+    match inst {
+        Inc(o, _) | Dec(o, _) | Set(o, _) | Input(o) => o..o.saturating_add(1),
+        Pipe => 0..1,
+        Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) => o..o.saturating_add(1),
+        CopyRange(_, d, n) => d..d.saturating_add_unsigned(n),
+        DivMod => 0..6,
+        Shl(_) | Shr(_) | Output(_) | JumpZ(_) | JumpNz(_) | Zero(_) => 0..0,
+        PrintDecimal(_) | OutputConst(_) => 0..0,
+    }
+}
+
+fn nonzero_after_inst(nonzero: &mut NonZero, inst: Instruction) {
+    use Instruction::*;
+
+    let Some(set) = nonzero else {
+        return;
+    };
+    match inst {
+        Shl(n) | Shr(n) => {
+            let shift = match inst {
+                Shl(_) => i16::try_from(n).ok(),
+                _ => i16::try_from(n).ok().and_then(i16::checked_neg),
+            };
+            *nonzero = shift.and_then(|shift| set.iter().map(|o| o.checked_add(shift)).collect());
+        }
+        Zero(o) | Set(o, 0) => _ = set.remove(&o),
+        Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) => {
+            if set.contains(&0) {
+                set.insert(o);
+            }
+        }
+        CopyRange(s, d, n) => {
+            let src = (0..n as i16)
+                .map(|i| set.contains(&s.wrapping_add(i)))
+                .collect::<Vec<_>>();
+            for (i, nonzero) in (0..n as i16).zip(src) {
+                let o = d.wrapping_add(i);
+                if nonzero {
+                    set.insert(o);
+                } else {
+                    set.remove(&o);
+                }
+            }
+        }
+        _ => set.extend(inst_writes(inst)),
+    }
+}
+
+fn contains_output(node: &Node) -> bool {
+    match node {
+        Node::Inst(inst) => matches!(inst, Instruction::Output(_) | Instruction::PrintDecimal(_)),
+        Node::Loop(l) => l.body.iter().any(contains_output),
+    }
+}
+
+/// The numbers of leading `nodes` that print a register as decimal number for the value 123, the
+/// candidates checked by [`check_print_decimal`]. A single simulation covers all of them, since
+/// the leading nodes are executed exactly like the start of all `nodes`.
+fn print_decimal_ends(nodes: &[Node], nonzero: &NonZero) -> Vec<usize> {
+    let mut instructions = Vec::new();
+    let mut ends = Vec::with_capacity(nodes.len());
+    for node in nodes.iter() {
+        instructions.extend(ir::lower(std::slice::from_ref(node)));
+        ends.push(instructions.len());
+    }
+    crate::update_jump_indices(&mut instructions);
+
+    let mut sim = PrintSimulation::new(&instructions, nonzero, 123, None);
+    let finished = sim.run().is_some();
+    let Some(completed) = sim.completed else {
+        return Vec::new();
+    };
+    // the nodes ending after the output was complete, and before the simulation failed
+    (ends.iter().enumerate())
+        .filter(|&(_, &end)| end >= completed && (finished || end <= sim.ip))
+        .map(|(i, _)| i + 1)
+        .collect()
+}
+
+/// Simulate the nodes for every value of the printed register, and generate the replacement
+/// instructions if they print it as decimal number.
+fn check_print_decimal(nodes: &[Node], nonzero: &NonZero) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+
+    let mut instructions = ir::lower(nodes);
+    crate::update_jump_indices(&mut instructions);
+
+    let mut offset = None;
+    let mut first_run = None;
+    // whether the printed register is restored, or always set to the same value
+    let (mut restored, mut is_constant, mut constant) = (true, true, None);
+    // start with a value printed using multiple digits, to reject most nodes early
+    for x in std::iter::once(123).chain(0..=255) {
+        let mut sim = PrintSimulation::new(&instructions, nonzero, x, offset);
+        sim.run()?;
+        offset = sim.offset;
+        let offset = offset?;
+
+        let idx = (offset + PRINT_TAPE_RADIUS) as usize;
+        let val = if sim.written[idx] {
+            sim.registers[idx]
+        } else {
+            x
+        };
+        restored &= val == x;
+        is_constant &= *constant.get_or_insert(val) == val;
+        let run = (sim.final_state(offset), sim.rp);
+        match &first_run {
+            None => first_run = Some(run),
+            Some(first) if *first != run => return None,
+            Some(_) => (),
+        }
+    }
+    let offset = offset?;
+    let (state, rp) = first_run?;
+
+    let mut replacement = vec![PrintDecimal(offset)];
+    let writes = state.into_iter().chain(match (restored, is_constant) {
+        (true, _) => None,
+        (false, true) => Some((offset, constant?)),
+        (false, false) => return None,
+    });
+    replacement.extend(writes.map(|(o, n)| match n {
+        0 => Zero(o),
+        _ => Set(o, n),
+    }));
+    match rp {
+        ..=-1 => replacement.push(Shl(rp.unsigned_abs())),
+        0 => (),
+        1.. => replacement.push(Shr(rp as u16)),
+    }
+    Some(replacement)
+}
+
+/// Execution of instructions, which only read a single register before writing it.
+struct PrintSimulation<'a> {
+    instructions: &'a [Instruction],
+    /// Registers that aren't known to be zero before the instructions.
+    nonzero: &'a NonZero,
+    registers: [u8; 2 * PRINT_TAPE_RADIUS as usize],
+    written: [bool; 2 * PRINT_TAPE_RADIUS as usize],
+    ip: usize,
+    rp: i16,
+    /// The value of the register read before being written.
+    x: u8,
+    /// The offset of the register read before being written, once it's known.
+    offset: Option<i16>,
+    output: Vec<u8>,
+    /// The instruction pointer after the output was complete, if it was.
+    completed: Option<usize>,
+}
+
+impl<'a> PrintSimulation<'a> {
+    fn new(
+        instructions: &'a [Instruction],
+        nonzero: &'a NonZero,
+        x: u8,
+        offset: Option<i16>,
+    ) -> Self {
+        Self {
+            instructions,
+            nonzero,
+            registers: [0; 2 * PRINT_TAPE_RADIUS as usize],
+            written: [false; 2 * PRINT_TAPE_RADIUS as usize],
+            ip: 0,
+            rp: 0,
+            x,
+            offset,
+            output: Vec::new(),
+            completed: None,
+        }
+    }
+
+    fn index(&self, o: i16) -> Option<usize> {
+        let idx = self.rp.checked_add(o)?.checked_add(PRINT_TAPE_RADIUS)?;
+        usize::try_from(idx)
+            .ok()
+            .filter(|&i| i < self.registers.len())
+    }
+
+    fn read(&mut self, idx: usize) -> Option<u8> {
+        if self.written[idx] {
+            return Some(self.registers[idx]);
+        }
+        let o = idx as i16 - PRINT_TAPE_RADIUS;
+        if self.is_known_zero(o) {
+            return Some(0);
+        }
+        match self.offset {
+            None => self.offset = Some(o),
+            Some(offset) if offset == o => (),
+            Some(_) => return None,
+        }
+        Some(self.x)
+    }
+
+    fn is_known_zero(&self, o: i16) -> bool {
+        self.nonzero.as_ref().is_some_and(|set| !set.contains(&o))
+    }
+
+    fn write(&mut self, idx: usize, val: u8) {
+        self.registers[idx] = val;
+        self.written[idx] = true;
+    }
+
+    fn print(&mut self, bytes: &[u8]) -> Option<()> {
+        self.output.extend_from_slice(bytes);
+        let mut buf = [0; 3];
+        let digits = interp::decimal_digits(self.x, &mut buf);
+        if !digits.starts_with(&self.output) {
+            return None;
+        }
+        if self.output.len() == digits.len() {
+            self.completed.get_or_insert(self.ip + 1);
+        }
+        Some(())
+    }
+
+    /// Run the instructions, and check that the complete output was printed.
+    fn run(&mut self) -> Option<()> {
+        use Instruction::*;
+
+        let mut steps = 0;
+        while let Some(&inst) = self.instructions.get(self.ip) {
+            steps += 1;
+            if steps > MAX_PRINT_STEPS {
+                return None;
+            }
+
+            match inst {
+                Shl(n) => self.rp = self.rp.checked_sub(i16::try_from(n).ok()?)?,
+                Shr(n) => self.rp = self.rp.checked_add(i16::try_from(n).ok()?)?,
+                Inc(o, n) => {
+                    let idx = self.index(o)?;
+                    let val = self.read(idx)?;
+                    self.write(idx, val.wrapping_add(n));
+                }
+                Dec(o, n) => {
+                    let idx = self.index(o)?;
+                    let val = self.read(idx)?;
+                    self.write(idx, val.wrapping_sub(n));
+                }
+                Output(o) => {
+                    let val = self.read(self.index(o)?)?;
+                    self.print(&[val])?;
+                }
+                PrintDecimal(o) => {
+                    let val = self.read(self.index(o)?)?;
+                    self.print(interp::decimal_digits(val, &mut [0; 3]))?;
+                }
+                OutputConst(n) => self.print(&[n])?,
+                Input(_) | Pipe => return None,
+                JumpZ(Jump::Unresolved) | JumpNz(Jump::Unresolved) => {
+                    unreachable!("unresolved jump")
+                }
+                JumpZ(jump) => {
+                    let val = self.read(self.index(0)?)?;
+                    if let Jump::Location(loc) = jump {
+                        if val == 0 {
+                            self.ip = loc.get() as usize;
+                            continue;
+                        }
+                    }
+                }
+                JumpNz(jump) => {
+                    let val = self.read(self.index(0)?)?;
+                    if let Jump::Location(loc) = jump {
+                        if val != 0 {
+                            self.ip = loc.get() as usize;
+                            continue;
+                        }
+                    }
+                }
+                Zero(o) => self.write(self.index(o)?, 0),
+                Set(o, n) => self.write(self.index(o)?, n),
+                Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) => {
+                    let factor = match inst {
+                        Add(_) => 1,
+                        Sub(_) => u8::MAX,
+                        AddMul(_, n) => n,
+                        SubMul(_, n) => n.wrapping_neg(),
+                        _ => unreachable!(),
+                    };
+                    let val = self.read(self.index(0)?)?;
+                    let idx = self.index(o)?;
+                    let r = self.read(idx)?;
+                    self.write(idx, r.wrapping_add(factor.wrapping_mul(val)));
+                }
+                CopyRange(s, d, n) => {
+                    let mut vals = Vec::with_capacity(n as usize);
+                    for i in 0..n as i16 {
+                        let idx = self.index(s.checked_add(i)?)?;
+                        vals.push(self.read(idx)?);
+                    }
+                    for (i, val) in (0..n as i16).zip(vals) {
+                        let idx = self.index(d.checked_add(i)?)?;
+                        self.write(idx, val);
+                    }
+                }
+                DivMod => {
+                    let mut registers = [0; 6];
+                    for (i, r) in (0..6).zip(registers.iter_mut()) {
+                        *r = self.read(self.index(i)?)?;
+                    }
+                    let prev = registers;
+                    interp::div_mod(&mut registers);
+                    for (i, (r, p)) in (0..6).zip(registers.into_iter().zip(prev)) {
+                        if r != p {
+                            self.write(self.index(i)?, r);
+                        }
+                    }
+                }
+            }
+            self.ip += 1;
+        }
+
+        let mut buf = [0; 3];
+        (self.output == interp::decimal_digits(self.x, &mut buf)).then_some(())
+    }
+
+    /// The offsets and values of all changed registers, except the one at `offset`.
+    fn final_state(&self, offset: i16) -> Vec<(i16, u8)> {
+        (self.written.iter().zip(self.registers.iter()).enumerate())
+            .filter(|(_, (&written, _))| written)
+            .map(|(i, (_, &val))| (i as i16 - PRINT_TAPE_RADIUS, val))
+            .filter(|&(o, val)| o != offset && !(val == 0 && self.is_known_zero(o)))
+            .collect()
+    }
+}
+
+/// Minimum number of adjacent registers copied, for a run of instructions to be replaced.
+const MIN_COPY_LEN: u16 = 2;
+
+/// The value of a register after a run of instructions, relative to the state before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    Const(u8),
+    /// The value of the register at the offset before the run.
+    Copy(i16),
+    Unknown,
+}
+
+impl Value {
+    /// The value of `self + factor * src`.
+    fn add(self, src: Value, factor: u8) -> Value {
+        match (self, src) {
+            (_, Value::Const(0)) => self,
+            _ if factor == 0 => self,
+            (Value::Const(a), Value::Const(b)) => {
+                Value::Const(a.wrapping_add(factor.wrapping_mul(b)))
+            }
+            (Value::Const(0), _) if factor == 1 => src,
+            _ => Value::Unknown,
+        }
+    }
+}
+
+/// Replace runs of instructions copying ranges of adjacent registers, like the nested loops used
+/// to move or copy values with a temporary register, with [`Instruction::CopyRange`].
+pub fn replace_copies(config: &Config, instructions: &mut Vec<Instruction>) {
+    use Instruction::*;
+
+    let mut start = 0;
+    while start < instructions.len() {
+        let end = (start..instructions.len())
+            .find(|&i| {
+                matches!(
+                    instructions[i],
+                    JumpZ(_)
+                        | JumpNz(_)
+                        | Output(_)
+                        | PrintDecimal(_)
+                        | OutputConst(_)
+                        | Input(_)
+                        | Pipe
+                        | CopyRange(..)
+                        | DivMod
+                )
+            })
+            .unwrap_or(instructions.len());
+
+        let range = start..end;
+        start = end + 1;
+        let Some(replacement) = synthesize_copies(&instructions[range.clone()]) else {
+            continue;
+        };
+        if replacement.len() >= range.len() {
+            continue;
+        }
+        if !peephole::verify(config, "copy", &instructions[range.clone()], &replacement) {
+            continue;
+        }
+        if config.verbose >= 2 {
+            let removed = &instructions[range.clone()];
+            println!("replaced copies {range:?} {removed:?} with {replacement:?}");
+        }
+        start -= range.len() - replacement.len();
+        instructions.splice(range, replacement);
+    }
+}
+
+/// Generate an equivalent sequence of instructions for a run of instructions without jumps or
+/// I/O, if every register it writes to ends up holding either a constant, or the value of another
+/// register before the run, and at least [`MIN_COPY_LEN`] adjacent registers are copied.
+fn synthesize_copies(run: &[Instruction]) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+
+    let mut values = BTreeMap::new();
+    let value = |values: &BTreeMap<i16, Value>, o: i16| *values.get(&o).unwrap_or(&Value::Copy(o));
+    let mut rp: i16 = 0;
+    for inst in run.iter() {
+        let (o, src, factor) = match *inst {
+            Shl(n) => {
+                rp = rp.checked_sub(i16::try_from(n).ok()?)?;
+                continue;
+            }
+            Shr(n) => {
+                rp = rp.checked_add(i16::try_from(n).ok()?)?;
+                continue;
+            }
+            Inc(o, n) => (o, Value::Const(n), 1),
+            Dec(o, n) => (o, Value::Const(n), u8::MAX),
+            Zero(o) => {
+                values.insert(rp.checked_add(o)?, Value::Const(0));
+                continue;
+            }
+            Set(o, n) => {
+                values.insert(rp.checked_add(o)?, Value::Const(n));
+                continue;
+            }
+            Add(o) => (o, value(&values, rp), 1),
+            Sub(o) => (o, value(&values, rp), u8::MAX),
+            AddMul(o, n) => (o, value(&values, rp), n),
+            SubMul(o, n) => (o, value(&values, rp), n.wrapping_neg()),
+            Output(_) | PrintDecimal(_) | OutputConst(_) | Input(_) | Pipe | JumpZ(_)
+            | JumpNz(_) | CopyRange(..) | DivMod => {
+                unreachable!()
+            }
+        };
+        let o = rp.checked_add(o)?;
+        values.insert(o, value(&values, o).add(src, factor));
+    }
+
+    // adjacent registers copied from adjacent registers, as `(src, dst, len)`
+    let mut copies: Vec<(i16, i16, u16)> = Vec::new();
+    let mut consts = Vec::new();
+    for (&o, &val) in values.iter() {
+        match val {
+            Value::Const(n) => consts.push((o, n)),
+            Value::Copy(src) if src == o => (),
+            Value::Copy(src) => match copies.last_mut() {
+                Some((s, d, len)) if *s + *len as i16 == src && *d + *len as i16 == o => *len += 1,
+                _ => copies.push((src, o, 1)),
+            },
+            Value::Unknown => return None,
+        }
+    }
+    if copies.iter().all(|&(_, _, len)| len < MIN_COPY_LEN) {
+        return None;
+    }
+
+    // all copies read the registers before the run, so no source may be overwritten by another copy
+    let overlaps = |a: i16, b: i16, len_a: u16, len_b: u16| {
+        (a as i32) < b as i32 + len_b as i32 && (b as i32) < a as i32 + len_a as i32
+    };
+    for (i, &(_, dst, dst_len)) in copies.iter().enumerate() {
+        for (j, &(src, _, src_len)) in copies.iter().enumerate() {
+            if i != j && overlaps(dst, src, dst_len, src_len) {
+                return None;
+            }
+        }
+    }
+
+    let mut replacement = Vec::with_capacity(copies.len() + consts.len() + 1);
+    replacement.extend(copies.iter().map(|&(s, d, len)| CopyRange(s, d, len)));
+    replacement.extend(consts.iter().map(|&(o, n)| match n {
+        0 => Zero(o),
+        _ => Set(o, n),
+    }));
+    match rp {
+        ..=-1 => replacement.push(Shl(rp.unsigned_abs())),
+        0 => (),
+        1.. => replacement.push(Shr(rp as u16)),
+    }
+    Some(replacement)
+}
+
+/// The possible offsets of the register pointer relative to the start, or the offsets of the
+/// registers accessed by a program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PointerRange {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl PointerRange {
+    const START: PointerRange = PointerRange { min: 0, max: 0 };
+
+    /// The smallest range containing both ranges.
+    pub fn join(self, other: PointerRange) -> PointerRange {
+        PointerRange {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn shift(self, n: i32) -> PointerRange {
+        PointerRange {
+            min: self.min.saturating_add(n),
+            max: self.max.saturating_add(n),
+        }
+    }
+
+    /// Whether the range lies inside a tape of `len` registers beginning at the start.
+    pub fn is_within(self, len: usize) -> bool {
+        self.min >= 0 && (self.max as usize) < len
+    }
+}
+
+/// The possible offsets of the register pointer before each instruction, or `None` if they can't
+/// be bounded. Loops are only bounded if every iteration ends at the register it started at, if
+/// statements may move the register pointer. The jumps have to be balanced, so this can't be used
+/// after [`compact_jumps`].
+pub fn pointer_ranges(instructions: &[Instruction]) -> Vec<Option<PointerRange>> {
+    let mut ranges = vec![None; instructions.len()];
+    if let Some(pairs) = matching_jumps(instructions) {
+        let range = 0..instructions.len();
+        pointer_ranges_in(
+            instructions,
+            &pairs,
+            range,
+            PointerRange::START,
+            &mut ranges,
+        );
+    }
+    ranges
+}
+
+/// The index of the matching jump of every jump, or `None` if the jumps aren't balanced.
+fn matching_jumps(instructions: &[Instruction]) -> Option<Vec<usize>> {
+    let mut pairs = vec![0; instructions.len()];
+    let mut stack = Vec::new();
+    for (i, inst) in instructions.iter().enumerate() {
+        match inst {
+            Instruction::JumpZ(_) => stack.push(i),
+            Instruction::JumpNz(_) => {
+                let start = stack.pop()?;
+                pairs[start] = i;
+                pairs[i] = start;
+            }
+            _ => (),
+        }
+    }
+    stack.is_empty().then_some(pairs)
+}
+
+/// Compute the ranges of the instructions in `range`, and return the range after them.
+fn pointer_ranges_in(
+    instructions: &[Instruction],
+    pairs: &[usize],
+    range: std::ops::Range<usize>,
+    mut rp: PointerRange,
+    ranges: &mut [Option<PointerRange>],
+) -> Option<PointerRange> {
+    let mut i = range.start;
+    while i < range.end {
+        ranges[i] = Some(rp);
+        match instructions[i] {
+            Instruction::Shl(n) => rp = rp.shift(-(n as i32)),
+            Instruction::Shr(n) => rp = rp.shift(n as i32),
+            Instruction::JumpZ(_) => {
+                let end = pairs[i];
+                // the ranges of the body only hold for the first iteration if the loop is unbounded
+                let Some(body) = pointer_ranges_in(instructions, pairs, i + 1..end, rp, ranges)
+                else {
+                    ranges[i..=end].fill(None);
+                    return None;
+                };
+                ranges[end] = Some(body);
+
+                // the body is skipped or executed until the current register is zero
+                let exit = rp.join(body);
+                let is_loop = !matches!(instructions[end], Instruction::JumpNz(Jump::Redundant));
+                if is_loop && exit != rp {
+                    ranges[i..=end].fill(None);
+                    return None;
+                }
+                rp = exit;
+                i = end;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    Some(rp)
+}
+
+/// The offsets of all registers the program might access relative to the start, or `None` if
+/// they can't be bounded, see [`pointer_ranges`].
+pub fn register_range(instructions: &[Instruction]) -> Option<PointerRange> {
+    let ranges = pointer_ranges(instructions);
+    let mut accessed = PointerRange::START;
+    for (inst, rp) in instructions.iter().zip(ranges) {
+        let rp = rp?;
+        let Some(range) = inst_accesses(inst) else {
+            continue;
+        };
+        accessed = accessed.join(PointerRange {
+            min: rp.min.saturating_add(range.min),
+            max: rp.max.saturating_add(range.max),
+        });
+    }
+    Some(accessed)
+}
+
+/// The offsets of the registers the instruction might access relative to the register pointer,
+/// or `None` if it doesn't access any.
+pub fn inst_accesses(inst: &Instruction) -> Option<PointerRange> {
+    use Instruction::*;
+
+    let (min, max) = match *inst {
+        Shl(_) | Shr(_) | OutputConst(_) => return None,
+        JumpZ(Jump::Redundant) | JumpNz(Jump::Redundant) => return None,
+        JumpZ(_) | JumpNz(_) => (0, 0),
+        Inc(o, _) | Dec(o, _) | Zero(o) | Set(o, _) => (o as i32, o as i32),
+        Output(o) | Input(o) | PrintDecimal(o) => (o as i32, o as i32),
+        Pipe => (0, 0),
+        Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) => (0.min(o as i32), 0.max(o as i32)),
+        CopyRange(_, _, 0) => return None,
+        CopyRange(s, d, n) => {
+            let (s, d) = (s as i32, d as i32);
+            (s.min(d), s.max(d) + n as i32 - 1)
+        }
+        // the divmod loop accesses up to six registers
+        DivMod => (0, 5),
+    };
+    Some(PointerRange { min, max })
+}
+
+/// The range of registers relative to the start inside `tape`, that might be read before being
+/// written, or `None` if there are none. Registers outside of it don't have to be zeroed before
+/// running the program. Only the start of the program up to the first jump is analyzed, and only
+/// registers at the edges of the range are excluded.
+pub fn zeroed_range(instructions: &[Instruction], tape: PointerRange) -> Option<PointerRange> {
+    use Instruction::*;
+
+    // whether the first access of a register is a write
+    let mut written_first = BTreeMap::new();
+    let mut access = |start: i32, len: u16, write: bool| {
+        for o in start..start + len as i32 {
+            written_first.entry(o).or_insert(write);
+        }
+    };
+    let mut rp: i32 = 0;
+    for inst in instructions.iter() {
+        match *inst {
+            Shl(n) => rp -= n as i32,
+            Shr(n) => rp += n as i32,
+            Inc(o, _) | Dec(o, _) | Output(o) | PrintDecimal(o) => access(rp + o as i32, 1, false),
+            // the register is left unchanged at the end of the input
+            Input(o) => access(rp + o as i32, 1, false),
+            Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) => {
+                access(rp, 1, false);
+                access(rp + o as i32, 1, false);
+            }
+            CopyRange(s, d, n) => {
+                access(rp + s as i32, n, false);
+                access(rp + d as i32, n, true);
+            }
+            Zero(o) | Set(o, _) => access(rp + o as i32, 1, true),
+            OutputConst(_) => (),
+            JumpZ(_) | JumpNz(_) | DivMod | Pipe => break,
+        }
+    }
+
+    let written_first = |o: i32| written_first.get(&o).copied().unwrap_or(false);
+    let PointerRange { mut min, mut max } = tape;
+    while min <= max && written_first(min) {
+        min += 1;
+    }
+    while min <= max && written_first(max) {
+        max -= 1;
+    }
+    (min <= max).then_some(PointerRange { min, max })
+}
+
+/// Whether all register accesses of the program are proven to stay inside the tape, so bounds
+/// checks can be skipped.
+pub fn in_bounds(config: &Config, instructions: &[Instruction]) -> bool {
+    if !config.pass(Pass::Bounds) {
+        return false;
+    }
+    let range = register_range(instructions);
+    if config.verbose >= 1 {
+        match range {
+            Some(r) => println!("accessed registers {}..={}", r.min, r.max),
+            None => println!("accessed registers unbounded"),
+        }
+        println!("============================================================");
+    }
+    range.is_some_and(|r| r.is_within(NUM_REGISTERS))
+}
+
+/// Report the register accesses the program might make outside of the [`PORTABLE_TAPE_LEN`]
+/// registers every implementation provides, left of the first register or beyond the last one.
+/// Accesses that can't be bounded aren't reported.
+pub fn portability_errors(instructions: &[Instruction]) -> Vec<String> {
+    let Some(range) = register_range(instructions) else {
+        return Vec::new();
+    };
+    let mut errors = Vec::new();
+    if range.min < 0 {
+        let min = range.min;
+        errors.push(format!(
+            "the program might access register {min}, left of the first register"
+        ));
+    }
+    if range.max as usize >= PORTABLE_TAPE_LEN {
+        let max = range.max;
+        errors.push(format!(
+            "the program might access register {max}, beyond the {PORTABLE_TAPE_LEN} registers portable programs can rely on"
+        ));
+    }
+    errors
+}
+
+/// Maximum number of jumps followed when threading a single jump.
+const MAX_THREAD_STEPS: usize = 64;
+
+/// Rewrite jumps landing on other jumps whose outcome is statically known, to directly jump to
+/// their final destination. This has to run after [`crate::update_jump_indices`], since the
+/// locations of threaded jumps no longer point after their matching bracket.
+pub fn thread_jumps(config: &Config, instructions: &mut [Instruction]) {
+    if !config.pass(Pass::Threading) {
+        return;
+    }
+
+    for i in 0..instructions.len() {
+        let (jump, zero) = match &instructions[i] {
+            Instruction::JumpZ(Jump::Location(loc)) => (*loc, true),
+            Instruction::JumpNz(Jump::Location(loc)) => (*loc, false),
+            _ => continue,
+        };
+        let target = thread_target(instructions, jump.get() as usize, zero);
+        if target == jump.get() as usize {
+            continue;
+        }
+
+        if config.verbose >= 2 {
+            println!("thread jump at {i} from {jump} to {target}");
+        }
+        let loc = NonZeroU32::new(target as u32).expect("jump targets are never the first index");
+        match &mut instructions[i] {
+            Instruction::JumpZ(jump) | Instruction::JumpNz(jump) => *jump = Jump::Location(loc),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Follow all jumps starting at `target`, whose outcome is known from the value of the current
+/// register being zero or non-zero.
+fn thread_target(instructions: &[Instruction], mut target: usize, zero: bool) -> usize {
+    for _ in 0..MAX_THREAD_STEPS {
+        match thread_step(instructions, target, zero) {
+            Some(next) => target = next,
+            None => break,
+        }
+    }
+    target
+}
+
+/// The location execution continues at, if the instruction at `target` is a jump.
+fn thread_step(instructions: &[Instruction], target: usize, zero: bool) -> Option<usize> {
+    let next = match instructions.get(target)? {
+        Instruction::JumpZ(Jump::Redundant) | Instruction::JumpNz(Jump::Redundant) => target + 1,
+        Instruction::JumpZ(Jump::Location(loc)) if zero => loc.get() as usize,
+        Instruction::JumpZ(Jump::Location(_)) => target + 1,
+        Instruction::JumpNz(Jump::Location(_)) if zero => target + 1,
+        Instruction::JumpNz(Jump::Location(loc)) => loc.get() as usize,
+        _ => return None,
+    };
+    Some(next)
+}
+
+/// Check that all jumps are resolved, and their locations point after their matching bracket, or
+/// to a location reachable from there by [`thread_jumps`].
+pub fn verify_jumps(instructions: &[Instruction]) -> Result<(), String> {
+    let mut resolved = instructions.to_vec();
+    crate::update_jump_indices(&mut resolved);
+
+    for (i, (inst, expected)) in instructions.iter().zip(resolved.iter()).enumerate() {
+        let (loc, expected, zero) = match (inst, expected) {
+            (Instruction::JumpZ(Jump::Unresolved) | Instruction::JumpNz(Jump::Unresolved), _) => {
+                return Err(format!("unresolved jump at {i}"));
+            }
+            (
+                Instruction::JumpZ(Jump::Location(loc)),
+                Instruction::JumpZ(Jump::Location(expected)),
+            ) => (loc.get() as usize, expected.get() as usize, true),
+            (
+                Instruction::JumpNz(Jump::Location(loc)),
+                Instruction::JumpNz(Jump::Location(expected)),
+            ) => (loc.get() as usize, expected.get() as usize, false),
+            _ => continue,
+        };
+
+        // jumps are threaded through already threaded jumps, so the chain is followed using the
+        // unthreaded locations, which can take more than `MAX_THREAD_STEPS` steps
+        let mut target = Some(expected);
+        let mut valid = false;
+        for _ in 0..=resolved.len() {
+            let Some(t) = target else { break };
+            if t == loc {
+                valid = true;
+                break;
+            }
+            target = thread_step(&resolved, t, zero);
+        }
+        if !valid {
+            return Err(format!(
+                "invalid jump location at {i}: {loc}, expected {expected} or a threaded location"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Remove redundant jumps, which are no-ops, and rewrite the locations of all remaining jumps.
+/// The brackets of the result are no longer balanced, so this has to be the last transformation
+/// before the instructions are interpreted.
+pub fn compact_jumps(config: &Config, instructions: &mut Vec<Instruction>) {
+    if !config.pass(Pass::Compact) {
+        return;
+    }
+
+    // the new index of every instruction, and of the end of the program
+    let mut new_indices = Vec::with_capacity(instructions.len() + 1);
+    let mut num_kept = 0;
+    for inst in instructions.iter() {
+        new_indices.push(num_kept as u32);
+        if !matches!(
+            inst,
+            Instruction::JumpZ(Jump::Redundant) | Instruction::JumpNz(Jump::Redundant)
+        ) {
+            num_kept += 1;
+        }
+    }
+    new_indices.push(num_kept as u32);
+
+    let prev_len = instructions.len();
+    instructions.retain(|inst| {
+        !matches!(
+            inst,
+            Instruction::JumpZ(Jump::Redundant) | Instruction::JumpNz(Jump::Redundant)
+        )
+    });
+    for inst in instructions.iter_mut() {
+        if let Instruction::JumpZ(Jump::Location(loc)) | Instruction::JumpNz(Jump::Location(loc)) =
+            inst
+        {
+            let new_loc = new_indices[loc.get() as usize];
+            *loc = NonZeroU32::new(new_loc).expect("jump targets are never the first index");
+        }
+    }
+
+    if config.verbose >= 2 {
+        println!("removed {} redundant jumps", prev_len - instructions.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{Interpreter, Program, TokenInterpreter};
+    use Instruction::*;
+
+    /// Run the `src` at `-O3` with `input`, and compare it to interpreting its tokens. Returns the
+    /// optimized instructions.
+    fn check(src: &str, input: &[u8]) -> Vec<Instruction> {
+        let tokens = crate::tokenize(src.as_bytes()).unwrap();
+        let mut expected = Vec::new();
+        let expected_status = TokenInterpreter::new(&tokens, input, &mut expected).run();
+
+        let config = Config::default().with_opt_level(3);
+        let instructions = crate::selftest::build(&config, src.as_bytes()).unwrap();
+        let program = Program::new(&config, instructions.clone());
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::from_config(&config, &program, input, &mut output);
+        let status = interpreter.run();
+        drop(interpreter);
+        assert_eq!(status, expected_status, "{src}");
+        assert_eq!(output, expected, "{src}");
+        instructions
+    }
+
+    #[test]
+    fn io_is_never_reordered() {
+        assert!(!commutes(Output(0), Input(1)));
+        assert!(!commutes(Output(0), OutputConst(b'a')));
+        assert!(!commutes(Input(0), Input(1)));
+        assert!(!commutes(Output(1), Inc(1, 1)));
+        assert!(!commutes(Input(1), Dec(1, 1)));
+        assert!(!commutes(Output(1), AddMul(1, 2)));
+        assert!(!commutes(Output(0), CopyRange(2, 1, 1)));
+        assert!(!commutes(Output(1), Shr(1)));
+
+        assert!(commutes(Output(0), Inc(1, 1)));
+        assert!(commutes(Input(0), Set(1, 3)));
+        assert!(commutes(OutputConst(b'a'), Zero(0)));
+    }
+
+    #[test]
+    fn registers_observed_by_io_stay_in_the_loop() {
+        // ,[.>,.>+.>++<<<-]
+        let inner = [
+            Output(0),
+            Shr(1),
+            Input(0),
+            Output(0),
+            Shr(1),
+            Inc(0, 1),
+            Output(0),
+            Shr(1),
+            Inc(0, 2),
+            Shl(3),
+            Dec(0, 1),
+        ];
+        let (hoisted, kept) = split_io_arithmetic_loop(&inner).unwrap();
+        assert_eq!(hoisted, [AddMul(3, 2)]);
+        assert_eq!(
+            kept,
+            [
+                Output(0),
+                Input(1),
+                Output(1),
+                Inc(2, 1),
+                Output(2),
+                Dec(0, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_without_hoistable_registers_are_kept() {
+        // the input overwrites the iteration register
+        let inner = [Input(0), Shr(1), Inc(0, 1), Shl(1), Dec(0, 1)];
+        assert_eq!(split_io_arithmetic_loop(&inner), None);
+        // the only other register is printed
+        let inner = [Shr(1), Inc(0, 1), Output(0), Shl(1), Dec(0, 1)];
+        assert_eq!(split_io_arithmetic_loop(&inner), None);
+        // the body doesn't return to the iteration register
+        let inner = [Output(0), Shr(1), Inc(0, 1), Dec(0, 1)];
+        assert_eq!(split_io_arithmetic_loop(&inner), None);
+    }
+
+    #[test]
+    fn io_arithmetic_loops_keep_their_output() {
+        let instructions = check(",[.>,.>+.>++<<<-]>>>.", b"\x03abc");
+        assert!(instructions.contains(&AddMul(3, 2)), "{instructions:?}");
+
+        let instructions = check(",[>+<.>.>++<<-]>>.>,[>+++<.-]>.", b"\x02\x03");
+        assert!(instructions.contains(&AddMul(2, 2)), "{instructions:?}");
+        assert!(instructions.contains(&AddMul(1, 3)), "{instructions:?}");
+
+        check("+++[>+<.>>,<<-]>.>.", b"xyz");
+        check("++[>-<.->++++<]>.", b"");
+    }
+}
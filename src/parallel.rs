@@ -0,0 +1,171 @@
+//! Processing batches of programs on multiple threads, used by the `test` mode and by the `run`
+//! and `check` modes when multiple programs are passed. The output of each program is captured
+//! while it's processed, and written in the order of the programs, so the output of a batch
+//! doesn't depend on the number of threads.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+
+/// The stack size of the worker threads, the same as the one of the main thread, since parsing
+/// and optimizing deeply nested loops recurses.
+const STACK_SIZE: usize = 8 << 20;
+
+/// The number of threads used if `--jobs` isn't passed.
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// The output captured while processing a program, in the order it was written.
+#[derive(Debug, Default)]
+pub struct Output {
+    chunks: Vec<(Stream, Vec<u8>)>,
+}
+
+impl Output {
+    fn push(&mut self, stream: Stream, bytes: &[u8]) {
+        match self.chunks.last_mut() {
+            Some((s, chunk)) if *s == stream => chunk.extend_from_slice(bytes),
+            _ => self.chunks.push((stream, bytes.to_vec())),
+        }
+    }
+
+    /// Write the captured output to stdout and stderr.
+    pub fn replay(&self) {
+        for (stream, bytes) in self.chunks.iter() {
+            match stream {
+                Stream::Stdout => {
+                    let mut stdout = std::io::stdout().lock();
+                    _ = stdout.write_all(bytes);
+                    _ = stdout.flush();
+                }
+                Stream::Stderr => _ = std::io::stderr().write_all(bytes),
+            }
+        }
+    }
+}
+
+/// The state of a thread capturing its output.
+struct Capture {
+    input: Arc<[u8]>,
+    output: Output,
+}
+
+thread_local! {
+    static CAPTURE: RefCell<Option<Capture>> = const { RefCell::new(None) };
+}
+
+/// Call `f` while capturing the output written by [`print`], [`eprint`] and [`Stdout`], which
+/// includes the diagnostics of the `error!` and `warn!` macros. Programs run by `f` read the
+/// `input`, see [`input`].
+pub fn capture<T>(input: Arc<[u8]>, f: impl FnOnce() -> T) -> (T, Output) {
+    CAPTURE.set(Some(Capture {
+        input,
+        output: Output::default(),
+    }));
+    let res = f();
+    let capture = CAPTURE.take().expect("set above");
+    (res, capture.output)
+}
+
+/// Whether the output of the thread is captured.
+pub fn capturing() -> bool {
+    CAPTURE.with_borrow(Option::is_some)
+}
+
+/// The input of programs run while the output is captured, each program reads all of it.
+pub fn input() -> Option<Arc<[u8]>> {
+    CAPTURE.with_borrow(|c| c.as_ref().map(|c| c.input.clone()))
+}
+
+/// Write to the captured output, or return the bytes if the output isn't captured.
+fn write_captured(stream: Stream, bytes: &[u8]) -> Result<(), ()> {
+    CAPTURE.with_borrow_mut(|c| match c {
+        Some(c) => {
+            c.output.push(stream, bytes);
+            Ok(())
+        }
+        None => Err(()),
+    })
+}
+
+/// Write to stdout, or to the captured output.
+pub fn print(args: std::fmt::Arguments) {
+    if write_captured(Stream::Stdout, args.to_string().as_bytes()).is_err() {
+        print!("{args}");
+    }
+}
+
+/// Write to stderr, or to the captured output.
+pub fn eprint(args: std::fmt::Arguments) {
+    if write_captured(Stream::Stderr, args.to_string().as_bytes()).is_err() {
+        eprint!("{args}");
+    }
+}
+
+/// Writes the output of programs to stdout, or to the captured output.
+pub struct Stdout;
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match write_captured(Stream::Stdout, buf) {
+            Ok(()) => Ok(buf.len()),
+            Err(()) => std::io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match capturing() {
+            true => Ok(()),
+            false => std::io::stdout().flush(),
+        }
+    }
+}
+
+/// Call `f` for each item on `jobs` threads, and pass the results to `emit` in the order of the
+/// items, each as soon as the results of all previous items were passed.
+pub fn map_ordered<'a, T: Sync, R: Send>(
+    items: &'a [T],
+    jobs: usize,
+    f: impl Fn(&T) -> R + Sync,
+    mut emit: impl FnMut(&'a T, R),
+) {
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            let (next, f, sender) = (&next, &f, sender.clone());
+            std::thread::Builder::new()
+                .stack_size(STACK_SIZE)
+                .spawn_scoped(s, move || loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    if sender.send((i, f(item))).is_err() {
+                        break;
+                    }
+                })
+                .expect("failed to spawn a worker thread");
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut done = 0;
+        for (i, res) in receiver {
+            pending.insert(i, res);
+            while let Some(res) = pending.remove(&done) {
+                emit(&items[done], res);
+                done += 1;
+            }
+        }
+    });
+}
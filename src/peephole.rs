@@ -0,0 +1,222 @@
+use crate::cli::Config;
+use crate::interp::{Interpreter, Limits, State, Status};
+use crate::rng::Rng;
+use crate::{error, Instruction, NUM_REGISTERS};
+
+/// Number of steps after which a rule example is considered to not terminate.
+const EXAMPLE_MAX_STEPS: u64 = 1 << 16;
+/// The register pointer when running a rule example, so it can move in both directions.
+const EXAMPLE_RP: i16 = 16;
+/// Input of rule examples.
+const EXAMPLE_INPUT: &[u8] = b"ab\0cd";
+/// Number of tapes a rewrite is checked with by `--verify-passes`.
+const VERIFY_TAPES: u32 = 16;
+/// The register pointer when checking a rewrite, in the middle of the tape so the offsets of the
+/// instructions stay inside of it.
+const VERIFY_RP: i16 = (NUM_REGISTERS / 2) as i16;
+/// Number of random registers on either side of the register pointer when checking a rewrite.
+const VERIFY_RADIUS: usize = 256;
+
+/// The number of matched instructions at the start of a window, and their replacement.
+pub type Rewrite = (usize, Vec<Instruction>);
+
+/// A peephole rewrite of a sequence of instructions, declared using [`rules!`].
+pub struct Rule {
+    pub name: &'static str,
+    /// Brainfuck code the rule applies to, used to check the rule.
+    pub example: &'static str,
+    pub apply: fn(&[Instruction]) -> Option<Rewrite>,
+}
+
+/// Declare peephole rules of the form:
+///
+/// ```text
+/// "name", "example": [pattern, ...] if guard => [replacement, ...];
+/// ```
+///
+/// The patterns are matched against consecutive instructions, and can bind operands used by the
+/// guard and the replacement. The variants of [`Instruction`] are in scope.
+macro_rules! rules {
+    ($(
+        $name:literal, $example:literal:
+        [$($pat:pat),+ $(,)?] $(if $guard:expr)? => [$($rep:expr),* $(,)?];
+    )*) => {
+        &[$($crate::peephole::Rule {
+            name: $name,
+            example: $example,
+            apply: |window| {
+                #[allow(unused_imports)]
+                use $crate::Instruction::*;
+                // matched by value, so the bindings are copies of the operands
+                match *window {
+                    [$($pat),+, ref rest @ ..] $(if $guard)? => {
+                        Some((window.len() - rest.len(), vec![$($rep),*]))
+                    }
+                    _ => None,
+                }
+            },
+        }),*]
+    };
+}
+pub(crate) use rules;
+
+/// Apply the `rules` in a single pass from the start to the end of the program, the first matching
+/// rule is applied and the instructions it produced aren't matched again. Returns the number of
+/// applied rewrites, jump locations have to be updated afterwards.
+pub fn rewrite(config: &Config, rules: &[Rule], instructions: &mut Vec<Instruction>) -> usize {
+    // the instructions are moved into a new list, splicing the replacements in place is quadratic
+    let mut rewritten = Vec::with_capacity(instructions.len());
+    let mut num_rewrites = 0;
+    let mut i = 0;
+    'outer: while i < instructions.len() {
+        for rule in rules {
+            let Some((len, replacement)) = (rule.apply)(&instructions[i..]) else {
+                continue;
+            };
+            if !verify(config, rule.name, &instructions[i..i + len], &replacement) {
+                continue;
+            }
+            if config.verbose >= 2 {
+                let range = i..i + len;
+                println!("{}: replaced {range:?} with {replacement:?}", rule.name);
+            }
+            rewritten.extend(replacement);
+            num_rewrites += 1;
+            i += len;
+            continue 'outer;
+        }
+        rewritten.push(instructions[i]);
+        i += 1;
+    }
+    *instructions = rewritten;
+    num_rewrites
+}
+
+/// Check that the `rule` applies to its example, and that the rewritten example behaves like the
+/// original one for a few initial tapes.
+pub fn check_rule(rule: &Rule) -> Result<(), String> {
+    let tokens = crate::tokenize(rule.example.as_bytes()).map_err(|errors| errors.join(", "))?;
+    let mut original = crate::combine(tokens);
+    let mut rewritten = original.clone();
+    if rewrite(
+        &Config::default(),
+        std::slice::from_ref(rule),
+        &mut rewritten,
+    ) == 0
+    {
+        return Err("doesn't apply to its example".to_owned());
+    }
+    crate::update_jump_indices(&mut original);
+    crate::update_jump_indices(&mut rewritten);
+
+    for seed in 0..8_u8 {
+        let mut state = State {
+            rp: EXAMPLE_RP,
+            ..State::default()
+        };
+        for (i, r) in state.registers[..2 * EXAMPLE_RP as usize]
+            .iter_mut()
+            .enumerate()
+        {
+            *r = (i as u8).wrapping_mul(seed);
+        }
+        // an example that doesn't terminate for this tape can't be compared
+        let Some(expected) = run_example(&original, state.clone()) else {
+            continue;
+        };
+        if run_example(&rewritten, state) != Some(expected) {
+            return Err(format!(
+                "the rewritten example behaves differently for seed {seed}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check that the `rewritten` instructions behave like the `original` ones, which are part of a
+/// larger program, by running both starting from the same tapes: a zeroed one, and random ones
+/// around the register pointer. Some random tapes only contain values close to zero, to cover
+/// wrapping arithmetic. Tapes the original instructions don't terminate for can't be compared.
+pub fn check_rewrite(original: &[Instruction], rewritten: &[Instruction]) -> Result<(), String> {
+    let mut original = original.to_vec();
+    let mut rewritten = rewritten.to_vec();
+    crate::update_jump_indices(&mut original);
+    crate::update_jump_indices(&mut rewritten);
+
+    // the same tapes are used for every rewrite so failures are reproducible
+    let mut rng = Rng::new(0);
+    for tape in 0..VERIFY_TAPES {
+        let mut state = State {
+            rp: VERIFY_RP,
+            ..State::default()
+        };
+        let rp = VERIFY_RP as usize;
+        for r in state.registers[rp - VERIFY_RADIUS..rp + VERIFY_RADIUS].iter_mut() {
+            *r = match tape {
+                0 => 0,
+                _ if tape % 2 == 0 => (rng.below(5) as u8).wrapping_sub(2),
+                _ => rng.byte(),
+            };
+        }
+        let Some(expected) = run_example(&original, state.clone()) else {
+            continue;
+        };
+        if run_example(&rewritten, state) != Some(expected) {
+            return Err(format!("behaves differently for tape {tape}"));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the rewrite of the `original` instructions by the `pass` can be applied, which is
+/// always the case unless `--verify-passes` is used and [`check_rewrite`] fails.
+pub fn verify(
+    config: &Config,
+    pass: &str,
+    original: &[Instruction],
+    rewritten: &[Instruction],
+) -> bool {
+    if !config.verify_passes {
+        return true;
+    }
+    match check_rewrite(original, rewritten) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("{pass}: skipped rewriting {original:?} with {rewritten:?}, which {e}");
+            false
+        }
+    }
+}
+
+/// Run an example, and return its output and final state.
+fn run_example(instructions: &[Instruction], state: State) -> Option<(Vec<u8>, State)> {
+    let mut output = Vec::new();
+    let limits = Limits {
+        steps: Some(EXAMPLE_MAX_STEPS),
+        ..Limits::default()
+    };
+    let mut interpreter =
+        Interpreter::new(instructions, EXAMPLE_INPUT, &mut output).with_limits(limits);
+    interpreter.restore(state);
+    if interpreter.run() != Status::Finished {
+        return None;
+    }
+    let mut state = interpreter.snapshot();
+    drop(interpreter);
+    // the instruction pointers differ, since the programs differ in length
+    state.ip = 0;
+    Some((output, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optim;
+
+    #[test]
+    fn rules_apply_to_their_examples() {
+        for rule in optim::ZERO_RULES.iter().chain(optim::PIPE_RULES) {
+            assert_eq!(check_rule(rule), Ok(()), "{}", rule.name);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::rc::Rc;
+
+use crate::cli::Config;
+use crate::interp::{Interpreter, Program, Status};
+use crate::{error, optim};
+
+/// Number of instructions a stage executes before the next one is scheduled.
+const TIME_SLICE: u64 = 1 << 16;
+/// Number of buffered bytes at which a stage isn't scheduled until the next one caught up.
+const CHANNEL_CAPACITY: usize = 1 << 16;
+
+type Stage<'a> = Interpreter<'a, Box<dyn Read>, Box<dyn Write>>;
+
+/// Interpret the programs as a pipeline, the output of each program is the input of the next one.
+/// The programs are run round-robin on the current thread, and connected using in-memory
+/// channels. Like a broken pipe, a program is stopped once the next one finished.
+pub fn pipeline(config: &Config, paths: &[PathBuf]) -> ExitCode {
+    let mut programs = Vec::with_capacity(paths.len());
+    for path in paths {
+        let Some(program) = build(config, path) else {
+            return ExitCode::FAILURE;
+        };
+        programs.push(program);
+    }
+
+    // the standard input is only read once all other stages are blocked, otherwise stages would
+    // lag behind while the first one is waiting for input
+    let idle = Rc::new(Cell::new(false));
+    let mut input: Box<dyn Read> = Box::new(Stdin {
+        idle: idle.clone(),
+        stdin: std::io::stdin(),
+    });
+    let mut channels = Vec::new();
+    let mut stages: Vec<Option<Stage>> = Vec::new();
+    for (i, program) in programs.iter().enumerate() {
+        let (output, next_input): (Box<dyn Write>, Box<dyn Read>) = if i + 1 < programs.len() {
+            let channel = Rc::new(RefCell::new(Channel::default()));
+            channels.push(channel.clone());
+            (
+                Box::new(Sender(channel.clone())),
+                Box::new(Receiver(channel)),
+            )
+        } else {
+            (
+                Box::new(std::io::stdout().lock()),
+                Box::new(std::io::empty()),
+            )
+        };
+        let input = std::mem::replace(&mut input, next_input);
+        stages.push(Some(Interpreter::from_config(
+            config, program, input, output,
+        )));
+    }
+
+    while stages.iter().any(Option::is_some) {
+        let mut progress = false;
+        for (i, slot) in stages.iter_mut().enumerate() {
+            let Some(stage) = slot else {
+                continue;
+            };
+            if channels
+                .get(i)
+                .is_some_and(|c| c.borrow().buf.len() >= CHANNEL_CAPACITY)
+            {
+                continue;
+            }
+            let steps = stage.steps();
+            let status = stage.run_for(TIME_SLICE);
+            progress |= stage.steps() != steps;
+            match status {
+                Status::Paused => (),
+                Status::Finished => *slot = None,
+                Status::Exceeded(limit) => {
+                    let path = paths[i].display();
+                    error!("`{path}`: {limit}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        // stop the stages whose output isn't read anymore
+        for i in (0..stages.len() - 1).rev() {
+            if stages[i + 1].is_none() {
+                stages[i] = None;
+            }
+        }
+        idle.set(!progress);
+    }
+    ExitCode::SUCCESS
+}
+
+fn build(config: &Config, path: &Path) -> Option<Program> {
+    let mut instructions = match File::open(path).and_then(|f| crate::parse(config, f)) {
+        Ok(Ok(i)) => i,
+        Ok(Err(errors)) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return None;
+        }
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return None;
+        }
+    };
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    Some(Program::new(config, instructions))
+}
+
+/// The bytes written by a stage, which haven't been read by the next one yet.
+#[derive(Default)]
+struct Channel {
+    buf: VecDeque<u8>,
+    /// The writing stage finished or was stopped.
+    closed: bool,
+}
+
+struct Sender(Rc<RefCell<Channel>>);
+
+impl Write for Sender {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().buf.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.0.borrow_mut().closed = true;
+    }
+}
+
+/// Reads from a [`Channel`], returning [`ErrorKind::WouldBlock`] while it's empty, so the
+/// interpreter pauses until the previous stage wrote more bytes.
+struct Receiver(Rc<RefCell<Channel>>);
+
+impl Read for Receiver {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut channel = self.0.borrow_mut();
+        if channel.buf.is_empty() && !channel.closed {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        channel.buf.read(buf)
+    }
+}
+
+/// The standard input, which returns [`ErrorKind::WouldBlock`] unless no stage made progress in
+/// the last round of the scheduler.
+struct Stdin {
+    idle: Rc<Cell<bool>>,
+    stdin: std::io::Stdin,
+}
+
+impl Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.idle.get() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        // the next stages might have to process the bytes read before blocking again
+        self.idle.set(false);
+        self.stdin.read(buf)
+    }
+}
@@ -0,0 +1,166 @@
+//! Pragmas in comments at the top of a program, like `#[bf: tape=32768, cells=8, eof=zero]`,
+//! which set the semantics the program expects. They override the defaults, but not the options
+//! passed on the command line or in the manifest of a project. The brackets of a pragma form a
+//! loop that is never entered, since the program starts with a zeroed register.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::cli::{Config, Syntax};
+use crate::interp::Eof;
+use crate::{Span, NUM_REGISTERS};
+
+const PREFIX: &str = "#[bf:";
+/// The number of bytes of a line read at once, so a program on a single line isn't read at once.
+const CHUNK_SIZE: u64 = 1 << 16;
+
+/// The semantics set by the pragmas of a program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pragmas {
+    pub eof: Option<Eof>,
+    /// The number of registers of the tape, only tapes of [`NUM_REGISTERS`] are supported.
+    pub tape: Option<usize>,
+    /// The number of bits of the registers.
+    pub cells: Option<u32>,
+}
+
+/// A line containing a pragma.
+struct Line<'a> {
+    span: Span,
+    /// The line without the leading whitespace.
+    text: &'a [u8],
+}
+
+/// The lines containing pragmas, which start with `#[bf:` and are only preceded by lines without
+/// any commands.
+fn pragma_lines(src: &[u8]) -> Vec<Line<'_>> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for (i, line) in src.split(|&b| b == b'\n').enumerate() {
+        let text = line.trim_ascii_start();
+        let indent = line.len() - text.len();
+        if text.starts_with(PREFIX.as_bytes()) {
+            let span = Span {
+                offset: offset + indent,
+                line: i + 1,
+                col: indent,
+            };
+            lines.push(Line { span, text });
+        } else if crate::tokens(line).next().is_some() {
+            break;
+        }
+        offset += line.len() + 1;
+    }
+    lines
+}
+
+/// Parse the pragmas at the top of the brainfuck source code `src`. Each pragma is a list of
+/// `key=value` pairs separated by commas, and later pragmas override earlier ones.
+pub fn parse(src: &[u8]) -> Result<Pragmas, Vec<String>> {
+    let mut pragmas = Pragmas::default();
+    let mut errors = Vec::new();
+    for Line { span, text } in pragma_lines(src) {
+        let text = String::from_utf8_lossy(text);
+        let Some(body) = (text.trim_ascii_end())
+            .strip_prefix(PREFIX)
+            .and_then(|t| t.strip_suffix(']'))
+        else {
+            errors.push(format!("missing closing bracket of the pragma at {span}"));
+            continue;
+        };
+        for pair in body.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                errors.push(format!(
+                    "expected `key=value` in the pragma at {span}, found `{pair}`"
+                ));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "eof" => match value.parse() {
+                    Ok(eof) => pragmas.eof = Some(eof),
+                    Err(()) => errors.push(format!(
+                        "invalid eof value `{value}` in the pragma at {span}, expected `unchanged`, `zero` or `max`"
+                    )),
+                },
+                "tape" => match value.parse() {
+                    Ok(NUM_REGISTERS) => pragmas.tape = Some(NUM_REGISTERS),
+                    _ => errors.push(format!(
+                        "unsupported tape size `{value}` in the pragma at {span}, only tapes of {NUM_REGISTERS} registers are supported"
+                    )),
+                },
+                "cells" => match value.parse() {
+                    Ok(8) => pragmas.cells = Some(8),
+                    _ => errors.push(format!(
+                        "unsupported cell size `{value}` in the pragma at {span}, only 8-bit cells are supported"
+                    )),
+                },
+                _ => errors.push(format!("unknown key `{key}` in the pragma at {span}")),
+            }
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(pragmas),
+        false => Err(errors),
+    }
+}
+
+/// Replace the pragmas with spaces, so tools like the linter don't report their brackets as a
+/// loop that is never entered. The spans of the commands don't change.
+pub fn strip(src: &mut [u8]) {
+    let spans = pragma_lines(src)
+        .into_iter()
+        .map(|l| (l.span.offset, l.text.len()))
+        .collect::<Vec<_>>();
+    for (offset, len) in spans {
+        src[offset..offset + len].fill(b' ');
+    }
+}
+
+/// Apply the pragmas of the brainfuck program at `path` to the `config`, unless the options were
+/// passed explicitly. Errors reading the program are left to the parser.
+pub fn apply_file(config: &Config, path: &Path) -> Result<Config, Vec<String>> {
+    let mut config = config.clone();
+    if config.syntax != Syntax::Brainfuck {
+        return Ok(config);
+    }
+    let Ok(src) = read_leading_lines(path) else {
+        return Ok(config);
+    };
+    let pragmas = parse(&src)?;
+    if let Some(eof) = pragmas.eof.filter(|_| !config.explicit_eof) {
+        config.eof = eof;
+        // the program chose the eof value, so reading past the end of the input is portable
+        config.strict_eof = false;
+    }
+    Ok(config)
+}
+
+/// Read the file at `path` up to and including the first line containing commands, pragmas below
+/// it aren't recognized.
+fn read_leading_lines(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut src = Vec::new();
+    let mut line_start = 0;
+    loop {
+        let n = (&mut reader).take(CHUNK_SIZE).read_until(b'\n', &mut src)?;
+        if n == 0 {
+            return Ok(src);
+        }
+        let line = src[line_start..].trim_ascii_start();
+        let complete = src.ends_with(b"\n");
+        let pragma = match complete {
+            true => line.starts_with(PREFIX.as_bytes()),
+            // the prefix might not have been read completely
+            false => line.starts_with(PREFIX.as_bytes()) || PREFIX.as_bytes().starts_with(line),
+        };
+        if !pragma && crate::tokens(line).next().is_some() {
+            return Ok(src);
+        }
+        if complete {
+            line_start = src.len();
+        }
+    }
+}
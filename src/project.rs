@@ -0,0 +1,371 @@
+//! Projects consisting of a `bf.toml` manifest and the source code of a program, created by the
+//! `new` and `init` modes. The options of the manifest are the defaults of the `build`, `run` and
+//! `test` modes inside of the project, the options passed on the command line override them.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use crate::backend::Target;
+use crate::{error, NUM_REGISTERS};
+
+/// The name of the manifest file in the root directory of a project.
+pub const MANIFEST: &str = "bf.toml";
+
+const MAIN: &str = "main.bf";
+const MAIN_SRC: &str = "\
+Prints Hello World
+
+++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.
+--------.>>+.>++.
+";
+const GITIGNORE: &str = "*.elf\n";
+
+/// The `bf.toml` manifest of a project.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub name: String,
+    /// The path of the program, relative to the root directory of the project.
+    pub main: PathBuf,
+    /// The options of the `[options]` section as command line arguments.
+    options: Vec<String>,
+    /// The options of the `[profile.<name>]` sections as command line arguments, which override
+    /// the ones of the `[options]` section.
+    profiles: Vec<(String, Vec<String>)>,
+    /// The test cases of the `[test.<name>]` sections, run by the `test` mode.
+    pub tests: Vec<TestCase>,
+}
+
+/// A test case of the manifest, a program run with the contents of the `input` file, which has to
+/// produce the contents of the `output` file and exit with the `exit` code. The paths are relative
+/// to the root directory of the project.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub main: PathBuf,
+    pub input: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub exit: u8,
+}
+
+/// The profile used by default, and the one used by `--release`.
+pub const DEV_PROFILE: &str = "dev";
+pub const RELEASE_PROFILE: &str = "release";
+
+/// A value of the manifest, only the subset of toml used by the manifest is supported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(u64),
+    Bool(bool),
+    Array(Vec<String>),
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "\"{s}\""),
+            Value::Integer(n) => write!(f, "{n}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Array(a) => {
+                let items = a.iter().map(|s| format!("\"{s}\"")).collect::<Vec<_>>();
+                write!(f, "[{}]", items.join(", "))
+            }
+        }
+    }
+}
+
+impl Manifest {
+    /// Read the manifest in the directory `dir`, or return `None` if there is none.
+    pub fn load(dir: &Path) -> Option<Result<Self, Vec<String>>> {
+        let path = dir.join(MANIFEST);
+        let src = match std::fs::read_to_string(&path) {
+            Ok(src) => src,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => return Some(Err(vec![format!("failed to read `{MANIFEST}`: {e}")])),
+        };
+        Some(Self::parse(&src).map(|mut manifest| {
+            manifest.main = dir.join(manifest.main);
+            for test in manifest.tests.iter_mut() {
+                test.main = dir.join(&test.main);
+                test.input = test.input.as_ref().map(|p| dir.join(p));
+                test.output = test.output.as_ref().map(|p| dir.join(p));
+            }
+            manifest
+        }))
+    }
+
+    /// Parse the manifest, which contains the `name` and the `main` program in the `[project]`
+    /// section, the options in the `[options]` section, the options of each profile in the
+    /// `[profile.<name>]` sections, and the test cases in the `[test.<name>]` sections.
+    pub fn parse(src: &str) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut name = None;
+        let mut main = None;
+        let mut options = Vec::new();
+        let mut profiles: Vec<(String, Vec<String>)> = Vec::new();
+        let mut tests: Vec<TestCase> = Vec::new();
+        let mut section = String::new();
+        for (i, line) in src.lines().enumerate() {
+            let line_num = i + 1;
+            let mut error = |msg: String| errors.push(format!("{MANIFEST}:{line_num}: {msg}"));
+            let line = line.split_once('#').map_or(line, |(l, _)| l).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(s) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = s.trim().to_owned();
+                if let Some(profile) = section.strip_prefix("profile.") {
+                    if profiles.iter().any(|(p, _)| p == profile) {
+                        error(format!("duplicate section `[{section}]`"));
+                    }
+                    profiles.push((profile.to_owned(), Vec::new()));
+                } else if let Some(test) = section.strip_prefix("test.") {
+                    if tests.iter().any(|t| t.name == test) {
+                        error(format!("duplicate section `[{section}]`"));
+                    }
+                    tests.push(TestCase {
+                        name: test.to_owned(),
+                        main: PathBuf::new(),
+                        input: None,
+                        output: None,
+                        exit: 0,
+                    });
+                } else if !matches!(section.as_str(), "project" | "options") {
+                    error(format!("unknown section `[{section}]`"));
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                error(format!("expected `key = value`, found `{line}`"));
+                continue;
+            };
+            let key = key.trim();
+            let Some(value) = parse_value(value.trim()) else {
+                error(format!(
+                    "invalid value of `{key}`, expected a string, an integer, a boolean or an array of strings"
+                ));
+                continue;
+            };
+            let args = match section.as_str() {
+                "project" => {
+                    match (key, value) {
+                        ("name", Value::String(s)) => name = Some(s),
+                        ("main", Value::String(s)) => main = Some(PathBuf::from(s)),
+                        (_, v) => error(format!("unknown key or invalid value `{key} = {v}`")),
+                    }
+                    continue;
+                }
+                s if s.starts_with("test.") => {
+                    let Some(test) = tests.last_mut() else {
+                        unreachable!("the test is added when its header is parsed");
+                    };
+                    match (key, value) {
+                        ("main", Value::String(s)) => test.main = PathBuf::from(s),
+                        ("input", Value::String(s)) => test.input = Some(PathBuf::from(s)),
+                        ("output", Value::String(s)) => test.output = Some(PathBuf::from(s)),
+                        ("exit", Value::Integer(n)) if n <= u8::MAX as u64 => test.exit = n as u8,
+                        (_, v) => error(format!("unknown key or invalid value `{key} = {v}`")),
+                    }
+                    continue;
+                }
+                "options" => &mut options,
+                s if s.starts_with("profile.") => match profiles.last_mut() {
+                    Some((_, args)) => args,
+                    None => unreachable!("the section is added when its header is parsed"),
+                },
+                "" => {
+                    error(format!("`{key}` outside of a section"));
+                    continue;
+                }
+                // the keys of unknown sections are covered by the error of the section
+                _ => continue,
+            };
+            if let Err(e) = option_args(key, value, args) {
+                error(e);
+            }
+        }
+
+        let (Some(name), Some(main)) = (name, main) else {
+            errors.push(format!(
+                "{MANIFEST}: missing `name` or `main` in the `[project]` section"
+            ));
+            return Err(errors);
+        };
+        for test in tests.iter().filter(|t| t.main.as_os_str().is_empty()) {
+            let name = &test.name;
+            errors.push(format!(
+                "{MANIFEST}: missing `main` in the `[test.{name}]` section"
+            ));
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            name,
+            main,
+            options,
+            profiles,
+            tests,
+        })
+    }
+
+    /// The options of the `[options]` section followed by the ones of the `profile`, or `None` if
+    /// the profile isn't defined. The `dev` and `release` profiles always exist.
+    pub fn args(&self, profile: &str) -> Option<Vec<String>> {
+        let profile_args = match self.profiles.iter().find(|(p, _)| p == profile) {
+            Some((_, args)) => args.as_slice(),
+            None if matches!(profile, DEV_PROFILE | RELEASE_PROFILE) => &[],
+            None => return None,
+        };
+        Some([self.options.as_slice(), profile_args].concat())
+    }
+}
+
+/// Append the command line arguments of the option `key` to `args`.
+fn option_args(key: &str, value: Value, args: &mut Vec<String>) -> Result<(), String> {
+    match (key, value) {
+        ("opt-level", Value::Integer(n)) => args.push(format!("-O{n}")),
+        ("eof", Value::String(s)) => args.extend(["--eof".into(), s]),
+        ("emit", Value::String(s)) => args.extend(["--emit".into(), s]),
+        ("target", Value::String(s)) => args.extend(["--target".into(), s]),
+        ("strict", Value::Bool(b)) => args.extend(b.then(|| "--strict".into())),
+        ("strict-syntax", Value::Bool(b)) => args.extend(b.then(|| "--strict-syntax".into())),
+        ("comment-marker", Value::String(s)) => args.extend(["--comment-marker".into(), s]),
+        ("sandbox", Value::Bool(b)) => args.extend(b.then(|| "--sandbox".into())),
+        ("enable-passes", Value::Array(passes)) => {
+            for p in passes {
+                args.extend(["--enable-pass".into(), p]);
+            }
+        }
+        ("disable-passes", Value::Array(passes)) => {
+            for p in passes {
+                args.extend(["--disable-pass".into(), p]);
+            }
+        }
+        ("cell-size", Value::Integer(8)) => (),
+        ("cell-size", v) => {
+            return Err(format!(
+                "unsupported cell size `{v}`, only 8-bit cells are supported"
+            ));
+        }
+        ("tape-size", Value::Integer(n)) if n as usize == NUM_REGISTERS => (),
+        ("tape-size", v) => {
+            return Err(format!(
+                "unsupported tape size `{v}`, only tapes of {NUM_REGISTERS} registers are supported"
+            ));
+        }
+        (_, v) => return Err(format!("unknown key or invalid value `{key} = {v}`")),
+    }
+    Ok(())
+}
+
+fn parse_value(s: &str) -> Option<Value> {
+    if let Some(s) = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        return (!s.contains('"')).then(|| Value::String(s.to_owned()));
+    }
+    if let Some(items) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        let items = items.split(',').map(str::trim).filter(|s| !s.is_empty());
+        return items
+            .map(|item| match parse_value(item)? {
+                Value::String(s) => Some(s),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .map(Value::Array);
+    }
+    match s {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => s.replace('_', "").parse().ok().map(Value::Integer),
+    }
+}
+
+/// The manifest of a new project, with the default options of the `build` and `run` modes.
+fn manifest_src(name: &str) -> String {
+    let target = Target::host();
+    format!(
+        "\
+[project]
+name = \"{name}\"
+main = \"{MAIN}\"
+
+# the defaults of the `build` and `run` modes, which are overridden by the options of the
+# profile, and those by the command line
+[options]
+eof = \"unchanged\"
+cell-size = 8
+tape-size = {NUM_REGISTERS}
+emit = \"elf\"
+target = \"{target}\"
+
+# used by default
+[profile.dev]
+opt-level = 1
+
+# used with `--release`
+[profile.release]
+opt-level = 3
+"
+    )
+}
+
+/// Create a project in the new directory `path`.
+pub fn new(path: &Path) -> ExitCode {
+    if path.exists() {
+        let path = path.display();
+        error!("destination `{path}` already exists");
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = std::fs::create_dir_all(path) {
+        let path = path.display();
+        error!("failed to create `{path}`: {e}");
+        return ExitCode::FAILURE;
+    }
+    init(path)
+}
+
+/// Create a project in the existing directory `path`, the name of the project is the name of the
+/// directory. Existing files aren't overwritten.
+pub fn init(path: &Path) -> ExitCode {
+    if path.join(MANIFEST).exists() {
+        let path = path.display();
+        error!("`{path}` already contains a project");
+        return ExitCode::FAILURE;
+    }
+    let name = match std::fs::canonicalize(path) {
+        Ok(p) => p.file_name().map(|n| n.to_string_lossy().into_owned()),
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let name = name.unwrap_or_else(|| "main".to_owned());
+
+    let files = [
+        (MANIFEST, manifest_src(&name)),
+        (MAIN, MAIN_SRC.to_owned()),
+        (".gitignore", GITIGNORE.to_owned()),
+    ];
+    for (file, contents) in files {
+        let file_path = path.join(file);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file_path);
+        let res = match file {
+            Ok(mut f) => f.write_all(contents.as_bytes()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            let file_path = file_path.display();
+            error!("failed to write `{file_path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    println!("created project `{name}` in `{}`", path.display());
+    ExitCode::SUCCESS
+}
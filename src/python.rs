@@ -0,0 +1,215 @@
+//! Python bindings, built as an extension module named `brainfuck`.
+
+// false positive in the code generated by the pyo3 macros
+#![allow(clippy::useless_conversion)]
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::cli::{Config, Pass, Passes};
+use crate::interp::{self, State, Status};
+use crate::{cli, optim, Instruction};
+
+#[pymodule]
+fn brainfuck(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(run, m)?)?;
+    m.add_function(wrap_pyfunction!(optimize, m)?)?;
+    m.add_class::<Interpreter>()?;
+    Ok(())
+}
+
+/// The config of the optimization `opt_level`, or of exactly the named `passes` if present.
+fn config(opt_level: u8, passes: Option<Vec<String>>) -> PyResult<Config> {
+    if opt_level > 3 {
+        return Err(PyValueError::new_err(format!(
+            "invalid optimization level {opt_level}"
+        )));
+    }
+    let passes = match passes {
+        Some(names) => {
+            let mut passes = Passes::NONE;
+            for name in names {
+                match name.parse::<Pass>() {
+                    Ok(pass) => passes.insert(pass),
+                    Err(()) => {
+                        let msg = format!("invalid optimization pass `{name}`");
+                        return Err(PyValueError::new_err(msg));
+                    }
+                }
+            }
+            passes
+        }
+        None => Passes::level(opt_level),
+    };
+    Ok(Config::default().with_passes(passes))
+}
+
+/// Parse and optimize the source code, raises a `ValueError` listing the errors if it's invalid.
+fn compile(config: &Config, src: &str) -> PyResult<Vec<Instruction>> {
+    let mut instructions = match crate::parse(config, src.as_bytes()) {
+        Ok(Ok(i)) => i,
+        Ok(Err(errors)) => return Err(PyValueError::new_err(errors.join("\n"))),
+        Err(e) => return Err(PyValueError::new_err(e.to_string())),
+    };
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    Ok(instructions)
+}
+
+/// Run the program with the `input`, and return its output. Raises a `RuntimeError` if the program
+/// leaves the tape or executes more than `max_steps` instructions.
+#[pyfunction]
+#[pyo3(signature = (src, input = None, opt_level = 3, max_steps = None))]
+fn run<'py>(
+    py: Python<'py>,
+    src: &str,
+    input: Option<&[u8]>,
+    opt_level: u8,
+    max_steps: Option<u64>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let config = config(opt_level, None)?;
+    let config = Config {
+        max_steps,
+        ..config
+    };
+    let program = interp::Program::new(&config, compile(&config, src)?);
+
+    let mut output = Vec::new();
+    let input = input.unwrap_or_default();
+    let mut interpreter = interp::Interpreter::from_config(&config, &program, input, &mut output);
+    if let Status::Exceeded(limit) = interpreter.run() {
+        return Err(PyRuntimeError::new_err(limit.to_string()));
+    }
+    drop(interpreter);
+    Ok(PyBytes::new_bound(py, &output))
+}
+
+/// Return the ir of the program optimized using the `opt_level`, or exactly the named `passes`
+/// if present, printed like the `ir` mode does.
+#[pyfunction]
+#[pyo3(signature = (src, opt_level = 3, passes = None))]
+fn optimize(src: &str, opt_level: u8, passes: Option<Vec<String>>) -> PyResult<String> {
+    let config = config(opt_level, passes)?;
+    let instructions = compile(&config, src)?;
+    Ok(cli::format_instructions(&instructions))
+}
+
+/// An interpreter which is fed its input incrementally. `run` executes the program until it
+/// finishes, needs more input, or executed `max_steps` instructions, and returns the output.
+#[pyclass]
+struct Interpreter {
+    program: interp::Program,
+    state: State,
+    steps: u64,
+    input: VecDeque<u8>,
+    input_closed: bool,
+    finished: bool,
+}
+
+#[pymethods]
+impl Interpreter {
+    #[new]
+    #[pyo3(signature = (src, opt_level = 3))]
+    fn new(src: &str, opt_level: u8) -> PyResult<Self> {
+        let config = config(opt_level, None)?;
+        let instructions = compile(&config, src)?;
+        Ok(Self {
+            program: interp::Program::new(&config, instructions),
+            state: State::default(),
+            steps: 0,
+            input: VecDeque::new(),
+            input_closed: false,
+            finished: false,
+        })
+    }
+
+    /// Append bytes to the input.
+    fn feed(&mut self, input: &[u8]) {
+        self.input.extend(input);
+    }
+
+    /// Mark the end of the input, input instructions no longer wait for more bytes.
+    fn close(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// Run until the program finishes, needs more input, or executed `max_steps` instructions, and
+    /// return the output. Raises a `RuntimeError` if the program leaves the tape.
+    #[pyo3(signature = (max_steps = None))]
+    fn run<'py>(
+        &mut self,
+        py: Python<'py>,
+        max_steps: Option<u64>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let mut output = Vec::new();
+        if self.finished {
+            return Ok(PyBytes::new_bound(py, &output));
+        }
+        let input = Feed {
+            input: &mut self.input,
+            closed: self.input_closed,
+        };
+        let mut interpreter =
+            interp::Interpreter::from_config(&Config::default(), &self.program, input, &mut output);
+        interpreter.restore(std::mem::take(&mut self.state));
+        let status = interpreter.run_for(max_steps.unwrap_or(u64::MAX));
+        self.state = interpreter.snapshot();
+        self.steps += interpreter.steps();
+        drop(interpreter);
+        match status {
+            Status::Paused => (),
+            Status::Finished => self.finished = true,
+            Status::Exceeded(limit) => return Err(PyRuntimeError::new_err(limit.to_string())),
+        }
+        Ok(PyBytes::new_bound(py, &output))
+    }
+
+    /// Whether the program finished.
+    #[getter]
+    fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The total number of executed instructions.
+    #[getter]
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// The register pointer.
+    #[getter]
+    fn rp(&self) -> i16 {
+        self.state.rp
+    }
+
+    /// All registers of the tape.
+    #[getter]
+    fn registers<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.state.registers[..])
+    }
+}
+
+/// The bytes fed to an [`Interpreter`], which returns [`ErrorKind::WouldBlock`] while it's empty
+/// unless the input was closed, so the interpreter pauses until more bytes are fed.
+struct Feed<'a> {
+    input: &'a mut VecDeque<u8>,
+    closed: bool,
+}
+
+impl Read for Feed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.input.is_empty() && !self.closed {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        // one byte at a time, because bytes buffered by the interpreter are lost once it's dropped
+        let n = buf.len().min(1);
+        self.input.read(&mut buf[..n])
+    }
+}
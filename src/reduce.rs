@@ -0,0 +1,228 @@
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::cli::Config;
+use crate::interp::{Interpreter, Limits, Program, Status};
+use crate::lint::{self, Level, Levels, Lint};
+use crate::{error, optim, Token};
+
+const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+/// The property a reduced program has to keep.
+enum Expectation {
+    /// Running the program produces exactly this output.
+    Output(Vec<u8>),
+    /// Optimizing or running the program panics.
+    Crash,
+    /// The program is reported by the lint.
+    Warning(Lint),
+}
+
+/// Reduce the program to a minimal one that still has the expected output, still crashes or is
+/// still reported by a lint, using delta debugging on the token stream, and print it.
+pub fn reduce(config: &Config, path: &Path) -> ExitCode {
+    let expectation = match &config.expect_output {
+        Some(output_path) => match crate::expect::load_output(output_path) {
+            Ok(output) => Expectation::Output(output),
+            Err(e) => {
+                let output_path = output_path.display();
+                error!("failed to read `{output_path}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => match config.expect_warning {
+            Some(lint) => Expectation::Warning(lint),
+            None => Expectation::Crash,
+        },
+    };
+    let src = match std::fs::read(path) {
+        Ok(src) => src,
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let tokens = match crate::tokenize(&src) {
+        Ok(t) => t,
+        Err(errors) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // diagnostics of the optimizer and panic messages shouldn't be printed for every candidate
+    let candidate_config = Config {
+        verbose: 0,
+        ..config.clone()
+    };
+    let panic_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let mut reducer = Reducer {
+        config: &candidate_config,
+        expectation: &expectation,
+        num_tests: 0,
+    };
+    let reduced = reducer.reduce(tokens.clone());
+    std::panic::set_hook(panic_hook);
+
+    let Some(reduced) = reduced else {
+        match expectation {
+            Expectation::Output(_) => error!("the program doesn't produce the expected output"),
+            Expectation::Crash => error!("the program doesn't crash"),
+            Expectation::Warning(lint) => error!("the program isn't reported by `{lint}`"),
+        }
+        return ExitCode::FAILURE;
+    };
+    if config.verbose >= 1 {
+        let (before, after, tests) = (tokens.len(), reduced.len(), reducer.num_tests);
+        println!("reduced {before} tokens to {after} using {tests} tests");
+    }
+    for t in reduced.iter() {
+        print!("{t}");
+    }
+    println!();
+    ExitCode::SUCCESS
+}
+
+struct Reducer<'a> {
+    config: &'a Config,
+    expectation: &'a Expectation,
+    num_tests: usize,
+}
+
+impl Reducer<'_> {
+    /// Reduce the tokens, or return `None` if they don't have the expected property to begin with.
+    fn reduce(&mut self, mut tokens: Vec<Token>) -> Option<Vec<Token>> {
+        if !self.test(&tokens) {
+            return None;
+        }
+        loop {
+            let len = tokens.len();
+            tokens = self.remove_chunks(tokens);
+            tokens = self.unwrap_loops(tokens);
+            if tokens.len() == len {
+                return Some(tokens);
+            }
+        }
+    }
+
+    /// The ddmin algorithm, try removing chunks of tokens, and refine the chunk size until single
+    /// tokens are removed.
+    fn remove_chunks(&mut self, mut tokens: Vec<Token>) -> Vec<Token> {
+        let mut num_chunks = 2;
+        while !tokens.is_empty() {
+            let chunk_len = tokens.len().div_ceil(num_chunks);
+            let mut removed = false;
+            let mut start = 0;
+            while start < tokens.len() {
+                let end = (start + chunk_len).min(tokens.len());
+                let mut candidate = tokens[..start].to_vec();
+                candidate.extend_from_slice(&tokens[end..]);
+                if self.test(&candidate) {
+                    tokens = candidate;
+                    removed = true;
+                } else {
+                    start = end;
+                }
+            }
+
+            if removed {
+                num_chunks = (num_chunks - 1).max(2);
+            } else if chunk_len == 1 {
+                break;
+            } else {
+                num_chunks = (2 * num_chunks).min(tokens.len());
+            }
+        }
+        tokens
+    }
+
+    /// Try removing the brackets of each loop while keeping its body, which can't be done by
+    /// removing a single chunk.
+    fn unwrap_loops(&mut self, mut tokens: Vec<Token>) -> Vec<Token> {
+        let mut i = 0;
+        while i < tokens.len() {
+            if tokens[i] != Token::LSquare {
+                i += 1;
+                continue;
+            }
+            let mut depth = 0;
+            let end = (i..tokens.len())
+                .find(|&j| {
+                    match tokens[j] {
+                        Token::LSquare => depth += 1,
+                        Token::RSquare => depth -= 1,
+                        _ => (),
+                    }
+                    depth == 0
+                })
+                .expect("brackets are matched");
+            let mut candidate = tokens.clone();
+            candidate.remove(end);
+            candidate.remove(i);
+            if self.test(&candidate) {
+                tokens = candidate;
+            } else {
+                i += 1;
+            }
+        }
+        tokens
+    }
+
+    /// Whether the candidate has the expected property, candidates with unmatched brackets don't.
+    fn test(&mut self, tokens: &[Token]) -> bool {
+        let mut depth = 0_usize;
+        for t in tokens.iter() {
+            match t {
+                Token::LSquare => depth += 1,
+                Token::RSquare if depth == 0 => return false,
+                Token::RSquare => depth -= 1,
+                _ => (),
+            }
+        }
+        if depth != 0 {
+            return false;
+        }
+
+        self.num_tests += 1;
+        if let Expectation::Warning(lint) = *self.expectation {
+            let mut levels = Levels::default();
+            levels.set(lint, Level::Warn);
+            let src = tokens.iter().map(Token::to_string).collect::<String>();
+            let diagnostics = lint::check(&levels, src.as_bytes());
+            return diagnostics.iter().any(|d| d.lint == lint);
+        }
+
+        let config = self.config;
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut instructions = crate::combine_tokens(config, tokens);
+            if config.optimize() {
+                optim::optimize(config, &mut instructions);
+            }
+            crate::update_jump_indices(&mut instructions);
+            optim::thread_jumps(config, &mut instructions);
+            let program = Program::new(config, instructions);
+
+            let limits = Limits {
+                steps: Some(config.max_steps.unwrap_or(DEFAULT_MAX_STEPS)),
+                ..config.limits()
+            };
+            let mut output = Vec::new();
+            let mut interpreter = Interpreter::from_config(config, &program, &[][..], &mut output)
+                .with_limits(limits);
+            let status = interpreter.run();
+            (status, output)
+        }));
+
+        match (self.expectation, result) {
+            (Expectation::Output(expected), Ok((Status::Finished, output))) => output == *expected,
+            (Expectation::Output(_), _) => false,
+            (Expectation::Crash, result) => result.is_err(),
+            (Expectation::Warning(_), _) => unreachable!("checked without running the program"),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt::Write;
+
+use crate::backend::{Artifact, Backend, RuntimeOptions};
+use crate::cli::Config;
+use crate::interp::Eof;
+use crate::ir::{self, Node};
+use crate::Instruction;
+
+/// The backend generating standalone rust programs.
+pub struct Rust;
+
+impl Backend for Rust {
+    fn name(&self) -> &'static str {
+        "rust"
+    }
+
+    fn extension(&self) -> &'static str {
+        "rs"
+    }
+
+    fn compile(&self, _: &Config, ir: &[Instruction], options: &RuntimeOptions) -> Artifact {
+        Artifact::Source(generate(options, ir))
+    }
+}
+
+/// Generate a standalone rust program implementing the `instructions` over a fixed size tape.
+/// Accesses outside the tape panic.
+pub fn generate(options: &RuntimeOptions, instructions: &[Instruction]) -> String {
+    let nodes = ir::build(instructions);
+    let mut body = String::new();
+    let mut uses = Uses::default();
+    write_nodes(options, &mut body, &nodes, 1, &mut uses);
+
+    let mut code = String::new();
+    // not every program uses the input, the tape or the register pointer
+    code.push_str("#![allow(unused)]\n\n");
+    if uses.pipe {
+        code.push_str("use std::io::{BufRead, Read, Write};\n\n");
+    } else {
+        code.push_str("use std::io::{Read, Write};\n\n");
+    }
+    _ = writeln!(code, "const TAPE_LEN: usize = {};\n", options.tape_len);
+    code.push_str("fn main() {\n");
+    code.push_str("    let mut t = [0u8; TAPE_LEN];\n");
+    code.push_str("    let mut p: usize = 0;\n");
+    if uses.input {
+        code.push_str("    let mut input = std::io::stdin().lock();\n");
+    }
+    code.push_str("    let mut output = std::io::BufWriter::new(std::io::stdout().lock());\n");
+    code.push('\n');
+    code.push_str(&body);
+    code.push('\n');
+    code.push_str("    _ = output.flush();\n");
+    code.push_str("}\n");
+
+    if uses.input {
+        let eof = match options.eof {
+            Eof::Unchanged => "current",
+            Eof::Zero => "0",
+            Eof::Max => "255",
+        };
+        code.push_str(
+            "\n\
+             /// Flush the output, so prompts are visible, and read a byte from the input.\n\
+             fn read(input: &mut impl Read, output: &mut impl Write, current: u8) -> u8 {\n\
+             \x20   _ = output.flush();\n\
+             \x20   let mut buf = [0];\n\
+             \x20   match input.read(&mut buf) {\n\
+             \x20       Ok(1) => buf[0],\n",
+        );
+        _ = writeln!(code, "        _ => {eof},");
+        code.push_str("    }\n}\n");
+    }
+    if uses.pipe {
+        code.push_str(
+            "\n\
+             /// Copy the input to the output like the echo loop `[.,]` following the call, until\n\
+             /// the register is zero or the input is exhausted.\n\
+             fn pipe(input: &mut impl BufRead, output: &mut impl Write, mut current: u8) -> u8 {\n\
+             \x20   while current != 0 {\n\
+             \x20       _ = output.flush();\n\
+             \x20       let buf = match input.fill_buf() {\n\
+             \x20           Ok(buf) if !buf.is_empty() => buf,\n\
+             \x20           _ => break,\n\
+             \x20       };\n\
+             \x20       let n = buf.iter().position(|&b| b == 0).map_or(buf.len(), |i| i + 1);\n\
+             \x20       _ = output.write_all(&[current]);\n\
+             \x20       _ = output.write_all(&buf[..n - 1]);\n\
+             \x20       current = buf[n - 1];\n\
+             \x20       input.consume(n);\n\
+             \x20   }\n\
+             \x20   current\n\
+             }\n",
+        );
+    }
+    if uses.div_mod {
+        code.push_str(
+            "\n\
+             /// The divmod loop `[->-[>+>>]>[+[-<+>]>+>>]<<<<<]` for the common case.\n\
+             fn div_mod(registers: &mut [u8]) {\n\
+             \x20   let &mut [n, d, r, q, a, b] = registers else {\n\
+             \x20       unreachable!()\n\
+             \x20   };\n\
+             \x20   if n == 0 || d < 2 || r != 0 || a != 0 || b != 0 {\n\
+             \x20       return;\n\
+             \x20   }\n\
+             \x20   registers[0] = 0;\n\
+             \x20   registers[1] = d - n % d;\n\
+             \x20   registers[2] = n % d;\n\
+             \x20   registers[3] = q.wrapping_add(n / d);\n\
+             }\n",
+        );
+    }
+    code
+}
+
+/// The helper functions used by the generated code.
+#[derive(Default)]
+struct Uses {
+    input: bool,
+    div_mod: bool,
+    pipe: bool,
+}
+
+/// The index of the register at `offset` from the current one.
+fn index(offset: i16) -> String {
+    match offset {
+        0 => "p".to_owned(),
+        1.. => format!("p.wrapping_add({offset})"),
+        _ => format!("p.wrapping_sub({})", offset.unsigned_abs()),
+    }
+}
+
+fn write_nodes(
+    options: &RuntimeOptions,
+    code: &mut String,
+    nodes: &[Node],
+    depth: usize,
+    uses: &mut Uses,
+) {
+    let indent = "    ".repeat(depth);
+    let mut i = 0;
+    while i < nodes.len() {
+        let inst = match &nodes[i] {
+            Node::Inst(inst) => *inst,
+            Node::Loop(l) => {
+                // a loop with a redundant closing jump executes its body at most once
+                let keyword = if l.end_redundant { "if" } else { "while" };
+                _ = writeln!(code, "{indent}{keyword} t[p] != 0 {{");
+                write_nodes(options, code, &l.body, depth + 1, uses);
+                _ = writeln!(code, "{indent}}}");
+                i += 1;
+                continue;
+            }
+        };
+
+        _ = match inst {
+            Instruction::Shl(n) => writeln!(code, "{indent}p = p.wrapping_sub({n});"),
+            Instruction::Shr(n) => writeln!(code, "{indent}p = p.wrapping_add({n});"),
+            Instruction::Inc(o, n) => {
+                let r = index(o);
+                writeln!(code, "{indent}t[{r}] = t[{r}].wrapping_add({n});")
+            }
+            Instruction::Dec(o, n) => {
+                let r = index(o);
+                writeln!(code, "{indent}t[{r}] = t[{r}].wrapping_sub({n});")
+            }
+            Instruction::Output(o) => {
+                let r = index(o);
+                writeln!(code, "{indent}_ = output.write_all(&[t[{r}]]);")
+            }
+            Instruction::Input(o) => {
+                uses.input = true;
+                let r = index(o);
+                writeln!(
+                    code,
+                    "{indent}t[{r}] = read(&mut input, &mut output, t[{r}]);"
+                )
+            }
+            Instruction::JumpZ(_) | Instruction::JumpNz(_) => unreachable!(),
+            Instruction::Zero(o) => writeln!(code, "{indent}t[{}] = 0;", index(o)),
+            Instruction::Set(o, n) => writeln!(code, "{indent}t[{}] = {n};", index(o)),
+            Instruction::Add(o) => {
+                let r = index(o);
+                writeln!(code, "{indent}t[{r}] = t[{r}].wrapping_add(t[p]);")
+            }
+            Instruction::Sub(o) => {
+                let r = index(o);
+                writeln!(code, "{indent}t[{r}] = t[{r}].wrapping_sub(t[p]);")
+            }
+            Instruction::AddMul(o, n) => {
+                let r = index(o);
+                let val = format!("t[p].wrapping_mul({n})");
+                writeln!(code, "{indent}t[{r}] = t[{r}].wrapping_add({val});")
+            }
+            Instruction::SubMul(o, n) => {
+                let r = index(o);
+                let val = format!("t[p].wrapping_mul({n})");
+                writeln!(code, "{indent}t[{r}] = t[{r}].wrapping_sub({val});")
+            }
+            Instruction::CopyRange(s, d, n) => {
+                let (src, dst) = (index(s), index(d));
+                writeln!(code, "{indent}t.copy_within({src}..{src} + {n}, {dst});")
+            }
+            Instruction::Pipe => {
+                uses.input = true;
+                uses.pipe = true;
+                writeln!(code, "{indent}t[p] = pipe(&mut input, &mut output, t[p]);")
+            }
+            Instruction::DivMod => {
+                uses.div_mod = true;
+                let range = "p..p.wrapping_add(6)";
+                writeln!(
+                    code,
+                    "{indent}if let Some(r) = t.get_mut({range}) {{ div_mod(r) }}"
+                )
+            }
+            Instruction::PrintDecimal(o) => {
+                writeln!(
+                    code,
+                    "{indent}_ = write!(output, \"{{}}\", t[{}]);",
+                    index(o)
+                )
+            }
+            Instruction::OutputConst(_) => {
+                // consecutive constant bytes are written at once
+                let mut bytes = Vec::new();
+                while let Some(&Node::Inst(Instruction::OutputConst(n))) = nodes.get(i) {
+                    bytes.push(n);
+                    i += 1;
+                }
+                let bytes = bytes.escape_ascii();
+                _ = writeln!(code, "{indent}_ = output.write_all(b\"{bytes}\");");
+                if !options.buffered_output {
+                    _ = writeln!(code, "{indent}_ = output.flush();");
+                }
+                continue;
+            }
+        };
+        let output = matches!(inst, Instruction::Output(_) | Instruction::PrintDecimal(_));
+        if output && !options.buffered_output {
+            _ = writeln!(code, "{indent}_ = output.flush();");
+        }
+        i += 1;
+    }
+}
@@ -0,0 +1,416 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::{ExitCode, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::backend::RuntimeOptions;
+use crate::cli::{Config, Passes};
+use crate::interp::{Eof, Interpreter, Program, Status, TokenInterpreter};
+use crate::lint::{self, Lint};
+use crate::{i686, jit, optim, x86, Instruction};
+
+/// Number of nested loops of the deep nesting test.
+const NESTING_DEPTH: usize = 1000;
+/// The width of the column of test names, which fits the longest lint name.
+const NAME_WIDTH: usize = 28;
+
+/// A program of the conformance corpus, and its expected output for the given input, or `None`
+/// if the program has to be rejected.
+struct Test {
+    name: &'static str,
+    src: String,
+    input: &'static [u8],
+    output: Option<Vec<u8>>,
+}
+
+/// The conformance corpus, most programs are taken from Daniel B. Cristofani's brainfuck tests.
+fn corpus(config: &Config) -> Vec<Test> {
+    let test = |name, src: &str, input, output: Option<&[u8]>| Test {
+        name,
+        src: src.to_owned(),
+        input,
+        output: output.map(<[u8]>::to_vec),
+    };
+    let eof = match config.eof {
+        Eof::Unchanged => b'K',
+        Eof::Zero => b'B',
+        Eof::Max => b'A',
+    };
+    vec![
+        test(
+            "hello",
+            include_str!("../examples/hello.bf"),
+            b"",
+            Some(b"Hello World!\n"),
+        ),
+        // cells are 8-bit and wrap around in both directions
+        test(
+            "cell-wrap",
+            "-.+.>++++++++[<++++++++++++++++++++++++++++++++>-]<.",
+            b"",
+            Some(b"\xFF\x00\x00"),
+        ),
+        // newlines are read as 10, and EOF is handled as configured
+        Test {
+            name: "eof",
+            src: ">,>+++++++++,>+++++++++++[<++++++<++++++<+>>>-]<<.>.<<-.>.>.<<.".to_owned(),
+            input: b"\n",
+            output: Some(vec![b'L', eof, b'\n', b'L', eof, b'\n']),
+        },
+        // the echo loop stops at a zero byte, the input after it is still available
+        test("echo-zero", ",[.,],.", b"ab\0cd", Some(b"abc")),
+        Test {
+            name: "deep-nesting",
+            src: format!(
+                "+{}-{}{}.",
+                "[".repeat(NESTING_DEPTH),
+                "]".repeat(NESTING_DEPTH),
+                "+".repeat(b'!' as usize),
+            ),
+            input: b"",
+            output: Some(b"!".to_vec()),
+        },
+        // the tape has at least 30000 registers
+        test(
+            "tape-30000",
+            "++++[>++++++<-]>[>+++++>+++++++<<-]>>++++<[[>[[>>+<<-]<]>>>-]>-[>+>+<<-]>]\
+             +++++[>+++++++<<++>-]>.<<.",
+            b"",
+            Some(b"#\n"),
+        ),
+        // the "H" test for several obscure problems, like non-command characters and empty loops
+        test(
+            "obscure",
+            "[]++++++++++[>>+>+>++++++[<<+<+++>>>-]<<<<-]\n\
+             \"A*$\";?@![#>>+<<]>[>>]<<<<[>++<[-]]>.>.",
+            b"",
+            Some(b"H\n"),
+        ),
+        // a self-interpreter running a program, which exercises the optimizations on code that
+        // wasn't written by hand
+        test(
+            "self-interpreter",
+            include_str!("../examples/dbfi.b"),
+            b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.\
+              ------.--------.>>+.>++.!",
+            Some(b"Hello World!\n"),
+        ),
+        // I/O inside arithmetic loops: only the registers the I/O doesn't observe are replaced
+        // with multiplications, the output and input stay in order with the other registers
+        test(
+            "io-arithmetic-loop",
+            ",[.>,.>+.>++<<<-]>>>.",
+            b"\x02ab",
+            Some(b"\x02a\x01\x01b\x02\x04"),
+        ),
+        test(
+            "io-reads-hoisted",
+            ",[>+<.>.>++<<-]>>.>,[>+++<.-]>.",
+            b"\x02\x03",
+            Some(b"\x02\x01\x01\x02\x04\x03\x02\x01\x09"),
+        ),
+        test("unmatched-open", "+++++[>+++++++>++<<-]>.>.[", b"", None),
+        test("unmatched-close", "+++++[>+++++++>++<<-]>.>.][", b"", None),
+    ]
+}
+
+/// The ways a program is executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    /// The token interpreter, the reference that doesn't use the ir.
+    Tokens,
+    /// The interpreter without any optimizations.
+    Unoptimized,
+    Interpreter,
+    /// The interpreter running the ir after each stage of the optimizer, see
+    /// [`optim::optimize_with_trace`].
+    Stages,
+    Jit,
+    Compiler,
+    /// The compiler generating 32-bit executables, see [`i686::compile`].
+    Compiler32,
+}
+
+impl Backend {
+    const ALL: [Backend; 7] = [
+        Backend::Tokens,
+        Backend::Unoptimized,
+        Backend::Interpreter,
+        Backend::Stages,
+        Backend::Jit,
+        Backend::Compiler,
+        Backend::Compiler32,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Tokens => "run --no-ir",
+            Backend::Unoptimized => "run -O0",
+            Backend::Interpreter => "run",
+            Backend::Stages => "stages",
+            Backend::Jit => "jit",
+            Backend::Compiler => "compile",
+            Backend::Compiler32 => "compile --target i686-linux",
+        }
+    }
+}
+
+/// Run the conformance corpus through the interpreter, the jit and the compiler, and report which
+/// programs don't produce the expected output.
+pub fn selftest(config: &Config) -> ExitCode {
+    let corpus = corpus(config);
+    let (mut passed, mut failed) = (0, 0);
+    for test in corpus.iter() {
+        let mut failures = Vec::new();
+        match &test.output {
+            Some(expected) => {
+                for backend in Backend::ALL {
+                    let output = run(config, backend, test);
+                    match output {
+                        Ok(output) if output == *expected => (),
+                        Ok(output) => {
+                            let expected = expected.escape_ascii();
+                            let output = output.escape_ascii();
+                            let msg = format!("expected output `{expected}`, got `{output}`");
+                            failures.push((backend.name(), msg));
+                        }
+                        Err(e) => failures.push((backend.name(), e)),
+                    }
+                }
+            }
+            None => {
+                if crate::tokenize(test.src.as_bytes()).is_ok() {
+                    failures.push(("check", "the program wasn't rejected".to_owned()));
+                }
+            }
+        }
+
+        if failures.is_empty() {
+            println!("{:<NAME_WIDTH$}ok", test.name);
+            passed += 1;
+        } else {
+            println!("{:<NAME_WIDTH$}FAILED", test.name);
+            for (backend, msg) in failures.iter() {
+                println!("    {backend}: {msg}");
+            }
+            failed += 1;
+        }
+    }
+
+    // the lints are checked using their examples
+    for lint in Lint::ALL {
+        match lint::check_example(lint) {
+            Ok(()) => {
+                println!("{:<NAME_WIDTH$}ok", lint.name());
+                passed += 1;
+            }
+            Err(e) => {
+                println!("{:<NAME_WIDTH$}FAILED", lint.name());
+                println!("    lint: {e}");
+                failed += 1;
+            }
+        }
+    }
+
+    println!();
+    println!("{passed} passed, {failed} failed");
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Run the program of the `test` using the `backend`, and return its output.
+fn run(config: &Config, backend: Backend, test: &Test) -> Result<Vec<u8>, String> {
+    if backend == Backend::Tokens {
+        let tokens = crate::tokenize(test.src.as_bytes()).map_err(|errors| errors.join(", "))?;
+        let mut output = Vec::new();
+        let mut interpreter = TokenInterpreter::new(&tokens, test.input, &mut output)
+            .with_limits(config.limits())
+            .with_eof(config.eof);
+        if let Status::Exceeded(limit) = interpreter.run() {
+            return Err(limit.to_string());
+        }
+        return Ok(output);
+    }
+    if backend == Backend::Stages {
+        let tokens = crate::tokenize(test.src.as_bytes()).map_err(|errors| errors.join(", "))?;
+        let unoptimized = crate::combine_tokens(config, &tokens);
+        let expected = interpret(config, unoptimized.clone(), test.input)?;
+        for (stage, instructions) in optim::optimize_with_trace(config, &unoptimized) {
+            let output = interpret(config, instructions, test.input)
+                .map_err(|e| format!("{e} after the `{stage}` stage"))?;
+            if output != expected {
+                return Err(format!("the output changed after the `{stage}` stage"));
+            }
+        }
+        return Ok(expected);
+    }
+
+    let unoptimized;
+    let config = if backend == Backend::Unoptimized {
+        unoptimized = Config {
+            passes: Passes::NONE,
+            ..config.clone()
+        };
+        &unoptimized
+    } else {
+        config
+    };
+    let instructions = build(config, test.src.as_bytes())?;
+
+    let mut output = Vec::new();
+    match backend {
+        Backend::Tokens | Backend::Stages => unreachable!(),
+        Backend::Unoptimized | Backend::Interpreter => {
+            let program = Program::new(config, instructions);
+            let mut interpreter =
+                Interpreter::from_config(config, &program, test.input, &mut output);
+            if let Status::Exceeded(limit) = interpreter.run() {
+                return Err(limit.to_string());
+            }
+        }
+        Backend::Jit => {
+            let mut input = test.input;
+            let status = jit::run(config, &instructions, &mut input, &mut output)
+                .map_err(|e| format!("failed to run: {e}"))?;
+            if let Status::Exceeded(limit) = status {
+                return Err(limit.to_string());
+            }
+        }
+        Backend::Compiler => {
+            let options = RuntimeOptions::new(config);
+            let code = x86::compile(config, &options, &instructions);
+            // `--emit hash` relies on the executable being reproducible, also when the code is
+            // reused from the code cache
+            let cache_path =
+                std::env::temp_dir().join(format!("bf-selftest-{}.code", std::process::id()));
+            let cached = RuntimeOptions {
+                code_cache: Some(cache_path.clone()),
+                ..options
+            };
+            let reproducible = (0..2).all(|_| x86::compile(config, &cached, &instructions) == code);
+            _ = std::fs::remove_file(&cache_path);
+            if !reproducible {
+                return Err("the executable isn't reproducible".to_owned());
+            }
+            output = run_executable(&code, test.input)?;
+        }
+        Backend::Compiler32 => {
+            let code = i686::compile(config, &RuntimeOptions::new(config), &instructions);
+            output = run_executable(&code, test.input)?;
+        }
+    }
+    Ok(output)
+}
+
+/// Interpret the instructions, whose jump locations don't have to be resolved.
+fn interpret(
+    config: &Config,
+    mut instructions: Vec<Instruction>,
+    input: &[u8],
+) -> Result<Vec<u8>, String> {
+    crate::update_jump_indices(&mut instructions);
+    let mut output = Vec::new();
+    let mut interpreter = Interpreter::new(&instructions, input, &mut output)
+        .with_limits(config.limits())
+        .with_eof(config.eof);
+    if let Status::Exceeded(limit) = interpreter.run() {
+        return Err(limit.to_string());
+    }
+    drop(interpreter);
+    Ok(output)
+}
+
+/// Parse and optimize the program the same way as the `run` and `compile` modes.
+pub fn build(config: &Config, src: &[u8]) -> Result<Vec<Instruction>, String> {
+    let tokens = crate::tokenize(src).map_err(|errors| errors.join(", "))?;
+    let mut instructions = crate::combine_tokens(config, &tokens);
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    Ok(instructions)
+}
+
+/// Write the executable to a temporary file, and run it with `input` as stdin.
+pub fn run_executable(code: &[u8], input: &[u8]) -> Result<Vec<u8>, String> {
+    // executables might be run in parallel by the `test` mode
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let pid = std::process::id();
+    let path = std::env::temp_dir().join(format!("bf-selftest-{pid}-{id}.elf"));
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .mode(0o755)
+            .open(&path)
+            .map_err(|e| format!("failed to create `{}`: {e}", path.display()))?;
+        file.write_all(code)
+            .map_err(|e| format!("failed to write `{}`: {e}", path.display()))?;
+        // the file has to be closed before it can be executed
+        drop(file);
+
+        let mut child = std::process::Command::new(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("failed to run `{}`: {e}", path.display()))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // the program might exit without reading all input
+            _ = stdin.write_all(input);
+        }
+        let out = child
+            .wait_with_output()
+            .map_err(|e| format!("failed to run `{}`: {e}", path.display()))?;
+        if !out.status.success() {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            return Err(format!("exited with {}: {}", out.status, stderr.trim_end()));
+        }
+        Ok(out.stdout)
+    })();
+    _ = std::fs::remove_file(&path);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The offset of the last register of the tape, the largest positive register pointer.
+    const LAST_REGISTER: usize = (1 << 15) - 1;
+
+    /// Run the program of the `test` using every backend, and check its output.
+    fn check(test: Test) {
+        let config = Config::default();
+        let expected = test.output.as_ref().unwrap();
+        for backend in Backend::ALL {
+            let output = run(&config, backend, &test);
+            assert_eq!(output.as_ref(), Ok(expected), "{}", backend.name());
+        }
+    }
+
+    /// Offsets up to the end of the tape.
+    #[test]
+    fn far_offset() {
+        check(Test {
+            name: "far-offset",
+            src: format!(
+                "{}{}{}.{}{}.",
+                "+".repeat(b'!' as usize),
+                ">".repeat(LAST_REGISTER),
+                "+".repeat(b'"' as usize),
+                "<".repeat(LAST_REGISTER),
+                "+",
+            ),
+            input: b"",
+            output: Some(b"\"\"".to_vec()),
+        });
+    }
+}
@@ -0,0 +1,384 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::AssertUnwindSafe;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cli::{self, Config};
+use crate::interp::{Interpreter, Limit, Limits, Program, Status};
+use crate::{error, optim, parallel};
+
+const DEFAULT_MAX_STEPS: u64 = 100_000_000;
+const DEFAULT_MAX_OUTPUT: usize = 1 << 16;
+/// Maximum size of the request body, containing the program and its input.
+const MAX_BODY_SIZE: usize = 1 << 20;
+const MAX_HEADER_LINES: usize = 64;
+/// Maximum length of the request line and of each header line.
+const MAX_LINE: usize = 8192;
+/// Maximum number of accepted connections waiting for a worker thread, further connections are
+/// rejected with `503 Service Unavailable`.
+const MAX_PENDING_CONNECTIONS: usize = 64;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a client has to send the complete request, regardless of how the data is split up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const USAGE: &str = "\
+POST /run with the program as request body, the program input can be passed as a
+percent-encoded `input` query parameter: `/run?input=hello%0A`
+
+The response is a json object containing:
+    status          `finished`, `step_limit`, `output_limit`, `time_limit`,
+                    `tape_limit` or `error`
+    steps           number of executed instructions
+    output          program output
+    ir              optimized intermediate representation
+    tokens          number of brainfuck tokens
+    instructions    number of instructions after optimization
+    errors          list of errors if the program is invalid
+";
+
+/// Serve an http api for running programs until the process is terminated.
+pub fn serve(config: &Config) -> ExitCode {
+    let port = config.port;
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("failed to bind to port {port}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("listening on http://127.0.0.1:{port}");
+
+    // diagnostics of the optimizer shouldn't be printed for every request
+    let config = Config {
+        verbose: 0,
+        ..config.clone()
+    };
+    // connections are handled by a fixed number of worker threads, so the number of programs run
+    // at the same time is bounded
+    let (sender, receiver) = mpsc::sync_channel(MAX_PENDING_CONNECTIONS);
+    let receiver = Mutex::new(receiver);
+    std::thread::scope(|s| {
+        for _ in 0..parallel::default_jobs() {
+            let (config, receiver) = (&config, &receiver);
+            s.spawn(move || loop {
+                let stream = receiver.lock().unwrap().recv();
+                let Ok(stream) = stream else { break };
+                if let Err(e) = handle_connection(config, stream) {
+                    error!("{e}");
+                }
+            });
+        }
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("failed to accept connection: {e}");
+                    continue;
+                }
+            };
+            if let Err(TrySendError::Full(stream)) = sender.try_send(stream) {
+                let response = Response::text("503 Service Unavailable", "too many connections");
+                if let Err(e) = write_response(&stream, &response) {
+                    error!("{e}");
+                }
+            }
+        }
+        drop(sender);
+    });
+
+    ExitCode::SUCCESS
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: body.into(),
+        }
+    }
+
+    fn json(status: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+}
+
+fn handle_connection(config: &Config, stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(DeadlineReader {
+        stream: &stream,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    });
+
+    let request = match read_request(&mut reader) {
+        Ok(request) => request,
+        Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+            let response = Response::text("408 Request Timeout", "request timed out");
+            return write_response(&stream, &response);
+        }
+        Err(e) => return Err(e),
+    };
+    // a panic while handling a request shouldn't take down the worker thread
+    let response = std::panic::catch_unwind(AssertUnwindSafe(|| match request {
+        Ok((method, target, body)) => handle_request(config, &method, &target, &body),
+        Err(response) => response,
+    }));
+    let response = response.unwrap_or_else(|_| {
+        let json = String::from("{\"status\":\"error\",\"errors\":[\"internal error\"]}");
+        Response::json("500 Internal Server Error", json)
+    });
+    write_response(&stream, &response)
+}
+
+fn handle_request(config: &Config, method: &str, target: &str, body: &[u8]) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match (method, path) {
+        ("GET", "/") => Response::text("200 OK", USAGE),
+        ("POST", "/run") => match query_param(query, "input") {
+            Some(Ok(input)) => run_program(config, body, &input),
+            Some(Err(())) => Response::text("400 Bad Request", "invalid input encoding"),
+            None => run_program(config, body, &[]),
+        },
+        (_, "/" | "/run") => Response::text("405 Method Not Allowed", USAGE),
+        _ => Response::text("404 Not Found", USAGE),
+    }
+}
+
+/// Reads from the stream until the deadline of the request passed, instead of applying the
+/// timeout to every single read.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response) -> std::io::Result<()> {
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len(),
+    )?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+type Request = (String, String, Vec<u8>);
+
+fn read_request(reader: &mut impl BufRead) -> std::io::Result<Result<Request, Response>> {
+    let mut line = String::new();
+    if !read_line(reader, &mut line)? {
+        let msg = format!("request line exceeds {MAX_LINE} bytes");
+        return Ok(Err(Response::text("414 URI Too Long", msg)));
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Ok(Err(Response::text(
+            "400 Bad Request",
+            "invalid request line",
+        )));
+    };
+    let (method, path) = (method.to_owned(), path.to_owned());
+
+    let mut content_length = 0;
+    let mut num_headers = 0;
+    loop {
+        line.clear();
+        if !read_line(reader, &mut line)? {
+            let msg = format!("header line exceeds {MAX_LINE} bytes");
+            return Ok(Err(Response::text(
+                "431 Request Header Fields Too Large",
+                msg,
+            )));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        num_headers += 1;
+        if num_headers > MAX_HEADER_LINES {
+            let msg = format!("request has more than {MAX_HEADER_LINES} headers");
+            return Ok(Err(Response::text(
+                "431 Request Header Fields Too Large",
+                msg,
+            )));
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            match value.trim().parse() {
+                Ok(len) => content_length = len,
+                Err(_) => {
+                    return Ok(Err(Response::text(
+                        "400 Bad Request",
+                        "invalid content length",
+                    )))
+                }
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        let msg = format!("request body exceeds {MAX_BODY_SIZE} bytes");
+        return Ok(Err(Response::text("413 Payload Too Large", msg)));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Ok((method, path, body)))
+}
+
+/// Read a line of at most [`MAX_LINE`] bytes, returns false if the line is longer.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> std::io::Result<bool> {
+    reader.take(MAX_LINE as u64 + 1).read_line(line)?;
+    Ok(line.len() <= MAX_LINE)
+}
+
+fn query_param(query: &str, name: &str) -> Option<Result<Vec<u8>, ()>> {
+    let value = query.split('&').find_map(|p| {
+        let (n, v) = p.split_once('=').unwrap_or((p, ""));
+        (n == name).then_some(v)
+    })?;
+    Some(percent_decode(value))
+}
+
+fn percent_decode(str: &str) -> Result<Vec<u8>, ()> {
+    let mut bytes = Vec::with_capacity(str.len());
+    let mut iter = str.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next().ok_or(())?, iter.next().ok_or(())?];
+                let hex = std::str::from_utf8(&hex).map_err(|_| ())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| ())?);
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+    Ok(bytes)
+}
+
+fn run_program(config: &Config, src: &[u8], input: &[u8]) -> Response {
+    let src = crate::preprocess(config, src.to_vec());
+    let tokens = match src.and_then(|src| crate::tokenize(&src)) {
+        Ok(t) => t,
+        Err(errors) => {
+            let mut json = String::from("{\"status\":\"error\",\"errors\":[");
+            for (i, e) in errors.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                cli::write_json_string(&mut json, e);
+            }
+            json.push_str("]}");
+            return Response::json("400 Bad Request", json);
+        }
+    };
+    let mut instructions = crate::combine_tokens(config, &tokens);
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+
+    let limits = Limits {
+        steps: Some(config.max_steps.unwrap_or(DEFAULT_MAX_STEPS)),
+        output: Some(config.max_output.unwrap_or(DEFAULT_MAX_OUTPUT)),
+        time: config.max_time,
+    };
+    let program = Program::new(config, instructions.clone());
+    let mut output = Vec::new();
+    let mut interpreter =
+        Interpreter::from_config(config, &program, input, &mut output).with_limits(limits);
+    let status = match interpreter.run() {
+        Status::Paused => unreachable!(),
+        Status::Finished => "finished",
+        Status::Exceeded(Limit::Steps) => "step_limit",
+        Status::Exceeded(Limit::Output) => "output_limit",
+        Status::Exceeded(Limit::Time) => "time_limit",
+        Status::Exceeded(Limit::Tape) => "tape_limit",
+        Status::Exceeded(Limit::Eof) => unreachable!("reads past the end are allowed"),
+    };
+    let steps = interpreter.steps();
+
+    let mut json = String::new();
+    _ = write!(
+        json,
+        "{{\"status\":\"{status}\",\"steps\":{steps},\"output\":"
+    );
+    cli::write_json_string(&mut json, &String::from_utf8_lossy(&output));
+    json.push_str(",\"ir\":");
+    cli::write_json_string(&mut json, &cli::format_instructions(&instructions));
+    _ = write!(
+        json,
+        ",\"tokens\":{},\"instructions\":{}}}",
+        tokens.len(),
+        instructions.len()
+    );
+
+    Response::json("200 OK", json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The status of the response to the `request`, or `None` if it's valid.
+    fn rejection(request: &[u8]) -> Option<&'static str> {
+        read_request(&mut &request[..])
+            .unwrap()
+            .err()
+            .map(|r| r.status)
+    }
+
+    #[test]
+    fn oversized_requests_are_rejected() {
+        let long = "a".repeat(MAX_LINE);
+        let request = format!("GET /{long} HTTP/1.1\r\n\r\n");
+        assert_eq!(rejection(request.as_bytes()), Some("414 URI Too Long"));
+        let request = format!("GET / HTTP/1.1\r\nX: {long}\r\n\r\n");
+        let status = Some("431 Request Header Fields Too Large");
+        assert_eq!(rejection(request.as_bytes()), status);
+        let request = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: y\r\n".repeat(MAX_HEADER_LINES + 1)
+        );
+        assert_eq!(rejection(request.as_bytes()), status);
+        let request = format!(
+            "POST /run HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert_eq!(rejection(request.as_bytes()), Some("413 Payload Too Large"));
+
+        let request = b"POST /run HTTP/1.1\r\nX: y\r\nContent-Length: 2\r\n\r\n+.";
+        assert_eq!(rejection(request), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! Static libraries embedding a compiled program as the C function `bf_prog_main`, generated by
+//! `--emit staticlib`. Unlike the C api of the interpreter, the library contains a single program
+//! instead of the engine. The program performs I/O using the callbacks of the `bf_io` struct
+//! declared in the generated header.
+
+use crate::backend::{Artifact, Backend, RuntimeOptions};
+use crate::cli::Config;
+use crate::jit::Io;
+use crate::object::{Function, Machine, Relocatable};
+use crate::{const_assert, x86, Instruction};
+
+/// The name of the C function running the program.
+pub const ENTRY: &str = "bf_prog_main";
+/// The name of the object inside the archive.
+const MEMBER: &str = "bf_prog.o";
+
+const MAGIC: &[u8] = b"!<arch>\n";
+const MEMBER_HEADER_LEN: usize = 60;
+
+/// The header declaring [`ENTRY`], the layout of `bf_io` matches [`crate::jit::Io`].
+const HEADER: &str = "\
+/* A brainfuck program compiled using `--emit staticlib`, link the static library into a C
+ * program and call `bf_prog_main` to run it.
+ */
+#ifndef BF_PROG_H
+#define BF_PROG_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+
+/* the I/O callbacks of the program, which receive the `bf_io` struct and the address of the
+ * current cell. The struct can be embedded at the start of a larger one, to pass more data to
+ * the callbacks. */
+typedef struct bf_io bf_io;
+struct bf_io {
+    /* write the cell */
+    void (*output)(bf_io *io, uint8_t *cell);
+    /* read the next byte into the cell, the callback decides what happens at the end of the
+     * input, usually the cell is left unchanged or set to zero */
+    void (*input)(bf_io *io, uint8_t *cell);
+    /* write the cell as a decimal number without leading zeros */
+    void (*print_decimal)(bf_io *io, uint8_t *cell);
+    /* write the `len` bytes at `bytes` */
+    void (*output_const)(bf_io *io, const uint8_t *bytes, size_t len);
+    /* called in front of the echo loop `[.,]`, which may be sped up by copying the input to the
+     * output until the cell is zero, the loop continues with the resulting cell afterwards, so
+     * the callback may do nothing */
+    void (*pipe)(bf_io *io, uint8_t *cell);
+    /* not accessed by the program */
+    void *user_data;
+};
+
+/* run the program with a fresh tape, which is allocated on the stack, returns 0 once the program
+ * finishes, or 1 if it was stopped before accessing a cell outside the tape. */
+int bf_prog_main(bf_io *io);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
+";
+
+// the callbacks of `bf_io` are five pointers in the same order
+const_assert!(std::mem::offset_of!(Io, output) == 0);
+const_assert!(std::mem::offset_of!(Io, input) == 8);
+const_assert!(std::mem::offset_of!(Io, print_decimal) == 16);
+const_assert!(std::mem::offset_of!(Io, output_const) == 24);
+const_assert!(std::mem::offset_of!(Io, pipe) == 32);
+
+/// The backend generating static libraries for C programs, which contain the program as
+/// `int bf_prog_main(bf_io *io)`.
+pub struct StaticLib;
+
+impl Backend for StaticLib {
+    fn name(&self) -> &'static str {
+        "staticlib"
+    }
+
+    fn extension(&self) -> &'static str {
+        "a"
+    }
+
+    fn compile(&self, config: &Config, ir: &[Instruction], options: &RuntimeOptions) -> Artifact {
+        let mut object = Relocatable::new(Machine::X86_64);
+        object.text = x86::compile_function(config, options, ir);
+        object.functions.push(Function {
+            name: ENTRY.into(),
+            offset: 0,
+            len: object.text.len(),
+        });
+        Artifact::Library {
+            archive: archive(MEMBER, &object.write(), &[ENTRY]),
+            header: HEADER.into(),
+        }
+    }
+}
+
+/// An `ar` archive containing the `object` named `member`, preceded by a symbol table of the
+/// `symbols` it defines, so linkers don't require running `ranlib`. The timestamps, owners and
+/// modes are fixed, so the archive is reproducible.
+fn archive(member: &str, object: &[u8], symbols: &[&str]) -> Vec<u8> {
+    let names_len = symbols.iter().map(|s| s.len() + 1).sum::<usize>();
+    let symtab_len = 4 + 4 * symbols.len() + names_len;
+    // the object follows the symbol table, whose contents are padded to an even length
+    let object_offset = MAGIC.len() + MEMBER_HEADER_LEN + symtab_len.next_multiple_of(2);
+
+    // the number of symbols, the offset of the header of the member defining each symbol, and
+    // the names of the symbols, the numbers are big-endian
+    let mut symtab = Vec::with_capacity(symtab_len);
+    symtab.extend_from_slice(&(symbols.len() as u32).to_be_bytes());
+    for _ in symbols {
+        symtab.extend_from_slice(&(object_offset as u32).to_be_bytes());
+    }
+    for s in symbols {
+        symtab.extend_from_slice(s.as_bytes());
+        symtab.push(0);
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    write_member(&mut bytes, "/", &symtab);
+    write_member(&mut bytes, &format!("{member}/"), object);
+    bytes
+}
+
+fn write_member(bytes: &mut Vec<u8>, name: &str, contents: &[u8]) {
+    // the name, timestamp, owner id, group id, octal mode and size, padded with spaces
+    let len = contents.len();
+    let header = format!("{name:<16}{:<12}{:<6}{:<6}{:<8}{len:<10}`\n", 0, 0, 0, 644);
+    debug_assert_eq!(header.len(), MEMBER_HEADER_LEN);
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.extend_from_slice(contents);
+    if len % 2 == 1 {
+        bytes.push(b'\n');
+    }
+}
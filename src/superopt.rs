@@ -0,0 +1,577 @@
+//! An experimental superoptimization pass using equality saturation. The values of the registers
+//! written by straight-line code between I/O and jumps are described by terms in an e-graph,
+//! which is saturated using algebraic rewrites. The cheapest equivalent terms are lowered to
+//! instructions again, and replace the segment if that's shorter.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::cli::Config;
+use crate::Instruction;
+
+/// Rewriting stops once the e-graph of a segment has this many nodes.
+const MAX_NODES: usize = 500;
+/// Maximum number of rewrite iterations per segment.
+const MAX_ITERATIONS: usize = 16;
+/// Longer segments are split, so the e-graphs stay small.
+const MAX_SEGMENT_LEN: usize = 64;
+/// The cost of terms that can't be lowered to instructions.
+const INFINITE: u32 = u32::MAX;
+
+type Id = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Node {
+    Const(u8),
+    /// The value of the register at the offset before the segment.
+    Reg(i16),
+    Add(Id, Id),
+    Mul(Id, Id),
+}
+
+/// The right hand side of a matched rewrite rule, which is equal to the matched class.
+#[derive(Clone, Copy, Debug)]
+enum Rewrite {
+    /// An existing class.
+    Class(Id),
+    Add(Id, Id),
+    Mul(Id, Id),
+    /// `a * c`
+    MulConst(Id, u8),
+    /// `a + (b + c)`
+    AddNested(Id, Id, Id),
+    /// `a * (b * c)`
+    MulNested(Id, Id, Id),
+    /// `a * c + b * c`
+    Distribute(Id, Id, Id),
+}
+
+/// Equivalence classes of terms, hash-consed so structurally equal terms share a class.
+#[derive(Default)]
+struct EGraph {
+    /// Union-find over the classes, a class is canonical if it's its own leader.
+    leaders: Vec<Id>,
+    /// The nodes of each canonical class.
+    nodes: Vec<Vec<Node>>,
+    /// The constant value of each canonical class, if known.
+    consts: Vec<Option<u8>>,
+    memo: HashMap<Node, Id>,
+}
+
+impl EGraph {
+    fn find(&self, mut id: Id) -> Id {
+        while self.leaders[id] != id {
+            id = self.leaders[id];
+        }
+        id
+    }
+
+    fn canonicalize(&self, node: Node) -> Node {
+        match node {
+            Node::Add(a, b) => Node::Add(self.find(a), self.find(b)),
+            Node::Mul(a, b) => Node::Mul(self.find(a), self.find(b)),
+            Node::Const(_) | Node::Reg(_) => node,
+        }
+    }
+
+    fn constant(&self, id: Id) -> Option<u8> {
+        self.consts[self.find(id)]
+    }
+
+    fn fold(&self, node: Node) -> Option<u8> {
+        match node {
+            Node::Const(c) => Some(c),
+            Node::Reg(_) => None,
+            Node::Add(a, b) => Some(self.constant(a)?.wrapping_add(self.constant(b)?)),
+            Node::Mul(a, b) => match (self.constant(a), self.constant(b)) {
+                (Some(0), _) | (_, Some(0)) => Some(0),
+                (Some(a), Some(b)) => Some(a.wrapping_mul(b)),
+                _ => None,
+            },
+        }
+    }
+
+    fn add(&mut self, node: Node) -> Id {
+        let node = self.canonicalize(node);
+        if let Some(&id) = self.memo.get(&node) {
+            return self.find(id);
+        }
+        let id = self.leaders.len();
+        self.leaders.push(id);
+        self.consts.push(self.fold(node));
+        self.nodes.push(vec![node]);
+        self.memo.insert(node, id);
+        id
+    }
+
+    fn add_const(&mut self, c: u8) -> Id {
+        self.add(Node::Const(c))
+    }
+
+    /// Merge the classes, returns whether they were distinct.
+    fn union(&mut self, a: Id, b: Id) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        debug_assert!(
+            self.consts[a]
+                .zip(self.consts[b])
+                .is_none_or(|(a, b)| a == b),
+            "a rewrite merged different constants"
+        );
+        self.leaders[b] = a;
+        let nodes = std::mem::take(&mut self.nodes[b]);
+        self.nodes[a].extend(nodes);
+        self.consts[a] = self.consts[a].or(self.consts[b]);
+        true
+    }
+
+    /// Restore the invariants after merging classes: nodes are canonical and unique, classes
+    /// containing congruent nodes are merged, and classes with a known constant contain it.
+    fn rebuild(&mut self) {
+        loop {
+            let mut changed = false;
+            let mut merges = Vec::new();
+            self.memo.clear();
+            for id in 0..self.leaders.len() {
+                if self.find(id) != id {
+                    continue;
+                }
+                let mut nodes = std::mem::take(&mut self.nodes[id]);
+                for n in nodes.iter_mut() {
+                    *n = self.canonicalize(*n);
+                }
+                nodes.sort_unstable();
+                nodes.dedup();
+                for &n in nodes.iter() {
+                    if self.consts[id].is_none() {
+                        self.consts[id] = self.fold(n);
+                        changed |= self.consts[id].is_some();
+                    }
+                    if let Some(other) = self.memo.insert(n, id) {
+                        merges.push((other, id));
+                    }
+                }
+                self.nodes[id] = nodes;
+            }
+            for id in 0..self.leaders.len() {
+                let Some(c) = self.consts[id].filter(|_| self.find(id) == id) else {
+                    continue;
+                };
+                match self.memo.get(&Node::Const(c)) {
+                    Some(&other) => merges.push((other, id)),
+                    None => {
+                        self.nodes[id].push(Node::Const(c));
+                        self.memo.insert(Node::Const(c), id);
+                    }
+                }
+            }
+            for (a, b) in merges {
+                changed |= self.union(a, b);
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// Find the matches of all rewrite rules.
+    fn search(&self) -> Vec<(Id, Rewrite)> {
+        let mut matches = Vec::new();
+        for id in 0..self.leaders.len() {
+            if self.find(id) != id {
+                continue;
+            }
+            for &node in self.nodes[id].iter() {
+                match node {
+                    Node::Const(_) | Node::Reg(_) => (),
+                    Node::Add(a, b) => {
+                        // a + b = b + a
+                        matches.push((id, Rewrite::Add(b, a)));
+                        // (x + y) + b = x + (y + b)
+                        for &n in self.nodes[a].iter() {
+                            if let Node::Add(x, y) = n {
+                                matches.push((id, Rewrite::AddNested(x, y, b)));
+                            }
+                        }
+                        // a + 0 = a
+                        if self.constant(b) == Some(0) {
+                            matches.push((id, Rewrite::Class(a)));
+                        }
+                        // a + a = a * 2
+                        if a == b {
+                            matches.push((id, Rewrite::MulConst(a, 2)));
+                        }
+                        // x * c1 + x * c2 = x * (c1 + c2), and x + x * c = x * (c + 1)
+                        for (x, c2) in self.scaled(b) {
+                            if x == a {
+                                matches.push((id, Rewrite::MulConst(x, c2.wrapping_add(1))));
+                            }
+                            for (y, c1) in self.scaled(a) {
+                                if x == y {
+                                    matches.push((id, Rewrite::MulConst(x, c1.wrapping_add(c2))));
+                                }
+                            }
+                        }
+                    }
+                    Node::Mul(a, b) => {
+                        // a * b = b * a
+                        matches.push((id, Rewrite::Mul(b, a)));
+                        // (x * y) * b = x * (y * b)
+                        for &n in self.nodes[a].iter() {
+                            if let Node::Mul(x, y) = n {
+                                matches.push((id, Rewrite::MulNested(x, y, b)));
+                            }
+                        }
+                        // a * 1 = a
+                        if self.constant(b) == Some(1) {
+                            matches.push((id, Rewrite::Class(a)));
+                        }
+                        // (x + y) * c = x * c + y * c, only by constants so the terms stay linear
+                        if self.constant(b).is_some() {
+                            for &n in self.nodes[a].iter() {
+                                if let Node::Add(x, y) = n {
+                                    matches.push((id, Rewrite::Distribute(x, y, b)));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        matches
+    }
+
+    /// The terms `x * c` with a constant `c` in the class.
+    fn scaled(&self, id: Id) -> impl Iterator<Item = (Id, u8)> + '_ {
+        self.nodes[id].iter().filter_map(|&n| match n {
+            Node::Mul(x, c) => Some((x, self.constant(c)?)),
+            _ => None,
+        })
+    }
+
+    /// Add the right hand side of a rewrite, and merge it with the matched class. Returns whether
+    /// the classes were distinct.
+    fn apply(&mut self, id: Id, rewrite: Rewrite) -> bool {
+        let rhs = match rewrite {
+            Rewrite::Class(a) => a,
+            Rewrite::Add(a, b) => self.add(Node::Add(a, b)),
+            Rewrite::Mul(a, b) => self.add(Node::Mul(a, b)),
+            Rewrite::MulConst(a, c) => {
+                let c = self.add_const(c);
+                self.add(Node::Mul(a, c))
+            }
+            Rewrite::AddNested(a, b, c) => {
+                let inner = self.add(Node::Add(b, c));
+                self.add(Node::Add(a, inner))
+            }
+            Rewrite::MulNested(a, b, c) => {
+                let inner = self.add(Node::Mul(b, c));
+                self.add(Node::Mul(a, inner))
+            }
+            Rewrite::Distribute(a, b, c) => {
+                let a = self.add(Node::Mul(a, c));
+                let b = self.add(Node::Mul(b, c));
+                self.add(Node::Add(a, b))
+            }
+        };
+        self.union(id, rhs)
+    }
+
+    /// Apply the rewrite rules until no new equalities are found, or a limit is reached.
+    fn saturate(&mut self) {
+        for _ in 0..MAX_ITERATIONS {
+            let mut changed = false;
+            for (id, rewrite) in self.search() {
+                changed |= self.apply(id, rewrite);
+                if self.memo.len() >= MAX_NODES {
+                    break;
+                }
+            }
+            self.rebuild();
+            if !changed || self.memo.len() >= MAX_NODES {
+                break;
+            }
+        }
+    }
+
+    /// The register offset of a class containing the initial value of a register.
+    fn register(&self, id: Id) -> Option<i16> {
+        self.nodes[self.find(id)].iter().find_map(|&n| match n {
+            Node::Reg(o) => Some(o),
+            _ => None,
+        })
+    }
+
+    /// The cost of a node, the number of instructions it roughly lowers to.
+    fn cost(&self, node: Node, costs: &[u32]) -> u32 {
+        match node {
+            Node::Const(_) | Node::Reg(_) => 1,
+            Node::Add(a, b) => costs[self.find(a)].saturating_add(costs[self.find(b)]),
+            // a multiplication of a register by a constant is a single instruction
+            Node::Mul(a, b) => {
+                let scaled = |a, b| self.register(a).is_some() && self.constant(b).is_some();
+                match scaled(a, b) || scaled(b, a) {
+                    true => 1,
+                    false => INFINITE,
+                }
+            }
+        }
+    }
+
+    /// The cheapest node of each canonical class.
+    fn extract(&self) -> Vec<Option<Node>> {
+        let mut costs = vec![INFINITE; self.leaders.len()];
+        let mut best = vec![None; self.leaders.len()];
+        loop {
+            let mut changed = false;
+            for id in 0..self.leaders.len() {
+                if self.find(id) != id {
+                    continue;
+                }
+                for &n in self.nodes[id].iter() {
+                    let cost = self.cost(n, &costs);
+                    if cost < costs[id] {
+                        costs[id] = cost;
+                        best[id] = Some(n);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        best
+    }
+
+    /// Add the cheapest term of the class to the linear combination, fails if it can't be lowered
+    /// to instructions.
+    fn linear(&self, best: &[Option<Node>], id: Id, value: &mut Linear) -> Option<()> {
+        let id = self.find(id);
+        if let Some(c) = self.consts[id] {
+            value.constant = value.constant.wrapping_add(c);
+            return Some(());
+        }
+        match best[id]? {
+            Node::Const(c) => value.constant = value.constant.wrapping_add(c),
+            Node::Reg(o) => value.add(o, 1),
+            Node::Add(a, b) => {
+                self.linear(best, a, value)?;
+                self.linear(best, b, value)?;
+            }
+            Node::Mul(a, b) => match (self.register(a), self.constant(b)) {
+                (Some(o), Some(c)) => value.add(o, c),
+                _ => value.add(self.register(b)?, self.constant(a)?),
+            },
+        }
+        Some(())
+    }
+}
+
+/// A value of the form `constant + Σ factor * register`.
+#[derive(Debug, Default)]
+struct Linear {
+    constant: u8,
+    /// The factors by register offset.
+    factors: BTreeMap<i16, u8>,
+}
+
+impl Linear {
+    fn add(&mut self, offset: i16, factor: u8) {
+        let f = self.factors.entry(offset).or_default();
+        *f = f.wrapping_add(factor);
+        if *f == 0 {
+            self.factors.remove(&offset);
+        }
+    }
+}
+
+/// Replace straight-line segments with shorter equivalent instructions, found using equality
+/// saturation.
+pub fn superoptimize(config: &Config, instructions: &mut Vec<Instruction>) {
+    let mut optimized = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    while i < instructions.len() {
+        let len = (instructions[i..].iter())
+            .take(MAX_SEGMENT_LEN)
+            .take_while(|inst| is_straight_line(inst))
+            .count();
+        if len < 2 {
+            optimized.push(instructions[i]);
+            i += 1;
+            continue;
+        }
+
+        let segment = &instructions[i..i + len];
+        match optimize_segment(segment) {
+            Some(replacement) if replacement.len() < len => {
+                if config.verbose >= 2 {
+                    let range = i..i + len;
+                    println!("superopt: replaced {range:?} with {replacement:?}");
+                }
+                optimized.extend(replacement);
+            }
+            _ => optimized.extend_from_slice(segment),
+        }
+        i += len;
+    }
+    *instructions = optimized;
+}
+
+/// Instructions that only move the register pointer and update registers.
+fn is_straight_line(inst: &Instruction) -> bool {
+    use Instruction::*;
+
+    matches!(
+        inst,
+        Shl(_)
+            | Shr(_)
+            | Inc(..)
+            | Dec(..)
+            | Zero(_)
+            | Set(..)
+            | Add(_)
+            | Sub(_)
+            | AddMul(..)
+            | SubMul(..)
+    )
+}
+
+/// Find the cheapest instructions equivalent to the segment, fails if the segment can't be
+/// described or lowered.
+fn optimize_segment(segment: &[Instruction]) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+
+    let mut egraph = EGraph::default();
+    // the classes of the registers written by the segment, by offset from the initial pointer
+    let mut registers = BTreeMap::<i16, Id>::new();
+    let mut rp = 0_i16;
+    for &inst in segment {
+        let mut get = |egraph: &mut EGraph, offset: i16| -> Option<Id> {
+            let o = rp.checked_add(offset)?;
+            Some(
+                *registers
+                    .entry(o)
+                    .or_insert_with(|| egraph.add(Node::Reg(o))),
+            )
+        };
+        let (offset, value) = match inst {
+            Shl(n) => {
+                rp = rp.checked_sub_unsigned(n)?;
+                continue;
+            }
+            Shr(n) => {
+                rp = rp.checked_add_unsigned(n)?;
+                continue;
+            }
+            Inc(o, n) | Dec(o, n) => {
+                let n = if matches!(inst, Inc(..)) {
+                    n
+                } else {
+                    n.wrapping_neg()
+                };
+                let reg = get(&mut egraph, o)?;
+                let n = egraph.add_const(n);
+                (o, egraph.add(Node::Add(reg, n)))
+            }
+            Zero(o) => (o, egraph.add_const(0)),
+            Set(o, n) => (o, egraph.add_const(n)),
+            Add(o) | Sub(o) | AddMul(o, _) | SubMul(o, _) => {
+                let factor = match inst {
+                    Add(_) => 1,
+                    Sub(_) => 255,
+                    AddMul(_, n) => n,
+                    SubMul(_, n) => n.wrapping_neg(),
+                    _ => unreachable!(),
+                };
+                let src = get(&mut egraph, 0)?;
+                let dst = get(&mut egraph, o)?;
+                let factor = egraph.add_const(factor);
+                let scaled = egraph.add(Node::Mul(src, factor));
+                (o, egraph.add(Node::Add(dst, scaled)))
+            }
+            _ => unreachable!("not straight-line code: {inst:?}"),
+        };
+        registers.insert(rp.checked_add(offset)?, value);
+    }
+
+    egraph.saturate();
+    let best = egraph.extract();
+    let mut targets = Vec::new();
+    for (&offset, &id) in registers.iter() {
+        let initial = egraph.add(Node::Reg(offset));
+        if egraph.find(id) == egraph.find(initial) {
+            continue;
+        }
+        let mut value = Linear::default();
+        egraph.linear(&best, id, &mut value)?;
+        targets.push((offset, value));
+    }
+    lower(targets, rp)
+}
+
+/// Generate the instructions writing the `targets`, and finally moving the register pointer by
+/// `shift`. Every register is written once, after all registers that read its initial value.
+fn lower(mut targets: Vec<(i16, Linear)>, shift: i16) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+
+    let mut instructions = Vec::new();
+    let mut rp = 0_i16;
+    let move_to = |instructions: &mut Vec<Instruction>, rp: &mut i16, target: i16| {
+        match target.checked_sub(*rp)? {
+            d @ ..=-1 => instructions.push(Shl(d.unsigned_abs())),
+            0 => (),
+            d @ 1.. => instructions.push(Shr(d as u16)),
+        }
+        *rp = target;
+        Some(())
+    };
+
+    while !targets.is_empty() {
+        // the nearest register whose initial value isn't read anymore
+        let unread = |&(offset, _): &(i16, Linear)| {
+            (targets.iter()).all(|(o, v)| *o == offset || !v.factors.contains_key(&offset))
+        };
+        let idx = (0..targets.len())
+            .filter(|&i| unread(&targets[i]))
+            .min_by_key(|&i| (targets[i].0 as i32 - rp as i32).abs())?;
+        let (offset, mut value) = targets.remove(idx);
+
+        let offset_from = |rp: i16| offset.checked_sub(rp);
+        match value.factors.remove(&offset) {
+            None => match value.constant {
+                0 => instructions.push(Zero(offset_from(rp)?)),
+                n => instructions.push(Set(offset_from(rp)?, n)),
+            },
+            Some(1) => match value.constant as i8 {
+                0 => (),
+                n @ ..=-1 => instructions.push(Dec(offset_from(rp)?, n.unsigned_abs())),
+                n @ 1.. => instructions.push(Inc(offset_from(rp)?, n as u8)),
+            },
+            // scaling a register in place isn't an instruction
+            Some(_) => return None,
+        }
+
+        // the sources are visited starting from the nearer end
+        let mut sources = value.factors.into_iter().collect::<Vec<_>>();
+        if let (Some(first), Some(last)) = (sources.first(), sources.last()) {
+            if (last.0 as i32 - rp as i32).abs() < (first.0 as i32 - rp as i32).abs() {
+                sources.reverse();
+            }
+        }
+        for (src, factor) in sources {
+            move_to(&mut instructions, &mut rp, src)?;
+            let o = offset_from(rp)?;
+            instructions.push(match factor as i8 {
+                ..=-2 => SubMul(o, (factor as i8).unsigned_abs()),
+                -1 => Sub(o),
+                0 => unreachable!("zero factors are removed"),
+                1 => Add(o),
+                2.. => AddMul(o, factor),
+            });
+        }
+    }
+    move_to(&mut instructions, &mut rp, shift)?;
+    Some(instructions)
+}
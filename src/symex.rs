@@ -0,0 +1,411 @@
+//! Symbolic execution of small programs, used by the experimental `symex` mode. The input bytes
+//! are symbols, and each path through the program is explored with the set of input values that
+//! lead to it.
+//!
+//! Registers are only changed by increments, decrements and input instructions, so the value of
+//! a register is always a constant, or an input byte plus a constant. Loop conditions therefore
+//! only constrain single input bytes, and the constraints of a path are exactly described by the
+//! set of values each input byte can have, without a solver. Loops depending on an input byte
+//! fork a path for each iteration count, which is bounded by `--max-depth`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::cli::Config;
+use crate::{error, Span, Token, NUM_REGISTERS};
+
+/// The number of input bytes that are symbols, if `--max-input` isn't passed. Longer inputs aren't
+/// explored.
+pub const DEFAULT_MAX_INPUT: usize = 4;
+/// The number of forks of each path, if `--max-depth` isn't passed.
+pub const DEFAULT_MAX_DEPTH: u32 = 32;
+/// The number of steps of each path, if `--max-steps` isn't passed.
+const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+/// The number of paths reported, exploration stops once it's reached.
+const MAX_PATHS: usize = 1024;
+/// The number of output bytes printed for each path, the rest is only counted.
+const MAX_PRINTED_OUTPUT: usize = 256;
+
+/// The value of a register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    Const(u8),
+    /// The input byte with the `index` plus `add`.
+    Input {
+        index: usize,
+        add: u8,
+    },
+}
+
+impl Value {
+    fn add(self, n: u8) -> Self {
+        match self {
+            Value::Const(c) => Value::Const(c.wrapping_add(n)),
+            Value::Input { index, add } => Value::Input {
+                index,
+                add: add.wrapping_add(n),
+            },
+        }
+    }
+}
+
+/// A set of byte values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Domain([u64; 4]);
+
+impl Domain {
+    const ALL: Domain = Domain([u64::MAX; 4]);
+
+    fn only(v: u8) -> Self {
+        let mut d = Domain([0; 4]);
+        d.0[v as usize / 64] = 1 << (v % 64);
+        d
+    }
+
+    fn contains(self, v: u8) -> bool {
+        self.0[v as usize / 64] & (1 << (v % 64)) != 0
+    }
+
+    fn remove(mut self, v: u8) -> Self {
+        self.0[v as usize / 64] &= !(1 << (v % 64));
+        self
+    }
+
+    fn len(self) -> u32 {
+        self.0.iter().map(|w| w.count_ones()).sum()
+    }
+
+    fn values(self) -> impl Iterator<Item = u8> {
+        (0..=u8::MAX).filter(move |&v| self.contains(v))
+    }
+}
+
+impl std::fmt::Display for Domain {
+    /// Print a single value, the excluded values if there are few, or a range.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MAX_LISTED: u32 = 8;
+
+        let len = self.len();
+        let list = |values: &mut dyn Iterator<Item = u8>| {
+            values.map(byte_literal).collect::<Vec<_>>().join(", ")
+        };
+        if len == 256 {
+            return f.write_str("any byte");
+        }
+        if len <= MAX_LISTED {
+            return write!(f, "{}", list(&mut self.values()));
+        }
+        if 256 - len <= MAX_LISTED {
+            let mut excluded = (0..=u8::MAX).filter(|&v| !self.contains(v));
+            return write!(f, "any byte except {}", list(&mut excluded));
+        }
+        let (min, max) = (self.values().min(), self.values().max());
+        let (Some(min), Some(max)) = (min, max) else {
+            return f.write_str("no byte");
+        };
+        match max as u32 - min as u32 + 1 == len {
+            true => write!(f, "{}..={}", byte_literal(min), byte_literal(max)),
+            false => write!(f, "one of {len} bytes in {}..={}", min, max),
+        }
+    }
+}
+
+/// The byte as a number, and as a char if it's printable ascii.
+fn byte_literal(b: u8) -> String {
+    match b {
+        b' '..=b'~' => format!("{b} '{}'", b as char),
+        _ => format!("{b}"),
+    }
+}
+
+/// How a path ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Finished,
+    /// The pointer was moved left of the first register by the token at the span.
+    TapeUnderflow(Span),
+    /// The pointer was moved right of the last register by the token at the span.
+    TapeOverflow(Span),
+    /// The path forked more than `--max-depth` times, the rest of it isn't explored.
+    DepthLimit,
+    /// The path executed more than `--max-steps` tokens.
+    StepLimit,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Finished => f.write_str("finished"),
+            Outcome::TapeUnderflow(span) => write!(f, "tape underflow at {span}"),
+            Outcome::TapeOverflow(span) => write!(f, "tape overflow at {span}"),
+            Outcome::DepthLimit => f.write_str("depth limit reached"),
+            Outcome::StepLimit => f.write_str("step limit reached"),
+        }
+    }
+}
+
+/// The state of a path through the program.
+#[derive(Clone, Debug)]
+struct Execution {
+    ip: usize,
+    rp: usize,
+    /// The registers that aren't zero.
+    tape: BTreeMap<usize, Value>,
+    /// The values of the input bytes read so far that lead to this path.
+    inputs: Vec<Domain>,
+    /// Whether the path read past the end of the input, which is as long as `inputs`.
+    eof: bool,
+    /// The first [`MAX_PRINTED_OUTPUT`] output bytes.
+    output: Vec<Value>,
+    output_len: usize,
+    forks: u32,
+    steps: u64,
+}
+
+impl Execution {
+    fn get(&self) -> Value {
+        self.tape.get(&self.rp).copied().unwrap_or(Value::Const(0))
+    }
+
+    fn set(&mut self, value: Value) {
+        match value {
+            Value::Const(0) => self.tape.remove(&self.rp),
+            v => self.tape.insert(self.rp, v),
+        };
+    }
+
+    /// The value as a constant, if its input byte only has a single value on this path.
+    fn resolve(&self, value: Value) -> Value {
+        match value {
+            Value::Input { index, add } if self.inputs[index].len() == 1 => {
+                let v = self.inputs[index].values().next().unwrap_or(0);
+                Value::Const(v.wrapping_add(add))
+            }
+            v => v,
+        }
+    }
+}
+
+/// A completely explored path.
+struct Report {
+    outcome: Outcome,
+    inputs: Vec<Domain>,
+    eof: bool,
+    output: Vec<Value>,
+    output_len: usize,
+}
+
+/// Symbolically execute the program at `path`, and print each path with the input bytes leading
+/// to it, its output and how it ended.
+pub fn symex(config: &Config, path: &Path) -> ExitCode {
+    let src = match std::fs::read(path) {
+        Ok(src) => src,
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let checked = crate::preprocess(config, src).and_then(|src| crate::tokenize(&src).map(|_| src));
+    let src = match checked {
+        Ok(src) => src,
+        Err(errors) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+    let (tokens, spans): (Vec<Token>, Vec<Span>) = crate::tokens(&src).unzip();
+    let mut matching = vec![0; tokens.len()];
+    let mut stack = Vec::new();
+    for (i, t) in tokens.iter().enumerate() {
+        match t {
+            Token::LSquare => stack.push(i),
+            Token::RSquare => {
+                let start = stack.pop().expect("checked by tokenize");
+                matching[start] = i;
+                matching[i] = start;
+            }
+            _ => (),
+        }
+    }
+
+    let max_input = config.max_input.unwrap_or(DEFAULT_MAX_INPUT);
+    let max_depth = config.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+    let max_steps = config.max_steps.unwrap_or(DEFAULT_MAX_STEPS);
+    let mut reports = Vec::new();
+    let mut pending = vec![Execution {
+        ip: 0,
+        rp: 0,
+        tape: BTreeMap::new(),
+        inputs: Vec::new(),
+        eof: false,
+        output: Vec::new(),
+        output_len: 0,
+        forks: 0,
+        steps: 0,
+    }];
+    while let Some(mut p) = pending.pop() {
+        if reports.len() == MAX_PATHS {
+            break;
+        }
+        let outcome = loop {
+            let Some(&token) = tokens.get(p.ip) else {
+                break Outcome::Finished;
+            };
+            if p.steps == max_steps {
+                break Outcome::StepLimit;
+            }
+            p.steps += 1;
+            match token {
+                Token::Shl => match p.rp.checked_sub(1) {
+                    Some(rp) => p.rp = rp,
+                    None => break Outcome::TapeUnderflow(spans[p.ip]),
+                },
+                Token::Shr if p.rp + 1 == NUM_REGISTERS => {
+                    break Outcome::TapeOverflow(spans[p.ip]);
+                }
+                Token::Shr => p.rp += 1,
+                Token::Inc => p.set(p.get().add(1)),
+                Token::Dec => p.set(p.get().add(u8::MAX)),
+                Token::Output => {
+                    if p.output_len < MAX_PRINTED_OUTPUT {
+                        p.output.push(p.get());
+                    }
+                    p.output_len += 1;
+                }
+                Token::Input if p.eof => {
+                    if let Some(v) = config.eof.value() {
+                        p.set(Value::Const(v));
+                    }
+                }
+                Token::Input => {
+                    // fork a path reading past the end of the input
+                    let mut ended = p.clone();
+                    ended.eof = true;
+                    ended.steps -= 1;
+                    if p.inputs.len() == max_input {
+                        p = ended;
+                        continue;
+                    }
+                    pending.push(ended);
+                    let index = p.inputs.len();
+                    p.inputs.push(Domain::ALL);
+                    p.set(Value::Input { index, add: 0 });
+                }
+                Token::LSquare | Token::RSquare => {
+                    // the inputs leading to the jump being taken, and not
+                    let (zero, nonzero) = branch(&p, p.get());
+                    let (jump, fall_through) = match token {
+                        Token::LSquare => (zero, nonzero),
+                        _ => (nonzero, zero),
+                    };
+                    match (jump, fall_through) {
+                        (Some(jump), Some(fall_through)) => {
+                            if p.forks == max_depth {
+                                break Outcome::DepthLimit;
+                            }
+                            p.forks += 1;
+                            let mut other = p.clone();
+                            other.inputs = jump;
+                            other.ip = matching[other.ip] + 1;
+                            pending.push(other);
+                            p.inputs = fall_through;
+                        }
+                        (Some(jump), None) => {
+                            p.inputs = jump;
+                            p.ip = matching[p.ip];
+                        }
+                        (None, Some(fall_through)) => p.inputs = fall_through,
+                        (None, None) => unreachable!("the domains of the inputs are never empty"),
+                    }
+                }
+            }
+            p.ip += 1;
+        };
+        let output = p.output.iter().map(|&v| p.resolve(v)).collect();
+        reports.push(Report {
+            outcome,
+            inputs: p.inputs,
+            eof: p.eof,
+            output,
+            output_len: p.output_len,
+        });
+    }
+
+    for (i, r) in reports.iter().enumerate() {
+        println!("path {}: {}", i + 1, r.outcome);
+        let mut input = String::new();
+        for (j, d) in r.inputs.iter().enumerate() {
+            _ = write!(input, "{}in{j} = {d}", if j > 0 { ", " } else { "" });
+        }
+        match (r.inputs.is_empty(), r.eof) {
+            (true, true) => input.push_str("empty"),
+            (true, false) => input.push_str("not read"),
+            (false, true) => input.push_str(", then the end"),
+            (false, false) => (),
+        }
+        println!("    input:  {input}");
+        let mut output = format_output(&r.output);
+        if r.output_len > r.output.len() {
+            _ = write!(output, " and {} more bytes", r.output_len - r.output.len());
+        }
+        println!("    output: {output}");
+    }
+    let limited = reports
+        .iter()
+        .filter(|r| matches!(r.outcome, Outcome::DepthLimit | Outcome::StepLimit));
+    let num_limited = limited.count();
+    println!("============================================================");
+    println!(
+        "{} paths, {num_limited} stopped by a limit{}",
+        reports.len(),
+        match pending.is_empty() {
+            true => "",
+            false => ", more paths weren't explored",
+        }
+    );
+    ExitCode::SUCCESS
+}
+
+/// The domains of the inputs if the `value` is zero, and if it isn't, or `None` if the value can't
+/// be zero or non-zero on the path `p`.
+fn branch(p: &Execution, value: Value) -> (Option<Vec<Domain>>, Option<Vec<Domain>>) {
+    match value {
+        Value::Const(0) => (Some(p.inputs.clone()), None),
+        Value::Const(_) => (None, Some(p.inputs.clone())),
+        Value::Input { index, add } => {
+            let domain = p.inputs[index];
+            // the input byte which makes the register zero
+            let v = add.wrapping_neg();
+            let with = |d: Domain| {
+                let mut inputs = p.inputs.clone();
+                inputs[index] = d;
+                inputs
+            };
+            let zero = domain.contains(v).then(|| with(Domain::only(v)));
+            let nonzero = (domain != Domain::only(v)).then(|| with(domain.remove(v)));
+            (zero, nonzero)
+        }
+    }
+}
+
+/// The output as a string literal, with the bytes depending on the input written like `{in0+1}`.
+fn format_output(output: &[Value]) -> String {
+    let mut s = String::from("\"");
+    for v in output {
+        match *v {
+            Value::Const(b) => s.extend(std::ascii::escape_default(b).map(char::from)),
+            Value::Input { index, add: 0 } => _ = write!(s, "{{in{index}}}"),
+            Value::Input { index, add } => match add as i8 {
+                n @ 1.. => _ = write!(s, "{{in{index}+{n}}}"),
+                n => _ = write!(s, "{{in{index}{n}}}"),
+            },
+        }
+    }
+    s.push('"');
+    s
+}
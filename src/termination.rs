@@ -0,0 +1,211 @@
+//! A termination analysis of each loop, reported by `check --termination`. A loop terminates if
+//! its iteration register is a ranking function: the loop doesn't move the pointer, all inner
+//! loops terminate, and each iteration changes the register by an odd amount, so it reaches zero
+//! after at most 256 iterations of wrapping arithmetic.
+
+use std::collections::BTreeSet;
+
+use crate::{Span, Token};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    Terminates,
+    /// The loop doesn't terminate for some or all values of its iteration register.
+    PossiblyNonTerminating,
+    /// The analysis couldn't decide, like for loops depending on input or scanning the tape.
+    Unknown,
+}
+
+impl std::fmt::Display for Termination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Termination::Terminates => "terminates",
+            Termination::PossiblyNonTerminating => "possibly non-terminating",
+            Termination::Unknown => "unknown",
+        })
+    }
+}
+
+/// The result of the analysis of a loop, located at its `[`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub span: Span,
+    pub termination: Termination,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}, {}", self.span, self.termination, self.reason)
+    }
+}
+
+/// The change of the iteration register in one iteration of a loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Diff {
+    Relative(u8),
+    Absolute(u8),
+    Unknown,
+}
+
+/// The facts about a loop the enclosing loop depends on.
+struct Summary {
+    termination: Termination,
+    /// The registers the loop may write relative to its start, `None` if it moves the pointer.
+    writes: Option<BTreeSet<i64>>,
+}
+
+/// Analyze each loop of the brainfuck source code `src`, and return the reports in the order of
+/// the source code. Unmatched brackets are ignored.
+pub fn analyze(src: &[u8]) -> Vec<Report> {
+    let (tokens, spans): (Vec<Token>, Vec<Span>) = crate::tokens(src).unzip();
+    let mut matching = vec![None; tokens.len()];
+    let mut stack = Vec::new();
+    for (i, t) in tokens.iter().enumerate() {
+        match t {
+            Token::LSquare => stack.push(i),
+            Token::RSquare => {
+                if let Some(start) = stack.pop() {
+                    matching[start] = Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut reports = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match matching[i] {
+            Some(end) => {
+                analyze_loop(&tokens, &spans, &matching, i, &mut reports);
+                i = end + 1;
+            }
+            None => i += 1,
+        }
+    }
+    reports.sort_by_key(|r| r.span);
+    reports
+}
+
+/// Analyze the loop starting at the token `start` and its inner loops, and add their reports.
+fn analyze_loop(
+    tokens: &[Token],
+    spans: &[Span],
+    matching: &[Option<usize>],
+    start: usize,
+    reports: &mut Vec<Report>,
+) -> Summary {
+    let end = matching[start].expect("only called on matched loops");
+    let mut offset = Some(0_i64);
+    let mut diff = Diff::Relative(0);
+    let mut writes = BTreeSet::new();
+    // the first inner loop that doesn't terminate, if any
+    let mut inner: Option<(Span, Termination)> = None;
+    let mut i = start + 1;
+    while i < end {
+        match tokens[i] {
+            Token::Shl => offset = offset.map(|o| o - 1),
+            Token::Shr => offset = offset.map(|o| o + 1),
+            Token::Inc | Token::Dec => {
+                let n = if tokens[i] == Token::Inc { 1 } else { u8::MAX };
+                if offset == Some(0) {
+                    diff = match diff {
+                        Diff::Relative(d) => Diff::Relative(d.wrapping_add(n)),
+                        Diff::Absolute(v) => Diff::Absolute(v.wrapping_add(n)),
+                        Diff::Unknown => Diff::Unknown,
+                    };
+                }
+                writes.extend(offset);
+            }
+            Token::Input => {
+                if offset == Some(0) {
+                    diff = Diff::Unknown;
+                }
+                writes.extend(offset);
+            }
+            Token::Output => (),
+            Token::LSquare => {
+                let Some(inner_end) = matching[i] else {
+                    i += 1;
+                    continue;
+                };
+                let summary = analyze_loop(tokens, spans, matching, i, reports);
+                if summary.termination != Termination::Terminates && inner.is_none() {
+                    inner = Some((spans[i], summary.termination));
+                }
+                match (offset, summary.writes) {
+                    (Some(o), Some(w)) => {
+                        // the inner loop ends with its iteration register set to zero
+                        if o == 0 {
+                            diff = Diff::Absolute(0);
+                        } else if w.contains(&-o) {
+                            diff = Diff::Unknown;
+                        }
+                        writes.extend(w.iter().map(|w| o + w));
+                    }
+                    _ => offset = None,
+                }
+                i = inner_end;
+            }
+            Token::RSquare => (),
+        }
+        i += 1;
+    }
+
+    let (termination, reason) = match (offset, inner, diff) {
+        (Some(0), Some((span, termination)), _) => {
+            (termination, format!("the inner loop at {span} doesn't terminate"))
+        }
+        (Some(0), None, Diff::Relative(d)) if d % 2 == 1 => (
+            Termination::Terminates,
+            format!(
+                "the loop changes its iteration register by {} in each iteration, so it reaches zero after at most 256 iterations",
+                d as i8
+            ),
+        ),
+        (Some(0), None, Diff::Relative(0)) => (
+            Termination::PossiblyNonTerminating,
+            "the loop doesn't change its iteration register, so it never terminates once it's entered".to_owned(),
+        ),
+        (Some(0), None, Diff::Relative(d)) => {
+            let gcd = 1_u8 << d.trailing_zeros();
+            (
+                Termination::PossiblyNonTerminating,
+                format!(
+                    "the loop changes its iteration register by {} in each iteration, so it only terminates if the register is a multiple of {gcd}",
+                    d as i8
+                ),
+            )
+        }
+        (Some(0), None, Diff::Absolute(0)) => (
+            Termination::Terminates,
+            "the loop always sets its iteration register to zero, so it runs at most once".to_owned(),
+        ),
+        (Some(0), None, Diff::Absolute(v)) => (
+            Termination::PossiblyNonTerminating,
+            format!("the loop always sets its iteration register to {v}, so it never terminates once it's entered"),
+        ),
+        (Some(0), None, Diff::Unknown) => (
+            Termination::Unknown,
+            "the loop reads its iteration register from the input, or an inner loop changes it".to_owned(),
+        ),
+        (Some(n), _, _) => (
+            Termination::Unknown,
+            format!("the loop moves the pointer by {n} in each iteration, so it terminates once it reaches a zero register"),
+        ),
+        (None, _, _) => (
+            Termination::Unknown,
+            "an inner loop moves the pointer by an unknown distance".to_owned(),
+        ),
+    };
+    reports.push(Report {
+        span: spans[start],
+        termination,
+        reason,
+    });
+    Summary {
+        termination,
+        writes: (offset == Some(0)).then_some(writes),
+    }
+}
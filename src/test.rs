@@ -0,0 +1,229 @@
+//! The `test` mode, which runs test suites of brainfuck programs. A test is a program in the
+//! `tests` directory with an adjacent `.in` file containing its input and `.out` file containing
+//! its expected output, or a `[test.<name>]` section of the manifest of the project. The tests
+//! are run in parallel, and reported in order.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use crate::backend::RuntimeOptions;
+use crate::cli::Config;
+use crate::interp::{Interpreter, Program, Status};
+use crate::project::{Manifest, TestCase};
+use crate::{error, expect, optim, parallel, pragma, selftest, x86, Instruction};
+
+const DEFAULT_MAX_STEPS: u64 = 100_000_000;
+/// The directory searched for tests if no paths are passed.
+const TESTS_DIR: &str = "tests";
+
+/// Run the tests at `paths`, or the ones in the `tests` directory and the manifest of the project
+/// in the working directory, and print a summary. Fails if any test fails.
+pub fn test(config: &Config, paths: Vec<PathBuf>) -> ExitCode {
+    let cases = match paths.is_empty() {
+        true => default_cases(),
+        false => cases(paths),
+    };
+    let cases = match cases {
+        Ok(cases) if cases.is_empty() => {
+            error!("no tests found, expected programs in `{TESTS_DIR}` or `[test.<name>]` sections in the manifest");
+            return ExitCode::FAILURE;
+        }
+        Ok(cases) => cases,
+        Err(errors) => {
+            for e in errors {
+                error!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let name_width = cases.iter().map(|c| c.name.len()).max().unwrap_or(0) + 2;
+    let (mut passed, mut failed) = (0, 0);
+    let jobs = config.jobs.unwrap_or_else(parallel::default_jobs);
+    let run = |case: &TestCase| {
+        let mut failures = Vec::new();
+        if let Err(e) = run_interpreted(config, case) {
+            failures.push(("run", e));
+        }
+        // compiled executables don't enforce limits, so only tests expected to finish are run
+        if config.compiled && case.exit == 0 {
+            if let Err(e) = run_compiled(config, case) {
+                failures.push(("compile", e));
+            }
+        }
+        failures
+    };
+    parallel::map_ordered(&cases, jobs, run, |case, failures| {
+        if failures.is_empty() {
+            println!("{:<name_width$}ok", case.name);
+            passed += 1;
+        } else {
+            println!("{:<name_width$}FAILED", case.name);
+            for (backend, lines) in failures.iter() {
+                println!("    {backend}: {}", lines[0]);
+                for line in lines[1..].iter() {
+                    println!("        {line}");
+                }
+            }
+            failed += 1;
+        }
+    });
+
+    println!();
+    println!("{passed} passed, {failed} failed");
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// The tests in the `tests` directory, if it exists, followed by the ones of the manifest.
+fn default_cases() -> Result<Vec<TestCase>, Vec<String>> {
+    let mut cases = match Path::new(TESTS_DIR).is_dir() {
+        true => cases(vec![PathBuf::from(TESTS_DIR)])?,
+        false => Vec::new(),
+    };
+    if let Some(manifest) = Manifest::load(Path::new(".")) {
+        cases.extend(manifest?.tests);
+    }
+    Ok(cases)
+}
+
+/// The programs at `paths`, directories are searched recursively, with their adjacent `.in` and
+/// `.out` files.
+fn cases(paths: Vec<PathBuf>) -> Result<Vec<TestCase>, Vec<String>> {
+    let programs = crate::collect_programs(paths).map_err(|(path, e)| {
+        let path = path.display();
+        vec![format!("failed to read `{path}`: {e}")]
+    })?;
+    let adjacent =
+        |path: &Path, extension| Some(path.with_extension(extension)).filter(|p| p.is_file());
+    let cases = (programs.into_iter())
+        .map(|path| TestCase {
+            name: path.with_extension("").display().to_string(),
+            input: adjacent(&path, "in"),
+            output: adjacent(&path, "out"),
+            main: path,
+            exit: 0,
+        })
+        .collect();
+    Ok(cases)
+}
+
+/// Interpret the program of the test case, and compare its output and exit code. Returns the
+/// lines describing the failure.
+fn run_interpreted(config: &Config, case: &TestCase) -> Result<(), Vec<String>> {
+    let Prepared {
+        config,
+        input,
+        expected,
+    } = prepare(config, case)?;
+    let program = Program::new(&config, build(&config, &case.main)?);
+
+    let mut limits = config.limits();
+    limits.steps = Some(limits.steps.unwrap_or(DEFAULT_MAX_STEPS));
+    let mut output = Vec::new();
+    let mut interpreter =
+        Interpreter::from_config(&config, &program, input.as_slice(), &mut output)
+            .with_limits(limits);
+    if config.strict_eof {
+        interpreter = interpreter.with_strict_eof();
+    }
+    let status = interpreter.run();
+    drop(interpreter);
+
+    let mut failure = Vec::new();
+    let exit = matches!(status, Status::Exceeded(_)) as u8;
+    if exit != case.exit {
+        let reason = match status {
+            Status::Exceeded(limit) => format!(" ({limit})"),
+            _ => String::new(),
+        };
+        let expected = case.exit;
+        failure.push(format!(
+            "exited with {exit}{reason}, but {expected} was expected"
+        ));
+    }
+    compare_output(expected.as_deref(), &output, &mut failure);
+    match failure.is_empty() {
+        true => Ok(()),
+        false => Err(failure),
+    }
+}
+
+/// Compile the program of the test case to an executable, run it, and compare its output.
+/// Returns the lines describing the failure.
+fn run_compiled(config: &Config, case: &TestCase) -> Result<(), Vec<String>> {
+    let Prepared {
+        config,
+        input,
+        expected,
+    } = prepare(config, case)?;
+    let instructions = build(&config, &case.main)?;
+    let options = RuntimeOptions::new(&config);
+    let code = x86::compile(&config, &options, &instructions);
+    let output = selftest::run_executable(&code, &input).map_err(|e| vec![e])?;
+
+    let mut failure = Vec::new();
+    compare_output(expected.as_deref(), &output, &mut failure);
+    match failure.is_empty() {
+        true => Ok(()),
+        false => Err(failure),
+    }
+}
+
+/// The configuration a test case is run with, its input and its expected output.
+struct Prepared {
+    config: Config,
+    input: Vec<u8>,
+    expected: Option<Vec<u8>>,
+}
+
+/// Apply the pragmas of the program, and read the input and the expected output of the test case.
+fn prepare(config: &Config, case: &TestCase) -> Result<Prepared, Vec<String>> {
+    let config = pragma::apply_file(config, &case.main)?;
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| {
+            let path = path.display();
+            vec![format!("failed to read `{path}`: {e}")]
+        })
+    };
+    let input = match &case.input {
+        Some(path) => read(path)?,
+        None => Vec::new(),
+    };
+    let expected = case.output.as_deref().map(read).transpose()?;
+    Ok(Prepared {
+        config,
+        input,
+        expected,
+    })
+}
+
+/// Parse and optimize the program the same way as the `run` and `compile` modes.
+pub fn build(config: &Config, path: &Path) -> Result<Vec<Instruction>, Vec<String>> {
+    let mut instructions = match File::open(path).and_then(|f| crate::parse(config, f)) {
+        Ok(Ok(i)) => i,
+        Ok(Err(errors)) => return Err(errors),
+        Err(e) => {
+            let path = path.display();
+            return Err(vec![format!("failed to read `{path}`: {e}")]);
+        }
+    };
+    if config.optimize() {
+        optim::optimize(config, &mut instructions);
+    }
+    crate::update_jump_indices(&mut instructions);
+    optim::thread_jumps(config, &mut instructions);
+    Ok(instructions)
+}
+
+/// Add the diff of the output to the `failure` if it doesn't match the expected output.
+fn compare_output(expected: Option<&[u8]>, output: &[u8], failure: &mut Vec<String>) {
+    if let Some(expected) = expected.filter(|&e| e != output) {
+        failure.push("the output doesn't match the expected output".to_owned());
+        failure.extend(expect::diff(expected, output));
+    }
+}
@@ -0,0 +1,317 @@
+//! Terminal handling of the `--interactive` mode, so interactive programs like games receive each
+//! key as soon as it's pressed, and see their output immediately, and of `--sanitize-output`, so
+//! untrusted programs can't control the terminal. Reads from stdin are also bounded by the
+//! time limit, see [`TimedStdin`].
+
+use std::io::{ErrorKind, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::FromRawFd;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+const STDIN_FD: i32 = 0;
+const STDOUT_FD: i32 = 1;
+const TCSANOW: i32 = 0;
+/// Canonical mode, the input is delivered one line at a time.
+const ICANON: u32 = 0o2;
+const VTIME: usize = 5;
+const VMIN: usize = 6;
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+/// There is data to read.
+const POLLIN: i16 = 0x1;
+
+/// The `termios` struct of Linux on x86-64.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Termios {
+    c_iflag: u32,
+    c_oflag: u32,
+    c_cflag: u32,
+    c_lflag: u32,
+    c_line: u8,
+    c_cc: [u8; 32],
+    c_ispeed: u32,
+    c_ospeed: u32,
+}
+
+#[repr(C)]
+struct PollFd {
+    fd: i32,
+    events: i16,
+    revents: i16,
+}
+
+extern "C" {
+    fn isatty(fd: i32) -> i32;
+    fn tcgetattr(fd: i32, termios: *mut Termios) -> i32;
+    fn tcsetattr(fd: i32, action: i32, termios: *const Termios) -> i32;
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    fn _exit(status: i32) -> !;
+    fn poll(fds: *mut PollFd, nfds: u64, timeout: i32) -> i32;
+}
+
+/// The settings of the terminal before non-canonical mode was enabled, which are restored when the
+/// process is interrupted.
+static ORIGINAL: OnceLock<Termios> = OnceLock::new();
+
+/// Restore the terminal and exit like the default action of the signal, `tcsetattr` and `_exit`
+/// are async-signal-safe.
+extern "C" fn restore_and_exit(signum: i32) {
+    if let Some(original) = ORIGINAL.get() {
+        unsafe { tcsetattr(STDIN_FD, TCSANOW, original) };
+    }
+    unsafe { _exit(128 + signum) };
+}
+
+/// The terminal connected to stdin in non-canonical mode, so reads return as soon as a single key
+/// is pressed instead of waiting for enter. Echoing and signal keys like `ctrl-c` keep working.
+/// The original settings are restored when dropped, or when the process is interrupted.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    /// Enable non-canonical mode, or return `None` if stdin isn't a terminal.
+    pub fn enable() -> Option<Self> {
+        if unsafe { isatty(STDIN_FD) } == 0 {
+            return None;
+        }
+        let mut original = std::mem::MaybeUninit::<Termios>::uninit();
+        if unsafe { tcgetattr(STDIN_FD, original.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let original = unsafe { original.assume_init() };
+
+        let mut raw = original;
+        raw.c_lflag &= !ICANON;
+        raw.c_cc[VMIN] = 1;
+        raw.c_cc[VTIME] = 0;
+        _ = ORIGINAL.set(original);
+        unsafe {
+            signal(SIGINT, restore_and_exit);
+            signal(SIGTERM, restore_and_exit);
+            if tcsetattr(STDIN_FD, TCSANOW, &raw) != 0 {
+                return None;
+            }
+        }
+        Some(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { tcsetattr(STDIN_FD, TCSANOW, &self.original) };
+    }
+}
+
+/// The unbuffered stdin, whose reads fail with [`ErrorKind::TimedOut`] once the `deadline` passed,
+/// so programs waiting for input can't exceed the time limit.
+pub struct TimedStdin {
+    stdin: ManuallyDrop<std::fs::File>,
+    deadline: Instant,
+}
+
+impl TimedStdin {
+    pub fn new(deadline: Instant) -> Self {
+        Self {
+            // stdin stays open when dropped
+            stdin: ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(STDIN_FD) }),
+            deadline,
+        }
+    }
+}
+
+impl Read for TimedStdin {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let left = self.deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let mut fd = PollFd {
+                fd: STDIN_FD,
+                events: POLLIN,
+                revents: 0,
+            };
+            let timeout = left.as_millis().clamp(1, i32::MAX as u128) as i32;
+            match unsafe { poll(&mut fd, 1, timeout) } {
+                0 => continue,
+                ..0 => {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() != ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                _ => return self.stdin.read(buf),
+            }
+        }
+    }
+}
+
+/// Writes the output immediately instead of buffering it until a newline. If the output goes to
+/// a terminal and doesn't end with a newline, one is written when dropped, so the prompt of the
+/// shell starts on a new line.
+pub struct Unbuffered<W: Write> {
+    inner: W,
+    terminal: bool,
+    last: Option<u8>,
+}
+
+impl<W: Write> Unbuffered<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            terminal: unsafe { isatty(STDOUT_FD) } != 0,
+            last: None,
+        }
+    }
+}
+
+impl<W: Write> Write for Unbuffered<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+        }
+        self.inner.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Unbuffered<W> {
+    fn drop(&mut self) {
+        if self.terminal && self.last.is_some_and(|b| b != b'\n') {
+            _ = self.inner.write_all(b"\n");
+        }
+        _ = self.inner.flush();
+    }
+}
+
+/// Escapes the control characters a terminal would interpret if the output goes to a terminal, so
+/// programs can't inject escape sequences or ring the bell. C0 control characters are written in
+/// caret notation like `^[`, except for newlines, tabs, carriage returns and backspaces. C1 control
+/// characters, both UTF-8 encoded and as raw bytes like the 8-bit CSI `0x9b`, are written like
+/// `\x9b`. Output that doesn't go to a terminal is left untouched.
+pub struct Sanitized<W: Write> {
+    inner: W,
+    terminal: bool,
+    /// Whether the last byte was `0xc2`, the first byte of the UTF-8 encoding of C1 control
+    /// characters, which isn't written until the next byte is known.
+    pending: bool,
+    /// The number of continuation bytes of the current UTF-8 encoded char still to come, which
+    /// aren't C1 control characters.
+    continuation: u8,
+}
+
+impl<W: Write> Sanitized<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            terminal: unsafe { isatty(STDOUT_FD) } != 0,
+            pending: false,
+            continuation: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for Sanitized<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.terminal {
+            return self.inner.write(buf);
+        }
+        let mut escaped = Vec::with_capacity(buf.len());
+        for &b in buf {
+            if std::mem::take(&mut self.pending) {
+                match b {
+                    0x80..=0x9f => {
+                        _ = write!(escaped, "\\x{b:02x}");
+                        continue;
+                    }
+                    0xa0..=0xbf => {
+                        escaped.extend_from_slice(&[0xc2, b]);
+                        continue;
+                    }
+                    _ => escaped.push(0xc2),
+                }
+            }
+            let continuation = std::mem::take(&mut self.continuation);
+            if continuation > 0 && (0x80..=0xbf).contains(&b) {
+                self.continuation = continuation - 1;
+                escaped.push(b);
+                continue;
+            }
+            match b {
+                b'\n' | b'\t' | b'\r' | 0x08 => escaped.push(b),
+                0x00..=0x1f => escaped.extend_from_slice(&[b'^', b + 0x40]),
+                0x7f => escaped.extend_from_slice(b"^?"),
+                0x80..=0x9f => _ = write!(escaped, "\\x{b:02x}"),
+                0xc2 => self.pending = true,
+                _ => {
+                    self.continuation = match b {
+                        0xc3..=0xdf => 1,
+                        0xe0..=0xef => 2,
+                        0xf0..=0xf4 => 3,
+                        _ => 0,
+                    };
+                    escaped.push(b);
+                }
+            }
+        }
+        self.inner.write_all(&escaped)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Sanitized<W> {
+    fn drop(&mut self) {
+        if self.pending {
+            _ = self.inner.write_all(&[0xc2]);
+        }
+        _ = self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The output of writing the `chunks` to a terminal.
+    fn sanitize(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut sanitized = Sanitized {
+            inner: &mut output,
+            terminal: true,
+            pending: false,
+            continuation: 0,
+        };
+        for chunk in chunks {
+            sanitized.write_all(chunk).unwrap();
+        }
+        drop(sanitized);
+        output
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(sanitize(&[b"\x1b[2Ja\x07\n"]), b"^[[2Ja^G\n");
+        assert_eq!(sanitize(&[b"\x9b2J"]), b"\\x9b2J");
+        assert_eq!(sanitize(&[b"\xc2", b"\x9b2J"]), b"\\x9b2J");
+    }
+
+    #[test]
+    fn utf8_is_written_unchanged() {
+        let text = "é€ 🦀 \u{a0}".as_bytes();
+        assert_eq!(sanitize(&[text]), text);
+        let chunks = text.chunks(1).collect::<Vec<_>>();
+        assert_eq!(sanitize(&chunks), text);
+    }
+}
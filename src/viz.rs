@@ -0,0 +1,294 @@
+//! Tape logs recording snapshots of the tape while a program is interpreted, written by
+//! `--tape-log`, and the `viz` mode rendering them as a heatmap of the registers over time.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::cli::Config;
+use crate::interp::{Interpreter, State, Status};
+use crate::{error, NUM_REGISTERS};
+
+const MAGIC: &[u8; 6] = b"bftape";
+const FORMAT_VERSION: u8 = 1;
+/// The number of executed instructions between snapshots, if `--tape-interval` isn't passed.
+pub const DEFAULT_INTERVAL: u64 = 10_000;
+/// The color of the register the pointer is at.
+const POINTER_COLOR: [u8; 3] = [0x00, 0xff, 0xff];
+
+/// The tape after a number of executed instructions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub steps: u64,
+    /// The register pointer.
+    pub rp: i16,
+    /// The registers up to the last one that isn't zero or the pointer is at, the rest are zero.
+    pub registers: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn new(state: &State, steps: u64) -> Self {
+        let last = state.registers.iter().rposition(|&r| r != 0);
+        let pointer = usize::try_from(state.rp)
+            .ok()
+            .filter(|&rp| rp < NUM_REGISTERS);
+        let len = last.max(pointer).map_or(0, |i| i + 1);
+        Self {
+            steps,
+            rp: state.rp,
+            registers: state.registers[..len].to_vec(),
+        }
+    }
+
+    /// Serialize the snapshot as the step count, the register pointer, the number of registers,
+    /// and the registers.
+    fn serialize(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.steps.to_le_bytes());
+        bytes.extend_from_slice(&self.rp.to_le_bytes());
+        bytes.extend_from_slice(&(self.registers.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.registers);
+    }
+}
+
+/// Deserialize a tape log, the magic bytes and format version followed by the snapshots.
+pub fn deserialize(bytes: &[u8]) -> Option<Vec<Snapshot>> {
+    let mut bytes = bytes.strip_prefix(&header())?;
+    let mut snapshots = Vec::new();
+    while !bytes.is_empty() {
+        let steps = u64::from_le_bytes(take(&mut bytes)?);
+        let rp = i16::from_le_bytes(take(&mut bytes)?);
+        let len = u32::from_le_bytes(take(&mut bytes)?) as usize;
+        if len > NUM_REGISTERS || len > bytes.len() {
+            return None;
+        }
+        let (registers, rest) = bytes.split_at(len);
+        bytes = rest;
+        snapshots.push(Snapshot {
+            steps,
+            rp,
+            registers: registers.to_vec(),
+        });
+    }
+    Some(snapshots)
+}
+
+fn header() -> [u8; MAGIC.len() + 1] {
+    let mut header = [FORMAT_VERSION; MAGIC.len() + 1];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+    let (chunk, rest) = bytes.split_first_chunk::<N>()?;
+    *bytes = rest;
+    Some(*chunk)
+}
+
+/// Run the `interpreter`, and write a snapshot of the tape to the log at `path` every `interval`
+/// executed instructions, and once more when the program stops. The snapshots are written while
+/// the program runs, so the log of a program that is killed can still be rendered.
+pub fn run_logged<R: Read, W: Write>(
+    interpreter: &mut Interpreter<R, W>,
+    interval: u64,
+    path: &Path,
+) -> std::io::Result<Status> {
+    let mut log = BufWriter::new(File::create(path)?);
+    log.write_all(&header())?;
+    let mut res = Ok(());
+    let mut bytes = Vec::new();
+    let status = interpreter.run_with_snapshots(interval, |state, steps| {
+        if res.is_ok() {
+            bytes.clear();
+            Snapshot::new(state, steps).serialize(&mut bytes);
+            res = log.write_all(&bytes);
+        }
+    });
+    res.and_then(|_| log.flush())?;
+    Ok(status)
+}
+
+/// Render the tape log at `path` as an image, with one column per register and one row per
+/// snapshot, the first snapshot at the top. The image is written to `--output`, or next to the
+/// log with the `png` extension, as a PPM image if the output has the `ppm` extension.
+pub fn viz(config: &Config, path: &Path) -> ExitCode {
+    let snapshots = match std::fs::read(path) {
+        Ok(bytes) => match deserialize(&bytes) {
+            Some(snapshots) => snapshots,
+            None => {
+                let path = path.display();
+                error!("`{path}` isn't a valid tape log");
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(e) => {
+            let path = path.display();
+            error!("failed to read `{path}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if snapshots.is_empty() {
+        let path = path.display();
+        error!("`{path}` doesn't contain any snapshots");
+        return ExitCode::FAILURE;
+    }
+
+    let image = render(&snapshots);
+    let out_path = config
+        .output
+        .clone()
+        .unwrap_or_else(|| path.with_extension("png"));
+    let bytes = match out_path.extension().is_some_and(|e| e == "ppm") {
+        true => image.ppm(),
+        false => image.png(),
+    };
+    if let Err(e) = std::fs::write(&out_path, bytes) {
+        let out_path = out_path.display();
+        error!("failed to write `{out_path}`: {e}");
+        return ExitCode::FAILURE;
+    }
+    let (width, height) = (image.width, image.height);
+    let out_path = out_path.display();
+    println!("written {width}x{height} heatmap to `{out_path}`");
+    ExitCode::SUCCESS
+}
+
+/// An RGB image, stored row by row.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// Render the snapshots as a heatmap, zero registers are black, and larger values go from purple
+/// over red and orange to yellow. The register the pointer is at is drawn in cyan.
+pub fn render(snapshots: &[Snapshot]) -> Image {
+    let width = (snapshots.iter())
+        .map(|s| s.registers.len())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let height = snapshots.len();
+    let mut pixels = Vec::with_capacity(3 * width * height);
+    for s in snapshots {
+        for i in 0..width {
+            let value = s.registers.get(i).copied().unwrap_or(0);
+            match i as i64 == s.rp as i64 {
+                true => pixels.extend_from_slice(&POINTER_COLOR),
+                false => pixels.extend_from_slice(&heat(value)),
+            }
+        }
+    }
+    Image {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// Interpolate between the colors of the heatmap, which are evenly spaced over the values.
+fn heat(value: u8) -> [u8; 3] {
+    const COLORS: [[u8; 3]; 5] = [
+        [0x00, 0x00, 0x00],
+        [0x50, 0x12, 0x7b],
+        [0xc0, 0x2a, 0x40],
+        [0xf5, 0x7d, 0x15],
+        [0xfc, 0xff, 0xa4],
+    ];
+    const SEGMENT: usize = 256 / (COLORS.len() - 1);
+
+    let (i, t) = (value as usize / SEGMENT, value as usize % SEGMENT);
+    let (from, to) = (COLORS[i], COLORS[(i + 1).min(COLORS.len() - 1)]);
+    // values in the first segment are shifted, so only zero is black
+    let t = match (i, value) {
+        (0, 1..) => t.max(SEGMENT / 4),
+        _ => t,
+    };
+    std::array::from_fn(|c| {
+        let (from, to) = (from[c] as usize, to[c] as usize);
+        ((from * (SEGMENT - t) + to * t) / SEGMENT) as u8
+    })
+}
+
+impl Image {
+    /// Encode the image as a binary PPM.
+    pub fn ppm(&self) -> Vec<u8> {
+        let mut bytes = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        bytes.extend_from_slice(&self.pixels);
+        bytes
+    }
+
+    /// Encode the image as a PNG, the image data is stored in uncompressed deflate blocks.
+    pub fn png(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity((3 * self.width + 1) * self.height);
+        for row in self.pixels.chunks(3 * self.width) {
+            // no filter
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8-bit depth, truecolor, deflate, adaptive filtering, no interlacing
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut bytes, b"IHDR", &ihdr);
+        write_chunk(&mut bytes, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut bytes, b"IEND", &[]);
+        bytes
+    }
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = bytes.len();
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(data);
+    let crc = crc32(&bytes[start..]);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = u16::MAX as usize;
+
+    // deflate with a 32K window, no preset dictionary, and a valid header checksum
+    let mut bytes = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        bytes.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        bytes.push(last as u8);
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(&(!len).to_le_bytes());
+        bytes.extend_from_slice(block);
+    }
+    bytes.extend_from_slice(&adler32(data).to_be_bytes());
+    bytes
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &byte in data {
+        a = (a + byte as u32) % MOD;
+        b = (b + a) % MOD;
+    }
+    (b << 16) | a
+}
@@ -1,50 +1,49 @@
-use crate::cli::Config;
-use crate::{Instruction, NUM_REGISTERS};
-
-#[repr(C)]
-pub struct ElfFileHeader {
-    pub ei_magic: [u8; 4],
-    pub ei_class: u8,
-    pub ei_data: u8,
-    pub ei_version: u8,
-    pub ei_osabi: u8,
-    pub ei_abiversion: u8,
-    pub ei_pad: [u8; 7],
-
-    pub e_type: u16,
-    pub e_machine: u16,
-    pub e_version: u32,
-    pub e_entry: u64,
-    /// program header offset
-    pub e_phoff: u64,
-    /// section header offset
-    pub e_shoff: u64,
-    pub e_flags: u32,
-    /// size of this header
-    pub e_ehsize: u16,
-    /// program header entry size
-    pub e_phentsize: u16,
-    /// number of program header table entries
-    pub e_phnum: u16,
-    /// section header entry size
-    pub e_shentsize: u16,
-    /// number of section header table entries
-    pub e_shnum: u16,
-    /// index of section header table entry that contains section names
-    pub e_shstrndx: u16,
-}
-
-#[repr(C)]
-pub struct ElfProgramHeader {
-    pub p_type: u32,
-    pub p_flags: u32,
-    pub p_offset: u64,
-    pub p_vaddr: u64,
-    pub p_paddr: u64,
-    pub p_filesz: u64,
-    pub p_memsz: u64,
-    pub p_align: u64,
-}
+use std::num::NonZeroU32;
+use std::ops::Range;
+
+use crate::backend::{Artifact, Backend, Checkpoint, RuntimeOptions};
+use crate::cache::{self, CodeCache, Fnv1a, Segment};
+use crate::cli::{self, Config, Pass};
+use crate::jit::Io;
+use crate::object::{Executable, Machine, Note, BSS_VADDR};
+use crate::optim::{self, PointerRange};
+use crate::{Instruction, Jump};
+
+/// Minimum number of instructions before the code of the top-level segments is generated in
+/// parallel.
+const PARALLEL_THRESHOLD: usize = 1 << 14;
+/// Minimum number of registers that have to be zeroed, for the tape of standalone executables to
+/// be mapped instead of being allocated on the stack.
+const MAP_TAPE_THRESHOLD: usize = 1 << 12;
+/// Number of bytes of stack space below a mapped tape, which also has to fit the frames of the
+/// signal handlers.
+const MAP_STACK_SIZE: usize = 1 << 16;
+/// Number of bytes of inaccessible guard pages on both ends of a mapped tape.
+const MAP_GUARD_SIZE: usize = 1 << 16;
+/// The granularity of memory protection.
+const PAGE_SIZE: usize = 1 << 12;
+/// Number of bytes of the stack buffer, that input is copied through by [`Instruction::Pipe`].
+const PIPE_BUF_LEN: usize = 1 << 12;
+/// Number of loop iterations after which the clock is read, to check if a [`Checkpoint`] is due.
+const CHECKPOINT_CLOCK_INTERVAL: i32 = 1 << 16;
+/// Number of loop iterations between safe points of programs without checkpoints, which only
+/// run earlier if a dump of the state is requested, see [`write_safe_point`].
+const SAFE_POINT_INTERVAL: i32 = i32::MAX;
+/// Number of bytes of the output buffer of standalone executables.
+const OUTPUT_BUF_LEN: i32 = 1 << 12;
+/// Number of bytes of the `termios` struct of the kernel, rounded up to a multiple of 8.
+const TERMIOS_LEN: i32 = 40;
+/// The runtime state of standalone executables is stored in the bss segment: the number of
+/// buffered output bytes, whether a dump of the state was requested using `SIGUSR1`, the output
+/// buffer, and the original and non-canonical settings of the terminal in the `--interactive`
+/// mode.
+const BSS_OUTPUT_LEN: i32 = BSS_VADDR as i32;
+const BSS_DUMP_REQUESTED: i32 = BSS_VADDR as i32 + 8;
+const BSS_OUTPUT_BUF: i32 = BSS_VADDR as i32 + 16;
+const BSS_TERMIOS: i32 = BSS_OUTPUT_BUF + OUTPUT_BUF_LEN;
+const BSS_TERMIOS_RAW: i32 = BSS_TERMIOS + TERMIOS_LEN;
+/// Number of bytes of the header of a checkpoint file, which precedes the tape.
+const CHECKPOINT_HEADER_LEN: i32 = 24;
 
 /// Address mode
 #[derive(Clone, Copy)]
@@ -119,6 +118,19 @@ pub enum Reg {
     Rdi = 0x7,
 }
 
+/// The extended registers `r8` to `r15`, which are selected using the `REX.B` prefix bit.
+#[derive(Clone, Copy)]
+pub enum RegExt {
+    R8 = 0x0,
+    R9 = 0x1,
+    R10 = 0x2,
+    R11 = 0x3,
+    R12 = 0x4,
+    R13 = 0x5,
+    R14 = 0x6,
+    R15 = 0x7,
+}
+
 /// Generate a `MOD-REG_R/M` byte with a `reg` field
 pub const fn modrm_reg(modrm: ModRm, reg: Reg) -> u8 {
     (modrm.mode() << 6) | ((reg as u8) << 3) | modrm.rm()
@@ -178,76 +190,98 @@ macro_rules! const_assert {
     };
 }
 
-/// Generate a 64-bit x86 linux ELF binary
-pub fn compile(config: &Config, instructions: &[Instruction]) -> Vec<u8> {
-    const B64_ELF_HEADER_LEN: usize = 0x40;
-    const B64_PROGRAM_HEADER_LEN: usize = 0x38;
-    const PROGRAM_OFFSET: usize = B64_ELF_HEADER_LEN + B64_PROGRAM_HEADER_LEN;
-    const VADDR: usize = 0x40_0000;
-
-    const_assert!(B64_ELF_HEADER_LEN == std::mem::size_of::<ElfFileHeader>());
-    const_assert!(B64_PROGRAM_HEADER_LEN == std::mem::size_of::<ElfProgramHeader>());
-
-    let elf_header: [u8; B64_ELF_HEADER_LEN] = {
-        let header = ElfFileHeader {
-            // e_ident
-            ei_magic: *b"\x7fELF",
-            ei_class: 0x02, // 64-bit
-            ei_data: 0x01,  // little-endian
-            ei_version: 0x01,
-            ei_osabi: 0x00, // system-v
-            ei_abiversion: 0x00,
-            ei_pad: [0x00; 7], // reserved
-
-            e_type: 0x0002,    // executable
-            e_machine: 0x003E, // AMD x86-64
-            e_version: 1,
-            e_entry: (VADDR + PROGRAM_OFFSET) as u64, // entry point offset
-            e_phoff: B64_ELF_HEADER_LEN as u64, // program header immediately follows the ELF header
-            e_shoff: 0,                         // no table thus 0 offset
-            e_flags: 0x0000_0000,               // no flags
-            e_ehsize: B64_ELF_HEADER_LEN as u16,
-            e_phentsize: B64_PROGRAM_HEADER_LEN as u16,
-            e_phnum: 1,
-            e_shentsize: 0, // no table thus irrelevant
-            e_shnum: 0,     // no table thus 0 entries
-            e_shstrndx: 0,
-        };
+/// The backend generating 64-bit x86 linux ELF binaries.
+pub struct Elf;
 
-        unsafe { std::mem::transmute(header) }
-    };
+impl Backend for Elf {
+    fn name(&self) -> &'static str {
+        "elf"
+    }
+
+    fn extension(&self) -> &'static str {
+        "elf"
+    }
+
+    fn compile(&self, config: &Config, ir: &[Instruction], options: &RuntimeOptions) -> Artifact {
+        Artifact::Executable(compile(config, options, ir))
+    }
+}
+
+/// Generate a 64-bit x86 linux ELF binary
+pub fn compile(config: &Config, options: &RuntimeOptions, instructions: &[Instruction]) -> Vec<u8> {
+    let mut cache = options.code_cache.as_deref().map(CodeCache::load);
+    let bytes = compile_elf(config, |code| {
+        let runtime = Runtime::Linux;
+        write_program(config, options, code, instructions, runtime, cache.as_mut());
+    });
+    if let Some(cache) = cache {
+        store_code_cache(config, &cache);
+    }
+    bytes
+}
 
-    let program_header: [u8; B64_PROGRAM_HEADER_LEN] = {
-        let header = ElfProgramHeader {
-            p_type: 0x0000_0001,                      // loadable segment
-            p_flags: 0x0000_0007,                     // read write execute
-            p_offset: PROGRAM_OFFSET as u64,          // loadable segment starts after this header
-            p_vaddr: (VADDR + PROGRAM_OFFSET) as u64, // virtual address to place the segment at
-            p_paddr: 0,                               // physical address is not used
-            p_filesz: 0,     // size of the whole file (write after code generation)
-            p_memsz: 0,      // size of the whole file (write after code generation)
-            p_align: 0x1000, // no alignment
+/// Generate a 64-bit x86 linux ELF binary, which runs the `programs` one after another, each with
+/// a fresh tape. If `pipe` is set, the output of each program is the input of the next one,
+/// otherwise they share the input and the output.
+pub fn compile_linked(
+    config: &Config,
+    options: &RuntimeOptions,
+    programs: &[Vec<Instruction>],
+    pipe: bool,
+) -> Vec<u8> {
+    let mut cache = options.code_cache.as_deref().map(CodeCache::load);
+    let bytes = compile_elf(config, |code| {
+        let runtime = Runtime::Linked {
+            shared_input: !pipe,
         };
+        if pipe {
+            write_save_stdout(code);
+        }
+        for (i, instructions) in programs.iter().enumerate() {
+            if pipe && i > 0 {
+                write_pipe_to_stdin(code);
+            }
+            if pipe && i < programs.len() - 1 {
+                write_pipe_from_stdout(code);
+            } else if pipe {
+                write_restore_stdout(code);
+            }
+            write_program(config, options, code, instructions, runtime, cache.as_mut());
+        }
 
-        unsafe { std::mem::transmute(header) }
-    };
+        const SYSCALL_EXIT: i32 = 60;
+        write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_EXIT));
+        write(code, xor_r64_r64(Reg::Rdi, Reg::Rdi));
+        write(code, SYSCALL);
+    });
+    if let Some(cache) = cache {
+        store_code_cache(config, &cache);
+    }
+    bytes
+}
 
-    let mut code = (elf_header.iter().copied())
-        .chain(program_header.iter().copied())
-        .collect();
+fn store_code_cache(config: &Config, cache: &CodeCache) {
+    if config.verbose >= 1 {
+        println!("reused {} segments from the code cache", cache.reused);
+        println!("============================================================");
+    }
+    cache.store();
+}
 
-    write_instructions(config, &mut code, instructions);
+/// Wrap the code generated by `write_code` into an ELF binary.
+fn compile_elf(config: &Config, write_code: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut executable = Executable::new(Machine::X86_64);
+    executable.bss_len = (BSS_TERMIOS_RAW - BSS_OUTPUT_LEN + TERMIOS_LEN) as usize;
+    write_code(&mut executable.text);
+    executable.notes.push(Note::version());
 
-    // update loadable segment size
-    {
-        let size = code.len() as u64 - PROGRAM_OFFSET as u64;
-        let program_header = &mut code[B64_ELF_HEADER_LEN..][..B64_PROGRAM_HEADER_LEN];
-        const P_FILESZ: usize = std::mem::offset_of!(ElfProgramHeader, p_filesz);
-        program_header[P_FILESZ..P_FILESZ + 8].copy_from_slice(&u64::to_le_bytes(size));
-        const P_MEMSZ: usize = std::mem::offset_of!(ElfProgramHeader, p_memsz);
-        program_header[P_MEMSZ..P_MEMSZ + 8].copy_from_slice(&u64::to_le_bytes(size));
+    #[cfg(feature = "disasm")]
+    if config.print_asm {
+        print_disassembly(&executable.text, executable.text_vaddr(), 64);
+        println!("============================================================");
     }
 
+    let code = executable.write();
     if config.verbose >= 1 {
         const K: usize = 1024;
         const M: usize = K * K;
@@ -266,103 +300,802 @@ pub fn compile(config: &Config, instructions: &[Instruction]) -> Vec<u8> {
     code
 }
 
-fn write_instructions(config: &Config, code: &mut Vec<u8>, instructions: &[Instruction]) {
-    // prepare brainfuck registers array
-    {
+/// Generate a 64-bit x86 `extern "sysv64" fn(*mut Io)` function, that can be executed inside the
+/// current process.
+pub fn compile_function(
+    config: &Config,
+    options: &RuntimeOptions,
+    instructions: &[Instruction],
+) -> Vec<u8> {
+    let mut code = Vec::new();
+    write_program(
+        config,
+        options,
+        &mut code,
+        instructions,
+        Runtime::InProcess,
+        None,
+    );
+    code
+}
+
+/// How the generated code interacts with its environment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Runtime {
+    /// A standalone linux executable performing I/O using system calls.
+    Linux,
+    /// A function called from inside the current process, performing I/O using the callbacks
+    /// passed in an [`Io`] struct.
+    InProcess,
+    /// A program inside a linked linux executable, which continues with the next program instead
+    /// of exiting. Unless the programs are piped, the input is shared with the following ones.
+    Linked { shared_input: bool },
+}
+
+fn write_program(
+    config: &Config,
+    options: &RuntimeOptions,
+    code: &mut Vec<u8>,
+    instructions: &[Instruction],
+    runtime: Runtime,
+    cache: Option<&mut CodeCache>,
+) {
+    if config.pass(Pass::Schedule) {
+        let (scheduled, origins) = schedule_pointer_moves(config, instructions);
+        write_instructions(config, options, code, &scheduled, &origins, runtime, cache);
+    } else {
+        let origins = (0..instructions.len()).collect::<Vec<_>>();
+        write_instructions(
+            config,
+            options,
+            code,
+            instructions,
+            &origins,
+            runtime,
+            cache,
+        );
+    }
+}
+
+/// Print the intel syntax disassembly of the generated machine `code` located at `vaddr`, which
+/// runs in 64-bit or 32-bit mode depending on the `bitness`.
+#[cfg(feature = "disasm")]
+pub fn print_disassembly(code: &[u8], vaddr: u64, bitness: u32) {
+    use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+
+    let mut decoder = Decoder::with_ip(bitness, code, vaddr, DecoderOptions::NONE);
+    let mut formatter = IntelFormatter::new();
+    let mut output = String::new();
+    for inst in &mut decoder {
+        output.clear();
+        formatter.format(&inst, &mut output);
+
+        let start = (inst.ip() - vaddr) as usize;
+        let bytes = &code[start..start + inst.len()];
+        print!("{:016x} ", inst.ip());
+        for b in bytes {
+            print!("{b:02x}");
+        }
+        for _ in bytes.len()..10 {
+            print!("  ");
+        }
+        println!(" {output}");
+    }
+}
+
+/// Defer pointer moves inside basic blocks by folding them into the displacement of the following
+/// instructions, so only one base-register adjustment is emitted per block. A block ends at jumps
+/// and instructions that read the current register.
+///
+/// The jump locations of the returned instructions aren't updated, since they aren't needed for
+/// code generation. The index of the instruction each scheduled instruction originates from is
+/// returned alongside.
+pub fn schedule_pointer_moves(
+    config: &Config,
+    instructions: &[Instruction],
+) -> (Vec<Instruction>, Vec<usize>) {
+    use Instruction::*;
+
+    fn flush(scheduled: &mut Vec<Instruction>, shift: &mut i16) {
+        match *shift {
+            ..=-1 => scheduled.push(Shl(shift.unsigned_abs())),
+            0 => (),
+            1.. => scheduled.push(Shr(*shift as u16)),
+        }
+        *shift = 0;
+    }
+
+    let mut scheduled = Vec::with_capacity(instructions.len());
+    let mut origins = Vec::with_capacity(instructions.len());
+    let mut shift: i16 = 0;
+    for (i, inst) in instructions.iter().enumerate() {
+        let displaced = |o: i16| shift.checked_add(o);
+        match *inst {
+            Shl(n) => match i16::try_from(n).ok().and_then(|n| shift.checked_sub(n)) {
+                Some(s) => shift = s,
+                None => {
+                    flush(&mut scheduled, &mut shift);
+                    scheduled.push(*inst);
+                }
+            },
+            Shr(n) => match i16::try_from(n).ok().and_then(|n| shift.checked_add(n)) {
+                Some(s) => shift = s,
+                None => {
+                    flush(&mut scheduled, &mut shift);
+                    scheduled.push(*inst);
+                }
+            },
+            Inc(o, n) if displaced(o).is_some() => scheduled.push(Inc(shift + o, n)),
+            Dec(o, n) if displaced(o).is_some() => scheduled.push(Dec(shift + o, n)),
+            Zero(o) if displaced(o).is_some() => scheduled.push(Zero(shift + o)),
+            Set(o, n) if displaced(o).is_some() => scheduled.push(Set(shift + o, n)),
+            Output(o) if displaced(o).is_some() => scheduled.push(Output(shift + o)),
+            Input(o) if displaced(o).is_some() => scheduled.push(Input(shift + o)),
+            PrintDecimal(o) if displaced(o).is_some() => scheduled.push(PrintDecimal(shift + o)),
+            OutputConst(_) => scheduled.push(*inst),
+            CopyRange(s, d, n) if displaced(s).is_some() && displaced(d).is_some() => {
+                scheduled.push(CopyRange(shift + s, shift + d, n))
+            }
+            _ => {
+                flush(&mut scheduled, &mut shift);
+                scheduled.push(*inst);
+            }
+        }
+        origins.resize(scheduled.len(), i);
+    }
+    flush(&mut scheduled, &mut shift);
+    origins.resize(scheduled.len(), instructions.len().saturating_sub(1));
+
+    if config.verbose >= 1 {
+        let num_shifts = |insts: &[Instruction]| {
+            insts
+                .iter()
+                .filter(|i| matches!(i, Shl(_) | Shr(_)))
+                .count()
+        };
+        println!(
+            "pointer moves before scheduling {} after: {}",
+            num_shifts(instructions),
+            num_shifts(&scheduled),
+        );
+        println!("============================================================");
+    }
+
+    (scheduled, origins)
+}
+
+/// Call the [`Routine::Overrun`] routine unless the registers at the offsets in `range` relative
+/// to the register pointer are inside the tape of `tape_len` registers, so the instruction
+/// accessing them isn't executed.
+fn write_bounds_check(code: &mut Vec<u8>, range: PointerRange, tape_len: usize, calls: &mut Calls) {
+    write(code, mov_r64_to_r64(Reg::Rcx, Reg::Rax));
+    match range.min {
+        0 => (),
+        -128..=127 => write(code, add_imm8_to_r64(Reg::Rax, range.min as i8)),
+        _ => write(code, add_imm32_to_r64(Reg::Rax, range.min)),
+    }
+    // offsets left of the tape are large unsigned values
+    let limit = (tape_len as i64 - (range.max - range.min) as i64).max(0);
+    write(code, cmp_r64_with_imm32(Reg::Rax, limit as i32));
+    const CALL_LEN: usize = call_rel32(0).len();
+    write(code, jb_rel8(CALL_LEN as i8));
+    calls.write(code, Routine::Overrun);
+}
+
+/// Write the routine returning 1 from an in-process function once a bounds check failed, which
+/// pops the return address of its call and the tape of `tape_len` registers off the stack.
+fn write_overrun_return(code: &mut Vec<u8>, tape_len: usize) {
+    write(code, add_imm32_to_r64(Reg::Rsp, 8 + tape_len as i32));
+    write(code, mov_imm32_to_r64(Reg::Rax, 1));
+    write(code, pop_r64(Reg::Rbx));
+    write(code, RET);
+}
+
+/// Write the address of the brainfuck register at displacement `disp` into `dest`.
+fn write_register_address(code: &mut Vec<u8>, dest: Reg, disp: i32) {
+    write(code, mov_r64_to_r64(Reg::Rsp, dest));
+    write(code, add_r64_to_r64(Reg::Rcx, dest));
+    match disp {
+        0 => (),
+        -128..=127 => write(code, add_imm8_to_r64(dest, disp as i8)),
+        _ => write(code, add_imm32_to_r64(dest, disp)),
+    }
+}
+
+/// Write the machine code of the `instructions`, `origins` contains the index of the original
+/// instruction each one originates from, which is reported when a tape overrun is detected.
+fn write_instructions(
+    config: &Config,
+    options: &RuntimeOptions,
+    code: &mut Vec<u8>,
+    instructions: &[Instruction],
+    origins: &[usize],
+    runtime: Runtime,
+    cache: Option<&mut CodeCache>,
+) {
+    if runtime == Runtime::InProcess {
+        // `rbx` is callee saved, and used to store the pointer to the `Io` struct
+        write(code, push_r64(Reg::Rbx));
+        write(code, mov_r64_to_r64(Reg::Rdi, Reg::Rbx));
+    }
+    if let Runtime::Linked { .. } = runtime {
+        // `r12` isn't used by the generated code, and stores the stack pointer, which is moved if
+        // the tape is mapped
+        write(code, mov_r64_to_r64_ext(Reg::Rsp, RegExt::R12));
+    }
+
+    // only allocate the registers the program can access if they are known, rounded up to keep the
+    // stack 16-byte aligned
+    let accessed = optim::register_range(instructions)
+        .filter(|r| config.pass(Pass::Bounds) && r.is_within(options.tape_len));
+    let tape_len = match accessed {
+        Some(r) => (r.max as usize + 1).next_multiple_of(16),
+        None => options.tape_len,
+    };
+    let tape = PointerRange {
+        min: 0,
+        max: tape_len as i32 - 1,
+    };
+    // only zero the accessed registers that might be read before being written
+    let zeroed = if config.pass(Pass::Bounds) {
+        optim::zeroed_range(instructions, accessed.unwrap_or(tape))
+    } else {
+        Some(tape)
+    };
+    // the tape of standalone executables that can't be proven to stay inside of it, is mapped with
+    // guard pages on both ends, and accesses outside of it are reported by a signal handler. The
+    // accesses of in-process functions are bounds checked instead, since they can't handle signals
+    let guard_tape = runtime != Runtime::InProcess && accessed.is_none();
+    let checked_tape_len =
+        (runtime == Runtime::InProcess && accessed.is_none()).then_some(tape_len);
+    // large tapes of standalone executables are mapped, and zeroed lazily by the kernel
+    let map_tape = guard_tape
+        || runtime != Runtime::InProcess
+            && zeroed.is_some_and(|r| (r.max - r.min) as usize >= MAP_TAPE_THRESHOLD);
+    if config.verbose >= 1 {
+        println!("register tape size: {tape_len}");
+        match zeroed {
+            _ if guard_tape => println!("mapped register tape with guard pages"),
+            _ if checked_tape_len.is_some() => println!("bounds checked register tape"),
+            _ if map_tape => println!("mapped register tape"),
+            Some(r) => println!("zeroed registers {}..={}", r.min, r.max),
+            None => println!("zeroed registers none"),
+        }
+        println!("============================================================");
+    }
+
+    if config.mem_stats {
+        cli::print_mem_stats(accessed, tape_len);
+    }
+
+    // the mapping and the signal handler have to be set up before the sandbox
+    if map_tape {
+        write_map_tape(code, tape_len);
+    }
+    // the signal handlers of standalone executables are written after the program, the positions
+    // of their addresses are updated afterwards
+    let standalone = runtime != Runtime::InProcess;
+    let buffered = standalone && options.buffered_output;
+    let interactive = runtime == Runtime::Linux && options.interactive;
+    if interactive {
+        write_raw_terminal(code);
+    }
+    let mut handler_fixups = Vec::new();
+    if standalone {
+        const SIGINT: i32 = 2;
+        const SIGSEGV: i32 = 11;
+        const SIGUSR1: i32 = 10;
+        const SA_RESTART: i32 = 0x1000_0000;
+        if guard_tape {
+            let fixup = write_install_handler(code, SIGSEGV, 0);
+            handler_fixups.push((Handler::Overrun, fixup));
+        }
+        let fixup = write_install_handler(code, SIGINT, 0);
+        handler_fixups.push((Handler::Interrupt, fixup));
+        // interrupted reads and writes are restarted, so requesting a dump doesn't change the
+        // behavior of the program
+        let fixup = write_install_handler(code, SIGUSR1, SA_RESTART);
+        handler_fixups.push((Handler::Dump, fixup));
+    }
+
+    if runtime != Runtime::InProcess && config.sandbox {
+        // `prctl(PR_SET_SECCOMP, SECCOMP_MODE_STRICT)` only allows the read, write, exit and
+        // sigreturn syscalls from here on
+        const SYSCALL_PRCTL: i32 = 157;
+        const PR_SET_SECCOMP: i32 = 22;
+        const SECCOMP_MODE_STRICT: i32 = 1;
+        write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_PRCTL));
+        write(code, mov_imm32_to_r64(Reg::Rdi, PR_SET_SECCOMP));
+        write(code, mov_imm32_to_r64(Reg::Rsi, SECCOMP_MODE_STRICT));
+        write(code, SYSCALL);
+
+        // exit with status 1 if the sandbox couldn't be set up
+        const SYSCALL_EXIT: i32 = 60;
+        const EXIT_LEN: usize = 2 * mov_imm32_to_r64(Reg::Rax, 0).len() + SYSCALL.len();
+        write(code, cmp_r32_with_imm8(Reg::Rax, 0x00));
+        write(code, jz_rel8(EXIT_LEN as i8));
+        write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_EXIT));
+        write(code, mov_imm32_to_r64(Reg::Rdi, 1));
+        write(code, SYSCALL);
+    }
+
+    if !map_tape {
         // allocate stack space for brainfuck registers array
-        write(code, sub_imm32_from_r64(Reg::Rsp, NUM_REGISTERS as i32));
+        write(code, sub_imm32_from_r64(Reg::Rsp, tape_len as i32));
+        if let Some(zeroed) = zeroed {
+            write_zero_registers(code, zeroed);
+        }
+    }
+
+    let layout = TapeLayout {
+        start: if map_tape { MAP_GUARD_SIZE as i32 } else { 0 },
+        len: tape_len as i32,
+    };
+    // checkpoints can only be resumed by the program that wrote them, which is identified by its
+    // instructions and the layout of its tape
+    let checkpoint = (options.checkpoint.as_ref())
+        .filter(|_| runtime == Runtime::Linux)
+        .map(|checkpoint| {
+            let mut hash = Fnv1a::new();
+            hash.write(format!("{instructions:?}").as_bytes());
+            hash.write(&tape_len.to_le_bytes());
+            hash.write(&[map_tape as u8]);
+            let hash = hash.finish() as u32;
+            let strings = write_resume_checkpoint(code, checkpoint, hash, layout);
+            CheckpointState {
+                checkpoint,
+                hash,
+                strings,
+            }
+        });
+    if standalone && checkpoint.is_none() {
+        write(code, mov_imm32_to_r64_ext(RegExt::R15, SAFE_POINT_INTERVAL));
+    }
 
-        const NUM_ITERATIONS: i32 = NUM_REGISTERS as i32 / 8;
-        write(code, mov_imm32_to_r64(Reg::Rcx, NUM_ITERATIONS));
+    // the register pointer starts at the first register, which is located after the guard pages
+    // if the tape is mapped
+    if map_tape {
+        write(code, mov_imm32_to_r64(Reg::Rcx, MAP_GUARD_SIZE as i32));
+    } else {
+        write(code, xor_r64_r64(Reg::Rcx, Reg::Rcx));
+    }
 
-        let loop_start = code.len();
-        write(code, sub_imm8_from_r32(Reg::Rcx, 0x01));
+    // the code of the top-level segments doesn't depend on each other, the segments that didn't
+    // change are reused from the cache, and the others are generated on multiple threads
+    let last_input = (instructions.iter()).rposition(|inst| matches!(inst, Instruction::Input(_)));
+    let context = SegmentContext {
+        config,
+        options,
+        runtime,
+        last_input,
+        checked_tape_len,
+    };
+    let ranges = top_level_segments(instructions);
+    let mut cache = cache.filter(|_| standalone);
+    let hashes = (ranges.iter())
+        .map(|range| {
+            let segment = &instructions[range.clone()];
+            cache
+                .is_some()
+                .then(|| segment_hash(options, segment, range.start, last_input, runtime))
+        })
+        .collect::<Vec<_>>();
+    let mut segments = (ranges.iter().zip(&hashes))
+        .map(|(range, hash)| {
+            let cached = cache.as_deref_mut()?.get((*hash)?)?;
+            let valid = cached.locations.len() == range.len()
+                && (cached.calls.iter()).all(|&(_, r)| (r as usize) < Routine::ALL.len());
+            valid.then(|| cached.clone())
+        })
+        .collect::<Vec<_>>();
+    generate_segments(&context, instructions, &ranges, &mut segments);
+
+    // the calls and locations are relative to the start of their segment
+    let mut calls = Calls::default();
+    let mut locations = Vec::new();
+    for ((range, segment), hash) in ranges.iter().zip(segments).zip(hashes) {
+        let segment = segment.expect("all segments are generated");
+        let base = code.len();
+        code.extend_from_slice(&segment.code);
+        if standalone {
+            for (&location, &origin) in segment.locations.iter().zip(&origins[range.clone()]) {
+                locations.push((base as u32 + location, origin as u32));
+            }
+        }
+        for &(pos, routine) in segment.calls.iter() {
+            calls
+                .0
+                .push((base + pos as usize, Routine::ALL[routine as usize]));
+        }
+        if let (Some(cache), Some(hash)) = (cache.as_deref_mut(), hash) {
+            cache.insert(hash, segment);
+        }
+    }
+
+    if !map_tape {
+        // pop brainfuck registers array off the stack
+        write(code, add_imm32_to_r64(Reg::Rsp, tape_len as i32));
+    }
+
+    if buffered {
+        calls.write(code, Routine::Flush);
+    }
+
+    match runtime {
+        Runtime::Linux => {
+            // a finished program starts from the beginning when it's run again
+            if let Some(state) = &checkpoint {
+                const SYSCALL_UNLINK: i32 = 87;
+                write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_UNLINK));
+                write_lea_position(code, state.strings.path, Reg::Rdi);
+                write(code, push_r64(Reg::Rcx));
+                write(code, SYSCALL);
+                write(code, pop_r64(Reg::Rcx));
+            }
+            if interactive {
+                write_restore_terminal(code);
+            }
+
+            const SYSCALL_EXIT: i32 = 60;
+            write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_EXIT));
+
+            // clear the edi register
+            write(code, xor_r64_r64(Reg::Rdi, Reg::Rdi));
+
+            write(code, SYSCALL);
+        }
+        Runtime::InProcess => {
+            // `bf_prog_main` of static libraries returns 0, or 1 if the program left the tape
+            write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+            write(code, pop_r64(Reg::Rbx));
+            write(code, RET);
+
+            if let Some(tape_len) = checked_tape_len {
+                let overrun = code.len();
+                write_overrun_return(code, tape_len);
+                for (pos, routine) in calls.0.drain(..) {
+                    debug_assert!(matches!(routine, Routine::Overrun));
+                    let disp = overrun as i32 - pos as i32;
+                    code[pos - 4..pos].copy_from_slice(&i32::to_le_bytes(disp));
+                }
+            }
+        }
+        Runtime::Linked { .. } => {
+            write(code, mov_r64_ext_to_r64(RegExt::R12, Reg::Rsp));
+        }
+    }
+    if !standalone {
+        return;
+    }
+
+    // the runtime routines and signal handlers follow the program, the next linked program
+    // follows them
+    let linked = matches!(runtime, Runtime::Linked { .. });
+    if linked {
+        write(code, jmp_rel32(0));
+    }
+    let next_pos = code.len();
+
+    let flush = buffered.then(|| {
+        let pos = code.len();
+        write_flush(code);
+        pos
+    });
+    let append = flush.map(|flush| {
+        let pos = code.len();
+        write_append(code, flush);
+        pos
+    });
+    let lookup = code.len();
+    write_lookup_instruction(code, &locations);
+    let safe_point = code.len();
+    write_safe_point(code, layout, lookup, flush, checkpoint.as_ref());
+    for (pos, routine) in calls.0 {
+        let target = match routine {
+            Routine::Flush => flush.expect("output is buffered"),
+            Routine::Append => append.expect("output is buffered"),
+            Routine::SafePoint => safe_point,
+            Routine::Overrun => unreachable!("only in-process code is bounds checked"),
+        };
+        let disp = target as i32 - pos as i32;
+        code[pos - 4..pos].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+
+    let restorer = code.len();
+    write_signal_restorer(code);
+    for (handler, (handler_pos, restorer_pos)) in handler_fixups {
+        let pos = code.len();
+        match handler {
+            Handler::Overrun => write_overrun_handler(code, lookup, flush, interactive),
+            Handler::Interrupt => write_interrupt_handler(code, flush, interactive),
+            Handler::Dump => write_dump_handler(code),
+        }
+        let disp = pos as i32 - handler_pos as i32;
+        code[handler_pos - 4..handler_pos].copy_from_slice(&i32::to_le_bytes(disp));
+        let disp = restorer as i32 - restorer_pos as i32;
+        code[restorer_pos - 4..restorer_pos].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+
+    if linked {
+        let disp = (code.len() - next_pos) as i32;
+        code[next_pos - 4..next_pos].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+}
+
+/// The runtime routines called by the code of the instructions.
+#[derive(Clone, Copy)]
+enum Routine {
+    /// See [`write_flush`].
+    Flush,
+    /// See [`write_append`].
+    Append,
+    /// See [`write_safe_point`].
+    SafePoint,
+    /// See [`write_overrun_return`].
+    Overrun,
+}
 
-        // write 0_i64 to stack at `rsp + 8 * rcx` using a scaled index byte (SIB)
-        const SIB: Sib = Sib::new(Scale::B8, Reg::Rcx, Reg::Rsp);
-        write(code, mov_imm32_to_sib64(SIB, 0));
+impl Routine {
+    /// Indexed by the discriminant, which is stored in the code cache.
+    const ALL: [Routine; 4] = [
+        Routine::Flush,
+        Routine::Append,
+        Routine::SafePoint,
+        Routine::Overrun,
+    ];
+}
+
+/// The options shared by the code of all top-level segments.
+struct SegmentContext<'a> {
+    config: &'a Config,
+    options: &'a RuntimeOptions,
+    runtime: Runtime,
+    /// The index of the last input instruction of the program.
+    last_input: Option<usize>,
+    /// The length of the tape if register accesses are bounds checked, see
+    /// [`write_bounds_check`].
+    checked_tape_len: Option<usize>,
+}
 
-        write(code, cmp_r32_with_imm8(Reg::Rcx, 0x00));
+/// Generate the code of the `segments` that weren't reused from the cache, on multiple threads if
+/// the program is large.
+fn generate_segments(
+    context: &SegmentContext,
+    instructions: &[Instruction],
+    ranges: &[Range<usize>],
+    segments: &mut [Option<Segment>],
+) {
+    let missing = (ranges.iter().zip(segments.iter_mut()))
+        .filter(|(_, segment)| segment.is_none())
+        .collect::<Vec<_>>();
+    let len = missing.iter().map(|(range, _)| range.len()).sum::<usize>();
+    let num_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    // the jumps printed at verbosity 3 are only in order if the code is generated sequentially
+    if len < PARALLEL_THRESHOLD || num_threads == 1 || context.config.verbose >= 3 {
+        for (range, segment) in missing {
+            *segment = Some(write_segment(context, instructions, range.clone()));
+        }
+        return;
+    }
 
-        // the instruction pointer will have already moved to the next instruction, so it will be
-        // after the jump instruction
-        let loop_end = code.len() + const { jnz_rel8(0).len() };
-        let rel_jump = (loop_start as isize - loop_end as isize) as i8;
-        write(code, jnz_rel8(rel_jump))
+    // segments are assigned to threads in order, until each thread has its share of instructions
+    let chunk_len = len.div_ceil(num_threads);
+    let mut chunks = vec![Vec::new()];
+    let mut current_len = 0;
+    for (range, segment) in missing {
+        if current_len >= chunk_len {
+            chunks.push(Vec::new());
+            current_len = 0;
+        }
+        current_len += range.len();
+        chunks.last_mut().unwrap().push((range, segment));
+    }
+    if context.config.verbose >= 1 {
+        println!("generating code on {} threads", chunks.len());
+        println!("============================================================");
     }
+    std::thread::scope(|s| {
+        for chunk in chunks {
+            s.spawn(move || {
+                for (range, segment) in chunk {
+                    *segment = Some(write_segment(context, instructions, range.clone()));
+                }
+            });
+        }
+    });
+}
+
+/// Write the machine code of the top-level segment `range` of the `instructions`.
+fn write_segment(
+    context: &SegmentContext,
+    instructions: &[Instruction],
+    range: Range<usize>,
+) -> Segment {
+    let SegmentContext {
+        config,
+        options,
+        runtime,
+        last_input,
+        checked_tape_len,
+    } = *context;
+    let standalone = runtime != Runtime::InProcess;
+    let buffered = standalone && options.buffered_output;
 
     // scaled index byte used to index into the brainfuck register array
     const SIB: Sib = Sib::new(Scale::B1, Reg::Rcx, Reg::Rsp);
 
+    let mut code = Vec::new();
+    let mut calls = Calls::default();
     // stores if the jump is redundant, and the location after the opening jump (`[`), the jump
     // offset is stored inside the 4 bytes before that
     let mut jump_stack = Vec::new();
-    for inst in instructions.iter() {
+    // consecutive constant output, which is written at once
+    let mut output = Vec::new();
+    // the start of the code of each instruction
+    let mut locations = Vec::new();
+    // the registers relative to the register pointer that are known to be inside the tape, since
+    // the pointer was last moved or a jump was taken
+    let mut checked = None;
+    for (i, inst) in instructions
+        .iter()
+        .enumerate()
+        .take(range.end)
+        .skip(range.start)
+    {
+        if !matches!(inst, Instruction::OutputConst(_)) && !output.is_empty() {
+            write_output_const(&mut code, &output, runtime, buffered.then_some(&mut calls));
+            output.clear();
+        }
+        locations.push(code.len() as u32);
+
+        if let (Some(tape_len), Some(range)) = (checked_tape_len, optim::inst_accesses(inst)) {
+            let covered = checked.is_some_and(|c: PointerRange| c.join(range) == c);
+            if !covered {
+                write_bounds_check(&mut code, range, tape_len, &mut calls);
+                checked = Some(checked.map_or(range, |c| c.join(range)));
+            }
+        }
+        if let Instruction::Shl(_)
+        | Instruction::Shr(_)
+        | Instruction::JumpZ(_)
+        | Instruction::JumpNz(_) = inst
+        {
+            checked = None;
+        }
+
         match *inst {
+            // the register pointer is sign extended when it's bounds checked, so that it can
+            // temporarily point left of the tape
+            #[rustfmt::skip]
+            Instruction::Shl(n) if checked_tape_len.is_some() => match n {
+                0..=128 => write(&mut code, add_imm8_to_r64(Reg::Rcx, -(n as i16) as i8)),
+                _ =>       write(&mut code, sub_imm32_from_r64(Reg::Rcx, n as i32)),
+            },
+            #[rustfmt::skip]
+            Instruction::Shr(n) if checked_tape_len.is_some() => match n {
+                0..=127 => write(&mut code, add_imm8_to_r64(Reg::Rcx, n as i8)),
+                _ =>       write(&mut code, add_imm32_to_r64(Reg::Rcx, n as i32)),
+            },
             #[rustfmt::skip]
             Instruction::Shl(n) => match n {
-                0..=127 => write(code, sub_imm8_from_r32(Reg::Rcx, n as i8)),
-                _ =>       write(code, sub_imm32_from_r32(Reg::Rcx, n as i32)),
+                0..=127 => write(&mut code, sub_imm8_from_r32(Reg::Rcx, n as i8)),
+                _ =>       write(&mut code, sub_imm32_from_r32(Reg::Rcx, n as i32)),
             },
             #[rustfmt::skip]
             Instruction::Shr(n) => match n {
-                0..=127 => write(code, add_imm8_to_r32(Reg::Rcx, n as i8)),
-                _ =>       write(code, add_imm32_to_r32(Reg::Rcx, n as i32)),
+                0..=127 => write(&mut code, add_imm8_to_r32(Reg::Rcx, n as i8)),
+                _ =>       write(&mut code, add_imm32_to_r32(Reg::Rcx, n as i32)),
             },
             #[rustfmt::skip]
             Instruction::Inc(disp, n) => match disp {
-                0 =>          write(code, add_imm8_to_sib8(SIB, n)),
-                -128..=127 => write(code, add_imm8_to_sib8_disp8(SIB, disp as i8, n)),
-                _ =>          write(code, add_imm8_to_sib8_disp32(SIB, disp as i32, n)),
+                0 =>          write(&mut code, add_imm8_to_sib8(SIB, n)),
+                -128..=127 => write(&mut code, add_imm8_to_sib8_disp8(SIB, disp as i8, n)),
+                _ =>          write(&mut code, add_imm8_to_sib8_disp32(SIB, disp as i32, n)),
             },
             #[rustfmt::skip]
             Instruction::Dec(disp, n) => match disp {
-                0 =>          write(code, sub_imm8_from_sib8(SIB, n)),
-                -128..=127 => write(code, sub_imm8_from_sib8_disp8(SIB, disp as i8, n)),
-                _ =>          write(code, sub_imm8_from_sib8_disp32(SIB, disp as i32, n)),
+                0 =>          write(&mut code, sub_imm8_from_sib8(SIB, n)),
+                -128..=127 => write(&mut code, sub_imm8_from_sib8_disp8(SIB, disp as i8, n)),
+                _ =>          write(&mut code, sub_imm8_from_sib8_disp32(SIB, disp as i32, n)),
             },
-            Instruction::Output => {
+            Instruction::Output(disp)
+            | Instruction::Input(disp)
+            | Instruction::PrintDecimal(disp)
+                if runtime == Runtime::InProcess =>
+            {
+                let offset = match *inst {
+                    Instruction::Output(_) => std::mem::offset_of!(Io, output),
+                    Instruction::Input(_) => std::mem::offset_of!(Io, input),
+                    _ => std::mem::offset_of!(Io, print_decimal),
+                };
+                write_io_callback(&mut code, disp, offset);
+            }
+            Instruction::Pipe if runtime == Runtime::InProcess => {
+                write_io_callback(&mut code, 0, std::mem::offset_of!(Io, pipe));
+            }
+            // reading ahead consumes the input after a zero byte, which is only fine if the echo
+            // loop runs at most once, contains the last input instruction, and the input isn't
+            // shared with a linked program. Each byte is only written once the next one was read,
+            // so interactive programs echo each key immediately using the echo loop instead.
+            Instruction::Pipe
+                if jump_stack.is_empty()
+                    && last_input == Some(i + 3)
+                    && runtime != (Runtime::Linked { shared_input: true })
+                    && !options.interactive =>
+            {
+                if buffered {
+                    calls.write(&mut code, Routine::Flush);
+                }
+                write_pipe(&mut code, options.eof.value());
+            }
+            // the echo loop following the pipe copies the input byte by byte
+            Instruction::Pipe => (),
+            Instruction::Output(disp) if buffered => {
+                // the register is read here, so a tape overrun is reported at this instruction
+                write_register_address(&mut code, Reg::Rsi, disp as i32);
+                write(&mut code, mov_m8_to_r8(RmI::RegRsi, Reg::Rax));
+                write(&mut code, mov_imm32_to_r64(Reg::Rdx, 1));
+                calls.write(&mut code, Routine::Append);
+            }
+            Instruction::Output(disp) => {
                 const SYSCALL_WRITE: i32 = 1;
-                write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+                write(&mut code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
 
                 const STDOUT_FD: i32 = 1;
-                write(code, mov_imm32_to_r64(Reg::Rdi, STDOUT_FD));
+                write(&mut code, mov_imm32_to_r64(Reg::Rdi, STDOUT_FD));
 
                 // write address of string to `rsi`
-                write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
-                write(code, add_r64_to_r64(Reg::Rcx, Reg::Rsi));
+                write_register_address(&mut code, Reg::Rsi, disp as i32);
 
                 const STRING_LEN: i32 = 1;
-                write(code, mov_imm32_to_r64(Reg::Rdx, STRING_LEN));
+                write(&mut code, mov_imm32_to_r64(Reg::Rdx, STRING_LEN));
 
-                write(code, push_r64(Reg::Rcx));
-                write(code, SYSCALL);
-                write(code, pop_r64(Reg::Rcx));
+                write(&mut code, push_r64(Reg::Rcx));
+                write(&mut code, SYSCALL);
+                write(&mut code, pop_r64(Reg::Rcx));
             }
-            Instruction::Input => {
+            Instruction::Input(disp) => {
+                // the output is written before the program waits for input
+                if buffered {
+                    calls.write(&mut code, Routine::Flush);
+                }
+
                 const _SYSCALL_READ: i32 = 0;
-                write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+                write(&mut code, xor_r64_r64(Reg::Rax, Reg::Rax));
 
                 const _STDIN_FD: i32 = 0;
-                write(code, xor_r64_r64(Reg::Rdi, Reg::Rdi));
+                write(&mut code, xor_r64_r64(Reg::Rdi, Reg::Rdi));
 
                 // write address of string to `rsi`
-                write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
-                write(code, add_r64_to_r64(Reg::Rcx, Reg::Rsi));
+                write_register_address(&mut code, Reg::Rsi, disp as i32);
 
                 const STRING_LEN: i32 = 1;
-                write(code, mov_imm32_to_r64(Reg::Rdx, STRING_LEN));
-
-                write(code, push_r64(Reg::Rcx));
-                write(code, SYSCALL);
-                write(code, pop_r64(Reg::Rcx));
+                write(&mut code, mov_imm32_to_r64(Reg::Rdx, STRING_LEN));
+
+                write(&mut code, push_r64(Reg::Rcx));
+                write(&mut code, SYSCALL);
+                write(&mut code, pop_r64(Reg::Rcx));
+
+                // no bytes are read at the end of the input
+                if let Some(val) = options.eof.value() {
+                    let mut set = Vec::new();
+                    #[rustfmt::skip]
+                    match disp {
+                        0 =>          write(&mut set, mov_imm8_to_sib8(SIB, val)),
+                        -128..=127 => write(&mut set, mov_imm8_to_sib8_disp8(SIB, disp as i8, val)),
+                        _ =>          write(&mut set, mov_imm8_to_sib8_disp32(SIB, disp as i32, val)),
+                    };
+                    write(&mut code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+                    write(&mut code, jnz_rel8(set.len() as i8));
+                    code.extend_from_slice(&set);
+                }
             }
             Instruction::JumpZ(jump) => {
                 let redundant = jump.is_redundant();
                 if !redundant {
-                    write(code, cmp_sib8_with_imm8(SIB, 0));
+                    write(&mut code, cmp_sib8_with_imm8(SIB, 0));
                     // actual jump offset is updated when writing the matching JumpNz (`]`) instruction
-                    write(code, jz_rel32(0));
+                    write(&mut code, jz_rel32(0));
                 }
 
                 let pos = code.len();
@@ -372,6 +1105,13 @@ fn write_instructions(config: &Config, code: &mut Vec<u8>, instructions: &[Instr
                 let Some((start_redundant, start_pos)) = jump_stack.pop() else {
                     unreachable!()
                 };
+                // `r15` counts down the loop iterations until the next safe point
+                if standalone && !jump.is_redundant() {
+                    const CALL_LEN: usize = call_rel32(0).len();
+                    write(&mut code, sub_imm8_from_r64_ext(RegExt::R15, 1));
+                    write(&mut code, jnz_rel8(CALL_LEN as i8));
+                    calls.write(&mut code, Routine::SafePoint);
+                }
 
                 const CMP_INST_LEN: usize = cmp_sib8_with_imm8(SIB, 0).len();
                 const REL8_INST_LEN: usize = CMP_INST_LEN + jnz_rel8(0).len();
@@ -401,119 +1141,1433 @@ fn write_instructions(config: &Config, code: &mut Vec<u8>, instructions: &[Instr
                     if rel8 {
                         let jump_inst = jz_rel8(offset as i8);
                         code.splice(start_pos - REL32_JUMP_INST_LEN..start_pos, jump_inst);
+
+                        // the code of the loop body was moved by the shorter jump
+                        let moved = (REL32_JUMP_INST_LEN - jump_inst.len()) as u32;
+                        for location in locations.iter_mut().rev() {
+                            if (*location as usize) < start_pos {
+                                break;
+                            }
+                            *location -= moved;
+                        }
+                        for (pos, _) in calls.0.iter_mut().rev() {
+                            if *pos < start_pos {
+                                break;
+                            }
+                            *pos -= moved as usize;
+                        }
                     } else {
-                        let offset = i32::to_le_bytes(offset as i32);
+                        let offset =
+                            i32::try_from(offset).expect("loop bodies are smaller than 2GiB");
+                        let offset = i32::to_le_bytes(offset);
                         code[start_pos - 4..start_pos].copy_from_slice(&offset);
                     }
                 }
 
                 if !redundant {
-                    write(code, cmp_sib8_with_imm8(SIB, 0));
+                    write(&mut code, cmp_sib8_with_imm8(SIB, 0));
                     if rel8 {
-                        write(code, jnz_rel8(-(offset as i8)));
+                        write(&mut code, jnz_rel8(-(offset as i8)));
                     } else {
-                        write(code, jnz_rel32(-(offset as i32)));
+                        write(&mut code, jnz_rel32(-(offset as i32)));
                     }
                 }
             }
 
             #[rustfmt::skip]
             Instruction::Zero(disp) => match disp {
-                0 =>          write(code, mov_imm8_to_sib8(SIB, 0x00)),
-                -128..=127 => write(code, mov_imm8_to_sib8_disp8(SIB, disp as i8, 0x00)),
-                _ =>          write(code, mov_imm8_to_sib8_disp32(SIB, disp as i32, 0x00)),
+                0 =>          write(&mut code, mov_imm8_to_sib8(SIB, 0x00)),
+                -128..=127 => write(&mut code, mov_imm8_to_sib8_disp8(SIB, disp as i8, 0x00)),
+                _ =>          write(&mut code, mov_imm8_to_sib8_disp32(SIB, disp as i32, 0x00)),
             },
             #[rustfmt::skip]
             Instruction::Set(disp, n) => match disp {
-                0 =>          write(code, mov_imm8_to_sib8(SIB, n)),
-                -128..=127 => write(code, mov_imm8_to_sib8_disp8(SIB, disp as i8, n)),
-                _ =>          write(code, mov_imm8_to_sib8_disp32(SIB, disp as i32, n)),
+                0 =>          write(&mut code, mov_imm8_to_sib8(SIB, n)),
+                -128..=127 => write(&mut code, mov_imm8_to_sib8_disp8(SIB, disp as i8, n)),
+                _ =>          write(&mut code, mov_imm8_to_sib8_disp32(SIB, disp as i32, n)),
             },
             Instruction::Add(disp) => {
-                write(code, mov_sib8_to_r8(SIB, Reg::Rax));
+                write(&mut code, mov_sib8_to_r8(SIB, Reg::Rax));
                 #[rustfmt::skip]
                 match disp {
-                    0 =>          write(code, add_r8_to_sib8(Reg::Rax, SIB)),
-                    -128..=127 => write(code, add_r8_to_sib8_disp8(Reg::Rax, SIB, disp as i8)),
-                    _ =>          write(code, add_r8_to_sib8_disp32(Reg::Rax, SIB, disp as i32)),
+                    0 =>          write(&mut code, add_r8_to_sib8(Reg::Rax, SIB)),
+                    -128..=127 => write(&mut code, add_r8_to_sib8_disp8(Reg::Rax, SIB, disp as i8)),
+                    _ =>          write(&mut code, add_r8_to_sib8_disp32(Reg::Rax, SIB, disp as i32)),
                 };
             }
             Instruction::Sub(disp) => {
-                write(code, mov_sib8_to_r8(SIB, Reg::Rax));
+                write(&mut code, mov_sib8_to_r8(SIB, Reg::Rax));
                 #[rustfmt::skip]
                 match disp {
-                    0 =>          write(code, sub_r8_from_sib8(Reg::Rax, SIB)),
-                    -128..=127 => write(code, sub_r8_from_sib8_disp8(Reg::Rax, SIB, disp as i8)),
-                    _ =>          write(code, sub_r8_from_sib8_disp32(Reg::Rax, SIB, disp as i32)),
+                    0 =>          write(&mut code, sub_r8_from_sib8(Reg::Rax, SIB)),
+                    -128..=127 => write(&mut code, sub_r8_from_sib8_disp8(Reg::Rax, SIB, disp as i8)),
+                    _ =>          write(&mut code, sub_r8_from_sib8_disp32(Reg::Rax, SIB, disp as i32)),
                 };
             }
             Instruction::AddMul(disp, n) => {
-                write(code, mov_imm8_to_r8(Reg::Rax, n));
-                write(code, mul_al_with_sib8(SIB));
+                write_mul_sib8_to_al(&mut code, SIB, n);
                 #[rustfmt::skip]
                 match disp {
-                    0 =>          write(code, add_r8_to_sib8(Reg::Rax, SIB)),
-                    -128..=127 => write(code, add_r8_to_sib8_disp8(Reg::Rax, SIB, disp as i8)),
-                    _ =>          write(code, add_r8_to_sib8_disp32(Reg::Rax, SIB, disp as i32)),
+                    0 =>          write(&mut code, add_r8_to_sib8(Reg::Rax, SIB)),
+                    -128..=127 => write(&mut code, add_r8_to_sib8_disp8(Reg::Rax, SIB, disp as i8)),
+                    _ =>          write(&mut code, add_r8_to_sib8_disp32(Reg::Rax, SIB, disp as i32)),
                 };
             }
             Instruction::SubMul(disp, n) => {
-                write(code, mov_imm8_to_r8(Reg::Rax, n));
-                write(code, mul_al_with_sib8(SIB));
+                write_mul_sib8_to_al(&mut code, SIB, n);
                 #[rustfmt::skip]
                 match disp {
-                    0 =>          write(code, sub_r8_from_sib8(Reg::Rax, SIB)),
-                    -128..=127 => write(code, sub_r8_from_sib8_disp8(Reg::Rax, SIB, disp as i8)),
-                    _ =>          write(code, sub_r8_from_sib8_disp32(Reg::Rax, SIB, disp as i32)),
+                    0 =>          write(&mut code, sub_r8_from_sib8(Reg::Rax, SIB)),
+                    -128..=127 => write(&mut code, sub_r8_from_sib8_disp8(Reg::Rax, SIB, disp as i8)),
+                    _ =>          write(&mut code, sub_r8_from_sib8_disp32(Reg::Rax, SIB, disp as i32)),
                 };
             }
+            Instruction::CopyRange(src, dst, len) => {
+                let (src, dst, len) = (src as i32, dst as i32, len as i32);
+                // copy backwards if the start of the destination overlaps the source
+                let backwards = src < dst && dst < src + len;
+                if backwards {
+                    write_register_address(&mut code, Reg::Rsi, src + len - 1);
+                    write_register_address(&mut code, Reg::Rdi, dst + len - 1);
+                    write(&mut code, STD);
+                } else {
+                    write_register_address(&mut code, Reg::Rsi, src);
+                    write_register_address(&mut code, Reg::Rdi, dst);
+                }
+
+                write(&mut code, push_r64(Reg::Rcx));
+                write(&mut code, mov_imm32_to_r64(Reg::Rcx, len));
+                write(&mut code, REP_MOVSB);
+                write(&mut code, pop_r64(Reg::Rcx));
+                if backwards {
+                    write(&mut code, CLD);
+                }
+            }
+            Instruction::DivMod => write_div_mod(&mut code, SIB),
+            Instruction::PrintDecimal(disp) => {
+                write_print_decimal(&mut code, disp, buffered.then_some(&mut calls))
+            }
+            Instruction::OutputConst(n) => output.push(n),
         }
     }
+    if !output.is_empty() {
+        write_output_const(&mut code, &output, runtime, buffered.then_some(&mut calls));
+    }
 
-    // pop brainfuck registers array off the stack
-    write(code, add_imm32_to_r64(Reg::Rsp, NUM_REGISTERS as i32));
+    Segment {
+        code,
+        locations,
+        calls: (calls.0.into_iter())
+            .map(|(pos, routine)| (pos as u32, routine as u8))
+            .collect(),
+    }
+}
 
-    const SYSCALL_EXIT: i32 = 60;
-    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_EXIT));
+/// Split the program after each top-level loop. The code of the segments doesn't depend on each
+/// other, the calls of runtime routines are patched afterwards.
+fn top_level_segments(instructions: &[Instruction]) -> Vec<Range<usize>> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut depth = 0_usize;
+    for (i, inst) in instructions.iter().enumerate() {
+        match inst {
+            Instruction::JumpZ(_) => depth += 1,
+            Instruction::JumpNz(_) => {
+                depth -= 1;
+                if depth == 0 {
+                    segments.push(start..i + 1);
+                    start = i + 1;
+                }
+            }
+            _ => (),
+        }
+    }
+    if start < instructions.len() {
+        segments.push(start..instructions.len());
+    }
+    segments
+}
+
+/// Identify the code of the top-level `segment` starting at instruction `start`, by its
+/// instructions and the options affecting their code.
+fn segment_hash(
+    options: &RuntimeOptions,
+    segment: &[Instruction],
+    start: usize,
+    last_input: Option<usize>,
+    runtime: Runtime,
+) -> u64 {
+    // jump locations are relative to the whole program, only redundant jumps affect the code
+    let normalized = (segment.iter())
+        .map(|inst| match *inst {
+            Instruction::JumpZ(Jump::Location(_)) => {
+                Instruction::JumpZ(Jump::Location(NonZeroU32::MIN))
+            }
+            Instruction::JumpNz(Jump::Location(_)) => {
+                Instruction::JumpNz(Jump::Location(NonZeroU32::MIN))
+            }
+            inst => inst,
+        })
+        .collect::<Vec<_>>();
+    let mut hash = Fnv1a::new();
+    hash.write(env!("CARGO_PKG_VERSION").as_bytes());
+    hash.write(&cache::serialize(&normalized));
+    // the pipe instruction depends on whether its echo loop contains the last input instruction
+    let last_input = last_input
+        .and_then(|i| i.checked_sub(start))
+        .filter(|&i| i < segment.len());
+    hash.write(&last_input.map_or(u64::MAX, |i| i as u64).to_le_bytes());
+    let runtime = match runtime {
+        Runtime::Linux => 0,
+        Runtime::InProcess => 1,
+        Runtime::Linked { shared_input } => 2 + shared_input as u8,
+    };
+    let eof = options.eof.value();
+    hash.write(&[
+        runtime,
+        eof.is_some() as u8,
+        eof.unwrap_or(0),
+        options.buffered_output as u8,
+        options.interactive as u8,
+    ]);
+    hash.finish()
+}
+
+/// The positions after the displacements of calls of runtime routines, which are updated once
+/// the routines are written.
+#[derive(Default)]
+struct Calls(Vec<(usize, Routine)>);
+
+impl Calls {
+    fn write(&mut self, code: &mut Vec<u8>, routine: Routine) {
+        write(code, call_rel32(0));
+        self.0.push((code.len(), routine));
+    }
+}
+
+/// The signal handlers of standalone executables.
+#[derive(Clone, Copy)]
+enum Handler {
+    /// `SIGSEGV`, see [`write_overrun_handler`].
+    Overrun,
+    /// `SIGINT`, see [`write_interrupt_handler`].
+    Interrupt,
+    /// `SIGUSR1`, see [`write_dump_handler`].
+    Dump,
+}
+
+/// The location of the tape relative to the stack pointer of the program.
+#[derive(Clone, Copy)]
+struct TapeLayout {
+    start: i32,
+    len: i32,
+}
 
-    // clear the edi register
+/// Map the register tape using `mmap`, and use it as the stack, leaving [`MAP_STACK_SIZE`] bytes
+/// below it for the stack usage of the generated code. The tape is surrounded by
+/// [`MAP_GUARD_SIZE`] bytes of inaccessible guard pages on both ends, so that accesses outside of
+/// it fault. The program exits with status 1 if the mapping fails.
+fn write_map_tape(code: &mut Vec<u8>, tape_len: usize) {
+    const SYSCALL_MMAP: i32 = 9;
+    const PROT_READ: i32 = 0x1;
+    const PROT_WRITE: i32 = 0x2;
+    const MAP_PRIVATE: i32 = 0x02;
+    const MAP_ANONYMOUS: i32 = 0x20;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_MMAP));
     write(code, xor_r64_r64(Reg::Rdi, Reg::Rdi));
+    let tape_len = tape_len.next_multiple_of(PAGE_SIZE);
+    let len = MAP_STACK_SIZE + MAP_GUARD_SIZE + tape_len + MAP_GUARD_SIZE;
+    write(code, mov_imm32_to_r64(Reg::Rsi, len as i32));
+    write(code, mov_imm32_to_r64(Reg::Rdx, PROT_READ | PROT_WRITE));
+    write(
+        code,
+        mov_imm32_to_r64_ext(RegExt::R10, MAP_PRIVATE | MAP_ANONYMOUS),
+    );
+    write(code, mov_imm32_to_r64_ext(RegExt::R8, -1));
+    write(code, mov_imm32_to_r64_ext(RegExt::R9, 0));
+    write(code, SYSCALL);
+    write_exit_on_error(code);
+
+    write(code, add_imm32_to_r64(Reg::Rax, MAP_STACK_SIZE as i32));
+    write(code, mov_r64_to_r64(Reg::Rax, Reg::Rsp));
+
+    // make the guard pages below and above the tape inaccessible
+    const SYSCALL_MPROTECT: i32 = 10;
+    const PROT_NONE: i32 = 0x0;
+    for offset in [0, MAP_GUARD_SIZE + tape_len] {
+        write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_MPROTECT));
+        write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rdi));
+        if offset != 0 {
+            write(code, add_imm32_to_r64(Reg::Rdi, offset as i32));
+        }
+        write(code, mov_imm32_to_r64(Reg::Rsi, MAP_GUARD_SIZE as i32));
+        write(code, mov_imm32_to_r64(Reg::Rdx, PROT_NONE));
+        write(code, SYSCALL);
+        write_exit_on_error(code);
+    }
+}
 
+/// Duplicate the standard output of a linked executable to `r13`, so it can be restored for the
+/// last of the piped programs, see [`write_restore_stdout`].
+fn write_save_stdout(code: &mut Vec<u8>) {
+    const SYSCALL_DUP: i32 = 32;
+    const STDOUT_FD: i32 = 1;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_DUP));
+    write(code, mov_imm32_to_r64(Reg::Rdi, STDOUT_FD));
     write(code, SYSCALL);
+    write_exit_on_error(code);
+    write(code, mov_r64_to_r64_ext(Reg::Rax, RegExt::R13));
 }
 
-fn write<const SIZE: usize>(code: &mut Vec<u8>, instruction: [u8; SIZE]) {
-    code.extend_from_slice(&instruction);
+/// Redirect the standard output into an anonymous in-memory file created using `memfd_create`,
+/// whose file descriptor is stored in `r14`. The program exits with status 1 if that fails.
+fn write_pipe_from_stdout(code: &mut Vec<u8>) {
+    const SYSCALL_MEMFD_CREATE: i32 = 319;
+    const SYSCALL_DUP2: i32 = 33;
+    const STDOUT_FD: i32 = 1;
+
+    // the name is an empty string on the stack
+    write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+    write(code, push_r64(Reg::Rax));
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_MEMFD_CREATE));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rdi));
+    write(code, xor_r64_r64(Reg::Rsi, Reg::Rsi));
+    write(code, SYSCALL);
+    write(code, pop_r64(Reg::Rdi));
+    write_exit_on_error(code);
+    write(code, mov_r64_to_r64_ext(Reg::Rax, RegExt::R14));
+
+    write(code, mov_r64_to_r64(Reg::Rax, Reg::Rdi));
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_DUP2));
+    write(code, mov_imm32_to_r64(Reg::Rsi, STDOUT_FD));
+    write(code, SYSCALL);
+    write_exit_on_error(code);
 }
 
-/// prefix for some 64-bit instructions
-const REXW: u8 = 0x48;
+/// Rewind the in-memory file stored in `r14`, written by the previous program, and make it the
+/// standard input, see [`write_pipe_from_stdout`].
+fn write_pipe_to_stdin(code: &mut Vec<u8>) {
+    const SYSCALL_LSEEK: i32 = 8;
+    const SYSCALL_DUP2: i32 = 33;
+    const SYSCALL_CLOSE: i32 = 3;
+    const SEEK_SET: i32 = 0;
+    const STDIN_FD: i32 = 0;
+
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_LSEEK));
+    write(code, mov_r64_ext_to_r64(RegExt::R14, Reg::Rdi));
+    write(code, xor_r64_r64(Reg::Rsi, Reg::Rsi));
+    write(code, mov_imm32_to_r64(Reg::Rdx, SEEK_SET));
+    write(code, SYSCALL);
+    write_exit_on_error(code);
 
-// ========================================
-//                   ADD
-// ========================================
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_DUP2));
+    write(code, mov_r64_ext_to_r64(RegExt::R14, Reg::Rdi));
+    write(code, mov_imm32_to_r64(Reg::Rsi, STDIN_FD));
+    write(code, SYSCALL);
+    write_exit_on_error(code);
 
-// 8-bit
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_CLOSE));
+    write(code, mov_r64_ext_to_r64(RegExt::R14, Reg::Rdi));
+    write(code, SYSCALL);
+}
 
-/// `00 /r` : `ADD r/m8 r8` : add r8 to r/m8
-pub const fn add_r8_to_r8(src: Reg, dest: Reg) -> [u8; 2] {
-    let modrm = modrm_reg(ModRm::Register(dest), src);
-    [0x00, modrm]
+/// Restore the standard output saved in `r13`, see [`write_save_stdout`].
+fn write_restore_stdout(code: &mut Vec<u8>) {
+    const SYSCALL_DUP2: i32 = 33;
+    const SYSCALL_CLOSE: i32 = 3;
+    const STDOUT_FD: i32 = 1;
+
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_DUP2));
+    write(code, mov_r64_ext_to_r64(RegExt::R13, Reg::Rdi));
+    write(code, mov_imm32_to_r64(Reg::Rsi, STDOUT_FD));
+    write(code, SYSCALL);
+    write_exit_on_error(code);
+
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_CLOSE));
+    write(code, mov_r64_ext_to_r64(RegExt::R13, Reg::Rdi));
+    write(code, SYSCALL);
 }
-/// `00 /r` : `ADD r/m8 r8` : add r8 to r/m8
-pub const fn add_r8_to_sib8(src: Reg, dest: Sib) -> [u8; 3] {
-    let modrm = modrm_reg(ModRm::Indirect(RmI::Sib), src);
-    [0x00, modrm, dest.sib()]
+
+/// Exit with status 1 if the system call returned an error, which is a negative number.
+fn write_exit_on_error(code: &mut Vec<u8>) {
+    const SYSCALL_EXIT: i32 = 60;
+    const EXIT_LEN: usize = 2 * mov_imm32_to_r64(Reg::Rax, 0).len() + SYSCALL.len();
+    write(code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+    write(code, jns_rel8(EXIT_LEN as i8));
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_EXIT));
+    write(code, mov_imm32_to_r64(Reg::Rdi, 1));
+    write(code, SYSCALL);
 }
-/// `00 /r` : `ADD r/m8 r8` : add r8 to r/m8
-pub const fn add_r8_to_sib8_disp8(src: Reg, dest: Sib, disp: i8) -> [u8; 4] {
-    let modrm = modrm_reg(ModRm::IndirectDisp8(RmID::Sib), src);
-    let [disp] = i8::to_le_bytes(disp);
-    [0x00, modrm, dest.sib(), disp]
+
+/// Load the address of the code at `pos` into `dest`, relative to the instruction pointer.
+fn write_lea_position(code: &mut Vec<u8>, pos: usize, dest: Reg) {
+    const LEA_LEN: usize = lea_rip_disp32_to_r64(0, Reg::Rax).len();
+    let disp = pos as isize - (code.len() + LEA_LEN) as isize;
+    write(code, lea_rip_disp32_to_r64(disp as i32, dest));
 }
-/// `00 /r` : `ADD r/m8 r8` : add r8 to r/m8
-pub const fn add_r8_to_sib8_disp32(src: Reg, dest: Sib, disp: i32) -> [u8; 7] {
-    let modrm = modrm_reg(ModRm::IndirectDisp32(RmID::Sib), src);
+
+/// The checkpoint of a program, see [`write_resume_checkpoint`].
+struct CheckpointState<'a> {
+    checkpoint: &'a Checkpoint,
+    /// Identifies the program, which wrote the checkpoint.
+    hash: u32,
+    strings: CheckpointStrings,
+}
+
+/// The positions of the null-terminated strings used by the checkpoint code.
+#[derive(Clone, Copy)]
+struct CheckpointStrings {
+    path: usize,
+    tmp_path: usize,
+    invalid: (usize, usize),
+    failed: (usize, usize),
+}
+
+/// Resume from the checkpoint file if it exists, otherwise continue with the initialization of
+/// the register pointer and start the program from the beginning.
+/// The file starts with a header containing the hash of the program, the location to resume at,
+/// relative to the start of the code, and the register pointer, followed by the tape. The
+/// program exits with status 1 if the file can't be read or was written by another program.
+///
+/// `r14` holds the time at which the next checkpoint is due, and `r15` counts down the loop
+/// iterations until the clock is read again, see [`write_checkpoint`].
+fn write_resume_checkpoint(
+    code: &mut Vec<u8>,
+    checkpoint: &Checkpoint,
+    hash: u32,
+    tape: TapeLayout,
+) -> CheckpointStrings {
+    const INVALID: &[u8] = b"invalid checkpoint\n";
+    const FAILED: &[u8] = b"failed to write checkpoint\n";
+    const SYSCALL_READ: i32 = 0;
+    const SYSCALL_WRITE: i32 = 1;
+    const SYSCALL_OPEN: i32 = 2;
+    const SYSCALL_CLOSE: i32 = 3;
+    const SYSCALL_EXIT: i32 = 60;
+    const STDERR_FD: i32 = 2;
+    const O_RDONLY: i32 = 0;
+
+    // the strings are embedded into the code and jumped over
+    let mut strings = Vec::new();
+    for s in [
+        checkpoint.path.as_bytes(),
+        b"\0",
+        checkpoint.path.as_bytes(),
+        b".tmp\0",
+    ] {
+        strings.extend_from_slice(s);
+    }
+    strings.extend_from_slice(INVALID);
+    strings.extend_from_slice(FAILED);
+    write(code, jmp_rel32(strings.len() as i32));
+    let start = code.len();
+    code.extend_from_slice(&strings);
+    let path_len = checkpoint.path.len() + 1;
+    let invalid = start + 2 * path_len + 4;
+    let strings = CheckpointStrings {
+        path: start,
+        tmp_path: start + path_len,
+        invalid: (invalid, INVALID.len()),
+        failed: (invalid + INVALID.len(), FAILED.len()),
+    };
+
+    // start from the beginning if the file can't be opened
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_OPEN));
+    write_lea_position(code, strings.path, Reg::Rdi);
+    write(code, mov_imm32_to_r64(Reg::Rsi, O_RDONLY));
+    write(code, SYSCALL);
+    write(code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+    write(code, js_rel32(0));
+    let fresh_jump = code.len();
+    write(code, mov_r64_to_r64(Reg::Rax, Reg::Rbx));
+
+    // read the header onto the stack, and the tape above it
+    let mut invalid_jumps = Vec::new();
+    write(code, sub_imm32_from_r64(Reg::Rsp, CHECKPOINT_HEADER_LEN));
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_READ));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, mov_imm32_to_r64(Reg::Rdx, CHECKPOINT_HEADER_LEN));
+    write(code, SYSCALL);
+    write(code, cmp_r32_with_imm32(Reg::Rax, CHECKPOINT_HEADER_LEN));
+    write(code, jnz_rel32(0));
+    invalid_jumps.push(code.len());
+    write(code, mov_m32_disp8_to_r32(RmID::RegRsi, 0, Reg::Rax));
+    write(code, cmp_r32_with_imm32(Reg::Rax, hash as i32));
+    write(code, jnz_rel32(0));
+    invalid_jumps.push(code.len());
+
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_READ));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    let offset = CHECKPOINT_HEADER_LEN + tape.start;
+    write(code, add_imm32_to_r64(Reg::Rsi, offset));
+    write(code, mov_imm32_to_r64(Reg::Rdx, tape.len));
+    write(code, SYSCALL);
+    write(code, cmp_r32_with_imm32(Reg::Rax, tape.len));
+    write(code, jnz_rel32(0));
+    invalid_jumps.push(code.len());
+
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_CLOSE));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write(code, SYSCALL);
+
+    // system calls overwrite `rcx`, so the register pointer is restored afterwards
+    write_checkpoint_deadline(code, checkpoint);
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRsi, 16, Reg::Rcx));
+    write(code, mov_m32_disp8_to_r32(RmID::RegRsi, 8, Reg::Rax));
+    write(code, add_imm32_to_r64(Reg::Rsp, CHECKPOINT_HEADER_LEN));
+    write_lea_position(code, 0, Reg::Rdx);
+    write(code, add_r64_to_r64(Reg::Rax, Reg::Rdx));
+    write(code, jmp_r64(Reg::Rdx));
+
+    for pos in invalid_jumps {
+        let disp = (code.len() - pos) as i32;
+        code[pos - 4..pos].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_imm32_to_r64(Reg::Rdi, STDERR_FD));
+    write_lea_position(code, strings.invalid.0, Reg::Rsi);
+    write(code, mov_imm32_to_r64(Reg::Rdx, strings.invalid.1 as i32));
+    write(code, SYSCALL);
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_EXIT));
+    write(code, mov_imm32_to_r64(Reg::Rdi, 1));
+    write(code, SYSCALL);
+
+    let disp = (code.len() - fresh_jump) as i32;
+    code[fresh_jump - 4..fresh_jump].copy_from_slice(&i32::to_le_bytes(disp));
+    write_checkpoint_deadline(code, checkpoint);
+
+    strings
+}
+
+/// Set the time the next checkpoint is due in `r14`, and reset the loop iteration counter in
+/// `r15`. Overwrites `rcx`, like system calls.
+fn write_checkpoint_deadline(code: &mut Vec<u8>, checkpoint: &Checkpoint) {
+    write(
+        code,
+        mov_imm32_to_r64_ext(RegExt::R15, CHECKPOINT_CLOCK_INTERVAL),
+    );
+    write_read_clock(code);
+    let interval = checkpoint.interval.min(i32::MAX as u32) as i32;
+    write(code, add_imm32_to_r64(Reg::Rax, interval));
+    write(code, mov_r64_to_r64_ext(Reg::Rax, RegExt::R14));
+}
+
+/// Read the seconds of the monotonic clock into `rax`.
+fn write_read_clock(code: &mut Vec<u8>) {
+    const SYSCALL_CLOCK_GETTIME: i32 = 228;
+    const CLOCK_MONOTONIC: i32 = 1;
+    write(code, sub_imm32_from_r64(Reg::Rsp, 16));
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_CLOCK_GETTIME));
+    write(code, mov_imm32_to_r64(Reg::Rdi, CLOCK_MONOTONIC));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, SYSCALL);
+    write(code, mov_m64_disp32_to_r64(RmID::RegRsi, 0, Reg::Rax));
+    write(code, add_imm32_to_r64(Reg::Rsp, 16));
+}
+
+/// Write the part of the safe point, which reads the clock, and if the checkpoint is due, writes
+/// the header and the tape to a temporary file, which is then renamed to the checkpoint file, so
+/// an interrupted write doesn't corrupt the previous checkpoint. The location the program is
+/// resumed at, is the return address of the safe point, which is followed by the saved register
+/// pointer on the stack. The buffered output is flushed before.
+///
+/// A checkpoint that can't be written is reported to stderr, and the program continues.
+fn write_checkpoint(
+    code: &mut Vec<u8>,
+    state: &CheckpointState,
+    tape: TapeLayout,
+    flush: Option<usize>,
+) {
+    let CheckpointState {
+        checkpoint,
+        hash,
+        strings,
+    } = *state;
+    const SYSCALL_WRITE: i32 = 1;
+    const SYSCALL_OPEN: i32 = 2;
+    const SYSCALL_CLOSE: i32 = 3;
+    const SYSCALL_FSYNC: i32 = 74;
+    const SYSCALL_RENAME: i32 = 82;
+    const STDERR_FD: i32 = 2;
+    const O_WRONLY: i32 = 0o1;
+    const O_CREAT: i32 = 0o100;
+    const O_TRUNC: i32 = 0o1000;
+    const MODE: i32 = 0o644;
+
+    write_read_clock(code);
+    write(code, mov_r64_ext_to_r64(RegExt::R14, Reg::Rdx));
+    write(code, cmp_r64_with_r64(Reg::Rax, Reg::Rdx));
+    write(code, jb_rel32(0));
+    let not_due_jump = code.len();
+    let interval = checkpoint.interval.min(i32::MAX as u32) as i32;
+    write(code, add_imm32_to_r64(Reg::Rax, interval));
+    write(code, mov_r64_to_r64_ext(Reg::Rax, RegExt::R14));
+    if let Some(flush) = flush {
+        write_call(code, flush);
+    }
+
+    // the header is pushed below the saved register pointer and the return address
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRsi, 0, Reg::Rdx));
+    write(code, push_r64(Reg::Rdx));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRsi, 8, Reg::Rax));
+    write_lea_position(code, 0, Reg::Rdx);
+    write(code, sub_r64_from_r64(Reg::Rdx, Reg::Rax));
+    write(code, push_r64(Reg::Rax));
+    write(code, mov_imm32_to_r64(Reg::Rax, hash as i32));
+    write(code, push_r64(Reg::Rax));
+
+    let mut failed_jumps = Vec::new();
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_OPEN));
+    write_lea_position(code, strings.tmp_path, Reg::Rdi);
+    write(
+        code,
+        mov_imm32_to_r64(Reg::Rsi, O_WRONLY | O_CREAT | O_TRUNC),
+    );
+    write(code, mov_imm32_to_r64(Reg::Rdx, MODE));
+    write(code, SYSCALL);
+    write(code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+    write(code, js_rel32(0));
+    failed_jumps.push(code.len());
+    write(code, mov_r64_to_r64(Reg::Rax, Reg::Rbx));
+
+    let mut close_jumps = Vec::new();
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, mov_imm32_to_r64(Reg::Rdx, CHECKPOINT_HEADER_LEN));
+    write(code, SYSCALL);
+    write(code, cmp_r32_with_imm32(Reg::Rax, CHECKPOINT_HEADER_LEN));
+    write(code, jnz_rel32(0));
+    close_jumps.push(code.len());
+
+    // the tape is above the header, the saved register pointer and the return address
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    let offset = CHECKPOINT_HEADER_LEN + 16 + tape.start;
+    write(code, add_imm32_to_r64(Reg::Rsi, offset));
+    write(code, mov_imm32_to_r64(Reg::Rdx, tape.len));
+    write(code, SYSCALL);
+    write(code, cmp_r32_with_imm32(Reg::Rax, tape.len));
+    write(code, jnz_rel32(0));
+    close_jumps.push(code.len());
+
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_FSYNC));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write(code, SYSCALL);
+    write(code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+    write(code, jnz_rel32(0));
+    close_jumps.push(code.len());
+
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_CLOSE));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write(code, SYSCALL);
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_RENAME));
+    write_lea_position(code, strings.tmp_path, Reg::Rdi);
+    write_lea_position(code, strings.path, Reg::Rsi);
+    write(code, SYSCALL);
+    write(code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+    write(code, jnz_rel32(0));
+    failed_jumps.push(code.len());
+    write(code, jmp_rel32(0));
+    let written_jump = code.len();
+
+    for pos in close_jumps {
+        let disp = (code.len() - pos) as i32;
+        code[pos - 4..pos].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_CLOSE));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write(code, SYSCALL);
+    for pos in failed_jumps {
+        let disp = (code.len() - pos) as i32;
+        code[pos - 4..pos].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_imm32_to_r64(Reg::Rdi, STDERR_FD));
+    write_lea_position(code, strings.failed.0, Reg::Rsi);
+    write(code, mov_imm32_to_r64(Reg::Rdx, strings.failed.1 as i32));
+    write(code, SYSCALL);
+
+    let disp = (code.len() - written_jump) as i32;
+    code[written_jump - 4..written_jump].copy_from_slice(&i32::to_le_bytes(disp));
+    write(code, add_imm32_to_r64(Reg::Rsp, CHECKPOINT_HEADER_LEN));
+
+    let disp = (code.len() - not_due_jump) as i32;
+    code[not_due_jump - 4..not_due_jump].copy_from_slice(&i32::to_le_bytes(disp));
+}
+
+/// Call the routine at `pos`.
+fn write_call(code: &mut Vec<u8>, pos: usize) {
+    const CALL_LEN: usize = call_rel32(0).len();
+    let disp = pos as isize - (code.len() + CALL_LEN) as isize;
+    write(code, call_rel32(disp as i32));
+}
+
+/// Install a handler for `signal` using `rt_sigaction`. Returns the positions after the
+/// displacements of the handler and the restorer addresses, which have to be updated once they
+/// are written, see [`write_signal_restorer`].
+fn write_install_handler(code: &mut Vec<u8>, signal: i32, flags: i32) -> (usize, usize) {
+    // the `sigaction` struct is built on the stack
+    write(code, lea_rip_disp32_to_r64(0, Reg::Rax));
+    let restorer_disp_pos = code.len();
+
+    const SA_SIGINFO: i32 = 0x0000_0004;
+    const SA_RESTORER: i32 = 0x0400_0000;
+    write(code, xor_r64_r64(Reg::Rdx, Reg::Rdx));
+    write(code, push_r64(Reg::Rdx)); // sa_mask
+    write(code, push_r64(Reg::Rax)); // sa_restorer
+    write(
+        code,
+        mov_imm32_to_r64(Reg::Rdx, SA_SIGINFO | SA_RESTORER | flags),
+    );
+    write(code, push_r64(Reg::Rdx)); // sa_flags
+    write(code, lea_rip_disp32_to_r64(0, Reg::Rax));
+    let handler_disp_pos = code.len();
+    write(code, push_r64(Reg::Rax)); // sa_handler
+
+    const SYSCALL_RT_SIGACTION: i32 = 13;
+    const SIGSET_SIZE: i32 = 8;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_RT_SIGACTION));
+    write(code, mov_imm32_to_r64(Reg::Rdi, signal));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, xor_r64_r64(Reg::Rdx, Reg::Rdx));
+    write(code, mov_imm32_to_r64_ext(RegExt::R10, SIGSET_SIZE));
+    write(code, SYSCALL);
+    write_exit_on_error(code);
+    write(code, add_imm32_to_r64(Reg::Rsp, 32));
+
+    (handler_disp_pos, restorer_disp_pos)
+}
+
+/// Write the routine that returns from signal handlers using `rt_sigreturn`, which is required on
+/// x86-64.
+fn write_signal_restorer(code: &mut Vec<u8>) {
+    const SYSCALL_RT_SIGRETURN: i32 = 15;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_RT_SIGRETURN));
+    write(code, SYSCALL);
+}
+
+/// Write the routine writing the buffered output to stdout. Output that can't be written is
+/// dropped. Preserves `rcx`.
+fn write_flush(code: &mut Vec<u8>) {
+    const SYSCALL_WRITE: i32 = 1;
+    const STDOUT_FD: i32 = 1;
+
+    write(code, push_r64(Reg::Rcx));
+    write(code, mov_imm32_to_r64(Reg::Rsi, BSS_OUTPUT_LEN));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRsi, 0, Reg::Rdx));
+    write(code, add_imm8_to_r64(Reg::Rsi, 16));
+
+    // `rsi` points to the bytes left, and `rdx` counts them
+    let mut done_jumps = Vec::new();
+    let loop_start = code.len();
+    write(code, cmp_r64_with_imm8(Reg::Rdx, 0x00));
+    write(code, jz_rel8(0));
+    done_jumps.push(code.len() - 1);
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_imm32_to_r64(Reg::Rdi, STDOUT_FD));
+    write(code, SYSCALL);
+    write(code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+    write(code, jle_rel8(0));
+    done_jumps.push(code.len() - 1);
+    write(code, add_r64_to_r64(Reg::Rax, Reg::Rsi));
+    write(code, sub_r64_from_r64(Reg::Rax, Reg::Rdx));
+    let loop_end = code.len() + const { jmp_rel8(0).len() };
+    write(
+        code,
+        jmp_rel8((loop_start as isize - loop_end as isize) as i8),
+    );
+
+    for pos in done_jumps {
+        code[pos] = (code.len() - (pos + 1)) as u8;
+    }
+    write(code, mov_imm32_to_r64(Reg::Rsi, BSS_OUTPUT_LEN));
+    write(code, mov_imm32_to_m64_disp32(RmID::RegRsi, 0, 0));
+    write(code, pop_r64(Reg::Rcx));
+    write(code, RET);
+}
+
+/// Write the routine appending the `rdx` bytes at `rsi` to the output buffer, which is flushed
+/// first if they don't fit. At most [`OUTPUT_BUF_LEN`] bytes can be appended at once. Preserves
+/// `rcx`.
+fn write_append(code: &mut Vec<u8>, flush: usize) {
+    write(code, push_r64(Reg::Rcx));
+    write(code, mov_imm32_to_r64(Reg::Rdi, BSS_OUTPUT_LEN));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRdi, 0, Reg::Rax));
+    write(code, add_r64_to_r64(Reg::Rdx, Reg::Rax));
+    write(code, cmp_r32_with_imm32(Reg::Rax, OUTPUT_BUF_LEN));
+    let mut flush_code = Vec::new();
+    write(&mut flush_code, push_r64(Reg::Rsi));
+    write(&mut flush_code, push_r64(Reg::Rdx));
+    let call_pos = code.len() + jbe_rel8(0).len() + flush_code.len();
+    let disp = flush as isize - (call_pos + call_rel32(0).len()) as isize;
+    write(&mut flush_code, call_rel32(disp as i32));
+    write(&mut flush_code, pop_r64(Reg::Rdx));
+    write(&mut flush_code, pop_r64(Reg::Rsi));
+    write(code, jbe_rel8(flush_code.len() as i8));
+    code.extend_from_slice(&flush_code);
+
+    // the length is updated before the bytes are copied after the buffered ones
+    write(code, mov_imm32_to_r64(Reg::Rdi, BSS_OUTPUT_LEN));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRdi, 0, Reg::Rax));
+    write(code, mov_r64_to_r64(Reg::Rax, Reg::Rcx));
+    write(code, add_r64_to_r64(Reg::Rdx, Reg::Rcx));
+    write(code, mov_r64_to_m64_disp32(Reg::Rcx, RmID::RegRdi, 0));
+    write(code, mov_imm32_to_r64(Reg::Rdi, BSS_OUTPUT_BUF));
+    write(code, add_r64_to_r64(Reg::Rax, Reg::Rdi));
+    write(code, mov_r64_to_r64(Reg::Rdx, Reg::Rcx));
+    write(code, REP_MOVSB);
+    write(code, pop_r64(Reg::Rcx));
+    write(code, RET);
+}
+
+/// Write the routine looking up the instruction the code at offset `eax` from the start of the
+/// code originates from, which is returned in `eax`. The table of the `locations` of the code of
+/// each instruction is embedded after the routine.
+fn write_lookup_instruction(code: &mut Vec<u8>, locations: &[(u32, u32)]) {
+    // find the last location before the offset, the table is terminated by a location after all
+    // code
+    write(code, lea_rip_disp32_to_r64(0, Reg::Rsi));
+    let table_disp_pos = code.len();
+    {
+        let loop_start = code.len();
+        write(code, add_imm8_to_r64(Reg::Rsi, 8));
+        write(code, cmp_m32_with_r32(RmI::RegRsi, Reg::Rax));
+        let loop_end = code.len() + const { jbe_rel8(0).len() };
+        write(
+            code,
+            jbe_rel8((loop_start as isize - loop_end as isize) as i8),
+        );
+    }
+    write(code, mov_m32_disp8_to_r32(RmID::RegRsi, -4, Reg::Rax));
+    write(code, RET);
+
+    let disp = (code.len() - table_disp_pos) as i32;
+    code[table_disp_pos - 4..table_disp_pos].copy_from_slice(&i32::to_le_bytes(disp));
+    for &(location, origin) in locations.iter().chain([&(u32::MAX, 0)]) {
+        code.extend_from_slice(&u32::to_le_bytes(location));
+        code.extend_from_slice(&u32::to_le_bytes(origin));
+    }
+}
+
+/// Make the code address in `rax` relative to the start of the code, and look up the instruction
+/// it originates from, see [`write_lookup_instruction`].
+fn write_call_lookup(code: &mut Vec<u8>, lookup: usize) {
+    write_lea_position(code, 0, Reg::Rsi);
+    write(code, sub_r64_from_r64(Reg::Rsi, Reg::Rax));
+    write_call(code, lookup);
+}
+
+/// Write the constant `bytes` to stderr. The bytes are embedded into the code and jumped over.
+fn write_stderr_const(code: &mut Vec<u8>, bytes: &[u8]) {
+    write(code, jmp_rel8(bytes.len() as i8));
+    let start = code.len();
+    code.extend_from_slice(bytes);
+
+    const SYSCALL_WRITE: i32 = 1;
+    const STDERR_FD: i32 = 2;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_imm32_to_r64(Reg::Rdi, STDERR_FD));
+    write_lea_position(code, start, Reg::Rsi);
+    write(code, mov_imm32_to_r64(Reg::Rdx, bytes.len() as i32));
+    write(code, SYSCALL);
+}
+
+/// Write `eax` to stderr as decimal number, preceded by the `prefix` byte, using a buffer on the
+/// stack. Overwrites `rcx`, see [`write_decimal_digits`].
+fn write_stderr_decimal(code: &mut Vec<u8>, prefix: Option<u8>) {
+    write(code, sub_imm32_from_r64(Reg::Rsp, 16));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, add_imm8_to_r64(Reg::Rsi, 16));
+    write_decimal_digits(code);
+    if let Some(prefix) = prefix {
+        write(code, add_imm8_to_r64(Reg::Rsi, -1));
+        write(code, mov_imm8_to_r8(Reg::Rdx, prefix));
+        write(code, mov_r8_to_m8(Reg::Rdx, RmI::RegRsi));
+    }
+
+    // the length is `rsp + 16 - rsi`
+    const SYSCALL_WRITE: i32 = 1;
+    const STDERR_FD: i32 = 2;
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rdx));
+    write(code, add_imm8_to_r64(Reg::Rdx, 16));
+    write(code, sub_r64_from_r64(Reg::Rsi, Reg::Rdx));
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_imm32_to_r64(Reg::Rdi, STDERR_FD));
+    write(code, SYSCALL);
+    write(code, add_imm32_to_r64(Reg::Rsp, 16));
+}
+
+/// Write the routine called when the loop iteration counter in `r15` reaches zero. If a dump of
+/// the state was requested by the `SIGUSR1` handler, the buffered output is flushed, and the
+/// instruction, the register pointer, and the registers up to the last non-zero one are written
+/// to stderr, like:
+///
+/// ```text
+/// instruction 12, register pointer 3, registers: 0 72 101 0 7
+/// ```
+///
+/// Afterwards the checkpoint is written if it's due, see [`write_checkpoint`]. The location of the
+/// program is the return address of the call, and the register pointer is saved above it.
+fn write_safe_point(
+    code: &mut Vec<u8>,
+    tape: TapeLayout,
+    lookup: usize,
+    flush: Option<usize>,
+    checkpoint: Option<&CheckpointState>,
+) {
+    let interval = match checkpoint {
+        Some(_) => CHECKPOINT_CLOCK_INTERVAL,
+        None => SAFE_POINT_INTERVAL,
+    };
+    write(code, mov_imm32_to_r64_ext(RegExt::R15, interval));
+    write(code, push_r64(Reg::Rcx));
+
+    write(code, mov_imm32_to_r64(Reg::Rsi, BSS_DUMP_REQUESTED));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRsi, 0, Reg::Rax));
+    write(code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+    write(code, jz_rel32(0));
+    let no_dump_jump = code.len();
+    write(code, mov_imm32_to_m64_disp32(RmID::RegRsi, 0, 0));
+    if let Some(flush) = flush {
+        write_call(code, flush);
+    }
+
+    // `rbx` points to the next register, and `rbp` after the last non-zero one
+    write(code, push_r64(Reg::Rbx));
+    write(code, push_r64(Reg::Rbp));
+    write_stderr_const(code, b"instruction ");
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRsi, 24, Reg::Rax));
+    write_call_lookup(code, lookup);
+    write_stderr_decimal(code, None);
+    write_stderr_const(code, b", register pointer ");
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, mov_m64_disp32_to_r64(RmID::RegRsi, 16, Reg::Rax));
+    write(code, sub_imm32_from_r64(Reg::Rax, tape.start));
+    write_stderr_decimal(code, None);
+    write_stderr_const(code, b", registers:");
+
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rbx));
+    write(code, add_imm32_to_r64(Reg::Rbx, 32 + tape.start));
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rbp));
+    write(code, add_imm32_to_r64(Reg::Rbp, tape.len));
+    {
+        let mut found_jumps = Vec::new();
+        let loop_start = code.len();
+        write(code, cmp_r64_with_r64(Reg::Rbp, Reg::Rbx));
+        write(code, jbe_rel8(0));
+        found_jumps.push(code.len() - 1);
+        write(code, mov_r64_to_r64(Reg::Rbp, Reg::Rsi));
+        write(code, add_imm8_to_r64(Reg::Rsi, -1));
+        write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+        write(code, mov_m8_to_r8(RmI::RegRsi, Reg::Rax));
+        write(code, cmp_r32_with_imm8(Reg::Rax, 0x00));
+        write(code, jnz_rel8(0));
+        found_jumps.push(code.len() - 1);
+        write(code, mov_r64_to_r64(Reg::Rsi, Reg::Rbp));
+        let loop_end = code.len() + const { jmp_rel8(0).len() };
+        write(
+            code,
+            jmp_rel8((loop_start as isize - loop_end as isize) as i8),
+        );
+        for pos in found_jumps {
+            code[pos] = (code.len() - (pos + 1)) as u8;
+        }
+    }
+    {
+        let loop_start = code.len();
+        write(code, cmp_r64_with_r64(Reg::Rbx, Reg::Rbp));
+        write(code, jz_rel32(0));
+        let done_jump = code.len();
+        write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+        write(code, mov_m8_to_r8(RmI::RegRbx, Reg::Rax));
+        write_stderr_decimal(code, Some(b' '));
+        write(code, add_imm8_to_r64(Reg::Rbx, 1));
+        let loop_end = code.len() + const { jmp_rel32(0).len() };
+        write(
+            code,
+            jmp_rel32((loop_start as isize - loop_end as isize) as i32),
+        );
+        let disp = (code.len() - done_jump) as i32;
+        code[done_jump - 4..done_jump].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+    write_stderr_const(code, b"\n");
+    write(code, pop_r64(Reg::Rbp));
+    write(code, pop_r64(Reg::Rbx));
+
+    let disp = (code.len() - no_dump_jump) as i32;
+    code[no_dump_jump - 4..no_dump_jump].copy_from_slice(&i32::to_le_bytes(disp));
+    if let Some(state) = checkpoint {
+        write_checkpoint(code, state, tape, flush);
+    }
+    write(code, pop_r64(Reg::Rcx));
+    write(code, RET);
+}
+
+/// Write the `SIGSEGV` handler, which flushes the buffered output, prints
+/// `tape overrun at instruction N` to stderr, and exits with status 1.
+///
+/// Since the tape is surrounded by guard pages, accesses outside of it cause a segmentation fault.
+/// Accesses of registers by the input system call fail instead.
+fn write_overrun_handler(
+    code: &mut Vec<u8>,
+    lookup: usize,
+    flush: Option<usize>,
+    restore_terminal: bool,
+) {
+    // the handler receives the signal number, the `siginfo_t` and the `ucontext_t`, the faulting
+    // instruction pointer is stored in `uc_mcontext.gregs[REG_RIP]`
+    const UC_MCONTEXT_RIP: i32 = 0xA8;
+    write(
+        code,
+        mov_m64_disp32_to_r64(RmID::RegRdx, UC_MCONTEXT_RIP, Reg::Rax),
+    );
+    write_call_lookup(code, lookup);
+    write(code, push_r64(Reg::Rax));
+    if let Some(flush) = flush {
+        write_call(code, flush);
+    }
+    write_stderr_const(code, b"tape overrun at instruction ");
+    write(code, pop_r64(Reg::Rax));
+    write_stderr_decimal(code, None);
+    write_stderr_const(code, b"\n");
+    if restore_terminal {
+        write_restore_terminal(code);
+    }
+
+    const SYSCALL_EXIT: i32 = 60;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_EXIT));
+    write(code, mov_imm32_to_r64(Reg::Rdi, 1));
+    write(code, SYSCALL);
+}
+
+/// Write the `SIGINT` handler, which flushes the buffered output, restores the terminal in the
+/// `--interactive` mode, and exits with status 130, like a process killed by the signal.
+fn write_interrupt_handler(code: &mut Vec<u8>, flush: Option<usize>, restore_terminal: bool) {
+    if let Some(flush) = flush {
+        write_call(code, flush);
+    }
+    if restore_terminal {
+        write_restore_terminal(code);
+    }
+    const SYSCALL_EXIT: i32 = 60;
+    const EXIT_INTERRUPTED: i32 = 128 + 2;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_EXIT));
+    write(code, mov_imm32_to_r64(Reg::Rdi, EXIT_INTERRUPTED));
+    write(code, SYSCALL);
+}
+
+/// Put the terminal connected to stdin into non-canonical mode, so reads return as soon as a single
+/// key is pressed instead of waiting for enter, like [`crate::tty::RawMode`]. The original
+/// settings are saved, and restored by [`write_restore_terminal`]. If stdin isn't a terminal the
+/// `ioctl`s fail without any effect.
+fn write_raw_terminal(code: &mut Vec<u8>) {
+    const SYSCALL_IOCTL: i32 = 16;
+    const TCGETS: i32 = 0x5401;
+    const TCSETS: i32 = 0x5402;
+    const C_LFLAG: i8 = 12;
+    const ICANON: u8 = 0o2;
+    const C_CC: i8 = 17;
+    const VTIME: i8 = 5;
+    const VMIN: i8 = 6;
+
+    for termios in [BSS_TERMIOS, BSS_TERMIOS_RAW] {
+        write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_IOCTL));
+        write(code, xor_r64_r64(Reg::Rdi, Reg::Rdi));
+        write(code, mov_imm32_to_r64(Reg::Rsi, TCGETS));
+        write(code, mov_imm32_to_r64(Reg::Rdx, termios));
+        write(code, SYSCALL);
+    }
+    write(code, and_imm8_to_m8_disp8(RmID::RegRdx, C_LFLAG, !ICANON));
+    write(code, mov_imm8_to_m8_disp8(RmID::RegRdx, C_CC + VMIN, 1));
+    write(code, mov_imm8_to_m8_disp8(RmID::RegRdx, C_CC + VTIME, 0));
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_IOCTL));
+    write(code, mov_imm32_to_r64(Reg::Rsi, TCSETS));
+    write(code, SYSCALL);
+}
+
+/// Restore the settings of the terminal saved by [`write_raw_terminal`].
+fn write_restore_terminal(code: &mut Vec<u8>) {
+    const SYSCALL_IOCTL: i32 = 16;
+    const TCSETS: i32 = 0x5402;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_IOCTL));
+    write(code, xor_r64_r64(Reg::Rdi, Reg::Rdi));
+    write(code, mov_imm32_to_r64(Reg::Rsi, TCSETS));
+    write(code, mov_imm32_to_r64(Reg::Rdx, BSS_TERMIOS));
+    write(code, SYSCALL);
+}
+
+/// Write the `SIGUSR1` handler, which requests a dump of the state at the next safe point, see
+/// [`write_safe_point`]. The loop iteration counter in `r15` is set to 1, so the safe point is
+/// called at the end of the next loop iteration.
+fn write_dump_handler(code: &mut Vec<u8>) {
+    const UC_MCONTEXT_R15: i32 = 0x60;
+    write(code, mov_imm32_to_r64(Reg::Rax, BSS_DUMP_REQUESTED));
+    write(code, mov_imm32_to_m64_disp32(RmID::RegRax, 0, 1));
+    write(
+        code,
+        mov_imm32_to_m64_disp32(RmID::RegRdx, UC_MCONTEXT_R15, 1),
+    );
+    write(code, RET);
+}
+
+/// Zero the registers in `range` 8 bytes at a time, the tape has to be a multiple of 8 bytes long.
+fn write_zero_registers(code: &mut Vec<u8>, range: PointerRange) {
+    let (start, end) = (range.min / 8, range.max / 8 + 1);
+    write(code, mov_imm32_to_r64(Reg::Rcx, end));
+
+    let loop_start = code.len();
+    write(code, sub_imm8_from_r32(Reg::Rcx, 0x01));
+
+    // write 0_i64 to stack at `rsp + 8 * rcx` using a scaled index byte (SIB)
+    const SIB: Sib = Sib::new(Scale::B8, Reg::Rcx, Reg::Rsp);
+    write(code, mov_imm32_to_sib64(SIB, 0));
+
+    match start {
+        0..=127 => write(code, cmp_r32_with_imm8(Reg::Rcx, start as i8)),
+        _ => write(code, cmp_r32_with_imm32(Reg::Rcx, start)),
+    }
+
+    // the instruction pointer will have already moved to the next instruction, so it will be
+    // after the jump instruction
+    let loop_end = code.len() + const { jnz_rel8(0).len() };
+    let rel_jump = (loop_start as isize - loop_end as isize) as i8;
+    write(code, jnz_rel8(rel_jump))
+}
+
+/// Call the callback at `offset` inside the [`Io`] struct, with the address of the register at
+/// displacement `disp`.
+fn write_io_callback(code: &mut Vec<u8>, disp: i16, offset: usize) {
+    // the callback receives the `Io` struct and the address of the register
+    write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+    write_register_address(code, Reg::Rsi, disp as i32);
+
+    // keep the stack 16-byte aligned across the call
+    write(code, push_r64(Reg::Rcx));
+    write(code, sub_imm32_from_r64(Reg::Rsp, 8));
+    write(code, call_rm64_disp8(RmID::RegRbx, offset as i8));
+    write(code, add_imm32_to_r64(Reg::Rsp, 8));
+    write(code, pop_r64(Reg::Rcx));
+}
+
+/// Copy the input to the output through a buffer on the stack, like the echo loop `[.,]`
+/// following the instruction, until the current register is zero or the input is exhausted. The
+/// register is stored in front of the bytes read, so every chunk is written using a single
+/// system call, up to the first zero byte.
+fn write_pipe(code: &mut Vec<u8>, eof: Option<u8>) {
+    const SYSCALL_WRITE: i32 = 1;
+    const STDOUT_FD: i32 = 1;
+
+    // `rbx` points to the register, `rsp` to the buffer
+    write_register_address(code, Reg::Rbx, 0);
+    write(code, push_r64(Reg::Rcx));
+    write(code, sub_imm32_from_r64(Reg::Rsp, PIPE_BUF_LEN as i32));
+
+    let loop_start = code.len();
+    write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+    write(code, mov_m8_to_r8(RmI::RegRbx, Reg::Rax));
+    write(code, cmp_r32_with_imm8(Reg::Rax, 0x00));
+    write(code, jz_rel32(0));
+    let done_jump = code.len();
+
+    // read into the buffer after the register
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, mov_r8_to_m8(Reg::Rax, RmI::RegRsi));
+    write(code, add_imm8_to_r64(Reg::Rsi, 1));
+    write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+    write(code, xor_r64_r64(Reg::Rdi, Reg::Rdi));
+    write(code, mov_imm32_to_r64(Reg::Rdx, PIPE_BUF_LEN as i32 - 1));
+    write(code, SYSCALL);
+    write(code, cmp_r64_with_imm8(Reg::Rax, 0x00));
+    write(code, jz_rel8(0));
+    let eof_jump = code.len() - 1;
+    write(code, jns_rel8(0));
+    let read_jump = code.len() - 1;
+
+    // at the end of the input, or if reading failed, write the register and handle the end of the
+    // input like the echo loop
+    code[eof_jump] = (code.len() - (eof_jump + 1)) as u8;
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_imm32_to_r64(Reg::Rdi, STDOUT_FD));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, mov_imm32_to_r64(Reg::Rdx, 1));
+    write(code, SYSCALL);
+    if let Some(val) = eof {
+        write(code, mov_imm8_to_r8(Reg::Rax, val));
+        write(code, mov_r8_to_m8(Reg::Rax, RmI::RegRbx));
+    }
+    write(code, jmp_rel32(0));
+    let eof_done_jump = code.len();
+
+    // find the first zero byte, `rdx` counts the bytes left
+    code[read_jump] = (code.len() - (read_jump + 1)) as u8;
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, add_imm8_to_r64(Reg::Rsi, 1));
+    write(code, mov_r64_to_r64(Reg::Rax, Reg::Rdx));
+    let mut found_jumps = Vec::new();
+    {
+        let scan_start = code.len();
+        write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+        write(code, mov_m8_to_r8(RmI::RegRsi, Reg::Rax));
+        write(code, cmp_r32_with_imm8(Reg::Rax, 0x00));
+        write(code, jz_rel8(0));
+        found_jumps.push(code.len() - 1);
+        write(code, add_imm8_to_r64(Reg::Rdx, -1));
+        write(code, jz_rel8(0));
+        found_jumps.push(code.len() - 1);
+        write(code, add_imm8_to_r64(Reg::Rsi, 1));
+        let scan_end = code.len() + const { jmp_rel8(0).len() };
+        write(
+            code,
+            jmp_rel8((scan_start as isize - scan_end as isize) as i8),
+        );
+    }
+    for pos in found_jumps {
+        code[pos] = (code.len() - (pos + 1)) as u8;
+    }
+
+    // the byte found is the next value of the register, the bytes before it are written
+    write(code, mov_m8_to_r8(RmI::RegRsi, Reg::Rax));
+    write(code, mov_r8_to_m8(Reg::Rax, RmI::RegRbx));
+    write(code, mov_r64_to_r64(Reg::Rsi, Reg::Rdx));
+    write(code, sub_r64_from_r64(Reg::Rsp, Reg::Rdx));
+    write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+    write(code, mov_imm32_to_r64(Reg::Rdi, STDOUT_FD));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, SYSCALL);
+    let loop_end = code.len() + const { jmp_rel32(0).len() };
+    write(
+        code,
+        jmp_rel32((loop_start as isize - loop_end as isize) as i32),
+    );
+
+    for pos in [done_jump, eof_done_jump] {
+        let disp = (code.len() - pos) as i32;
+        code[pos - 4..pos].copy_from_slice(&i32::to_le_bytes(disp));
+    }
+    write(code, add_imm32_to_r64(Reg::Rsp, PIPE_BUF_LEN as i32));
+    write(code, pop_r64(Reg::Rcx));
+}
+
+/// Write the constant `bytes` to the output at once, or append them to the output buffer if
+/// `calls` is passed and they fit. The bytes are embedded into the code and jumped over.
+fn write_output_const(
+    code: &mut Vec<u8>,
+    bytes: &[u8],
+    runtime: Runtime,
+    calls: Option<&mut Calls>,
+) {
+    let calls = match calls {
+        Some(calls) if bytes.len() > OUTPUT_BUF_LEN as usize => {
+            calls.write(code, Routine::Flush);
+            None
+        }
+        calls => calls,
+    };
+    match bytes.len() {
+        0..=127 => write(code, jmp_rel8(bytes.len() as i8)),
+        _ => write(code, jmp_rel32(bytes.len() as i32)),
+    }
+    let start = code.len();
+    code.extend_from_slice(bytes);
+
+    // the displacement is relative to the end of the `lea` instruction
+    const LEA_LEN: usize = lea_rip_disp32_to_r64(0, Reg::Rsi).len();
+    let disp = start as isize - (code.len() + LEA_LEN) as isize;
+    write(code, lea_rip_disp32_to_r64(disp as i32, Reg::Rsi));
+    write(code, mov_imm32_to_r64(Reg::Rdx, bytes.len() as i32));
+
+    if let Some(calls) = calls {
+        calls.write(code, Routine::Append);
+        return;
+    }
+    match runtime {
+        Runtime::Linux | Runtime::Linked { .. } => {
+            const SYSCALL_WRITE: i32 = 1;
+            write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+
+            const STDOUT_FD: i32 = 1;
+            write(code, mov_imm32_to_r64(Reg::Rdi, STDOUT_FD));
+
+            write(code, push_r64(Reg::Rcx));
+            write(code, SYSCALL);
+            write(code, pop_r64(Reg::Rcx));
+        }
+        Runtime::InProcess => {
+            // the callback receives the `Io` struct, the address and the length of the bytes
+            write(code, mov_r64_to_r64(Reg::Rbx, Reg::Rdi));
+
+            // keep the stack 16-byte aligned across the call
+            write(code, push_r64(Reg::Rcx));
+            write(code, sub_imm32_from_r64(Reg::Rsp, 8));
+            const OFFSET: usize = std::mem::offset_of!(Io, output_const);
+            write(code, call_rm64_disp8(RmID::RegRbx, OFFSET as i8));
+            write(code, add_imm32_to_r64(Reg::Rsp, 8));
+            write(code, pop_r64(Reg::Rcx));
+        }
+    }
+}
+
+/// Compute the effect of the divmod loop following the instruction using `div`, if the registers
+/// starting at `sib` are laid out as expected, see [`crate::interp::div_mod`]. The code runs in
+/// 64-bit and 32-bit mode.
+pub fn write_div_mod(code: &mut Vec<u8>, sib: Sib) {
+    // the positions of the rel8 offsets of the jumps skipping the division
+    let mut skip_jumps = Vec::new();
+    write(code, cmp_sib8_with_imm8(sib, 0));
+    write(code, jz_rel8(0));
+    skip_jumps.push(code.len() - 1);
+    write(code, cmp_sib8_disp8_with_imm8(sib, 1, 2));
+    write(code, jb_rel8(0));
+    skip_jumps.push(code.len() - 1);
+    for disp in [2, 4, 5] {
+        write(code, cmp_sib8_disp8_with_imm8(sib, disp, 0));
+        write(code, jnz_rel8(0));
+        skip_jumps.push(code.len() - 1);
+    }
+
+    // `al = n / d`, `ah = n % d`
+    write(code, xor_r32_r32(Reg::Rax, Reg::Rax));
+    write(code, mov_sib8_to_r8(sib, Reg::Rax));
+    write(code, div_ax_by_sib8_disp8(sib, 1));
+    write(code, add_r8_to_sib8_disp8(Reg::Rax, sib, 3));
+    write(code, shr_r32_by_imm8(Reg::Rax, 8));
+    write(code, mov_r8_to_sib8_disp8(Reg::Rax, sib, 2));
+    write(code, sub_r8_from_sib8_disp8(Reg::Rax, sib, 1));
+    write(code, mov_imm8_to_sib8(sib, 0));
+
+    let end = code.len();
+    for pos in skip_jumps {
+        code[pos] = (end - (pos + 1)) as u8;
+    }
+}
+
+/// Write the value of the register at displacement `disp` to stdout as decimal number, the digits
+/// are generated into a buffer on the stack, and written using a single system call, or appended
+/// to the output buffer if `calls` is passed.
+fn write_print_decimal(code: &mut Vec<u8>, disp: i16, calls: Option<&mut Calls>) {
+    write_register_address(code, Reg::Rsi, disp as i32);
+    write(code, xor_r64_r64(Reg::Rax, Reg::Rax));
+    write(code, mov_m8_to_r8(RmI::RegRsi, Reg::Rax));
+
+    // the digits are written backwards from the end of the buffer
+    write(code, push_r64(Reg::Rcx));
+    write(code, sub_imm32_from_r64(Reg::Rsp, 8));
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rsi));
+    write(code, add_imm8_to_r64(Reg::Rsi, 8));
+    write_decimal_digits(code);
+
+    // the length of the digits is `rsp + 8 - rsi`
+    write(code, mov_r64_to_r64(Reg::Rsp, Reg::Rdx));
+    write(code, add_imm8_to_r64(Reg::Rdx, 8));
+    write(code, sub_r64_from_r64(Reg::Rsi, Reg::Rdx));
+
+    if let Some(calls) = calls {
+        calls.write(code, Routine::Append);
+    } else {
+        const SYSCALL_WRITE: i32 = 1;
+        write(code, mov_imm32_to_r64(Reg::Rax, SYSCALL_WRITE));
+        const STDOUT_FD: i32 = 1;
+        write(code, mov_imm32_to_r64(Reg::Rdi, STDOUT_FD));
+        write(code, SYSCALL);
+    }
+
+    write(code, add_imm32_to_r64(Reg::Rsp, 8));
+    write(code, pop_r64(Reg::Rcx));
+}
+
+/// Write the decimal digits of `eax` backwards into the buffer ending at `rsi`, which is left
+/// pointing to the first digit. `rcx` is used as divisor.
+fn write_decimal_digits(code: &mut Vec<u8>) {
+    write(code, mov_imm32_to_r64(Reg::Rcx, 10));
+    let loop_start = code.len();
+    write(code, add_imm8_to_r64(Reg::Rsi, -1));
+    write(code, xor_r64_r64(Reg::Rdx, Reg::Rdx));
+    write(code, div_edx_eax_by_r32(Reg::Rcx));
+    write(code, add_imm8_to_r8(Reg::Rdx, b'0'));
+    write(code, mov_r8_to_m8(Reg::Rdx, RmI::RegRsi));
+    write(code, cmp_r32_with_imm8(Reg::Rax, 0x00));
+    let loop_end = code.len() + const { jnz_rel8(0).len() };
+    write(
+        code,
+        jnz_rel8((loop_start as isize - loop_end as isize) as i8),
+    );
+}
+
+/// Multiply the value at `sib` by `n` and store the lower 8-bits of the result in `al`, using
+/// shifts or `lea` when possible instead of the slower `mul`. The code runs in 64-bit and 32-bit
+/// mode.
+pub fn write_mul_sib8_to_al(code: &mut Vec<u8>, sib: Sib, n: u8) {
+    match n {
+        _ if n.is_power_of_two() => {
+            write(code, mov_sib8_to_r8(sib, Reg::Rax));
+            let shift = n.trailing_zeros() as u8;
+            if shift > 0 {
+                write(code, shl_r8_by_imm8(Reg::Rax, shift));
+            }
+        }
+        // the upper bits of `eax` are garbage, but they don't affect the lower 8 bits of the result
+        3 | 5 | 9 => {
+            write(code, mov_sib8_to_r8(sib, Reg::Rax));
+            let scale = match n {
+                3 => Scale::B2,
+                5 => Scale::B4,
+                _ => Scale::B8,
+            };
+            write(
+                code,
+                lea_sib_to_r32(Sib::new(scale, Reg::Rax, Reg::Rax), Reg::Rax),
+            );
+        }
+        _ => {
+            write(code, mov_imm8_to_r8(Reg::Rax, n));
+            write(code, mul_al_with_sib8(sib));
+        }
+    }
+}
+
+pub fn write<const SIZE: usize>(code: &mut Vec<u8>, instruction: [u8; SIZE]) {
+    code.extend_from_slice(&instruction);
+}
+
+/// prefix for some 64-bit instructions
+const REXW: u8 = 0x48;
+/// prefix for some 64-bit instructions using an extended register in the r/m field
+const REXWB: u8 = 0x49;
+/// prefix for some 64-bit instructions using an extended register in the reg field
+const REXWR: u8 = 0x4C;
+
+// ========================================
+//                   ADD
+// ========================================
+
+// 8-bit
+
+/// `00 /r` : `ADD r/m8 r8` : add r8 to r/m8
+pub const fn add_r8_to_r8(src: Reg, dest: Reg) -> [u8; 2] {
+    let modrm = modrm_reg(ModRm::Register(dest), src);
+    [0x00, modrm]
+}
+/// `00 /r` : `ADD r/m8 r8` : add r8 to r/m8
+pub const fn add_r8_to_sib8(src: Reg, dest: Sib) -> [u8; 3] {
+    let modrm = modrm_reg(ModRm::Indirect(RmI::Sib), src);
+    [0x00, modrm, dest.sib()]
+}
+/// `00 /r` : `ADD r/m8 r8` : add r8 to r/m8
+pub const fn add_r8_to_sib8_disp8(src: Reg, dest: Sib, disp: i8) -> [u8; 4] {
+    let modrm = modrm_reg(ModRm::IndirectDisp8(RmID::Sib), src);
+    let [disp] = i8::to_le_bytes(disp);
+    [0x00, modrm, dest.sib(), disp]
+}
+/// `00 /r` : `ADD r/m8 r8` : add r8 to r/m8
+pub const fn add_r8_to_sib8_disp32(src: Reg, dest: Sib, disp: i32) -> [u8; 7] {
+    let modrm = modrm_reg(ModRm::IndirectDisp32(RmID::Sib), src);
     let [b0, b1, b2, b3] = i32::to_le_bytes(disp);
     [0x00, modrm, dest.sib(), b0, b1, b2, b3]
 }
@@ -557,6 +2611,12 @@ pub const fn add_imm32_to_r32(dest: Reg, id: i32) -> [u8; 6] {
     [0x81, modrm, b0, b1, b2, b3]
 }
 
+/// `01 /r` : `ADD r/m32 r32` : add r32 to r/m32
+pub const fn add_r32_to_r32(src: Reg, dest: Reg) -> [u8; 2] {
+    let modrm = modrm_reg(ModRm::Register(dest), src);
+    [0x01, modrm]
+}
+
 // 64-bit
 
 /// `REX.W 01 /r` : `ADD r/m64 r64` : add r64 to r/m64
@@ -565,6 +2625,13 @@ pub const fn add_r64_to_r64(src: Reg, dest: Reg) -> [u8; 3] {
     [REXW, 0x01, modrm]
 }
 
+/// `REX.W 83 /0 ib` : `ADD r/m64 imm8` : add imm8 sign extended to 64-bits to r/m64
+pub const fn add_imm8_to_r64(dest: Reg, ib: i8) -> [u8; 4] {
+    let modrm = modrm_ext(ModRm::Register(dest), 0);
+    let [ib] = i8::to_le_bytes(ib);
+    [REXW, 0x83, modrm, ib]
+}
+
 /// `REX.W 81 /0 id` : `ADD r/m64 imm32` : add imm32 sign extended to 64-bits to r/m64
 pub const fn add_imm32_to_r64(dest: Reg, id: i32) -> [u8; 7] {
     let modrm = modrm_ext(ModRm::Register(dest), 0);
@@ -640,14 +2707,32 @@ pub const fn sub_imm32_from_r32(dest: Reg, id: i32) -> [u8; 6] {
     [0x81, modrm, b0, b1, b2, b3]
 }
 
+/// `29 /r` : `SUB r/m32 r32` : subtract r32 from r/m32
+pub const fn sub_r32_from_r32(src: Reg, dest: Reg) -> [u8; 2] {
+    let modrm = modrm_reg(ModRm::Register(dest), src);
+    [0x29, modrm]
+}
+
 // 64-bit
 
+/// `REX.W 29 /r` : `SUB r/m64 r64` : subtract r64 from r/m64
+pub const fn sub_r64_from_r64(src: Reg, dest: Reg) -> [u8; 3] {
+    let modrm = modrm_reg(ModRm::Register(dest), src);
+    [REXW, 0x29, modrm]
+}
+
 /// `REX.W 81 /5 id` : `SUB r/m64 imm32` : subtract imm32 sign extended to 64-bits from r/m64
 pub const fn sub_imm32_from_r64(dest: Reg, id: i32) -> [u8; 7] {
     let modrm = modrm_ext(ModRm::Register(dest), 5);
     let [b0, b1, b2, b3] = i32::to_le_bytes(id);
     [REXW, 0x81, modrm, b0, b1, b2, b3]
 }
+/// `REX.W + REX.B 83 /5 ib` : `SUB r/m64 imm8` : subtract imm8 sign extended to 64-bits from r/m64
+pub const fn sub_imm8_from_r64_ext(dest: RegExt, ib: i8) -> [u8; 4] {
+    let modrm = (0b11 << 6) | (5 << 3) | dest as u8;
+    let [ib] = i8::to_le_bytes(ib);
+    [REXWB, 0x83, modrm, ib]
+}
 
 // ========================================
 //                   MOV
@@ -661,6 +2746,18 @@ pub const fn mov_sib8_to_r8(src: Sib, dest: Reg) -> [u8; 3] {
     [0x8A, modrm, src.sib()]
 }
 
+/// `8A /r`: `MOV r8 r/m8` : move r/m8 to r8
+pub const fn mov_m8_to_r8(src: RmI, dest: Reg) -> [u8; 2] {
+    let modrm = modrm_reg(ModRm::Indirect(src), dest);
+    [0x8A, modrm]
+}
+
+/// `88 /r`: `MOV r/m8 r8` : move r8 to r/m8
+pub const fn mov_r8_to_m8(src: Reg, dest: RmI) -> [u8; 2] {
+    let modrm = modrm_reg(ModRm::Indirect(dest), src);
+    [0x88, modrm]
+}
+
 /// `C6 /0 ib` : `MOV r/m8 imm8` : move imm8 to r/m8
 pub const fn mov_imm8_to_r8(dest: Reg, ib: u8) -> [u8; 3] {
     let modrm = modrm_ext(ModRm::Register(dest), 0);
@@ -684,6 +2781,41 @@ pub const fn mov_imm8_to_sib8_disp32(sib: Sib, disp: i32, ib: u8) -> [u8; 8] {
     [0xC6, modrm, sib.sib(), b0, b1, b2, b3, ib]
 }
 
+/// `C6 /0 ib` : `MOV r/m8 imm8` : move imm8 to r/m8
+pub const fn mov_imm8_to_m8_disp8(dest: RmID, disp: i8, ib: u8) -> [u8; 4] {
+    let modrm = modrm_ext(ModRm::IndirectDisp8(dest), 0);
+    let [disp] = i8::to_le_bytes(disp);
+    [0xC6, modrm, disp, ib]
+}
+
+// 32-bit
+
+/// `8B /r` : `MOV r32 r/m32` : move r/m32 to r32
+pub const fn mov_m32_disp8_to_r32(src: RmID, disp: i8, dest: Reg) -> [u8; 3] {
+    let modrm = modrm_reg(ModRm::IndirectDisp8(src), dest);
+    let [disp] = i8::to_le_bytes(disp);
+    [0x8B, modrm, disp]
+}
+
+/// `89 /r` : `MOV r/m32 r32` : move r32 to r/m32
+pub const fn mov_r32_to_r32(src: Reg, dest: Reg) -> [u8; 2] {
+    let modrm = modrm_reg(ModRm::Register(dest), src);
+    [0x89, modrm]
+}
+
+/// `89 /r` : `MOV r/m32 r32` : move r32 to r/m32
+pub const fn mov_r32_to_m32_disp8(src: Reg, dest: RmID, disp: i8) -> [u8; 3] {
+    let modrm = modrm_reg(ModRm::IndirectDisp8(dest), src);
+    let [disp] = i8::to_le_bytes(disp);
+    [0x89, modrm, disp]
+}
+
+/// `B8 +rd id` : `MOV r32 imm32` : move imm32 to r32
+pub const fn mov_imm32_to_r32(dest: Reg, id: i32) -> [u8; 5] {
+    let [b0, b1, b2, b3] = i32::to_le_bytes(id);
+    [0xB8 + dest as u8, b0, b1, b2, b3]
+}
+
 // 64-bit
 
 /// `REX.W 89 /r` : `MOV r/m64 r64` : move r64 to r/m64
@@ -692,12 +2824,50 @@ pub const fn mov_r64_to_r64(src: Reg, dest: Reg) -> [u8; 3] {
     [REXW, 0x89, modrm]
 }
 
+/// `REX.W + REX.B 89 /r` : `MOV r/m64 r64` : move r64 to r/m64
+pub const fn mov_r64_to_r64_ext(src: Reg, dest: RegExt) -> [u8; 3] {
+    let modrm = (0b11 << 6) | ((src as u8) << 3) | dest as u8;
+    [REXWB, 0x89, modrm]
+}
+/// `REX.W + REX.R 89 /r` : `MOV r/m64 r64` : move r64 to r/m64
+pub const fn mov_r64_ext_to_r64(src: RegExt, dest: Reg) -> [u8; 3] {
+    let modrm = modrm_reg(ModRm::Register(dest), Reg::Rax) | ((src as u8) << 3);
+    [REXWR, 0x89, modrm]
+}
+
+/// `REX.W 89 /r` : `MOV r/m64 r64` : move r64 to r/m64
+pub const fn mov_r64_to_m64_disp32(src: Reg, dest: RmID, disp: i32) -> [u8; 7] {
+    let modrm = modrm_reg(ModRm::IndirectDisp32(dest), src);
+    let [b0, b1, b2, b3] = i32::to_le_bytes(disp);
+    [REXW, 0x89, modrm, b0, b1, b2, b3]
+}
+
+/// `REX.W 8B /r` : `MOV r64 r/m64` : move r/m64 to r64
+pub const fn mov_m64_disp32_to_r64(src: RmID, disp: i32, dest: Reg) -> [u8; 7] {
+    let modrm = modrm_reg(ModRm::IndirectDisp32(src), dest);
+    let [b0, b1, b2, b3] = i32::to_le_bytes(disp);
+    [REXW, 0x8B, modrm, b0, b1, b2, b3]
+}
+
 /// `REX.W C7 /0 id` : `MOV r/m64 imm32` : move imm32 sign extended to 64-bits to r/m64
 pub const fn mov_imm32_to_r64(dest: Reg, id: i32) -> [u8; 7] {
     let modrm = modrm_ext(ModRm::Register(dest), 0);
     let [b0, b1, b2, b3] = i32::to_le_bytes(id);
     [REXW, 0xC7, modrm, b0, b1, b2, b3]
 }
+/// `REX.W + REX.B C7 /0 id` : `MOV r/m64 imm32` : move imm32 sign extended to 64-bits to r/m64
+pub const fn mov_imm32_to_r64_ext(dest: RegExt, id: i32) -> [u8; 7] {
+    let modrm = (0b11 << 6) | dest as u8;
+    let [b0, b1, b2, b3] = i32::to_le_bytes(id);
+    [REXWB, 0xC7, modrm, b0, b1, b2, b3]
+}
+/// `REX.W C7 /0 id` : `MOV r/m64 imm32` : move imm32 sign extended to 64-bits to r/m64
+pub const fn mov_imm32_to_m64_disp32(dest: RmID, disp: i32, id: i32) -> [u8; 11] {
+    let modrm = modrm_ext(ModRm::IndirectDisp32(dest), 0);
+    let [d0, d1, d2, d3] = i32::to_le_bytes(disp);
+    let [b0, b1, b2, b3] = i32::to_le_bytes(id);
+    [REXW, 0xC7, modrm, d0, d1, d2, d3, b0, b1, b2, b3]
+}
 /// `REX.W C7 /0 id` : `MOV r/m64 imm32` : move imm32 sign extended to 64-bits to r/m64
 pub const fn mov_imm32_to_sib64(dest: Sib, id: i32) -> [u8; 8] {
     let modrm = modrm_ext(ModRm::Indirect(RmI::Sib), 0);
@@ -705,6 +2875,98 @@ pub const fn mov_imm32_to_sib64(dest: Sib, id: i32) -> [u8; 8] {
     [REXW, 0xC7, modrm, dest.sib(), b0, b1, b2, b3]
 }
 
+// ========================================
+//                   SHL
+// ========================================
+
+// 8-bit
+
+/// `88 /r`: `MOV r/m8 r8` : move r8 to r/m8
+pub const fn mov_r8_to_sib8_disp8(src: Reg, dest: Sib, disp: i8) -> [u8; 4] {
+    let modrm = modrm_reg(ModRm::IndirectDisp8(RmID::Sib), src);
+    let [disp] = i8::to_le_bytes(disp);
+    [0x88, modrm, dest.sib(), disp]
+}
+
+/// `C0 /4 ib` : `SHL r/m8 imm8` : multiply r/m8 by 2, imm8 times
+pub const fn shl_r8_by_imm8(dest: Reg, ib: u8) -> [u8; 3] {
+    let modrm = modrm_ext(ModRm::Register(dest), 4);
+    [0xC0, modrm, ib]
+}
+
+const_assert!(matches!(shl_r8_by_imm8(Reg::Rax, 3), [0xC0, 0xE0, 0x03]));
+
+// ========================================
+//                   AND
+// ========================================
+
+// 8-bit
+
+/// `80 /4 ib` : `AND r/m8 imm8` : r/m8 AND imm8
+pub const fn and_imm8_to_m8_disp8(dest: RmID, disp: i8, ib: u8) -> [u8; 4] {
+    let modrm = modrm_ext(ModRm::IndirectDisp8(dest), 4);
+    let [disp] = i8::to_le_bytes(disp);
+    [0x80, modrm, disp, ib]
+}
+
+// `and byte [rdx + 12], 0xFD`
+const_assert!(matches!(
+    and_imm8_to_m8_disp8(RmID::RegRdx, 12, 0xFD),
+    [0x80, 0x62, 0x0C, 0xFD]
+));
+// `mov byte [rdx + 23], 1`
+const_assert!(matches!(
+    mov_imm8_to_m8_disp8(RmID::RegRdx, 23, 1),
+    [0xC6, 0x42, 0x17, 0x01]
+));
+
+// ========================================
+//                   SHR
+// ========================================
+
+// 32-bit
+
+/// `C1 /5 ib` : `SHR r/m32 imm8` : unsigned divide r/m32 by 2, imm8 times
+pub const fn shr_r32_by_imm8(dest: Reg, ib: u8) -> [u8; 3] {
+    let modrm = modrm_ext(ModRm::Register(dest), 5);
+    [0xC1, modrm, ib]
+}
+
+const_assert!(matches!(shr_r32_by_imm8(Reg::Rax, 8), [0xC1, 0xE8, 0x08]));
+
+// ========================================
+//                   LEA
+// ========================================
+
+// 32-bit
+
+/// `8D /r` : `LEA r32 m` : store effective address for m in r32
+pub const fn lea_sib_to_r32(src: Sib, dest: Reg) -> [u8; 3] {
+    let modrm = modrm_reg(ModRm::Indirect(RmI::Sib), dest);
+    [0x8D, modrm, src.sib()]
+}
+
+// `lea eax, [rax + 2 * rax]`
+const_assert!(matches!(
+    lea_sib_to_r32(Sib::new(Scale::B2, Reg::Rax, Reg::Rax), Reg::Rax),
+    [0x8D, 0x04, 0x40]
+));
+// `lea eax, [rax + 8 * rax]`
+const_assert!(matches!(
+    lea_sib_to_r32(Sib::new(Scale::B8, Reg::Rax, Reg::Rax), Reg::Rax),
+    [0x8D, 0x04, 0xC0]
+));
+
+// 64-bit
+
+/// `REX.W 8D /r` : `LEA r64 m` : store effective address for m in r64, using the instruction
+/// pointer relative displacement
+pub const fn lea_rip_disp32_to_r64(disp: i32, dest: Reg) -> [u8; 7] {
+    let modrm = modrm_reg(ModRm::Indirect(RmI::Disp32), dest);
+    let [b0, b1, b2, b3] = i32::to_le_bytes(disp);
+    [REXW, 0x8D, modrm, b0, b1, b2, b3]
+}
+
 // ========================================
 //                   MISC
 // ========================================
@@ -715,6 +2977,28 @@ pub const fn mul_al_with_sib8(src: Sib) -> [u8; 3] {
     [0xF6, modrm, src.sib()]
 }
 
+/// `F6 /6`: `DIV r/m8` : unsigned divide ax by r/m8, with the quotient in al and the remainder
+/// in ah
+pub const fn div_ax_by_sib8_disp8(src: Sib, disp: i8) -> [u8; 4] {
+    let modrm = modrm_ext(ModRm::IndirectDisp8(RmID::Sib), 6);
+    let [disp] = i8::to_le_bytes(disp);
+    [0xF6, modrm, src.sib(), disp]
+}
+
+/// `F7 /6`: `DIV r/m32` : unsigned divide edx:eax by r/m32, with the quotient in eax and the
+/// remainder in edx
+pub const fn div_edx_eax_by_r32(src: Reg) -> [u8; 2] {
+    let modrm = modrm_ext(ModRm::Register(src), 6);
+    [0xF7, modrm]
+}
+
+/// `31 /r`: `XOR r/m32 r32` : performs r/m32 xor r32 into r/m32, in 64-bit mode the upper 32
+/// bits of the destination are cleared
+pub const fn xor_r32_r32(src: Reg, dest: Reg) -> [u8; 2] {
+    let modrm = modrm_reg(ModRm::Register(dest), src);
+    [0x31, modrm]
+}
+
 /// `31 /r`: `XOR r/m64 r64` : performs r/m64 xor r64 into r/m64
 pub const fn xor_r64_r64(src: Reg, dest: Reg) -> [u8; 3] {
     let modrm = modrm_reg(ModRm::Register(dest), src);
@@ -727,6 +3011,25 @@ pub const fn cmp_sib8_with_imm8(src: Sib, ib: u8) -> [u8; 4] {
     [0x80, modrm, src.sib(), ib]
 }
 
+/// `80 /7 ib` : `CMP r/m8 imm8` : compare r/m8 with imm8
+pub const fn cmp_sib8_disp8_with_imm8(src: Sib, disp: i8, ib: u8) -> [u8; 5] {
+    let modrm = const { modrm_ext(ModRm::IndirectDisp8(RmID::Sib), 7) };
+    let [disp] = i8::to_le_bytes(disp);
+    [0x80, modrm, src.sib(), disp, ib]
+}
+
+/// `80 /7 ib` : `CMP r/m8 imm8` : compare r/m8 with imm8
+pub const fn cmp_r8_with_imm8(src: Reg, ib: u8) -> [u8; 3] {
+    let modrm = modrm_ext(ModRm::Register(src), 7);
+    [0x80, modrm, ib]
+}
+
+/// `F6 /0 ib` : `TEST r/m8 imm8` : and imm8 with r/m8, and set the flags according to the result
+pub const fn test_r8_with_imm8(src: Reg, ib: u8) -> [u8; 3] {
+    let modrm = modrm_ext(ModRm::Register(src), 0);
+    [0xF6, modrm, ib]
+}
+
 /// `83 /7 ib` : `CMP r/m32 imm8` : compare r/m32 with imm8
 pub const fn cmp_r32_with_imm8(src: Reg, ib: i8) -> [u8; 3] {
     let modrm = modrm_ext(ModRm::Register(src), 7);
@@ -734,6 +3037,93 @@ pub const fn cmp_r32_with_imm8(src: Reg, ib: i8) -> [u8; 3] {
     [0x83, modrm, ib]
 }
 
+/// `39 /r` : `CMP r/m32 r32` : compare r/m32 with r32
+pub const fn cmp_m32_with_r32(src: RmI, reg: Reg) -> [u8; 2] {
+    let modrm = modrm_reg(ModRm::Indirect(src), reg);
+    [0x39, modrm]
+}
+
+/// `81 /7 id` : `CMP r/m32 imm32` : compare r/m32 with imm32
+pub const fn cmp_r32_with_imm32(src: Reg, id: i32) -> [u8; 6] {
+    let modrm = modrm_ext(ModRm::Register(src), 7);
+    let [b0, b1, b2, b3] = i32::to_le_bytes(id);
+    [0x81, modrm, b0, b1, b2, b3]
+}
+
+/// `REX.W 83 /7 ib` : `CMP r/m64 imm8` : compare r/m64 with imm8 sign extended to 64-bits
+pub const fn cmp_r64_with_imm8(src: Reg, ib: i8) -> [u8; 4] {
+    let modrm = modrm_ext(ModRm::Register(src), 7);
+    let [ib] = i8::to_le_bytes(ib);
+    [REXW, 0x83, modrm, ib]
+}
+
+/// `REX.W 81 /7 id` : `CMP r/m64 imm32` : compare r/m64 with imm32 sign extended to 64-bits
+pub const fn cmp_r64_with_imm32(src: Reg, id: i32) -> [u8; 7] {
+    let modrm = modrm_ext(ModRm::Register(src), 7);
+    let [b0, b1, b2, b3] = i32::to_le_bytes(id);
+    [REXW, 0x81, modrm, b0, b1, b2, b3]
+}
+
+/// `REX.W 39 /r` : `CMP r/m64 r64` : compare r/m64 with r64
+pub const fn cmp_r64_with_r64(src: Reg, reg: Reg) -> [u8; 3] {
+    let modrm = modrm_reg(ModRm::Register(src), reg);
+    [REXW, 0x39, modrm]
+}
+
+/// `79 cb` : `JNS rel8` : jump rel8 if not sign
+pub const fn jns_rel8(cb: i8) -> [u8; 2] {
+    let [cb] = i8::to_le_bytes(cb);
+    [0x79, cb]
+}
+
+/// `72 cb` : `JB rel8` : jump rel8 if below (unsigned)
+pub const fn jb_rel8(cb: i8) -> [u8; 2] {
+    let [cb] = i8::to_le_bytes(cb);
+    [0x72, cb]
+}
+
+/// `0F 88 cd` : `JS rel32` : jump rel32 if sign
+pub const fn js_rel32(cd: i32) -> [u8; 6] {
+    let [b0, b1, b2, b3] = i32::to_le_bytes(cd);
+    [0x0F, 0x88, b0, b1, b2, b3]
+}
+
+/// `0F 82 cd` : `JB rel32` : jump rel32 if below (unsigned)
+pub const fn jb_rel32(cd: i32) -> [u8; 6] {
+    let [b0, b1, b2, b3] = i32::to_le_bytes(cd);
+    [0x0F, 0x82, b0, b1, b2, b3]
+}
+
+/// `76 cb` : `JBE rel8` : jump rel8 if below or equal (unsigned)
+pub const fn jbe_rel8(cb: i8) -> [u8; 2] {
+    let [cb] = i8::to_le_bytes(cb);
+    [0x76, cb]
+}
+
+/// `7E cb` : `JLE rel8` : jump rel8 if less or equal (signed)
+pub const fn jle_rel8(cb: i8) -> [u8; 2] {
+    let [cb] = i8::to_le_bytes(cb);
+    [0x7E, cb]
+}
+
+/// `EB cb` : `JMP rel8` : jump rel8
+pub const fn jmp_rel8(cb: i8) -> [u8; 2] {
+    let [cb] = i8::to_le_bytes(cb);
+    [0xEB, cb]
+}
+
+/// `E9 cd` : `JMP rel32` : jump rel32
+pub const fn jmp_rel32(cd: i32) -> [u8; 5] {
+    let [b0, b1, b2, b3] = i32::to_le_bytes(cd);
+    [0xE9, b0, b1, b2, b3]
+}
+
+/// `FF /4` : `JMP r/m64` : jump near, absolute indirect, address given in r/m64
+pub const fn jmp_r64(src: Reg) -> [u8; 2] {
+    let modrm = modrm_ext(ModRm::Register(src), 4);
+    [0xFF, modrm]
+}
+
 /// `74 cb` : `JZ rel8` : jump rel8 if zero
 pub const fn jz_rel8(cb: i8) -> [u8; 2] {
     let [cb] = i8::to_le_bytes(cb);
@@ -770,5 +3160,664 @@ pub const fn pop_r64(dest: Reg) -> [u8; 2] {
     [0x8F, modrm]
 }
 
+/// `FF /6`: `PUSH r32` : push r32 onto the stack, in 32-bit mode
+pub const fn push_r32(src: Reg) -> [u8; 2] {
+    push_r64(src)
+}
+
+/// `8F /0`: `POP r32` : pop r32 off the stack, in 32-bit mode
+pub const fn pop_r32(dest: Reg) -> [u8; 2] {
+    pop_r64(dest)
+}
+
+/// `E8 cd`: `CALL rel32` : call near, relative
+pub const fn call_rel32(cd: i32) -> [u8; 5] {
+    let [b0, b1, b2, b3] = i32::to_le_bytes(cd);
+    [0xE8, b0, b1, b2, b3]
+}
+
+/// `FF /2`: `CALL r/m64` : call near, absolute indirect, address given in r/m64
+pub const fn call_rm64_disp8(src: RmID, disp: i8) -> [u8; 3] {
+    let modrm = modrm_ext(ModRm::IndirectDisp8(src), 2);
+    let [disp] = i8::to_le_bytes(disp);
+    [0xFF, modrm, disp]
+}
+
+/// `C3`: `RET` : near return to calling procedure
+pub const RET: [u8; 1] = [0xC3];
+
 /// `0F 05`: `SYSCALL` : fast system call
 pub const SYSCALL: [u8; 2] = [0x0F, 0x05];
+
+/// `CD 80`: `INT 0x80` : system call of 32-bit linux
+pub const INT_0X80: [u8; 2] = [0xCD, 0x80];
+
+/// `EC`: `IN AL, DX` : input a byte from the i/o port in `dx` into `al`
+pub const IN_AL_DX: [u8; 1] = [0xEC];
+
+/// `EE`: `OUT DX, AL` : output the byte in `al` to the i/o port in `dx`
+pub const OUT_DX_AL: [u8; 1] = [0xEE];
+
+/// `FA`: `CLI` : clear the interrupt flag, external interrupts are ignored
+pub const CLI: [u8; 1] = [0xFA];
+
+/// `F4`: `HLT` : halt the processor until the next interrupt
+pub const HLT: [u8; 1] = [0xF4];
+
+/// `F3 A4`: `REP MOVSB` : move `rcx` bytes from `[rsi]` to `[rdi]`
+pub const REP_MOVSB: [u8; 2] = [0xF3, 0xA4];
+
+/// `F3 AA`: `REP STOSB` : fill `rcx` bytes at `[rdi]` with `al`
+pub const REP_STOSB: [u8; 2] = [0xF3, 0xAA];
+
+/// `FD`: `STD` : set the direction flag, string instructions decrement `rsi` and `rdi`
+pub const STD: [u8; 1] = [0xFD];
+
+/// `FC`: `CLD` : clear the direction flag, string instructions increment `rsi` and `rdi`
+pub const CLD: [u8; 1] = [0xFC];
+
+// ========================================
+//             ENCODING CHECKS
+// ========================================
+
+// Byte patterns of the instructions emitted by the code generator, cross-checked with the
+// GNU assembler. `SIB` is `[rsp + rcx]`, the address of the current brainfuck register.
+const _: () = {
+    const SIB: Sib = Sib::new(Scale::B1, Reg::Rcx, Reg::Rsp);
+
+    // add byte [rsp + rcx], 5
+    const_assert!(matches!(add_imm8_to_sib8(SIB, 5), [0x80, 0x04, 0x0C, 0x05]));
+    // add byte [rsp + rcx + 3], 5
+    const_assert!(matches!(
+        add_imm8_to_sib8_disp8(SIB, 3, 5),
+        [0x80, 0x44, 0x0C, 0x03, 0x05]
+    ));
+    // add byte [rsp + rcx + 300], 5
+    const_assert!(matches!(
+        add_imm8_to_sib8_disp32(SIB, 300, 5),
+        [0x80, 0x84, 0x0C, 0x2C, 0x01, 0x00, 0x00, 0x05]
+    ));
+    // sub byte [rsp + rcx], 5
+    const_assert!(matches!(
+        sub_imm8_from_sib8(SIB, 5),
+        [0x80, 0x2C, 0x0C, 0x05]
+    ));
+    // sub byte [rsp + rcx - 3], 5
+    const_assert!(matches!(
+        sub_imm8_from_sib8_disp8(SIB, -3, 5),
+        [0x80, 0x6C, 0x0C, 0xFD, 0x05]
+    ));
+    // sub byte [rsp + rcx - 300], 5
+    const_assert!(matches!(
+        sub_imm8_from_sib8_disp32(SIB, -300, 5),
+        [0x80, 0xAC, 0x0C, 0xD4, 0xFE, 0xFF, 0xFF, 0x05]
+    ));
+    // mov byte [rsp + rcx], 7
+    const_assert!(matches!(mov_imm8_to_sib8(SIB, 7), [0xC6, 0x04, 0x0C, 0x07]));
+    // mov byte [rsp + rcx + 3], 7
+    const_assert!(matches!(
+        mov_imm8_to_sib8_disp8(SIB, 3, 7),
+        [0xC6, 0x44, 0x0C, 0x03, 0x07]
+    ));
+    // mov byte [rsp + rcx + 300], 7
+    const_assert!(matches!(
+        mov_imm8_to_sib8_disp32(SIB, 300, 7),
+        [0xC6, 0x84, 0x0C, 0x2C, 0x01, 0x00, 0x00, 0x07]
+    ));
+
+    // add byte [rsp + rcx], al
+    const_assert!(matches!(add_r8_to_sib8(Reg::Rax, SIB), [0x00, 0x04, 0x0C]));
+    // add byte [rsp + rcx - 3], al
+    const_assert!(matches!(
+        add_r8_to_sib8_disp8(Reg::Rax, SIB, -3),
+        [0x00, 0x44, 0x0C, 0xFD]
+    ));
+    // add byte [rsp + rcx + 300], al
+    const_assert!(matches!(
+        add_r8_to_sib8_disp32(Reg::Rax, SIB, 300),
+        [0x00, 0x84, 0x0C, 0x2C, 0x01, 0x00, 0x00]
+    ));
+    // sub byte [rsp + rcx], al
+    const_assert!(matches!(
+        sub_r8_from_sib8(Reg::Rax, SIB),
+        [0x28, 0x04, 0x0C]
+    ));
+    // sub byte [rsp + rcx - 3], al
+    const_assert!(matches!(
+        sub_r8_from_sib8_disp8(Reg::Rax, SIB, -3),
+        [0x28, 0x44, 0x0C, 0xFD]
+    ));
+    // sub byte [rsp + rcx + 300], al
+    const_assert!(matches!(
+        sub_r8_from_sib8_disp32(Reg::Rax, SIB, 300),
+        [0x28, 0x84, 0x0C, 0x2C, 0x01, 0x00, 0x00]
+    ));
+    // mov al, byte [rsp + rcx]
+    const_assert!(matches!(mov_sib8_to_r8(SIB, Reg::Rax), [0x8A, 0x04, 0x0C]));
+    // mov al, 7 (alternative encoding of `B0 07`)
+    const_assert!(matches!(mov_imm8_to_r8(Reg::Rax, 7), [0xC6, 0xC0, 0x07]));
+    // mul byte [rsp + rcx]
+    const_assert!(matches!(mul_al_with_sib8(SIB), [0xF6, 0x24, 0x0C]));
+    // cmp byte [rsp + rcx], 0
+    const_assert!(matches!(
+        cmp_sib8_with_imm8(SIB, 0),
+        [0x80, 0x3C, 0x0C, 0x00]
+    ));
+    // cmp byte [rsp + rcx + 1], 2
+    const_assert!(matches!(
+        cmp_sib8_disp8_with_imm8(SIB, 1, 2),
+        [0x80, 0x7C, 0x0C, 0x01, 0x02]
+    ));
+    // div byte [rsp + rcx + 1]
+    const_assert!(matches!(
+        div_ax_by_sib8_disp8(SIB, 1),
+        [0xF6, 0x74, 0x0C, 0x01]
+    ));
+    // mov al, byte [rsi]
+    const_assert!(matches!(mov_m8_to_r8(RmI::RegRsi, Reg::Rax), [0x8A, 0x06]));
+    // mov byte [rsi], dl
+    const_assert!(matches!(mov_r8_to_m8(Reg::Rdx, RmI::RegRsi), [0x88, 0x16]));
+    // add dl, 0x30
+    const_assert!(matches!(add_imm8_to_r8(Reg::Rdx, 0x30), [0x80, 0xC2, 0x30]));
+    // div ecx
+    const_assert!(matches!(div_edx_eax_by_r32(Reg::Rcx), [0xF7, 0xF1]));
+    // sub rdx, rsi
+    const_assert!(matches!(
+        sub_r64_from_r64(Reg::Rsi, Reg::Rdx),
+        [0x48, 0x29, 0xF2]
+    ));
+    // mov byte [rsp + rcx + 2], al
+    const_assert!(matches!(
+        mov_r8_to_sib8_disp8(Reg::Rax, SIB, 2),
+        [0x88, 0x44, 0x0C, 0x02]
+    ));
+
+    // add ecx, 3
+    const_assert!(matches!(add_imm8_to_r32(Reg::Rcx, 3), [0x83, 0xC1, 0x03]));
+    // add ecx, 300
+    const_assert!(matches!(
+        add_imm32_to_r32(Reg::Rcx, 300),
+        [0x81, 0xC1, 0x2C, 0x01, 0x00, 0x00]
+    ));
+    // sub ecx, 3
+    const_assert!(matches!(sub_imm8_from_r32(Reg::Rcx, 3), [0x83, 0xE9, 0x03]));
+    // sub ecx, 300
+    const_assert!(matches!(
+        sub_imm32_from_r32(Reg::Rcx, 300),
+        [0x81, 0xE9, 0x2C, 0x01, 0x00, 0x00]
+    ));
+    // cmp ecx, 0
+    const_assert!(matches!(cmp_r32_with_imm8(Reg::Rcx, 0), [0x83, 0xF9, 0x00]));
+
+    // add rsp, 0x8000
+    const_assert!(matches!(
+        add_imm32_to_r64(Reg::Rsp, 0x8000),
+        [0x48, 0x81, 0xC4, 0x00, 0x80, 0x00, 0x00]
+    ));
+    // sub rsp, 0x8000
+    const_assert!(matches!(
+        sub_imm32_from_r64(Reg::Rsp, 0x8000),
+        [0x48, 0x81, 0xEC, 0x00, 0x80, 0x00, 0x00]
+    ));
+    // mov rax, 60
+    const_assert!(matches!(
+        mov_imm32_to_r64(Reg::Rax, 60),
+        [0x48, 0xC7, 0xC0, 0x3C, 0x00, 0x00, 0x00]
+    ));
+    // mov qword [rsp + 8 * rcx], 0
+    const_assert!(matches!(
+        mov_imm32_to_sib64(Sib::new(Scale::B8, Reg::Rcx, Reg::Rsp), 0),
+        [0x48, 0xC7, 0x04, 0xCC, 0x00, 0x00, 0x00, 0x00]
+    ));
+    // mov rsi, rsp
+    const_assert!(matches!(
+        mov_r64_to_r64(Reg::Rsp, Reg::Rsi),
+        [0x48, 0x89, 0xE6]
+    ));
+    // add rsi, rcx
+    const_assert!(matches!(
+        add_r64_to_r64(Reg::Rcx, Reg::Rsi),
+        [0x48, 0x01, 0xCE]
+    ));
+    // add rsi, 3
+    const_assert!(matches!(
+        add_imm8_to_r64(Reg::Rsi, 3),
+        [0x48, 0x83, 0xC6, 0x03]
+    ));
+    // add rsi, 300
+    const_assert!(matches!(
+        add_imm32_to_r64(Reg::Rsi, 300),
+        [0x48, 0x81, 0xC6, 0x2C, 0x01, 0x00, 0x00]
+    ));
+    // mov rdi, rsp
+    const_assert!(matches!(
+        mov_r64_to_r64(Reg::Rsp, Reg::Rdi),
+        [0x48, 0x89, 0xE7]
+    ));
+    // xor rdi, rdi
+    const_assert!(matches!(
+        xor_r64_r64(Reg::Rdi, Reg::Rdi),
+        [0x48, 0x31, 0xFF]
+    ));
+    // push rcx (alternative encoding of `51`)
+    const_assert!(matches!(push_r64(Reg::Rcx), [0xFF, 0xF1]));
+    // pop rcx (alternative encoding of `59`)
+    const_assert!(matches!(pop_r64(Reg::Rcx), [0x8F, 0xC1]));
+
+    // call [rbx + 8]
+    const_assert!(matches!(
+        call_rm64_disp8(RmID::RegRbx, 8),
+        [0xFF, 0x53, 0x08]
+    ));
+
+    // lea rsi, [rip - 0x10]
+    const_assert!(matches!(
+        lea_rip_disp32_to_r64(-0x10, Reg::Rsi),
+        [0x48, 0x8D, 0x35, 0xF0, 0xFF, 0xFF, 0xFF]
+    ));
+
+    // jmp +5
+    const_assert!(matches!(jmp_rel8(5), [0xEB, 0x05]));
+    // jmp +0x1234
+    const_assert!(matches!(jmp_rel32(0x1234), [0xE9, 0x34, 0x12, 0x00, 0x00]));
+    // mov r10, 0x22
+    const_assert!(matches!(
+        mov_imm32_to_r64_ext(RegExt::R10, 0x22),
+        [0x49, 0xC7, 0xC2, 0x22, 0x00, 0x00, 0x00]
+    ));
+    // mov r8, -1
+    const_assert!(matches!(
+        mov_imm32_to_r64_ext(RegExt::R8, -1),
+        [0x49, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]
+    ));
+    // mov r12, rsp
+    const_assert!(matches!(
+        mov_r64_to_r64_ext(Reg::Rsp, RegExt::R12),
+        [0x49, 0x89, 0xE4]
+    ));
+    // mov rsp, r12
+    const_assert!(matches!(
+        mov_r64_ext_to_r64(RegExt::R12, Reg::Rsp),
+        [0x4C, 0x89, 0xE4]
+    ));
+    // mov rdi, r14
+    const_assert!(matches!(
+        mov_r64_ext_to_r64(RegExt::R14, Reg::Rdi),
+        [0x4C, 0x89, 0xF7]
+    ));
+    // cmp ecx, 300
+    const_assert!(matches!(
+        cmp_r32_with_imm32(Reg::Rcx, 300),
+        [0x81, 0xF9, 0x2C, 0x01, 0x00, 0x00]
+    ));
+    // cmp rax, 0
+    const_assert!(matches!(
+        cmp_r64_with_imm8(Reg::Rax, 0),
+        [0x48, 0x83, 0xF8, 0x00]
+    ));
+    // cmp dword [rsi], eax
+    const_assert!(matches!(
+        cmp_m32_with_r32(RmI::RegRsi, Reg::Rax),
+        [0x39, 0x06]
+    ));
+    // mov rax, [rdx + 0xa8]
+    const_assert!(matches!(
+        mov_m64_disp32_to_r64(RmID::RegRdx, 0xA8, Reg::Rax),
+        [0x48, 0x8B, 0x82, 0xA8, 0x00, 0x00, 0x00]
+    ));
+    // mov eax, [rsi - 4]
+    const_assert!(matches!(
+        mov_m32_disp8_to_r32(RmID::RegRsi, -4, Reg::Rax),
+        [0x8B, 0x46, 0xFC]
+    ));
+    // mov [rdi + 0], rcx
+    const_assert!(matches!(
+        mov_r64_to_m64_disp32(Reg::Rcx, RmID::RegRdi, 0),
+        [0x48, 0x89, 0x8F, 0x00, 0x00, 0x00, 0x00]
+    ));
+    // mov qword [rdx + 0x60], 1
+    const_assert!(matches!(
+        mov_imm32_to_m64_disp32(RmID::RegRdx, 0x60, 1),
+        [0x48, 0xC7, 0x82, 0x60, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+    ));
+
+    // jns +5
+    const_assert!(matches!(jns_rel8(5), [0x79, 0x05]));
+    // jb -2
+    const_assert!(matches!(jb_rel8(-2), [0x72, 0xFE]));
+    // jbe -2
+    const_assert!(matches!(jbe_rel8(-2), [0x76, 0xFE]));
+    // jle -2
+    const_assert!(matches!(jle_rel8(-2), [0x7E, 0xFE]));
+    // jz -2
+    const_assert!(matches!(jz_rel8(-2), [0x74, 0xFE]));
+    // jz +0x1234
+    const_assert!(matches!(
+        jz_rel32(0x1234),
+        [0x0F, 0x84, 0x34, 0x12, 0x00, 0x00]
+    ));
+    // jnz -2
+    const_assert!(matches!(jnz_rel8(-2), [0x75, 0xFE]));
+    // jnz -0x1234
+    const_assert!(matches!(
+        jnz_rel32(-0x1234),
+        [0x0F, 0x85, 0xCC, 0xED, 0xFF, 0xFF]
+    ));
+    // js +0x1234
+    const_assert!(matches!(
+        js_rel32(0x1234),
+        [0x0F, 0x88, 0x34, 0x12, 0x00, 0x00]
+    ));
+    // jb +0x1234
+    const_assert!(matches!(
+        jb_rel32(0x1234),
+        [0x0F, 0x82, 0x34, 0x12, 0x00, 0x00]
+    ));
+    // jmp rdx
+    const_assert!(matches!(jmp_r64(Reg::Rdx), [0xFF, 0xE2]));
+    // call +0x1234
+    const_assert!(matches!(call_rel32(0x1234), [0xE8, 0x34, 0x12, 0x00, 0x00]));
+    // sub r15, 1
+    const_assert!(matches!(
+        sub_imm8_from_r64_ext(RegExt::R15, 1),
+        [0x49, 0x83, 0xEF, 0x01]
+    ));
+    // cmp rax, rdx
+    const_assert!(matches!(
+        cmp_r64_with_r64(Reg::Rax, Reg::Rdx),
+        [0x48, 0x39, 0xD0]
+    ));
+    // 32-bit mode, where the registers are `eax` to `edi`
+
+    // mov ecx, 0x10000
+    const_assert!(matches!(
+        mov_imm32_to_r32(Reg::Rcx, 0x10000),
+        [0xB9, 0x00, 0x00, 0x01, 0x00]
+    ));
+    // mov esp, eax
+    const_assert!(matches!(mov_r32_to_r32(Reg::Rax, Reg::Rsp), [0x89, 0xC4]));
+    // mov [edi + 0], edx
+    const_assert!(matches!(
+        mov_r32_to_m32_disp8(Reg::Rdx, RmID::RegRdi, 0),
+        [0x89, 0x57, 0x00]
+    ));
+    // add esi, ecx
+    const_assert!(matches!(add_r32_to_r32(Reg::Rcx, Reg::Rsi), [0x01, 0xCE]));
+    // sub edx, esi
+    const_assert!(matches!(sub_r32_from_r32(Reg::Rsi, Reg::Rdx), [0x29, 0xF2]));
+    // xor ebx, ebx
+    const_assert!(matches!(xor_r32_r32(Reg::Rbx, Reg::Rbx), [0x31, 0xDB]));
+    // push ecx
+    const_assert!(matches!(push_r32(Reg::Rcx), [0xFF, 0xF1]));
+    // pop ecx
+    const_assert!(matches!(pop_r32(Reg::Rcx), [0x8F, 0xC1]));
+    // cmp al, 4
+    const_assert!(matches!(cmp_r8_with_imm8(Reg::Rax, 4), [0x80, 0xF8, 0x04]));
+    // test al, 0x20
+    const_assert!(matches!(
+        test_r8_with_imm8(Reg::Rax, 0x20),
+        [0xF6, 0xC0, 0x20]
+    ));
+    // cmp rax, 0x8000
+    const_assert!(matches!(
+        cmp_r64_with_imm32(Reg::Rax, 0x8000),
+        [0x48, 0x81, 0xF8, 0x00, 0x80, 0x00, 0x00]
+    ));
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest;
+
+    /// The scaled index byte addressing the current register.
+    const SIB: Sib = Sib::new(Scale::B1, Reg::Rcx, Reg::Rsp);
+
+    /// The example programs, the standard corpus of the code generation tests.
+    const EXAMPLES: [(&str, &str); 6] = [
+        ("hello", include_str!("../examples/hello.bf")),
+        ("mandelbrot", include_str!("../examples/mandelbrot.bf")),
+        ("numwarp", include_str!("../examples/numwarp.bf")),
+        ("dbfi", include_str!("../examples/dbfi.b")),
+        ("gameoflife", include_str!("../examples/gameoflife.bf")),
+        ("tictactoe", include_str!("../examples/tictactoe.bf")),
+    ];
+
+    /// The code of an in-process function running the program `src` optimized with `-O2`.
+    fn compile_src(src: &str) -> Vec<u8> {
+        compile_with(&Config::o2(), src)
+    }
+
+    fn compile_with(config: &Config, src: &str) -> Vec<u8> {
+        let instructions = selftest::build(config, src.as_bytes()).unwrap();
+        compile_function(config, &RuntimeOptions::new(config), &instructions)
+    }
+
+    /// The code of the `instructions`, without the setup and the runtime routines.
+    fn encode(instructions: &[Instruction]) -> Vec<u8> {
+        let mut instructions = instructions.to_vec();
+        crate::update_jump_indices(&mut instructions);
+        let config = Config::o2();
+        let options = RuntimeOptions::new(&config);
+        let context = SegmentContext {
+            config: &config,
+            options: &options,
+            runtime: Runtime::InProcess,
+            last_input: None,
+            checked_tape_len: None,
+        };
+        write_segment(&context, &instructions, 0..instructions.len()).code
+    }
+
+    fn contains(code: &[u8], bytes: &[u8]) -> bool {
+        code.windows(bytes.len()).any(|w| w == bytes)
+    }
+
+    fn mul_to_al(n: u8) -> Vec<u8> {
+        let mut code = Vec::new();
+        write_mul_sib8_to_al(&mut code, SIB, n);
+        code
+    }
+
+    #[test]
+    fn mul_by_power_of_two_is_shifted() {
+        // mov al, [rsp + rcx]
+        assert_eq!(mul_to_al(1), [0x8A, 0x04, 0x0C]);
+        // mov al, [rsp + rcx]; shl al, 1
+        assert_eq!(mul_to_al(2), [0x8A, 0x04, 0x0C, 0xC0, 0xE0, 0x01]);
+        // mov al, [rsp + rcx]; shl al, 7
+        assert_eq!(mul_to_al(128), [0x8A, 0x04, 0x0C, 0xC0, 0xE0, 0x07]);
+    }
+
+    #[test]
+    fn mul_by_small_factor_uses_lea() {
+        // mov al, [rsp + rcx]; lea eax, [rax + 2 * rax]
+        assert_eq!(mul_to_al(3), [0x8A, 0x04, 0x0C, 0x8D, 0x04, 0x40]);
+        // mov al, [rsp + rcx]; lea eax, [rax + 4 * rax]
+        assert_eq!(mul_to_al(5), [0x8A, 0x04, 0x0C, 0x8D, 0x04, 0x80]);
+        // mov al, [rsp + rcx]; lea eax, [rax + 8 * rax]
+        assert_eq!(mul_to_al(9), [0x8A, 0x04, 0x0C, 0x8D, 0x04, 0xC0]);
+    }
+
+    #[test]
+    fn mul_by_other_factor_uses_mul() {
+        // mov al, 7; mul byte [rsp + rcx]
+        assert_eq!(mul_to_al(7), [0xC6, 0xC0, 0x07, 0xF6, 0x24, 0x0C]);
+        // mov al, 255; mul byte [rsp + rcx]
+        assert_eq!(mul_to_al(255), [0xC6, 0xC0, 0xFF, 0xF6, 0x24, 0x0C]);
+    }
+
+    #[test]
+    fn add_mul_loops_are_strength_reduced() {
+        const MUL: [u8; 3] = mul_al_with_sib8(SIB);
+        // the loops are replaced by `AddMul(1, n)` and `SubMul(1, n)`
+        for src in [",[->++++<]>.", ",[->+++++<]>.", ",[->--------<]>."] {
+            let code = compile_src(src);
+            assert!(!contains(&code, &MUL), "`{src}` multiplies");
+        }
+        assert!(contains(&compile_src(",[->+++++++<]>."), &MUL));
+    }
+
+    #[test]
+    fn pointer_moves_are_folded_into_displacements() {
+        use Instruction::*;
+
+        let instructions = [Shr(1), Inc(0, 1), Shr(2), Dec(-1, 2), Shl(3), Output(0)];
+        let (scheduled, origins) = schedule_pointer_moves(&Config::o2(), &instructions);
+        assert_eq!(scheduled, [Inc(1, 1), Dec(2, 2), Output(0)]);
+        assert_eq!(origins, [1, 3, 5]);
+
+        // jumps end the block, the pending move is emitted before them
+        let instructions = [Shr(2), Inc(0, 1), JumpZ(Jump::Redundant), Shl(1)];
+        let (scheduled, origins) = schedule_pointer_moves(&Config::o2(), &instructions);
+        assert_eq!(
+            scheduled,
+            [Inc(2, 1), Shr(2), JumpZ(Jump::Redundant), Shl(1)]
+        );
+        assert_eq!(origins, [1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn scheduling_reduces_emitted_pointer_moves() {
+        let config = Config::o2();
+        let shifts = |insts: &[Instruction]| {
+            (insts.iter())
+                .filter(|i| matches!(i, Instruction::Shl(_) | Instruction::Shr(_)))
+                .count()
+        };
+        let (mut before, mut after) = (0, 0);
+        for (name, src) in EXAMPLES {
+            let instructions = selftest::build(&config, src.as_bytes()).unwrap();
+            let (scheduled, origins) = schedule_pointer_moves(&config, &instructions);
+            assert_eq!(scheduled.len(), origins.len(), "{name}");
+            assert!(shifts(&scheduled) <= shifts(&instructions), "{name}");
+            before += shifts(&instructions);
+            after += shifts(&scheduled);
+        }
+        assert!(
+            after < before,
+            "{after} pointer moves after scheduling, {before} before"
+        );
+    }
+
+    #[test]
+    fn scheduling_reduces_code_size() {
+        // the executables, since the bounds checks of in-process functions aren't scheduled
+        let executable = |config: &Config, src: &str| {
+            let instructions = selftest::build(config, src.as_bytes()).unwrap();
+            compile(config, &RuntimeOptions::new(config), &instructions).len()
+        };
+        let config = Config::o2();
+        let unscheduled = config.clone().with_pass(Pass::Schedule, false);
+        let (mut before, mut after) = (0, 0);
+        for (_, src) in EXAMPLES {
+            before += executable(&unscheduled, src);
+            after += executable(&config, src);
+        }
+        assert!(
+            after < before,
+            "{after} bytes after scheduling, {before} before"
+        );
+    }
+
+    #[test]
+    fn instructions_encode_to_expected_bytes() {
+        use Instruction::*;
+
+        #[rustfmt::skip]
+        let cases: [(&[Instruction], &[u8]); 9] = [
+            // add byte [rsp + rcx], 3
+            (&[Inc(0, 3)], &[0x80, 0x04, 0x0C, 0x03]),
+            // sub byte [rsp + rcx - 1], 1
+            (&[Dec(-1, 1)], &[0x80, 0x6C, 0x0C, 0xFF, 0x01]),
+            // add byte [rsp + rcx + 1000], 1
+            (&[Inc(1000, 1)], &[0x80, 0x84, 0x0C, 0xE8, 0x03, 0x00, 0x00, 0x01]),
+            // add ecx, 5
+            (&[Shr(5)], &[0x83, 0xC1, 0x05]),
+            // sub ecx, 300
+            (&[Shl(300)], &[0x81, 0xE9, 0x2C, 0x01, 0x00, 0x00]),
+            // mov byte [rsp + rcx], 0
+            (&[Zero(0)], &[0xC6, 0x04, 0x0C, 0x00]),
+            // mov byte [rsp + rcx + 2], 7
+            (&[Set(2, 7)], &[0xC6, 0x44, 0x0C, 0x02, 0x07]),
+            // mov al, [rsp + rcx]; add [rsp + rcx + 1], al
+            (&[Add(1)], &[0x8A, 0x04, 0x0C, 0x00, 0x44, 0x0C, 0x01]),
+            // cmp byte [rsp + rcx], 0; jz +10; sub byte [rsp + rcx], 1;
+            // cmp byte [rsp + rcx], 0; jnz -10
+            (
+                &[JumpZ(Jump::Unresolved), Dec(0, 1), JumpNz(Jump::Unresolved)],
+                &[
+                    0x80, 0x3C, 0x0C, 0x00, 0x74, 0x0A,
+                    0x80, 0x2C, 0x0C, 0x01,
+                    0x80, 0x3C, 0x0C, 0x00, 0x75, 0xF6,
+                ],
+            ),
+        ];
+        for (instructions, expected) in cases {
+            assert_eq!(encode(instructions), expected, "{instructions:?}");
+        }
+    }
+
+    #[test]
+    fn long_loops_use_rel32_jumps() {
+        use Instruction::*;
+
+        let mut instructions = vec![JumpZ(Jump::Unresolved)];
+        instructions.extend((0..40).map(|i| Inc(i, 1)));
+        instructions.push(JumpNz(Jump::Unresolved));
+        let code = encode(&instructions);
+        // cmp byte [rsp + rcx], 0; jz rel32
+        assert_eq!(code[..6], [0x80, 0x3C, 0x0C, 0x00, 0x0F, 0x84]);
+        // cmp byte [rsp + rcx], 0; jnz rel32
+        assert_eq!(
+            code[code.len() - 10..][..6],
+            [0x80, 0x3C, 0x0C, 0x00, 0x0F, 0x85]
+        );
+        // both jumps skip the body and the other jump
+        let offset = (code.len() - 10) as i32;
+        assert_eq!(code[6..10], i32::to_le_bytes(offset));
+        assert_eq!(code[code.len() - 4..], i32::to_le_bytes(-offset));
+    }
+
+    #[cfg(feature = "disasm")]
+    #[test]
+    fn disassembly_snapshot() {
+        use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+        use Instruction::*;
+
+        let code = encode(&[
+            Inc(0, 8),
+            JumpZ(Jump::Unresolved),
+            AddMul(1, 4),
+            SubMul(2, 3),
+            Zero(0),
+            JumpNz(Jump::Unresolved),
+            Shr(1),
+        ]);
+        let mut formatter = IntelFormatter::new();
+        let lines = Decoder::new(64, &code, DecoderOptions::NONE)
+            .into_iter()
+            .map(|inst| {
+                let mut line = String::new();
+                formatter.format(&inst, &mut line);
+                line
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            "add byte ptr [rsp+rcx],8",
+            "cmp byte ptr [rsp+rcx],0",
+            "je short 0000000000000028h",
+            "mov al,[rsp+rcx]",
+            "shl al,2",
+            "add [rsp+rcx+1],al",
+            "mov al,[rsp+rcx]",
+            "lea eax,[rax+rax*2]",
+            "sub [rsp+rcx+2],al",
+            "mov byte ptr [rsp+rcx],0",
+            "cmp byte ptr [rsp+rcx],0",
+            "jne short 000000000000000Ah",
+            "add ecx,1",
+        ];
+        assert_eq!(lines, expected);
+    }
+}